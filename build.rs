@@ -0,0 +1,61 @@
+//! Captures what `nix-installer` is built with, for `nix-installer --build-info`
+//!
+//! Everything is passed to the crate as `NIX_INSTALLER_BUILD_*` environment variables, read by the
+//! `build_info` module.
+
+use std::{env, path::Path, process::Command};
+
+fn main() {
+    let mut features = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<_>>();
+    features.sort();
+    println!(
+        "cargo:rustc-env=NIX_INSTALLER_BUILD_FEATURES={}",
+        features.join(",")
+    );
+
+    println!(
+        "cargo:rustc-env=NIX_INSTALLER_BUILD_TARGET={}",
+        env::var("TARGET").unwrap_or_else(|_| "unknown".into())
+    );
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    println!(
+        "cargo:rustc-env=NIX_INSTALLER_BUILD_RUSTC={}",
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".into())
+    );
+
+    // Builds without a `.git`, like those in the Nix sandbox, pass the revision in
+    println!("cargo:rerun-if-env-changed=NIX_INSTALLER_GIT_REVISION");
+    let git_revision = env::var("NIX_INSTALLER_GIT_REVISION")
+        .ok()
+        .filter(|revision| !revision.is_empty())
+        .or_else(|| command_output("git", &["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=NIX_INSTALLER_BUILD_GIT_REVISION={git_revision}");
+    // A path which does not exist would rerun this on every build
+    for path in [".git/HEAD", ".git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+
+    println!("cargo:rerun-if-env-changed=DETERMINATE_NIXD_VERSION");
+    if let Ok(version) = env::var("DETERMINATE_NIXD_VERSION") {
+        println!("cargo:rustc-env=NIX_INSTALLER_BUILD_DETERMINATE_NIXD_VERSION={version}");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string())
+}
@@ -110,6 +110,8 @@
 
             NIX_INSTALLER_TARBALL_PATH = nixTarballs.${final.stdenv.system};
             DETERMINATE_NIXD_BINARY_PATH = optionalPathToDeterminateNixd final.stdenv.system;
+            # The sandbox has no `.git` for `build.rs` to read the revision from
+            NIX_INSTALLER_GIT_REVISION = self.rev or self.dirtyRev or "unknown";
 
             override = { preBuild ? "", ... }: {
               preBuild = preBuild + ''
@@ -8,7 +8,7 @@ use tracing::{span, Span};
 use crate::action::{ActionError, ActionErrorKind};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription, StatefulAction};
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
 
 /**
 Create an operating system level user in the given group
@@ -37,7 +37,7 @@ impl AddUserToGroup {
             gid,
         };
 
-        match OperatingSystem::host() {
+        match crate::cross_target::operating_system() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
                 if !(which::which("addgroup").is_ok() || which::which("gpasswd").is_ok()) {
@@ -73,7 +73,7 @@ impl AddUserToGroup {
             }
 
             // See if group membership needs to be done
-            match OperatingSystem::host() {
+            match crate::cross_target::operating_system() {
                 OperatingSystem::MacOSX {
                     major: _,
                     minor: _,
@@ -195,6 +195,38 @@ impl Action for AddUserToGroup {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        use target_lexicon::OperatingSystem;
+        match OperatingSystem::host() {
+            OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => vec![
+                PrivilegedOperation::command(
+                    "/usr/bin/dscl",
+                    [
+                        ".",
+                        "-append",
+                        &format!("/Groups/{}", self.groupname),
+                        "GroupMembership",
+                        &self.name,
+                    ],
+                ),
+                PrivilegedOperation::command(
+                    "/usr/sbin/dseditgroup",
+                    ["-o", "edit", "-a", &self.name, &self.groupname],
+                ),
+            ],
+            _ if which::which("gpasswd").is_err() && which::which("addgroup").is_ok() => {
+                vec![PrivilegedOperation::command(
+                    "addgroup",
+                    [&self.name, &self.groupname],
+                )]
+            },
+            _ => vec![PrivilegedOperation::command(
+                "gpasswd",
+                ["-a", &self.name, &self.groupname],
+            )],
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         use target_lexicon::OperatingSystem;
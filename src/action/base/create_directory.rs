@@ -1,3 +1,4 @@
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 
@@ -7,19 +8,21 @@ use target_lexicon::OperatingSystem;
 use tokio::process::Command;
 use tracing::{span, Span};
 
-use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState};
+use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState, PrivilegedOperation};
 use crate::action::{ActionError, StatefulAction};
 use crate::execute_command;
-use crate::util::OnMissing;
+use crate::util::{LossyPath, OnMissing};
 
 /** Create a directory at the given location, optionally with an owning user, group, and mode.
 
 If `force_prune_on_revert` is set, the folder will always be deleted on
 [`revert`](CreateDirectory::revert).
 */
+#[serde_with::serde_as]
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_directory")]
 pub struct CreateDirectory {
+    #[serde_as(as = "LossyPath")]
     pub(crate) path: PathBuf,
     user: Option<String>,
     group: Option<String>,
@@ -116,6 +119,25 @@ impl CreateDirectory {
     }
 }
 
+impl StatefulAction<CreateDirectory> {
+    /// Never revert (or execute) a directory which already existed when planning, such as one in
+    /// a shared Nix store
+    pub(crate) fn preserve_existing(mut self) -> Self {
+        if self.state == ActionState::Completed {
+            self.state = ActionState::Skipped;
+        }
+        self
+    }
+
+    /// Treat the directory as a mountpoint which is created (and mounted) by an earlier action,
+    /// such as a ZFS dataset, so it is only cleaned on revert
+    pub(crate) fn onto_mountpoint(mut self) -> Self {
+        self.action.is_mountpoint = true;
+        self.state = ActionState::Completed;
+        self
+    }
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "create_directory")]
 impl Action for CreateDirectory {
@@ -143,6 +165,13 @@ impl Action for CreateDirectory {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        if self.is_mountpoint {
+            return vec![];
+        }
+        vec![PrivilegedOperation::write(&self.path, self.mode)]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
@@ -295,10 +324,8 @@ impl Action for CreateDirectory {
 
 // There are cleaner ways of doing this (eg `systemctl status $PATH`) however we need a widely supported way.
 async fn path_is_mountpoint(path: &Path) -> Result<bool, ActionErrorKind> {
-    let path_str = match path.to_str() {
-        Some(path_str) => path_str,
-        None => return Err(ActionErrorKind::PathNoneString(path.to_path_buf())),
-    };
+    // Paths (and so mount output) are not necessarily UTF-8, so compare bytes
+    let path_bytes = path.as_os_str().as_bytes();
 
     let mut mount_command = match OperatingSystem::host() {
         OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
@@ -316,51 +343,68 @@ async fn path_is_mountpoint(path: &Path) -> Result<bool, ActionErrorKind> {
     mount_command.process_group(0);
 
     let output = execute_command(&mut mount_command).await?;
-    let output_string = String::from_utf8(output.stdout).map_err(ActionErrorKind::FromUtf8)?;
 
-    for line in output_string.lines() {
-        let mut line_splitter = line.split(" on ");
-        match line_splitter.next() {
-            Some(_device) => (),
-            None => continue,
-        }
-        let destination_and_options = match line_splitter.next() {
-            Some(destination_and_options) => destination_and_options,
-            None => continue,
-        };
+    let split_token: &[u8] = match OperatingSystem::host() {
+        // Each line on MacOS looks like `/dev/disk3s6 on /System/Volumes/VM (apfs, local, noexec, journaled, noatime, nobrowse)`
+        OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => b"(",
+        // Each line on Linux looks like `portal on /run/user/1000/doc type fuse.portal (rw,nosuid,nodev,relatime,user_id=1000,group_id=100)`
+        _ => b"type",
+    };
 
-        let split_token = match OperatingSystem::host() {
-            // Each line on MacOS looks like `/dev/disk3s6 on /System/Volumes/VM (apfs, local, noexec, journaled, noatime, nobrowse)`
-            OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => "(",
-            // Each line on Linux looks like `portal on /run/user/1000/doc type fuse.portal (rw,nosuid,nodev,relatime,user_id=1000,group_id=100)`
-            _ => "type",
+    for line in output.stdout.split(|byte| *byte == b'\n') {
+        let destination_and_options = match split_once(line, b" on ") {
+            Some((_device, rest)) => split_once(rest, b" on ").map_or(rest, |(first, _)| first),
+            None => continue,
         };
 
-        if let Some(mount_path) = destination_and_options.rsplit(split_token).last() {
-            let trimmed = mount_path.trim();
-            if trimmed == path_str {
-                tracing::trace!("Found mountpoint for `{mount_path}`");
-                return Ok(true);
-            }
+        let mount_path = split_once(destination_and_options, split_token)
+            .map_or(destination_and_options, |(first, _)| first);
+        if mount_path.trim_ascii() == path_bytes {
+            tracing::trace!(
+                "Found mountpoint for `{}`",
+                String::from_utf8_lossy(mount_path.trim_ascii())
+            );
+            return Ok(true);
         }
     }
 
     Ok(false)
 }
 
+/// Split `haystack` around the first occurrence of `needle`
+fn split_once<'a>(haystack: &'a [u8], needle: &[u8]) -> Option<(&'a [u8], &'a [u8])> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|idx| (&haystack[..idx], &haystack[idx + needle.len()..]))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_harness::{FakeCommand, SandboxContext};
 
     #[tokio::test]
     async fn creates_and_deletes_empty_directory() -> eyre::Result<()> {
-        let temp_dir = tempfile::tempdir()?;
-        let test_dir = temp_dir.path().join("creates_and_deletes_empty_directory");
-        let mut action = CreateDirectory::plan(test_dir.clone(), None, None, None, false).await?;
-
-        action.try_execute().await?;
-
-        action.try_revert().await?;
+        let sandbox = SandboxContext::new()?;
+        let test_dir = sandbox.path("/nix");
+        let mut actions = vec![sandbox
+            .scope(CreateDirectory::plan(
+                test_dir.clone(),
+                None,
+                None,
+                0o0755,
+                false,
+            ))
+            .await?
+            .boxed()];
+
+        sandbox.execute(&mut actions).await?;
+
+        let metadata = tokio::fs::metadata(&test_dir).await?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o0755);
+
+        sandbox.revert(&mut actions).await?;
 
         assert!(!test_dir.exists(), "Folder should have been deleted");
 
@@ -407,4 +451,39 @@ mod test {
 
         Ok(())
     }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn detects_non_utf8_mountpoint() -> eyre::Result<()> {
+        use std::ffi::OsStr;
+
+        let sandbox = SandboxContext::new()?;
+        let test_dir = sandbox.path("/nix").join(OsStr::from_bytes(b"store-\xff"));
+        tokio::fs::create_dir_all(&test_dir).await?;
+
+        let mut mount_output = b"/dev/sda1 on ".to_vec();
+        mount_output.extend_from_slice(test_dir.as_os_str().as_bytes());
+        mount_output.extend_from_slice(b" type ext4 (rw,relatime)\n");
+        sandbox.fake("mount", FakeCommand::success().stdout(mount_output));
+
+        let action = sandbox
+            .scope(CreateDirectory::plan(
+                test_dir.clone(),
+                None,
+                None,
+                None,
+                false,
+            ))
+            .await?;
+        assert!(
+            action.inner().is_mountpoint,
+            "Should have been a mountpoint"
+        );
+
+        // The receipt is JSON, the path can only be recorded lossily
+        let receipt = serde_json::to_string(&action)?;
+        assert!(receipt.contains("store-\u{FFFD}"));
+
+        Ok(())
+    }
 }
@@ -1,18 +1,20 @@
-use nix::unistd::{chown, Group, User};
+use nix::unistd::{Group, User};
 use tracing::{span, Span};
 
 use std::{
     os::{unix::fs::MetadataExt, unix::fs::PermissionsExt},
     path::{Path, PathBuf},
 };
-use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt},
-};
+use tokio::{fs::File, io::AsyncReadExt};
 
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
-    util::OnMissing,
+    action::{
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
+    },
+    drift::WrittenFile,
+    replace_file::{replace_file, Attributes},
+    util::{LossyPath, OnMissing},
 };
 
 /** Create a file at the given location with the provided `buf`,
@@ -21,15 +23,20 @@ optionally with an owning user, group, and mode.
 If `force` is set, the file will always be overwritten (and deleted)
 regardless of its presence prior to install.
  */
+#[serde_with::serde_as]
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_file")]
 pub struct CreateFile {
+    #[serde_as(as = "LossyPath")]
     pub(crate) path: PathBuf,
     user: Option<String>,
     group: Option<String>,
     mode: Option<u32>,
     buf: String,
     force: bool,
+    /// What was written, once executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    written_file: Option<WrittenFile>,
 }
 
 impl CreateFile {
@@ -53,6 +60,7 @@ impl CreateFile {
             mode,
             buf,
             force,
+            written_file: None,
         };
 
         if this.path.exists() {
@@ -177,6 +185,10 @@ impl Action for CreateFile {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::write(&self.path, self.mode)]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         if tracing::enabled!(tracing::Level::TRACE) {
@@ -184,24 +196,6 @@ impl Action for CreateFile {
             span.record("buf", &self.buf);
         }
 
-        let mut options = OpenOptions::new();
-        options.create_new(true).write(true).read(true);
-
-        if let Some(mode) = self.mode {
-            options.mode(mode);
-        }
-
-        let mut file = options
-            .open(&self.path)
-            .await
-            .map_err(|e| ActionErrorKind::Open(self.path.to_owned(), e))
-            .map_err(Self::error)?;
-
-        file.write_all(self.buf.as_bytes())
-            .await
-            .map_err(|e| ActionErrorKind::Write(self.path.to_owned(), e))
-            .map_err(Self::error)?;
-
         let gid = if let Some(ref group) = self.group {
             Some(
                 Group::from_name(group.as_str())
@@ -226,9 +220,17 @@ impl Action for CreateFile {
         } else {
             None
         };
-        chown(&self.path, uid, gid)
-            .map_err(|e| ActionErrorKind::Chown(self.path.clone(), e))
+        let attributes = Attributes {
+            mode: self.mode.unwrap_or(0o644),
+            uid,
+            gid,
+        };
+        replace_file(&self.path, self.buf.as_bytes(), attributes)
+            .await
             .map_err(Self::error)?;
+        let written = WrittenFile::whole(&self.path, self.buf.as_bytes());
+        crate::managed_files::register(Self::action_tag(), &written);
+        self.written_file = Some(written);
 
         Ok(())
     }
@@ -241,6 +243,7 @@ impl Action for CreateFile {
             mode: _,
             buf: _,
             force: _,
+            written_file: _,
         } = &self;
 
         vec![ActionDescription::new(
@@ -258,8 +261,17 @@ impl Action for CreateFile {
             mode: _,
             buf: _,
             force: _,
+            written_file,
         } = self;
 
+        // The file belongs to `nix-installer`, it is removed either way
+        if crate::drift::modified_since(path, written_file.as_ref()).await {
+            tracing::warn!(
+                "`{}` was changed since it was written, removing it anyway",
+                path.display()
+            );
+        }
+
         crate::util::remove_file(path, OnMissing::Ignore)
             .await
             .map_err(|e| ActionErrorKind::Remove(path.to_owned(), e))
@@ -272,21 +284,88 @@ impl Action for CreateFile {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_harness::SandboxContext;
     use color_eyre::eyre::eyre;
     use tokio::fs::write;
 
     #[tokio::test]
     async fn creates_and_deletes_file() -> eyre::Result<()> {
-        let temp_dir = tempfile::tempdir()?;
-        let test_file = temp_dir.path().join("creates_and_deletes_file");
-        let mut action =
-            CreateFile::plan(test_file.clone(), None, None, None, "Test".into(), false).await?;
+        let sandbox = SandboxContext::new()?;
+        let test_file = sandbox.path("/etc/profile.d/nix.sh");
+        let mut actions = vec![sandbox
+            .scope(CreateFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                0o0644,
+                "Test".into(),
+                false,
+            ))
+            .await?
+            .boxed()];
+
+        sandbox.execute(&mut actions).await?;
+
+        assert_eq!(tokio::fs::read_to_string(&test_file).await?, "Test");
+
+        sandbox.revert(&mut actions).await?;
 
-        action.try_execute().await?;
+        assert!(!test_file.exists(), "File should have been deleted");
 
-        action.try_revert().await?;
+        Ok(())
+    }
 
-        assert!(!test_file.exists(), "File should have been deleted");
+    #[tokio::test]
+    async fn records_hash_of_written_content() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let test_file = sandbox.path("/etc/profile.d/nix.sh");
+        let mut action = sandbox
+            .scope(CreateFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                0o0644,
+                "Test".into(),
+                false,
+            ))
+            .await?;
+
+        sandbox.scope(action.try_execute()).await?;
+        let written_file = action.action.written_file.clone().unwrap();
+        assert_eq!(written_file.path, test_file);
+        assert_eq!(written_file.sha256, crate::backup::sha256(b"Test"));
+        assert!(!crate::drift::modified_since(&test_file, Some(&written_file)).await);
+
+        write(&test_file, "Edited").await?;
+        assert!(crate::drift::modified_since(&test_file, Some(&written_file)).await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn creates_file_when_renames_cross_devices() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fail_renames(nix::errno::Errno::EXDEV);
+        let test_file = sandbox.path("/etc/profile.d/nix.sh");
+        let mut actions = vec![sandbox
+            .scope(CreateFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                0o0640,
+                "Test".into(),
+                false,
+            ))
+            .await?
+            .boxed()];
+
+        sandbox.execute(&mut actions).await?;
+
+        assert_eq!(tokio::fs::read_to_string(&test_file).await?, "Test");
+        assert_eq!(
+            std::fs::metadata(&test_file)?.permissions().mode() & 0o777,
+            0o640
+        );
 
         Ok(())
     }
@@ -5,17 +5,25 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::settings::CommonSettings;
+use crate::util::host_path;
 
-use crate::action::{Action, ActionDescription, StatefulAction};
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
 
 /**
 Create an operating system level user group
+
+With `--use-existing-build-group`, a group which already exists is adopted with whatever GID it
+has, and is never deleted.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_group")]
 pub struct CreateGroup {
     name: String,
-    gid: u32,
+    pub(crate) gid: u32,
+    /// The group already existed and was adopted, so it is left in place on revert
+    #[serde(default)]
+    pub(crate) adopted: bool,
 }
 
 impl CreateGroup {
@@ -24,9 +32,10 @@ impl CreateGroup {
         let this = Self {
             name: name.clone(),
             gid,
+            adopted: false,
         };
 
-        match OperatingSystem::host() {
+        match crate::cross_target::operating_system() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
                 if !(which::which("groupadd").is_ok() || which::which("addgroup").is_ok()) {
@@ -39,10 +48,7 @@ impl CreateGroup {
         }
 
         // Ensure group does not exists
-        if let Some(group) = Group::from_name(name.as_str())
-            .map_err(|e| ActionErrorKind::GettingGroupId(name.clone(), e))
-            .map_err(Self::error)?
-        {
+        if let Some(group) = find_group(&name).map_err(Self::error)? {
             if group.gid.as_raw() != gid {
                 return Err(Self::error(ActionErrorKind::GroupGidMismatch(
                     name.clone(),
@@ -56,6 +62,151 @@ impl CreateGroup {
         }
         Ok(StatefulAction::uncompleted(this))
     }
+
+    /// Like [`plan`](Self::plan), but adopting the group if it already exists, whatever its GID
+    ///
+    /// Only the Nix build users, named `member_prefix` followed by a number, may use an adopted
+    /// group: it is refused if it has other members, or is the primary group of other users.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn plan_adopting(
+        name: String,
+        gid: u32,
+        member_prefix: &str,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let Some(group) = find_group(&name).map_err(Self::error)? else {
+            return Self::plan(name, gid);
+        };
+        let existing_gid = group.gid.as_raw();
+        let primary_users = primary_group_users(existing_gid).map_err(Self::error)?;
+        check_adoptable(&group, &primary_users, member_prefix).map_err(Self::error)?;
+
+        if existing_gid != gid {
+            tracing::debug!(
+                "Adopting group `{name}` with its GID {existing_gid} rather than {gid}"
+            );
+        }
+        Ok(StatefulAction::completed(Self {
+            name,
+            gid: existing_gid,
+            adopted: true,
+        }))
+    }
+}
+
+/// The GID the Nix build group has, or will have, according to `settings`
+///
+/// With `--use-existing-build-group` that is the GID of the existing group, if there is one.
+pub(crate) fn build_group_gid(settings: &CommonSettings) -> Result<u32, ActionErrorKind> {
+    if settings.use_existing_build_group {
+        if let Some(group) = find_group(&settings.nix_build_group_name)? {
+            return Ok(group.gid.as_raw());
+        }
+    }
+    Ok(settings.nix_build_group_id)
+}
+
+/// Look up the group `name`
+///
+/// While a [`SandboxContext`](crate::test_harness::SandboxContext) is active, only the sandbox's
+/// `/etc/group` is read.
+fn find_group(name: &str) -> Result<Option<Group>, ActionErrorKind> {
+    #[cfg(any(test, feature = "test-harness"))]
+    if crate::test_harness::is_active() {
+        let path = host_path("/etc/group");
+        let groups = match std::fs::read_to_string(&path) {
+            Ok(groups) => groups,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(ActionErrorKind::Read(path, e)),
+        };
+        return Ok(parse_group(&groups, name));
+    }
+    Group::from_name(name).map_err(|e| ActionErrorKind::GettingGroupId(name.to_string(), e))
+}
+
+/// The group `name` from the `/etc/group` formatted `groups`
+#[cfg(any(test, feature = "test-harness"))]
+fn parse_group(groups: &str, name: &str) -> Option<Group> {
+    groups.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let (Some(found), Some(_), Some(gid), Some(members)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return None;
+        };
+        if found != name {
+            return None;
+        }
+        Some(Group {
+            name: found.to_string(),
+            passwd: Default::default(),
+            gid: nix::unistd::Gid::from_raw(gid.parse().ok()?),
+            mem: members
+                .split(',')
+                .filter(|member| !member.is_empty())
+                .map(String::from)
+                .collect(),
+        })
+    })
+}
+
+/// The users in `/etc/passwd` whose primary group is `gid`
+///
+/// Users from a directory service are not listed in `/etc/passwd`, so are not found.
+fn primary_group_users(gid: u32) -> Result<Vec<String>, ActionErrorKind> {
+    let path = host_path("/etc/passwd");
+    let users = match std::fs::read_to_string(&path) {
+        Ok(users) => users,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(ActionErrorKind::Read(path, e)),
+    };
+    Ok(users
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let user_gid: u32 = fields.nth(2)?.parse().ok()?;
+            (user_gid == gid).then(|| name.to_string())
+        })
+        .collect())
+}
+
+/// Refuse to adopt `group` if anyone but the Nix build users could be using it
+///
+/// `primary_users` are the users whose primary group it is.
+fn check_adoptable(
+    group: &Group,
+    primary_users: &[String],
+    member_prefix: &str,
+) -> Result<(), CreateGroupError> {
+    let is_build_user = |user: &String| {
+        user.strip_prefix(member_prefix)
+            .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let primary_users = primary_users
+        .iter()
+        .filter(|user| !is_build_user(user))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !primary_users.is_empty() {
+        return Err(CreateGroupError::PrimaryGroup {
+            group: group.name.clone(),
+            users: primary_users.join(", "),
+        });
+    }
+    let members = group
+        .mem
+        .iter()
+        .filter(|member| !is_build_user(member))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !members.is_empty() {
+        return Err(CreateGroupError::OtherMembers {
+            group: group.name.clone(),
+            members: members.join(", "),
+            member_prefix: member_prefix.to_string(),
+        });
+    }
+    Ok(())
 }
 
 #[async_trait::async_trait]
@@ -68,7 +219,14 @@ impl Action for CreateGroup {
         format!("Create group `{}` (GID {})", self.name, self.gid)
     }
     fn execute_description(&self) -> Vec<ActionDescription> {
-        let Self { name: _, gid: _ } = &self;
+        if self.adopted {
+            return vec![ActionDescription::new(
+                format!("Use the existing group `{}` (GID {})", self.name, self.gid),
+                vec![format!(
+                    "The nix daemon requires a system user group its system users can be part of"
+                )],
+            )];
+        }
         vec![ActionDescription::new(
             self.tracing_synopsis(),
             vec![format!(
@@ -86,9 +244,47 @@ impl Action for CreateGroup {
         )
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let Self { name, gid, adopted } = self;
+        if *adopted {
+            return vec![];
+        }
+        let gid = gid.to_string();
+        match OperatingSystem::host() {
+            OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
+                vec![PrivilegedOperation::command(
+                    "/usr/sbin/dseditgroup",
+                    [
+                        "-o",
+                        "create",
+                        "-r",
+                        "Nix build group for nix-daemon",
+                        "-i",
+                        &gid,
+                        name,
+                    ],
+                )]
+            },
+            _ if which::which("groupadd").is_err() && which::which("addgroup").is_ok() => {
+                vec![PrivilegedOperation::command(
+                    "addgroup",
+                    ["-g", &gid, "--system", name],
+                )]
+            },
+            _ => vec![PrivilegedOperation::command(
+                "groupadd",
+                ["-g", &gid, "--system", name],
+            )],
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid } = self;
+        let Self {
+            name,
+            gid,
+            adopted: _,
+        } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -144,7 +340,10 @@ impl Action for CreateGroup {
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
-        let Self { name, gid } = &self;
+        let Self { name, gid, adopted } = &self;
+        if *adopted {
+            return vec![];
+        }
         vec![ActionDescription::new(
             format!("Delete group `{name}` (GID {gid})"),
             vec![format!(
@@ -155,7 +354,15 @@ impl Action for CreateGroup {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid: _ } = self;
+        let Self {
+            name,
+            gid: _,
+            adopted,
+        } = self;
+        if *adopted {
+            tracing::debug!("Leaving the adopted group `{name}` in place");
+            return Ok(());
+        }
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -201,3 +408,59 @@ impl Action for CreateGroup {
         Ok(())
     }
 }
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateGroupError {
+    #[error("The group `{group}` is the primary group of {users}, so it can't be used as the Nix build group")]
+    PrimaryGroup { group: String, users: String },
+    #[error("The group `{group}` has members besides the Nix build users (`{member_prefix}<number>`): {members}, so it can't be used as the Nix build group")]
+    OtherMembers {
+        group: String,
+        members: String,
+        member_prefix: String,
+    },
+}
+
+impl From<CreateGroupError> for ActionErrorKind {
+    fn from(val: CreateGroupError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_adoptable, parse_group, CreateGroupError};
+
+    const GROUPS: &str = "root:x:0:\nnixbld:x:4242:nixbld1,nixbld2\nstaff:x:50:nixbld1,alice\n";
+
+    #[test]
+    fn parses_groups() {
+        let group = parse_group(GROUPS, "nixbld").unwrap();
+        assert_eq!(group.gid.as_raw(), 4242);
+        assert_eq!(group.mem, ["nixbld1", "nixbld2"]);
+        assert!(parse_group(GROUPS, "root").unwrap().mem.is_empty());
+        assert!(parse_group(GROUPS, "nix").is_none());
+    }
+
+    #[test]
+    fn only_groups_of_build_users_are_adoptable() {
+        let nixbld = parse_group(GROUPS, "nixbld").unwrap();
+        assert!(check_adoptable(&nixbld, &[], "nixbld").is_ok());
+        assert!(check_adoptable(&nixbld, &["nixbld7".into()], "nixbld").is_ok());
+        assert!(matches!(
+            check_adoptable(&nixbld, &["nixbld".into()], "nixbld"),
+            Err(CreateGroupError::PrimaryGroup { users, .. }) if users == "nixbld"
+        ));
+        assert!(matches!(
+            check_adoptable(&nixbld, &[], "_nixbld"),
+            Err(CreateGroupError::OtherMembers { members, .. }) if members == "nixbld1, nixbld2"
+        ));
+
+        let staff = parse_group(GROUPS, "staff").unwrap();
+        assert!(matches!(
+            check_adoptable(&staff, &[], "nixbld"),
+            Err(CreateGroupError::OtherMembers { members, .. }) if members == "alice"
+        ));
+    }
+}
@@ -1,8 +1,11 @@
 use nix::unistd::{chown, Group, User};
 
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
+use crate::drift::{DriftStatus, WrittenFile};
+use crate::util::LossyPath;
 use rand::Rng;
 use std::{
     io::SeekFrom,
@@ -27,15 +30,20 @@ contents, optionally with an owning user, group, and mode.
 If the file exists, the provided `buf` will be inserted at its
 beginning or end, depending on the position field.
  */
+#[serde_with::serde_as]
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_or_insert_into_file")]
 pub struct CreateOrInsertIntoFile {
-    path: PathBuf,
+    #[serde_as(as = "LossyPath")]
+    pub(crate) path: PathBuf,
     user: Option<String>,
     group: Option<String>,
     mode: Option<u32>,
     buf: String,
     position: Position,
+    /// The block inserted, once executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    written_file: Option<WrittenFile>,
 }
 
 impl CreateOrInsertIntoFile {
@@ -59,6 +67,7 @@ impl CreateOrInsertIntoFile {
             mode,
             buf,
             position,
+            written_file: None,
         };
         if this.path.exists() {
             // If the path exists, perhaps we can just skip this
@@ -161,7 +170,7 @@ impl Action for CreateOrInsertIntoFile {
     fn tracing_span(&self) -> Span {
         let span = span!(
             tracing::Level::DEBUG,
-            "create_or_insert_file",
+            "create_or_insert_into_file",
             path = tracing::field::display(self.path.display()),
             user = self.user,
             group = self.group,
@@ -181,6 +190,10 @@ impl Action for CreateOrInsertIntoFile {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::write(&self.path, self.mode)]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
@@ -190,6 +203,7 @@ impl Action for CreateOrInsertIntoFile {
             mode,
             buf,
             position,
+            written_file,
         } = self;
 
         let mut orig_file = match OpenOptions::new().read(true).open(&path).await {
@@ -309,6 +323,10 @@ impl Action for CreateOrInsertIntoFile {
             .await
             .map_err(|e| ActionErrorKind::Rename(path.to_owned(), temp_file_path.to_owned(), e))
             .map_err(Self::error)?;
+        *written_file = WrittenFile::fenced(path.as_path(), buf);
+        if let Some(written) = written_file {
+            crate::managed_files::register(Self::action_tag(), written);
+        }
 
         Ok(())
     }
@@ -321,6 +339,7 @@ impl Action for CreateOrInsertIntoFile {
             mode: _,
             buf,
             position: _,
+            written_file: _,
         } = &self;
         vec![ActionDescription::new(
             format!("Delete Nix related fragment from file `{}`", path.display()),
@@ -340,6 +359,7 @@ impl Action for CreateOrInsertIntoFile {
             mode: _,
             buf,
             position: _,
+            written_file,
         } = self;
         // The user already deleted it
         if !path.exists() {
@@ -364,6 +384,15 @@ impl Action for CreateOrInsertIntoFile {
         if let Some(start) = file_contents.rfind(buf.as_str()) {
             let end = start + buf.len();
             file_contents.replace_range(start..end, "")
+        } else if written_file
+            .as_ref()
+            .map(|written| written.status_of(file_contents.as_bytes()))
+            == Some(DriftStatus::Modified)
+        {
+            tracing::warn!(
+                "The Nix related fragment in `{}` was changed since it was written, leaving it in place",
+                path.display()
+            );
         }
 
         if file_contents.is_empty() {
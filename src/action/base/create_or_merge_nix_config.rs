@@ -1,25 +1,31 @@
-use std::{
-    os::unix::fs::PermissionsExt,
-    path::{Path, PathBuf},
-};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 
+use nix::unistd::{Gid, Group};
 use nix_config_parser::NixConfig;
-use rand::Rng;
-use tokio::{
-    fs::{remove_file, OpenOptions},
-    io::AsyncWriteExt,
-};
+use tokio::fs::remove_file;
 use tracing::{span, Span};
 
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
+use crate::backup::{Backup, BackupError, BackupStore};
+use crate::drift::{DriftStatus, WrittenFile};
+use crate::replace_file::{replace_file, Attributes};
+use crate::util::OnMissing;
 
 /// The `nix.conf` configuration names that are safe to merge.
 // FIXME(@cole-h): make configurable by downstream users?
-const MERGEABLE_CONF_NAMES: &[&str] = &["experimental-features"];
+const MERGEABLE_CONF_NAMES: &[&str] = &["experimental-features", "trusted-users", "allowed-users"];
 const NIX_CONF_MODE: u32 = 0o644;
+/// The modes `nix.conf` may be given, every user of Nix must be able to read it
+pub const NIX_CONF_MODES: &[u32] = &[0o644, 0o664];
 const NIX_CONF_COMMENT_CHAR: char = '#';
+/// Appended to the name of an existing `nix.conf` backed up next to it, when the backups in the
+/// state directory cannot be written
+const SIDE_BACKUP_SUFFIX: &str = "before-nix-installer";
+const GENERATED_BY: &str = "# Generated by https://github.com/DeterminateSystems/nix-installer.";
 
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
@@ -33,6 +39,67 @@ pub enum CreateOrMergeNixConfigError {
         .collect::<Vec<_>>()
         .join(", "))]
     UnmergeableConfig(Vec<String>, std::path::PathBuf),
+    #[error("`{0:04o}` is not a mode `nix.conf` can have, use one of {}", NIX_CONF_MODES.iter().map(|mode| format!("`{mode:04o}`")).collect::<Vec<_>>().join(", "))]
+    UnsafeMode(u32),
+    #[error("`nix.conf` can only be group-writable (`{0:04o}`) when its owner group is set, pass `--nix-conf-owner-group`")]
+    GroupWritableWithoutGroup(u32),
+}
+
+/// The owner group and mode `nix.conf` is written with
+///
+/// Without either, it is written `0644` and owned by `root`, and the mode of an existing
+/// `nix.conf` is left as it is.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct NixConfPermissions {
+    /// See [`CommonSettings::nix_conf_owner_group`](crate::settings::CommonSettings::nix_conf_owner_group)
+    #[serde(default)]
+    pub group: Option<String>,
+    /// See [`CommonSettings::nix_conf_mode`](crate::settings::CommonSettings::nix_conf_mode)
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+impl NixConfPermissions {
+    fn is_default(&self) -> bool {
+        self.group.is_none() && self.mode.is_none()
+    }
+
+    pub(crate) fn mode(&self) -> u32 {
+        self.mode.unwrap_or(NIX_CONF_MODE)
+    }
+
+    /// Check the mode is one of [`NIX_CONF_MODES`], and look up the group
+    fn validate(&self) -> Result<Option<Gid>, ActionErrorKind> {
+        let mode = self.mode();
+        if !NIX_CONF_MODES.contains(&mode) {
+            return Err(CreateOrMergeNixConfigError::UnsafeMode(mode).into());
+        }
+        let Some(group) = &self.group else {
+            if mode & 0o020 != 0 {
+                return Err(CreateOrMergeNixConfigError::GroupWritableWithoutGroup(mode).into());
+            }
+            return Ok(None);
+        };
+        let gid = Group::from_name(group)
+            .map_err(|e| ActionErrorKind::GettingGroupId(group.clone(), e))?
+            .ok_or_else(|| ActionErrorKind::NoGroup(group.clone()))?
+            .gid;
+        Ok(Some(gid))
+    }
+
+    fn attributes(&self, gid: Option<Gid>) -> Attributes {
+        Attributes {
+            mode: self.mode(),
+            uid: None,
+            gid,
+        }
+    }
+
+    /// If `metadata` has this mode and group
+    fn matches(&self, metadata: &std::fs::Metadata, gid: Option<Gid>) -> bool {
+        metadata.mode() & 0o777 == self.mode()
+            && gid.is_none_or(|gid| metadata.gid() == gid.as_raw())
+    }
 }
 
 impl From<CreateOrMergeNixConfigError> for ActionErrorKind {
@@ -42,24 +109,82 @@ impl From<CreateOrMergeNixConfigError> for ActionErrorKind {
 }
 
 /// Create or merge an existing `nix.conf` at the specified path.
+///
+/// An existing `nix.conf` is backed up before it is merged into, and restored on revert. If it was
+/// edited since, and the block of settings added can be removed without losing anything, only the
+/// block is removed instead, keeping the edits.
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_or_merge_nix_config")]
 pub struct CreateOrMergeNixConfig {
     pub(crate) path: PathBuf,
     pending_nix_config: NixConfig,
+    /// See [`CommonSettings::managed_file_annotation`](crate::settings::CommonSettings::managed_file_annotation)
+    #[serde(default)]
+    annotation: Option<String>,
+    /// Where an existing `nix.conf` is backed up, receipts from before backups have none
+    #[serde(default)]
+    backups: Option<BackupStore>,
+    /// The existing `nix.conf`, once backed up
+    #[serde(default)]
+    backup: Option<Backup>,
+    /// Where the existing `nix.conf` was backed up to, once executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backup_location: Option<PathBuf>,
+    /// The block of settings added to an existing `nix.conf`, when removing it restores the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    added_block: Option<WrittenFile>,
+    /// What was written, once executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    written_file: Option<WrittenFile>,
+    #[serde(default, skip_serializing_if = "NixConfPermissions::is_default")]
+    permissions: NixConfPermissions,
 }
 
 impl CreateOrMergeNixConfig {
+    /// The settings being written
+    pub(crate) fn pending_nix_config(&self) -> &NixConfig {
+        &self.pending_nix_config
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         path: impl AsRef<Path>,
         pending_nix_config: NixConfig,
+        annotation: Option<String>,
+        backups: Option<BackupStore>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Self::plan_with_permissions(
+            path,
+            pending_nix_config,
+            annotation,
+            backups,
+            NixConfPermissions::default(),
+        )
+        .await
+    }
+
+    /// Like [`plan`](Self::plan), giving `nix.conf` the owner group and mode of `permissions`
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan_with_permissions(
+        path: impl AsRef<Path>,
+        pending_nix_config: NixConfig,
+        annotation: Option<String>,
+        backups: Option<BackupStore>,
+        permissions: NixConfPermissions,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let path = path.as_ref().to_path_buf();
+        let gid = permissions.validate().map_err(Self::error)?;
 
         let this = Self {
             path,
             pending_nix_config,
+            annotation,
+            backups,
+            backup: None,
+            backup_location: None,
+            added_block: None,
+            written_file: None,
+            permissions,
         };
 
         if this.path.exists() {
@@ -68,6 +193,12 @@ impl CreateOrMergeNixConfig {
 
             if !merged_nix_config.settings().is_empty() {
                 return Ok(StatefulAction::uncompleted(this));
+            } else if !this.permissions.is_default() && !this.has_permissions(gid)? {
+                tracing::debug!(
+                    "`{}` has its settings, but not the configured owner group and mode",
+                    this.path.display()
+                );
+                return Ok(StatefulAction::uncompleted(this));
             } else {
                 tracing::debug!(
                     "Setting Nix configurations in `{}` already complete",
@@ -105,9 +236,11 @@ impl CreateOrMergeNixConfig {
                 } else if MERGEABLE_CONF_NAMES.contains(&pending_conf_name.as_str()) {
                     let mut merged_conf_value =
                         Vec::with_capacity(pending_conf_value.len() + existing_conf_value.len());
-                    merged_conf_value.extend(pending_conf_value);
-                    merged_conf_value.extend(existing_conf_value);
-                    merged_conf_value.dedup();
+                    for value in pending_conf_value.into_iter().chain(existing_conf_value) {
+                        if !merged_conf_value.contains(&value) {
+                            merged_conf_value.push(value);
+                        }
+                    }
                     let merged_conf_value = merged_conf_value.join(" ");
                     let merged_conf_value = merged_conf_value.trim();
 
@@ -134,6 +267,15 @@ impl CreateOrMergeNixConfig {
         Ok((merged_nix_config, existing_nix_config.clone()))
     }
 
+    /// If the existing `nix.conf` has the configured mode and owner group
+    fn has_permissions(&self, gid: Option<Gid>) -> Result<bool, ActionError> {
+        let metadata = self
+            .path
+            .metadata()
+            .map_err(|e| Self::error(ActionErrorKind::GettingMetadata(self.path.clone(), e)))?;
+        Ok(self.permissions.matches(&metadata, gid))
+    }
+
     fn validate_existing_nix_config(
         pending_nix_config: &NixConfig,
         path: &Path,
@@ -180,7 +322,7 @@ impl Action for CreateOrMergeNixConfig {
             tracing::Level::DEBUG,
             "create_or_merge_nix_config",
             path = tracing::field::display(self.path.display()),
-            mode = tracing::field::display(format!("{:#o}", NIX_CONF_MODE)),
+            mode = tracing::field::display(format!("{:#o}", self.permissions.mode())),
             pending_nix_config = tracing::field::Empty,
         );
 
@@ -199,19 +341,41 @@ impl Action for CreateOrMergeNixConfig {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(
-            self.tracing_synopsis(),
-            vec![format!(
-                "Added settings: {settings}",
-                settings = self
-                    .pending_nix_config
-                    .settings()
-                    .iter()
-                    .map(|(k, v)| format!("{k}=\"{v}\""))
-                    .collect::<Vec<_>>()
-                    .join(", "),
-            )],
-        )]
+        let mut explanation = vec![format!(
+            "Added settings: {settings}",
+            settings = self
+                .pending_nix_config
+                .settings()
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{v}\""))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )];
+        if let Some(location) = &self.backup_location {
+            explanation.push(format!(
+                "The existing `{}` was backed up to `{}`",
+                self.path.display(),
+                location.display()
+            ));
+        } else if let Some(backups) = self.backups.as_ref().filter(|_| self.path.exists()) {
+            explanation.push(format!(
+                "Back up the existing `{}` to `{}` first",
+                self.path.display(),
+                backups.dir().display()
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = vec![PrivilegedOperation::write(
+            &self.path,
+            self.permissions.mode(),
+        )];
+        if let Some(backups) = &self.backups {
+            operations.push(PrivilegedOperation::write(backups.dir(), None));
+        }
+        operations
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
@@ -219,7 +383,38 @@ impl Action for CreateOrMergeNixConfig {
         let Self {
             path,
             pending_nix_config,
+            annotation,
+            backups,
+            backup,
+            backup_location,
+            added_block,
+            written_file,
+            permissions,
         } = self;
+        let annotation_comment = crate::settings::annotation_comment(annotation.as_deref());
+        let gid = permissions.validate().map_err(Self::error)?;
+
+        if let Some(backups) = backups {
+            match backups.back_up(path).await {
+                Ok(stored) => {
+                    *backup_location = stored
+                        .as_ref()
+                        .map(|stored| backups.dir().join(&stored.hash));
+                    *backup = stored;
+                },
+                // The state directory may not be writable yet
+                Err(BackupError::Write(dir, e)) => {
+                    tracing::warn!(
+                        "Could not write to `{}` ({e}), backing up `{}` next to it instead",
+                        dir.display(),
+                        path.display()
+                    );
+                    *backup = None;
+                    *backup_location = back_up_beside(path).await.map_err(Self::error)?;
+                },
+                Err(e) => return Err(Self::error(e)),
+            }
+        }
 
         if tracing::enabled!(tracing::Level::TRACE) {
             let span = tracing::Span::current();
@@ -234,31 +429,6 @@ impl Action for CreateOrMergeNixConfig {
             );
         }
 
-        // Create a temporary file in the same directory as the one
-        // that the final file goes in, so that we can rename it
-        // atomically
-        let parent_dir = path.parent().expect("File must be in a directory");
-        let mut temp_file_path = parent_dir.to_owned();
-        {
-            let mut rng = rand::thread_rng();
-            temp_file_path.push(format!("nix-installer-tmp.{}", rng.gen::<u32>()));
-        }
-        let mut temp_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            // If the file is created, ensure that it has harmless
-            // permissions regardless of whether the mode will be
-            // changed later (if we ever create setuid executables,
-            // they should only become setuid once they are owned by
-            // the appropriate user)
-            .mode(0o600)
-            .open(&temp_file_path)
-            .await
-            .map_err(|e| {
-                Self::error(ActionErrorKind::Open(temp_file_path.clone(), e))
-            })?;
-
         let (mut merged_nix_config, mut existing_nix_config) = if path.exists() {
             let (merged_nix_config, existing_nix_config) =
                 Self::validate_existing_nix_config(pending_nix_config, path)?;
@@ -266,6 +436,31 @@ impl Action for CreateOrMergeNixConfig {
         } else {
             (pending_nix_config.clone(), None)
         };
+        if existing_nix_config.is_some()
+            && merged_nix_config.settings().is_empty()
+            && !permissions.is_default()
+        {
+            // Only the owner group and mode were not yet as configured
+            let content = tokio::fs::read(&path)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Read(path.to_path_buf(), e)))?;
+            replace_file(path, &content, permissions.attributes(gid))
+                .await
+                .map_err(Self::error)?;
+            let written = WrittenFile::whole(path.as_path(), &content)
+                .with_permissions(permissions.mode(), gid.map(Gid::as_raw));
+            crate::managed_files::register(Self::action_tag(), &written);
+            *written_file = Some(written);
+            return Ok(());
+        }
+        // Settings merged with existing ones drop the existing line, so removing the added block
+        // alone would lose them
+        let block_is_separable = existing_nix_config.as_ref().is_some_and(|existing| {
+            merged_nix_config
+                .settings()
+                .keys()
+                .all(|name| !existing.settings().contains_key(name))
+        });
 
         let mut new_config = String::new();
 
@@ -288,8 +483,11 @@ impl Action for CreateOrMergeNixConfig {
                  line| {
                     let line = line.trim();
 
-                    // Don't associate our "Generated by" comment if it appears
-                    if line.starts_with("# Generated by") {
+                    // Don't associate our "Generated by" comment (or annotation) if it appears,
+                    // they are written again below
+                    if line.starts_with("# Generated by")
+                        || (!annotation_comment.is_empty() && line == annotation_comment.trim_end())
+                    {
                         return (all_assoc, current_assoc, associating);
                     }
 
@@ -397,44 +595,34 @@ impl Action for CreateOrMergeNixConfig {
             new_config.push('\n');
         }
 
-        new_config
-            .push_str("# Generated by https://github.com/DeterminateSystems/nix-installer.\n");
-        new_config.push_str("# See `/nix/nix-installer --version` for the version details.\n");
-        new_config.push('\n');
+        let mut block = String::new();
+        block.push_str(GENERATED_BY);
+        block.push('\n');
+        block.push_str("# See `/nix/nix-installer --version` for the version details.\n");
+        block.push_str(&annotation_comment);
+        block.push('\n');
 
         for (name, value) in merged_nix_config.settings() {
-            new_config.push_str(name);
-            new_config.push_str(" = ");
-            new_config.push_str(value);
-            new_config.push('\n');
+            block.push_str(name);
+            block.push_str(" = ");
+            block.push_str(value);
+            block.push('\n');
         }
+        new_config.push_str(&block);
+        *added_block = block_is_separable
+            .then(|| WrittenFile::fenced(path.as_path(), &block))
+            .flatten();
 
-        temp_file
-            .write_all(new_config.as_bytes())
-            .await
-            .map_err(|e| Self::error(ActionErrorKind::Write(temp_file_path.clone(), e)))?;
-        tokio::fs::set_permissions(&temp_file_path, PermissionsExt::from_mode(NIX_CONF_MODE))
-            .await
-            .map_err(|e| {
-                Self::error(ActionErrorKind::SetPermissions(
-                    NIX_CONF_MODE,
-                    path.to_owned(),
-                    e,
-                ))
-            })?;
-        temp_file
-            .sync_all()
+        replace_file(path, new_config.as_bytes(), permissions.attributes(gid))
             .await
-            .map_err(|e| Self::error(ActionErrorKind::Sync(temp_file_path.clone(), e)))?;
-        tokio::fs::rename(&temp_file_path, &path)
-            .await
-            .map_err(|e| {
-                Self::error(ActionErrorKind::Rename(
-                    temp_file_path.to_owned(),
-                    path.to_owned(),
-                    e,
-                ))
-            })?;
+            .map_err(Self::error)?;
+        let written = WrittenFile::whole(path.as_path(), new_config.as_bytes());
+        let written = match permissions.is_default() {
+            true => written,
+            false => written.with_permissions(permissions.mode(), gid.map(Gid::as_raw)),
+        };
+        crate::managed_files::register(Self::action_tag(), &written);
+        *written_file = Some(written);
 
         Ok(())
     }
@@ -443,8 +631,24 @@ impl Action for CreateOrMergeNixConfig {
         let Self {
             path,
             pending_nix_config: _,
+            annotation: _,
+            backups,
+            backup,
+            backup_location,
+            added_block: _,
+            written_file: _,
+            permissions: _,
         } = &self;
 
+        if (backups.is_some() && backup.is_some()) || backup_location.is_some() {
+            return vec![ActionDescription::new(
+                format!("Restore file `{}`", path.display()),
+                vec![format!(
+                    "Restore `{}` as it was before the install, from its backup (or only remove the added settings, if it was edited since)",
+                    path.display()
+                )],
+            )];
+        }
         vec![ActionDescription::new(
             format!("Delete file `{}`", path.display()),
             vec![format!("Delete file `{}`", path.display())],
@@ -456,8 +660,69 @@ impl Action for CreateOrMergeNixConfig {
         let Self {
             path,
             pending_nix_config: _,
+            annotation: _,
+            backups,
+            backup,
+            backup_location,
+            added_block,
+            written_file,
+            permissions,
         } = self;
 
+        let stored = match (backups, backup) {
+            (Some(backups), Some(backup)) => Some((backups, backup)),
+            _ => None,
+        };
+        let beside = match (&stored, backup_location) {
+            (None, Some(location)) => Some(location),
+            _ => None,
+        };
+        if stored.is_some() || beside.is_some() {
+            let current = tokio::fs::read_to_string(&path).await.ok();
+            let edited = match (&current, written_file.as_ref()) {
+                (Some(current), Some(written)) => {
+                    written.status_of(current.as_bytes()) != DriftStatus::Unchanged
+                },
+                _ => false,
+            };
+            if edited {
+                let without_block = current
+                    .as_deref()
+                    .zip(added_block.as_ref())
+                    .and_then(|(current, block)| block.remove_from(current));
+                if let Some(content) = without_block {
+                    tracing::debug!(
+                        "`{}` was edited since the install, only removing the added settings",
+                        path.display()
+                    );
+                    // The group may have been removed since
+                    let gid = permissions.validate().ok().flatten();
+                    replace_file(path, content.as_bytes(), permissions.attributes(gid))
+                        .await
+                        .map_err(Self::error)?;
+                    if let Some(beside) = beside {
+                        crate::util::remove_file(beside, OnMissing::Ignore)
+                            .await
+                            .map_err(|e| Self::error(ActionErrorKind::Remove(beside.clone(), e)))?;
+                    }
+                    return Ok(());
+                }
+                tracing::warn!(
+                    "`{}` was edited since the install, and the added settings cannot be told apart from the edits, restoring it from its backup",
+                    path.display()
+                );
+            }
+
+            if let Some((backups, backup)) = stored {
+                backups.restore(backup).await.map_err(Self::error)?;
+            } else if let Some(beside) = beside {
+                tokio::fs::rename(&beside, &path).await.map_err(|e| {
+                    Self::error(ActionErrorKind::Rename(beside.clone(), path.clone(), e))
+                })?;
+            }
+            return Ok(());
+        }
+
         remove_file(&path)
             .await
             .map_err(|e| Self::error(ActionErrorKind::Remove(path.to_owned(), e)))?;
@@ -466,10 +731,44 @@ impl Action for CreateOrMergeNixConfig {
     }
 }
 
+/// Where an existing `nix.conf` at `path` is backed up when the state directory cannot be written
+fn side_backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(SIDE_BACKUP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Copy the file at `path` next to it, returning where, if there is a file
+///
+/// A backup already there from an earlier install is kept, a timestamp tells the new one apart.
+async fn back_up_beside(path: &Path) -> Result<Option<PathBuf>, ActionErrorKind> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut location = side_backup_path(path);
+    if location.exists() {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map(|v| v.as_secs())
+            .unwrap_or_default();
+        let mut name = location.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{created}"));
+        location.set_file_name(name);
+    }
+    tokio::fs::copy(path, &location)
+        .await
+        .map_err(|e| ActionErrorKind::Copy(path.to_path_buf(), location.clone(), e))?;
+    tracing::debug!("Backed up `{}` to `{}`", path.display(), location.display());
+    Ok(Some(location))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::action::ActionState;
     use color_eyre::eyre::eyre;
+    use std::os::unix::fs::PermissionsExt;
     use tokio::fs::write;
 
     #[tokio::test]
@@ -480,7 +779,7 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "ca-references".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config, None, None).await?;
 
         action.try_execute().await?;
 
@@ -506,7 +805,7 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "ca-references".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config, None, None).await?;
 
         action.try_execute().await?;
 
@@ -534,7 +833,7 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "flakes".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config, None, None).await?;
 
         action.try_execute().await?;
 
@@ -566,7 +865,7 @@ mod test {
         nix_config
             .settings_mut()
             .insert("allow-dirty".into(), "false".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config, None, None).await?;
 
         action.try_execute().await?;
 
@@ -590,6 +889,45 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn merges_trusted_users_without_duplicates() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir
+            .path()
+            .join("merges_trusted_users_without_duplicates");
+
+        write(
+            test_file.as_path(),
+            "trusted-users = root bob
+allowed-users = *
+",
+        )
+        .await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(NIX_CONF_MODE)).await?;
+
+        let mut nix_config = NixConfig::new();
+        nix_config
+            .settings_mut()
+            .insert("trusted-users".into(), "root alice @wheel".into());
+        nix_config
+            .settings_mut()
+            .insert("allowed-users".into(), "*".into());
+        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config, None, None).await?;
+        action.try_execute().await?;
+
+        let merged = NixConfig::parse_file(&test_file)?;
+        assert_eq!(
+            merged.settings().get("trusted-users").map(String::as_str),
+            Some("root alice @wheel bob")
+        );
+        assert_eq!(
+            merged.settings().get("allowed-users").map(String::as_str),
+            Some("*")
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn recognizes_existing_different_files_and_fails_to_merge() -> eyre::Result<()> {
         let temp_dir = tempfile::TempDir::new()?;
@@ -611,7 +949,7 @@ mod test {
         nix_config
             .settings_mut()
             .insert("warn-dirty".into(), "false".into());
-        match CreateOrMergeNixConfig::plan(&test_file, nix_config).await {
+        match CreateOrMergeNixConfig::plan(&test_file, nix_config, None, None).await {
             Err(err) => {
                 if let ActionErrorKind::Custom(e) = err.kind() {
                     match e.downcast_ref::<CreateOrMergeNixConfigError>() {
@@ -653,7 +991,7 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "ca-references".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config, None, None).await?;
 
         action.try_execute().await?;
 
@@ -685,7 +1023,7 @@ mod test {
         nix_config
             .settings_mut()
             .insert("experimental-features".into(), "ca-references".into());
-        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config).await?;
+        let mut action = CreateOrMergeNixConfig::plan(&test_file, nix_config, None, None).await?;
 
         action.try_execute().await?;
 
@@ -701,4 +1039,354 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn annotation_round_trips_through_merge() -> eyre::Result<()> {
+        const ANNOTATION: &str = "Owned by team-infra, see OPS-1234";
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir.path().join("annotation_round_trips_through_merge");
+        let mut nix_config = NixConfig::new();
+        nix_config
+            .settings_mut()
+            .insert("experimental-features".into(), "ca-references".into());
+        let mut action = CreateOrMergeNixConfig::plan(
+            &test_file,
+            nix_config.clone(),
+            Some(ANNOTATION.into()),
+            None,
+        )
+        .await?;
+
+        action.try_execute().await?;
+
+        let s = std::fs::read_to_string(&test_file)?;
+        assert!(s.contains(&format!(
+            "# See `/nix/nix-installer --version` for the version details.\n# {ANNOTATION}\n"
+        )));
+        assert!(NixConfig::parse_file(&test_file).is_ok());
+
+        // The annotation doesn't hide that the settings are already in place
+        let completed = CreateOrMergeNixConfig::plan(
+            &test_file,
+            nix_config.clone(),
+            Some(ANNOTATION.into()),
+            None,
+        )
+        .await?;
+        assert_eq!(completed.state, crate::action::ActionState::Completed);
+
+        // Merging into a file we annotated keeps a single annotation
+        nix_config
+            .settings_mut()
+            .insert("warn-dirty".into(), "false".into());
+        let mut merge =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, Some(ANNOTATION.into()), None)
+                .await?;
+        assert_eq!(merge.state, crate::action::ActionState::Uncompleted);
+        merge.try_execute().await?;
+
+        let s = std::fs::read_to_string(&test_file)?;
+        assert_eq!(s.matches(ANNOTATION).count(), 1);
+        assert!(s.contains("ca-references"));
+        assert!(s.contains("warn-dirty = false"));
+
+        merge.try_revert().await?;
+
+        assert!(!test_file.exists(), "File should have been deleted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merged_files_are_restored_from_their_backup() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir
+            .path()
+            .join("merged_files_are_restored_from_their_backup");
+        let original =
+            "# Mine\nexperimental-features = flakes\naccess-tokens = github.com=hunter2\n";
+        write(test_file.as_path(), original).await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o600)).await?;
+
+        let mut nix_config = NixConfig::new();
+        nix_config
+            .settings_mut()
+            .insert("experimental-features".into(), "nix-command flakes".into());
+        let backups = BackupStore::new(temp_dir.path().join("state"));
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, None, Some(backups.clone()))
+                .await?;
+
+        action.try_execute().await?;
+
+        assert!(std::fs::read_to_string(&test_file)?.contains("nix-command"));
+        let backup = action
+            .action
+            .backup
+            .clone()
+            .expect("nix.conf was backed up");
+        assert_eq!(backup.mode, 0o600);
+        assert_eq!(backups.list().await?, vec![backup]);
+
+        action.try_revert().await?;
+
+        assert_eq!(std::fs::read_to_string(&test_file)?, original);
+        assert_eq!(
+            std::fs::metadata(&test_file)?.permissions().mode() & 0o777,
+            0o600
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn created_files_are_deleted_despite_backups() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir
+            .path()
+            .join("created_files_are_deleted_despite_backups");
+        let mut nix_config = NixConfig::new();
+        nix_config
+            .settings_mut()
+            .insert("experimental-features".into(), "nix-command".into());
+        let backups = BackupStore::new(temp_dir.path().join("state"));
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, None, Some(backups.clone()))
+                .await?;
+
+        action.try_execute().await?;
+        assert!(action.action.backup.is_none());
+        assert!(action.action.backup_location.is_none());
+        assert!(backups.list().await?.is_empty());
+
+        action.try_revert().await?;
+
+        assert!(!test_file.exists(), "File should have been deleted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn edited_files_only_lose_the_added_settings() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir
+            .path()
+            .join("edited_files_only_lose_the_added_settings");
+        let original = "warn-dirty = true\n";
+        let mut nix_config = NixConfig::new();
+        nix_config
+            .settings_mut()
+            .insert("experimental-features".into(), "nix-command".into());
+        let backups = BackupStore::new(temp_dir.path().join("state"));
+
+        // Edited outside of the added settings, the edit is kept
+        write(test_file.as_path(), original).await?;
+        let mut action = CreateOrMergeNixConfig::plan(
+            &test_file,
+            nix_config.clone(),
+            None,
+            Some(backups.clone()),
+        )
+        .await?;
+        action.try_execute().await?;
+        let backup = action
+            .action
+            .backup
+            .clone()
+            .expect("nix.conf was backed up");
+        assert_eq!(
+            action.action.backup_location,
+            Some(backups.dir().join(&backup.hash))
+        );
+        assert!(action.action.execute_description()[0]
+            .explanation
+            .iter()
+            .any(|line| line.contains(&backup.hash)));
+        let edited = format!("{}max-jobs = 4\n", std::fs::read_to_string(&test_file)?);
+        write(test_file.as_path(), edited).await?;
+        action.try_revert().await?;
+        assert_eq!(
+            std::fs::read_to_string(&test_file)?,
+            "warn-dirty = true\n\nmax-jobs = 4\n"
+        );
+
+        // Edited inside of them, they cannot be told apart and the backup wins
+        write(test_file.as_path(), original).await?;
+        let mut action = CreateOrMergeNixConfig::plan(
+            &test_file,
+            nix_config.clone(),
+            None,
+            Some(backups.clone()),
+        )
+        .await?;
+        action.try_execute().await?;
+        let edited =
+            std::fs::read_to_string(&test_file)?.replace("nix-command", "nix-command flakes");
+        write(test_file.as_path(), edited).await?;
+        action.try_revert().await?;
+        assert_eq!(std::fs::read_to_string(&test_file)?, original);
+
+        // Merged into an existing setting, removing the added ones would lose it
+        let original = "experimental-features = flakes\n";
+        write(test_file.as_path(), original).await?;
+        let mut action =
+            CreateOrMergeNixConfig::plan(&test_file, nix_config, None, Some(backups.clone()))
+                .await?;
+        action.try_execute().await?;
+        assert!(action.action.added_block.is_none());
+        let edited = format!("{}max-jobs = 4\n", std::fs::read_to_string(&test_file)?);
+        write(test_file.as_path(), edited).await?;
+        action.try_revert().await?;
+        assert_eq!(std::fs::read_to_string(&test_file)?, original);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backs_up_beside_the_file_without_a_state_directory() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir.path().join("nix.conf");
+        let original = "warn-dirty = true\n";
+        write(test_file.as_path(), original).await?;
+        // Not a directory, so no backups can be written under it
+        let state_dir = temp_dir.path().join("state");
+        write(&state_dir, "").await?;
+
+        let mut nix_config = NixConfig::new();
+        nix_config
+            .settings_mut()
+            .insert("experimental-features".into(), "nix-command".into());
+        let mut action = CreateOrMergeNixConfig::plan(
+            &test_file,
+            nix_config,
+            None,
+            Some(BackupStore::new(&state_dir)),
+        )
+        .await?;
+        action.try_execute().await?;
+
+        let beside = temp_dir.path().join("nix.conf.before-nix-installer");
+        assert!(action.action.backup.is_none());
+        assert_eq!(action.action.backup_location.as_ref(), Some(&beside));
+        assert_eq!(std::fs::read_to_string(&beside)?, original);
+
+        action.try_revert().await?;
+
+        assert_eq!(std::fs::read_to_string(&test_file)?, original);
+        assert!(!beside.exists());
+
+        Ok(())
+    }
+
+    fn experimental_features() -> NixConfig {
+        let mut nix_config = NixConfig::new();
+        nix_config
+            .settings_mut()
+            .insert("experimental-features".into(), "flakes".into());
+        nix_config
+    }
+
+    /// The name of the current group, which the file can be given without being root
+    fn current_group() -> eyre::Result<String> {
+        Ok(Group::from_gid(Gid::effective())?
+            .ok_or_else(|| eyre!("The current group has no name"))?
+            .name)
+    }
+
+    #[tokio::test]
+    async fn planning_checks_the_group_and_mode() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir.path().join("nix.conf");
+        let plan = |group: Option<&str>, mode| {
+            CreateOrMergeNixConfig::plan_with_permissions(
+                &test_file,
+                experimental_features(),
+                None,
+                None,
+                NixConfPermissions {
+                    group: group.map(Into::into),
+                    mode,
+                },
+            )
+        };
+
+        let err = plan(Some("nix-installer-no-such-group"), Some(0o664))
+            .await
+            .expect_err("The group does not exist");
+        assert!(
+            matches!(err.kind(), ActionErrorKind::NoGroup(group) if group == "nix-installer-no-such-group")
+        );
+
+        let group = current_group()?;
+        for mode in [0o666, 0o600, 0o4755] {
+            let err = plan(Some(&group), Some(mode))
+                .await
+                .expect_err("The mode is not allowed");
+            assert!(
+                err.kind()
+                    .to_string()
+                    .contains("is not a mode `nix.conf` can have"),
+                "{err}"
+            );
+        }
+        let err = plan(None, Some(0o664))
+            .await
+            .expect_err("Group-writable without a group");
+        assert!(err.kind().to_string().contains("--nix-conf-owner-group"));
+
+        plan(Some(&group), Some(0o664)).await?;
+        plan(None, Some(0o644)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn existing_file_with_the_configured_mode_is_complete() -> eyre::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let test_file = temp_dir.path().join("nix.conf");
+        write(test_file.as_path(), "experimental-features = flakes\n").await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o664)).await?;
+        let permissions = NixConfPermissions {
+            group: Some(current_group()?),
+            mode: Some(0o664),
+        };
+        let plan = || {
+            CreateOrMergeNixConfig::plan_with_permissions(
+                &test_file,
+                experimental_features(),
+                None,
+                None,
+                permissions.clone(),
+            )
+        };
+
+        assert_eq!(plan().await?.state, ActionState::Completed);
+        // Without configured permissions, the mode of an existing file is left alone
+        assert_eq!(
+            CreateOrMergeNixConfig::plan(&test_file, experimental_features(), None, None)
+                .await?
+                .state,
+            ActionState::Completed
+        );
+
+        // Only the mode is applied, the content is kept
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o644)).await?;
+        let mut action = plan().await?;
+        assert_eq!(action.state, ActionState::Uncompleted);
+        action.try_execute().await?;
+        assert_eq!(
+            std::fs::read_to_string(&test_file)?,
+            "experimental-features = flakes\n"
+        );
+        assert_eq!(std::fs::metadata(&test_file)?.mode() & 0o777, 0o664);
+
+        // Drift checks expect the configured mode
+        let written = action.action.written_file.clone().unwrap();
+        assert_eq!(written.check().await?.status, DriftStatus::Unchanged);
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o644)).await?;
+        assert_eq!(
+            written.check().await?.status,
+            DriftStatus::PermissionsChanged
+        );
+        Ok(())
+    }
 }
@@ -1,4 +1,5 @@
 use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
 
 use nix::unistd::User;
 use target_lexicon::OperatingSystem;
@@ -7,14 +8,63 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::util::{host_path, which};
+use crate::warning::{self, WarningKind};
 
-use crate::action::{Action, ActionDescription, StatefulAction};
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
 
 static WARNED_USER_HIDDEN: std::sync::atomic::AtomicBool =
     std::sync::atomic::AtomicBool::new(false);
 
+/**
+The login shell and home directory of a user created by [`CreateUser`]
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct UserShellAndHome {
+    /// The login shell, which must exist when planning unless it is the default
+    pub shell: PathBuf,
+    /// The home directory
+    pub home: PathBuf,
+    /// If the home directory should be created
+    pub create_home: bool,
+}
+
+impl Default for UserShellAndHome {
+    fn default() -> Self {
+        Self {
+            shell: PathBuf::from("/sbin/nologin"),
+            home: PathBuf::from("/var/empty"),
+            create_home: false,
+        }
+    }
+}
+
+/// The usual non-login shells, in the order the default is picked from
+const NOLOGIN_SHELLS: &[&str] = &["/sbin/nologin", "/usr/sbin/nologin", "/usr/bin/false"];
+
+impl UserShellAndHome {
+    /// The first of the usual non-login shells which exists, not every distribution has
+    /// `/sbin/nologin`, such as those without a merged `/usr`
+    pub(crate) fn default_shell() -> PathBuf {
+        let fallback = Self::default().shell;
+        if crate::cross_target::is_active() {
+            return fallback;
+        }
+        NOLOGIN_SHELLS
+            .iter()
+            .map(PathBuf::from)
+            .find(|shell| host_path(shell).exists())
+            .unwrap_or(fallback)
+    }
+}
+
 /**
 Create an operating system level user in the given group
+
+If the user already exists with a different shell or home directory, they are updated instead.
+With `--adopt-existing-build-users`, a user which already exists in the group is adopted with
+whatever UID it has, and is never deleted.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_user")]
@@ -24,6 +74,16 @@ pub struct CreateUser {
     pub(crate) groupname: String,
     pub(crate) gid: u32,
     comment: String,
+    #[serde(flatten)]
+    pub(crate) shell_and_home: UserShellAndHome,
+    #[serde(default)]
+    pub(crate) update_existing: bool,
+    /// If reverting left the user in place, so a later uninstall retries deleting them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) deletion_skipped: bool,
+    /// The user already existed and was adopted, so it is left in place on revert
+    #[serde(default)]
+    pub(crate) adopted: bool,
 }
 
 impl CreateUser {
@@ -34,28 +94,44 @@ impl CreateUser {
         groupname: String,
         gid: u32,
         comment: String,
+        shell_and_home: UserShellAndHome,
         check_completed: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let this = Self {
+        let mut this = Self {
             name: name.clone(),
             uid,
             groupname,
             gid,
             comment,
+            shell_and_home,
+            update_existing: false,
+            deletion_skipped: false,
+            adopted: false,
         };
 
-        match OperatingSystem::host() {
+        match crate::cross_target::operating_system() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("useradd").is_ok() || which::which("adduser").is_ok()) {
+                if !(which("useradd").is_ok() || which("adduser").is_ok()) {
                     return Err(Self::error(ActionErrorKind::MissingUserCreationCommand));
                 }
-                if !(which::which("userdel").is_ok() || which::which("deluser").is_ok()) {
+                if !(which("userdel").is_ok() || which("deluser").is_ok()) {
                     return Err(Self::error(ActionErrorKind::MissingUserDeletionCommand));
                 }
             },
         }
 
+        // Only a shell which was asked for must exist, the default is swapped for a non-login
+        // shell the host has, and used unchecked if it has none of them
+        let shell = &this.shell_and_home.shell;
+        if *shell == UserShellAndHome::default().shell {
+            this.shell_and_home.shell = UserShellAndHome::default_shell();
+        } else if !host_path(shell).exists() {
+            return Err(Self::error(ActionErrorKind::MissingUserShell(
+                shell.clone(),
+            )));
+        }
+
         if check_completed {
             // Ensure user does not exist
             if let Some(user) = User::from_name(name.as_str())
@@ -78,6 +154,23 @@ impl CreateUser {
                     )));
                 }
 
+                if user.shell != this.shell_and_home.shell || user.dir != this.shell_and_home.home {
+                    if !matches!(
+                        crate::cross_target::operating_system(),
+                        OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin
+                    ) && which("usermod").is_err()
+                    {
+                        return Err(Self::error(ActionErrorKind::MissingUserModificationCommand));
+                    }
+
+                    tracing::debug!(
+                        "User `{}` exists with a different shell or home directory, will update",
+                        this.name
+                    );
+                    this.update_existing = true;
+                    return Ok(StatefulAction::uncompleted(this));
+                }
+
                 tracing::debug!("Creating user `{}` already complete", this.name);
                 return Ok(StatefulAction::completed(this));
             }
@@ -85,6 +178,141 @@ impl CreateUser {
 
         Ok(StatefulAction::uncompleted(this))
     }
+
+    /// Like [`plan`](Self::plan), but adopting the user if it already exists, whatever its UID
+    ///
+    /// An adopted user must already have `gid` as its primary group, its shell and home directory
+    /// are left as they are.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan_adopting(
+        name: String,
+        uid: u32,
+        groupname: String,
+        gid: u32,
+        comment: String,
+        shell_and_home: UserShellAndHome,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let Some(user) = User::from_name(name.as_str())
+            .map_err(|e| ActionErrorKind::GettingUserId(name.clone(), e))
+            .map_err(Self::error)?
+        else {
+            return Self::plan(name, uid, groupname, gid, comment, shell_and_home, true).await;
+        };
+        if user.gid.as_raw() != gid {
+            return Err(Self::error(CreateUserError::NotInGroup {
+                user: name,
+                gid: user.gid.as_raw(),
+                group: groupname,
+                expected_gid: gid,
+            }));
+        }
+
+        let existing_uid = user.uid.as_raw();
+        if existing_uid != uid {
+            tracing::debug!("Adopting user `{name}` with its UID {existing_uid} rather than {uid}");
+        }
+        Ok(StatefulAction::completed(Self {
+            name,
+            uid: existing_uid,
+            groupname,
+            gid,
+            comment,
+            shell_and_home,
+            update_existing: false,
+            deletion_skipped: false,
+            adopted: true,
+        }))
+    }
+
+    fn useradd_args(&self) -> Vec<String> {
+        let Self {
+            name,
+            uid,
+            gid,
+            comment,
+            shell_and_home,
+            ..
+        } = self;
+
+        vec![
+            "--home-dir".into(),
+            shell_and_home.home.display().to_string(),
+            if shell_and_home.create_home {
+                "--create-home".into()
+            } else {
+                "--no-create-home".into()
+            },
+            "--comment".into(),
+            comment.clone(),
+            "--gid".into(),
+            gid.to_string(),
+            "--groups".into(),
+            gid.to_string(),
+            "--no-user-group".into(),
+            "--system".into(),
+            "--shell".into(),
+            shell_and_home.shell.display().to_string(),
+            "--uid".into(),
+            uid.to_string(),
+            "--password".into(),
+            "!".into(),
+            name.clone(),
+        ]
+    }
+
+    fn adduser_args(&self) -> Vec<String> {
+        let Self {
+            name,
+            uid,
+            groupname,
+            comment,
+            shell_and_home,
+            ..
+        } = self;
+
+        let mut args = vec!["--home".into(), shell_and_home.home.display().to_string()];
+        if !shell_and_home.create_home {
+            args.push("-H".into()); // Don't create a home.
+        }
+        args.extend([
+            "--gecos".into(),
+            comment.clone(),
+            "--ingroup".into(),
+            groupname.clone(),
+            "--system".into(),
+            "--shell".into(),
+            shell_and_home.shell.display().to_string(),
+            "--uid".into(),
+            uid.to_string(),
+            "--disabled-password".into(),
+            name.clone(),
+        ]);
+        args
+    }
+
+    fn usermod_args(&self) -> Vec<String> {
+        vec![
+            "--shell".into(),
+            self.shell_and_home.shell.display().to_string(),
+            "--home".into(),
+            self.shell_and_home.home.display().to_string(),
+            self.name.clone(),
+        ]
+    }
+
+    /// The `dscl` attributes of the user, in the order they are set
+    fn dscl_attributes(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("UniqueID", self.uid.to_string()),
+            ("PrimaryGroupID", self.gid.to_string()),
+            (
+                "NFSHomeDirectory",
+                self.shell_and_home.home.display().to_string(),
+            ),
+            ("UserShell", self.shell_and_home.shell.display().to_string()),
+            ("RealName", self.name.clone()),
+        ]
+    }
 }
 
 #[async_trait::async_trait]
@@ -94,10 +322,20 @@ impl Action for CreateUser {
         ActionTag("create_user")
     }
     fn tracing_synopsis(&self) -> String {
-        format!(
-            "Create user `{}` (UID {}) in group `{}` (GID {})",
-            self.name, self.uid, self.groupname, self.gid
-        )
+        if self.update_existing {
+            format!(
+                "Update user `{}` (UID {}) to use shell `{}` and home `{}`",
+                self.name,
+                self.uid,
+                self.shell_and_home.shell.display(),
+                self.shell_and_home.home.display(),
+            )
+        } else {
+            format!(
+                "Create user `{}` (UID {}) in group `{}` (GID {})",
+                self.name, self.uid, self.groupname, self.gid
+            )
+        }
     }
 
     fn tracing_span(&self) -> Span {
@@ -108,10 +346,20 @@ impl Action for CreateUser {
             uid = self.uid,
             groupname = self.groupname,
             gid = self.gid,
+            shell = %self.shell_and_home.shell.display(),
+            home = %self.shell_and_home.home.display(),
         )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
+        if self.adopted {
+            return vec![ActionDescription::new(
+                format!("Use the existing user `{}` (UID {})", self.name, self.uid),
+                vec![format!(
+                    "The Nix daemon requires system users it can act as in order to build"
+                )],
+            )];
+        }
         vec![ActionDescription::new(
             self.tracing_synopsis(),
             vec![format!(
@@ -120,77 +368,81 @@ impl Action for CreateUser {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        if self.adopted {
+            return vec![];
+        }
+        match OperatingSystem::host() {
+            OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
+                let user = format!("/Users/{}", self.name);
+                let mut operations = vec![PrivilegedOperation::command(
+                    "/usr/bin/dscl",
+                    [".", "-create", user.as_str()],
+                )];
+                operations.extend(
+                    self.dscl_attributes()
+                        .into_iter()
+                        .chain([("IsHidden", "1".to_string())])
+                        .map(|(key, value)| {
+                            PrivilegedOperation::command(
+                                "/usr/bin/dscl",
+                                [".", "-create", user.as_str(), key, value.as_str()],
+                            )
+                        }),
+                );
+                if self.shell_and_home.create_home {
+                    operations.push(PrivilegedOperation::command(
+                        "/usr/sbin/createhomedir",
+                        ["-c", "-u", self.name.as_str()],
+                    ));
+                }
+                operations
+            },
+            _ if self.update_existing => {
+                vec![PrivilegedOperation::command("usermod", self.usermod_args())]
+            },
+            _ if which("useradd").is_err() && which("adduser").is_ok() => {
+                vec![PrivilegedOperation::command("adduser", self.adduser_args())]
+            },
+            _ => vec![PrivilegedOperation::command("useradd", self.useradd_args())],
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self {
-            name,
-            uid,
-            groupname,
-            gid,
-            comment,
-        } = self;
-
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
-                create_user_macos(name, *uid, *gid)
-                    .await
-                    .map_err(Self::error)?;
+                // `dscl . -create` is create-or-update, so existing users are updated the same way
+                create_user_macos(
+                    &self.name,
+                    &self.dscl_attributes(),
+                    self.shell_and_home.create_home,
+                )
+                .await
+                .map_err(Self::error)?;
             },
             _ => {
-                if which::which("useradd").is_ok() {
-                    execute_command(
-                        Command::new("useradd")
-                            .process_group(0)
-                            .args([
-                                "--home-dir",
-                                "/var/empty",
-                                "--comment",
-                                comment,
-                                "--gid",
-                                &gid.to_string(),
-                                "--groups",
-                                &gid.to_string(),
-                                "--no-user-group",
-                                "--system",
-                                "--shell",
-                                "/sbin/nologin",
-                                "--uid",
-                                &uid.to_string(),
-                                "--password",
-                                "!",
-                                name,
-                            ])
-                            .stdin(std::process::Stdio::null()),
-                    )
-                    .await
-                    .map_err(Self::error)?;
-                } else if which::which("adduser").is_ok() {
-                    execute_command(
-                        Command::new("adduser")
-                            .process_group(0)
-                            .args([
-                                "--home",
-                                "/var/empty",
-                                "-H", // Don't create a home.
-                                "--gecos",
-                                comment,
-                                "--ingroup",
-                                groupname,
-                                "--system",
-                                "--shell",
-                                "/sbin/nologin",
-                                "--uid",
-                                &uid.to_string(),
-                                "--disabled-password",
-                                name,
-                            ])
-                            .stdin(std::process::Stdio::null()),
-                    )
-                    .await
-                    .map_err(Self::error)?;
+                let (program, args) = if self.update_existing {
+                    if which("usermod").is_err() {
+                        return Err(Self::error(ActionErrorKind::MissingUserModificationCommand));
+                    }
+                    ("usermod", self.usermod_args())
+                } else if which("useradd").is_ok() {
+                    ("useradd", self.useradd_args())
+                } else if which("adduser").is_ok() {
+                    ("adduser", self.adduser_args())
                 } else {
                     return Err(Self::error(ActionErrorKind::MissingUserCreationCommand));
-                }
+                };
+
+                execute_command(
+                    Command::new(program)
+                        .process_group(0)
+                        .args(args)
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map_err(Self::error)?;
             },
         }
 
@@ -198,6 +450,9 @@ impl Action for CreateUser {
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
+        if self.adopted {
+            return vec![];
+        }
         vec![ActionDescription::new(
             format!(
                 "Delete user `{}` (UID {}) in group {} (GID {})",
@@ -209,14 +464,23 @@ impl Action for CreateUser {
         )]
     }
 
+    fn revert_incomplete(&self) -> bool {
+        self.deletion_skipped
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
+        if self.adopted {
+            tracing::debug!("Leaving the adopted user `{}` in place", self.name);
+            return Ok(());
+        }
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
-                delete_user_macos(&self.name).await.map_err(Self::error)?;
+                let deletion = delete_user_macos(&self.name).await.map_err(Self::error)?;
+                self.deletion_skipped = deletion == UserDeletion::Skipped;
             },
             _ => {
-                if which::which("userdel").is_ok() {
+                if which("userdel").is_ok() {
                     execute_command(
                         Command::new("userdel")
                             .process_group(0)
@@ -225,7 +489,7 @@ impl Action for CreateUser {
                     )
                     .await
                     .map_err(Self::error)?;
-                } else if which::which("deluser").is_ok() {
+                } else if which("deluser").is_ok() {
                     execute_command(
                         Command::new("deluser")
                             .process_group(0)
@@ -292,49 +556,23 @@ async fn execute_dscl_retry_on_specific_errors(dscl_args: &[&str]) -> Result<(),
 }
 
 #[tracing::instrument(level = "debug", skip_all)]
-async fn create_user_macos(name: &str, uid: u32, gid: u32) -> Result<(), ActionErrorKind> {
+async fn create_user_macos(
+    name: &str,
+    attributes: &[(&str, String)],
+    create_home: bool,
+) -> Result<(), ActionErrorKind> {
     execute_dscl_retry_on_specific_errors(&[".", "-create", &format!("/Users/{name}")]).await?;
 
-    execute_dscl_retry_on_specific_errors(&[
-        ".",
-        "-create",
-        &format!("/Users/{name}"),
-        "UniqueID",
-        &format!("{uid}"),
-    ])
-    .await?;
-    execute_dscl_retry_on_specific_errors(&[
-        ".",
-        "-create",
-        &format!("/Users/{name}"),
-        "PrimaryGroupID",
-        &format!("{gid}"),
-    ])
-    .await?;
-    execute_dscl_retry_on_specific_errors(&[
-        ".",
-        "-create",
-        &format!("/Users/{name}"),
-        "NFSHomeDirectory",
-        "/var/empty",
-    ])
-    .await?;
-    execute_dscl_retry_on_specific_errors(&[
-        ".",
-        "-create",
-        &format!("/Users/{name}"),
-        "UserShell",
-        "/sbin/nologin",
-    ])
-    .await?;
-    execute_dscl_retry_on_specific_errors(&[
-        ".",
-        "-create",
-        &format!("/Users/{name}"),
-        "RealName",
-        name,
-    ])
-    .await?;
+    for (key, value) in attributes {
+        execute_dscl_retry_on_specific_errors(&[
+            ".",
+            "-create",
+            &format!("/Users/{name}"),
+            key,
+            value,
+        ])
+        .await?;
+    }
     execute_dscl_retry_on_specific_errors(&[
         ".",
         "-create",
@@ -347,7 +585,7 @@ async fn create_user_macos(name: &str, uid: u32, gid: u32) -> Result<(), ActionE
         if let ActionErrorKind::CommandOutput { ref output, .. } = e {
             if output.status.signal() == Some(9) {
                 if !WARNED_USER_HIDDEN.swap(true, std::sync::atomic::Ordering::SeqCst) {
-                    tracing::warn!("Failed to automatically mark nixbld users as hidden. See: https://dtr.mn/mark-user-hidden");
+                    warning::warn(WarningKind::UsersNotHidden, "Failed to automatically mark nixbld users as hidden. See: https://dtr.mn/mark-user-hidden");
                 }
                 return Ok(())
             }
@@ -356,11 +594,59 @@ async fn create_user_macos(name: &str, uid: u32, gid: u32) -> Result<(), ActionE
         Err(e)
     })?;
 
+    if create_home {
+        execute_command(
+            Command::new("/usr/sbin/createhomedir")
+                .process_group(0)
+                .args(["-c", "-u", name])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await?;
+    }
+
     Ok(())
 }
 
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateUserError {
+    #[error("The user `{user}` has the primary group GID {gid} rather than `{group}` (GID {expected_gid}), so it can't be adopted as a Nix build user")]
+    NotInGroup {
+        user: String,
+        gid: u32,
+        group: String,
+        expected_gid: u32,
+    },
+}
+
+impl From<CreateUserError> for ActionErrorKind {
+    fn from(val: CreateUserError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+/// What [`delete_user_macos`] did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserDeletion {
+    Deleted,
+    /// The user could not be deleted without an administrator, a [`Warning`](crate::warning::Warning) explains how
+    Skipped,
+}
+
 #[tracing::instrument(level = "debug", skip_all)]
-pub async fn delete_user_macos(name: &str) -> Result<(), ActionErrorKind> {
+pub async fn delete_user_macos(name: &str) -> Result<UserDeletion, ActionErrorKind> {
+    // A user holding a Secure Token (which managed Macs sometimes give build users) can only be
+    // deleted by an administrator who also holds one, `dscl` fails for them
+    if secure_token_status(name).await == Some(true) {
+        warning::warn(
+            WarningKind::SecureTokenHolder,
+            format!(
+                "User `{name}` holds a Secure Token, so it was not deleted. To delete it, run `sudo sysadminctl -deleteUser {name} -adminUser <admin> -adminPassword -`, where `<admin>` is an administrator who also holds a Secure Token (see `sysadminctl -secureTokenStatus <admin>`)"
+            ),
+        );
+        return Ok(UserDeletion::Skipped);
+    }
+
     // MacOS is a "Special" case
     // It's only possible to delete users under certain conditions.
     // Documentation on https://it.megocollector.com/macos/cant-delete-a-macos-user-with-dscl-resolution/ and http://www.aixperts.co.uk/?p=214 suggested it was a secure token
@@ -370,8 +656,7 @@ pub async fn delete_user_macos(name: &str) -> Result<(), ActionErrorKind> {
     command.args([".", "-delete", &format!("/Users/{}", name)]);
     command.stdin(std::process::Stdio::null());
 
-    let output = command
-        .output()
+    let output = crate::command_output(&mut command)
         .await
         .map_err(|e| ActionErrorKind::command(&command, e))?;
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -380,7 +665,8 @@ pub async fn delete_user_macos(name: &str) -> Result<(), ActionErrorKind> {
         Some(40) if stderr.contains("-14120") => {
             // The user is on an ephemeral Mac, like detsys uses
             // These Macs cannot always delete users, as sometimes there is no graphical login
-            tracing::warn!("Encountered an exit code 40 with -14120 error while removing user, this is likely because the initial executing user did not have a secure token, or that there was no graphical login session. To delete the user, log in graphically, then run `/usr/bin/dscl . -delete /Users/{}`", name);
+            warning::warn(WarningKind::UserNotDeleted, format!("Encountered an exit code 40 with -14120 error while removing user, this is likely because the initial executing user did not have a secure token, or that there was no graphical login session. To delete the user, log in graphically, then run `/usr/bin/dscl . -delete /Users/{name}`"));
+            return Ok(UserDeletion::Skipped);
         },
         Some(185) if stderr.contains("-14009 (eDSUnknownNodeName)") => {
             // The user has already been deleted
@@ -392,5 +678,248 @@ pub async fn delete_user_macos(name: &str) -> Result<(), ActionErrorKind> {
         },
     }
 
-    Ok(())
+    Ok(UserDeletion::Deleted)
+}
+
+/// If `name` holds a Secure Token, `None` if `sysadminctl` could not tell
+async fn secure_token_status(name: &str) -> Option<bool> {
+    let mut command = Command::new("/usr/sbin/sysadminctl");
+    command.process_group(0);
+    command.args(["-secureTokenStatus", name]);
+    command.stdin(std::process::Stdio::null());
+    let output = match crate::command_output(&mut command).await {
+        Ok(output) => output,
+        Err(e) => {
+            tracing::debug!(user = %name, "Checking for a Secure Token: {e}");
+            return None;
+        },
+    };
+    // `sysadminctl` logs its answer, to stderr
+    let status = parse_secure_token_status(&String::from_utf8_lossy(&output.stderr))
+        .or_else(|| parse_secure_token_status(&String::from_utf8_lossy(&output.stdout)));
+    if status.is_none() {
+        tracing::debug!(user = %name, "Could not tell if the user holds a Secure Token");
+    }
+    status
+}
+
+/// Parse the output of `sysadminctl -secureTokenStatus`, like `... Secure token is ENABLED for user _nixbld1`
+fn parse_secure_token_status(output: &str) -> Option<bool> {
+    output.lines().find_map(|line| {
+        let (_, status) = line.split_once("Secure token is ")?;
+        match status.split_whitespace().next()? {
+            "ENABLED" => Some(true),
+            "DISABLED" => Some(false),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create_user(shell_and_home: UserShellAndHome) -> CreateUser {
+        CreateUser {
+            name: "nixbld1".into(),
+            uid: 30001,
+            groupname: "nixbld".into(),
+            gid: 30000,
+            comment: "Nix build user 1".into(),
+            shell_and_home,
+            update_existing: false,
+            deletion_skipped: false,
+            adopted: false,
+        }
+    }
+
+    fn custom() -> UserShellAndHome {
+        UserShellAndHome {
+            shell: "/usr/sbin/nologin".into(),
+            home: "/var/lib/nixbld".into(),
+            create_home: true,
+        }
+    }
+
+    #[test]
+    fn useradd_args() {
+        let args = create_user(UserShellAndHome::default()).useradd_args();
+        assert_eq!(args[..3], ["--home-dir", "/var/empty", "--no-create-home"]);
+        assert!(args.windows(2).any(|w| w == ["--shell", "/sbin/nologin"]));
+
+        let args = create_user(custom()).useradd_args();
+        assert_eq!(
+            args[..3],
+            ["--home-dir", "/var/lib/nixbld", "--create-home"]
+        );
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--shell", "/usr/sbin/nologin"]));
+        assert_eq!(args.last().map(String::as_str), Some("nixbld1"));
+    }
+
+    #[test]
+    fn adduser_args() {
+        let args = create_user(UserShellAndHome::default()).adduser_args();
+        assert_eq!(args[..3], ["--home", "/var/empty", "-H"]);
+        assert!(args.windows(2).any(|w| w == ["--shell", "/sbin/nologin"]));
+
+        let args = create_user(custom()).adduser_args();
+        assert_eq!(args[..3], ["--home", "/var/lib/nixbld", "--gecos"]);
+        assert!(!args.contains(&"-H".to_string()));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["--shell", "/usr/sbin/nologin"]));
+    }
+
+    #[test]
+    fn usermod_args() {
+        assert_eq!(
+            create_user(custom()).usermod_args(),
+            [
+                "--shell",
+                "/usr/sbin/nologin",
+                "--home",
+                "/var/lib/nixbld",
+                "nixbld1"
+            ]
+        );
+    }
+
+    #[test]
+    fn dscl_attributes() {
+        let attributes = create_user(custom()).dscl_attributes();
+        assert!(attributes.contains(&("UserShell", "/usr/sbin/nologin".into())));
+        assert!(attributes.contains(&("NFSHomeDirectory", "/var/lib/nixbld".into())));
+    }
+
+    #[test]
+    fn deserializes_receipt_without_shell_and_home() {
+        let create_user: CreateUser = serde_json::from_value(serde_json::json!({
+            "action_name": "create_user",
+            "name": "nixbld1",
+            "uid": 30001,
+            "groupname": "nixbld",
+            "gid": 30000,
+            "comment": "Nix build user 1",
+        }))
+        .unwrap();
+        assert_eq!(create_user.shell_and_home, UserShellAndHome::default());
+        assert!(!create_user.update_existing);
+    }
+
+    #[test]
+    fn parses_secure_token_status() {
+        assert_eq!(
+            parse_secure_token_status(
+                "2024-05-02 10:11:12.345 sysadminctl[1234:5678] Secure token is ENABLED for user _nixbld3\n"
+            ),
+            Some(true)
+        );
+        assert_eq!(
+            parse_secure_token_status(
+                "2024-05-02 10:11:12.345 sysadminctl[1234:5678] Secure token is DISABLED for user _nixbld1\n"
+            ),
+            Some(false)
+        );
+        assert_eq!(
+            parse_secure_token_status(
+                "2024-05-02 10:11:12.345 sysadminctl[1234:5678] ### Error:-14090 File:/AppleInternal/Library/BuildRoots/sysadminctl.m Line:1520\n"
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn secure_token_holders_are_skipped() -> eyre::Result<()> {
+        let sandbox = crate::test_harness::SandboxContext::new()?;
+        sandbox.fake_once(
+            "sysadminctl",
+            crate::test_harness::FakeCommand::success().stderr(
+                "2024-05-02 10:11:12.345 sysadminctl[1234:5678] Secure token is ENABLED for user _nixbld3\n",
+            ),
+        );
+        sandbox.fake_once(
+            "sysadminctl",
+            crate::test_harness::FakeCommand::success().stderr(
+                "2024-05-02 10:11:12.345 sysadminctl[1234:5678] Secure token is DISABLED for user _nixbld4\n",
+            ),
+        );
+        sandbox.fake("dscl", crate::test_harness::FakeCommand::success());
+
+        let ((skipped, deleted), warnings) = sandbox
+            .scope(crate::warning::collect(async {
+                (
+                    delete_user_macos("_nixbld3").await,
+                    delete_user_macos("_nixbld4").await,
+                )
+            }))
+            .await;
+        assert_eq!(skipped?, UserDeletion::Skipped);
+        assert_eq!(deleted?, UserDeletion::Deleted);
+        assert_eq!(
+            sandbox.invocations_of("dscl"),
+            [crate::test_harness::Invocation::new(
+                "dscl",
+                [".", "-delete", "/Users/_nixbld4"]
+            )]
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::SecureTokenHolder);
+        assert!(warnings[0]
+            .message
+            .contains("sysadminctl -deleteUser _nixbld3"));
+
+        // A user left in place is recorded, so a later uninstall retries deleting them
+        let mut skipped_user = create_user(UserShellAndHome::default());
+        skipped_user.deletion_skipped = true;
+        let recorded = serde_json::to_value(&skipped_user)?;
+        assert_eq!(recorded["deletion_skipped"], true);
+        let skipped_user: CreateUser = serde_json::from_value(recorded)?;
+        assert!(skipped_user.revert_incomplete());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn existing_users_are_adopted() -> eyre::Result<()> {
+        let sandbox = crate::test_harness::SandboxContext::new()?;
+        let adopt = |name: &str, groupname: &str, gid: u32| {
+            sandbox.scope(CreateUser::plan_adopting(
+                name.into(),
+                39001,
+                groupname.into(),
+                gid,
+                "Nix build user 1".into(),
+                UserShellAndHome::default(),
+            ))
+        };
+
+        // `root` exists on every host, in the group with GID 0
+        let adopted = adopt("root", "root", 0).await?;
+        assert_eq!(adopted.state, crate::action::ActionState::Completed);
+        assert!(adopted.inner().adopted);
+        assert_eq!(adopted.inner().uid, 0, "The actual UID is recorded");
+        assert!(adopted.privileged_operations().is_empty());
+
+        let receipt = serde_json::to_string(&adopted)?;
+        let mut actions =
+            vec![serde_json::from_str::<StatefulAction<CreateUser>>(&receipt)?.boxed()];
+        sandbox.revert(&mut actions).await?;
+        assert!(sandbox.invocations_of("userdel").is_empty());
+        assert!(sandbox.invocations_of("deluser").is_empty());
+
+        // A user outside the build group is refused
+        assert!(matches!(
+            adopt("root", "nixbld", 39000).await,
+            Err(err) if matches!(
+                err.kind(),
+                ActionErrorKind::Custom(e) if matches!(
+                    e.downcast_ref::<CreateUserError>(),
+                    Some(CreateUserError::NotInGroup { gid: 0, .. })
+                )
+            )
+        ));
+        Ok(())
+    }
 }
@@ -7,7 +7,7 @@ use crate::action::base::create_user::delete_user_macos;
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription, StatefulAction};
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
 
 /**
 Delete an operating system level user
@@ -23,7 +23,7 @@ impl DeleteUser {
     pub async fn plan(name: String) -> Result<StatefulAction<Self>, ActionError> {
         let this = Self { name: name.clone() };
 
-        match OperatingSystem::host() {
+        match crate::cross_target::operating_system() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
                 if !(which::which("userdel").is_ok() || which::which("deluser").is_ok()) {
@@ -72,6 +72,21 @@ impl Action for DeleteUser {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        match OperatingSystem::host() {
+            OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
+                vec![PrivilegedOperation::command(
+                    "/usr/bin/dscl",
+                    [".", "-delete", &format!("/Users/{}", self.name)],
+                )]
+            },
+            _ if which::which("userdel").is_err() && which::which("deluser").is_ok() => {
+                vec![PrivilegedOperation::command("deluser", [&self.name])]
+            },
+            _ => vec![PrivilegedOperation::command("userdel", [&self.name])],
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         match OperatingSystem::host() {
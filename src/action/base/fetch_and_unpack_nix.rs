@@ -1,26 +1,48 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use bytes::{Buf, Bytes};
 use reqwest::Url;
 use tracing::{span, Span};
 
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
+    },
     parse_ssl_cert,
     settings::UrlOrPath,
     util::OnMissing,
 };
 
+/// How long to wait before the first retry of a fetch, doubling for each retry after
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest wait between retries
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
 /**
 Fetch a URL to the given path
+
+A fetch which fails from a connection failure, a timeout, a server error, or a truncated download
+is retried up to `retries` times, waiting longer each time.
+
+If `sha256` is set, the package (fetched, read from a path, or bundled) must have that SHA-256
+before anything is unpacked.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "fetch_and_unpack_nix")]
 pub struct FetchAndUnpackNix {
     url_or_path: Option<UrlOrPath>,
     dest: PathBuf,
+    /// Recorded without its credentials, which are only used by the install that planned it
+    #[serde(serialize_with = "crate::proxy::serialize_without_credentials")]
     proxy: Option<Url>,
     ssl_cert_file: Option<PathBuf>,
+    /// Absent from receipts written before fetches were retried
+    #[serde(default)]
+    retries: u32,
+    #[serde(default)]
+    sha256: Option<String>,
 }
 
 impl FetchAndUnpackNix {
@@ -30,6 +52,8 @@ impl FetchAndUnpackNix {
         dest: PathBuf,
         proxy: Option<Url>,
         ssl_cert_file: Option<PathBuf>,
+        retries: u32,
+        sha256: Option<String>,
     ) -> Result<StatefulAction<Self>, ActionError> {
         // TODO(@hoverbear): Check URL exists?
         // TODO(@hoverbear): Check tempdir exists
@@ -41,6 +65,11 @@ impl FetchAndUnpackNix {
             }
         }
 
+        // The bundled Nix was built for the platform `nix-installer` was built for
+        if url_or_path.is_none() && !crate::cross_target::runs_bundled() {
+            crate::cross_target::placeholder("nix_package_url");
+        }
+
         if let Some(proxy) = &proxy {
             match proxy.scheme() {
                 "https" | "http" | "socks5" => (),
@@ -57,6 +86,8 @@ impl FetchAndUnpackNix {
             dest,
             proxy,
             ssl_cert_file,
+            retries,
+            sha256,
         }
         .into())
     }
@@ -89,7 +120,10 @@ impl Action for FetchAndUnpackNix {
             dest = tracing::field::display(self.dest.display()),
         );
         if let Some(proxy) = &self.proxy {
-            span.record("proxy", tracing::field::display(&proxy));
+            span.record(
+                "proxy",
+                tracing::field::display(crate::proxy::without_credentials(proxy)),
+            );
         }
         if let Some(ssl_cert_file) = &self.ssl_cert_file {
             span.record(
@@ -101,7 +135,18 @@ impl Action for FetchAndUnpackNix {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        let mut explanation = vec![];
+        if let Some(sha256) = &self.sha256 {
+            explanation.push(format!("Check that its SHA-256 is `{sha256}`"));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![
+            PrivilegedOperation::remove(&self.dest),
+            PrivilegedOperation::write(&self.dest, None),
+        ]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
@@ -111,36 +156,17 @@ impl Action for FetchAndUnpackNix {
             Some(UrlOrPath::Url(url)) => {
                 let bytes = match url.scheme() {
                     "https" | "http" => {
-                        let mut buildable_client = reqwest::Client::builder();
-                        if let Some(proxy) = &self.proxy {
-                            buildable_client = buildable_client.proxy(
-                                reqwest::Proxy::all(proxy.clone())
-                                    .map_err(ActionErrorKind::Reqwest)
-                                    .map_err(Self::error)?,
-                            )
-                        }
-                        if let Some(ssl_cert_file) = &self.ssl_cert_file {
-                            let ssl_cert =
-                                parse_ssl_cert(ssl_cert_file).await.map_err(Self::error)?;
-                            buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                        }
-                        let client = buildable_client
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        let req = client
-                            .get(url.clone())
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        let res = client
-                            .execute(req)
+                        let client = crate::http_client_builder(
+                            self.proxy.as_ref(),
+                            self.ssl_cert_file.as_deref(),
+                        )
+                        .await
+                        .map_err(Self::error)?
+                        .build()
+                        .map_err(ActionErrorKind::Reqwest)
+                        .map_err(Self::error)?;
+                        fetch_with_retries(&client, url, self.retries, RETRY_BACKOFF)
                             .await
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        res.bytes()
-                            .await
-                            .map_err(ActionErrorKind::Reqwest)
                             .map_err(Self::error)?
                     },
                     "file" => {
@@ -163,6 +189,10 @@ impl Action for FetchAndUnpackNix {
             },
         };
 
+        if let Some(expected) = &self.sha256 {
+            verify_sha256(&bytes, expected).map_err(Self::error)?;
+        }
+
         // TODO(@Hoverbear): Pick directory
         tracing::trace!("Unpacking tar.xz");
 
@@ -198,17 +228,382 @@ impl Action for FetchAndUnpackNix {
     }
 }
 
+/// Fail unless `bytes` has the SHA-256 `expected`
+fn verify_sha256(bytes: &[u8], expected: &str) -> Result<(), ActionErrorKind> {
+    let actual = crate::backup::sha256(bytes);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(ActionErrorKind::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    tracing::debug!(sha256 = %actual, "Verified the checksum of the Nix package");
+    Ok(())
+}
+
+/// Fetch `url`, trying again up to `retries` times after a transient failure
+///
+/// Each attempt starts a new request, so a partial download is never kept.
+async fn fetch_with_retries(
+    client: &reqwest::Client,
+    url: &Url,
+    retries: u32,
+    backoff: Duration,
+) -> Result<Bytes, FetchUrlError> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match fetch(client, url).await {
+            Ok(bytes) => return Ok(bytes),
+            Err(e) if e.is_transient() && attempts <= retries => {
+                let wait = backoff
+                    .saturating_mul(2u32.saturating_pow(attempts - 1))
+                    .min(MAX_RETRY_BACKOFF);
+                tracing::warn!(
+                    "Fetching `{url}` failed ({e}), retrying in {}s (attempt {attempts} of {})",
+                    wait.as_secs_f32(),
+                    retries + 1
+                );
+                tokio::time::sleep(wait).await;
+            },
+            Err(source) => {
+                return Err(FetchUrlError::Fetch {
+                    url: url.clone(),
+                    attempts,
+                    source,
+                })
+            },
+        }
+    }
+}
+
+/// Fetch `url` once, failing if the body is shorter than the server said it would be
+async fn fetch(client: &reqwest::Client, url: &Url) -> Result<Bytes, FetchAttemptError> {
+    let res = client.get(url.clone()).send().await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(FetchAttemptError::Status(status));
+    }
+    let expected = res.content_length();
+    let bytes = res.bytes().await?;
+    match expected {
+        Some(expected) if bytes.len() as u64 != expected => Err(FetchAttemptError::Truncated {
+            expected,
+            received: bytes.len() as u64,
+        }),
+        _ => Ok(bytes),
+    }
+}
+
+/// Why a single attempt at fetching failed
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum FetchAttemptError {
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error("The server responded with `{0}`")]
+    Status(reqwest::StatusCode),
+    #[error("The download ended after {received} of {expected} bytes")]
+    Truncated { expected: u64, received: u64 },
+}
+
+impl FetchAttemptError {
+    /// If trying again could succeed, like after a dropped connection rather than a missing file
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Reqwest(e) if crate::proxy::rejected_credentials(e) => false,
+            // Nothing is decompressed, so decoding only fails when reading the body does, like on a dropped connection
+            Self::Reqwest(e) => {
+                e.is_connect() || e.is_timeout() || e.is_request() || e.is_body() || e.is_decode()
+            },
+            Self::Status(status) => status.is_server_error(),
+            Self::Truncated { .. } => true,
+        }
+    }
+
+    /// If the proxy refused the request for lacking valid credentials
+    fn rejected_by_proxy(&self) -> bool {
+        match self {
+            Self::Reqwest(e) => crate::proxy::rejected_credentials(e),
+            Self::Status(status) => *status == reqwest::StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            Self::Truncated { .. } => false,
+        }
+    }
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum FetchUrlError {
     #[error("Unarchiving error")]
     Unarchive(#[source] std::io::Error),
+    #[error("Fetching `{url}` failed after {attempts} attempt(s)")]
+    Fetch {
+        url: Url,
+        attempts: u32,
+        #[source]
+        source: FetchAttemptError,
+    },
     #[error("Unknown proxy scheme, `https://`, `socks5://`, and `http://` supported")]
     UnknownProxyScheme,
 }
 
 impl From<FetchUrlError> for ActionErrorKind {
     fn from(val: FetchUrlError) -> Self {
-        ActionErrorKind::Custom(Box::new(val))
+        match val {
+            FetchUrlError::Fetch { url, source, .. } if source.rejected_by_proxy() => {
+                ActionErrorKind::ProxyRejectedCredentials(url)
+            },
+            val => ActionErrorKind::Custom(Box::new(val)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use reqwest::Url;
+
+    use super::{fetch_with_retries, FetchAndUnpackNix, FetchAttemptError, FetchUrlError};
+    use crate::action::{ActionError, ActionErrorKind};
+    use crate::settings::UrlOrPath;
+    use crate::test_harness::SandboxContext;
+
+    const BODY: &str = "nix.tar.xz";
+
+    fn ok() -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{BODY}",
+            BODY.len()
+        )
+    }
+
+    fn status(status: u16) -> String {
+        format!("HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    }
+
+    /// The connection drops halfway through the body
+    fn truncated() -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            BODY.len(),
+            &BODY[..4]
+        )
+    }
+
+    /// A mirror responding with each of `responses` in turn, counting the requests
+    fn mirror(responses: Vec<String>) -> std::io::Result<(Url, Arc<AtomicUsize>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = Url::parse(&format!("http://{}/nix.tar.xz", listener.local_addr()?))
+            .expect("Mirror URL is valid");
+        let requests = Arc::new(AtomicUsize::new(0));
+        let thread_requests = requests.clone();
+        std::thread::spawn(move || {
+            let mut responses = responses.into_iter();
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let mut reader = BufReader::new(stream.try_clone().expect("Cloning stream"));
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+                }
+                thread_requests.fetch_add(1, Ordering::SeqCst);
+                let response = responses.next().unwrap_or_else(|| status(410));
+                stream.write_all(response.as_bytes()).ok();
+            }
+        });
+        Ok((url, requests))
+    }
+
+    /// A `.tar.xz` holding a single `nix-2.24.0/README`
+    fn tarball() -> eyre::Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(vec![], 6));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(BODY.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "nix-2.24.0/README", BODY.as_bytes())?;
+        Ok(builder.into_inner()?.finish()?)
+    }
+
+    async fn fetch_and_unpack(
+        source: UrlOrPath,
+        dest: std::path::PathBuf,
+        sha256: &str,
+    ) -> Result<(), ActionError> {
+        FetchAndUnpackNix::plan(Some(source), dest, None, None, 0, Some(sha256.into()))
+            .await?
+            .try_execute()
+            .await
+    }
+
+    #[tokio::test]
+    async fn checksum_is_verified_before_unpacking_a_path() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let tarball = tarball()?;
+        let sha256 = crate::backup::sha256(&tarball);
+        let path = sandbox.path("/tmp/nix.tar.xz");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, &tarball)?;
+
+        let dest = sandbox.path("/nix/temp-install-dir");
+        fetch_and_unpack(
+            UrlOrPath::Path(path.clone()),
+            dest.clone(),
+            &sha256.to_uppercase(),
+        )
+        .await?;
+        assert_eq!(
+            std::fs::read_to_string(dest.join("nix-2.24.0/README"))?,
+            BODY
+        );
+
+        let mismatched = sandbox.path("/nix/mismatched");
+        let err = fetch_and_unpack(UrlOrPath::Path(path), mismatched.clone(), &"0".repeat(64))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.kind(), ActionErrorKind::ChecksumMismatch { actual, .. } if *actual == sha256),
+            "{err:?}"
+        );
+        assert!(!mismatched.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checksum_is_verified_for_urls() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let tarball = tarball()?;
+        let path = sandbox.path("/mirror/nix.tar.xz");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, &tarball)?;
+        let url = Url::from_file_path(&path).expect("Sandbox paths are absolute");
+        let dest = sandbox.path("/nix/temp-install-dir");
+        fetch_and_unpack(
+            UrlOrPath::Url(url),
+            dest.clone(),
+            &crate::backup::sha256(&tarball),
+        )
+        .await?;
+        assert!(dest.join("nix-2.24.0/README").exists());
+
+        // A mirror serving something else
+        let (url, _) = mirror(vec![ok()])?;
+        let expected = crate::backup::sha256(&tarball);
+        let err = fetch_and_unpack(
+            UrlOrPath::Url(url),
+            sandbox.path("/nix/from-mirror"),
+            &expected,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(
+                err.kind(),
+                ActionErrorKind::ChecksumMismatch { expected: e, actual }
+                    if *e == expected && *actual == crate::backup::sha256(BODY.as_bytes())
+            ),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() -> eyre::Result<()> {
+        let (url, requests) = mirror(vec![status(503), truncated(), ok()])?;
+        let client = reqwest::Client::new();
+
+        let bytes = fetch_with_retries(&client, &url, 2, Duration::ZERO).await?;
+        assert_eq!(bytes, BODY.as_bytes());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_the_attempts_when_giving_up() -> eyre::Result<()> {
+        let (url, requests) = mirror(vec![status(503), status(502), status(500), ok()])?;
+        let client = reqwest::Client::new();
+
+        let err = fetch_with_retries(&client, &url, 2, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                FetchUrlError::Fetch { attempts: 3, source: FetchAttemptError::Status(status), .. } if status.as_u16() == 500
+            ),
+            "{err:?}"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_transient_failures_are_retried() -> eyre::Result<()> {
+        // Without retries, the first failure is final, like before fetches were retried
+        let (url, requests) = mirror(vec![status(503), ok()])?;
+        let client = reqwest::Client::new();
+        let err = fetch_with_retries(&client, &url, 0, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, FetchUrlError::Fetch { attempts: 1, .. }),
+            "{err:?}"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // A missing tarball will still be missing
+        let (url, requests) = mirror(vec![status(404), ok()])?;
+        let err = fetch_with_retries(&client, &url, 2, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, FetchUrlError::Fetch { attempts: 1, .. }),
+            "{err:?}"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_proxy_credentials_are_not_retried() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let (mut proxy, requests) = mirror(vec![status(407), ok()])?;
+        proxy.set_path("");
+        proxy.set_username("build").expect("Mirror URL has a host");
+        proxy
+            .set_password(Some("secret"))
+            .expect("Mirror URL has a host");
+        let url = Url::parse("http://mirror.invalid/nix.tar.xz")?;
+        let mut action = FetchAndUnpackNix::plan(
+            Some(UrlOrPath::Url(url.clone())),
+            sandbox.path("/nix/temp-install-dir"),
+            Some(proxy),
+            None,
+            2,
+            None,
+        )
+        .await?;
+
+        let recorded = serde_json::to_string(&action)?;
+        assert!(!recorded.contains("secret"), "{recorded}");
+
+        let err = action.try_execute().await.unwrap_err();
+        assert!(
+            matches!(err.kind(), ActionErrorKind::ProxyRejectedCredentials(rejected) if *rejected == url),
+            "{err:?}"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        Ok(())
     }
 }
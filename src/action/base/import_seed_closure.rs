@@ -0,0 +1,376 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::{
+    action::{
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
+    },
+    execute_command,
+    profile::DEFAULT_PROFILE,
+    warning::{self, WarningKind},
+};
+
+/**
+Import a closure into the freshly installed store, so machines built from an image don't each
+fetch it from a binary cache
+
+The source is either a file written by `nix-store --export`, imported with `nix-store --import`,
+or a directory of `.narinfo` and `.nar` files, as `nix copy --to file://` writes it, copied with
+`nix copy --from file://`. Runs once the daemon is up, and never fails the install: a seed which
+can't be imported is a warning, and the store is left as it was installed.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "import_seed_closure")]
+pub struct ImportSeedClosure {
+    source: PathBuf,
+    require_sigs: bool,
+    /// The SHA-256 of the source when planned, of its files in order for a directory
+    source_sha256: String,
+    /// How many store paths were imported, if the import succeeded
+    #[serde(default)]
+    imported: Option<usize>,
+}
+
+impl ImportSeedClosure {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        source: impl AsRef<Path>,
+        require_sigs: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let source = source.as_ref().to_path_buf();
+        let source_sha256 = source_sha256(&source).map_err(Self::error)?;
+        if source.is_dir() && store_paths(&source).map_err(Self::error)?.is_empty() {
+            return Err(Self::error(ImportSeedClosureError::NoNarinfo(source)));
+        }
+
+        Ok(Self {
+            source,
+            require_sigs,
+            source_sha256,
+            imported: None,
+        }
+        .into())
+    }
+
+    fn command(&self) -> Result<Command, ActionErrorKind> {
+        let bin = PathBuf::from(DEFAULT_PROFILE).join("bin");
+        let mut command = if self.source.is_dir() {
+            let mut command = Command::new(bin.join("nix"));
+            command
+                .args(["--extra-experimental-features", "nix-command"])
+                .arg("copy")
+                .arg("--from")
+                .arg(format!("file://{}", self.source.display()));
+            if !self.require_sigs {
+                command.arg("--no-check-sigs");
+            }
+            command
+                .args(store_paths(&self.source)?)
+                .stdin(std::process::Stdio::null());
+            command
+        } else {
+            let export = std::fs::File::open(&self.source)
+                .map_err(|e| ActionErrorKind::Open(self.source.clone(), e))?;
+            let mut command = Command::new(bin.join("nix-store"));
+            command
+                .args(["--option", "require-sigs", &self.require_sigs.to_string()])
+                .arg("--import")
+                .stdin(export);
+            command
+        };
+        command.process_group(0);
+        Ok(command)
+    }
+
+    async fn import(&self) -> Result<usize, ActionErrorKind> {
+        let output = execute_command(&mut self.command()?).await?;
+        if self.source.is_dir() {
+            Ok(store_paths(&self.source)?.len())
+        } else {
+            // `nix-store --import` prints every path it imported
+            Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .count())
+        }
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "import_seed_closure")]
+impl Action for ImportSeedClosure {
+    fn action_tag() -> ActionTag {
+        ActionTag("import_seed_closure")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Import the seed closure `{}`", self.source.display())
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "import_seed_closure",
+            source = tracing::field::display(self.source.display()),
+            require_sigs = self.require_sigs,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let how = if self.source.is_dir() {
+            "Copy the store paths of its `.narinfo` files with `nix copy`"
+        } else {
+            "Import it with `nix-store --import`"
+        };
+        let sigs = if self.require_sigs {
+            "Only paths signed by a trusted key are imported"
+        } else {
+            "Signatures are not checked"
+        };
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                format!("{how}, with SHA-256 `{}`", self.source_sha256),
+                sigs.to_string(),
+                "If it can't be imported, the install continues without it".to_string(),
+            ],
+        )]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let bin = PathBuf::from(DEFAULT_PROFILE).join("bin");
+        if self.source.is_dir() {
+            let mut args = vec![
+                "--extra-experimental-features".to_string(),
+                "nix-command".to_string(),
+                "copy".to_string(),
+                "--from".to_string(),
+                format!("file://{}", self.source.display()),
+            ];
+            if !self.require_sigs {
+                args.push("--no-check-sigs".to_string());
+            }
+            args.push("<store paths>".to_string());
+            vec![PrivilegedOperation::command(
+                bin.join("nix").display().to_string(),
+                args,
+            )]
+        } else {
+            vec![PrivilegedOperation::command(
+                bin.join("nix-store").display().to_string(),
+                [
+                    "--option".to_string(),
+                    "require-sigs".to_string(),
+                    self.require_sigs.to_string(),
+                    "--import".to_string(),
+                ],
+            )]
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        match self.import().await {
+            Ok(imported) => {
+                tracing::info!(
+                    source = %self.source.display(),
+                    imported,
+                    "Imported {imported} store paths from the seed closure"
+                );
+                self.imported = Some(imported);
+            },
+            Err(err) => {
+                warning::warn(
+                    WarningKind::SeedClosureNotImported,
+                    format!(
+                        "The seed closure `{}` could not be imported, the install continues without it: {err}",
+                        self.source.display()
+                    ),
+                );
+            },
+        }
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // The imported paths are removed along with the rest of `/nix`
+        Ok(())
+    }
+}
+
+/// The SHA-256 of a file, or of the relative path and contents of every file in a directory
+fn source_sha256(source: &Path) -> Result<String, ActionErrorKind> {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    if source.is_dir() {
+        for entry in walkdir::WalkDir::new(source).sort_by_file_name() {
+            let entry = entry
+                .map_err(|e| ActionErrorKind::ReadDir(source.into(), std::io::Error::other(e)))?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(source).unwrap_or(entry.path());
+            context.update(relative.to_string_lossy().as_bytes());
+            context.update(&[0]);
+            hash_file(&mut context, entry.path())?;
+        }
+    } else {
+        hash_file(&mut context, source)?;
+    }
+
+    Ok(context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+fn hash_file(context: &mut ring::digest::Context, path: &Path) -> Result<(), ActionErrorKind> {
+    let mut file = std::fs::File::open(path).map_err(|e| ActionErrorKind::Open(path.into(), e))?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buf)
+            .map_err(|e| ActionErrorKind::Read(path.into(), e))?;
+        if read == 0 {
+            return Ok(());
+        }
+        context.update(&buf[..read]);
+    }
+}
+
+/// The store paths the `.narinfo` files at the top of a binary cache directory describe
+fn store_paths(source: &Path) -> Result<Vec<String>, ActionErrorKind> {
+    let mut narinfos = std::fs::read_dir(source)
+        .map_err(|e| ActionErrorKind::ReadDir(source.into(), e))?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "narinfo"))
+        .collect::<Vec<_>>();
+    narinfos.sort();
+
+    let mut store_paths = vec![];
+    for narinfo in narinfos {
+        let contents = std::fs::read_to_string(&narinfo)
+            .map_err(|e| ActionErrorKind::Read(narinfo.clone(), e))?;
+        store_paths.extend(
+            contents
+                .lines()
+                .filter_map(|line| line.strip_prefix("StorePath:"))
+                .map(|store_path| store_path.trim().to_string()),
+        );
+    }
+    Ok(store_paths)
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ImportSeedClosureError {
+    #[error("`{0}` has no `.narinfo` files, a seed closure directory must be a binary cache written with `nix copy --to file://`")]
+    NoNarinfo(PathBuf),
+}
+
+impl From<ImportSeedClosureError> for ActionErrorKind {
+    fn from(val: ImportSeedClosureError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        test_harness::{FakeCommand, SandboxContext},
+        warning::{self, WarningKind},
+    };
+
+    use super::ImportSeedClosure;
+
+    #[tokio::test]
+    async fn imports_an_export_or_a_binary_cache() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let dir = tempfile::tempdir()?;
+        let export = dir.path().join("closure.export");
+        std::fs::write(&export, "not really an export")?;
+        sandbox.fake(
+            "nix-store",
+            FakeCommand::success().stdout("/nix/store/aaaa-stdenv\n/nix/store/bbbb-gcc\n"),
+        );
+
+        let mut action = sandbox
+            .scope(ImportSeedClosure::plan(&export, false))
+            .await?;
+        assert_eq!(
+            action.inner().source_sha256,
+            crate::backup::sha256(b"not really an export")
+        );
+        sandbox.scope(action.try_execute()).await?;
+        let nix_store = sandbox.invocations_of("nix-store");
+        assert_eq!(
+            nix_store[0].args,
+            ["--option", "require-sigs", "false", "--import"]
+        );
+        assert_eq!(action.inner().imported, Some(2));
+
+        let cache = dir.path().join("cache");
+        std::fs::create_dir(&cache)?;
+        std::fs::write(cache.join("nix-cache-info"), "StoreDir: /nix/store\n")?;
+        std::fs::write(
+            cache.join("aaaa.narinfo"),
+            "StorePath: /nix/store/aaaa-stdenv\nURL: nar/aaaa.nar.xz\n",
+        )?;
+        let mut action = sandbox.scope(ImportSeedClosure::plan(&cache, true)).await?;
+        sandbox.scope(action.try_execute()).await?;
+        let nix = sandbox.invocations_of("nix");
+        assert_eq!(
+            nix[0].args,
+            [
+                "--extra-experimental-features",
+                "nix-command",
+                "copy",
+                "--from",
+                &format!("file://{}", cache.display()),
+                "/nix/store/aaaa-stdenv",
+            ]
+        );
+        assert_eq!(action.inner().imported, Some(1));
+
+        // A directory which isn't a binary cache is refused when planning
+        let empty = dir.path().join("empty");
+        std::fs::create_dir(&empty)?;
+        assert!(sandbox
+            .scope(ImportSeedClosure::plan(&empty, false))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_failed_import_is_a_warning() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let dir = tempfile::tempdir()?;
+        let export = dir.path().join("closure.export");
+        std::fs::write(&export, "")?;
+        sandbox.fake(
+            "nix-store",
+            FakeCommand::failure(1).stderr("error: cannot add path because it lacks a signature"),
+        );
+
+        let mut action = sandbox
+            .scope(ImportSeedClosure::plan(&export, true))
+            .await?;
+        let (result, warnings) = warning::collect(sandbox.scope(action.try_execute())).await;
+        result?;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::SeedClosureNotImported);
+        assert_eq!(action.inner().imported, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use std::path::PathBuf;
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::{
+    action::{
+        base::SetupDefaultProfileError, Action, ActionDescription, ActionError, ActionErrorKind,
+        ActionTag, PrivilegedOperation, StatefulAction,
+    },
+    execute_command,
+    profile::{validate_paths_can_cohabitate, verify_default_profile, DEFAULT_PROFILE},
+    settings::DefaultProfilePackage,
+};
+
+/**
+Realize flake references with the freshly installed `nix`, and install them into the default profile
+
+Runs once the daemon is up, with substitution allowed, unlike the store paths
+[`SetupDefaultProfile`](crate::action::base::SetupDefaultProfile) installs from the Nix tarball.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "install_default_profile_flakes")]
+pub struct InstallDefaultProfileFlakes {
+    flakes: Vec<String>,
+    /// The outputs each flake was realized to
+    #[serde(default)]
+    installed: Vec<PathBuf>,
+}
+
+impl InstallDefaultProfileFlakes {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        packages: &[DefaultProfilePackage],
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(Self {
+            flakes: packages
+                .iter()
+                .filter_map(DefaultProfilePackage::flake)
+                .map(ToString::to_string)
+                .collect(),
+            installed: vec![],
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "install_default_profile_flakes")]
+impl Action for InstallDefaultProfileFlakes {
+    fn action_tag() -> ActionTag {
+        ActionTag("install_default_profile_flakes")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Install flakes into the default Nix profile".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "install_default_profile_flakes",
+            flakes = self.flakes.join(" "),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            self.flakes
+                .iter()
+                .map(|flake| format!("Build `{flake}` and install it into `{DEFAULT_PROFILE}`"))
+                .collect(),
+        )]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let bin = PathBuf::from(DEFAULT_PROFILE).join("bin");
+        self.flakes
+            .iter()
+            .flat_map(|flake| {
+                [
+                    PrivilegedOperation::command(
+                        bin.join("nix").display().to_string(),
+                        [
+                            "--extra-experimental-features",
+                            "nix-command flakes",
+                            "build",
+                            "--no-link",
+                            "--print-out-paths",
+                            flake,
+                        ],
+                    ),
+                    PrivilegedOperation::command(
+                        bin.join("nix-env").display().to_string(),
+                        ["--option", "post-build-hook", "", "-i", "<flake outputs>"],
+                    ),
+                ]
+            })
+            .collect()
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let home =
+            dirs::home_dir().ok_or_else(|| Self::error(SetupDefaultProfileError::NoRootHome))?;
+        let bin = PathBuf::from(DEFAULT_PROFILE).join("bin");
+
+        for flake in &self.flakes {
+            let package_error =
+                |e: ActionErrorKind| SetupDefaultProfileError::Package(flake.clone(), Box::new(e));
+
+            let output = execute_command(
+                Command::new(bin.join("nix"))
+                    .process_group(0)
+                    .args(["--extra-experimental-features", "nix-command flakes"])
+                    .args(["build", "--no-link", "--print-out-paths"])
+                    .arg(flake)
+                    .stdin(std::process::Stdio::null())
+                    .env("HOME", &home),
+            )
+            .await
+            .map_err(package_error)
+            .map_err(Self::error)?;
+            let outputs = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>();
+
+            let mut packages = vec![(
+                "the default profile".to_string(),
+                verify_default_profile().await.map_err(Self::error)?,
+            )];
+            packages.extend(outputs.iter().map(|output| (flake.clone(), output.clone())));
+            validate_paths_can_cohabitate(&packages).map_err(Self::error)?;
+
+            execute_command(
+                Command::new(bin.join("nix-env"))
+                    .process_group(0)
+                    .args(["--option", "post-build-hook", ""])
+                    .arg("-i")
+                    .args(&outputs)
+                    .stdin(std::process::Stdio::null())
+                    .env("HOME", &home),
+            )
+            .await
+            .map_err(package_error)
+            .map_err(Self::error)?;
+
+            self.installed.extend(outputs);
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // The default profile is removed along with the rest of `/nix`
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::{
+        settings::DefaultProfilePackage,
+        test_harness::{FakeCommand, SandboxContext},
+    };
+
+    use super::InstallDefaultProfileFlakes;
+
+    const ENVIRONMENT: &str = "/nix/store/cccc-user-environment";
+
+    #[tokio::test]
+    async fn realizes_and_installs_flakes() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path(ENVIRONMENT).join("bin"))?;
+        std::fs::write(sandbox.path(ENVIRONMENT).join("bin/nix"), "")?;
+        sandbox.symlink(ENVIRONMENT, "/nix/var/nix/profiles/default")?;
+        let git = sandbox.path("/nix/store/eeee-git-2.47.0/bin");
+        std::fs::create_dir_all(&git)?;
+        std::fs::write(git.join("git"), "")?;
+        sandbox.fake(
+            "nix",
+            FakeCommand::success().stdout("/nix/store/eeee-git-2.47.0\n"),
+        );
+
+        let mut action = sandbox
+            .scope(InstallDefaultProfileFlakes::plan(&[
+                DefaultProfilePackage::StorePath(PathBuf::from("/nix/store/dddd-cachix-1.7.4")),
+                DefaultProfilePackage::Flake("nixpkgs#git".into()),
+            ]))
+            .await?;
+        sandbox.scope(action.try_execute()).await?;
+
+        let nix_env = sandbox.invocations_of("nix-env");
+        assert_eq!(nix_env.len(), 1);
+        assert_eq!(
+            nix_env[0].args.last().map(String::as_str),
+            Some("/nix/store/eeee-git-2.47.0")
+        );
+        assert_eq!(
+            action.action.installed,
+            [PathBuf::from("/nix/store/eeee-git-2.47.0")]
+        );
+
+        // A flake providing `bin/nix` would shadow the installed `nix`
+        std::fs::create_dir_all(sandbox.path("/nix/store/ffff-nix-2.25.0/bin"))?;
+        std::fs::write(sandbox.path("/nix/store/ffff-nix-2.25.0/bin/nix"), "")?;
+        sandbox.fake(
+            "nix",
+            FakeCommand::success().stdout("/nix/store/ffff-nix-2.25.0\n"),
+        );
+        let mut action = sandbox
+            .scope(InstallDefaultProfileFlakes::plan(&[
+                DefaultProfilePackage::Flake("nixpkgs#nixVersions.latest".into()),
+            ]))
+            .await?;
+        let err = sandbox.scope(action.try_execute()).await.unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("nixpkgs#nixVersions.latest"),
+            "{err:?}"
+        );
+        Ok(())
+    }
+}
@@ -9,19 +9,29 @@ pub(crate) mod create_or_merge_nix_config;
 pub(crate) mod create_user;
 pub(crate) mod delete_user;
 pub(crate) mod fetch_and_unpack_nix;
+pub(crate) mod import_seed_closure;
+pub(crate) mod install_default_profile_flakes;
 pub(crate) mod move_unpacked_nix;
 pub(crate) mod remove_directory;
+pub(crate) mod remove_user_data;
+pub(crate) mod restore_default_profile;
 pub(crate) mod setup_default_profile;
+pub(crate) mod verify_store;
 
 pub use add_user_to_group::AddUserToGroup;
 pub use create_directory::CreateDirectory;
 pub use create_file::CreateFile;
-pub use create_group::CreateGroup;
+pub use create_group::{CreateGroup, CreateGroupError};
 pub use create_or_insert_into_file::CreateOrInsertIntoFile;
 pub use create_or_merge_nix_config::CreateOrMergeNixConfig;
-pub use create_user::CreateUser;
+pub use create_user::{CreateUser, CreateUserError, UserShellAndHome};
 pub use delete_user::DeleteUser;
-pub use fetch_and_unpack_nix::{FetchAndUnpackNix, FetchUrlError};
+pub use fetch_and_unpack_nix::{FetchAndUnpackNix, FetchAttemptError, FetchUrlError};
+pub use import_seed_closure::{ImportSeedClosure, ImportSeedClosureError};
+pub use install_default_profile_flakes::InstallDefaultProfileFlakes;
 pub use move_unpacked_nix::{MoveUnpackedNix, MoveUnpackedNixError};
 pub use remove_directory::RemoveDirectory;
+pub use remove_user_data::{RemoveUserData, UserData, UserDataKind};
+pub use restore_default_profile::RestoreDefaultProfile;
 pub use setup_default_profile::{SetupDefaultProfile, SetupDefaultProfileError};
+pub use verify_store::{VerifyStore, VerifyStoreError};
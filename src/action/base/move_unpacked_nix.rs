@@ -7,7 +7,10 @@ use tracing::{span, Span};
 use walkdir::WalkDir;
 
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
+    },
     util::OnMissing,
 };
 
@@ -43,7 +46,7 @@ impl Action for MoveUnpackedNix {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "mount_unpacked_nix",
+            "move_unpacked_nix",
             src = tracing::field::display(self.unpacked_path.display()),
             dest = DEST,
         )
@@ -59,6 +62,18 @@ impl Action for MoveUnpackedNix {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let dest_store = Path::new(DEST).join("store");
+        vec![
+            PrivilegedOperation::write(&dest_store, None),
+            // Store paths already present are replaced
+            PrivilegedOperation::remove(dest_store.join("<store path>")),
+            PrivilegedOperation::write(dest_store.join("<store path>"), None),
+            // The store paths are symlinked back into the unpacked Nix
+            PrivilegedOperation::write(&self.unpacked_path, None),
+        ]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self { unpacked_path } = self;
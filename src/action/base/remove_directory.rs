@@ -2,7 +2,7 @@ use std::path::{Path, PathBuf};
 
 use tracing::{span, Span};
 
-use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState};
+use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState, PrivilegedOperation};
 use crate::action::{ActionError, StatefulAction};
 use crate::util::OnMissing;
 
@@ -48,6 +48,10 @@ impl Action for RemoveDirectory {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::remove(&self.path)]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         if self.path.exists() {
@@ -0,0 +1,220 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{span, Span};
+
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::homed;
+use crate::util::{host_path, LossyPath, OnMissing};
+
+/// The Nix data in a user's home, relative to it, which uninstalling leaves behind
+pub(crate) const IN_HOME: &[(&str, UserDataKind)] = &[
+    (".nix-profile", UserDataKind::ProfileLink),
+    (".nix-defexpr", UserDataKind::Defexpr),
+    (".nix-channels", UserDataKind::Channels),
+    (".local/state/nix", UserDataKind::State),
+    (".cache/nix", UserDataKind::Cache),
+];
+
+/// The Nix data of each user in `/nix/var/nix`, named after them, which uninstalling leaves behind
+/// when the Nix store is kept
+pub(crate) const IN_NIX: &[(&str, UserDataKind)] = &[
+    ("/nix/var/nix/profiles/per-user", UserDataKind::Profiles),
+    ("/nix/var/nix/gcroots/per-user", UserDataKind::GcRoots),
+];
+
+/// What a piece of [`UserData`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserDataKind {
+    /// `~/.nix-profile`
+    ProfileLink,
+    /// `~/.nix-defexpr`
+    Defexpr,
+    /// `~/.nix-channels`
+    Channels,
+    /// `~/.local/state/nix`
+    State,
+    /// `~/.cache/nix`
+    Cache,
+    /// `/nix/var/nix/profiles/per-user/<user>`
+    Profiles,
+    /// `/nix/var/nix/gcroots/per-user/<user>`
+    GcRoots,
+}
+
+impl UserDataKind {
+    /// What removing it loses
+    pub fn loses(&self) -> &'static str {
+        match self {
+            UserDataKind::ProfileLink => "the link to their profile, and with it the packages installed with `nix profile` or `nix-env`",
+            UserDataKind::Defexpr => "the Nix expressions `nix-env` uses, such as their channels",
+            UserDataKind::Channels => "the channels they subscribed to",
+            UserDataKind::State => "their profiles and channels, if Nix keeps them under `$XDG_STATE_HOME`, and the history of `nix repl`",
+            UserDataKind::Cache => "downloaded flakes and tarballs, which are fetched again as needed",
+            UserDataKind::Profiles => "their profile generations, so they can no longer roll back",
+            UserDataKind::GcRoots => "their garbage collector roots, so what they kept alive may be collected",
+        }
+    }
+}
+
+/// A path holding Nix data of a user
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct UserData {
+    #[serde_as(as = "LossyPath")]
+    pub path: PathBuf,
+    pub kind: UserDataKind,
+}
+
+/**
+Remove the Nix data a user accumulated, which uninstalling otherwise leaves behind
+
+Planned by `nix-installer uninstall --purge-user-data` as already completed, so uninstalling removes
+it. Nothing is done on execute.
+*/
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "remove_user_data")]
+pub struct RemoveUserData {
+    user: String,
+    #[serde_as(as = "LossyPath")]
+    home: PathBuf,
+    data: Vec<UserData>,
+}
+
+impl RemoveUserData {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        user: impl Into<String>,
+        home: impl AsRef<Path>,
+        data: Vec<UserData>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(StatefulAction::completed(Self {
+            user: user.into(),
+            home: home.as_ref().to_path_buf(),
+            data,
+        }))
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn data(&self) -> &[UserData] {
+        &self.data
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "remove_user_data")]
+impl Action for RemoveUserData {
+    fn action_tag() -> ActionTag {
+        ActionTag("remove_user_data")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Remove the Nix data of user `{}`", self.user)
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "remove_user_data",
+            user = self.user,
+            paths = tracing::field::display(
+                self.data
+                    .iter()
+                    .map(|data| data.path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.data
+            .iter()
+            .map(|data| PrivilegedOperation::remove(&data.path))
+            .collect()
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("{}, which can't be recovered", self.tracing_synopsis()),
+            self.data
+                .iter()
+                .map(|data| {
+                    format!(
+                        "Remove `{}`, losing {}",
+                        data.path.display(),
+                        data.kind.loses()
+                    )
+                })
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let home = host_path(&self.home);
+        let skipped_home = match homed::inactive_home(&self.user).await {
+            Some(homed_user) => {
+                let left = self
+                    .data
+                    .iter()
+                    .filter(|data| host_path(&data.path).starts_with(&home))
+                    .map(|data| format!("`{}`", data.path.display()))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                homed::warn_inactive(
+                    &homed_user,
+                    "its Nix data was not removed",
+                    &format!("remove {left}"),
+                );
+                true
+            },
+            None => false,
+        };
+
+        let mut errors = vec![];
+        for data in &self.data {
+            let path = host_path(&data.path);
+            if skipped_home && path.starts_with(&home) {
+                continue;
+            }
+            let removed = match tokio::fs::symlink_metadata(&path).await {
+                Ok(metadata) if metadata.is_dir() => {
+                    crate::util::remove_dir_all(&path, OnMissing::Ignore).await
+                },
+                Ok(_) => crate::util::remove_file(&path, OnMissing::Ignore).await,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = removed {
+                errors.push(Self::error(ActionErrorKind::Remove(data.path.clone(), e)));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::path::PathBuf;
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::{
+    action::{
+        base::SetupDefaultProfileError, Action, ActionDescription, ActionError, ActionErrorKind,
+        ActionTag, PrivilegedOperation, StatefulAction,
+    },
+    execute_command,
+    profile::{dangling_default_profile_links, verify_default_profile, DEFAULT_PROFILE},
+    util::host_path,
+};
+
+/**
+Rebuild a broken default Nix profile from `nix` (and `nss-cacert`) packages still in the store
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "restore_default_profile")]
+pub struct RestoreDefaultProfile {
+    nix_store_path: PathBuf,
+    nss_ca_cert_store_path: Option<PathBuf>,
+}
+
+impl RestoreDefaultProfile {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        nix_store_path: PathBuf,
+        nss_ca_cert_store_path: Option<PathBuf>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let this = Self {
+            nix_store_path,
+            nss_ca_cert_store_path,
+        };
+
+        if verify_default_profile().await.is_ok() {
+            tracing::debug!("Default profile already resolves to `nix`");
+            return Ok(StatefulAction::completed(this));
+        }
+
+        Ok(StatefulAction::uncompleted(this))
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "restore_default_profile")]
+impl Action for RestoreDefaultProfile {
+    fn action_tag() -> ActionTag {
+        ActionTag("restore_default_profile")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Rebuild the default Nix profile from `{}`",
+            self.nix_store_path.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "restore_default_profile",
+            nix_store_path = %self.nix_store_path.display(),
+            nss_ca_cert_store_path = self.nss_ca_cert_store_path.as_ref().map(|v| tracing::field::display(v.display())),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!(
+            "Remove links in `{DEFAULT_PROFILE}`'s chain which point to missing store paths"
+        )];
+        match &self.nss_ca_cert_store_path {
+            Some(nss_ca_cert_store_path) => explanation.push(format!(
+                "Install `{}` and `{}` into a new generation of `{DEFAULT_PROFILE}`",
+                self.nix_store_path.display(),
+                nss_ca_cert_store_path.display()
+            )),
+            None => explanation.push(format!(
+                "Install `{}` into a new generation of `{DEFAULT_PROFILE}`",
+                self.nix_store_path.display(),
+            )),
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut args = vec![
+            "--profile".to_string(),
+            DEFAULT_PROFILE.to_string(),
+            "--option".to_string(),
+            "substitute".to_string(),
+            "false".to_string(),
+            "--option".to_string(),
+            "post-build-hook".to_string(),
+            "".to_string(),
+            "-i".to_string(),
+            self.nix_store_path.display().to_string(),
+        ];
+        if let Some(nss_ca_cert_store_path) = &self.nss_ca_cert_store_path {
+            args.push(nss_ca_cert_store_path.display().to_string());
+        }
+        vec![
+            // Links of the profile chain pointing to missing store paths
+            PrivilegedOperation::remove(DEFAULT_PROFILE),
+            PrivilegedOperation::remove(format!("{DEFAULT_PROFILE}-<generation>-link")),
+            PrivilegedOperation::command(
+                self.nix_store_path
+                    .join("bin/nix-env")
+                    .display()
+                    .to_string(),
+                args,
+            ),
+        ]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        // Removing a generation link can leave `default` dangling in turn
+        loop {
+            let dangling = dangling_default_profile_links()
+                .await
+                .map_err(Self::error)?;
+            if dangling.is_empty() {
+                break;
+            }
+            for link in dangling {
+                tracing::debug!(link = %link.display(), "Removing dangling profile link");
+                tokio::fs::remove_file(host_path(&link))
+                    .await
+                    .map_err(|e| ActionErrorKind::Remove(link.clone(), e))
+                    .map_err(Self::error)?;
+            }
+        }
+
+        let mut command = Command::new(self.nix_store_path.join("bin/nix-env"));
+        command
+            .process_group(0)
+            .arg("--profile")
+            .arg(DEFAULT_PROFILE)
+            .args(["--option", "substitute", "false"])
+            .args(["--option", "post-build-hook", ""])
+            .arg("-i")
+            .arg(&self.nix_store_path)
+            .stdin(std::process::Stdio::null())
+            .env(
+                "HOME",
+                dirs::home_dir()
+                    .ok_or_else(|| Self::error(SetupDefaultProfileError::NoRootHome))?,
+            );
+        if let Some(nss_ca_cert_store_path) = &self.nss_ca_cert_store_path {
+            command.arg(nss_ca_cert_store_path).env(
+                "NIX_SSL_CERT_FILE",
+                nss_ca_cert_store_path.join("etc/ssl/certs/ca-bundle.crt"),
+            );
+        }
+        execute_command(&mut command).await.map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // The previous generation was unusable, there is nothing worth restoring
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::test_harness::{Invocation, SandboxContext};
+
+    use super::RestoreDefaultProfile;
+
+    #[tokio::test]
+    async fn removes_dangling_links_and_reinstalls_nix() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/nix/var/nix/profiles"))?;
+        std::os::unix::fs::symlink(
+            "/nix/store/cccc-user-environment",
+            sandbox.path("/nix/var/nix/profiles/default-1-link"),
+        )?;
+        std::os::unix::fs::symlink(
+            "default-1-link",
+            sandbox.path("/nix/var/nix/profiles/default"),
+        )?;
+        std::fs::create_dir_all(sandbox.path("/nix/var/nix/profiles/per-user"))?;
+
+        let mut action = sandbox
+            .scope(RestoreDefaultProfile::plan(
+                PathBuf::from("/nix/store/aaaa-nix-2.24.9"),
+                None,
+            ))
+            .await?;
+        sandbox.scope(action.try_execute()).await?;
+
+        assert!(!sandbox
+            .path("/nix/var/nix/profiles/default-1-link")
+            .is_symlink());
+        assert!(!sandbox.path("/nix/var/nix/profiles/default").is_symlink());
+        assert!(sandbox.path("/nix/var/nix/profiles/per-user").is_dir());
+        assert_eq!(
+            sandbox.invocations_of("nix-env"),
+            vec![Invocation::new(
+                "nix-env",
+                [
+                    "--profile",
+                    "/nix/var/nix/profiles/default",
+                    "--option",
+                    "substitute",
+                    "false",
+                    "--option",
+                    "post-build-hook",
+                    "",
+                    "-i",
+                    "/nix/store/aaaa-nix-2.24.9",
+                ]
+            )]
+        );
+        Ok(())
+    }
+}
@@ -1,28 +1,91 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::{
     action::{common::ConfigureNix, ActionError, ActionErrorKind, ActionTag, StatefulAction},
-    execute_command, set_env,
+    execute_command,
+    profile::{
+        current_generation, dead_profile_references, restore_sidelined_profile, sideline_profile,
+        validate_paths_can_cohabitate, SidelinedProfile, DEFAULT_PROFILE, ROOT_CHANNELS_PROFILE,
+        SIDELINED_PROFILES_DIR,
+    },
+    set_env,
+    settings::DefaultProfilePackage,
 };
 
 use tokio::{io::AsyncWriteExt, process::Command};
 use tracing::{span, Span};
 
-use crate::action::{Action, ActionDescription};
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
 
 /**
-Setup the default Nix profile with `nss-cacert` and `nix` itself.
+Setup the default Nix profile with `nss-cacert` and `nix` itself, along with any extra packages.
+
+Extra packages given as store paths are installed here, from the Nix tarball's closure. Those
+given as flake references are recorded here but realized by
+[`InstallDefaultProfileFlakes`](crate::action::base::InstallDefaultProfileFlakes) once the daemon
+is running.
+
+When `/nix` was restored from a backup or partially garbage collected, the default profile or
+root's channels profile can refer to store paths which no longer exist (or are no longer valid),
+which `nix-env` fails on part way through. Such profiles are moved aside, below
+`/nix/var/nix/profiles/sidelined`, and fresh ones started in their place. Reverting moves them back.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "setup_default_profile")]
 pub struct SetupDefaultProfile {
     unpacked_path: PathBuf,
+    /// Recorded so `nix-installer repair default-profile` can rebuild the profile later
+    #[serde(default)]
+    nix_store_path: Option<PathBuf>,
+    #[serde(default)]
+    nss_ca_cert_store_path: Option<PathBuf>,
+    /// Every extra package the profile was set up with, so it is known to be installer managed
+    #[serde(default)]
+    packages: Vec<DefaultProfilePackage>,
+    /// Broken profiles moved aside before building on them, restored on revert
+    #[serde(default)]
+    sidelined_profiles: Vec<SidelinedProfile>,
 }
 
+/// The profiles `nix-env` (and later `nix-channel`) build on, as root
+const BUILT_ON_PROFILES: &[&str] = &[DEFAULT_PROFILE, ROOT_CHANNELS_PROFILE];
+
 impl SetupDefaultProfile {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(unpacked_path: PathBuf) -> Result<StatefulAction<Self>, ActionError> {
-        Ok(Self { unpacked_path }.into())
+    pub async fn plan(
+        unpacked_path: PathBuf,
+        packages: Vec<DefaultProfilePackage>,
+        offline: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        if offline {
+            if let Some(flake) = packages.iter().find_map(DefaultProfilePackage::flake) {
+                return Err(Self::error(SetupDefaultProfileError::FlakeOffline(
+                    flake.to_string(),
+                )));
+            }
+        }
+
+        Ok(Self {
+            unpacked_path,
+            nix_store_path: None,
+            nss_ca_cert_store_path: None,
+            packages,
+            sidelined_profiles: vec![],
+        }
+        .into())
+    }
+
+    /// Move `profile` aside, recording where to, so a fresh one is started in its place
+    async fn sideline(&mut self, profile: &Path, reason: &str) -> Result<(), ActionError> {
+        if let Some(sidelined) = sideline_profile(profile).await.map_err(Self::error)? {
+            tracing::warn!(
+                profile = %profile.display(),
+                backup = %sidelined.backup.display(),
+                "Moved the profile aside and started a fresh one, as {reason}"
+            );
+            self.sidelined_profiles.push(sidelined);
+        }
+        Ok(())
     }
 }
 
@@ -45,11 +108,105 @@ impl Action for SetupDefaultProfile {
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        let explanation = self
+            .packages
+            .iter()
+            .map(|package| match package {
+                DefaultProfilePackage::StorePath(store_path) => {
+                    format!("Install `{}`", store_path.display())
+                },
+                DefaultProfilePackage::Flake(flake) => {
+                    format!("Install `{flake}` once the Nix daemon is running")
+                },
+            })
+            .collect();
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let nix_env = "<nix store path>/bin/nix-env";
+        let mut operations = vec![];
+        // Broken profiles are moved aside
+        for profile in BUILT_ON_PROFILES {
+            operations.push(PrivilegedOperation::remove(*profile));
+            operations.push(PrivilegedOperation::remove(format!(
+                "{profile}-<generation>-link"
+            )));
+        }
+        operations.push(PrivilegedOperation::write(SIDELINED_PROFILES_DIR, None));
+        operations.extend([
+            PrivilegedOperation::command("<nix store path>/bin/nix-store", ["--load-db"]),
+            PrivilegedOperation::command(
+                nix_env,
+                [
+                    "--option",
+                    "substitute",
+                    "false",
+                    "--option",
+                    "post-build-hook",
+                    "",
+                    "-i",
+                    "<nix store path>",
+                ],
+            ),
+            PrivilegedOperation::command(
+                nix_env,
+                [
+                    "--option",
+                    "substitute",
+                    "false",
+                    "--option",
+                    "post-build-hook",
+                    "",
+                    "-i",
+                    "<nss-cacert store path>",
+                ],
+            ),
+        ]);
+        operations.extend(
+            self.packages
+                .iter()
+                .filter_map(DefaultProfilePackage::store_path)
+                .map(|store_path| {
+                    PrivilegedOperation::command(
+                        nix_env,
+                        [
+                            "--option",
+                            "substitute",
+                            "false",
+                            "--option",
+                            "post-build-hook",
+                            "",
+                            "-i",
+                            &store_path.display().to_string(),
+                        ],
+                    )
+                }),
+        );
+        operations
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        for profile in BUILT_ON_PROFILES {
+            let profile = Path::new(profile);
+            let dead = dead_profile_references(profile)
+                .await
+                .map_err(Self::error)?;
+            if !dead.is_empty() {
+                let dead = dead
+                    .iter()
+                    .map(|path| format!("`{}`", path.display()))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                self.sideline(
+                    profile,
+                    &format!("it refers to missing store paths: {dead}"),
+                )
+                .await?;
+            }
+        }
+
         let (nix_pkg, nss_ca_cert_pkg) =
             ConfigureNix::find_nix_and_ca_cert(&self.unpacked_path).await?;
         let found_nix_paths = glob::glob(&format!("{}/nix-*", self.unpacked_path.display()))
@@ -65,6 +222,36 @@ impl Action for SetupDefaultProfile {
             .await
             .map_err(|e| ActionErrorKind::Read(reginfo_path.to_path_buf(), e))
             .map_err(Self::error)?;
+
+        let store_paths = self
+            .packages
+            .iter()
+            .filter_map(DefaultProfilePackage::store_path)
+            .cloned()
+            .collect::<Vec<_>>();
+        if !store_paths.is_empty() {
+            // `.reginfo` lists every path in the tarball's closure (along with their references)
+            let registered = String::from_utf8_lossy(&reginfo);
+            if let Some(missing) = store_paths.iter().find(|store_path| {
+                !registered
+                    .lines()
+                    .any(|line| Path::new(line) == **store_path)
+            }) {
+                return Err(Self::error(SetupDefaultProfileError::NotInClosure(
+                    missing.to_path_buf(),
+                )));
+            }
+            let mut packages = vec![
+                ("nix".to_string(), nix_pkg.clone()),
+                ("nss-cacert".to_string(), nss_ca_cert_pkg.clone()),
+            ];
+            packages.extend(
+                store_paths
+                    .iter()
+                    .map(|store_path| (store_path.display().to_string(), store_path.to_path_buf())),
+            );
+            validate_paths_can_cohabitate(&packages).map_err(Self::error)?;
+        }
         let mut load_db_command = Command::new(nix_pkg.join("bin/nix-store"));
         load_db_command.process_group(0);
         load_db_command.arg("--load-db");
@@ -114,6 +301,45 @@ impl Action for SetupDefaultProfile {
             )));
         };
 
+        // With the tarball registered, check the surviving profiles are valid in the Nix database too
+        for profile in BUILT_ON_PROFILES {
+            let profile = Path::new(profile);
+            let Some(generation) = current_generation(profile).await else {
+                continue;
+            };
+            let mut verify_command = Command::new(nix_pkg.join("bin/nix"));
+            verify_command
+                .process_group(0)
+                .args(["--extra-experimental-features", "nix-command"])
+                .args([
+                    "store",
+                    "verify",
+                    "--no-trust",
+                    "--no-contents",
+                    "--recursive",
+                ])
+                .arg(&generation)
+                .stdin(std::process::Stdio::null());
+            let output = crate::command_output(&mut verify_command)
+                .await
+                .map_err(|e| ActionErrorKind::command(&verify_command, e))
+                .map_err(Self::error)?;
+            if !output.status.success() {
+                tracing::debug!(
+                    stderr = %String::from_utf8_lossy(&output.stderr),
+                    "`nix store verify` failed"
+                );
+                self.sideline(
+                    profile,
+                    &format!(
+                        "`{}` (or something it refers to) is not valid in the Nix database",
+                        generation.display()
+                    ),
+                )
+                .await?;
+            }
+        }
+
         // Install `nix` itself into the store
         execute_command(
             Command::new(nix_pkg.join("bin/nix-env"))
@@ -158,18 +384,58 @@ impl Action for SetupDefaultProfile {
         .await
         .map_err(Self::error)?;
 
+        for store_path in store_paths {
+            execute_command(
+                Command::new(nix_pkg.join("bin/nix-env"))
+                    .process_group(0)
+                    .args(["--option", "substitute", "false"])
+                    .args(["--option", "post-build-hook", ""])
+                    .arg("-i")
+                    .arg(&store_path)
+                    .stdin(std::process::Stdio::null())
+                    .env(
+                        "HOME",
+                        dirs::home_dir()
+                            .ok_or_else(|| Self::error(SetupDefaultProfileError::NoRootHome))?,
+                    )
+                    .env(
+                        "NIX_SSL_CERT_FILE",
+                        nss_ca_cert_pkg.join("etc/ssl/certs/ca-bundle.crt"),
+                    ),
+            )
+            .await
+            .map_err(|e| {
+                Self::error(SetupDefaultProfileError::Package(
+                    store_path.display().to_string(),
+                    Box::new(e),
+                ))
+            })?;
+        }
+
         set_env(
             "NIX_SSL_CERT_FILE",
             "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
         );
 
+        self.nix_store_path = Some(nix_pkg);
+        self.nss_ca_cert_store_path = Some(nss_ca_cert_pkg);
+
         Ok(())
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             "Unset the default Nix profile".to_string(),
-            vec![],
+            self.sidelined_profiles
+                .iter()
+                .map(|sidelined| {
+                    format!(
+                        "Restore `{}` from `{}`",
+                        sidelined.profile.display(),
+                        sidelined.backup.display()
+                    )
+                })
+                .collect(),
         )]
     }
 
@@ -177,6 +443,13 @@ impl Action for SetupDefaultProfile {
     async fn revert(&mut self) -> Result<(), ActionError> {
         std::env::remove_var("NIX_SSL_CERT_FILE");
 
+        while let Some(sidelined) = self.sidelined_profiles.pop() {
+            if let Err(e) = restore_sidelined_profile(&sidelined).await {
+                self.sidelined_profiles.push(sidelined);
+                return Err(Self::error(e));
+            }
+        }
+
         Ok(())
     }
 }
@@ -186,6 +459,12 @@ impl Action for SetupDefaultProfile {
 pub enum SetupDefaultProfileError {
     #[error("No root home found to place channel configuration in")]
     NoRootHome,
+    #[error("`{0}` is not in the closure of the Nix tarball, so it cannot be installed into the default profile without fetching it")]
+    NotInClosure(PathBuf),
+    #[error("`{0}` is a flake reference, which cannot be realized while substitution is disabled (`substitute = false`)")]
+    FlakeOffline(String),
+    #[error("Installing `{0}` into the default profile")]
+    Package(String, #[source] Box<ActionErrorKind>),
 }
 
 impl From<SetupDefaultProfileError> for ActionErrorKind {
@@ -193,3 +472,32 @@ impl From<SetupDefaultProfileError> for ActionErrorKind {
         ActionErrorKind::Custom(Box::new(val))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::settings::DefaultProfilePackage;
+
+    use super::SetupDefaultProfile;
+
+    #[tokio::test]
+    async fn flakes_are_rejected_offline() -> eyre::Result<()> {
+        let packages = vec![
+            DefaultProfilePackage::StorePath(PathBuf::from("/nix/store/aaaa-cachix-1.7.4")),
+            DefaultProfilePackage::Flake("nixpkgs#git".into()),
+        ];
+        SetupDefaultProfile::plan(
+            PathBuf::from("/nix/temp-install-dir"),
+            packages.clone(),
+            false,
+        )
+        .await?;
+
+        let err = SetupDefaultProfile::plan(PathBuf::from("/nix/temp-install-dir"), packages, true)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("nixpkgs#git"), "{err:?}");
+        Ok(())
+    }
+}
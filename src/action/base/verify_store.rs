@@ -0,0 +1,443 @@
+use std::path::PathBuf;
+use std::process::Output;
+use std::time::{Duration, Instant};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::{
+    action::{
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
+    },
+    daemon_capabilities,
+    nix_settings::{series_of, series_to_install},
+    profile::DEFAULT_PROFILE,
+    settings::UrlOrPath,
+    util::host_path,
+    warning::{self, WarningKind},
+};
+
+/// How long verifying the store may take before it is given up on
+pub(crate) const VERIFY_STORE_BUDGET: Duration = Duration::from_secs(15 * 60);
+
+/// How often a verification still running is reported
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where the daemon listens, when the store is verified after the install
+const DAEMON_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
+/**
+Verify the contents of every path in the freshly installed store, so a store corrupted on the way
+to the disk fails the install rather than a build days later
+
+Runs once the daemon is up, through it when it was started. A store which could not be verified
+within [`VERIFY_STORE_BUDGET`] is a warning, not a failure.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "verify_store")]
+pub struct VerifyStore {
+    command: VerifyCommand,
+    /// If the store is reached through the daemon, rather than opened directly
+    through_daemon: bool,
+    budget_secs: u64,
+}
+
+impl VerifyStore {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        series: Option<&str>,
+        through_daemon: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(Self {
+            command: VerifyCommand::for_series(series),
+            through_daemon,
+            budget_secs: VERIFY_STORE_BUDGET.as_secs(),
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "verify_store")]
+impl Action for VerifyStore {
+    fn action_tag() -> ActionTag {
+        ActionTag("verify_store")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Verify the contents of the Nix store".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "verify_store",
+            command = ?self.command,
+            through_daemon = self.through_daemon,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let (program, args) = self.command.program_and_args();
+        let mut explanation = vec![format!(
+            "Run `{} {}`{}",
+            program.display(),
+            args.join(" "),
+            match self.through_daemon {
+                true => " through the Nix daemon",
+                false => "",
+            }
+        )];
+        explanation.push(format!(
+            "A modified store path fails the install, a verification taking longer than {} minutes is skipped with a warning",
+            self.budget_secs / 60
+        ));
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let (program, args) = self.command.program_and_args();
+        vec![PrivilegedOperation::command(
+            program.display().to_string(),
+            args,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let verification = verify_store(
+            self.command,
+            self.through_daemon,
+            Duration::from_secs(self.budget_secs),
+        )
+        .await;
+        match verification {
+            StoreVerification::Verified => {
+                tracing::info!("Verified the contents of the Nix store");
+                Ok(())
+            },
+            StoreVerification::TimedOut(budget) => {
+                warning::warn(
+                    WarningKind::StoreNotVerified,
+                    format!(
+                        "Verifying the Nix store took longer than {} minutes and was stopped, run `nix-installer status --verify-store` to verify it",
+                        budget.as_secs() / 60
+                    ),
+                );
+                Ok(())
+            },
+            verification => verification.into_result().map_err(Self::error),
+        }
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // Verifying changes nothing
+        Ok(())
+    }
+}
+
+/// The command which verifies the store, by the Nix release installed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum VerifyCommand {
+    /// `nix store verify --all --no-trust`
+    NixStoreVerify,
+    /// `nix-store --verify --check-contents`, for releases without `nix store verify`
+    LegacyVerify,
+}
+
+impl VerifyCommand {
+    /// The command the Nix `series` (such as `2.25`) has, a release whose series is not known is
+    /// assumed to be recent
+    pub(crate) fn for_series(series: Option<&str>) -> Self {
+        match series.and_then(daemon_capabilities::capabilities) {
+            Some(capabilities) if !capabilities.store_verify => Self::LegacyVerify,
+            _ => Self::NixStoreVerify,
+        }
+    }
+
+    fn program_and_args(&self) -> (PathBuf, Vec<String>) {
+        let bin = PathBuf::from(DEFAULT_PROFILE).join("bin");
+        let (program, args): (_, &[&str]) = match self {
+            Self::NixStoreVerify => (
+                bin.join("nix"),
+                &[
+                    "--extra-experimental-features",
+                    "nix-command",
+                    "store",
+                    "verify",
+                    "--all",
+                    "--no-trust",
+                ],
+            ),
+            Self::LegacyVerify => (bin.join("nix-store"), &["--verify", "--check-contents"]),
+        };
+        (program, args.iter().map(ToString::to_string).collect())
+    }
+
+    fn command(&self, through_daemon: bool) -> Command {
+        let (program, args) = self.program_and_args();
+        let mut command = Command::new(program);
+        command.args(args);
+        if through_daemon {
+            command.env("NIX_REMOTE", "daemon");
+        }
+        command
+            .process_group(0)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true);
+        command
+    }
+}
+
+/// The outcome of verifying the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StoreVerification {
+    Verified,
+    /// The store paths whose contents do not match their hash
+    Corrupted(Vec<String>),
+    /// The store could not be verified, with why
+    Failed(String),
+    /// Verifying took longer than its budget
+    TimedOut(Duration),
+}
+
+impl StoreVerification {
+    pub(crate) fn into_result(self) -> Result<(), VerifyStoreError> {
+        match self {
+            Self::Verified => Ok(()),
+            Self::Corrupted(paths) => Err(VerifyStoreError::Corrupted(paths)),
+            Self::Failed(reason) => Err(VerifyStoreError::Failed(reason)),
+            Self::TimedOut(budget) => Err(VerifyStoreError::TimedOut(budget)),
+        }
+    }
+}
+
+/// The release series of the Nix the install recorded in `receipt` installed, named by its
+/// `--nix-package-url` or `--nix-version`
+pub(crate) fn series_of_receipt(receipt: &serde_json::Value) -> Option<String> {
+    let settings = &receipt["planner"]["settings"];
+    // Older receipts recorded the URL as a string
+    let nix_package_url = match &settings["nix_package_url"] {
+        serde_json::Value::String(url) => Some(match url::Url::parse(url) {
+            Ok(url) => UrlOrPath::Url(url),
+            Err(_) => UrlOrPath::Path(url.into()),
+        }),
+        other => serde_json::from_value::<Option<UrlOrPath>>(other.clone()).ok()?,
+    };
+    series_to_install(nix_package_url.as_ref())
+        .or_else(|| settings["nix_version"].as_str().and_then(series_of))
+}
+
+/// Verify the store the install recorded in `receipt` installed, through the daemon if it is
+/// listening, with the command of the Nix installed
+pub(crate) async fn verify_installed_store(
+    receipt: Option<&serde_json::Value>,
+) -> StoreVerification {
+    let series = receipt.and_then(series_of_receipt);
+    verify_store(
+        VerifyCommand::for_series(series.as_deref()),
+        host_path(DAEMON_SOCKET).exists(),
+        VERIFY_STORE_BUDGET,
+    )
+    .await
+}
+
+/// Verify the store with `command`, giving up after `budget`
+pub(crate) async fn verify_store(
+    command: VerifyCommand,
+    through_daemon: bool,
+    budget: Duration,
+) -> StoreVerification {
+    let mut command = command.command(through_daemon);
+    let description = format!("{:?}", command.as_std());
+    tracing::debug!(command = description, "Verifying the Nix store");
+    let started = Instant::now();
+    let output = crate::command_output(&mut command);
+    tokio::pin!(output);
+    let deadline = tokio::time::sleep(budget);
+    tokio::pin!(deadline);
+    let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+    // The first tick completes immediately
+    interval.tick().await;
+    let output = loop {
+        tokio::select! {
+            output = &mut output => break output,
+            _ = &mut deadline => return StoreVerification::TimedOut(budget),
+            _ = interval.tick() => tracing::info!(
+                "Still verifying the Nix store, {}s of at most {}s",
+                started.elapsed().as_secs(),
+                budget.as_secs()
+            ),
+        }
+    };
+    match output {
+        Ok(output) => classify(&output),
+        Err(e) => StoreVerification::Failed(format!("`{description}` could not run: {e}")),
+    }
+}
+
+/// What the `output` of a verification says of the store
+pub(crate) fn classify(output: &Output) -> StoreVerification {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // Both commands report a path like `path '/nix/store/...' was modified! expected hash ...`
+    let modified = stderr
+        .lines()
+        .filter(|line| line.contains("was modified!"))
+        .filter_map(|line| {
+            let path = &line[line.find("/nix/store/")?..];
+            let end = path
+                .find(|c: char| c.is_whitespace() || "'`‘’\"".contains(c))
+                .unwrap_or(path.len());
+            Some(path[..end].to_string())
+        })
+        .collect::<Vec<_>>();
+    if output.status.success() {
+        StoreVerification::Verified
+    } else if !modified.is_empty() {
+        StoreVerification::Corrupted(modified)
+    } else {
+        let reason = stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map(|line| line.trim().to_string())
+            .unwrap_or_else(|| format!("it exited with {}", output.status));
+        StoreVerification::Failed(reason)
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyStoreError {
+    #[error("The Nix store is corrupted, the contents of {} do not match their hash; the disk it is on may be failing", .0.iter().map(|path| format!("`{path}`")).collect::<Vec<_>>().join(", "))]
+    Corrupted(Vec<String>),
+    #[error("The Nix store could not be verified: {0}")]
+    Failed(String),
+    #[error("Verifying the Nix store took longer than {} minutes and was stopped", .0.as_secs() / 60)]
+    TimedOut(Duration),
+}
+
+impl From<VerifyStoreError> for ActionErrorKind {
+    fn from(val: VerifyStoreError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    use crate::test_harness::{FakeCommand, SandboxContext};
+
+    use super::{classify, StoreVerification, VerifyCommand, VerifyStore};
+
+    fn output(code: i32, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: vec![],
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn command_follows_the_nix_version() -> eyre::Result<()> {
+        assert_eq!(
+            VerifyCommand::for_series(Some("2.3")),
+            VerifyCommand::LegacyVerify
+        );
+        assert_eq!(
+            VerifyCommand::for_series(Some("2.4")),
+            VerifyCommand::NixStoreVerify
+        );
+        assert_eq!(
+            VerifyCommand::for_series(None),
+            VerifyCommand::NixStoreVerify
+        );
+
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake("nix-store", FakeCommand::success());
+        let mut action = sandbox.scope(VerifyStore::plan(Some("2.3"), true)).await?;
+        sandbox.scope(action.try_execute()).await?;
+        assert_eq!(
+            sandbox.invocations_of("nix-store")[0].args,
+            ["--verify", "--check-contents"]
+        );
+
+        sandbox.fake("nix", FakeCommand::success());
+        let mut action = sandbox.scope(VerifyStore::plan(Some("2.25"), true)).await?;
+        sandbox.scope(action.try_execute()).await?;
+        assert_eq!(
+            sandbox.invocations_of("nix")[0].args,
+            [
+                "--extra-experimental-features",
+                "nix-command",
+                "store",
+                "verify",
+                "--all",
+                "--no-trust"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn outcomes_are_classified() {
+        assert_eq!(
+            classify(&output(0, "warning: something unrelated\n")),
+            StoreVerification::Verified
+        );
+        assert_eq!(
+            classify(&output(
+                1,
+                "path '/nix/store/aaaa-hello-2.12.drv' was modified! expected hash 'sha256:1', got 'sha256:2'\n"
+            )),
+            StoreVerification::Corrupted(vec!["/nix/store/aaaa-hello-2.12.drv".into()])
+        );
+        // Old releases quote paths differently, and complain they could not fix them
+        assert_eq!(
+            classify(&output(
+                1,
+                "path ‘/nix/store/bbbb-gcc’ was modified! expected hash ‘1’, got ‘2’\nwarning: not all errors were fixed\n"
+            )),
+            StoreVerification::Corrupted(vec!["/nix/store/bbbb-gcc".into()])
+        );
+        assert_eq!(
+            classify(&output(
+                1,
+                "error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket': Connection refused\n"
+            )),
+            StoreVerification::Failed(
+                "error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket': Connection refused"
+                    .into()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn a_corrupted_store_fails_the_action() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake(
+            "nix",
+            FakeCommand::failure(1).stderr(
+                "path '/nix/store/aaaa-hello.drv' was modified! expected hash 'sha256:1', got 'sha256:2'\n",
+            ),
+        );
+        let mut action = sandbox.scope(VerifyStore::plan(None, true)).await?;
+        let err = sandbox
+            .scope(action.try_execute())
+            .await
+            .expect_err("A corrupted store fails the install");
+        assert!(err
+            .kind()
+            .to_string()
+            .contains("`/nix/store/aaaa-hello.drv`"));
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::common::configure_init_service::UnitSrc;
+use crate::action::common::ConfigureInitService;
+use crate::action::macos::DARWIN_LAUNCHD_DOMAIN;
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::drift::WrittenFile;
+use crate::execute_command;
+use crate::settings::InitSystem;
+use crate::util::OnMissing;
+use crate::warning::{self, WarningKind};
+
+/// `nix-daemon --stdio` serves one client over stdin/stdout, forwarding it to the daemon
+const NIX_DAEMON_BIN: &str = "/nix/var/nix/profiles/default/bin/nix-daemon";
+
+// Linux
+pub(crate) const TCP_SOCKET_NAME: &str = "nix-daemon-tcp.socket";
+pub(crate) const TCP_SERVICE_NAME: &str = "nix-daemon-tcp@.service";
+const TCP_SOCKET_DEST: &str = "/etc/systemd/system/nix-daemon-tcp.socket";
+const TCP_SERVICE_DEST: &str = "/etc/systemd/system/nix-daemon-tcp@.service";
+
+// Darwin
+const DARWIN_TCP_SERVICE_NAME: &str = "org.nixos.nix-daemon-tcp";
+const DARWIN_TCP_SERVICE_DEST: &str = "/Library/LaunchDaemons/org.nixos.nix-daemon-tcp.plist";
+
+/**
+Expose the Nix daemon on a TCP address, with an inetd-style socket which runs `nix-daemon --stdio`
+for each connection
+
+The daemon's own socket unit is left alone: `nix-daemon` refuses to start when socket activation
+hands it more than one socket.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "configure_daemon_tcp_listener")]
+pub struct ConfigureDaemonTcpListener {
+    init: InitSystem,
+    listen: SocketAddr,
+    start_daemon: bool,
+    /// The units or plist, once executed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    written_files: Vec<WrittenFile>,
+}
+
+impl ConfigureDaemonTcpListener {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        init: InitSystem,
+        listen: SocketAddr,
+        start_daemon: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        if init == InitSystem::None {
+            return Err(Self::error(ConfigureDaemonTcpListenerError::NoInit));
+        }
+
+        warning::warn(
+            WarningKind::UnauthenticatedDaemon,
+            format!("The Nix daemon will accept unauthenticated connections on `{listen}`, anyone who can reach it can act as root through the daemon"),
+        );
+
+        Ok(Self {
+            init,
+            listen,
+            start_daemon,
+            written_files: vec![],
+        }
+        .into())
+    }
+
+    /// The files to write, and what to write to them
+    fn files(&self) -> Result<Vec<(PathBuf, Vec<u8>)>, ActionErrorKind> {
+        Ok(match self.init {
+            InitSystem::Systemd => systemd_units(self.listen)
+                .into_iter()
+                .map(|(dest, content)| (PathBuf::from(dest), content.into_bytes()))
+                .collect(),
+            InitSystem::Launchd => {
+                let mut buf = Vec::new();
+                plist::to_writer_xml(&mut buf, &launchd_plist(self.listen))?;
+                vec![(PathBuf::from(DARWIN_TCP_SERVICE_DEST), buf)]
+            },
+            InitSystem::None => vec![],
+        })
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_daemon_tcp_listener")]
+impl Action for ConfigureDaemonTcpListener {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_daemon_tcp_listener")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Expose the Nix daemon over TCP on `{}`, without authentication",
+            self.listen
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_daemon_tcp_listener",
+            listen = tracing::field::display(self.listen),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            "WARNING: anyone who can connect to this address is a trusted Nix user, able to act as root through the daemon".to_string(),
+        ];
+        match self.init {
+            InitSystem::Systemd => {
+                explanation.push(format!(
+                    "Create `{TCP_SOCKET_DEST}` and `{TCP_SERVICE_DEST}`"
+                ));
+                explanation.push("Run `systemctl daemon-reload`".to_string());
+                explanation.push(format!(
+                    "Run `systemctl enable {TCP_SOCKET_NAME}{}`",
+                    if self.start_daemon { " --now" } else { "" }
+                ));
+            },
+            InitSystem::Launchd => {
+                explanation.push(format!("Create `{DARWIN_TCP_SERVICE_DEST}`"));
+                explanation.push(format!(
+                    "Run `launchctl bootstrap {DARWIN_LAUNCHD_DOMAIN} {DARWIN_TCP_SERVICE_DEST}`"
+                ));
+            },
+            InitSystem::None => (),
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = vec![];
+        match self.init {
+            InitSystem::Systemd => {
+                operations.extend([
+                    PrivilegedOperation::write(TCP_SOCKET_DEST, None),
+                    PrivilegedOperation::write(TCP_SERVICE_DEST, None),
+                    PrivilegedOperation::command("systemctl", ["daemon-reload"]),
+                    PrivilegedOperation::service(TCP_SOCKET_NAME),
+                ]);
+                let mut enable = vec!["enable", TCP_SOCKET_NAME];
+                if self.start_daemon {
+                    enable.push("--now");
+                }
+                operations.push(PrivilegedOperation::command("systemctl", enable));
+            },
+            InitSystem::Launchd => {
+                operations.extend([
+                    PrivilegedOperation::write(DARWIN_TCP_SERVICE_DEST, None),
+                    PrivilegedOperation::service(DARWIN_TCP_SERVICE_NAME),
+                    PrivilegedOperation::command(
+                        "launchctl",
+                        ["bootstrap", DARWIN_LAUNCHD_DOMAIN, DARWIN_TCP_SERVICE_DEST],
+                    ),
+                ]);
+            },
+            InitSystem::None => (),
+        }
+        operations
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let files = self.files().map_err(Self::error)?;
+        self.written_files.clear();
+
+        for (path, content) in files {
+            let dest = crate::util::host_path(&path);
+            match self.init {
+                InitSystem::Systemd => {
+                    ConfigureInitService::check_if_systemd_unit_exists(
+                        &UnitSrc::Literal(String::from_utf8_lossy(&content).into_owned()),
+                        &dest,
+                    )
+                    .await
+                    .map_err(Self::error)?;
+                },
+                _ => {
+                    if let Ok(existing) = tokio::fs::read(&dest).await {
+                        if existing != content {
+                            return Err(Self::error(ActionErrorKind::DifferentContent(dest)));
+                        }
+                    }
+                },
+            }
+
+            tracing::trace!(dest = %dest.display(), "Writing");
+            tokio::fs::write(&dest, &content)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Write(dest.clone(), e)))?;
+            let written = WrittenFile::whole(path, &content);
+            crate::managed_files::register(Self::action_tag(), &written);
+            self.written_files.push(written);
+        }
+
+        match self.init {
+            InitSystem::Systemd => {
+                execute_command(
+                    Command::new("systemctl")
+                        .process_group(0)
+                        .arg("daemon-reload")
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                .map_err(Self::error)?;
+
+                let mut enable = Command::new("systemctl");
+                enable.process_group(0).args(["enable", TCP_SOCKET_NAME]);
+                if self.start_daemon {
+                    enable.arg("--now");
+                }
+                execute_command(enable.stdin(std::process::Stdio::null()))
+                    .await
+                    .map_err(Self::error)?;
+            },
+            InitSystem::Launchd => {
+                crate::action::macos::retry_bootstrap(
+                    DARWIN_LAUNCHD_DOMAIN,
+                    DARWIN_TCP_SERVICE_NAME,
+                    Path::new(DARWIN_TCP_SERVICE_DEST),
+                )
+                .await
+                .map_err(Self::error)?;
+            },
+            InitSystem::None => (),
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let explanation = match self.init {
+            InitSystem::Systemd => vec![
+                format!("Run `systemctl disable --now {TCP_SOCKET_NAME}`"),
+                format!("Delete `{TCP_SOCKET_DEST}` and `{TCP_SERVICE_DEST}`"),
+                "Run `systemctl daemon-reload`".to_string(),
+            ],
+            InitSystem::Launchd => vec![
+                format!(
+                    "Run `launchctl bootout {DARWIN_LAUNCHD_DOMAIN}/{DARWIN_TCP_SERVICE_NAME}`"
+                ),
+                format!("Delete `{DARWIN_TCP_SERVICE_DEST}`"),
+            ],
+            InitSystem::None => vec![],
+        };
+        vec![ActionDescription::new(
+            format!("Stop exposing the Nix daemon over TCP on `{}`", self.listen),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        match self.init {
+            InitSystem::Systemd => {
+                if let Err(err) = execute_command(
+                    Command::new("systemctl")
+                        .process_group(0)
+                        .args(["disable", "--now", TCP_SOCKET_NAME])
+                        .stdin(std::process::Stdio::null()),
+                )
+                .await
+                {
+                    errors.push(err);
+                }
+            },
+            InitSystem::Launchd => {
+                if let Err(err) = crate::action::macos::retry_bootout(
+                    DARWIN_LAUNCHD_DOMAIN,
+                    DARWIN_TCP_SERVICE_NAME,
+                )
+                .await
+                {
+                    errors.push(err);
+                }
+            },
+            InitSystem::None => (),
+        }
+
+        let dests = match self.init {
+            InitSystem::Systemd => vec![TCP_SOCKET_DEST, TCP_SERVICE_DEST],
+            InitSystem::Launchd => vec![DARWIN_TCP_SERVICE_DEST],
+            InitSystem::None => vec![],
+        };
+        for dest in dests {
+            let dest = crate::util::host_path(dest);
+            if let Err(err) = crate::util::remove_file(&dest, OnMissing::Ignore)
+                .await
+                .map_err(|e| ActionErrorKind::Remove(dest.clone(), e))
+            {
+                errors.push(err);
+            }
+        }
+
+        if self.init == InitSystem::Systemd {
+            if let Err(err) = execute_command(
+                Command::new("systemctl")
+                    .process_group(0)
+                    .arg("daemon-reload")
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            {
+                errors.push(err);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(Self::error(
+                errors
+                    .into_iter()
+                    .next()
+                    .expect("Expected 1 len Vec to have at least 1 item"),
+            ))
+        } else {
+            Err(Self::error(ActionErrorKind::Multiple(errors)))
+        }
+    }
+}
+
+/// The socket unit listening on `listen`, and the service template it starts for each connection
+pub(crate) fn systemd_units(listen: SocketAddr) -> [(&'static str, String); 2] {
+    [
+        (
+            TCP_SOCKET_DEST,
+            format!(
+                "\
+                [Unit]\n\
+                Description=Nix Daemon over TCP (unauthenticated)\n\
+                \n\
+                [Socket]\n\
+                ListenStream={listen}\n\
+                Accept=yes\n\
+                \n\
+                [Install]\n\
+                WantedBy=sockets.target\n\
+                "
+            ),
+        ),
+        (
+            TCP_SERVICE_DEST,
+            format!(
+                "\
+                [Unit]\n\
+                Description=Nix Daemon connection over TCP\n\
+                After=nix-daemon.socket\n\
+                \n\
+                [Service]\n\
+                ExecStart=@{NIX_DAEMON_BIN} nix-daemon --stdio\n\
+                StandardInput=socket\n\
+                StandardOutput=socket\n\
+                StandardError=journal\n\
+                "
+            ),
+        ),
+    ]
+}
+
+/// An inetd-style launchd service listening on `listen`
+pub(crate) fn launchd_plist(listen: SocketAddr) -> DaemonTcpListenerPlist {
+    DaemonTcpListenerPlist {
+        label: DARWIN_TCP_SERVICE_NAME.into(),
+        program_arguments: vec![NIX_DAEMON_BIN.into(), "--stdio".into()],
+        inetd_compatibility: InetdCompatibility { wait: false },
+        sockets: Sockets {
+            listeners: Listener {
+                sock_node_name: listen.ip().to_string(),
+                sock_service_name: listen.port().to_string(),
+                sock_type: "stream".into(),
+                sock_family: match listen {
+                    SocketAddr::V4(_) => "IPv4".into(),
+                    SocketAddr::V6(_) => "IPv6".into(),
+                },
+            },
+        },
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug, serde::Serialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct DaemonTcpListenerPlist {
+    label: String,
+    program_arguments: Vec<String>,
+    #[serde(rename = "inetdCompatibility")]
+    inetd_compatibility: InetdCompatibility,
+    sockets: Sockets,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, serde::Serialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct InetdCompatibility {
+    wait: bool,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, serde::Serialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Sockets {
+    listeners: Listener,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, serde::Serialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Listener {
+    sock_node_name: String,
+    sock_service_name: String,
+    sock_type: String,
+    sock_family: String,
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigureDaemonTcpListenerError {
+    #[error("Exposing the Nix daemon over TCP requires an init system to listen on the socket")]
+    NoInit,
+}
+
+impl From<ConfigureDaemonTcpListenerError> for ActionErrorKind {
+    fn from(val: ConfigureDaemonTcpListenerError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_harness::{FakeCommand, Invocation, SandboxContext};
+
+    #[test]
+    fn systemd_units_listen_on_the_address() {
+        let [(socket_dest, socket), (service_dest, service)] =
+            systemd_units("10.0.0.5:9000".parse().unwrap());
+        assert_eq!(socket_dest, TCP_SOCKET_DEST);
+        assert!(socket.contains("\nListenStream=10.0.0.5:9000\nAccept=yes\n"));
+        assert_eq!(service_dest, TCP_SERVICE_DEST);
+        assert!(service.contains(
+            "\nExecStart=@/nix/var/nix/profiles/default/bin/nix-daemon nix-daemon --stdio\n"
+        ));
+
+        let [(_, socket), _] = systemd_units("[fd00::5]:9000".parse().unwrap());
+        assert!(socket.contains("\nListenStream=[fd00::5]:9000\n"));
+    }
+
+    #[test]
+    fn launchd_plist_is_inetd_style() -> eyre::Result<()> {
+        let mut buf = Vec::new();
+        plist::to_writer_xml(&mut buf, &launchd_plist("[fd00::5]:9000".parse()?))?;
+        let plist: plist::Value = plist::from_bytes(&buf)?;
+        let plist = plist.as_dictionary().unwrap();
+        assert_eq!(
+            plist.get("inetdCompatibility"),
+            Some(&plist::Value::Dictionary(
+                [("Wait".to_string(), plist::Value::Boolean(false))]
+                    .into_iter()
+                    .collect()
+            ))
+        );
+        let listener = plist
+            .get("Sockets")
+            .and_then(|v| v.as_dictionary())
+            .and_then(|v| v.get("Listeners"))
+            .and_then(|v| v.as_dictionary())
+            .unwrap();
+        assert_eq!(
+            listener.get("SockNodeName").and_then(|v| v.as_string()),
+            Some("fd00::5")
+        );
+        assert_eq!(
+            listener.get("SockServiceName").and_then(|v| v.as_string()),
+            Some("9000")
+        );
+        assert_eq!(
+            listener.get("SockFamily").and_then(|v| v.as_string()),
+            Some("IPv6")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn places_and_removes_systemd_units() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/etc/systemd/system"))?;
+        sandbox.fake("systemctl", FakeCommand::success());
+
+        let mut actions = vec![sandbox
+            .scope(ConfigureDaemonTcpListener::plan(
+                InitSystem::Systemd,
+                "10.0.0.5:9000".parse()?,
+                true,
+            ))
+            .await?
+            .boxed()];
+        sandbox.execute(&mut actions).await?;
+
+        let socket = tokio::fs::read_to_string(sandbox.path(TCP_SOCKET_DEST)).await?;
+        assert!(socket.contains("ListenStream=10.0.0.5:9000"));
+        assert!(sandbox.path(TCP_SERVICE_DEST).exists());
+        assert!(sandbox
+            .invocations_of("systemctl")
+            .contains(&Invocation::new(
+                "systemctl",
+                ["enable", TCP_SOCKET_NAME, "--now"]
+            )));
+        let receipt = serde_json::to_value(&actions)?;
+        assert_eq!(crate::drift::written_files(&receipt).len(), 2);
+
+        sandbox.revert(&mut actions).await?;
+        assert!(!sandbox.path(TCP_SOCKET_DEST).exists());
+        assert!(!sandbox.path(TCP_SERVICE_DEST).exists());
+        assert!(sandbox
+            .invocations_of("systemctl")
+            .contains(&Invocation::new(
+                "systemctl",
+                ["disable", "--now", TCP_SOCKET_NAME]
+            )));
+        Ok(())
+    }
+}
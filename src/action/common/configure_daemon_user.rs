@@ -0,0 +1,253 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use tracing::{span, Span};
+
+use crate::action::base::{CreateUser, UserShellAndHome};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::settings::{CommonSettings, DaemonUser};
+use crate::util::host_path;
+
+use super::provision_nix::NIX_STORE_LOCATION;
+
+/// Everything the daemon writes outside of the store
+const NIX_VAR_LOCATION: &str = "/nix/var";
+
+/**
+Create the non-root user the Nix daemon runs as, and hand it the Nix store and `/nix/var`
+
+Only what `root` owns is handed over, so the per-user profiles and roots keep their owners. On
+revert, what the user owns is handed back to `root`, and the user is deleted if it was created.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "configure_daemon_user")]
+pub struct ConfigureDaemonUser {
+    pub(crate) user: DaemonUser,
+    create_user: StatefulAction<CreateUser>,
+}
+
+impl ConfigureDaemonUser {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(settings: &CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+        let user = settings
+            .daemon_user()
+            .ok_or_else(|| Self::error(ConfigureDaemonUserError::NoDaemonUser))?;
+        let create_user = CreateUser::plan(
+            user.name.clone(),
+            user.uid,
+            user.group.clone(),
+            user.gid,
+            "Nix daemon".into(),
+            UserShellAndHome {
+                shell: settings.nix_build_user_shell.clone(),
+                home: settings.nix_build_user_home.clone(),
+                create_home: false,
+            },
+            true,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(Self { user, create_user }.into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_daemon_user")]
+impl Action for ConfigureDaemonUser {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_daemon_user")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Run the Nix daemon as `{}` (UID {}) instead of `root`",
+            self.user.name, self.user.uid
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_daemon_user",
+            user = self.user.name,
+            uid = self.user.uid,
+            gid = self.user.gid,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            "EXPERIMENTAL: builds run as this user, rather than as the build users".to_string(),
+        ];
+        explanation.extend(
+            self.create_user
+                .describe_execute()
+                .into_iter()
+                .map(|description| description.description),
+        );
+        explanation.push(format!(
+            "Hand what `root` owns in `{NIX_STORE_LOCATION}` and `{NIX_VAR_LOCATION}` to `{}`",
+            self.user.name
+        ));
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self.create_user.privileged_operations();
+        operations.extend([
+            PrivilegedOperation::write(NIX_STORE_LOCATION, None),
+            PrivilegedOperation::write(NIX_VAR_LOCATION, None),
+        ]);
+        operations
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_user.try_execute().await.map_err(Self::error)?;
+
+        let DaemonUser { uid, gid, .. } = self.user;
+        // The store keeps the Nix build group, which is the daemon user's group
+        reown(Path::new(NIX_STORE_LOCATION), 0, uid, None);
+        reown(Path::new(NIX_VAR_LOCATION), 0, uid, Some(gid));
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!(
+            "Hand what `{}` owns in `{NIX_STORE_LOCATION}` and `{NIX_VAR_LOCATION}` back to `root`",
+            self.user.name
+        )];
+        if !self.create_user.inner().update_existing {
+            explanation.extend(
+                self.create_user
+                    .describe_revert()
+                    .into_iter()
+                    .map(|description| description.description),
+            );
+        }
+        vec![ActionDescription::new(
+            format!(
+                "Run the Nix daemon as `root` instead of `{}`",
+                self.user.name
+            ),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let uid = self.user.uid;
+        reown(Path::new(NIX_STORE_LOCATION), uid, 0, None);
+        reown(Path::new(NIX_VAR_LOCATION), uid, 0, Some(0));
+
+        // A user which existed before the install is left, only its shell and home were updated
+        if !self.create_user.inner().update_existing {
+            self.create_user.try_revert().await.map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The user a `receipt` configured the daemon to run as, if any
+pub(crate) fn daemon_user_of(receipt: &serde_json::Value) -> Option<DaemonUser> {
+    receipt
+        .get("actions")?
+        .as_array()?
+        .iter()
+        .filter(|action| action.get("state").and_then(|v| v.as_str()) == Some("Completed"))
+        .filter_map(|action| action.get("action"))
+        .find(|action| {
+            action.get("action_name").and_then(|v| v.as_str()) == Some("configure_daemon_user")
+        })
+        .and_then(|action| serde_json::from_value(action.get("user")?.clone()).ok())
+}
+
+/// Change the owner of everything under `path` owned by `from` to `to`, and the group to `gid`
+///
+/// Like the other ownership fixes, a path which cannot be changed is only warned about.
+fn reown(path: &Path, from: u32, to: u32, gid: Option<u32>) {
+    let entries = walkdir::WalkDir::new(host_path(path))
+        .follow_links(false)
+        .same_file_system(true)
+        .contents_first(true)
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                tracing::warn!(%e, "Failed to get entry in `{}`", path.display());
+                None
+            },
+        })
+        .filter(|entry| {
+            entry
+                .metadata()
+                .is_ok_and(|metadata| metadata.uid() == from)
+        });
+    for entry in entries {
+        if let Err(e) = std::os::unix::fs::lchown(entry.path(), Some(to), gid) {
+            tracing::warn!(
+                path = %entry.path().to_string_lossy(),
+                %e,
+                "Failed to set the owner to {to}"
+            );
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigureDaemonUserError {
+    #[error("No daemon user was set, pass `--daemon-user`")]
+    NoDaemonUser,
+}
+
+impl From<ConfigureDaemonUserError> for ActionErrorKind {
+    fn from(val: ConfigureDaemonUserError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    use nix::unistd::{Gid, Uid};
+
+    use super::reown;
+    use crate::test_harness::SandboxContext;
+
+    #[tokio::test]
+    async fn only_reowns_what_the_old_owner_owns() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/nix/var/nix/db"))?;
+        std::fs::write(sandbox.path("/nix/var/nix/db/db.sqlite"), "")?;
+        let current = Uid::effective().as_raw();
+        // Only root can give the files away
+        let (uid, gid) = match Uid::effective().is_root() {
+            true => (12345, 12345),
+            false => (current, Gid::effective().as_raw()),
+        };
+        let owner = || -> std::io::Result<(u32, u32)> {
+            let metadata = std::fs::metadata(sandbox.path("/nix/var/nix/db/db.sqlite"))?;
+            Ok((metadata.uid(), metadata.gid()))
+        };
+        let original = owner()?;
+
+        sandbox
+            .scope(async { reown(Path::new("/nix/var"), current + 1, uid, Some(gid)) })
+            .await;
+        assert_eq!(owner()?, original);
+
+        sandbox
+            .scope(async { reown(Path::new("/nix/var"), current, uid, Some(gid)) })
+            .await;
+        assert_eq!(owner()?, (uid, gid));
+        Ok(())
+    }
+}
@@ -5,10 +5,13 @@ use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
 use tracing::{span, Span};
 
-use crate::action::common::configure_init_service::{SocketFile, UnitSrc};
-use crate::action::{common::ConfigureInitService, Action, ActionDescription};
+use crate::action::common::configure_init_service::{
+    add_service_settings, launchd_settings_list, systemd_settings_list, SocketFile, UnitSrc,
+};
+use crate::action::{common::ConfigureInitService, Action, ActionDescription, PrivilegedOperation};
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
-use crate::settings::InitSystem;
+use crate::drift::WrittenFile;
+use crate::settings::{DaemonResources, InitSystem};
 use crate::util::OnMissing;
 
 // Linux
@@ -17,7 +20,7 @@ const LINUX_NIXD_DAEMON_DEST: &str = "/etc/systemd/system/nix-daemon.service";
 // Darwin
 pub(crate) const DARWIN_NIXD_DAEMON_DEST: &str =
     "/Library/LaunchDaemons/systems.determinate.nix-daemon.plist";
-const DARWIN_NIXD_SERVICE_NAME: &str = "systems.determinate.nix-daemon";
+pub(crate) const DARWIN_NIXD_SERVICE_NAME: &str = "systems.determinate.nix-daemon";
 
 /**
 Configure the init to run the Nix daemon
@@ -30,6 +33,9 @@ Configure the init to run the Nix daemon
 pub struct ConfigureDeterminateNixdInitService {
     init: InitSystem,
     configure_init_service: StatefulAction<ConfigureInitService>,
+    /// The daemon plist or unit, once executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    written_file: Option<WrittenFile>,
 }
 
 impl ConfigureDeterminateNixdInitService {
@@ -89,6 +95,7 @@ impl ConfigureDeterminateNixdInitService {
                     dest: "/etc/systemd/system/determinate-nixd.socket".into(),
                 },
             ],
+            None,
         )
         .await
         .map_err(Self::error)?;
@@ -96,11 +103,28 @@ impl ConfigureDeterminateNixdInitService {
         Ok(Self {
             init,
             configure_init_service,
+            written_file: None,
         }
         .into())
     }
 }
 
+impl StatefulAction<ConfigureDeterminateNixdInitService> {
+    /// Have the init schedule the daemon as `resources` says, see [`DaemonResources`]
+    pub(crate) fn with_resources(mut self, resources: DaemonResources) -> Self {
+        self.action.configure_init_service =
+            self.action.configure_init_service.with_resources(resources);
+        self
+    }
+}
+
+impl ConfigureDeterminateNixdInitService {
+    /// How the init schedules the daemon, recorded with the service it configures
+    fn resources(&self) -> DaemonResources {
+        self.configure_init_service.inner().resources
+    }
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "configure_determinate_nixd_init_service")]
 impl Action for ConfigureDeterminateNixdInitService {
@@ -114,22 +138,49 @@ impl Action for ConfigureDeterminateNixdInitService {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "configure_determinate_nixd_init_service"
+            "configure_determinate_nixd_init_service",
+            init = %self.init,
         )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(
-            self.tracing_synopsis(),
-            vec![self.configure_init_service.tracing_synopsis()],
-        )]
+        let mut explanation = vec![self.configure_init_service.tracing_synopsis()];
+        let resources = self.resources();
+        if !resources.is_unset() {
+            match self.init {
+                InitSystem::Launchd => {
+                    let scheduled = launchd_settings_list(&resources);
+                    if !scheduled.is_empty() {
+                        explanation.push(format!("Set {scheduled} in `{DARWIN_NIXD_DAEMON_DEST}`"));
+                    }
+                },
+                InitSystem::Systemd => explanation.push(format!(
+                    "Write {} to `{LINUX_NIXD_DAEMON_DEST}`",
+                    systemd_settings_list(&resources)
+                )),
+                InitSystem::None => (),
+            }
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = match self.init {
+            InitSystem::Launchd => vec![PrivilegedOperation::write(DARWIN_NIXD_DAEMON_DEST, None)],
+            InitSystem::Systemd => vec![PrivilegedOperation::write(LINUX_NIXD_DAEMON_DEST, None)],
+            InitSystem::None => vec![],
+        };
+        operations.append(&mut self.configure_init_service.privileged_operations());
+        operations
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        let resources = self.resources();
         let Self {
             init,
             configure_init_service,
+            written_file,
         } = self;
 
         if *init == InitSystem::Launchd {
@@ -137,7 +188,7 @@ impl Action for ConfigureDeterminateNixdInitService {
 
             // This is the only part that is actually different from configure_init_service, beyond variable parameters.
 
-            let generated_plist = generate_plist();
+            let generated_plist = generate_plist(&resources);
 
             let mut options = tokio::fs::OpenOptions::new();
             options.create(true).write(true).read(true);
@@ -152,16 +203,24 @@ impl Action for ConfigureDeterminateNixdInitService {
             file.write_all(&buf)
                 .await
                 .map_err(|e| Self::error(ActionErrorKind::Write(PathBuf::from(daemon_file), e)))?;
+            let written = WrittenFile::whole(daemon_file, &buf);
+            crate::managed_files::register(Self::action_tag(), &written);
+            *written_file = Some(written);
         } else if *init == InitSystem::Systemd {
             let daemon_file = PathBuf::from(LINUX_NIXD_DAEMON_DEST);
 
-            tokio::fs::write(
-                &daemon_file,
-                include_str!("./nix-daemon.determinate-nixd.service"),
-            )
-            .await
-            .map_err(|e| ActionErrorKind::Write(daemon_file.clone(), e))
-            .map_err(Self::error)?;
+            let unit = include_str!("./nix-daemon.determinate-nixd.service");
+            let unit = match resources.is_unset() {
+                true => unit.to_string(),
+                false => add_service_settings(unit, &resources.systemd_settings()),
+            };
+            tokio::fs::write(&daemon_file, &unit)
+                .await
+                .map_err(|e| ActionErrorKind::Write(daemon_file.clone(), e))
+                .map_err(Self::error)?;
+            let written = WrittenFile::whole(daemon_file, unit.as_bytes());
+            crate::managed_files::register(Self::action_tag(), &written);
+            *written_file = Some(written);
         }
 
         configure_init_service
@@ -198,6 +257,10 @@ pub struct DeterminateNixDaemonPlist {
     standard_out_path: String,
     soft_resource_limits: ResourceLimits,
     hard_resource_limits: ResourceLimits,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nice: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    process_type: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
@@ -222,7 +285,7 @@ enum SocketFamily {
     Unix,
 }
 
-fn generate_plist() -> DeterminateNixDaemonPlist {
+fn generate_plist(resources: &DaemonResources) -> DeterminateNixDaemonPlist {
     DeterminateNixDaemonPlist {
         run_at_load: false,
         label: "systems.determinate.nix-daemon".into(),
@@ -239,6 +302,8 @@ fn generate_plist() -> DeterminateNixDaemonPlist {
             number_of_processes: 1024 * 1024,
             stack: 64 * 1024 * 1024,
         },
+        nice: resources.nice.map(i64::from),
+        process_type: resources.launchd_process_type().map(String::from),
         sockets: HashMap::from([
             (
                 "determinate-nixd.socket".to_string(),
@@ -259,3 +324,31 @@ fn generate_plist() -> DeterminateNixDaemonPlist {
         ]),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::generate_plist;
+    use crate::settings::DaemonResources;
+
+    fn rendered(resources: &DaemonResources) -> eyre::Result<String> {
+        let mut buf = Vec::new();
+        plist::to_writer_xml(&mut buf, &generate_plist(resources))?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    #[test]
+    fn plist_is_only_scheduled_when_asked() -> eyre::Result<()> {
+        let unscheduled = rendered(&DaemonResources::default())?;
+        assert!(!unscheduled.contains("<key>Nice</key>"));
+        assert!(!unscheduled.contains("<key>ProcessType</key>"));
+
+        let scheduled = rendered(&DaemonResources {
+            nice: Some(10),
+            cpu_weight: Some(50),
+            io_weight: None,
+        })?;
+        assert!(scheduled.contains("<key>Nice</key>\n\t<integer>10</integer>"));
+        assert!(scheduled.contains("<key>ProcessType</key>\n\t<string>Background</string>"));
+        Ok(())
+    }
+}
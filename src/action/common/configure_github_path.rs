@@ -0,0 +1,149 @@
+use std::path::Path;
+
+use nix::unistd::User;
+use tracing::{span, Span};
+
+use crate::action::base::{create_or_insert_into_file, CreateOrInsertIntoFile};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionTag, PrivilegedOperation, StatefulAction,
+};
+
+/**
+Add the default profile to the `$PATH` of the later steps of a GitHub Actions job
+
+GitHub Actions adds each line of the file named by `$GITHUB_PATH` to the `$PATH` of the steps
+after the one writing it. On revert, only the lines added are removed.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "configure_github_path")]
+pub struct ConfigureGithubPath {
+    insert_into_github_path: StatefulAction<CreateOrInsertIntoFile>,
+}
+
+impl ConfigureGithubPath {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(github_path: impl AsRef<Path>) -> Result<StatefulAction<Self>, ActionError> {
+        let insert_into_github_path = CreateOrInsertIntoFile::plan(
+            github_path,
+            None,
+            None,
+            // We want the `nix-installer-action` to not error if it writes here.
+            // Prior to `v5` this was done in this crate, in `v5` and later, this is done in the action.
+            0o777,
+            github_path_entries(),
+            create_or_insert_into_file::Position::End,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(Self {
+            insert_into_github_path,
+        }
+        .into())
+    }
+}
+
+/// The lines added to the file named by `$GITHUB_PATH`
+pub(crate) fn github_path_entries() -> String {
+    let mut buf = "/nix/var/nix/profiles/default/bin\n".to_string();
+    // Actions runners operate as `runner` user by default
+    if let Ok(Some(runner)) = User::from_name("runner") {
+        #[cfg(target_os = "linux")]
+        let path = format!("/home/{}/.nix-profile/bin\n", runner.name);
+        #[cfg(target_os = "macos")]
+        let path = format!("/Users/{}/.nix-profile/bin\n", runner.name);
+        buf += &path;
+    }
+    buf
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_github_path")]
+impl Action for ConfigureGithubPath {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_github_path")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Add the Nix profile to `$PATH` in `{}` for the later steps of the GitHub Actions job",
+            self.insert_into_github_path.inner().path.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_github_path",
+            path = tracing::field::display(self.insert_into_github_path.inner().path.display()),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec!["The runner does not log in again, so shell profiles are not used".to_string()],
+        )]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.insert_into_github_path.privileged_operations()
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.insert_into_github_path
+            .try_execute()
+            .await
+            .map_err(Self::error)
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!(
+                "Remove the Nix profile from `{}`",
+                self.insert_into_github_path.inner().path.display()
+            ),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        self.insert_into_github_path
+            .try_revert()
+            .await
+            .map_err(Self::error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConfigureGithubPath;
+    use crate::test_harness::SandboxContext;
+
+    #[tokio::test]
+    async fn appends_to_github_path_and_removes_only_its_lines() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let github_path = sandbox.path("/runner/_temp/_runner_file_commands/add_path");
+        std::fs::create_dir_all(github_path.parent().unwrap())?;
+        std::fs::write(&github_path, "/opt/hostedtoolcache/node/bin\n")?;
+
+        let mut action = sandbox
+            .scope(ConfigureGithubPath::plan(&github_path))
+            .await?
+            .boxed();
+        sandbox.execute(std::slice::from_mut(&mut action)).await?;
+        let content = std::fs::read_to_string(&github_path)?;
+        assert!(content.starts_with("/opt/hostedtoolcache/node/bin\n"));
+        assert!(content
+            .lines()
+            .any(|line| line == "/nix/var/nix/profiles/default/bin"));
+
+        sandbox.revert(std::slice::from_mut(&mut action)).await?;
+        assert_eq!(
+            std::fs::read_to_string(&github_path)?,
+            "/opt/hostedtoolcache/node/bin\n"
+        );
+        Ok(())
+    }
+}
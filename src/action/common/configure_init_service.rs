@@ -1,3 +1,4 @@
+use std::ffi::OsStr;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -8,8 +9,9 @@ use crate::action::macos::DARWIN_LAUNCHD_DOMAIN;
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription};
-use crate::settings::InitSystem;
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
+use crate::drift::WrittenFile;
+use crate::settings::{DaemonResources, DaemonUser, InitSystem};
 use crate::util::OnMissing;
 
 const TMPFILES_SRC: &str = "/nix/var/nix/profiles/default/lib/tmpfiles.d/nix-daemon.conf";
@@ -28,19 +30,103 @@ pub enum UnitSrc {
     Literal(String),
 }
 
+/// The systemd manager the units are configured in
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitScope {
+    #[default]
+    System,
+    /// The user manager of `user`, which is made to linger so the daemon runs without a login
+    User { user: String, uid: u32 },
+}
+
+impl UnitScope {
+    pub(crate) fn is_system(&self) -> bool {
+        *self == UnitScope::System
+    }
+
+    /// The arguments `systemctl` needs before its command to reach this manager
+    ///
+    /// `--machine <user>@` reaches the user manager of `user` from `root`, without a session.
+    pub(crate) fn systemctl_args(&self) -> Vec<String> {
+        match self {
+            UnitScope::System => vec![],
+            UnitScope::User { user, .. } => {
+                vec!["--user".into(), "--machine".into(), format!("{user}@")]
+            },
+        }
+    }
+
+    /// `systemctl`, reaching this manager
+    fn systemctl(&self) -> Command {
+        let mut command = Command::new("systemctl");
+        command.args(self.systemctl_args());
+        command
+    }
+
+    /// `systemctl` with `args`, reaching this manager, as a [`PrivilegedOperation`]
+    fn systemctl_operation<'a>(
+        &self,
+        args: impl IntoIterator<Item = &'a str>,
+    ) -> PrivilegedOperation {
+        let mut all = self.systemctl_args();
+        all.extend(args.into_iter().map(String::from));
+        PrivilegedOperation::command("systemctl", all)
+    }
+
+    /// How `systemctl` is run for this manager, for descriptions
+    fn systemctl_display(&self) -> String {
+        std::iter::once("systemctl".to_string())
+            .chain(self.systemctl_args())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// How systemd starts the daemon
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceMode {
+    /// Started by its socket units when a client first connects
+    #[default]
+    SocketActivated,
+    /// Started at boot and kept running, for a daemon which cannot be socket activated
+    AlwaysRunning,
+}
+
 /**
 Configure the init to run the Nix daemon
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "configure_init_service")]
 pub struct ConfigureInitService {
-    init: InitSystem,
+    pub(crate) init: InitSystem,
     start_daemon: bool,
     // TODO(cole-h): make an enum so we can distinguish between "written out by another step" vs "actually there isn't one"
     service_src: Option<PathBuf>,
     service_name: Option<String>,
     service_dest: Option<PathBuf>,
     socket_files: Vec<SocketFile>,
+    /// The plist and units written, rather than symlinked, once executed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    written_files: Vec<WrittenFile>,
+    /// The non-root user the daemon runs as, the service is then written rather than symlinked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    daemon_user: Option<DaemonUser>,
+    /// The systemd manager the units are in
+    #[serde(default, skip_serializing_if = "UnitScope::is_system")]
+    scope: UnitScope,
+    /// If executing enabled lingering for the user of a [`UnitScope::User`], so reverting disables it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    enabled_linger: bool,
+    /// How systemd starts the daemon, absent from receipts written before it could be anything but
+    /// socket activated
+    #[serde(default)]
+    service_mode: ServiceMode,
+    /// How the init schedules the daemon, rendered into the service this writes, the service is
+    /// then written rather than symlinked
+    #[serde(default, skip_serializing_if = "DaemonResources::is_unset")]
+    pub(crate) resources: DaemonResources,
 }
 
 impl ConfigureInitService {
@@ -98,6 +184,7 @@ impl ConfigureInitService {
         service_dest: Option<PathBuf>,
         service_name: Option<String>,
         socket_files: Vec<SocketFile>,
+        daemon_user: Option<DaemonUser>,
     ) -> Result<StatefulAction<Self>, ActionError> {
         match init {
             InitSystem::Launchd => {
@@ -109,12 +196,12 @@ impl ConfigureInitService {
                 if start_daemon {
                     // If /run/systemd/system exists, we can be reasonably sure the machine is booted
                     // with systemd: https://www.freedesktop.org/software/systemd/man/sd_booted.html
-                    if !Path::new("/run/systemd/system").exists() {
+                    if !crate::util::host_path("/run/systemd/system").exists() {
                         return Err(Self::error(ActionErrorKind::SystemdMissing));
                     }
                 }
 
-                if which::which("systemctl").is_err() {
+                if crate::util::which("systemctl").is_err() {
                     return Err(Self::error(ActionErrorKind::SystemdMissing));
                 }
             },
@@ -123,6 +210,21 @@ impl ConfigureInitService {
             },
         };
 
+        // Generated socket units listen under `/run`, never its legacy `/var/run` alias
+        let socket_files = socket_files
+            .into_iter()
+            .map(|SocketFile { name, src, dest }| SocketFile {
+                name,
+                src: match src {
+                    UnitSrc::Literal(content) => {
+                        UnitSrc::Literal(crate::daemon_socket::migrate_unit(&content))
+                    },
+                    src => src,
+                },
+                dest,
+            })
+            .collect();
+
         Ok(Self {
             init,
             start_daemon,
@@ -130,9 +232,66 @@ impl ConfigureInitService {
             service_dest,
             service_name,
             socket_files,
+            written_files: vec![],
+            daemon_user,
+            scope: UnitScope::System,
+            enabled_linger: false,
+            service_mode: ServiceMode::SocketActivated,
+            resources: DaemonResources::default(),
         }
         .into())
     }
+
+    /// If the service is rendered and written, rather than symlinked from the profile
+    fn writes_service(&self) -> bool {
+        self.daemon_user.is_some() || !self.resources.is_unset()
+    }
+}
+
+impl StatefulAction<ConfigureInitService> {
+    /// Configure the units in the user manager of `user` rather than the system manager
+    ///
+    /// The service is placed in `unit_dir`, a directory the user manager loads units from like
+    /// `~/.config/systemd/user`, and `socket_files` replace the sockets, which can't listen where
+    /// the system ones do.
+    pub(crate) fn in_user_manager(
+        mut self,
+        user: impl Into<String>,
+        uid: u32,
+        unit_dir: &Path,
+        socket_files: Vec<SocketFile>,
+    ) -> Self {
+        self.action.scope = UnitScope::User {
+            user: user.into(),
+            uid,
+        };
+        self.action.service_dest =
+            self.action
+                .service_dest
+                .take()
+                .map(|dest| match dest.file_name() {
+                    Some(name) => unit_dir.join(name),
+                    None => dest,
+                });
+        self.action.socket_files = socket_files;
+        self
+    }
+
+    /// Start the daemon as `service_mode` says, an [`ServiceMode::AlwaysRunning`] daemon has no
+    /// socket units
+    pub(crate) fn with_service_mode(mut self, service_mode: ServiceMode) -> Self {
+        self.action.service_mode = service_mode;
+        if service_mode == ServiceMode::AlwaysRunning {
+            self.action.socket_files.clear();
+        }
+        self
+    }
+
+    /// Have the init schedule the daemon as `resources` says
+    pub(crate) fn with_resources(mut self, resources: DaemonResources) -> Self {
+        self.action.resources = resources;
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -142,37 +301,79 @@ impl Action for ConfigureInitService {
         ActionTag("configure_init_service")
     }
     fn tracing_synopsis(&self) -> String {
-        match self.init {
-            InitSystem::Systemd => "Configure Nix daemon related settings with systemd".to_string(),
-            InitSystem::Launchd => {
+        match (&self.init, &self.scope) {
+            (InitSystem::Systemd, scope) => {
+                let manager = match scope {
+                    UnitScope::User { user, .. } => format!("the systemd user manager of `{user}`"),
+                    UnitScope::System => "systemd".to_string(),
+                };
+                match self.service_mode {
+                    ServiceMode::SocketActivated => {
+                        format!("Configure Nix daemon related settings with {manager}")
+                    },
+                    ServiceMode::AlwaysRunning => format!(
+                        "Configure Nix daemon related settings with {manager}, running the daemon always rather than socket activated"
+                    ),
+                }
+            },
+            (InitSystem::Launchd, _) => {
                 "Configure Nix daemon related settings with launchctl".to_string()
             },
-            InitSystem::None => "Leave the Nix daemon unconfigured".to_string(),
+            (InitSystem::None, _) => "Leave the Nix daemon unconfigured".to_string(),
         }
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "configure_init_service")
+        span!(
+            tracing::Level::DEBUG,
+            "configure_init_service",
+            init = %self.init,
+            start_daemon = self.start_daemon,
+            service_mode = ?self.service_mode,
+            service_dest = self
+                .service_dest
+                .as_ref()
+                .map(|v| tracing::field::display(v.display())),
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
         let mut vec = Vec::new();
         match self.init {
             InitSystem::Systemd => {
-                let mut explanation = vec![
-                    "Run `systemd-tmpfiles --create --prefix=/nix/var/nix`".to_string(),
-                    format!(
-                        "Symlink `{0}` to `{1}`",
-                        self.service_src
-                            .as_ref()
-                            .expect("service_src should be defined for systemd")
-                            .display(),
-                        self.service_dest
-                            .as_ref()
-                            .expect("service_src should be defined for systemd")
-                            .display()
+                let service_src = self
+                    .service_src
+                    .as_ref()
+                    .expect("service_src should be defined for systemd")
+                    .display();
+                let service_dest = self
+                    .service_dest
+                    .as_ref()
+                    .expect("service_src should be defined for systemd")
+                    .display();
+                let systemctl = self.scope.systemctl_display();
+                let mut explanation = vec![];
+                match &self.scope {
+                    UnitScope::System => explanation
+                        .push("Run `systemd-tmpfiles --create --prefix=/nix/var/nix`".to_string()),
+                    UnitScope::User { user, .. } => explanation.push(format!(
+                        "Run `loginctl enable-linger {user}`, unless `{user}` already lingers"
+                    )),
+                }
+                let scheduled = match self.resources.is_unset() {
+                    true => String::new(),
+                    false => format!(", with {}", systemd_settings_list(&self.resources)),
+                };
+                explanation.push(match (&self.daemon_user, self.writes_service()) {
+                    (Some(user), _) => format!(
+                        "Write `{service_src}` to `{service_dest}`, running as `{}`{scheduled}",
+                        user.name
                     ),
-                ];
+                    (None, true) => {
+                        format!("Write `{service_src}` to `{service_dest}`{scheduled}")
+                    },
+                    (None, false) => format!("Symlink `{service_src}` to `{service_dest}`"),
+                });
 
                 for SocketFile { src, dest, .. } in self.socket_files.iter() {
                     match src {
@@ -188,13 +389,19 @@ impl Action for ConfigureInitService {
                         },
                     }
                 }
-                explanation.push("Run `systemctl daemon-reload`".to_string());
+                explanation.push(format!("Run `{systemctl} daemon-reload`"));
 
                 if self.start_daemon {
                     for SocketFile { name, .. } in self.socket_files.iter() {
-                        explanation.push(format!("Run `systemctl enable --now {}`", name));
+                        explanation.push(format!("Run `{systemctl} enable --now {}`", name));
                     }
                 }
+                if self.service_mode == ServiceMode::AlwaysRunning {
+                    explanation.push(format!(
+                        "Run `{systemctl} enable{} nix-daemon.service`",
+                        if self.start_daemon { " --now" } else { "" }
+                    ));
+                }
                 vec.push(ActionDescription::new(self.tracing_synopsis(), explanation))
             },
             InitSystem::Launchd => {
@@ -208,6 +415,13 @@ impl Action for ConfigureInitService {
                             .expect("service_dest should be defined for launchd")
                             .display(),
                     ));
+                    if let Some(user) = &self.daemon_user {
+                        explanation.push(format!("Run the daemon as `{}`", user.name));
+                    }
+                    let scheduled = launchd_settings_list(&self.resources);
+                    if !scheduled.is_empty() {
+                        explanation.push(format!("Set {scheduled} in the plist"));
+                    }
                 }
 
                 if self.start_daemon {
@@ -226,8 +440,113 @@ impl Action for ConfigureInitService {
         vec
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = vec![];
+        match self.init {
+            InitSystem::Launchd => {
+                let service_dest = self
+                    .service_dest
+                    .as_ref()
+                    .expect("service_dest should be set for Launchd");
+                let service = self
+                    .service_name
+                    .as_ref()
+                    .expect("service_name should be set for Launchd");
+                let service_target = format!("{DARWIN_LAUNCHD_DOMAIN}/{service}");
+                if self.service_src.is_some() {
+                    operations.push(PrivilegedOperation::write(service_dest, None));
+                }
+                operations.extend([
+                    PrivilegedOperation::service(service),
+                    PrivilegedOperation::command(
+                        "launchctl",
+                        [
+                            "bootstrap",
+                            DARWIN_LAUNCHD_DOMAIN,
+                            &service_dest.display().to_string(),
+                        ],
+                    ),
+                    PrivilegedOperation::command("launchctl", ["enable", &service_target]),
+                ]);
+                if self.start_daemon {
+                    operations.push(PrivilegedOperation::command(
+                        "launchctl",
+                        ["kickstart", "-k", &service_target],
+                    ));
+                }
+            },
+            InitSystem::Systemd => {
+                // Units left running or enabled by an earlier install are stopped first
+                for unit in self
+                    .socket_files
+                    .iter()
+                    .map(|SocketFile { name, .. }| name.as_str())
+                    .chain(["nix-daemon.service"])
+                {
+                    operations.extend([
+                        self.scope.systemctl_operation(["disable", unit, "--now"]),
+                        self.scope.systemctl_operation(["stop", unit]),
+                    ]);
+                }
+                match &self.scope {
+                    UnitScope::System => operations.extend([
+                        PrivilegedOperation::write(TMPFILES_DEST, None),
+                        PrivilegedOperation::command(
+                            "systemd-tmpfiles",
+                            ["--create", "--prefix=/nix/var/nix"],
+                        ),
+                    ]),
+                    UnitScope::User { user, .. } => operations.push(PrivilegedOperation::command(
+                        "loginctl",
+                        ["enable-linger", user.as_str()],
+                    )),
+                }
+                if let Some(service_dest) =
+                    self.service_src.as_ref().and(self.service_dest.as_ref())
+                {
+                    operations.extend([
+                        PrivilegedOperation::service("nix-daemon.service"),
+                        PrivilegedOperation::remove(service_dest),
+                        PrivilegedOperation::write(service_dest, None),
+                    ]);
+                }
+                for SocketFile { dest, .. } in self.socket_files.iter() {
+                    operations.extend([
+                        PrivilegedOperation::remove(dest),
+                        PrivilegedOperation::write(dest, None),
+                    ]);
+                }
+                if self.start_daemon {
+                    operations.push(self.scope.systemctl_operation(["daemon-reload"]));
+                }
+                for SocketFile { name, src, .. } in self.socket_files.iter() {
+                    let unit = match src {
+                        UnitSrc::Path(path) => path.display().to_string(),
+                        UnitSrc::Literal(_) => name.clone(),
+                    };
+                    operations.extend([
+                        PrivilegedOperation::service(name),
+                        self.scope.systemctl_operation(["enable", &unit, "--now"]),
+                    ]);
+                }
+                if let (ServiceMode::AlwaysRunning, Some(service_src)) =
+                    (self.service_mode, &self.service_src)
+                {
+                    let unit = match self.writes_service() {
+                        true => "nix-daemon.service".to_string(),
+                        false => service_src.display().to_string(),
+                    };
+                    operations.push(self.scope.systemctl_operation(["enable", &unit, "--now"]));
+                }
+            },
+            InitSystem::None => (),
+        }
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        let writes_service = self.writes_service();
         let Self {
             init,
             start_daemon,
@@ -235,7 +554,14 @@ impl Action for ConfigureInitService {
             service_dest,
             service_name,
             socket_files,
+            written_files,
+            daemon_user,
+            scope,
+            enabled_linger,
+            service_mode,
+            resources,
         } = self;
+        written_files.clear();
 
         match init {
             InitSystem::Launchd => {
@@ -257,6 +583,18 @@ impl Action for ConfigureInitService {
                                 e,
                             ))
                         })?;
+                    if let Some(user) = daemon_user {
+                        run_plist_as(service_dest, user).map_err(Self::error)?;
+                    }
+                    if !resources.is_unset() {
+                        schedule_plist(service_dest, resources).map_err(Self::error)?;
+                    }
+                    let written = tokio::fs::read(service_dest)
+                        .await
+                        .map_err(|e| Self::error(ActionErrorKind::Read(service_dest.clone(), e)))?;
+                    let written = WrittenFile::whole(service_dest, &written);
+                    crate::managed_files::register(Self::action_tag(), &written);
+                    written_files.push(written);
                 }
 
                 crate::action::macos::retry_bootstrap(domain, service, service_dest)
@@ -289,15 +627,20 @@ impl Action for ConfigureInitService {
                     .as_ref()
                     .expect("service_dest should be defined for systemd");
 
+                // The user manager only runs while the user is logged in, or lingers
+                if let UnitScope::User { user, .. } = scope {
+                    *enabled_linger = enable_linger(user).await.map_err(Self::error)?;
+                }
+
                 // The goal state is the `socket` enabled and active, the service not enabled and stopped (it activates via socket activation)
                 let mut any_socket_was_active = false;
                 for SocketFile { name, .. } in socket_files.iter() {
-                    let is_active = is_active(name).await.map_err(Self::error)?;
+                    let is_active = is_active(scope, name).await.map_err(Self::error)?;
 
-                    if is_enabled(name).await.map_err(Self::error)? {
-                        disable(name, is_active).await.map_err(Self::error)?;
+                    if is_enabled(scope, name).await.map_err(Self::error)? {
+                        disable(scope, name, is_active).await.map_err(Self::error)?;
                     } else if is_active {
-                        stop(name).await.map_err(Self::error)?;
+                        stop(scope, name).await.map_err(Self::error)?;
                     };
 
                     if is_active {
@@ -305,72 +648,119 @@ impl Action for ConfigureInitService {
                     }
                 }
 
-                {
-                    let is_active = is_active("nix-daemon.service").await.map_err(Self::error)?;
+                let service_was_active = {
+                    let is_active = is_active(scope, "nix-daemon.service")
+                        .await
+                        .map_err(Self::error)?;
 
-                    if is_enabled("nix-daemon.service")
+                    if is_enabled(scope, "nix-daemon.service")
                         .await
                         .map_err(Self::error)?
                     {
-                        disable("nix-daemon.service", is_active)
+                        disable(scope, "nix-daemon.service", is_active)
                             .await
                             .map_err(Self::error)?;
                     } else if is_active {
-                        stop("nix-daemon.service").await.map_err(Self::error)?;
+                        stop(scope, "nix-daemon.service")
+                            .await
+                            .map_err(Self::error)?;
                     };
-                }
-
-                if !Path::new(TMPFILES_DEST).exists() {
-                    tracing::trace!(src = TMPFILES_SRC, dest = TMPFILES_DEST, "Symlinking");
-                    tokio::fs::symlink(TMPFILES_SRC, TMPFILES_DEST)
+                    is_active
+                };
+
+                // The user manager's socket is in its runtime directory, which needs no tmpfiles
+                let tmpfiles_dest = crate::util::host_path(TMPFILES_DEST);
+                if scope.is_system() && !tmpfiles_dest.exists() {
+                    tracing::trace!(src = TMPFILES_SRC, dest = %tmpfiles_dest.display(), "Symlinking");
+                    tokio::fs::symlink(TMPFILES_SRC, &tmpfiles_dest)
                         .await
                         .map_err(|e| {
                             ActionErrorKind::Symlink(
                                 PathBuf::from(TMPFILES_SRC),
-                                PathBuf::from(TMPFILES_DEST),
+                                tmpfiles_dest.clone(),
                                 e,
                             )
                         })
                         .map_err(Self::error)?;
                 }
 
-                execute_command(
-                    Command::new("systemd-tmpfiles")
-                        .process_group(0)
-                        .arg("--create")
-                        .arg("--prefix=/nix/var/nix")
-                        .stdin(std::process::Stdio::null()),
-                )
-                .await
-                .map_err(Self::error)?;
+                if scope.is_system() {
+                    execute_command(
+                        Command::new("systemd-tmpfiles")
+                            .process_group(0)
+                            .arg("--create")
+                            .arg("--prefix=/nix/var/nix")
+                            .stdin(std::process::Stdio::null()),
+                    )
+                    .await
+                    .map_err(Self::error)?;
+                }
+
+                if let UnitScope::User { uid, .. } = scope {
+                    create_unit_dir(service_dest, *uid)
+                        .await
+                        .map_err(Self::error)?;
+                }
 
                 // TODO: once we have a way to communicate interaction between the library and the
                 // cli, interactively ask for permission to remove the file
 
                 if let Some(service_src) = service_src.as_ref() {
-                    Self::check_if_systemd_unit_exists(
-                        &UnitSrc::Path(service_src.to_path_buf()),
-                        service_dest,
-                    )
-                    .await
-                    .map_err(Self::error)?;
+                    // The unit is only in the profile once Nix is installed, so it is rendered now
+                    let unit = match writes_service {
+                        true => {
+                            let mut unit = tokio::fs::read_to_string(&service_src)
+                                .await
+                                .map_err(|e| ActionErrorKind::Read(service_src.clone(), e))
+                                .map_err(Self::error)?;
+                            if let Some(user) = daemon_user {
+                                // A user manager runs everything as its user, and refuses `User=`
+                                unit = match scope {
+                                    UnitScope::System => run_unit_as(&unit, user),
+                                    UnitScope::User { .. } => run_unit_in_user_manager(&unit),
+                                };
+                            }
+                            if !resources.is_unset() {
+                                unit = add_service_settings(&unit, &resources.systemd_settings());
+                            }
+                            UnitSrc::Literal(unit)
+                        },
+                        false => UnitSrc::Path(service_src.to_path_buf()),
+                    };
+                    Self::check_if_systemd_unit_exists(&unit, service_dest)
+                        .await
+                        .map_err(Self::error)?;
 
                     crate::util::remove_file(service_dest, OnMissing::Ignore)
                         .await
                         .map_err(|e| ActionErrorKind::Remove(service_dest.into(), e))
                         .map_err(Self::error)?;
 
-                    tracing::trace!(src = %service_src.display(), dest = %service_dest.display(), "Symlinking");
-                    tokio::fs::symlink(service_src, service_dest)
-                        .await
-                        .map_err(|e| {
-                            ActionErrorKind::Symlink(
-                                service_src.clone(),
-                                PathBuf::from(service_dest),
-                                e,
-                            )
-                        })
-                        .map_err(Self::error)?;
+                    match unit {
+                        UnitSrc::Path(service_src) => {
+                            tracing::trace!(src = %service_src.display(), dest = %service_dest.display(), "Symlinking");
+                            tokio::fs::symlink(&service_src, service_dest)
+                                .await
+                                .map_err(|e| {
+                                    ActionErrorKind::Symlink(
+                                        service_src.clone(),
+                                        PathBuf::from(service_dest),
+                                        e,
+                                    )
+                                })
+                                .map_err(Self::error)?;
+                        },
+                        UnitSrc::Literal(content) => {
+                            tracing::trace!(src = %service_src.display(), dest = %service_dest.display(), "Writing");
+                            tokio::fs::write(service_dest, &content)
+                                .await
+                                .map_err(|e| ActionErrorKind::Write(service_dest.clone(), e))
+                                .map_err(Self::error)?;
+                            let written = WrittenFile::whole(service_dest, content.as_bytes());
+                            crate::managed_files::register(Self::action_tag(), &written);
+                            written_files.push(written);
+                        },
+                    }
                 }
 
                 for SocketFile { src, dest, .. } in socket_files.iter() {
@@ -403,13 +793,17 @@ impl Action for ConfigureInitService {
                                 .await
                                 .map_err(|e| ActionErrorKind::Write(dest.clone(), e))
                                 .map_err(Self::error)?;
+                            let written = WrittenFile::whole(dest.as_path(), content.as_bytes());
+                            crate::managed_files::register(Self::action_tag(), &written);
+                            written_files.push(written);
                         },
                     }
                 }
 
                 if *start_daemon {
                     execute_command(
-                        Command::new("systemctl")
+                        scope
+                            .systemctl()
                             .process_group(0)
                             .arg("daemon-reload")
                             .stdin(std::process::Stdio::null()),
@@ -430,15 +824,27 @@ impl Action for ConfigureInitService {
                             // `/nix/var/nix/profiles/default` -> `/nix/store/............/nix-
                             // daemon.socket` to fail with "Failed to execute operation: Too many
                             // levels of symbolic links"
-                            enable(path.display().to_string().as_ref(), enable_now)
-                                .await
-                                .map_err(Self::error)?;
+                            enable(scope, path, enable_now).await.map_err(Self::error)?;
                         },
                         UnitSrc::Literal(_) => {
-                            enable(name, enable_now).await.map_err(Self::error)?;
+                            enable(scope, name, enable_now).await.map_err(Self::error)?;
                         },
                     }
                 }
+
+                // Without sockets to start it, the daemon is started like any other service
+                if let (ServiceMode::AlwaysRunning, Some(service_src)) =
+                    (*service_mode, service_src.as_ref())
+                {
+                    let enable_now = *start_daemon || service_was_active;
+                    let unit = match writes_service {
+                        // Enabled by path for the same reason as the sockets above
+                        false => service_src.as_os_str(),
+                        // The written service is no symlink
+                        true => OsStr::new("nix-daemon.service"),
+                    };
+                    enable(scope, unit, enable_now).await.map_err(Self::error)?;
+                }
             },
             InitSystem::None => {
                 // Nothing here, no init system
@@ -451,21 +857,28 @@ impl Action for ConfigureInitService {
     fn revert_description(&self) -> Vec<ActionDescription> {
         match self.init {
             InitSystem::Systemd => {
+                let systemctl = self.scope.systemctl_display();
                 let mut steps = vec![];
 
                 for SocketFile { name, .. } in self.socket_files.iter() {
-                    steps.push(format!("Run `systemctl disable {}`", name));
+                    steps.push(format!("Run `{systemctl} disable {}`", name));
                 }
 
                 steps.push(format!(
-                    "Run `systemctl disable {0}`",
+                    "Run `{systemctl} disable {0}`",
                     self.service_src
                         .as_ref()
                         .expect("service_src should be defined for systemd")
                         .display()
                 ));
-                steps.push("Run `systemd-tempfiles --remove --prefix=/nix/var/nix`".to_string());
-                steps.push("Run `systemctl daemon-reload`".to_string());
+                if self.scope.is_system() {
+                    steps
+                        .push("Run `systemd-tempfiles --remove --prefix=/nix/var/nix`".to_string());
+                }
+                steps.push(format!("Run `{systemctl} daemon-reload`"));
+                if let (UnitScope::User { user, .. }, true) = (&self.scope, self.enabled_linger) {
+                    steps.push(format!("Run `loginctl disable-linger {user}`"));
+                }
 
                 vec![ActionDescription::new(
                     "Unconfigure Nix daemon related settings with systemd".to_string(),
@@ -526,13 +939,15 @@ impl Action for ConfigureInitService {
                 // We separate stop and disable (instead of using `--now`) to avoid cases where the service isn't started, but is enabled.
 
                 // These have to fail fast.
+                let scope = &self.scope;
                 for SocketFile { name, .. } in self.socket_files.iter() {
-                    let socket_is_active = is_active(name).await.map_err(Self::error)?;
-                    let socket_is_enabled = is_enabled(name).await.map_err(Self::error)?;
+                    let socket_is_active = is_active(scope, name).await.map_err(Self::error)?;
+                    let socket_is_enabled = is_enabled(scope, name).await.map_err(Self::error)?;
 
                     if socket_is_active {
                         if let Err(err) = execute_command(
-                            Command::new("systemctl")
+                            scope
+                                .systemctl()
                                 .process_group(0)
                                 .args(["stop", name])
                                 .stdin(std::process::Stdio::null()),
@@ -545,7 +960,8 @@ impl Action for ConfigureInitService {
 
                     if socket_is_enabled {
                         if let Err(err) = execute_command(
-                            Command::new("systemctl")
+                            scope
+                                .systemctl()
                                 .process_group(0)
                                 .args(["disable", name])
                                 .stdin(std::process::Stdio::null()),
@@ -556,15 +972,17 @@ impl Action for ConfigureInitService {
                         }
                     }
                 }
-                let service_is_active =
-                    is_active("nix-daemon.service").await.map_err(Self::error)?;
-                let service_is_enabled = is_enabled("nix-daemon.service")
+                let service_is_active = is_active(scope, "nix-daemon.service")
+                    .await
+                    .map_err(Self::error)?;
+                let service_is_enabled = is_enabled(scope, "nix-daemon.service")
                     .await
                     .map_err(Self::error)?;
 
                 if service_is_active {
                     if let Err(err) = execute_command(
-                        Command::new("systemctl")
+                        scope
+                            .systemctl()
                             .process_group(0)
                             .args(["stop", "nix-daemon.service"])
                             .stdin(std::process::Stdio::null()),
@@ -577,7 +995,8 @@ impl Action for ConfigureInitService {
 
                 if service_is_enabled {
                     if let Err(err) = execute_command(
-                        Command::new("systemctl")
+                        scope
+                            .systemctl()
                             .process_group(0)
                             .args(["disable", "nix-daemon.service"])
                             .stdin(std::process::Stdio::null()),
@@ -588,28 +1007,31 @@ impl Action for ConfigureInitService {
                     }
                 }
 
-                if let Err(err) = execute_command(
-                    Command::new("systemd-tmpfiles")
-                        .process_group(0)
-                        .arg("--remove")
-                        .arg("--prefix=/nix/var/nix")
-                        .stdin(std::process::Stdio::null()),
-                )
-                .await
-                {
-                    errors.push(err);
-                }
+                if scope.is_system() {
+                    if let Err(err) = execute_command(
+                        Command::new("systemd-tmpfiles")
+                            .process_group(0)
+                            .arg("--remove")
+                            .arg("--prefix=/nix/var/nix")
+                            .stdin(std::process::Stdio::null()),
+                    )
+                    .await
+                    {
+                        errors.push(err);
+                    }
 
-                if let Err(err) =
-                    crate::util::remove_file(Path::new(TMPFILES_DEST), OnMissing::Ignore)
+                    let tmpfiles_dest = crate::util::host_path(TMPFILES_DEST);
+                    if let Err(err) = crate::util::remove_file(&tmpfiles_dest, OnMissing::Ignore)
                         .await
-                        .map_err(|e| ActionErrorKind::Remove(PathBuf::from(TMPFILES_DEST), e))
-                {
-                    errors.push(err);
+                        .map_err(|e| ActionErrorKind::Remove(tmpfiles_dest.clone(), e))
+                    {
+                        errors.push(err);
+                    }
                 }
 
                 if let Err(err) = execute_command(
-                    Command::new("systemctl")
+                    scope
+                        .systemctl()
                         .process_group(0)
                         .arg("daemon-reload")
                         .stdin(std::process::Stdio::null()),
@@ -618,6 +1040,20 @@ impl Action for ConfigureInitService {
                 {
                     errors.push(err);
                 }
+
+                if let (UnitScope::User { user, .. }, true) = (scope, self.enabled_linger) {
+                    match execute_command(
+                        Command::new("loginctl")
+                            .process_group(0)
+                            .args(["disable-linger", user])
+                            .stdin(std::process::Stdio::null()),
+                    )
+                    .await
+                    {
+                        Ok(_) => self.enabled_linger = false,
+                        Err(err) => errors.push(err),
+                    }
+                }
             },
             InitSystem::None => {
                 // Nothing here, no init
@@ -662,14 +1098,21 @@ impl Action for ConfigureInitService {
 pub enum ConfigureNixDaemonServiceError {
     #[error("No supported init system found")]
     InitNotSupported,
+    #[error("Could not enable lingering for `{user}` with `loginctl enable-linger {user}`, so its systemd user manager (and the Nix daemon) would stop when they log out, ask an administrator to enable it or install with `--systemd-scope system`: {reason}")]
+    LingerNotEnabled { user: String, reason: String },
+}
+
+impl From<ConfigureNixDaemonServiceError> for ActionErrorKind {
+    fn from(val: ConfigureNixDaemonServiceError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
 }
 
-async fn stop(unit: &str) -> Result<(), ActionErrorKind> {
-    let mut command = Command::new("systemctl");
+async fn stop(scope: &UnitScope, unit: &str) -> Result<(), ActionErrorKind> {
+    let mut command = scope.systemctl();
     command.arg("stop");
     command.arg(unit);
-    let output = command
-        .output()
+    let output = crate::command_output(&mut command)
         .await
         .map_err(|e| ActionErrorKind::command(&command, e))?;
     match output.status.success() {
@@ -681,35 +1124,38 @@ async fn stop(unit: &str) -> Result<(), ActionErrorKind> {
     }
 }
 
-async fn enable(unit: &str, now: bool) -> Result<(), ActionErrorKind> {
-    let mut command = Command::new("systemctl");
+async fn enable(
+    scope: &UnitScope,
+    unit: impl AsRef<OsStr>,
+    now: bool,
+) -> Result<(), ActionErrorKind> {
+    let unit = unit.as_ref();
+    let mut command = scope.systemctl();
     command.arg("enable");
     command.arg(unit);
     if now {
         command.arg("--now");
     }
-    let output = command
-        .output()
+    let output = crate::command_output(&mut command)
         .await
         .map_err(|e| ActionErrorKind::command(&command, e))?;
     match output.status.success() {
         true => {
-            tracing::trace!(unit = %unit, %now, "Enabled unit");
+            tracing::trace!(unit = %unit.to_string_lossy(), %now, "Enabled unit");
             Ok(())
         },
         false => Err(ActionErrorKind::command_output(&command, output)),
     }
 }
 
-async fn disable(unit: &str, now: bool) -> Result<(), ActionErrorKind> {
-    let mut command = Command::new("systemctl");
+async fn disable(scope: &UnitScope, unit: &str, now: bool) -> Result<(), ActionErrorKind> {
+    let mut command = scope.systemctl();
     command.arg("disable");
     command.arg(unit);
     if now {
         command.arg("--now");
     }
-    let output = command
-        .output()
+    let output = crate::command_output(&mut command)
         .await
         .map_err(|e| ActionErrorKind::command(&command, e))?;
     match output.status.success() {
@@ -721,12 +1167,11 @@ async fn disable(unit: &str, now: bool) -> Result<(), ActionErrorKind> {
     }
 }
 
-async fn is_active(unit: &str) -> Result<bool, ActionErrorKind> {
-    let mut command = Command::new("systemctl");
+async fn is_active(scope: &UnitScope, unit: &str) -> Result<bool, ActionErrorKind> {
+    let mut command = scope.systemctl();
     command.arg("is-active");
     command.arg(unit);
-    let output = command
-        .output()
+    let output = crate::command_output(&mut command)
         .await
         .map_err(|e| ActionErrorKind::command(&command, e))?;
     if String::from_utf8(output.stdout)?.starts_with("active") {
@@ -738,12 +1183,202 @@ async fn is_active(unit: &str) -> Result<bool, ActionErrorKind> {
     }
 }
 
-async fn is_enabled(unit: &str) -> Result<bool, ActionErrorKind> {
-    let mut command = Command::new("systemctl");
+/// The daemon, unlike its clients, uses the store directly rather than through itself
+const DAEMON_USER_NIX_CONFIG: &str = "store = local";
+
+/// `unit`, a `nix-daemon.service`, running the daemon as `user`
+fn run_unit_as(unit: &str, user: &DaemonUser) -> String {
+    let settings = format!(
+        "User={}\nGroup={}\nEnvironment=\"NIX_CONFIG={DAEMON_USER_NIX_CONFIG}\"\n",
+        user.name, user.group
+    );
+    add_service_settings(unit, &settings)
+}
+
+/// `unit`, a `nix-daemon.service`, run by the user manager of the daemon user
+fn run_unit_in_user_manager(unit: &str) -> String {
+    add_service_settings(
+        unit,
+        &format!("Environment=\"NIX_CONFIG={DAEMON_USER_NIX_CONFIG}\"\n"),
+    )
+}
+
+/// `unit` with `settings` at the start of its `[Service]` section
+pub(crate) fn add_service_settings(unit: &str, settings: &str) -> String {
+    let mut rendered = String::with_capacity(unit.len() + settings.len());
+    let mut placed = false;
+    for line in unit.split_inclusive('\n') {
+        rendered.push_str(line);
+        if !placed && line.trim() == "[Service]" {
+            if !line.ends_with('\n') {
+                rendered.push('\n');
+            }
+            rendered.push_str(settings);
+            placed = true;
+        }
+    }
+    if !placed {
+        if !rendered.is_empty() && !rendered.ends_with('\n') {
+            rendered.push('\n');
+        }
+        rendered.push_str("[Service]\n");
+        rendered.push_str(settings);
+    }
+    rendered
+}
+
+/// Edit the launchd plist at `path` to run the daemon as `user`
+fn run_plist_as(path: &Path, user: &DaemonUser) -> Result<(), ActionErrorKind> {
+    let mut plist = plist::Value::from_file(path)?;
+    let Some(dictionary) = plist.as_dictionary_mut() else {
+        return Err(ActionErrorKind::DifferentContent(path.to_path_buf()));
+    };
+    dictionary.insert("UserName".into(), user.name.clone().into());
+    dictionary.insert("GroupName".into(), user.group.clone().into());
+    if !dictionary.contains_key("EnvironmentVariables") {
+        dictionary.insert(
+            "EnvironmentVariables".into(),
+            plist::Dictionary::new().into(),
+        );
+    }
+    if let Some(environment) = dictionary
+        .get_mut("EnvironmentVariables")
+        .and_then(plist::Value::as_dictionary_mut)
+    {
+        environment.insert("NIX_CONFIG".into(), DAEMON_USER_NIX_CONFIG.into());
+    }
+    plist.to_file_xml(path)?;
+    Ok(())
+}
+
+/// How the daemon service a `receipt` configured schedules the daemon, however deeply its
+/// `configure_init_service` action is nested
+pub(crate) fn daemon_resources_of(receipt: &serde_json::Value) -> DaemonResources {
+    fn find(value: &serde_json::Value) -> Option<DaemonResources> {
+        match value {
+            serde_json::Value::Object(object) => {
+                if object.get("action_name").and_then(|name| name.as_str())
+                    == Some("configure_init_service")
+                {
+                    return object
+                        .get("resources")
+                        .and_then(|resources| serde_json::from_value(resources.clone()).ok());
+                }
+                object.values().find_map(find)
+            },
+            serde_json::Value::Array(values) => values.iter().find_map(find),
+            _ => None,
+        }
+    }
+    find(receipt).unwrap_or_default()
+}
+
+/// Edit the launchd plist at `path` to schedule the daemon as `resources` says
+pub(crate) fn schedule_plist(
+    path: &Path,
+    resources: &DaemonResources,
+) -> Result<(), ActionErrorKind> {
+    let mut plist = plist::Value::from_file(path)?;
+    let Some(dictionary) = plist.as_dictionary_mut() else {
+        return Err(ActionErrorKind::DifferentContent(path.to_path_buf()));
+    };
+    if let Some(nice) = resources.nice {
+        dictionary.insert("Nice".into(), i64::from(nice).into());
+    }
+    if let Some(process_type) = resources.launchd_process_type() {
+        dictionary.insert("ProcessType".into(), process_type.into());
+    }
+    plist.to_file_xml(path)?;
+    Ok(())
+}
+
+/// The systemd settings of `resources`, like `` `Nice=10`, `CPUWeight=20` ``
+pub(crate) fn systemd_settings_list(resources: &DaemonResources) -> String {
+    resources
+        .systemd_settings()
+        .lines()
+        .map(|line| format!("`{line}`"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The launchd settings of `resources`, like `` `Nice` to `10`, `ProcessType` to `Background` ``
+pub(crate) fn launchd_settings_list(resources: &DaemonResources) -> String {
+    resources
+        .nice
+        .map(|nice| format!("`Nice` to `{nice}`"))
+        .into_iter()
+        .chain(
+            resources
+                .launchd_process_type()
+                .map(|process_type| format!("`ProcessType` to `{process_type}`")),
+        )
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Make the user manager of `user` linger, returning if it did not already
+async fn enable_linger(user: &str) -> Result<bool, ActionErrorKind> {
+    let mut command = Command::new("loginctl");
+    command.args(["show-user", user, "--property=Linger", "--value"]);
+    command.stdin(std::process::Stdio::null());
+    // `loginctl` fails for a user without a session, who then does not linger either
+    let lingers = crate::command_output(&mut command)
+        .await
+        .map_err(|e| ActionErrorKind::command(&command, e))
+        .map(|output| {
+            output.status.success() && String::from_utf8_lossy(&output.stdout).trim() == "yes"
+        })?;
+    if lingers {
+        tracing::trace!(%user, "Already lingers");
+        return Ok(false);
+    }
+
+    let mut command = Command::new("loginctl");
+    command.process_group(0);
+    command.args(["enable-linger", user]);
+    command.stdin(std::process::Stdio::null());
+    let output = crate::command_output(&mut command)
+        .await
+        .map_err(|e| ActionErrorKind::command(&command, e))?;
+    if !output.status.success() {
+        return Err(ConfigureNixDaemonServiceError::LingerNotEnabled {
+            user: user.to_string(),
+            reason: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .into());
+    }
+    tracing::trace!(%user, "Enabled lingering");
+    Ok(true)
+}
+
+/// Create the directories up to the unit at `unit_dest` in the home of the user `uid`, owned by them
+async fn create_unit_dir(unit_dest: &Path, uid: u32) -> Result<(), ActionErrorKind> {
+    let Some(dir) = unit_dest.parent() else {
+        return Ok(());
+    };
+    let mut missing = vec![];
+    for ancestor in dir.ancestors() {
+        if ancestor.exists() {
+            break;
+        }
+        missing.push(ancestor.to_path_buf());
+    }
+    for path in missing.into_iter().rev() {
+        tokio::fs::create_dir(&path)
+            .await
+            .map_err(|e| ActionErrorKind::CreateDirectory(path.clone(), e))?;
+        nix::unistd::chown(&path, Some(nix::unistd::Uid::from_raw(uid)), None)
+            .map_err(|e| ActionErrorKind::Chown(path.clone(), e))?;
+    }
+    Ok(())
+}
+
+async fn is_enabled(scope: &UnitScope, unit: &str) -> Result<bool, ActionErrorKind> {
+    let mut command = scope.systemctl();
     command.arg("is-enabled");
     command.arg(unit);
-    let output = command
-        .output()
+    let output = crate::command_output(&mut command)
         .await
         .map_err(|e| ActionErrorKind::command(&command, e))?;
     let stdout = String::from_utf8(output.stdout)?;
@@ -755,3 +1390,393 @@ async fn is_enabled(unit: &str) -> Result<bool, ActionErrorKind> {
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_harness::{FakeCommand, Invocation, SandboxContext};
+
+    #[tokio::test]
+    async fn places_systemd_units() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let service_src =
+            sandbox.path("/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service");
+        let service_dest = sandbox.path("/etc/systemd/system/nix-daemon.service");
+        let socket_dest = sandbox.path("/etc/systemd/system/nix-daemon.socket");
+        let tmpfiles_dest = sandbox.path(TMPFILES_DEST);
+        // Neither unit is active or enabled yet
+        sandbox.fake("systemctl", FakeCommand::success().stdout("inactive\n"));
+
+        let mut actions = vec![sandbox
+            .scope(ConfigureInitService::plan(
+                InitSystem::Systemd,
+                false,
+                Some(service_src.clone()),
+                Some(service_dest.clone()),
+                None,
+                vec![SocketFile {
+                    name: "nix-daemon.socket".into(),
+                    src: UnitSrc::Literal(
+                        "[Socket]\nListenStream=/nix/var/nix/daemon-socket/socket\n".into(),
+                    ),
+                    dest: socket_dest.clone(),
+                }],
+                None,
+            ))
+            .await?
+            .boxed()];
+
+        sandbox.execute(&mut actions).await?;
+
+        assert_eq!(tokio::fs::read_link(&service_dest).await?, service_src);
+        assert_eq!(
+            tokio::fs::read_link(&tmpfiles_dest).await?,
+            PathBuf::from(TMPFILES_SRC)
+        );
+        assert!(tokio::fs::read_to_string(&socket_dest)
+            .await?
+            .starts_with("[Socket]"));
+        assert!(sandbox
+            .invocations_of("systemd-tmpfiles")
+            .contains(&Invocation::new(
+                "systemd-tmpfiles",
+                ["--create", "--prefix=/nix/var/nix"]
+            )));
+        assert!(sandbox
+            .invocations_of("systemctl")
+            .contains(&Invocation::new(
+                "systemctl",
+                ["enable", "nix-daemon.socket"]
+            )));
+        // `--no-start-daemon` should not reload or start anything
+        assert!(!sandbox
+            .invocations_of("systemctl")
+            .contains(&Invocation::new("systemctl", ["daemon-reload"])));
+
+        sandbox.revert(&mut actions).await?;
+
+        assert!(
+            !service_dest.is_symlink(),
+            "Service should have been removed"
+        );
+        assert!(
+            !tmpfiles_dest.is_symlink(),
+            "Tmpfiles should have been removed"
+        );
+        assert!(!socket_dest.exists(), "Socket should have been removed");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn always_running_daemons_have_no_sockets() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let service_src =
+            sandbox.path("/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service");
+        let service_dest = sandbox.path("/etc/systemd/system/nix-daemon.service");
+        let socket_dest = sandbox.path("/etc/systemd/system/nix-daemon.socket");
+        std::fs::create_dir_all(sandbox.path("/run/systemd/system"))?;
+        sandbox.fake("systemctl", FakeCommand::success().stdout("inactive\n"));
+
+        let mut actions = vec![sandbox
+            .scope(ConfigureInitService::plan(
+                InitSystem::Systemd,
+                true,
+                Some(service_src.clone()),
+                Some(service_dest.clone()),
+                None,
+                vec![SocketFile {
+                    name: "nix-daemon.socket".into(),
+                    src: UnitSrc::Path(socket_dest.with_file_name("unused.socket")),
+                    dest: socket_dest.clone(),
+                }],
+                None,
+            ))
+            .await?
+            .with_service_mode(ServiceMode::AlwaysRunning)
+            .boxed()];
+        let receipt = serde_json::to_value(&actions[0])?;
+        assert_eq!(receipt["action"]["service_mode"], "always_running");
+
+        sandbox.execute(&mut actions).await?;
+
+        assert_eq!(tokio::fs::read_link(&service_dest).await?, service_src);
+        assert!(!socket_dest.exists());
+        let systemctl = sandbox.invocations_of("systemctl");
+        let service_src = service_src.display().to_string();
+        assert!(systemctl.contains(&Invocation::new(
+            "systemctl",
+            ["enable", service_src.as_str(), "--now"]
+        )));
+        assert!(
+            !systemctl
+                .iter()
+                .any(|invocation| invocation.args.iter().any(|arg| arg.ends_with(".socket"))),
+            "{systemctl:?}"
+        );
+
+        sandbox.revert(&mut actions).await?;
+        assert!(!service_dest.is_symlink());
+        Ok(())
+    }
+
+    #[test]
+    fn daemon_user_units_run_as_the_user() {
+        let user = DaemonUser {
+            name: "nix-daemon".into(),
+            uid: 30100,
+            group: "nixbld".into(),
+            gid: 30000,
+        };
+        let unit = "[Unit]\nDescription=Nix Daemon\n\n[Service]\nExecStart=@/nix/var/nix/profiles/default/bin/nix-daemon nix-daemon --daemon\nKillMode=process\n";
+        assert_eq!(
+            run_unit_as(unit, &user),
+            "[Unit]\nDescription=Nix Daemon\n\n[Service]\nUser=nix-daemon\nGroup=nixbld\nEnvironment=\"NIX_CONFIG=store = local\"\nExecStart=@/nix/var/nix/profiles/default/bin/nix-daemon nix-daemon --daemon\nKillMode=process\n"
+        );
+        assert_eq!(
+            run_unit_as("[Unit]\nDescription=Nix Daemon", &user),
+            "[Unit]\nDescription=Nix Daemon\n[Service]\nUser=nix-daemon\nGroup=nixbld\nEnvironment=\"NIX_CONFIG=store = local\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn scheduled_daemons_have_written_units() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let service_src =
+            sandbox.path("/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service");
+        std::fs::create_dir_all(service_src.parent().unwrap())?;
+        std::fs::write(
+            &service_src,
+            "[Service]\nExecStart=@/nix/var/nix/profiles/default/bin/nix-daemon nix-daemon --daemon\n",
+        )?;
+        let service_dest = sandbox.path("/etc/systemd/system/nix-daemon.service");
+        std::fs::create_dir_all(service_dest.parent().unwrap())?;
+        sandbox.fake("systemctl", FakeCommand::success().stdout("inactive\n"));
+
+        let mut actions = vec![sandbox
+            .scope(ConfigureInitService::plan(
+                InitSystem::Systemd,
+                false,
+                Some(service_src.clone()),
+                Some(service_dest.clone()),
+                None,
+                vec![],
+                None,
+            ))
+            .await?
+            .with_resources(DaemonResources {
+                nice: Some(10),
+                cpu_weight: Some(20),
+                io_weight: None,
+            })
+            .boxed()];
+        let receipt = serde_json::to_value(&actions[0])?;
+        assert_eq!(
+            receipt["action"]["resources"],
+            serde_json::json!({ "nice": 10, "cpu_weight": 20 })
+        );
+
+        sandbox.execute(&mut actions).await?;
+
+        assert!(!service_dest.is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(&service_dest)?,
+            "[Service]\nNice=10\nCPUWeight=20\nExecStart=@/nix/var/nix/profiles/default/bin/nix-daemon nix-daemon --daemon\n"
+        );
+
+        sandbox.revert(&mut actions).await?;
+        assert!(!service_dest.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn scheduled_plists_set_nice_and_process_type() -> eyre::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("org.nixos.nix-daemon.plist");
+        let mut dictionary = plist::Dictionary::new();
+        dictionary.insert("Label".into(), "org.nixos.nix-daemon".into());
+        plist::Value::Dictionary(dictionary).to_file_xml(&path)?;
+
+        schedule_plist(
+            &path,
+            &DaemonResources {
+                nice: Some(5),
+                cpu_weight: None,
+                io_weight: Some(50),
+            },
+        )?;
+
+        let plist = plist::Value::from_file(&path)?;
+        let dictionary = plist.as_dictionary().expect("a dictionary");
+        assert_eq!(
+            dictionary
+                .get("Nice")
+                .and_then(plist::Value::as_signed_integer),
+            Some(5)
+        );
+        assert_eq!(
+            dictionary
+                .get("ProcessType")
+                .and_then(plist::Value::as_string),
+            Some("Background")
+        );
+        assert_eq!(
+            launchd_settings_list(&DaemonResources {
+                cpu_weight: Some(200),
+                ..Default::default()
+            }),
+            ""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn systemctl_reaches_the_scope() {
+        assert!(UnitScope::System.systemctl_args().is_empty());
+        let user = UnitScope::User {
+            user: "nix-daemon".into(),
+            uid: 30100,
+        };
+        assert_eq!(
+            user.systemctl_args(),
+            ["--user", "--machine", "nix-daemon@"]
+        );
+        assert_eq!(
+            user.systemctl_display(),
+            "systemctl --user --machine nix-daemon@"
+        );
+        assert_eq!(
+            user.systemctl_operation(["enable", "nix-daemon.socket", "--now"]),
+            PrivilegedOperation::command(
+                "systemctl",
+                [
+                    "--user",
+                    "--machine",
+                    "nix-daemon@",
+                    "enable",
+                    "nix-daemon.socket",
+                    "--now"
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn places_systemd_user_units() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let uid = nix::unistd::getuid().as_raw();
+        let service_src =
+            sandbox.path("/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service");
+        std::fs::create_dir_all(service_src.parent().unwrap())?;
+        std::fs::write(
+            &service_src,
+            "[Service]\nExecStart=@/nix/var/nix/profiles/default/bin/nix-daemon nix-daemon --daemon\n",
+        )?;
+        let home = sandbox.path("/home/nix-daemon");
+        std::fs::create_dir_all(&home)?;
+        let unit_dir = home.join(".config/systemd/user");
+        let tmpfiles_dest = sandbox.path(TMPFILES_DEST);
+        std::fs::create_dir_all(sandbox.path("/run/systemd/system"))?;
+        sandbox.fake("systemctl", FakeCommand::success().stdout("inactive\n"));
+        // The user does not linger yet
+        sandbox.fake("loginctl", FakeCommand::success().stdout("no\n"));
+
+        let mut actions = vec![sandbox
+            .scope(ConfigureInitService::plan(
+                InitSystem::Systemd,
+                true,
+                Some(service_src.clone()),
+                Some("/etc/systemd/system/nix-daemon.service".into()),
+                None,
+                vec![],
+                Some(DaemonUser {
+                    name: "nix-daemon".into(),
+                    uid,
+                    group: "nixbld".into(),
+                    gid: 30000,
+                }),
+            ))
+            .await?
+            .in_user_manager(
+                "nix-daemon",
+                uid,
+                &unit_dir,
+                vec![SocketFile {
+                    name: "nix-daemon.socket".into(),
+                    src: UnitSrc::Literal(
+                        "[Socket]\nListenStream=%t/nix/daemon-socket/socket\n".into(),
+                    ),
+                    dest: unit_dir.join("nix-daemon.socket"),
+                }],
+            )
+            .boxed()];
+
+        sandbox.execute(&mut actions).await?;
+
+        let service = std::fs::read_to_string(unit_dir.join("nix-daemon.service"))?;
+        assert!(!service.contains("User="), "{service}");
+        assert!(service.contains("Environment=\"NIX_CONFIG=store = local\""));
+        assert!(unit_dir.join("nix-daemon.socket").exists());
+        assert!(!tmpfiles_dest.exists(), "User units need no tmpfiles");
+        assert!(sandbox.invocations_of("systemd-tmpfiles").is_empty());
+        assert!(sandbox
+            .invocations_of("loginctl")
+            .contains(&Invocation::new(
+                "loginctl",
+                ["enable-linger", "nix-daemon"]
+            )));
+        let systemctl = sandbox.invocations_of("systemctl");
+        assert!(systemctl.contains(&Invocation::new(
+            "systemctl",
+            ["--user", "--machine", "nix-daemon@", "daemon-reload"]
+        )));
+        assert!(systemctl.contains(&Invocation::new(
+            "systemctl",
+            [
+                "--user",
+                "--machine",
+                "nix-daemon@",
+                "enable",
+                "nix-daemon.socket",
+                "--now"
+            ]
+        )));
+        assert!(
+            systemctl
+                .iter()
+                .all(|invocation| invocation.args.first().map(String::as_str) == Some("--user")),
+            "{systemctl:?}"
+        );
+
+        sandbox.revert(&mut actions).await?;
+
+        assert!(!unit_dir.join("nix-daemon.service").exists());
+        assert!(!unit_dir.join("nix-daemon.socket").exists());
+        assert!(sandbox
+            .invocations_of("loginctl")
+            .contains(&Invocation::new(
+                "loginctl",
+                ["disable-linger", "nix-daemon"]
+            )));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unlingering_users_are_an_error() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake_once("loginctl", FakeCommand::success().stdout("no\n"));
+        sandbox.fake_once(
+            "loginctl",
+            FakeCommand::failure(1).stderr("Could not enable linger: Access denied\n"),
+        );
+
+        let err = sandbox
+            .scope(enable_linger("nix-daemon"))
+            .await
+            .expect_err("lingering could not be enabled");
+        assert!(err.to_string().contains("Access denied"), "{err}");
+
+        Ok(())
+    }
+}
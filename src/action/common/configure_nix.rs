@@ -3,9 +3,14 @@ use std::path::{Path, PathBuf};
 use crate::{
     action::{
         base::SetupDefaultProfile,
-        common::{ConfigureShellProfile, PlaceNixConfiguration},
-        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+        common::{
+            configure_shell_profile::login_shells, ConfigureGithubPath, ConfigureShellProfile,
+            PlaceNixConfiguration, RegisterNixShells,
+        },
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
     },
+    backup::BackupStore,
     planner::ShellProfileLocations,
     settings::{CommonSettings, SCRATCH_DIR},
 };
@@ -21,6 +26,10 @@ Configure Nix and start it
 pub struct ConfigureNix {
     setup_default_profile: StatefulAction<SetupDefaultProfile>,
     configure_shell_profile: Option<StatefulAction<ConfigureShellProfile>>,
+    #[serde(default)]
+    configure_github_path: Option<StatefulAction<ConfigureGithubPath>>,
+    #[serde(default)]
+    register_nix_shells: Option<StatefulAction<RegisterNixShells>>,
     place_nix_configuration: Option<StatefulAction<PlaceNixConfiguration>>,
 }
 
@@ -31,13 +40,43 @@ impl ConfigureNix {
         settings: &CommonSettings,
         extra_internal_conf: Option<nix_config_parser::NixConfig>,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let setup_default_profile = SetupDefaultProfile::plan(PathBuf::from(SCRATCH_DIR))
-            .await
-            .map_err(Self::error)?;
+        let setup_default_profile = SetupDefaultProfile::plan(
+            PathBuf::from(SCRATCH_DIR),
+            settings.default_profile_packages.clone(),
+            settings.offline(),
+        )
+        .await
+        .map_err(Self::error)?;
 
         let configure_shell_profile = if settings.modify_profile {
             Some(
-                ConfigureShellProfile::plan(shell_profile_locations)
+                ConfigureShellProfile::plan(
+                    shell_profile_locations.without(&settings.skip_shell_profiles),
+                    settings.managed_file_annotation.clone(),
+                    Some(BackupStore::new(&settings.state_dir)),
+                    settings.use_xdg_base_directories,
+                    settings.user_profile,
+                )
+                .await
+                .map_err(Self::error)?,
+            )
+        } else {
+            None
+        };
+
+        // The shell profiles already add to `$GITHUB_PATH` when they are modified
+        let configure_github_path = match std::env::var_os("GITHUB_PATH") {
+            Some(github_path) if settings.ci && configure_shell_profile.is_none() => Some(
+                ConfigureGithubPath::plan(github_path)
+                    .await
+                    .map_err(Self::error)?,
+            ),
+            _ => None,
+        };
+
+        let register_nix_shells = if settings.register_nix_shells {
+            Some(
+                RegisterNixShells::plan("/etc/shells", &login_shells().await)
                     .await
                     .map_err(Self::error)?,
             )
@@ -53,9 +92,20 @@ impl ConfigureNix {
                     settings.nix_build_group_name.clone(),
                     settings.proxy.clone(),
                     settings.ssl_cert_file.clone(),
+                    settings.config_profile,
                     extra_internal_conf.clone(),
                     settings.extra_conf.clone(),
+                    settings.trusted_users.clone(),
+                    settings.allowed_users.clone(),
+                    settings.substituters.clone(),
+                    settings.trusted_public_keys.clone(),
                     settings.force,
+                    settings.strict_nix_conf,
+                    settings.managed_file_annotation.clone(),
+                    Some(BackupStore::new(&settings.state_dir)),
+                    settings.daemon_user.is_some(),
+                    settings.use_xdg_base_directories,
+                    settings.nix_conf_permissions(),
                 )
                 .await
                 .map_err(Self::error)?,
@@ -66,6 +116,8 @@ impl ConfigureNix {
             place_nix_configuration,
             setup_default_profile,
             configure_shell_profile,
+            configure_github_path,
+            register_nix_shells,
         }
         .into())
     }
@@ -141,7 +193,14 @@ impl Action for ConfigureNix {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "configure_nix",)
+        span!(
+            tracing::Level::DEBUG,
+            "configure_nix",
+            configure_shell_profile = self.configure_shell_profile.is_some(),
+            configure_github_path = self.configure_github_path.is_some(),
+            register_nix_shells = self.register_nix_shells.is_some(),
+            place_nix_configuration = self.place_nix_configuration.is_some(),
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
@@ -149,6 +208,8 @@ impl Action for ConfigureNix {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            configure_github_path,
+            register_nix_shells,
         } = &self;
 
         let mut buf = setup_default_profile.describe_execute();
@@ -158,6 +219,38 @@ impl Action for ConfigureNix {
         if let Some(configure_shell_profile) = configure_shell_profile {
             buf.append(&mut configure_shell_profile.describe_execute());
         }
+        if let Some(configure_github_path) = configure_github_path {
+            buf.append(&mut configure_github_path.describe_execute());
+        }
+        if let Some(register_nix_shells) = register_nix_shells {
+            buf.append(&mut register_nix_shells.describe_execute());
+        }
+        buf
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let Self {
+            setup_default_profile,
+            place_nix_configuration,
+            configure_shell_profile,
+            configure_github_path,
+            register_nix_shells,
+        } = &self;
+
+        let mut buf = vec![];
+        if let Some(place_nix_configuration) = place_nix_configuration {
+            buf.append(&mut place_nix_configuration.privileged_operations());
+        }
+        buf.append(&mut setup_default_profile.privileged_operations());
+        if let Some(configure_shell_profile) = configure_shell_profile {
+            buf.append(&mut configure_shell_profile.privileged_operations());
+        }
+        if let Some(configure_github_path) = configure_github_path {
+            buf.append(&mut configure_github_path.privileged_operations());
+        }
+        if let Some(register_nix_shells) = register_nix_shells {
+            buf.append(&mut register_nix_shells.privileged_operations());
+        }
         buf
     }
 
@@ -167,6 +260,8 @@ impl Action for ConfigureNix {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            configure_github_path,
+            register_nix_shells,
         } = self;
 
         if let Some(place_nix_configuration) = place_nix_configuration {
@@ -185,6 +280,18 @@ impl Action for ConfigureNix {
                 .await
                 .map_err(Self::error)?;
         }
+        if let Some(configure_github_path) = configure_github_path {
+            configure_github_path
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+        if let Some(register_nix_shells) = register_nix_shells {
+            register_nix_shells
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
 
         Ok(())
     }
@@ -194,9 +301,17 @@ impl Action for ConfigureNix {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            configure_github_path,
+            register_nix_shells,
         } = &self;
 
         let mut buf = Vec::default();
+        if let Some(register_nix_shells) = register_nix_shells {
+            buf.append(&mut register_nix_shells.describe_revert());
+        }
+        if let Some(configure_github_path) = configure_github_path {
+            buf.append(&mut configure_github_path.describe_revert());
+        }
         if let Some(configure_shell_profile) = configure_shell_profile {
             buf.append(&mut configure_shell_profile.describe_revert());
         }
@@ -211,6 +326,16 @@ impl Action for ConfigureNix {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         let mut errors = vec![];
+        if let Some(register_nix_shells) = &mut self.register_nix_shells {
+            if let Err(err) = register_nix_shells.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Some(configure_github_path) = &mut self.configure_github_path {
+            if let Err(err) = configure_github_path.try_revert().await {
+                errors.push(err);
+            }
+        }
         if let Some(configure_shell_profile) = &mut self.configure_shell_profile {
             if let Err(err) = configure_shell_profile.try_revert().await {
                 errors.push(err);
@@ -1,10 +1,20 @@
 use crate::action::base::{create_or_insert_into_file, CreateDirectory, CreateOrInsertIntoFile};
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
+use crate::audit::recognize_foreign_shell_hook;
+use crate::backup::{Backup, BackupStore};
+use crate::homed::{self, HomedUser};
 use crate::planner::ShellProfileLocations;
+use crate::settings::Shell;
+use crate::util::{host_path, LossyPath, OnMissing};
+use crate::warning::{self, WarningKind};
 
-use nix::unistd::User;
+use nix::{
+    errno::Errno,
+    unistd::{AccessFlags, Uid, User},
+};
 use std::path::{Path, PathBuf};
 use tokio::task::JoinSet;
 use tracing::{span, Instrument, Span};
@@ -12,35 +22,170 @@ use tracing::{span, Instrument, Span};
 const PROFILE_NIX_FILE_SHELL: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh";
 const PROFILE_NIX_FILE_FISH: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish";
 
+/// Where a login shell is provided by Nix, such as a store path or a `home-manager` profile
+const NIX_SHELL_PREFIXES: &[&str] = &[
+    "/nix/store/",
+    "/nix/var/nix/profiles/",
+    "/run/current-system/",
+    "/etc/profiles/per-user/",
+];
+/// Where a login shell in a user's home is provided by Nix
+const NIX_SHELL_HOME_PROFILES: &[&str] = &[
+    "/.nix-profile/",
+    "/.local/state/nix/profile/",
+    "/.local/state/nix/profiles/",
+];
+
+/// Put the per-user profile of either layout on `$PATH` and in `$NIX_PROFILES`, for hooks written
+/// with `--use-xdg-base-directories`
+///
+/// `nix-daemon.sh` only adds one of them, so a profile left in `~/.nix-profile` while moving to
+/// `$XDG_STATE_HOME` would go missing. The profile under `$XDG_STATE_HOME` is added last, so it wins.
+const XDG_PROFILES_SHELL: &str = r#"for NIX_USER_PROFILE in "$HOME/.nix-profile" "${XDG_STATE_HOME:-$HOME/.local/state}/nix/profile"; do
+    if [ -e "$NIX_USER_PROFILE" ]; then
+        case ":$PATH:" in
+            *":$NIX_USER_PROFILE/bin:"*) ;;
+            *) export PATH="$NIX_USER_PROFILE/bin:$PATH" ;;
+        esac
+        case " ${NIX_PROFILES-} " in
+            *" $NIX_USER_PROFILE "*) ;;
+            *) export NIX_PROFILES="${NIX_PROFILES:+$NIX_PROFILES }$NIX_USER_PROFILE" ;;
+        esac
+    fi
+done
+unset NIX_USER_PROFILE
+"#;
+/// [`XDG_PROFILES_SHELL`] for fish
+const XDG_PROFILES_FISH: &str = r#"set --local nix_state_home $HOME/.local/state
+test -n "$XDG_STATE_HOME"; and set nix_state_home $XDG_STATE_HOME
+for nix_user_profile in $HOME/.nix-profile $nix_state_home/nix/profile
+    if test -e $nix_user_profile
+        contains -- $nix_user_profile/bin $PATH; or set --global --export --prepend PATH $nix_user_profile/bin
+        contains -- $nix_user_profile (string split ' ' -- "$NIX_PROFILES"); or set --global --export NIX_PROFILES (string trim -- "$NIX_PROFILES $nix_user_profile")
+    end
+end
+"#;
+
 /**
 Configure any detected shell profiles to include Nix support
+
+A profile holding only the hook of another installer, such as an `/etc/profile.d/nix.sh` from the
+shell installer, is taken over: it is backed up and replaced by our hook, and restored from its
+backup on revert.
+
+With `--use-xdg-base-directories`, the hook also puts the per-user profile under `$XDG_STATE_HOME`
+on `$PATH`, alongside a `~/.nix-profile` which has not been moved yet.
+
+With `--user-profile`, or when the system-wide profiles are on a read-only file system (like an
+immutable `/etc`), the hook is written to the profiles of the user who started `nix-installer`
+instead.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "configure_shell_profile")]
 pub struct ConfigureShellProfile {
     locations: ShellProfileLocations,
+    /// The users whose login shell is provided by Nix
+    #[serde(default)]
+    nix_login_shells: Vec<LoginShell>,
     create_directories: Vec<StatefulAction<CreateDirectory>>,
     create_or_insert_into_files: Vec<StatefulAction<CreateOrInsertIntoFile>>,
+    /// Where taken over profiles are backed up, nothing is taken over without it
+    #[serde(default)]
+    backups: Option<BackupStore>,
+    #[serde(default)]
+    takeovers: Vec<ShellHookTakeover>,
+    /// If the hooks put the per-user profile under `$XDG_STATE_HOME` on `$PATH`
+    #[serde(default)]
+    use_xdg_base_directories: bool,
+    /// The user whose own profiles are configured, rather than those of the system
+    #[serde(default)]
+    owner: Option<ProfileOwner>,
+}
+
+/// A user whose own shell profiles are configured, in place of those of the system
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub(crate) struct ProfileOwner {
+    pub(crate) name: String,
+    #[serde_as(as = "LossyPath")]
+    pub(crate) home: PathBuf,
+}
+
+impl ProfileOwner {
+    /// The user who started `nix-installer`, rather than `root` after `sudo`
+    fn invoking() -> Result<Self, ConfigureShellProfileError> {
+        let user = match std::env::var("SUDO_USER") {
+            Ok(name) if Uid::effective().is_root() => User::from_name(&name),
+            _ => User::from_uid(Uid::effective()),
+        }
+        .map_err(ConfigureShellProfileError::InvokingUser)?
+        .ok_or(ConfigureShellProfileError::NoInvokingUser)?;
+        Ok(Self {
+            name: user.name,
+            home: user.dir,
+        })
+    }
+}
+
+/// A profile holding only the hook of another installer, replaced by ours
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub(crate) struct ShellHookTakeover {
+    #[serde_as(as = "LossyPath")]
+    pub(crate) path: PathBuf,
+    /// What wrote the hook, as recognized
+    pub(crate) origin: String,
+    /// Recorded once the profile is backed up, the profile is restored from it on revert
+    #[serde(default)]
+    pub(crate) backup: Option<Backup>,
 }
 
 impl ConfigureShellProfile {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         locations: ShellProfileLocations,
+        annotation: Option<String>,
+        backups: Option<BackupStore>,
+        use_xdg_base_directories: bool,
+        user_profile: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
+        let owner = match (user_profile, read_only_location(&locations)) {
+            (true, _) => Some(ProfileOwner::invoking().map_err(Self::error)?),
+            (false, Some(read_only)) => {
+                let owner = ProfileOwner::invoking().map_err(Self::error)?;
+                warning::warn(
+                    WarningKind::UserShellProfiles,
+                    format!(
+                        "`{}` is on a read-only file system, so the shell profiles of `{}` are configured instead of those of the system",
+                        read_only.display(),
+                        owner.name
+                    ),
+                );
+                Some(owner)
+            },
+            (false, None) => None,
+        };
+        let (locations, user, backups) = match &owner {
+            // A user's own profiles are never taken over
+            Some(owner) => (
+                locations.for_user(&host_path(&owner.home)),
+                Some(owner.name.clone()),
+                None,
+            ),
+            None => (locations, None, backups),
+        };
+
         let mut create_or_insert_files = Vec::default();
         let mut create_directories = Vec::default();
+        let mut takeovers = Vec::default();
+        let annotation = crate::settings::annotation_comment(annotation.as_deref());
+        let nix_login_shells = login_shells()
+            .await
+            .into_iter()
+            .filter(LoginShell::is_nix_provided)
+            .collect::<Vec<_>>();
 
-        let shell_buf = format!(
-            "\n\
-            # Nix\n\
-            if [ -e '{PROFILE_NIX_FILE_SHELL}' ]; then\n\
-            {inde}. '{PROFILE_NIX_FILE_SHELL}'\n\
-            fi\n\
-            # End Nix\n
-        \n",
-            inde = "    ", // indent
-        );
+        let shell_buf = shell_hook(&annotation, use_xdg_base_directories);
 
         for profile_target in locations.bash.iter().chain(locations.zsh.iter()) {
             let profile_target_path = Path::new(profile_target);
@@ -49,16 +194,19 @@ impl ConfigureShellProfile {
                 if !profile_target_path.is_symlink() {
                     if !parent.exists() {
                         create_directories.push(
-                            CreateDirectory::plan(parent, None, None, 0o0755, false)
+                            CreateDirectory::plan(parent, user.clone(), None, 0o0755, false)
                                 .await
                                 .map_err(Self::error)?,
                         );
                     }
 
+                    if backups.is_some() {
+                        takeovers.extend(foreign_hook_takeover(profile_target_path, &shell_buf));
+                    }
                     create_or_insert_files.push(
                         CreateOrInsertIntoFile::plan(
                             profile_target_path,
-                            None,
+                            user.clone(),
                             None,
                             0o644,
                             shell_buf.to_string(),
@@ -71,16 +219,7 @@ impl ConfigureShellProfile {
             }
         }
 
-        let fish_buf = format!(
-            "\n\
-            # Nix\n\
-            if test -e '{PROFILE_NIX_FILE_FISH}'\n\
-            {inde}. '{PROFILE_NIX_FILE_FISH}'\n\
-            end\n\
-            # End Nix\n\
-        \n",
-            inde = "    ", // indent
-        );
+        let fish_buf = fish_hook(&annotation, use_xdg_base_directories);
 
         for fish_prefix in &locations.fish.confd_prefixes {
             let fish_prefix_path = PathBuf::from(fish_prefix);
@@ -97,15 +236,21 @@ impl ConfigureShellProfile {
             if !profile_target.is_symlink() {
                 if let Some(conf_d) = profile_target.parent() {
                     create_directories.push(
-                        CreateDirectory::plan(conf_d.to_path_buf(), None, None, 0o755, false)
-                            .await?,
+                        CreateDirectory::plan(
+                            conf_d.to_path_buf(),
+                            user.clone(),
+                            None,
+                            0o755,
+                            false,
+                        )
+                        .await?,
                     );
                 }
 
                 create_or_insert_files.push(
                     CreateOrInsertIntoFile::plan(
                         profile_target,
-                        None,
+                        user.clone(),
                         None,
                         0o644,
                         fish_buf.to_string(),
@@ -128,14 +273,15 @@ impl ConfigureShellProfile {
 
             if let Some(conf_d) = profile_target.parent() {
                 create_directories.push(
-                    CreateDirectory::plan(conf_d.to_path_buf(), None, None, 0o755, false).await?,
+                    CreateDirectory::plan(conf_d.to_path_buf(), user.clone(), None, 0o755, false)
+                        .await?,
                 );
             }
 
             create_or_insert_files.push(
                 CreateOrInsertIntoFile::plan(
                     profile_target,
-                    None,
+                    user.clone(),
                     None,
                     0o644,
                     fish_buf.to_string(),
@@ -145,18 +291,50 @@ impl ConfigureShellProfile {
             );
         }
 
+        // Fish from Nix reads the same `conf.d` as any other, which may not exist yet
+        let fish_found = locations
+            .fish
+            .confd_prefixes
+            .iter()
+            .chain(locations.fish.vendor_confd_prefixes.iter())
+            .any(|prefix| prefix.exists());
+        let nix_fish = nix_login_shells
+            .iter()
+            .any(|login_shell| login_shell.shell() == Some(Shell::Fish));
+        if let (false, true, Some(fish_prefix)) =
+            (fish_found, nix_fish, locations.fish.confd_prefixes.first())
+        {
+            let profile_target = fish_prefix.join(&locations.fish.confd_suffix);
+            let mut missing = profile_target
+                .ancestors()
+                .skip(1)
+                .take_while(|ancestor| !ancestor.exists())
+                .collect::<Vec<_>>();
+            missing.reverse();
+            for directory in missing {
+                create_directories.push(
+                    CreateDirectory::plan(directory, user.clone(), None, 0o755, false)
+                        .await
+                        .map_err(Self::error)?,
+                );
+            }
+            create_or_insert_files.push(
+                CreateOrInsertIntoFile::plan(
+                    profile_target,
+                    user.clone(),
+                    None,
+                    0o644,
+                    fish_buf.to_string(),
+                    create_or_insert_into_file::Position::Beginning,
+                )
+                .await
+                .map_err(Self::error)?,
+            );
+        }
+
         // If the `$GITHUB_PATH` environment exists, we're almost certainly running on Github
         // Actions, and almost certainly wants the relevant `$PATH` additions added.
         if let Ok(github_path) = std::env::var("GITHUB_PATH") {
-            let mut buf = "/nix/var/nix/profiles/default/bin\n".to_string();
-            // Actions runners operate as `runner` user by default
-            if let Ok(Some(runner)) = User::from_name("runner") {
-                #[cfg(target_os = "linux")]
-                let path = format!("/home/{}/.nix-profile/bin\n", runner.name);
-                #[cfg(target_os = "macos")]
-                let path = format!("/Users/{}/.nix-profile/bin\n", runner.name);
-                buf += &path;
-            }
             create_or_insert_files.push(
                 CreateOrInsertIntoFile::plan(
                     &github_path,
@@ -165,22 +343,166 @@ impl ConfigureShellProfile {
                     // We want the `nix-installer-action` to not error if it writes here.
                     // Prior to `v5` this was done in this crate, in `v5` and later, this is done in the action.
                     0o777,
-                    buf,
+                    super::configure_github_path::github_path_entries(),
                     create_or_insert_into_file::Position::End,
                 )
                 .await?,
             );
         }
 
+        // An inactive home is left alone, its user repairs the hooks once they log in
+        if let Some(owner) = &owner {
+            if let Some(homed_user) = homed::inactive_home(&owner.name).await {
+                let home = host_path(&owner.home);
+                homed::warn_inactive(
+                    &homed_user,
+                    "its shell profiles were not configured",
+                    "run `sudo nix-installer repair hooks` as them",
+                );
+                create_directories.retain(|directory| !directory.inner().path.starts_with(&home));
+                create_or_insert_files.retain(|file| !file.inner().path.starts_with(&home));
+            }
+        }
+
         Ok(Self {
             locations,
+            nix_login_shells,
             create_directories,
             create_or_insert_into_files: create_or_insert_files,
+            backups,
+            takeovers,
+            use_xdg_base_directories,
+            owner,
         }
         .into())
     }
 }
 
+impl ConfigureShellProfile {
+    /// The owner and their home, if this has files in it and systemd-homed has not activated it
+    async fn inactive_home(&self) -> Option<(HomedUser, PathBuf)> {
+        let owner = self.owner.as_ref()?;
+        let home = host_path(&owner.home);
+        let in_home = self
+            .create_directories
+            .iter()
+            .map(|directory| &directory.inner().path)
+            .chain(
+                self.create_or_insert_into_files
+                    .iter()
+                    .map(|file| &file.inner().path),
+            )
+            .any(|path| path.starts_with(&home));
+        if !in_home {
+            return None;
+        }
+        let homed_user = homed::inactive_home(&owner.name).await?;
+        Some((homed_user, home))
+    }
+}
+
+/// The first of the system-wide `locations` which is on a read-only file system, such as an
+/// immutable `/etc`
+fn read_only_location(locations: &ShellProfileLocations) -> Option<PathBuf> {
+    // Nothing of the host says anything about another platform
+    if crate::cross_target::is_active() {
+        return None;
+    }
+    locations
+        .bash
+        .iter()
+        .chain(&locations.zsh)
+        .filter_map(|profile| profile.parent())
+        .chain(locations.fish.confd_prefixes.iter().map(PathBuf::as_path))
+        .find(|location| {
+            // Where the location does not exist yet, it would be created in its nearest ancestor
+            let Some(existing) = location.ancestors().find(|ancestor| ancestor.exists()) else {
+                return false;
+            };
+            nix::unistd::access(existing, AccessFlags::W_OK) == Err(Errno::EROFS)
+        })
+        .map(Path::to_path_buf)
+}
+
+/// The hook loading Nix from the `sh`, `bash` and `zsh` profiles
+fn shell_hook(annotation: &str, use_xdg_base_directories: bool) -> String {
+    let xdg_profiles = match use_xdg_base_directories {
+        true => XDG_PROFILES_SHELL,
+        false => "",
+    };
+    format!(
+        "\n\
+        # Nix\n\
+        {annotation}\
+        if [ -e '{PROFILE_NIX_FILE_SHELL}' ]; then\n\
+        {inde}. '{PROFILE_NIX_FILE_SHELL}'\n\
+        fi\n\
+        {xdg_profiles}\
+        # End Nix\n
+        \n",
+        inde = "    ", // indent
+    )
+}
+
+/// The hook loading Nix from the fish `conf.d`
+fn fish_hook(annotation: &str, use_xdg_base_directories: bool) -> String {
+    let xdg_profiles = match use_xdg_base_directories {
+        true => XDG_PROFILES_FISH,
+        false => "",
+    };
+    format!(
+        "\n\
+        # Nix\n\
+        {annotation}\
+        if test -e '{PROFILE_NIX_FILE_FISH}'\n\
+        {inde}. '{PROFILE_NIX_FILE_FISH}'\n\
+        end\n\
+        {xdg_profiles}\
+        # End Nix\n\
+    \n",
+        inde = "    ", // indent
+    )
+}
+
+/// The per-user profiles in `home`: `~/.nix-profile`, and the one under `$XDG_STATE_HOME` (or
+/// `~/.local/state`) which `use-xdg-base-directories` moves it to
+pub(crate) fn user_profiles(home: &Path, xdg_state_home: Option<&Path>) -> [PathBuf; 2] {
+    let state_home = match xdg_state_home {
+        Some(xdg_state_home) if xdg_state_home.is_absolute() => xdg_state_home.to_path_buf(),
+        // The XDG base directory specification ignores relative paths
+        _ => home.join(".local/state"),
+    };
+    [home.join(".nix-profile"), state_home.join("nix/profile")]
+}
+
+/// A takeover of `path`, if it holds only a hook of another installer and not `hook`
+fn foreign_hook_takeover(path: &Path, hook: &str) -> Option<ShellHookTakeover> {
+    if !path.is_file() {
+        return None;
+    }
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            tracing::debug!(%e, "Could not read `{}` for a foreign hook", path.display());
+            return None;
+        },
+    };
+    if content.contains(hook) {
+        return None;
+    }
+    let foreign = recognize_foreign_shell_hook(&content)?;
+    tracing::debug!(
+        "`{}` holds only the hook of {}, taking it over",
+        path.display(),
+        foreign.origin
+    );
+    Some(ShellHookTakeover {
+        path: path.to_path_buf(),
+        origin: foreign.origin.to_string(),
+        backup: None,
+    })
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "configure_shell_profile")]
 impl Action for ConfigureShellProfile {
@@ -188,32 +510,128 @@ impl Action for ConfigureShellProfile {
         ActionTag("configure_shell_profile")
     }
     fn tracing_synopsis(&self) -> String {
-        "Configure the shell profiles".to_string()
+        match &self.owner {
+            Some(owner) => format!("Configure the shell profiles of user `{}`", owner.name),
+            None => "Configure the shell profiles".to_string(),
+        }
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "configure_shell_profile",)
+        span!(
+            tracing::Level::DEBUG,
+            "configure_shell_profile",
+            paths = tracing::field::display(
+                self.create_or_insert_into_files
+                    .iter()
+                    .map(|v| v.inner().path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(
-            self.tracing_synopsis(),
-            vec!["Update shell profiles to import Nix".to_string()],
-        )]
+        let mut explanation = match &self.owner {
+            Some(owner) => std::iter::once(format!(
+                "Update the shell profiles of user `{}` in `{}` to import Nix, rather than those of the system",
+                owner.name,
+                owner.home.display()
+            ))
+            .chain(self.create_or_insert_into_files.iter().map(|file| {
+                format!("Add the Nix hook to `{}`", file.inner().path.display())
+            }))
+            .collect(),
+            None => vec!["Update shell profiles to import Nix".to_string()],
+        };
+        if self.use_xdg_base_directories {
+            explanation.push(
+                "Put the per-user profile under `$XDG_STATE_HOME` on `$PATH`, alongside `~/.nix-profile`"
+                    .to_string(),
+            );
+        }
+        explanation.extend(self.nix_login_shells.iter().map(|login_shell| {
+            format!(
+                "`{}` logs in with `{}`, which is provided by Nix",
+                login_shell.user,
+                login_shell.path.display()
+            )
+        }));
+        explanation.extend(self.takeovers.iter().map(|takeover| {
+            format!(
+                "Take over `{}` from {}, backing it up to restore on uninstall",
+                takeover.path.display(),
+                takeover.origin
+            )
+        }));
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.create_directories
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+            .chain(
+                self.create_or_insert_into_files
+                    .iter()
+                    .flat_map(|action| action.privileged_operations()),
+            )
+            .chain(
+                self.takeovers
+                    .iter()
+                    .map(|takeover| PrivilegedOperation::remove(&takeover.path)),
+            )
+            .chain(
+                self.backups
+                    .iter()
+                    .filter(|_| !self.takeovers.is_empty())
+                    .map(|backups| PrivilegedOperation::write(backups.dir(), None)),
+            )
+            .collect()
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        let skipped_home = self.inactive_home().await.map(|(homed_user, home)| {
+            homed::warn_inactive(
+                &homed_user,
+                "its shell profiles were not configured",
+                "run `sudo nix-installer repair hooks` as them",
+            );
+            home
+        });
+        let skipped = |path: &Path| {
+            skipped_home
+                .as_ref()
+                .is_some_and(|home| path.starts_with(home))
+        };
+
         for create_directory in &mut self.create_directories {
+            if skipped(&create_directory.inner().path) {
+                continue;
+            }
             create_directory.try_execute().await?;
         }
 
+        // Our hook is written in place of the foreign one, which is only kept as a backup
+        if let Some(backups) = &self.backups {
+            for takeover in &mut self.takeovers {
+                takeover.backup = backups.back_up(&takeover.path).await.map_err(Self::error)?;
+                crate::util::remove_file(&host_path(&takeover.path), OnMissing::Ignore)
+                    .await
+                    .map_err(|e| ActionErrorKind::Remove(takeover.path.clone(), e))
+                    .map_err(Self::error)?;
+            }
+        }
+
         let mut set = JoinSet::new();
         let mut errors = vec![];
 
         for (idx, create_or_insert_into_file) in
             self.create_or_insert_into_files.iter_mut().enumerate()
         {
+            if skipped(&create_or_insert_into_file.inner().path) {
+                continue;
+            }
             let span = tracing::Span::current().clone();
             let mut create_or_insert_into_file_clone = create_or_insert_into_file.clone();
             let _abort_handle = set.spawn(async move {
@@ -250,20 +668,65 @@ impl Action for ConfigureShellProfile {
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation =
+            match &self.owner {
+                Some(owner) => std::iter::once(format!(
+                    "Update the shell profiles of user `{}` in `{}` to no longer import Nix",
+                    owner.name,
+                    owner.home.display()
+                ))
+                .chain(self.create_or_insert_into_files.iter().map(|file| {
+                    format!("Remove the Nix hook from `{}`", file.inner().path.display())
+                }))
+                .collect(),
+                None => vec!["Update shell profiles to no longer import Nix".to_string()],
+            };
+        explanation.extend(self.takeovers.iter().map(|takeover| {
+            format!(
+                "Restore `{}` as {} wrote it",
+                takeover.path.display(),
+                takeover.origin
+            )
+        }));
         vec![ActionDescription::new(
             "Unconfigure the shell profiles".to_string(),
-            vec!["Update shell profiles to no longer import Nix".to_string()],
+            explanation,
         )]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
+        let skipped_home = self.inactive_home().await.map(|(homed_user, home)| {
+            let left = self
+                .create_or_insert_into_files
+                .iter()
+                .map(|file| &file.inner().path)
+                .filter(|path| path.starts_with(&home))
+                .map(|path| format!("`{}`", path.display()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            homed::warn_inactive(
+                &homed_user,
+                &format!("the Nix hook was left in {left}"),
+                "remove the blocks from `# Nix` to `# End Nix` in them, they do nothing without Nix",
+            );
+            home
+        });
+        let skipped = |path: &Path| {
+            skipped_home
+                .as_ref()
+                .is_some_and(|home| path.starts_with(home))
+        };
+
         let mut set = JoinSet::new();
         let mut errors = vec![];
 
         for (idx, create_or_insert_into_file) in
             self.create_or_insert_into_files.iter_mut().enumerate()
         {
+            if skipped(&create_or_insert_into_file.inner().path) {
+                continue;
+            }
             let mut create_or_insert_file_clone = create_or_insert_into_file.clone();
             let _abort_handle = set.spawn(async move {
                 create_or_insert_file_clone.try_revert().await?;
@@ -282,7 +745,21 @@ impl Action for ConfigureShellProfile {
             };
         }
 
-        for create_directory in self.create_directories.iter_mut() {
+        // Removing our hook left the taken over profiles empty, and so deleted
+        for takeover in &self.takeovers {
+            let (Some(backups), Some(backup)) = (&self.backups, &takeover.backup) else {
+                continue;
+            };
+            if let Err(e) = backups.restore(backup).await {
+                errors.push(Self::error(ActionErrorKind::from(e)));
+            }
+        }
+
+        // A directory is only empty once those created inside it are removed
+        for create_directory in self.create_directories.iter_mut().rev() {
+            if skipped(&create_directory.inner().path) {
+                continue;
+            }
             if let Err(err) = create_directory.try_revert().await {
                 errors.push(err);
             }
@@ -300,3 +777,602 @@ impl Action for ConfigureShellProfile {
         }
     }
 }
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigureShellProfileError {
+    #[error(
+        "Looking up the user who started `nix-installer`, whose shell profiles are configured"
+    )]
+    InvokingUser(#[source] Errno),
+    #[error("The user who started `nix-installer` does not exist, so their shell profiles cannot be configured")]
+    NoInvokingUser,
+}
+
+impl From<ConfigureShellProfileError> for ActionErrorKind {
+    fn from(val: ConfigureShellProfileError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+/// The login shell of a user, from `/etc/passwd` (or `dscl` on macOS)
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub(crate) struct LoginShell {
+    pub(crate) user: String,
+    #[serde_as(as = "LossyPath")]
+    pub(crate) path: PathBuf,
+}
+
+impl LoginShell {
+    /// The shell this is, if it is one whose profile is configured
+    pub(crate) fn shell(&self) -> Option<Shell> {
+        match self.path.file_name()?.to_str()? {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+
+    /// If the shell is in the Nix store, or in a profile pointing into it
+    pub(crate) fn is_nix_provided(&self) -> bool {
+        let path = self.path.to_string_lossy();
+        if NIX_SHELL_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+            || NIX_SHELL_HOME_PROFILES
+                .iter()
+                .any(|profile| path.contains(profile))
+        {
+            return true;
+        }
+        // Such as `/usr/local/bin/fish` linked into the store by hand
+        match std::fs::canonicalize(host_path(&self.path)) {
+            Ok(resolved) => resolved.starts_with(host_path("/nix/store")),
+            Err(_) => false,
+        }
+    }
+}
+
+/// The login shell of each user on this host
+///
+/// Entries which cannot be read are skipped, as are users without a login shell.
+pub(crate) async fn login_shells() -> Vec<LoginShell> {
+    #[cfg(target_os = "macos")]
+    let login_shells = {
+        let output = crate::command_output(
+            tokio::process::Command::new("/usr/bin/dscl")
+                .process_group(0)
+                .args([".", "-list", "/Users", "UserShell"])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await;
+        match output {
+            Ok(output) if output.status.success() => parse_dscl_user_shells(&output.stdout),
+            Ok(output) => {
+                tracing::debug!(
+                    stderr = %String::from_utf8_lossy(&output.stderr),
+                    "Could not list the login shells of users"
+                );
+                vec![]
+            },
+            Err(e) => {
+                tracing::debug!(%e, "Could not list the login shells of users");
+                vec![]
+            },
+        }
+    };
+    #[cfg(not(target_os = "macos"))]
+    let login_shells = match tokio::fs::read(host_path("/etc/passwd")).await {
+        Ok(content) => parse_passwd(&content),
+        Err(e) => {
+            tracing::debug!(%e, "Could not read `/etc/passwd` for the login shells of users");
+            vec![]
+        },
+    };
+    login_shells
+}
+
+/// The login shells in `content`, in the format of `/etc/passwd`
+#[cfg(not(target_os = "macos"))]
+fn parse_passwd(content: &[u8]) -> Vec<LoginShell> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    content
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty() && !line.starts_with(b"#"))
+        .filter_map(|line| {
+            let fields = line.split(|byte| *byte == b':').collect::<Vec<_>>();
+            let [user, _, _, _, _, _, shell] = fields.as_slice() else {
+                tracing::debug!(
+                    entry = %String::from_utf8_lossy(line),
+                    "Skipping an unreadable `/etc/passwd` entry"
+                );
+                return None;
+            };
+            let shell = shell.strip_suffix(b"\r").unwrap_or(*shell);
+            // NIS entries (`+user`) have no shell of their own
+            if user.is_empty() || user.starts_with(b"+") || user.starts_with(b"-") {
+                return None;
+            }
+            if shell.is_empty() {
+                return None;
+            }
+            Some(LoginShell {
+                user: String::from_utf8_lossy(user).to_string(),
+                path: PathBuf::from(OsStr::from_bytes(shell)),
+            })
+        })
+        .collect()
+}
+
+/// The login shells in the output of `dscl . -list /Users UserShell`
+#[cfg(target_os = "macos")]
+fn parse_dscl_user_shells(content: &[u8]) -> Vec<LoginShell> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter_map(|line| {
+            let (user, shell) = line.trim().split_once(char::is_whitespace)?;
+            let shell = shell.trim();
+            // Users without a `UserShell` only list their name
+            (!shell.is_empty()).then(|| LoginShell {
+                user: user.to_string(),
+                path: PathBuf::from(shell),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        planner::FishShellProfileLocations,
+        test_harness::{FakeCommand, SandboxContext},
+    };
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn handles_non_utf8_fish_prefix() -> eyre::Result<()> {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let sandbox = SandboxContext::new()?;
+        let fish_prefix = sandbox.path("/etc").join(OsStr::from_bytes(b"fish-\xff"));
+        tokio::fs::create_dir_all(fish_prefix.join("conf.d")).await?;
+
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![fish_prefix.clone()],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![],
+            zsh: vec![],
+        };
+        let mut actions = vec![sandbox
+            .scope(ConfigureShellProfile::plan(
+                locations, None, None, false, false,
+            ))
+            .await?
+            .boxed()];
+
+        sandbox.execute(&mut actions).await?;
+
+        let profile = fish_prefix.join("conf.d/nix.fish");
+        assert!(tokio::fs::read_to_string(&profile)
+            .await?
+            .contains(PROFILE_NIX_FILE_FISH));
+        // Writing the receipt must not fail
+        serde_json::to_string(&actions)?;
+
+        sandbox.revert(&mut actions).await?;
+
+        assert!(!profile.exists(), "Profile should have been deleted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn annotation_is_part_of_the_hook() -> eyre::Result<()> {
+        const ANNOTATION: &str = "Owned by team-infra, see OPS-1234";
+        let sandbox = SandboxContext::new()?;
+        let bashrc = sandbox.path("/etc/bashrc");
+        tokio::fs::create_dir_all(sandbox.path("/etc")).await?;
+        tokio::fs::write(&bashrc, "# System bashrc\n").await?;
+
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![bashrc.clone()],
+            zsh: vec![],
+        };
+        let mut actions = vec![sandbox
+            .scope(ConfigureShellProfile::plan(
+                locations.clone(),
+                Some(ANNOTATION.into()),
+                None,
+                false,
+                false,
+            ))
+            .await?
+            .boxed()];
+        sandbox.execute(&mut actions).await?;
+
+        let contents = tokio::fs::read_to_string(&bashrc).await?;
+        assert!(contents.contains(&format!("# Nix\n# {ANNOTATION}\nif [ -e")));
+
+        // The annotated hook is recognized as already in place
+        let replanned = sandbox
+            .scope(ConfigureShellProfile::plan(
+                locations,
+                Some(ANNOTATION.into()),
+                None,
+                false,
+                false,
+            ))
+            .await?;
+        assert!(replanned
+            .action
+            .create_or_insert_into_files
+            .iter()
+            .all(|v| v.state == crate::action::ActionState::Completed));
+
+        sandbox.revert(&mut actions).await?;
+        assert_eq!(
+            tokio::fs::read_to_string(&bashrc).await?,
+            "# System bashrc\n"
+        );
+
+        Ok(())
+    }
+
+    const FOREIGN_HOOKS: &[(&str, &str)] = &[
+        (
+            "the Nix 2.x shell installer",
+            include_str!("../../../tests/fixtures/shell-installer/nix-2.x-profile.d-nix.sh"),
+        ),
+        (
+            "a Nix 1.x multi-user install",
+            include_str!("../../../tests/fixtures/shell-installer/nix-1.x-profile.d-nix.sh"),
+        ),
+    ];
+
+    fn profile_d_locations(profile: &Path) -> ShellProfileLocations {
+        ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![profile.to_path_buf()],
+            zsh: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn takes_over_foreign_hooks_and_restores_them() -> eyre::Result<()> {
+        for (origin, foreign) in FOREIGN_HOOKS {
+            let sandbox = SandboxContext::new()?;
+            let profile = sandbox.path("/etc/profile.d/nix.sh");
+            tokio::fs::create_dir_all(profile.parent().unwrap()).await?;
+            tokio::fs::write(&profile, foreign).await?;
+
+            let action = sandbox
+                .scope(ConfigureShellProfile::plan(
+                    profile_d_locations(&profile),
+                    None,
+                    Some(BackupStore::new("/nix/var/nix-installer")),
+                    false,
+                    false,
+                ))
+                .await?;
+            assert_eq!(action.inner().takeovers.len(), 1, "{origin}");
+            assert_eq!(action.inner().takeovers[0].origin, *origin);
+            let mut actions = vec![action.boxed()];
+            sandbox.execute(&mut actions).await?;
+
+            // Only our hook is left, not both
+            let contents = tokio::fs::read_to_string(&profile).await?;
+            assert!(contents.contains(PROFILE_NIX_FILE_SHELL), "{origin}");
+            assert!(!contents.contains("\n  . '"), "{origin}: {contents}");
+            let receipt = serde_json::to_value(&actions)?;
+            assert!(
+                receipt.to_string().contains("\"backup\":{"),
+                "{origin}: the backup is recorded"
+            );
+
+            sandbox.revert(&mut actions).await?;
+            assert_eq!(
+                tokio::fs::read_to_string(&profile).await?,
+                *foreign,
+                "{origin}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unrecognized_profiles_are_not_taken_over() -> eyre::Result<()> {
+        let (_, foreign) = FOREIGN_HOOKS[0];
+        let edited = format!("{foreign}export NIX_PATH=nixpkgs=/etc/nixpkgs\n");
+        let sandbox = SandboxContext::new()?;
+        let profile = sandbox.path("/etc/profile.d/nix.sh");
+        tokio::fs::create_dir_all(profile.parent().unwrap()).await?;
+        tokio::fs::write(&profile, &edited).await?;
+
+        let action = sandbox
+            .scope(ConfigureShellProfile::plan(
+                profile_d_locations(&profile),
+                None,
+                Some(BackupStore::new("/nix/var/nix-installer")),
+                false,
+                false,
+            ))
+            .await?;
+        assert!(action.inner().takeovers.is_empty());
+        let mut actions = vec![action.boxed()];
+        sandbox.execute(&mut actions).await?;
+        assert!(tokio::fs::read_to_string(&profile)
+            .await?
+            .ends_with(&edited));
+
+        sandbox.revert(&mut actions).await?;
+        assert_eq!(tokio::fs::read_to_string(&profile).await?, edited);
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn reads_login_shells_from_passwd() {
+        let passwd = b"root:x:0:0:root:/root:/bin/bash\n\
+            # A comment\n\
+            alice:x:1000:1000:Alice:/home/alice:/run/current-system/sw/bin/fish\r\n\
+            broken:x:1001\n\
+            +nisuser::::::\n\
+            nobody:x:65534:65534::/nonexistent:\n\
+            bob:x:1002:1002:\xff:/home/bob:/home/bob/.nix-profile/bin/zsh\n\
+            carol:x:1003:1003::/home/carol:/nix/store/abc-bash-5.2/bin/bash\n";
+        let login_shells = parse_passwd(passwd);
+        assert_eq!(
+            login_shells
+                .iter()
+                .map(|v| (v.user.as_str(), v.is_nix_provided(), v.shell()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("root", false, Some(Shell::Bash)),
+                ("alice", true, Some(Shell::Fish)),
+                ("bob", true, Some(Shell::Zsh)),
+                ("carol", true, Some(Shell::Bash)),
+            ]
+        );
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[tokio::test]
+    async fn nix_provided_fish_gets_a_hook_without_etc_fish() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        tokio::fs::create_dir_all(sandbox.path("/etc")).await?;
+        tokio::fs::write(
+            sandbox.path("/etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\n\
+            alice:x:1000:1000::/home/alice:/etc/profiles/per-user/alice/bin/fish\n",
+        )
+        .await?;
+        let fish_prefix = sandbox.path("/etc/fish");
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![fish_prefix.clone()],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![],
+            zsh: vec![],
+        };
+
+        let action = sandbox
+            .scope(ConfigureShellProfile::plan(
+                locations, None, None, false, false,
+            ))
+            .await?;
+        assert_eq!(
+            action
+                .inner()
+                .nix_login_shells
+                .iter()
+                .map(|v| v.user.as_str())
+                .collect::<Vec<_>>(),
+            vec!["alice"]
+        );
+        let mut actions = vec![action.boxed()];
+        sandbox.execute(&mut actions).await?;
+        assert!(
+            tokio::fs::read_to_string(fish_prefix.join("conf.d/nix.fish"))
+                .await?
+                .contains(PROFILE_NIX_FILE_FISH)
+        );
+
+        sandbox.revert(&mut actions).await?;
+        assert!(
+            !fish_prefix.exists(),
+            "`/etc/fish` should have been removed"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hooks_only_add_the_xdg_profiles_when_asked() {
+        // Unchanged from earlier releases, so their hooks are recognized as in place
+        assert_eq!(
+            shell_hook("", false),
+            format!("\n# Nix\nif [ -e '{PROFILE_NIX_FILE_SHELL}' ]; then\n    . '{PROFILE_NIX_FILE_SHELL}'\nfi\n# End Nix\n\n        \n")
+        );
+        assert_eq!(
+            fish_hook("", false),
+            format!("\n# Nix\nif test -e '{PROFILE_NIX_FILE_FISH}'\n    . '{PROFILE_NIX_FILE_FISH}'\nend\n# End Nix\n\n")
+        );
+
+        for (legacy, xdg, profiles) in [
+            (
+                shell_hook("", false),
+                shell_hook("", true),
+                XDG_PROFILES_SHELL,
+            ),
+            (fish_hook("", false), fish_hook("", true), XDG_PROFILES_FISH),
+        ] {
+            let (before, after) = legacy.split_once("# End Nix").unwrap();
+            assert_eq!(xdg, format!("{before}{profiles}# End Nix{after}"));
+            assert!(xdg.contains("$HOME/.nix-profile"));
+        }
+        assert!(shell_hook("", true).contains("${XDG_STATE_HOME:-$HOME/.local/state}/nix/profile"));
+        assert!(fish_hook("", true).contains("$nix_state_home/nix/profile"));
+    }
+
+    #[test]
+    fn xdg_hook_puts_both_layouts_on_path() -> eyre::Result<()> {
+        let home = tempfile::tempdir()?;
+        let legacy = home.path().join(".nix-profile");
+        let xdg = home.path().join(".local/state/nix/profile");
+        std::fs::create_dir_all(&legacy)?;
+        std::fs::create_dir_all(&xdg)?;
+
+        let hook = shell_hook("", true);
+        // Loading the hook twice, like a login shell starting another, adds nothing more
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "{hook}{hook}printf '%s\\n%s\\n' \"$PATH\" \"$NIX_PROFILES\""
+            ))
+            .env_clear()
+            .env("HOME", home.path())
+            .env("PATH", "/usr/bin:/bin")
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        let stdout = String::from_utf8(output.stdout)?;
+        let (legacy, xdg) = (legacy.display(), xdg.display());
+        assert_eq!(
+            stdout,
+            format!("{xdg}/bin:{legacy}/bin:/usr/bin:/bin\n{legacy} {xdg}\n")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_profiles_stand_in_for_the_system_ones() -> eyre::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let sandbox = SandboxContext::new()?;
+        let owner = ProfileOwner::invoking()?;
+        let home = sandbox.path(&owner.home);
+        std::fs::create_dir_all(home.join(".config/fish"))?;
+        std::fs::write(home.join(".profile"), "# My profile\n")?;
+
+        // Skipped shells stay skipped
+        let without_zsh = ShellProfileLocations::default()
+            .without(&[Shell::Zsh])
+            .for_user(&home);
+        assert!(without_zsh.zsh.is_empty());
+        assert_eq!(without_zsh.bash, [home.join(".profile")]);
+
+        let action = sandbox
+            .scope(ConfigureShellProfile::plan(
+                ShellProfileLocations::default(),
+                None,
+                Some(BackupStore::new("/nix/var/nix-installer")),
+                false,
+                true,
+            ))
+            .await?;
+        assert_eq!(
+            action
+                .inner()
+                .create_or_insert_into_files
+                .iter()
+                .map(|file| file.inner().path.clone())
+                .collect::<Vec<_>>(),
+            [
+                home.join(".profile"),
+                home.join(".zshrc"),
+                home.join(".config/fish/conf.d/nix.fish")
+            ]
+        );
+        let description = &action.describe_execute()[0];
+        assert!(description.description.contains(&owner.name));
+        assert!(description.explanation[0].contains(&owner.home.display().to_string()));
+
+        let mut actions = vec![action.boxed()];
+        sandbox.execute(&mut actions).await?;
+        let profile = std::fs::read_to_string(home.join(".profile"))?;
+        assert!(profile.contains(PROFILE_NIX_FILE_SHELL));
+        assert!(profile.ends_with("# My profile\n"));
+        let zshrc = std::fs::metadata(home.join(".zshrc"))?;
+        assert_eq!(
+            zshrc.uid(),
+            User::from_name(&owner.name)?
+                .expect("The owner exists")
+                .uid
+                .as_raw()
+        );
+
+        // Only the lines which were added are removed
+        sandbox.revert(&mut actions).await?;
+        assert_eq!(
+            std::fs::read_to_string(home.join(".profile"))?,
+            "# My profile\n"
+        );
+        assert!(!home.join(".zshrc").exists());
+        assert!(!home.join(".config/fish/conf.d").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inactive_homes_are_skipped() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let owner = ProfileOwner::invoking()?;
+        let home = sandbox.path(&owner.home);
+        std::fs::create_dir_all(&home)?;
+        std::fs::write(home.join(".profile"), "# My profile\n")?;
+        let homectl_list = |state: &str| {
+            FakeCommand::success().stdout(format!(
+                "{} 60100 60100 {state} Ada Lovelace {} /bin/bash\n",
+                owner.name,
+                owner.home.display()
+            ))
+        };
+        let plan = || {
+            ConfigureShellProfile::plan(ShellProfileLocations::default(), None, None, false, true)
+        };
+
+        // Nothing is planned in an inactive home
+        sandbox.fake("homectl", homectl_list("inactive"));
+        let (action, warnings) = warning::collect(sandbox.scope(plan())).await;
+        assert!(action?.inner().create_or_insert_into_files.is_empty());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::InactiveHome);
+        assert!(warnings[0].message.contains("nix-installer repair hooks"));
+
+        sandbox.fake("homectl", homectl_list("active"));
+        let mut actions = vec![sandbox.scope(plan()).await?.boxed()];
+        sandbox.execute(&mut actions).await?;
+        assert!(std::fs::read_to_string(home.join(".profile"))?.contains(PROFILE_NIX_FILE_SHELL));
+
+        // The home is left alone, listing what is left in it
+        sandbox.fake("homectl", homectl_list("inactive"));
+        let (reverted, warnings) = warning::collect(sandbox.revert(&mut actions)).await;
+        reverted?;
+        assert!(std::fs::read_to_string(home.join(".profile"))?.contains(PROFILE_NIX_FILE_SHELL));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::InactiveHome);
+        assert!(warnings[0]
+            .message
+            .contains(&format!("`{}`", home.join(".profile").display())));
+        assert!(!sandbox
+            .invocations_of("homectl")
+            .iter()
+            .any(|invocation| invocation.args.first().map(String::as_str) == Some("activate")));
+        Ok(())
+    }
+}
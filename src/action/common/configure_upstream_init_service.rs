@@ -4,20 +4,34 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 
-use crate::action::common::configure_init_service::{SocketFile, UnitSrc};
-use crate::action::{common::ConfigureInitService, Action, ActionDescription};
-use crate::settings::InitSystem;
+use crate::action::common::configure_init_service::{ServiceMode, SocketFile, UnitSrc};
+use crate::action::{common::ConfigureInitService, Action, ActionDescription, PrivilegedOperation};
+use crate::settings::{DaemonResources, DaemonUser, InitSystem};
 use crate::util::OnMissing;
 
 // Linux
 const SERVICE_SRC: &str = "/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service";
-const SERVICE_DEST: &str = "/etc/systemd/system/nix-daemon.service";
+pub(crate) const SERVICE_DEST: &str = "/etc/systemd/system/nix-daemon.service";
+/// Where a user manager loads units from, under the home of its user
+pub(crate) const USER_UNIT_DIR: &str = ".config/systemd/user";
+/// The socket for a daemon in a user manager, in its runtime directory (`%t`), `/run/user/<uid>`
+const USER_SOCKET_UNIT: &str = "\
+[Unit]
+Description=Nix Daemon Socket
+ConditionPathIsReadWrite=/nix/var/nix
+
+[Socket]
+ListenStream=%t/nix/daemon-socket/socket
+
+[Install]
+WantedBy=sockets.target
+";
 
 // Darwin
 const DARWIN_NIX_DAEMON_SOURCE: &str =
     "/nix/var/nix/profiles/default/Library/LaunchDaemons/org.nixos.nix-daemon.plist";
 pub(crate) const DARWIN_NIX_DAEMON_DEST: &str = "/Library/LaunchDaemons/org.nixos.nix-daemon.plist";
-const DARWIN_LAUNCHD_SERVICE_NAME: &str = "org.nixos.nix-daemon";
+pub(crate) const DARWIN_LAUNCHD_SERVICE_NAME: &str = "org.nixos.nix-daemon";
 
 /**
 Configure the init to run the Nix daemon
@@ -33,6 +47,7 @@ impl ConfigureUpstreamInitService {
     pub async fn plan(
         init: InitSystem,
         start_daemon: bool,
+        daemon_user: Option<DaemonUser>,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let service_src: Option<PathBuf> = match init {
             InitSystem::Launchd => Some(DARWIN_NIX_DAEMON_SOURCE.into()),
@@ -84,6 +99,7 @@ impl ConfigureUpstreamInitService {
                 ),
                 dest: "/etc/systemd/system/nix-daemon.socket".into(),
             }],
+            daemon_user,
         )
         .await
         .map_err(Self::error)?;
@@ -95,6 +111,40 @@ impl ConfigureUpstreamInitService {
     }
 }
 
+impl StatefulAction<ConfigureUpstreamInitService> {
+    /// Run the daemon in the systemd user manager of `user`, whose home is `home`
+    pub(crate) fn in_user_manager(mut self, user: &str, uid: u32, home: &Path) -> Self {
+        let unit_dir = home.join(USER_UNIT_DIR);
+        self.action.configure_init_service = self.action.configure_init_service.in_user_manager(
+            user,
+            uid,
+            &unit_dir,
+            vec![SocketFile {
+                name: "nix-daemon.socket".into(),
+                src: UnitSrc::Literal(USER_SOCKET_UNIT.into()),
+                dest: unit_dir.join("nix-daemon.socket"),
+            }],
+        );
+        self
+    }
+
+    /// Start the daemon as `service_mode` says, see [`ServiceMode`]
+    pub(crate) fn with_service_mode(mut self, service_mode: ServiceMode) -> Self {
+        self.action.configure_init_service = self
+            .action
+            .configure_init_service
+            .with_service_mode(service_mode);
+        self
+    }
+
+    /// Have the init schedule the daemon as `resources` says, see [`DaemonResources`]
+    pub(crate) fn with_resources(mut self, resources: DaemonResources) -> Self {
+        self.action.configure_init_service =
+            self.action.configure_init_service.with_resources(resources);
+        self
+    }
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "create_upstream_init_service")]
 impl Action for ConfigureUpstreamInitService {
@@ -106,7 +156,11 @@ impl Action for ConfigureUpstreamInitService {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "create_upstream_init_service",)
+        span!(
+            tracing::Level::DEBUG,
+            "create_upstream_init_service",
+            init = %self.configure_init_service.inner().init,
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
@@ -116,6 +170,10 @@ impl Action for ConfigureUpstreamInitService {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.configure_init_service.privileged_operations()
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         self.configure_init_service
@@ -1,11 +1,15 @@
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
 use tracing::{span, Span};
 
 use crate::action::base::CreateDirectory;
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
+use crate::settings::DaemonUser;
+use crate::util::host_path;
 
 const PATHS: &[&str] = &[
     "/nix/var",
@@ -29,23 +33,52 @@ Create the `/nix` tree
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_nix_tree")]
 pub struct CreateNixTree {
-    create_directories: Vec<StatefulAction<CreateDirectory>>,
+    pub(crate) create_directories: Vec<StatefulAction<CreateDirectory>>,
+    /// Installing alongside a Nix store `nix-installer` did not create, see [`check_shared_store`](crate::planner::check_shared_store)
+    #[serde(default)]
+    shared_store: bool,
+    /// The [state directory](crate::state_dir), receipts written before it existed have none
+    #[serde(default)]
+    state_dir: Option<PathBuf>,
+    /// The `--daemon-user` which owns `/nix/var`, rather than `root`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    daemon_user: Option<DaemonUser>,
 }
 
 impl CreateNixTree {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan() -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(
+        shared_store: bool,
+        state_dir: &Path,
+        daemon_user: Option<DaemonUser>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
         let mut create_directories = Vec::default();
         for path in PATHS {
             // We use `create_dir` over `create_dir_all` to ensure we always set permissions right
-            create_directories.push(
-                CreateDirectory::plan(path, None, None, 0o0755, true)
+            let create_directory =
+                CreateDirectory::plan(host_path(path), None, None, 0o0755, !shared_store)
                     .await
-                    .map_err(Self::error)?,
-            )
+                    .map_err(Self::error)?;
+            // A shared store's existing directories (and their contents) are never removed
+            create_directories.push(if shared_store {
+                create_directory.preserve_existing()
+            } else {
+                create_directory
+            })
         }
 
-        Ok(Self { create_directories }.into())
+        Ok(Self {
+            create_directories,
+            shared_store,
+            state_dir: Some(state_dir.to_path_buf()),
+            daemon_user,
+        }
+        .into())
+    }
+
+    /// If this was installed alongside a Nix store `nix-installer` did not create, which it leaves
+    pub(crate) fn shared_store(&self) -> bool {
+        self.shared_store
     }
 }
 
@@ -60,11 +93,21 @@ impl Action for CreateNixTree {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "create_nix_tree",)
+        span!(
+            tracing::Level::DEBUG,
+            "create_nix_tree",
+            directories = self.create_directories.len(),
+            shared_store = self.shared_store,
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        let Self { create_directories } = &self;
+        let Self {
+            create_directories,
+            shared_store,
+            state_dir,
+            daemon_user,
+        } = &self;
 
         let mut create_directory_descriptions = Vec::new();
         for create_directory in create_directories {
@@ -72,15 +115,42 @@ impl Action for CreateNixTree {
                 create_directory_descriptions.push(val.description.clone())
             }
         }
-        vec![
-            ActionDescription::new(self.tracing_synopsis(), create_directory_descriptions),
-            ActionDescription::new(
+        if let Some(state_dir) = state_dir {
+            create_directory_descriptions.push(format!(
+                "Create the state directory `{}`",
+                state_dir.display()
+            ));
+        }
+        let mut buf = vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            create_directory_descriptions,
+        )];
+        if !shared_store {
+            let (uid, gid) = nix_var_owner(daemon_user.as_ref());
+            buf.push(ActionDescription::new(
                 "Synchronize /nix/var ownership".to_string(),
                 vec![format!(
-                    "Will update existing files in /nix/var to be owned by User ID 0, Group ID 0"
+                    "Will update existing files in /nix/var to be owned by User ID {uid}, Group ID {gid}"
                 )],
-            ),
-        ]
+            ));
+        }
+        buf
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self
+            .create_directories
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+            .collect::<Vec<_>>();
+        if let Some(state_dir) = &self.state_dir {
+            operations.push(PrivilegedOperation::write(state_dir, None));
+        }
+        if !self.shared_store {
+            // Re-owned to `root`, or the daemon user
+            operations.push(PrivilegedOperation::write("/nix/var", None));
+        }
+        operations
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
@@ -90,28 +160,57 @@ impl Action for CreateNixTree {
             create_directory.try_execute().await.map_err(Self::error)?;
         }
 
-        ensure_nix_var_ownership().await.map_err(Self::error)?;
+        if let Some(state_dir) = &self.state_dir {
+            create_state_dir(state_dir).await.map_err(Self::error)?;
+        }
+
+        if !self.shared_store {
+            let (uid, gid) = nix_var_owner(self.daemon_user.as_ref());
+            ensure_nix_var_ownership(uid, gid)
+                .await
+                .map_err(Self::error)?;
+        }
 
         Ok(())
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
+        // The state directory holds the receipts uninstalling may still need, so `uninstall`
+        // removes it once everything else is reverted
+        if self.shared_store {
+            // Only what this install created, and only once empty
+            return vec![ActionDescription::new(
+                "Remove the directories this install created in `/nix`".to_string(),
+                self.create_directories
+                    .iter()
+                    .rev()
+                    .flat_map(|create_directory| create_directory.describe_revert())
+                    .map(|desc| desc.description)
+                    .collect(),
+            )];
+        }
+
+        let mut explanation = vec![
+            format!("Nix and the Nix daemon require a Nix Store, which will be stored at `/nix`"),
+            format!(
+                "Removes: {}",
+                PATHS
+                    .iter()
+                    .rev()
+                    .map(|v| format!("`{v}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ];
+        if let Some(state_dir) = &self.state_dir {
+            explanation.push(format!(
+                "The state directory `{}` is removed last, once everything else is uninstalled",
+                state_dir.display()
+            ));
+        }
         vec![ActionDescription::new(
             "Remove the directory tree in `/nix`".to_string(),
-            vec![
-                format!(
-                    "Nix and the Nix daemon require a Nix Store, which will be stored at `/nix`"
-                ),
-                format!(
-                    "Removes: {}",
-                    PATHS
-                        .iter()
-                        .rev()
-                        .map(|v| format!("`{v}`"))
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                ),
-            ],
+            explanation,
         )]
     }
 
@@ -138,14 +237,33 @@ impl Action for CreateNixTree {
     }
 }
 
-/// Everything under /nix/var (with two deprecated exceptions below) should be owned by 0:0.
+/// Create the state directory, which may be outside `/nix`, or already exist when reinstalling
+async fn create_state_dir(state_dir: &Path) -> Result<(), ActionErrorKind> {
+    let path = host_path(state_dir);
+    tokio::fs::create_dir_all(&path)
+        .await
+        .map_err(|e| ActionErrorKind::CreateDirectory(path.clone(), e))?;
+    tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+        .await
+        .map_err(|e| ActionErrorKind::SetPermissions(0o755, path.clone(), e))
+}
+
+/// Who owns /nix/var, the daemon user if the daemon does not run as `root`
+fn nix_var_owner(daemon_user: Option<&DaemonUser>) -> (u32, u32) {
+    daemon_user.map_or((0, 0), |user| (user.uid, user.gid))
+}
+
+/// Everything under /nix/var (with two deprecated exceptions below) should be owned by `uid:gid`,
+/// 0:0 unless the daemon runs as another user.
 ///
 /// * /nix/var/nix/profiles/per-user/*
 /// * /nix/var/nix/gcroots/per-user/*
 ///
 /// This function walks /nix/var and makes sure that is true.
-async fn ensure_nix_var_ownership() -> Result<(), ActionErrorKind> {
-    let entryiter = walkdir::WalkDir::new("/nix/var")
+async fn ensure_nix_var_ownership(uid: u32, gid: u32) -> Result<(), ActionErrorKind> {
+    let per_user_profiles = host_path("/nix/var/nix/profiles/per-user");
+    let per_user_gcroots = host_path("/nix/var/nix/gcroots/per-user");
+    let entryiter = walkdir::WalkDir::new(host_path("/nix/var"))
         .follow_links(false)
         .same_file_system(true)
         .contents_first(true)
@@ -153,8 +271,8 @@ async fn ensure_nix_var_ownership() -> Result<(), ActionErrorKind> {
         .filter_entry(|entry| {
             let parent = entry.path().parent();
 
-            if parent == Some(std::path::Path::new("/nix/var/nix/profiles/per-user"))
-                || parent == Some(std::path::Path::new("/nix/var/nix/gcroots/per-user"))
+            if parent == Some(per_user_profiles.as_path())
+                || parent == Some(per_user_gcroots.as_path())
             {
                 // False means do *not* descend into this directory
                 // ...which we don't want to do, because the per-user subdirectories are usually owned by that user.
@@ -182,8 +300,8 @@ async fn ensure_nix_var_ownership() -> Result<(), ActionErrorKind> {
             },
         })
         .filter_map(|(entry, metadata)| {
-            // Dirents that are already 0:0 are to be skipped
-            if metadata.uid() == 0 && metadata.gid() == 0 {
+            // Dirents that are already owned correctly are to be skipped
+            if metadata.uid() == uid && metadata.gid() == gid {
                 return None;
             }
 
@@ -192,16 +310,69 @@ async fn ensure_nix_var_ownership() -> Result<(), ActionErrorKind> {
     for (entry, _metadata) in entryiter {
         tracing::debug!(
             path = %entry.path().to_string_lossy(),
-            "Re-owning path to 0:0"
+            "Re-owning path to {uid}:{gid}"
         );
 
-        if let Err(e) = std::os::unix::fs::lchown(entry.path(), Some(0), Some(0)) {
+        if let Err(e) = std::os::unix::fs::lchown(entry.path(), Some(uid), Some(gid)) {
             tracing::warn!(
                 path = %entry.path().to_string_lossy(),
                 %e,
-                "Failed to set the owner:group to 0:0"
+                "Failed to set the owner:group to {uid}:{gid}"
             );
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use super::CreateNixTree;
+    use crate::action::Action;
+    use crate::state_dir::DEFAULT_STATE_DIR;
+    use crate::test_harness::SandboxContext;
+
+    #[tokio::test]
+    async fn creates_the_state_dir() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/nix"))?;
+
+        let mut create_nix_tree = sandbox
+            .scope(CreateNixTree::plan(
+                false,
+                Path::new(DEFAULT_STATE_DIR),
+                None,
+            ))
+            .await?;
+        sandbox.scope(create_nix_tree.try_execute()).await?;
+
+        let metadata = std::fs::metadata(sandbox.path(DEFAULT_STATE_DIR))?;
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert!(create_nix_tree
+            .action
+            .revert_description()
+            .iter()
+            .flat_map(|description| &description.explanation)
+            .any(|line| line.contains(DEFAULT_STATE_DIR)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn creates_an_alternate_state_dir_instead() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/nix"))?;
+        let state_dir = Path::new("/persist/nix-installer");
+
+        let mut create_nix_tree = sandbox
+            .scope(CreateNixTree::plan(false, state_dir, None))
+            .await?;
+        sandbox.scope(create_nix_tree.try_execute()).await?;
+
+        assert!(sandbox.path(state_dir).is_dir());
+        assert!(!sandbox.path(DEFAULT_STATE_DIR).exists());
+        Ok(())
+    }
+}
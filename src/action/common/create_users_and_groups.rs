@@ -1,7 +1,8 @@
 use crate::{
     action::{
-        base::{AddUserToGroup, CreateGroup, CreateUser},
-        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+        base::{AddUserToGroup, CreateGroup, CreateUser, UserShellAndHome},
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionState, ActionTag,
+        PrivilegedOperation, StatefulAction,
     },
     settings::CommonSettings,
 };
@@ -15,6 +16,8 @@ pub struct CreateUsersAndGroups {
     pub(crate) nix_build_user_count: u32,
     pub(crate) nix_build_user_prefix: String,
     pub(crate) nix_build_user_id_base: u32,
+    #[serde(default)]
+    pub(crate) nix_build_user_shell_and_home: UserShellAndHome,
     pub(crate) create_group: StatefulAction<CreateGroup>,
     pub(crate) create_users: Vec<StatefulAction<CreateUser>>,
     pub(crate) add_users_to_groups: Vec<StatefulAction<AddUserToGroup>>,
@@ -23,31 +26,65 @@ pub struct CreateUsersAndGroups {
 impl CreateUsersAndGroups {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(settings: CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
-        let create_group = CreateGroup::plan(
-            settings.nix_build_group_name.clone(),
-            settings.nix_build_group_id,
-        )?;
+        let create_group = match settings.use_existing_build_group {
+            true => CreateGroup::plan_adopting(
+                settings.nix_build_group_name.clone(),
+                settings.nix_build_group_id,
+                &settings.nix_build_user_prefix,
+            )?,
+            false => CreateGroup::plan(
+                settings.nix_build_group_name.clone(),
+                settings.nix_build_group_id,
+            )?,
+        };
+        // An adopted group keeps its GID, which the users are created with
+        let nix_build_group_id = create_group.inner().gid;
+        let nix_build_user_shell_and_home = UserShellAndHome {
+            shell: settings.nix_build_user_shell.clone(),
+            home: settings.nix_build_user_home.clone(),
+            create_home: settings.nix_build_user_create_home,
+        };
         let mut create_users = Vec::with_capacity(settings.nix_build_user_count as usize);
         let mut add_users_to_groups = Vec::with_capacity(settings.nix_build_user_count as usize);
         for index in 1..=settings.nix_build_user_count {
-            create_users.push(
-                CreateUser::plan(
-                    format!("{}{index}", settings.nix_build_user_prefix),
-                    settings.nix_build_user_id_base + index,
-                    settings.nix_build_group_name.clone(),
-                    settings.nix_build_group_id,
-                    format!("Nix build user {index}"),
-                    true,
-                )
-                .await
-                .map_err(Self::error)?,
-            );
+            let name = format!("{}{index}", settings.nix_build_user_prefix);
+            let uid = settings.nix_build_user_id_base + index;
+            let comment = format!("Nix build user {index}");
+            let create_user = match settings.adopt_existing_build_users {
+                true => {
+                    CreateUser::plan_adopting(
+                        name.clone(),
+                        uid,
+                        settings.nix_build_group_name.clone(),
+                        nix_build_group_id,
+                        comment,
+                        nix_build_user_shell_and_home.clone(),
+                    )
+                    .await
+                },
+                false => {
+                    CreateUser::plan(
+                        name.clone(),
+                        uid,
+                        settings.nix_build_group_name.clone(),
+                        nix_build_group_id,
+                        comment,
+                        nix_build_user_shell_and_home.clone(),
+                        true,
+                    )
+                    .await
+                },
+            }
+            .map_err(Self::error)?;
+            // An adopted user keeps its UID
+            let uid = create_user.inner().uid;
+            create_users.push(create_user);
             add_users_to_groups.push(
                 AddUserToGroup::plan(
-                    format!("{}{index}", settings.nix_build_user_prefix),
-                    settings.nix_build_user_id_base + index,
+                    name,
+                    uid,
                     settings.nix_build_group_name.clone(),
-                    settings.nix_build_group_id,
+                    nix_build_group_id,
                 )
                 .await
                 .map_err(Self::error)?,
@@ -56,9 +93,10 @@ impl CreateUsersAndGroups {
         Ok(Self {
             nix_build_user_count: settings.nix_build_user_count,
             nix_build_group_name: settings.nix_build_group_name,
-            nix_build_group_id: settings.nix_build_group_id,
+            nix_build_group_id,
             nix_build_user_prefix: settings.nix_build_user_prefix,
             nix_build_user_id_base: settings.nix_build_user_id_base,
+            nix_build_user_shell_and_home,
             create_group,
             create_users,
             add_users_to_groups,
@@ -67,6 +105,25 @@ impl CreateUsersAndGroups {
     }
 }
 
+/// Execute `create_user`, leaving it `Uncompleted` if it failed before the user existed
+///
+/// The users created before it stay `Completed` in the receipt, so a retry only creates the users
+/// which are missing, and a revert only deletes the users which were created.
+async fn execute_create_user(
+    create_user: &mut StatefulAction<CreateUser>,
+) -> Result<(), ActionError> {
+    let result = create_user.try_execute().await;
+    if result.is_err() {
+        let name = &create_user.inner().name;
+        // A user which exists, even partially configured, stays `Progress` so a revert deletes it
+        if matches!(nix::unistd::User::from_name(name), Ok(None)) {
+            tracing::debug!("Creating user `{name}` failed before it existed");
+            create_user.state = ActionState::Uncompleted;
+        }
+    }
+    result
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "create_users_and_group")]
 impl Action for CreateUsersAndGroups {
@@ -105,6 +162,7 @@ impl Action for CreateUsersAndGroups {
             nix_build_group_id: _,
             nix_build_user_prefix: _,
             nix_build_user_id_base: _,
+            nix_build_user_shell_and_home: _,
             create_group,
             create_users,
             add_users_to_groups,
@@ -136,6 +194,21 @@ impl Action for CreateUsersAndGroups {
         vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self.create_group.privileged_operations();
+        operations.extend(
+            self.create_users
+                .iter()
+                .flat_map(|action| action.privileged_operations()),
+        );
+        operations.extend(
+            self.add_users_to_groups
+                .iter()
+                .flat_map(|action| action.privileged_operations()),
+        );
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
@@ -147,6 +220,7 @@ impl Action for CreateUsersAndGroups {
             nix_build_group_id: _,
             nix_build_user_prefix: _,
             nix_build_user_id_base: _,
+            nix_build_user_shell_and_home: _,
         } = self;
 
         // Create group
@@ -162,12 +236,16 @@ impl Action for CreateUsersAndGroups {
             }
             | OperatingSystem::Darwin => {
                 for create_user in create_users.iter_mut() {
-                    create_user.try_execute().await.map_err(Self::error)?;
+                    execute_create_user(create_user)
+                        .await
+                        .map_err(Self::error)?;
                 }
             },
             _ => {
                 for create_user in create_users.iter_mut() {
-                    create_user.try_execute().await.map_err(Self::error)?;
+                    execute_create_user(create_user)
+                        .await
+                        .map_err(Self::error)?;
                 }
                 // While we may be tempted to do something like this, it can break on many older OSes like Ubuntu 18.04:
                 // ```
@@ -218,6 +296,7 @@ impl Action for CreateUsersAndGroups {
             nix_build_group_id: _,
             nix_build_user_prefix: _,
             nix_build_user_id_base: _,
+            nix_build_user_shell_and_home: _,
             create_group,
             create_users,
             add_users_to_groups,
@@ -258,6 +337,12 @@ impl Action for CreateUsersAndGroups {
         }
     }
 
+    fn revert_incomplete(&self) -> bool {
+        self.create_users
+            .iter()
+            .any(|create_user| create_user.action.revert_incomplete())
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         let mut errors = vec![];
@@ -289,3 +374,210 @@ impl Action for CreateUsersAndGroups {
         }
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::CreateUsersAndGroups;
+    use crate::{
+        action::{
+            base::{AddUserToGroup, CreateGroup, CreateUser, UserShellAndHome},
+            Action, ActionState, StatefulAction,
+        },
+        settings::CommonSettings,
+        test_harness::{FakeCommand, SandboxContext},
+    };
+
+    const PREFIX: &str = "nixinstallertestbld";
+
+    /// `count` build users, with names and IDs which do not exist on the host
+    async fn plan(
+        sandbox: &SandboxContext,
+        count: u32,
+    ) -> eyre::Result<StatefulAction<Box<dyn Action>>> {
+        std::fs::create_dir_all(sandbox.path("/sbin"))?;
+        std::fs::write(sandbox.path("/sbin/nologin"), "")?;
+        let action = sandbox
+            .scope(async {
+                let mut create_users = vec![];
+                let mut add_users_to_groups = vec![];
+                for index in 1..=count {
+                    create_users.push(
+                        CreateUser::plan(
+                            format!("{PREFIX}{index}"),
+                            39000 + index,
+                            PREFIX.into(),
+                            39000,
+                            format!("Nix build user {index}"),
+                            UserShellAndHome::default(),
+                            false,
+                        )
+                        .await?,
+                    );
+                    add_users_to_groups.push(
+                        AddUserToGroup::plan(
+                            format!("{PREFIX}{index}"),
+                            39000 + index,
+                            PREFIX.into(),
+                            39000,
+                        )
+                        .await?,
+                    );
+                }
+                eyre::Ok(CreateUsersAndGroups {
+                    nix_build_group_name: PREFIX.into(),
+                    nix_build_group_id: 39000,
+                    nix_build_user_count: count,
+                    nix_build_user_prefix: PREFIX.into(),
+                    nix_build_user_id_base: 39000,
+                    nix_build_user_shell_and_home: UserShellAndHome::default(),
+                    create_group: CreateGroup::plan(PREFIX.into(), 39000)?,
+                    create_users,
+                    add_users_to_groups,
+                })
+            })
+            .await?;
+        Ok(StatefulAction::uncompleted(action).boxed())
+    }
+
+    /// Execute `action`, failing to create the `fail_at`th user, then round trip it through a receipt
+    async fn fail_at(
+        sandbox: &SandboxContext,
+        mut action: StatefulAction<Box<dyn Action>>,
+        fail_at: u32,
+    ) -> eyre::Result<StatefulAction<CreateUsersAndGroups>> {
+        for _ in 1..fail_at {
+            sandbox.fake_once("useradd", FakeCommand::success());
+        }
+        sandbox.fake_once(
+            "useradd",
+            FakeCommand::failure(1).stderr("useradd: cannot lock /etc/passwd"),
+        );
+        assert!(sandbox.scope(action.try_execute()).await.is_err());
+
+        let receipt = serde_json::to_string(&action)?;
+        let action: StatefulAction<Box<dyn Action>> = serde_json::from_str(&receipt)?;
+        Ok(action.downcast::<CreateUsersAndGroups>().unwrap())
+    }
+
+    fn states(action: &CreateUsersAndGroups) -> Vec<ActionState> {
+        action.create_users.iter().map(|user| user.state).collect()
+    }
+
+    #[tokio::test]
+    async fn a_failed_user_leaves_the_created_users_completed() -> eyre::Result<()> {
+        use ActionState::{Completed, Uncompleted};
+        let sandbox = SandboxContext::new()?;
+        let action = fail_at(&sandbox, plan(&sandbox, 5).await?, 3).await?;
+
+        assert_eq!(action.state, ActionState::Progress);
+        assert_eq!(action.inner().create_group.state, Completed);
+        assert_eq!(
+            states(action.inner()),
+            [Completed, Completed, Uncompleted, Uncompleted, Uncompleted]
+        );
+
+        // A retry only creates the missing users
+        let mut action = action.boxed();
+        sandbox.scope(action.try_execute()).await?;
+        let created = sandbox
+            .invocations_of("useradd")
+            .into_iter()
+            .filter_map(|invocation| invocation.args.last().cloned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            created,
+            [1, 2, 3, 3, 4, 5].map(|index| format!("{PREFIX}{index}"))
+        );
+        assert_eq!(sandbox.invocations_of("groupadd").len(), 1);
+        assert_eq!(action.state, Completed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reverting_a_partial_execution_only_deletes_the_created_users() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let action = fail_at(&sandbox, plan(&sandbox, 5).await?, 3).await?;
+
+        let descriptions = action.describe_revert();
+        let explanation = &descriptions[0].explanation;
+        assert_eq!(
+            explanation
+                .iter()
+                .filter(|line| line.contains(PREFIX) && line.starts_with("Delete user"))
+                .count(),
+            2
+        );
+
+        let mut action = action.boxed();
+        sandbox.scope(action.try_revert()).await?;
+        let deleted = sandbox
+            .invocations_of("userdel")
+            .into_iter()
+            .flat_map(|invocation| invocation.args)
+            .collect::<Vec<_>>();
+        assert_eq!(deleted, [1, 2].map(|index| format!("{PREFIX}{index}")));
+        assert_eq!(sandbox.invocations_of("groupdel").len(), 1);
+        assert_eq!(action.state, ActionState::Uncompleted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn an_existing_group_is_adopted_with_its_gid() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/etc"))?;
+        std::fs::write(
+            sandbox.path("/etc/group"),
+            format!("root:x:0:\n{PREFIX}:x:4242:{PREFIX}1\n"),
+        )?;
+        std::fs::write(sandbox.path("/etc/passwd"), "root:x:0:0::/root:/bin/sh\n")?;
+        let mut settings = CommonSettings::default().await?;
+        settings.nix_build_group_name = PREFIX.into();
+        settings.nix_build_group_id = 39000;
+        settings.nix_build_user_prefix = PREFIX.into();
+        settings.nix_build_user_id_base = 39000;
+        settings.nix_build_user_count = 2;
+        settings.use_existing_build_group = true;
+        let shell = sandbox.path(&settings.nix_build_user_shell);
+        std::fs::create_dir_all(shell.parent().unwrap())?;
+        std::fs::write(shell, "")?;
+
+        let action = sandbox
+            .scope(CreateUsersAndGroups::plan(settings.clone()))
+            .await?;
+        let create_group = &action.inner().create_group;
+        assert_eq!(create_group.state, ActionState::Completed);
+        assert!(create_group.inner().adopted);
+        assert_eq!(action.inner().nix_build_group_id, 4242);
+        assert!(action
+            .inner()
+            .create_users
+            .iter()
+            .all(|user| user.inner().gid == 4242));
+        assert!(action
+            .inner()
+            .add_users_to_groups
+            .iter()
+            .all(|add| add.inner().gid == 4242));
+        let provision_nix = sandbox
+            .scope(crate::action::common::ProvisionNix::plan(&settings, false))
+            .await?;
+        assert_eq!(
+            serde_json::to_value(&provision_nix)?["action"]["nix_store_gid"],
+            4242
+        );
+
+        // The receipt records the adoption, so the group is never deleted
+        let receipt = serde_json::to_string(&action.boxed())?;
+        let mut action: StatefulAction<Box<dyn Action>> = serde_json::from_str(&receipt)?;
+        sandbox.scope(action.try_execute()).await?;
+        assert!(sandbox.invocations_of("groupadd").is_empty());
+        assert!(action.describe_revert()[0]
+            .explanation
+            .iter()
+            .all(|line| !line.starts_with("Delete group")));
+        sandbox.scope(action.try_revert()).await?;
+        assert_eq!(sandbox.invocations_of("userdel").len(), 2);
+        assert!(sandbox.invocations_of("groupdel").is_empty());
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
-use crate::action::{
-    base::DeleteUser, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
-    StatefulAction,
+use crate::{
+    action::{
+        base::DeleteUser, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+        PrivilegedOperation, StatefulAction,
+    },
+    stale_build_users::StaleBuildUser,
 };
 use tracing::{span, Span};
 
@@ -72,6 +75,13 @@ impl Action for DeleteUsersInGroup {
         vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.delete_users
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+            .collect()
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         for delete_user in self.delete_users.iter_mut() {
@@ -117,3 +127,94 @@ impl Action for DeleteUsersInGroup {
         }
     }
 }
+
+/**
+Delete the build users left by installs with another `nix_build_user_prefix` or
+`nix_build_user_count`, with `--remove-stale-build-users`
+
+They are found while planning, users still running processes are left out.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "delete_stale_build_users")]
+pub struct DeleteStaleBuildUsers {
+    users: Vec<StaleBuildUser>,
+    delete_users: Vec<StatefulAction<DeleteUser>>,
+}
+
+impl DeleteStaleBuildUsers {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) async fn plan(
+        users: Vec<StaleBuildUser>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let mut delete_users = vec![];
+        for user in &users {
+            delete_users.push(DeleteUser::plan(user.name.clone()).await?)
+        }
+
+        Ok(Self {
+            users,
+            delete_users,
+        }
+        .into())
+    }
+
+    /// The users it deletes
+    pub(crate) fn users(&self) -> &[StaleBuildUser] {
+        &self.users
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "delete_stale_build_users")]
+impl Action for DeleteStaleBuildUsers {
+    fn action_tag() -> ActionTag {
+        ActionTag("delete_stale_build_users")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Delete {} stale build user(s) left by an install with another build user prefix or count",
+            self.users.len()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "delete_stale_build_users",
+            users = self.users.len(),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            "Requested with `--remove-stale-build-users`, users still running processes were left out, and users holding a Secure Token are skipped".to_string(),
+        ];
+        explanation.extend(self.users.iter().map(ToString::to_string));
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.delete_users
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+            .collect()
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        for delete_user in self.delete_users.iter_mut() {
+            delete_user.try_execute().await.map_err(Self::error)?;
+        }
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // Deleted users are not recreated
+        Ok(())
+    }
+}
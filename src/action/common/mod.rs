@@ -1,6 +1,9 @@
 //! [`Action`](crate::action::Action)s which only call other base plugins
 
+pub(crate) mod configure_daemon_tcp_listener;
+pub(crate) mod configure_daemon_user;
 pub(crate) mod configure_determinate_nixd_init_service;
+pub(crate) mod configure_github_path;
 pub(crate) mod configure_init_service;
 pub(crate) mod configure_nix;
 pub(crate) mod configure_shell_profile;
@@ -11,15 +14,27 @@ pub(crate) mod delete_users;
 pub(crate) mod place_nix_configuration;
 pub(crate) mod provision_determinate_nixd;
 pub(crate) mod provision_nix;
+pub(crate) mod register_nix_shells;
+pub(crate) mod requires_host_probing;
 
+pub use configure_daemon_tcp_listener::{
+    ConfigureDaemonTcpListener, ConfigureDaemonTcpListenerError,
+};
+pub use configure_daemon_user::{ConfigureDaemonUser, ConfigureDaemonUserError};
 pub use configure_determinate_nixd_init_service::ConfigureDeterminateNixdInitService;
-pub use configure_init_service::{ConfigureInitService, ConfigureNixDaemonServiceError};
+pub use configure_github_path::ConfigureGithubPath;
+pub use configure_init_service::{
+    ConfigureInitService, ConfigureNixDaemonServiceError, ServiceMode, SocketFile, UnitScope,
+    UnitSrc,
+};
 pub use configure_nix::ConfigureNix;
-pub use configure_shell_profile::ConfigureShellProfile;
+pub use configure_shell_profile::{ConfigureShellProfile, ConfigureShellProfileError};
 pub use configure_upstream_init_service::ConfigureUpstreamInitService;
 pub use create_nix_tree::CreateNixTree;
 pub use create_users_and_groups::CreateUsersAndGroups;
-pub use delete_users::DeleteUsersInGroup;
-pub use place_nix_configuration::PlaceNixConfiguration;
+pub use delete_users::{DeleteStaleBuildUsers, DeleteUsersInGroup};
+pub use place_nix_configuration::{PlaceNixConfiguration, PlaceNixConfigurationError};
 pub use provision_determinate_nixd::ProvisionDeterminateNixd;
 pub use provision_nix::ProvisionNix;
+pub use register_nix_shells::RegisterNixShells;
+pub use requires_host_probing::{RequiresHostProbing, RequiresHostProbingError};
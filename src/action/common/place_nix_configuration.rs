@@ -1,18 +1,23 @@
 use tracing::{span, Span};
 use url::Url;
 
-use crate::action::base::create_or_merge_nix_config::CreateOrMergeNixConfigError;
+use crate::action::base::create_or_merge_nix_config::{
+    CreateOrMergeNixConfigError, NixConfPermissions,
+};
 use crate::action::base::{CreateDirectory, CreateOrMergeNixConfig};
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
-use crate::parse_ssl_cert;
-use crate::settings::UrlOrPathOrString;
-use indexmap::map::Entry;
+use crate::backup::BackupStore;
+use crate::nix_settings::{known_settings, unknown_settings, UnknownSetting, BUNDLED_NIX_SERIES};
+use crate::settings::{ConfigProfile, UrlOrPathOrString};
+use crate::warning::{self, WarningKind};
+use indexmap::{map::Entry, IndexMap};
 use std::path::PathBuf;
 
 pub const NIX_CONF_FOLDER: &str = "/etc/nix";
-const NIX_CONF: &str = "/etc/nix/nix.conf";
+pub(crate) const NIX_CONF: &str = "/etc/nix/nix.conf";
 
 /**
 Place the `/etc/nix/nix.conf` file
@@ -22,69 +27,137 @@ Place the `/etc/nix/nix.conf` file
 pub struct PlaceNixConfiguration {
     create_directory: StatefulAction<CreateDirectory>,
     create_or_merge_nix_config: StatefulAction<CreateOrMergeNixConfig>,
+    /// Where each setting came from, receipts from before config profiles have none
+    #[serde(default)]
+    sources: IndexMap<String, Vec<NixConfSource>>,
 }
 
 impl PlaceNixConfiguration {
+    #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         nix_build_group_name: String,
         proxy: Option<Url>,
         ssl_cert_file: Option<PathBuf>,
+        config_profile: ConfigProfile,
         extra_internal_conf: Option<nix_config_parser::NixConfig>,
         extra_conf: Vec<UrlOrPathOrString>,
+        trusted_users: Vec<String>,
+        allowed_users: Vec<String>,
+        substituters: Vec<String>,
+        trusted_public_keys: Vec<String>,
         force: bool,
+        strict: bool,
+        annotation: Option<String>,
+        backups: Option<BackupStore>,
+        daemon_user: bool,
+        use_xdg_base_directories: bool,
+        permissions: NixConfPermissions,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let nix_config = Self::setup_nix_config(
+        let (nix_config, sources) = Self::setup_nix_config(
             nix_build_group_name,
             proxy,
             ssl_cert_file,
+            config_profile,
             extra_internal_conf,
             extra_conf,
+            trusted_users,
+            allowed_users,
+            substituters,
+            trusted_public_keys,
+            daemon_user,
+            use_xdg_base_directories,
         )
         .await?;
+        Self::check_known_settings(&nix_config, strict)?;
 
         let create_directory = CreateDirectory::plan(NIX_CONF_FOLDER, None, None, 0o0755, force)
             .await
             .map_err(Self::error)?;
-        let create_or_merge_nix_config = CreateOrMergeNixConfig::plan(NIX_CONF, nix_config)
-            .await
-            .map_err(Self::error)?;
+        let create_or_merge_nix_config = CreateOrMergeNixConfig::plan_with_permissions(
+            NIX_CONF,
+            nix_config,
+            annotation,
+            backups,
+            permissions,
+        )
+        .await
+        .map_err(Self::error)?;
         Ok(Self {
             create_directory,
             create_or_merge_nix_config,
+            sources,
         }
         .into())
     }
 
+    /// Check the settings `nix-installer` writes are known to the installed Nix
+    ///
+    /// Only the settings being added are checked, those already in an existing `nix.conf` are left
+    /// alone. Unknown settings are warned about, or are an error if `strict`.
+    fn check_known_settings(
+        nix_config: &nix_config_parser::NixConfig,
+        strict: bool,
+    ) -> Result<(), ActionError> {
+        let Some(known) = known_settings(BUNDLED_NIX_SERIES) else {
+            return Ok(());
+        };
+        let unknown = unknown_settings(nix_config.settings().keys().map(String::as_str), known);
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        if strict {
+            return Err(Self::error(PlaceNixConfigurationError::UnknownSettings(
+                unknown,
+            )));
+        }
+        for setting in unknown {
+            warning::warn(
+                WarningKind::UnknownNixSetting,
+                format!("Nix {BUNDLED_NIX_SERIES} does not know the setting {setting} being written to `{NIX_CONF}`, it will be ignored"),
+            );
+        }
+        Ok(())
+    }
+
+    /// The settings to write, and where each came from
+    ///
+    /// Starting from the settings of `config_profile`, `extra_conf` is applied, then the dedicated
+    /// settings (`extra_internal_conf` is those of `--determinate`), so the later win. Settings
+    /// holding lists are combined instead.
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     async fn setup_nix_config(
         nix_build_group_name: String,
         proxy: Option<Url>,
         ssl_cert_file: Option<PathBuf>,
+        config_profile: ConfigProfile,
         extra_internal_conf: Option<nix_config_parser::NixConfig>,
         extra_conf: Vec<UrlOrPathOrString>,
-    ) -> Result<nix_config_parser::NixConfig, ActionError> {
+        trusted_users: Vec<String>,
+        allowed_users: Vec<String>,
+        substituters: Vec<String>,
+        trusted_public_keys: Vec<String>,
+        daemon_user: bool,
+        use_xdg_base_directories: bool,
+    ) -> Result<
+        (
+            nix_config_parser::NixConfig,
+            IndexMap<String, Vec<NixConfSource>>,
+        ),
+        ActionError,
+    > {
         let mut extra_conf_text = vec![];
         for extra in extra_conf {
             let buf = match &extra {
                 UrlOrPathOrString::Url(url) => match url.scheme() {
                     "https" | "http" => {
-                        let mut buildable_client = reqwest::Client::builder();
-                        if let Some(proxy) = &proxy {
-                            buildable_client = buildable_client.proxy(
-                                reqwest::Proxy::all(proxy.clone())
-                                    .map_err(ActionErrorKind::Reqwest)
-                                    .map_err(Self::error)?,
-                            )
-                        }
-                        if let Some(ssl_cert_file) = &ssl_cert_file {
-                            let ssl_cert =
-                                parse_ssl_cert(ssl_cert_file).await.map_err(Self::error)?;
-                            buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                        }
-                        let client = buildable_client
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
+                        let client =
+                            crate::http_client_builder(proxy.as_ref(), ssl_cert_file.as_deref())
+                                .await
+                                .map_err(Self::error)?
+                                .build()
+                                .map_err(ActionErrorKind::Reqwest)
+                                .map_err(Self::error)?;
                         let req = client
                             .get(url.clone())
                             .build()
@@ -93,8 +166,13 @@ impl PlaceNixConfiguration {
                         let res = client
                             .execute(req)
                             .await
-                            .map_err(ActionErrorKind::Reqwest)
+                            .map_err(|e| ActionErrorKind::fetch(url, e))
                             .map_err(Self::error)?;
+                        if res.status() == reqwest::StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+                            return Err(Self::error(ActionErrorKind::ProxyRejectedCredentials(
+                                url.clone(),
+                            )));
+                        }
                         res.text()
                             .await
                             .map_err(ActionErrorKind::Reqwest)
@@ -116,117 +194,185 @@ impl PlaceNixConfiguration {
         }
 
         let extra_conf = extra_conf_text.join("\n");
-        let mut nix_config = nix_config_parser::NixConfig::parse_string(extra_conf, None)
+        let extra_conf = nix_config_parser::NixConfig::parse_string(extra_conf, None)
             .map_err(CreateOrMergeNixConfigError::ParseNixConfig)
             .map_err(Self::error)?;
 
-        let settings = nix_config.settings_mut();
-
+        let mut layers = NixConfLayers::default();
+        for (name, value) in config_profile.nix_conf() {
+            layers.apply(name, value, NixConfSource::Profile(config_profile));
+        }
+        for (name, value) in extra_conf.settings() {
+            layers.apply(name, value, NixConfSource::ExtraConf);
+        }
         if let Some(extra) = extra_internal_conf {
-            settings.extend(extra.into_settings().into_iter());
+            for (name, value) in extra.settings() {
+                layers.apply(name, value, NixConfSource::Flag("--determinate".into()));
+            }
+        }
+        layers.apply(
+            "build-users-group",
+            &nix_build_group_name,
+            NixConfSource::Flag("--nix-build-group-name".into()),
+        );
+        if !trusted_users.is_empty() {
+            // Writing `trusted-users` replaces Nix's default of `root`, which must stay trusted
+            if !layers.contains("trusted-users") {
+                layers.apply(
+                    "trusted-users",
+                    "root",
+                    NixConfSource::Flag("--trusted-user".into()),
+                );
+            }
+            layers.apply(
+                "trusted-users",
+                &trusted_users.join(" "),
+                NixConfSource::Flag("--trusted-user".into()),
+            );
+        }
+        if !allowed_users.is_empty() {
+            layers.apply(
+                "allowed-users",
+                &allowed_users.join(" "),
+                NixConfSource::Flag("--allowed-user".into()),
+            );
+        }
+        // Added to Nix's default substituters and keys, like `cache.nixos.org`, rather than
+        // replacing them
+        if !substituters.is_empty() {
+            layers.apply(
+                "extra-substituters",
+                &substituters.join(" "),
+                NixConfSource::Flag("--substituter".into()),
+            );
+        }
+        if !trusted_public_keys.is_empty() {
+            layers.apply(
+                "extra-trusted-public-keys",
+                &trusted_public_keys.join(" "),
+                NixConfSource::Flag("--trusted-public-key".into()),
+            );
+        }
+        if daemon_user {
+            // Without `root`, the daemon cannot switch to the build users and builds as itself
+            layers.apply(
+                "build-users-group",
+                "",
+                NixConfSource::Flag("--daemon-user".into()),
+            );
+            // Clients, even `root`, go through the daemon rather than writing to its store, the
+            // daemon itself is started with `store = local`. An `--extra-conf` store says where
+            // the daemon listens, like the user runtime directory of `--systemd-scope user`.
+            if !extra_conf.settings().contains_key("store") {
+                layers.apply(
+                    "store",
+                    "daemon",
+                    NixConfSource::Flag("--daemon-user".into()),
+                );
+            }
+        }
+        if use_xdg_base_directories {
+            layers.apply(
+                "use-xdg-base-directories",
+                "true",
+                NixConfSource::Flag("--use-xdg-base-directories".into()),
+            );
+        }
+        if let Some(ssl_cert_file) = ssl_cert_file {
+            let ssl_cert_file_canonical = ssl_cert_file
+                .canonicalize()
+                .map_err(|e| Self::error(ActionErrorKind::Canonicalize(ssl_cert_file, e)))?;
+            layers.apply(
+                "ssl-cert-file",
+                &crate::util::path_to_string_lossy(&ssl_cert_file_canonical),
+                NixConfSource::Flag("--ssl-cert-file".into()),
+            );
         }
 
-        settings.insert("build-users-group".to_string(), nix_build_group_name);
+        Ok(layers.finish())
+    }
+}
 
-        let experimental_features = ["nix-command", "flakes"];
-        match settings.entry("experimental-features".to_string()) {
-            Entry::Occupied(mut slot) => {
+/// Where a setting in the `nix.conf` the installer writes came from
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "source", content = "name", rename_all = "snake_case")]
+pub enum NixConfSource {
+    /// The `--config-profile`
+    Profile(ConfigProfile),
+    /// `--extra-conf`
+    ExtraConf,
+    /// A dedicated flag, such as `--ssl-cert-file`
+    Flag(String),
+}
+
+impl std::fmt::Display for NixConfSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixConfSource::Profile(profile) => write!(f, "the `{profile}` profile"),
+            NixConfSource::ExtraConf => write!(f, "`--extra-conf`"),
+            NixConfSource::Flag(flag) => write!(f, "`{flag}`"),
+        }
+    }
+}
+
+/// The settings of each source in turn, later ones winning
+#[derive(Debug, Default)]
+struct NixConfLayers {
+    nix_config: nix_config_parser::NixConfig,
+    sources: IndexMap<String, Vec<NixConfSource>>,
+}
+
+impl NixConfLayers {
+    fn apply(&mut self, name: &str, value: &str, source: NixConfSource) {
+        let settings = self.nix_config.settings_mut();
+        let sources = self.sources.entry(name.to_string()).or_default();
+        match settings.entry(name.to_string()) {
+            // Lists are combined, so each source only adds to them
+            Entry::Occupied(mut slot) if is_list(name) => {
                 let slot_mut = slot.get_mut();
-                for experimental_feature in experimental_features {
-                    if !slot_mut.contains(experimental_feature) {
+                for item in value.split_whitespace() {
+                    if !slot_mut.split_whitespace().any(|existing| existing == item) {
                         *slot_mut += " ";
-                        *slot_mut += experimental_feature;
+                        *slot_mut += item;
                     }
                 }
+                if !sources.contains(&source) {
+                    sources.push(source);
+                }
+            },
+            Entry::Occupied(mut slot) => {
+                *slot.get_mut() = value.to_string();
+                *sources = vec![source];
             },
             Entry::Vacant(slot) => {
-                let _ = slot.insert(experimental_features.join(" "));
+                slot.insert(value.to_string());
+                *sources = vec![source];
             },
-        };
-
-        // https://github.com/DeterminateSystems/nix-installer/issues/449#issuecomment-1551782281
-        #[cfg(not(target_os = "macos"))]
-        settings.insert("auto-optimise-store".to_string(), "true".to_string());
-
-        // https://github.com/NixOS/nix/pull/8047
-        settings.insert("always-allow-substitutes".to_string(), "true".to_string());
-
-        // base, unintrusive Determinate Nix options
-        {
-            // Add FlakeHub cache to the list of possible substituters, but disabled by default.
-            // This allows a user to turn on FlakeHub Cache by adding it to the `extra-substituters`
-            // list without being a trusted user.
-            let extra_trusted_substituters = ["https://cache.flakehub.com"];
-            match settings.entry("extra-trusted-substituters".to_string()) {
-                Entry::Occupied(mut slot) => {
-                    let slot_mut = slot.get_mut();
-                    for extra_trusted_substituter in extra_trusted_substituters {
-                        if !slot_mut.contains(extra_trusted_substituter) {
-                            *slot_mut += " ";
-                            *slot_mut += extra_trusted_substituter;
-                        }
-                    }
-                },
-                Entry::Vacant(slot) => {
-                    let _ = slot.insert(extra_trusted_substituters.join(" "));
-                },
-            };
-
-            // Add FlakeHub's cache signing keys to the allowed list, but unused unless a user
-            // specifies FlakeHub Cache as an `extra-substituter`.
-            let extra_trusted_public_keys = [
-                "cache.flakehub.com-3:hJuILl5sVK4iKm86JzgdXW12Y2Hwd5G07qKtHTOcDCM=",
-                "cache.flakehub.com-4:Asi8qIv291s0aYLyH6IOnr5Kf6+OF14WVjkE6t3xMio=",
-                "cache.flakehub.com-5:zB96CRlL7tiPtzA9/WKyPkp3A2vqxqgdgyTVNGShPDU=",
-                "cache.flakehub.com-6:W4EGFwAGgBj3he7c5fNh9NkOXw0PUVaxygCVKeuvaqU=",
-                "cache.flakehub.com-7:mvxJ2DZVHn/kRxlIaxYNMuDG1OvMckZu32um1TadOR8=",
-                "cache.flakehub.com-8:moO+OVS0mnTjBTcOUh2kYLQEd59ExzyoW1QgQ8XAARQ=",
-                "cache.flakehub.com-9:wChaSeTI6TeCuV/Sg2513ZIM9i0qJaYsF+lZCXg0J6o=",
-                "cache.flakehub.com-10:2GqeNlIp6AKp4EF2MVbE1kBOp9iBSyo0UPR9KoR0o1Y=",
-            ];
-            match settings.entry("extra-trusted-public-keys".to_string()) {
-                Entry::Occupied(mut slot) => {
-                    let slot_mut = slot.get_mut();
-                    for extra_trusted_public_key in extra_trusted_public_keys {
-                        if !slot_mut.contains(extra_trusted_public_key) {
-                            *slot_mut += " ";
-                            *slot_mut += extra_trusted_public_key;
-                        }
-                    }
-                },
-                Entry::Vacant(slot) => {
-                    let _ = slot.insert(extra_trusted_public_keys.join(" "));
-                },
-            };
         }
+    }
 
-        settings.insert(
-            "bash-prompt-prefix".to_string(),
-            "(nix:$name)\\040".to_string(),
-        );
-        settings.insert("max-jobs".to_string(), "auto".to_string());
-        if let Some(ssl_cert_file) = ssl_cert_file {
-            let ssl_cert_file_canonical = ssl_cert_file
-                .canonicalize()
-                .map_err(|e| Self::error(ActionErrorKind::Canonicalize(ssl_cert_file, e)))?;
-            settings.insert(
-                "ssl-cert-file".to_string(),
-                ssl_cert_file_canonical.display().to_string(),
-            );
-        }
-        settings.insert(
-            "extra-nix-path".to_string(),
-            "nixpkgs=flake:nixpkgs".to_string(),
-        );
-        settings.insert(
-            "upgrade-nix-store-path-url".to_string(),
-            "https://install.determinate.systems/nix-upgrade/stable/universal".to_string(),
-        );
+    fn contains(&self, name: &str) -> bool {
+        self.nix_config.settings().contains_key(name)
+    }
 
-        Ok(nix_config)
+    fn finish(
+        self,
+    ) -> (
+        nix_config_parser::NixConfig,
+        IndexMap<String, Vec<NixConfSource>>,
+    ) {
+        (self.nix_config, self.sources)
     }
 }
 
+/// If `name` is a setting holding a list which more than one source may add to
+fn is_list(name: &str) -> bool {
+    matches!(
+        name,
+        "experimental-features" | "trusted-users" | "allowed-users"
+    ) || name.starts_with("extra-")
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "place_nix_configuration")]
 impl Action for PlaceNixConfiguration {
@@ -238,13 +384,18 @@ impl Action for PlaceNixConfiguration {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "place_nix_configuration",)
+        span!(
+            tracing::Level::DEBUG,
+            "place_nix_configuration",
+            path = %self.create_or_merge_nix_config.inner().path.display(),
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
         let Self {
             create_or_merge_nix_config,
             create_directory,
+            sources,
         } = self;
 
         let mut explanation = vec![
@@ -255,13 +406,36 @@ impl Action for PlaceNixConfiguration {
         if let Some(val) = create_directory.describe_execute().first() {
             explanation.push(val.description.clone())
         }
-        for val in create_or_merge_nix_config.describe_execute().iter() {
-            explanation.push(val.description.clone())
+        if sources.is_empty() {
+            for val in create_or_merge_nix_config.describe_execute().iter() {
+                explanation.push(val.description.clone())
+            }
+        }
+        let settings = create_or_merge_nix_config
+            .action
+            .pending_nix_config()
+            .settings();
+        for (name, sources) in sources {
+            let Some(value) = settings.get(name) else {
+                continue;
+            };
+            let sources = sources
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" and ");
+            explanation.push(format!("`{name} = {value}` from {sources}"));
         }
 
         vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self.create_directory.privileged_operations();
+        operations.append(&mut self.create_or_merge_nix_config.privileged_operations());
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         self.create_directory
@@ -309,21 +483,41 @@ impl Action for PlaceNixConfiguration {
     }
 }
 
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum PlaceNixConfigurationError {
+    #[error("Nix {BUNDLED_NIX_SERIES} does not know these settings (pass them without `--strict-nix-conf` to write them anyway):\n{}", .0.iter().map(|setting| format!("* {setting}")).collect::<Vec<_>>().join("\n"))]
+    UnknownSettings(Vec<UnknownSetting>),
+}
+
+impl From<PlaceNixConfigurationError> for ActionErrorKind {
+    fn from(val: PlaceNixConfigurationError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[tokio::test]
     async fn extra_trusted_no_error() -> eyre::Result<()> {
-        let nix_config = PlaceNixConfiguration::setup_nix_config(
+        let (nix_config, _) = PlaceNixConfiguration::setup_nix_config(
             String::from("foo"),
             None,
             None,
+            ConfigProfile::DeterminateDefaults,
             None,
             vec![
                 UrlOrPathOrString::String(String::from("extra-trusted-substituters = barfoo")),
                 UrlOrPathOrString::String(String::from("extra-trusted-public-keys = foobar")),
             ],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            false,
+            false,
         )
         .await?;
 
@@ -347,4 +541,281 @@ mod tests {
 
         Ok(())
     }
+
+    async fn nix_config(extra_conf: &str) -> Result<nix_config_parser::NixConfig, ActionError> {
+        PlaceNixConfiguration::setup_nix_config(
+            String::from("nixbld"),
+            None,
+            None,
+            ConfigProfile::DeterminateDefaults,
+            Some(crate::settings::determinate_nix_settings()),
+            vec![UrlOrPathOrString::String(String::from(extra_conf))],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            false,
+            false,
+        )
+        .await
+        .map(|(nix_config, _)| nix_config)
+    }
+
+    #[tokio::test]
+    async fn extra_conf_and_flags_win_over_the_profile() -> eyre::Result<()> {
+        let (nix_config, sources) = PlaceNixConfiguration::setup_nix_config(
+            String::from("nixbld"),
+            None,
+            None,
+            ConfigProfile::Flakes,
+            None,
+            vec![UrlOrPathOrString::String(String::from(
+                "max-jobs = 4\nexperimental-features = ca-derivations flakes\nbuild-users-group = mine",
+            ))],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            false,
+            false,
+        )
+        .await?;
+        let settings = nix_config.settings();
+        let profile = NixConfSource::Profile(ConfigProfile::Flakes);
+
+        assert_eq!(settings.get("max-jobs").map(String::as_str), Some("4"));
+        assert_eq!(sources["max-jobs"], vec![NixConfSource::ExtraConf]);
+        assert_eq!(
+            settings.get("experimental-features").map(String::as_str),
+            Some("nix-command flakes ca-derivations")
+        );
+        assert_eq!(
+            sources["experimental-features"],
+            vec![profile.clone(), NixConfSource::ExtraConf]
+        );
+        assert_eq!(
+            settings.get("build-users-group").map(String::as_str),
+            Some("nixbld")
+        );
+        assert_eq!(
+            sources["build-users-group"],
+            vec![NixConfSource::Flag("--nix-build-group-name".into())]
+        );
+        assert_eq!(sources["accept-flake-config"], vec![profile]);
+        // Nothing from the profiles it was not given
+        assert!(!settings.contains_key("upgrade-nix-store-path-url"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trusted_and_allowed_users_are_combined() -> eyre::Result<()> {
+        let (nix_config, sources) = PlaceNixConfiguration::setup_nix_config(
+            String::from("nixbld"),
+            None,
+            None,
+            ConfigProfile::Conservative,
+            None,
+            vec![],
+            vec![String::from("alice"), String::from("@wheel")],
+            vec![String::from("alice")],
+            vec![],
+            vec![],
+            false,
+            false,
+        )
+        .await?;
+        let settings = nix_config.settings();
+        // Nix's default of `root` is kept
+        assert_eq!(
+            settings.get("trusted-users").map(String::as_str),
+            Some("root alice @wheel")
+        );
+        assert_eq!(
+            settings.get("allowed-users").map(String::as_str),
+            Some("alice")
+        );
+        assert_eq!(
+            sources["trusted-users"],
+            vec![NixConfSource::Flag("--trusted-user".into())]
+        );
+
+        // Combined with `--extra-conf`, which decides whether `root` is trusted
+        let (nix_config, sources) = PlaceNixConfiguration::setup_nix_config(
+            String::from("nixbld"),
+            None,
+            None,
+            ConfigProfile::Conservative,
+            None,
+            vec![UrlOrPathOrString::String(String::from(
+                "trusted-users = bob alice",
+            ))],
+            vec![String::from("alice"), String::from("carol")],
+            vec![],
+            vec![],
+            vec![],
+            false,
+            false,
+        )
+        .await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("trusted-users")
+                .map(String::as_str),
+            Some("bob alice carol")
+        );
+        assert_eq!(
+            sources["trusted-users"],
+            vec![
+                NixConfSource::ExtraConf,
+                NixConfSource::Flag("--trusted-user".into())
+            ]
+        );
+        assert!(!nix_config.settings().contains_key("allowed-users"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn substituters_and_keys_are_added_to_the_defaults() -> eyre::Result<()> {
+        let key = "cache.example.com-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=";
+        let (nix_config, sources) = PlaceNixConfiguration::setup_nix_config(
+            String::from("nixbld"),
+            None,
+            None,
+            ConfigProfile::DeterminateDefaults,
+            Some(crate::settings::determinate_nix_settings()),
+            vec![],
+            vec![],
+            vec![],
+            vec![String::from("https://cache.example.com")],
+            vec![String::from(key)],
+            false,
+            false,
+        )
+        .await?;
+        let settings = nix_config.settings();
+        assert_eq!(
+            settings.get("extra-substituters").map(String::as_str),
+            Some("https://cache.flakehub.com https://cache.example.com")
+        );
+        assert!(settings["extra-trusted-public-keys"].ends_with(&format!(" {key}")));
+        assert!(!settings.contains_key("substituters"));
+        assert!(!settings.contains_key("trusted-public-keys"));
+        assert_eq!(
+            sources["extra-substituters"].last(),
+            Some(&NixConfSource::Flag("--substituter".into()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conservative_profile_writes_only_what_is_needed() -> eyre::Result<()> {
+        let (nix_config, _) = PlaceNixConfiguration::setup_nix_config(
+            String::from("nixbld"),
+            None,
+            None,
+            ConfigProfile::Conservative,
+            None,
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            false,
+            false,
+        )
+        .await?;
+        assert_eq!(
+            nix_config.settings().keys().collect::<Vec<_>>(),
+            vec!["build-users-group"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn daemon_user_clients_use_the_daemon() -> eyre::Result<()> {
+        let store = |extra_conf: Vec<UrlOrPathOrString>| async move {
+            let (nix_config, _) = PlaceNixConfiguration::setup_nix_config(
+                String::from("nixbld"),
+                None,
+                None,
+                ConfigProfile::Conservative,
+                None,
+                extra_conf,
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                true,
+                false,
+            )
+            .await?;
+            eyre::Ok(nix_config.settings().get("store").cloned())
+        };
+        assert_eq!(store(vec![]).await?.as_deref(), Some("daemon"));
+        // Like the socket of a daemon in a systemd user manager
+        assert_eq!(
+            store(vec![UrlOrPathOrString::String(String::from(
+                "store = unix:///run/user/30100/nix/daemon-socket/socket"
+            ))])
+            .await?
+            .as_deref(),
+            Some("unix:///run/user/30100/nix/daemon-socket/socket")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn xdg_base_directories_are_set_by_their_flag() -> eyre::Result<()> {
+        let (nix_config, sources) = PlaceNixConfiguration::setup_nix_config(
+            String::from("nixbld"),
+            None,
+            None,
+            ConfigProfile::Conservative,
+            None,
+            vec![UrlOrPathOrString::String(String::from(
+                "use-xdg-base-directories = false",
+            ))],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            false,
+            true,
+        )
+        .await?;
+        assert_eq!(
+            nix_config
+                .settings()
+                .get("use-xdg-base-directories")
+                .map(String::as_str),
+            Some("true")
+        );
+        assert_eq!(
+            sources["use-xdg-base-directories"],
+            vec![NixConfSource::Flag("--use-xdg-base-directories".into())]
+        );
+        PlaceNixConfiguration::check_known_settings(&nix_config, true)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_settings_are_caught() -> eyre::Result<()> {
+        // Everything `nix-installer` writes itself is known
+        let known = nix_config("extra-substituters = https://example.com").await?;
+        PlaceNixConfiguration::check_known_settings(&known, true)?;
+
+        let typo = nix_config("experimental-fetures = ca-derivations").await?;
+        PlaceNixConfiguration::check_known_settings(&typo, false)?;
+        let err = PlaceNixConfiguration::check_known_settings(&typo, true).unwrap_err();
+        let ActionErrorKind::Custom(err) = err.kind() else {
+            panic!("Expected unknown settings, got {err:?}");
+        };
+        let err = err.to_string();
+        assert!(
+            err.contains("* `experimental-fetures` (did you mean `experimental-features`?)"),
+            "{err}"
+        );
+        Ok(())
+    }
 }
@@ -4,11 +4,14 @@ use std::path::PathBuf;
 use tracing::{span, Span};
 
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
+    },
     util::OnMissing,
 };
 
-const DETERMINATE_NIXD_BINARY_PATH: &str = "/usr/local/bin/determinate-nixd";
+pub(crate) const DETERMINATE_NIXD_BINARY_PATH: &str = "/usr/local/bin/determinate-nixd";
 /**
 Provision the determinate-nixd binary
 */
@@ -57,6 +60,13 @@ impl Action for ProvisionDeterminateNixd {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![
+            PrivilegedOperation::remove(&self.binary_location),
+            PrivilegedOperation::write(&self.binary_location, 0o555),
+        ]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let bytes = crate::settings::DETERMINATE_NIXD_BINARY
@@ -3,8 +3,9 @@ use tracing::{span, Span};
 use super::CreateNixTree;
 use crate::{
     action::{
-        base::{FetchAndUnpackNix, MoveUnpackedNix},
-        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+        base::{create_group::build_group_gid, FetchAndUnpackNix, MoveUnpackedNix},
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
     },
     settings::{CommonSettings, SCRATCH_DIR},
 };
@@ -20,6 +21,9 @@ Place Nix and it's requirements onto the target
 #[serde(tag = "action_name", rename = "provision_nix")]
 pub struct ProvisionNix {
     nix_store_gid: u32,
+    /// Installing alongside a Nix store `nix-installer` did not create, see [`check_shared_store`](crate::planner::check_shared_store)
+    #[serde(default)]
+    shared_store: bool,
 
     pub(crate) fetch_nix: StatefulAction<FetchAndUnpackNix>,
     pub(crate) create_nix_tree: StatefulAction<CreateNixTree>,
@@ -28,21 +32,31 @@ pub struct ProvisionNix {
 
 impl ProvisionNix {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(settings: &CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(
+        settings: &CommonSettings,
+        shared_store: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
         let fetch_nix = FetchAndUnpackNix::plan(
             settings.nix_package_url.clone(),
             PathBuf::from(SCRATCH_DIR),
             settings.proxy.clone(),
             settings.ssl_cert_file.clone(),
+            settings.fetch_retries,
+            settings.nix_package_sha256.clone(),
         )
         .await?;
 
-        let create_nix_tree = CreateNixTree::plan().await.map_err(Self::error)?;
+        let create_nix_tree =
+            CreateNixTree::plan(shared_store, &settings.state_dir, settings.daemon_user())
+                .await
+                .map_err(Self::error)?;
         let move_unpacked_nix = MoveUnpackedNix::plan(PathBuf::from(SCRATCH_DIR))
             .await
             .map_err(Self::error)?;
+        let nix_store_gid = build_group_gid(settings).map_err(Self::error)?;
         Ok(Self {
-            nix_store_gid: settings.nix_build_group_id,
+            nix_store_gid,
+            shared_store,
             fetch_nix,
             create_nix_tree,
             move_unpacked_nix,
@@ -62,7 +76,12 @@ impl Action for ProvisionNix {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "provision_nix",)
+        span!(
+            tracing::Level::DEBUG,
+            "provision_nix",
+            nix_store_gid = self.nix_store_gid,
+            shared_store = self.shared_store,
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
@@ -71,6 +90,7 @@ impl Action for ProvisionNix {
             create_nix_tree,
             move_unpacked_nix,
             nix_store_gid,
+            shared_store,
         } = &self;
 
         let mut buf = Vec::default();
@@ -79,16 +99,29 @@ impl Action for ProvisionNix {
         buf.append(&mut create_nix_tree.describe_execute());
         buf.append(&mut move_unpacked_nix.describe_execute());
 
-        buf.push(ActionDescription::new(
-            "Synchronize /nix/store ownership".to_string(),
-            vec![format!(
-                "Will update existing files in the Nix Store to use the Nix build group ID {nix_store_gid}"
-            )],
-        ));
+        if !shared_store {
+            buf.push(ActionDescription::new(
+                "Synchronize /nix/store ownership".to_string(),
+                vec![format!(
+                    "Will update existing files in the Nix Store to use the Nix build group ID {nix_store_gid}"
+                )],
+            ));
+        }
 
         buf
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self.fetch_nix.privileged_operations();
+        operations.append(&mut self.create_nix_tree.privileged_operations());
+        operations.append(&mut self.move_unpacked_nix.privileged_operations());
+        if !self.shared_store {
+            // Re-grouped to the Nix build group
+            operations.push(PrivilegedOperation::write("/nix/store", None));
+        }
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         // We fetch nix while doing the rest, then move it over.
@@ -112,9 +145,12 @@ impl Action for ProvisionNix {
             .await
             .map_err(Self::error)?;
 
-        ensure_nix_store_group(self.nix_store_gid)
-            .await
-            .map_err(Self::error)?;
+        // A shared store belongs to someone else, leave its ownership alone
+        if !self.shared_store {
+            ensure_nix_store_group(self.nix_store_gid)
+                .await
+                .map_err(Self::error)?;
+        }
 
         Ok(())
     }
@@ -125,6 +161,7 @@ impl Action for ProvisionNix {
             create_nix_tree,
             move_unpacked_nix,
             nix_store_gid: _,
+            shared_store: _,
         } = &self;
 
         let mut buf = Vec::default();
@@ -229,3 +266,96 @@ async fn ensure_nix_store_group(desired_nix_build_group_id: u32) -> Result<(), A
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        action::ActionState,
+        planner::{check_shared_store, PlannerError},
+        test_harness::SandboxContext,
+    };
+
+    /// A sandbox with a store that `nix-installer` did not create, like a container host's
+    fn shared_store_sandbox() -> std::io::Result<SandboxContext> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/nix/store/00000000000000000000000000000000-hello"))?;
+        std::fs::create_dir_all(sandbox.path("/nix/var/nix/db"))?;
+        Ok(sandbox)
+    }
+
+    #[tokio::test]
+    async fn shared_store_requires_acknowledgement() -> eyre::Result<()> {
+        let sandbox = shared_store_sandbox()?;
+        let mut settings = CommonSettings::default().await?;
+
+        let res = sandbox.scope(async { check_shared_store(&settings) }).await;
+        assert!(matches!(
+            res,
+            Err(PlannerError::SharedStoreNotAcknowledged(_))
+        ));
+
+        settings.shared_store_ok = true;
+        assert!(
+            sandbox
+                .scope(async { check_shared_store(&settings) })
+                .await?
+        );
+
+        // A store `nix-installer` created is never considered shared
+        settings.shared_store_ok = false;
+        std::fs::write(sandbox.path("/nix/receipt.json"), "{}")?;
+        assert!(
+            !sandbox
+                .scope(async { check_shared_store(&settings) })
+                .await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_store_revert_removes_no_store_contents() -> eyre::Result<()> {
+        let sandbox = shared_store_sandbox()?;
+        let settings = CommonSettings::default().await?;
+
+        let mut provision_nix = sandbox.scope(ProvisionNix::plan(&settings, true)).await?;
+        let create_directories = &mut provision_nix
+            .action
+            .create_nix_tree
+            .action
+            .create_directories;
+
+        // Directories which already existed are never touched
+        let db = create_directories
+            .iter()
+            .find(|create_directory| create_directory.action.path.ends_with("nix/var/nix/db"))
+            .expect("`/nix/var/nix/db` is planned");
+        assert_eq!(db.state, ActionState::Skipped);
+
+        // Pretend everything planned was executed, so the full revert plan is described
+        for create_directory in create_directories.iter_mut() {
+            if create_directory.state == ActionState::Uncompleted {
+                create_directory.state = ActionState::Completed;
+            }
+        }
+        provision_nix.action.create_nix_tree.state = ActionState::Completed;
+        provision_nix.state = ActionState::Completed;
+
+        let reverts = provision_nix
+            .describe_revert()
+            .into_iter()
+            .flat_map(|desc| std::iter::once(desc.description).chain(desc.explanation))
+            .collect::<Vec<_>>();
+        assert!(!reverts.is_empty());
+        for revert in reverts {
+            assert!(!revert.contains("Clean contents"), "{revert}");
+            assert!(!revert.starts_with("Removes:"), "{revert}");
+            if revert.starts_with("Remove the directory `") {
+                assert!(revert.ends_with("if no other contents exists"), "{revert}");
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{span, Span};
+
+use crate::action::base::{create_or_insert_into_file, CreateOrInsertIntoFile};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::util::LossyPath;
+
+use super::configure_shell_profile::LoginShell;
+
+/// The shells of the default profile are linked here
+const DEFAULT_PROFILE_BIN: &str = "/nix/var/nix/profiles/default/bin";
+
+/**
+List the shells provided by Nix in `/etc/shells`, so the users logging in with them keep a valid
+login shell
+
+For each shell users log in with from Nix, its path and that of the default profile are added,
+unless they are already listed. On revert, only the lines added are removed.
+*/
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "register_nix_shells")]
+pub struct RegisterNixShells {
+    #[serde_as(as = "Vec<LossyPath>")]
+    shells: Vec<PathBuf>,
+    insert_into_shells: Option<StatefulAction<CreateOrInsertIntoFile>>,
+}
+
+impl RegisterNixShells {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        shells_file: impl AsRef<Path>,
+        login_shells: &[LoginShell],
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let shells_file = shells_file.as_ref();
+        let listed = match tokio::fs::read_to_string(shells_file).await {
+            Ok(listed) => listed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(Self::error(ActionErrorKind::Read(
+                    shells_file.to_path_buf(),
+                    e,
+                )))
+            },
+        };
+        let shells = missing_shells(login_shells, &listed);
+        if shells.is_empty() {
+            return Ok(StatefulAction::completed(Self {
+                shells,
+                insert_into_shells: None,
+            }));
+        }
+
+        let mut buf = match listed.is_empty() || listed.ends_with('\n') {
+            true => String::new(),
+            false => "\n".to_string(),
+        };
+        for shell in &shells {
+            buf += &format!("{}\n", shell.display());
+        }
+        let insert_into_shells = CreateOrInsertIntoFile::plan(
+            shells_file,
+            None,
+            None,
+            0o644,
+            buf,
+            create_or_insert_into_file::Position::End,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(Self {
+            shells,
+            insert_into_shells: Some(insert_into_shells),
+        }
+        .into())
+    }
+}
+
+/// The shells provided by Nix which `login_shells` need and `listed`, the content of
+/// `/etc/shells`, does not have
+fn missing_shells(login_shells: &[LoginShell], listed: &str) -> Vec<PathBuf> {
+    let mut missing: Vec<PathBuf> = vec![];
+    for login_shell in login_shells.iter().filter(|v| v.is_nix_provided()) {
+        let Some(shell) = login_shell.shell() else {
+            continue;
+        };
+        let default_profile_shell = Path::new(DEFAULT_PROFILE_BIN).join(shell.to_string());
+        for path in [login_shell.path.clone(), default_profile_shell] {
+            let already_listed = listed.lines().any(|line| Path::new(line.trim()) == path);
+            if !already_listed && !missing.contains(&path) {
+                missing.push(path);
+            }
+        }
+    }
+    missing
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "register_nix_shells")]
+impl Action for RegisterNixShells {
+    fn action_tag() -> ActionTag {
+        ActionTag("register_nix_shells")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Add the shells provided by Nix to `/etc/shells`".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "register_nix_shells",
+            shells = tracing::field::display(
+                self.shells
+                    .iter()
+                    .map(|v| v.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            self.shells
+                .iter()
+                .map(|shell| format!("Add `{}`", shell.display()))
+                .collect(),
+        )]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.insert_into_shells
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+            .collect()
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        if let Some(insert_into_shells) = &mut self.insert_into_shells {
+            insert_into_shells
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            "Remove the shells provided by Nix from `/etc/shells`".to_string(),
+            self.shells
+                .iter()
+                .map(|shell| format!("Remove `{}`", shell.display()))
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        if let Some(insert_into_shells) = &mut self.insert_into_shells {
+            insert_into_shells.try_revert().await.map_err(Self::error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::RegisterNixShells;
+    use crate::action::common::configure_shell_profile::LoginShell;
+    use crate::action::ActionState;
+    use crate::test_harness::SandboxContext;
+
+    const SHELLS: &str = "# /etc/shells: valid login shells\n/bin/sh\n/bin/bash\n/usr/bin/zsh";
+
+    fn login_shell(user: &str, path: &str) -> LoginShell {
+        LoginShell {
+            user: user.into(),
+            path: path.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn adds_only_missing_nix_shells_and_reverts_cleanly() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let shells_file = sandbox.path("/etc/shells");
+        std::fs::create_dir_all(shells_file.parent().unwrap())?;
+        std::fs::write(&shells_file, SHELLS)?;
+        let login_shells = [
+            login_shell("alice", "/etc/profiles/per-user/alice/bin/fish"),
+            login_shell("bob", "/bin/bash"),
+            login_shell("carol", "/nix/var/nix/profiles/default/bin/fish"),
+        ];
+
+        let mut action = sandbox
+            .scope(RegisterNixShells::plan(&shells_file, &login_shells))
+            .await?;
+        assert_eq!(
+            action.inner().shells,
+            vec![
+                PathBuf::from("/etc/profiles/per-user/alice/bin/fish"),
+                PathBuf::from("/nix/var/nix/profiles/default/bin/fish"),
+            ]
+        );
+        let mut actions = [action.boxed()];
+        sandbox.execute(&mut actions).await?;
+        assert_eq!(
+            std::fs::read_to_string(&shells_file)?,
+            format!("{SHELLS}\n/etc/profiles/per-user/alice/bin/fish\n/nix/var/nix/profiles/default/bin/fish\n")
+        );
+
+        // Nothing is missing any more
+        action = sandbox
+            .scope(RegisterNixShells::plan(&shells_file, &login_shells))
+            .await?;
+        assert_eq!(action.state, ActionState::Completed);
+
+        sandbox.revert(&mut actions).await?;
+        assert_eq!(std::fs::read_to_string(&shells_file)?, SHELLS);
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use tracing::{span, Span};
+
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+
+/**
+Stand in for an action which could not be planned without inspecting the host, in a plan made for
+another platform with `nix-installer plan --target`
+
+It never runs, the plan must be made again on the host.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "requires_host_probing")]
+pub struct RequiresHostProbing {
+    /// The tag of the action this stands in for, such as `create_nix_volume`
+    pub(crate) action: String,
+    /// Why it could not be planned
+    pub(crate) reason: String,
+}
+
+impl RequiresHostProbing {
+    /// Stand in for the `action` planning it failed with `err`
+    pub(crate) fn plan(action: ActionTag, err: &ActionError) -> StatefulAction<Self> {
+        let mut reason = err.kind().to_string();
+        let mut source = std::error::Error::source(err.kind());
+        while let Some(err) = source {
+            reason.push_str(&format!(": {err}"));
+            source = err.source();
+        }
+        Self {
+            action: action.to_string(),
+            reason,
+        }
+        .into()
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "requires_host_probing")]
+impl Action for RequiresHostProbing {
+    fn action_tag() -> ActionTag {
+        ActionTag("requires_host_probing")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("`{}` requires host probing", self.action)
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "requires_host_probing",
+            action = self.action,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                format!("Planned without inspecting the host: {}", self.reason),
+                "Plan again on the host to install".to_string(),
+            ],
+        )]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        Err(Self::error(RequiresHostProbingError(self.action.clone())))
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("`{0}` was planned for another platform without inspecting the host, plan again on the host to install")]
+pub struct RequiresHostProbingError(String);
+
+impl From<RequiresHostProbingError> for ActionErrorKind {
+    fn from(val: RequiresHostProbingError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
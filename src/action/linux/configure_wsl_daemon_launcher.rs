@@ -0,0 +1,293 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::base::{create_or_insert_into_file, CreateFile, CreateOrInsertIntoFile};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::execute_command;
+use crate::planner::ShellProfileLocations;
+
+/// The launcher the shell profiles run, beside the `nix.conf` of the install
+pub(crate) const WSL_DAEMON_LAUNCHER: &str = "/etc/nix/wsl-start-nix-daemon.sh";
+
+/// Only one daemon is started: `flock` holds this lock for as long as the daemon it started runs
+const LAUNCHER: &str = r#"#!/bin/sh
+# Start the Nix daemon on WSL without systemd, unless it is running already
+#
+# Run by the shell profiles. Users other than root start it with `sudo -n`, so it is only started
+# for them when `sudo` needs no password (yet).
+daemon=/nix/var/nix/profiles/default/bin/nix-daemon
+lock=/nix/var/nix/wsl-daemon.lock
+[ -x "$daemon" ] || exit 0
+command -v flock >/dev/null 2>&1 || exit 0
+if [ "$(id -u)" -eq 0 ]; then
+    setsid -f flock -n "$lock" "$daemon" </dev/null >/dev/null 2>&1
+elif command -v sudo >/dev/null 2>&1 && sudo -n true >/dev/null 2>&1; then
+    sudo -n setsid -f flock -n "$lock" "$daemon" </dev/null >/dev/null 2>&1
+fi
+exit 0
+"#;
+
+/**
+Start the Nix daemon from the shell profiles, on WSL without systemd
+
+WSL only runs systemd when `/etc/wsl.conf` enables it, without it there is nothing to start the
+daemon at boot. A launcher is written instead, which every new shell runs to start the daemon if it
+is not running yet.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "configure_wsl_daemon_launcher")]
+pub struct ConfigureWslDaemonLauncher {
+    create_launcher: StatefulAction<CreateFile>,
+    create_or_insert_into_files: Vec<StatefulAction<CreateOrInsertIntoFile>>,
+}
+
+impl ConfigureWslDaemonLauncher {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        locations: ShellProfileLocations,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let create_launcher = CreateFile::plan(
+            WSL_DAEMON_LAUNCHER,
+            None,
+            None,
+            0o755,
+            LAUNCHER.into(),
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        let shell_buf = format!(
+            "\n\
+            # Nix daemon launcher\n\
+            if [ -x '{WSL_DAEMON_LAUNCHER}' ]; then\n\
+            {inde}'{WSL_DAEMON_LAUNCHER}'\n\
+            fi\n\
+            # End Nix daemon launcher\n",
+            inde = "    ", // indent
+        );
+        let fish_buf = format!(
+            "\n\
+            # Nix daemon launcher\n\
+            if test -x '{WSL_DAEMON_LAUNCHER}'\n\
+            {inde}'{WSL_DAEMON_LAUNCHER}'\n\
+            end\n\
+            # End Nix daemon launcher\n",
+            inde = "    ", // indent
+        );
+
+        let fish_profiles = locations
+            .fish
+            .confd_prefixes
+            .iter()
+            .map(|prefix| prefix.join(&locations.fish.confd_suffix));
+        let profiles = locations
+            .bash
+            .iter()
+            .chain(locations.zsh.iter())
+            .map(|profile| (profile.clone(), &shell_buf))
+            .chain(fish_profiles.map(|profile| (profile, &fish_buf)));
+
+        let mut create_or_insert_into_files = vec![];
+        for (profile, buf) in profiles {
+            // Like the shell hooks, only next to profiles which can exist, and never through a symlink
+            if profile.is_symlink() || !profile.parent().is_some_and(Path::exists) {
+                continue;
+            }
+            create_or_insert_into_files.push(
+                CreateOrInsertIntoFile::plan(
+                    &profile,
+                    None,
+                    None,
+                    0o644,
+                    buf.to_string(),
+                    create_or_insert_into_file::Position::End,
+                )
+                .await
+                .map_err(Self::error)?,
+            );
+        }
+
+        Ok(Self {
+            create_launcher,
+            create_or_insert_into_files,
+        }
+        .into())
+    }
+
+    fn profiles(&self) -> Vec<PathBuf> {
+        self.create_or_insert_into_files
+            .iter()
+            .map(|action| action.inner().path.clone())
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_wsl_daemon_launcher")]
+impl Action for ConfigureWslDaemonLauncher {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_wsl_daemon_launcher")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Start the Nix daemon from the shell profiles".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_wsl_daemon_launcher",
+            launcher = WSL_DAEMON_LAUNCHER,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            "systemd is not running in WSL, so nothing else starts the Nix daemon".to_string(),
+            format!("Write the launcher `{WSL_DAEMON_LAUNCHER}`, and run it to start the daemon"),
+        ];
+        for profile in self.profiles() {
+            explanation.push(format!(
+                "Run the launcher from `{}` in every new shell",
+                profile.display()
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self.create_launcher.privileged_operations();
+        operations.extend(
+            self.create_or_insert_into_files
+                .iter()
+                .flat_map(|action| action.privileged_operations()),
+        );
+        operations.push(PrivilegedOperation::command(
+            WSL_DAEMON_LAUNCHER,
+            Vec::<String>::new(),
+        ));
+        operations
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_launcher
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+        for create_or_insert_into_file in &mut self.create_or_insert_into_files {
+            create_or_insert_into_file
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+
+        // Start the daemon now, rather than with the next shell
+        execute_command(
+            Command::new(WSL_DAEMON_LAUNCHER)
+                .process_group(0)
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            format!("Remove the launcher `{WSL_DAEMON_LAUNCHER}`"),
+            "Stop the Nix daemon it started".to_string(),
+        ];
+        for profile in self.profiles() {
+            explanation.push(format!(
+                "Stop running the launcher from `{}`",
+                profile.display()
+            ));
+        }
+        vec![ActionDescription::new(
+            "Stop starting the Nix daemon from the shell profiles".to_string(),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        for create_or_insert_into_file in self.create_or_insert_into_files.iter_mut().rev() {
+            if let Err(err) = create_or_insert_into_file.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Err(err) = self.create_launcher.try_revert().await {
+            errors.push(err);
+        }
+
+        // No daemon running is fine, `pkill` exits 1 when it matches nothing
+        let mut pkill = Command::new("pkill");
+        pkill
+            .process_group(0)
+            .args(["--exact", "nix-daemon"])
+            .stdin(std::process::Stdio::null());
+        match crate::command_output(&mut pkill).await {
+            Ok(output) if output.status.success() || output.status.code() == Some(1) => (),
+            Ok(output) => errors.push(Self::error(ActionErrorKind::command_output(&pkill, output))),
+            Err(e) => errors.push(Self::error(ActionErrorKind::command(&pkill, e))),
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConfigureWslDaemonLauncher, WSL_DAEMON_LAUNCHER};
+    use crate::planner::ShellProfileLocations;
+
+    #[tokio::test]
+    async fn runs_the_launcher_from_existing_profiles() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("conf.d"))?;
+        let mut locations = ShellProfileLocations {
+            bash: vec![dir.path().join("bashrc"), dir.path().join("missing/bashrc")],
+            zsh: vec![],
+            ..Default::default()
+        };
+        locations.fish.confd_prefixes = vec![dir.path().to_path_buf()];
+
+        let action = ConfigureWslDaemonLauncher::plan(locations).await?;
+        let profiles = action.inner().profiles();
+        assert_eq!(
+            profiles,
+            [
+                dir.path().join("bashrc"),
+                dir.path()
+                    .join(&ShellProfileLocations::default().fish.confd_suffix),
+            ]
+        );
+        let planned = serde_json::to_value(action.inner())?;
+        let block = |idx: usize| {
+            planned["create_or_insert_into_files"][idx]["action"]["buf"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string()
+        };
+        assert!(block(0).contains(&format!("if [ -x '{WSL_DAEMON_LAUNCHER}' ]; then")));
+        assert!(block(1).contains(&format!("if test -x '{WSL_DAEMON_LAUNCHER}'")));
+        Ok(())
+    }
+}
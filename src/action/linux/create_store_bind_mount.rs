@@ -0,0 +1,375 @@
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::base::{create_or_insert_into_file, CreateOrInsertIntoFile};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionState, ActionTag,
+    PrivilegedOperation, StatefulAction,
+};
+use crate::execute_command;
+use crate::util::host_path;
+
+/// Where the store root is bind mounted
+pub(crate) const STORE_ROOT_MOUNTPOINT: &str = "/nix";
+const FSTAB: &str = "/etc/fstab";
+
+/**
+Bind mount a directory on another filesystem, like `/data/nix`, at `/nix`, so the Nix store
+physically lives there
+
+The mount is added to `/etc/fstab` so it is mounted again at boot. As every later action works
+through `/nix`, what they write (the unpacked Nix, the store, and the database) ends up under the
+store root. The Nix daemon units require the mounts for `/nix/store`, so systemd starts them after
+the `nix.mount` generated from the `/etc/fstab` entry.
+
+An empty `/nix` directory is mounted over, one with contents is refused, as is an `/etc/fstab`
+which already mounts something else at `/nix`. On revert, `/nix` is unmounted and its
+`/etc/fstab` entry removed, leaving the store root itself in place.
+*/
+#[serde_with::serde_as]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "create_store_bind_mount")]
+pub struct CreateStoreBindMount {
+    #[serde_as(as = "crate::util::LossyPath")]
+    pub(crate) store_root: PathBuf,
+    insert_into_fstab: StatefulAction<CreateOrInsertIntoFile>,
+}
+
+impl CreateStoreBindMount {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(store_root: impl AsRef<Path>) -> Result<StatefulAction<Self>, ActionError> {
+        let store_root = store_root.as_ref().to_path_buf();
+        let fstab_path = host_path(FSTAB);
+        let fstab = match tokio::fs::read_to_string(&fstab_path).await {
+            Ok(fstab) => fstab,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Self::error(ActionErrorKind::Read(fstab_path, e))),
+        };
+        let entry = fstab_entry(&store_root);
+        if let Some(existing) = nix_fstab_entry(&fstab) {
+            if existing.split_whitespace().ne(entry.split_whitespace()) {
+                return Err(Self::error(CreateStoreBindMountError::FstabHasNix(
+                    existing.to_string(),
+                )));
+            }
+        }
+
+        let mounted = is_bind_mounted(&store_root).map_err(Self::error)?;
+        if !mounted && has_contents(&host_path(STORE_ROOT_MOUNTPOINT)).map_err(Self::error)? {
+            return Err(Self::error(CreateStoreBindMountError::NixNotEmpty));
+        }
+
+        let buf = match fstab.is_empty() || fstab.ends_with('\n') {
+            true => format!("{entry}\n"),
+            false => format!("\n{entry}\n"),
+        };
+        let insert_into_fstab = CreateOrInsertIntoFile::plan(
+            fstab_path,
+            None,
+            None,
+            0o644,
+            buf,
+            create_or_insert_into_file::Position::End,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        let completed = mounted && insert_into_fstab.state == ActionState::Completed;
+        let this = Self {
+            store_root,
+            insert_into_fstab,
+        };
+        match completed {
+            true => Ok(StatefulAction::completed(this)),
+            false => Ok(StatefulAction::uncompleted(this)),
+        }
+    }
+
+    fn mount_args(&self) -> Vec<String> {
+        vec![
+            "--bind".to_string(),
+            self.store_root.display().to_string(),
+            STORE_ROOT_MOUNTPOINT.to_string(),
+        ]
+    }
+}
+
+/// The `/etc/fstab` line bind mounting `store_root` at `/nix`, once the filesystem holding it is mounted
+fn fstab_entry(store_root: &Path) -> String {
+    let source = fstab_escape(&store_root.display().to_string());
+    format!("{source} {STORE_ROOT_MOUNTPOINT} none bind,x-systemd.requires-mounts-for={source} 0 0")
+}
+
+/// Escape the whitespace `/etc/fstab` would otherwise split `field` on
+fn fstab_escape(field: &str) -> String {
+    field
+        .replace('\\', "\\134")
+        .replace(' ', "\\040")
+        .replace('\t', "\\011")
+}
+
+/// The line of `fstab` mounting something at `/nix`, if any
+fn nix_fstab_entry(fstab: &str) -> Option<&str> {
+    fstab.lines().map(str::trim).find(|line| {
+        !line.starts_with('#') && line.split_whitespace().nth(1) == Some(STORE_ROOT_MOUNTPOINT)
+    })
+}
+
+/// Whether `/nix` is already `store_root`, as it is once bind mounted
+pub(crate) fn is_bind_mounted(store_root: &Path) -> Result<bool, ActionErrorKind> {
+    let metadata = |path: PathBuf| match std::fs::metadata(&path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ActionErrorKind::GettingMetadata(path, e)),
+    };
+    match (
+        metadata(host_path(store_root))?,
+        metadata(host_path(STORE_ROOT_MOUNTPOINT))?,
+    ) {
+        (Some(store_root), Some(nix)) => {
+            Ok(store_root.dev() == nix.dev() && store_root.ino() == nix.ino())
+        },
+        _ => Ok(false),
+    }
+}
+
+fn has_contents(path: &Path) -> Result<bool, ActionErrorKind> {
+    match path.read_dir() {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(ActionErrorKind::Read(path.to_path_buf(), e)),
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "create_store_bind_mount")]
+impl Action for CreateStoreBindMount {
+    fn action_tag() -> ActionTag {
+        ActionTag("create_store_bind_mount")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Bind mount `{}` at `{STORE_ROOT_MOUNTPOINT}`",
+            self.store_root.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "create_store_bind_mount",
+            store_root = tracing::field::display(self.store_root.display()),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                format!("Run `mount {}`", self.mount_args().join(" ")),
+                format!(
+                    "Add `{}` to `{FSTAB}`, so it is mounted at boot",
+                    fstab_entry(&self.store_root)
+                ),
+            ],
+        )]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = vec![
+            PrivilegedOperation::command("mount", self.mount_args()),
+            PrivilegedOperation::command("umount", [STORE_ROOT_MOUNTPOINT]),
+        ];
+        operations.extend(self.insert_into_fstab.privileged_operations());
+        operations
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let mountpoint = host_path(STORE_ROOT_MOUNTPOINT);
+        if !is_bind_mounted(&self.store_root).map_err(Self::error)? {
+            tokio::fs::create_dir_all(&mountpoint)
+                .await
+                .map_err(|e| ActionErrorKind::CreateDirectory(mountpoint.clone(), e))
+                .map_err(Self::error)?;
+            execute_command(
+                Command::new("mount")
+                    .process_group(0)
+                    .args(self.mount_args())
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
+
+        self.insert_into_fstab
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("Unmount `{STORE_ROOT_MOUNTPOINT}` and remove its entry from `{FSTAB}`"),
+            vec![format!(
+                "`{}` itself is left in place",
+                self.store_root.display()
+            )],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut command = Command::new("umount");
+        command
+            .process_group(0)
+            .arg(STORE_ROOT_MOUNTPOINT)
+            .stdin(std::process::Stdio::null());
+        let output = crate::command_output(&mut command)
+            .await
+            .map_err(|e| ActionErrorKind::command(&command, e))
+            .map_err(Self::error)?;
+        if !output.status.success() {
+            // Such as when a failed install never mounted it
+            if String::from_utf8_lossy(&output.stderr).contains("not mounted") {
+                tracing::debug!("`{STORE_ROOT_MOUNTPOINT}` was not mounted");
+            } else {
+                return Err(Self::error(ActionErrorKind::command_output(
+                    &command, output,
+                )));
+            }
+        }
+
+        self.insert_into_fstab
+            .try_revert()
+            .await
+            .map_err(Self::error)?;
+
+        // The mountpoint is left behind once unmounted
+        let mountpoint = host_path(STORE_ROOT_MOUNTPOINT);
+        match tokio::fs::remove_dir(&mountpoint).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Self::error(ActionErrorKind::Remove(
+                STORE_ROOT_MOUNTPOINT.into(),
+                e,
+            ))),
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateStoreBindMountError {
+    #[error("`{FSTAB}` already mounts something at `{STORE_ROOT_MOUNTPOINT}` (`{0}`), remove it or install without `--store-root`")]
+    FstabHasNix(String),
+    #[error("`{STORE_ROOT_MOUNTPOINT}` has contents which the store root would be mounted over, remove them or install without `--store-root`")]
+    NixNotEmpty,
+}
+
+impl From<CreateStoreBindMountError> for ActionErrorKind {
+    fn from(val: CreateStoreBindMountError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fstab_entry, nix_fstab_entry, CreateStoreBindMount, CreateStoreBindMountError};
+    use crate::action::{ActionErrorKind, ActionState};
+    use crate::test_harness::{FakeCommand, Invocation, SandboxContext};
+
+    const FSTAB: &str =
+        "# /etc/fstab\nUUID=1234 / ext4 defaults 0 1\nUUID=5678 /data xfs defaults 0 2";
+
+    #[test]
+    fn fstab_entries() {
+        assert_eq!(
+            fstab_entry("/data/nix store".as_ref()),
+            "/data/nix\\040store /nix none bind,x-systemd.requires-mounts-for=/data/nix\\040store 0 0"
+        );
+        assert_eq!(nix_fstab_entry(FSTAB), None);
+        assert_eq!(
+            nix_fstab_entry("#/data/nix /nix none bind 0 0\n/dev/sdb1 /nix ext4 defaults 0 2\n"),
+            Some("/dev/sdb1 /nix ext4 defaults 0 2")
+        );
+    }
+
+    #[tokio::test]
+    async fn mounts_and_reverts() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/data/nix"))?;
+        std::fs::create_dir_all(sandbox.path("/etc"))?;
+        std::fs::write(sandbox.path("/etc/fstab"), FSTAB)?;
+
+        let action = sandbox
+            .scope(CreateStoreBindMount::plan("/data/nix"))
+            .await?;
+        assert_eq!(action.state, ActionState::Uncompleted);
+        let mut actions = [action.boxed()];
+        sandbox.execute(&mut actions).await?;
+        assert_eq!(
+            sandbox.invocations_of("mount"),
+            [Invocation::new("mount", ["--bind", "/data/nix", "/nix"])]
+        );
+        assert!(sandbox.path("/nix").is_dir());
+        assert_eq!(
+            std::fs::read_to_string(sandbox.path("/etc/fstab"))?,
+            format!(
+                "{FSTAB}\n/data/nix /nix none bind,x-systemd.requires-mounts-for=/data/nix 0 0\n"
+            )
+        );
+
+        // Something else mounted at `/nix` is refused
+        assert!(matches!(
+            sandbox.scope(CreateStoreBindMount::plan("/data/other")).await,
+            Err(err) if matches!(
+                err.kind(),
+                ActionErrorKind::Custom(e) if matches!(
+                    e.downcast_ref::<CreateStoreBindMountError>(),
+                    Some(CreateStoreBindMountError::FstabHasNix(_))
+                )
+            )
+        ));
+
+        sandbox.fake(
+            "umount",
+            FakeCommand::failure(32).stderr("umount: /nix: not mounted."),
+        );
+        sandbox.revert(&mut actions).await?;
+        assert_eq!(
+            sandbox.invocations_of("umount"),
+            [Invocation::new("umount", ["/nix"])]
+        );
+        assert_eq!(std::fs::read_to_string(sandbox.path("/etc/fstab"))?, FSTAB);
+        assert!(!sandbox.path("/nix").exists());
+        assert!(sandbox.path("/data/nix").is_dir());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_a_nix_with_contents() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/data/nix"))?;
+        std::fs::create_dir_all(sandbox.path("/nix/store"))?;
+
+        let err = sandbox
+            .scope(CreateStoreBindMount::plan("/data/nix"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("create_store_bind_mount"), "{err}");
+        assert!(matches!(
+            err.kind(),
+            ActionErrorKind::Custom(e) if matches!(
+                e.downcast_ref::<CreateStoreBindMountError>(),
+                Some(CreateStoreBindMountError::NixNotEmpty)
+            )
+        ));
+        Ok(())
+    }
+}
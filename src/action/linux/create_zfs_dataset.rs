@@ -0,0 +1,581 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::error::HasExpectedErrors;
+use crate::execute_command;
+use crate::util::host_path;
+use crate::warning::{self, WarningKind};
+
+/// Where the dataset is mounted
+pub(crate) const ZFS_DATASET_MOUNTPOINT: &str = "/nix";
+/// The property snapshot tools like `zfs-auto-snapshot` and `sanoid` read to skip a dataset
+const AUTO_SNAPSHOT_PROPERTY: &str = "com.sun:auto-snapshot";
+
+/**
+Create a ZFS dataset mounted at `/nix`, so the Nix store can be snapshotted and given a quota
+separately from the root dataset
+
+The pool must exist, and neither the dataset nor another dataset mounted at `/nix` may. An empty
+`/nix` directory is mounted over, one with contents is refused. A dataset which already exists
+with `mountpoint=/nix` is used as-is, and kept on revert. Once created, the dataset must be mounted
+at `/nix`.
+
+On revert, the dataset is destroyed only once the earlier steps of the uninstall have emptied it,
+and only if it has no snapshots, which someone other than the installer took.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "create_zfs_dataset")]
+pub struct CreateZfsDataset {
+    pub(crate) dataset: String,
+    disable_auto_snapshot: bool,
+}
+
+impl CreateZfsDataset {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        dataset: impl Into<String>,
+        disable_auto_snapshot: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let dataset = dataset.into();
+        let pool = pool_of(&dataset).map_err(Self::error)?.to_string();
+        if crate::util::which("zfs").is_err() {
+            return Err(Self::error(CreateZfsDatasetError::MissingZfs));
+        }
+
+        let output = execute_command(
+            Command::new("zfs")
+                .process_group(0)
+                .args(["list", "-H", "-o", "name,mountpoint", "-t", "filesystem"])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+        let datasets = parse_zfs_list(&String::from_utf8_lossy(&output.stdout));
+
+        let nix_contents = match host_path(ZFS_DATASET_MOUNTPOINT).read_dir() {
+            Ok(mut entries) => Some(entries.next().is_some()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(Self::error(ActionErrorKind::Read(
+                    ZFS_DATASET_MOUNTPOINT.into(),
+                    e,
+                )))
+            },
+        };
+
+        let action = Self {
+            dataset,
+            disable_auto_snapshot,
+        };
+        match check_conflicts(&action.dataset, &pool, &datasets, nix_contents)
+            .map_err(Self::error)?
+        {
+            true => Ok(StatefulAction::skipped(action)),
+            false => Ok(StatefulAction::uncompleted(action)),
+        }
+    }
+
+    /// Whether the dataset is mounted at `/nix`
+    async fn is_mounted(&self) -> Result<bool, ActionErrorKind> {
+        let output = execute_command(
+            Command::new("zfs")
+                .process_group(0)
+                .args(["get", "-H", "-o", "value", "mounted,mountpoint"])
+                .arg(&self.dataset)
+                .stdin(std::process::Stdio::null()),
+        )
+        .await?;
+        Ok(is_mounted_at_nix(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// The snapshots of the dataset, which the installer never takes
+    async fn snapshots(&self) -> Result<Vec<String>, ActionErrorKind> {
+        let output = execute_command(
+            Command::new("zfs")
+                .process_group(0)
+                .args(["list", "-H", "-o", "name", "-t", "snapshot", "-d", "1"])
+                .arg(&self.dataset)
+                .stdin(std::process::Stdio::null()),
+        )
+        .await?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(ToString::to_string)
+            .collect())
+    }
+
+    /// The arguments to `zfs` creating the dataset
+    fn create_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "create".to_string(),
+            "-o".to_string(),
+            format!("mountpoint={ZFS_DATASET_MOUNTPOINT}"),
+        ];
+        if self.disable_auto_snapshot {
+            args.push("-o".to_string());
+            args.push(format!("{AUTO_SNAPSHOT_PROPERTY}=false"));
+        }
+        args.push(self.dataset.clone());
+        args
+    }
+}
+
+/// The pool of `dataset`, which must be a child of the pool's root dataset
+fn pool_of(dataset: &str) -> Result<&str, CreateZfsDatasetError> {
+    match dataset.split_once('/') {
+        Some((pool, path))
+            if !pool.is_empty()
+                && !path.is_empty()
+                && path.split('/').all(|component| !component.is_empty()) =>
+        {
+            Ok(pool)
+        },
+        _ => Err(CreateZfsDatasetError::InvalidDataset(dataset.to_string())),
+    }
+}
+
+/// Whether the output of `zfs get -H -o value mounted,mountpoint` says the dataset is mounted at
+/// `/nix`
+fn is_mounted_at_nix(output: &str) -> bool {
+    let mut values = output.lines().map(str::trim);
+    values.next() == Some("yes") && values.next() == Some(ZFS_DATASET_MOUNTPOINT)
+}
+
+/// The `(name, mountpoint)` pairs in the output of `zfs list -H -o name,mountpoint`
+fn parse_zfs_list(output: &str) -> Vec<(String, String)> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, mountpoint)| (name.to_string(), mountpoint.trim().to_string()))
+        .collect()
+}
+
+/// Whether `dataset` can be created at `/nix`, given the existing `datasets` and whether `/nix`
+/// exists and has contents
+///
+/// Returns `true` if `dataset` already exists and is mounted at `/nix`, so there is nothing to do.
+fn check_conflicts(
+    dataset: &str,
+    pool: &str,
+    datasets: &[(String, String)],
+    nix_contents: Option<bool>,
+) -> Result<bool, CreateZfsDatasetError> {
+    if !datasets.iter().any(|(name, _)| name == pool) {
+        return Err(CreateZfsDatasetError::PoolNotFound(pool.to_string()));
+    }
+    if let Some((_, mountpoint)) = datasets.iter().find(|(name, _)| name == dataset) {
+        if mountpoint == ZFS_DATASET_MOUNTPOINT {
+            return Ok(true);
+        }
+        return Err(CreateZfsDatasetError::DatasetExists {
+            dataset: dataset.to_string(),
+            mountpoint: mountpoint.clone(),
+        });
+    }
+    if let Some((name, _)) = datasets
+        .iter()
+        .find(|(_, mountpoint)| mountpoint == ZFS_DATASET_MOUNTPOINT)
+    {
+        return Err(CreateZfsDatasetError::NixIsDataset(name.clone()));
+    }
+    if nix_contents == Some(true) {
+        return Err(CreateZfsDatasetError::NixNotEmpty(
+            ZFS_DATASET_MOUNTPOINT.into(),
+        ));
+    }
+    Ok(false)
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "create_zfs_dataset")]
+impl Action for CreateZfsDataset {
+    fn action_tag() -> ActionTag {
+        ActionTag("create_zfs_dataset")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Create the ZFS dataset `{}` mounted at `{ZFS_DATASET_MOUNTPOINT}`",
+            self.dataset
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "create_zfs_dataset",
+            dataset = self.dataset,
+            disable_auto_snapshot = self.disable_auto_snapshot,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!("Run `zfs {}`", self.create_args().join(" "))];
+        if self.disable_auto_snapshot {
+            explanation.push(format!(
+                "`{AUTO_SNAPSHOT_PROPERTY}=false` excludes the Nix store from automatic snapshots"
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![
+            PrivilegedOperation::command("zfs", self.create_args()),
+            PrivilegedOperation::command("zfs", ["mount", self.dataset.as_str()]),
+            PrivilegedOperation::command("zfs", ["destroy", self.dataset.as_str()]),
+        ]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        execute_command(
+            Command::new("zfs")
+                .process_group(0)
+                .args(self.create_args())
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        // `zfs create` leaves a dataset unmounted when `/nix` could not be mounted over, and the
+        // store would be written into the root dataset instead
+        let mut mounted = self.is_mounted().await.map_err(Self::error)?;
+        if !mounted {
+            execute_command(
+                Command::new("zfs")
+                    .process_group(0)
+                    .args(["mount", self.dataset.as_str()])
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+            mounted = self.is_mounted().await.map_err(Self::error)?;
+        }
+        if !mounted {
+            return Err(Self::error(CreateZfsDatasetError::NotMounted(
+                self.dataset.clone(),
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("Destroy the ZFS dataset `{}` if it is empty", self.dataset),
+            vec![
+                "A dataset which still has contents or snapshots is kept, with a warning"
+                    .to_string(),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mountpoint = host_path(ZFS_DATASET_MOUNTPOINT);
+        if has_contents(&mountpoint).map_err(Self::error)? {
+            warning::warn(
+                WarningKind::ZfsDatasetKept,
+                format!(
+                    "Not destroying the ZFS dataset `{}`, `{ZFS_DATASET_MOUNTPOINT}` is not empty",
+                    self.dataset
+                ),
+            );
+            return Ok(());
+        }
+
+        let snapshots = self.snapshots().await.map_err(Self::error)?;
+        if let Some(snapshot) = snapshots.first() {
+            warning::warn(
+                WarningKind::ZfsDatasetKept,
+                format!(
+                    "Not destroying the ZFS dataset `{}`, it has {} snapshot(s) like `{snapshot}`, `zfs destroy -r {}` destroys them with it",
+                    self.dataset,
+                    snapshots.len(),
+                    self.dataset,
+                ),
+            );
+            return Ok(());
+        }
+
+        execute_command(
+            Command::new("zfs")
+                .process_group(0)
+                .args(["destroy", self.dataset.as_str()])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        // The mountpoint may be left behind once the dataset is unmounted
+        match tokio::fs::remove_dir(&mountpoint).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Self::error(ActionErrorKind::Remove(
+                ZFS_DATASET_MOUNTPOINT.into(),
+                e,
+            ))),
+        }
+    }
+}
+
+fn has_contents(path: &Path) -> Result<bool, ActionErrorKind> {
+    match path.read_dir() {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(ActionErrorKind::Read(path.to_path_buf(), e)),
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateZfsDatasetError {
+    #[error("`zfs` was not found, install the ZFS userspace tools to use `--zfs-dataset`")]
+    MissingZfs,
+    #[error("`{0}` is not a ZFS dataset name like `rpool/nix`, it must be inside a pool")]
+    InvalidDataset(String),
+    #[error("The ZFS pool `{0}` does not exist, see `zpool list`")]
+    PoolNotFound(String),
+    #[error("The ZFS dataset `{dataset}` already exists with `mountpoint={mountpoint}`, set `mountpoint=/nix` to use it or choose another dataset")]
+    DatasetExists { dataset: String, mountpoint: String },
+    #[error("`/nix` is already the ZFS dataset `{0}`, pass `--zfs-dataset {0}` to install into it, or install without `--zfs-dataset`")]
+    NixIsDataset(String),
+    #[error("`{0}` has contents which the ZFS dataset would be mounted over, remove them or install without `--zfs-dataset`")]
+    NixNotEmpty(PathBuf),
+    #[error("The ZFS dataset `{0}` was created but is not mounted at `/nix`, check `zfs get mounted,mountpoint,canmount {0}`")]
+    NotMounted(String),
+}
+
+impl HasExpectedErrors for CreateZfsDatasetError {
+    fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
+        match self {
+            CreateZfsDatasetError::MissingZfs
+            | CreateZfsDatasetError::InvalidDataset(_)
+            | CreateZfsDatasetError::PoolNotFound(_)
+            | CreateZfsDatasetError::DatasetExists { .. }
+            | CreateZfsDatasetError::NixIsDataset(_)
+            | CreateZfsDatasetError::NixNotEmpty(_) => Some(Box::new(self)),
+            CreateZfsDatasetError::NotMounted(_) => None,
+        }
+    }
+}
+
+impl From<CreateZfsDatasetError> for ActionErrorKind {
+    fn from(val: CreateZfsDatasetError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_conflicts, is_mounted_at_nix, pool_of, CreateZfsDataset, CreateZfsDatasetError,
+    };
+    use crate::action::{ActionErrorKind, ActionState};
+    use crate::error::HasExpectedErrors;
+    use crate::test_harness::{FakeCommand, Invocation, SandboxContext};
+    use crate::warning::{self, WarningKind};
+
+    const ZFS_LIST: &str =
+        "rpool\tnone\nrpool/ROOT\tnone\nrpool/ROOT/debian\t/\nrpool/home\t/home\n";
+
+    fn datasets(list: &str) -> Vec<(String, String)> {
+        super::parse_zfs_list(list)
+    }
+
+    #[test]
+    fn create_args() {
+        let action = |disable_auto_snapshot| CreateZfsDataset {
+            dataset: "rpool/nix".into(),
+            disable_auto_snapshot,
+        };
+        assert_eq!(
+            action(false).create_args(),
+            ["create", "-o", "mountpoint=/nix", "rpool/nix"]
+        );
+        assert_eq!(
+            action(true).create_args(),
+            [
+                "create",
+                "-o",
+                "mountpoint=/nix",
+                "-o",
+                "com.sun:auto-snapshot=false",
+                "rpool/nix"
+            ]
+        );
+    }
+
+    #[test]
+    fn dataset_must_be_inside_a_pool() {
+        assert_eq!(pool_of("rpool/nix").unwrap(), "rpool");
+        assert_eq!(pool_of("tank/local/nix").unwrap(), "tank");
+        for invalid in ["rpool", "rpool/", "/nix", "rpool//nix", ""] {
+            assert!(
+                matches!(
+                    pool_of(invalid),
+                    Err(CreateZfsDatasetError::InvalidDataset(_))
+                ),
+                "`{invalid}` was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        let list = datasets(ZFS_LIST);
+        // Missing `/nix`, or an empty one, is mounted over
+        assert!(!check_conflicts("rpool/nix", "rpool", &list, None).unwrap());
+        assert!(!check_conflicts("rpool/nix", "rpool", &list, Some(false)).unwrap());
+        // Contents would be hidden by the mount
+        assert!(matches!(
+            check_conflicts("rpool/nix", "rpool", &list, Some(true)),
+            Err(CreateZfsDatasetError::NixNotEmpty(_))
+        ));
+        assert!(matches!(
+            check_conflicts("tank/nix", "tank", &list, None),
+            Err(CreateZfsDatasetError::PoolNotFound(pool)) if pool == "tank"
+        ));
+        assert!(matches!(
+            check_conflicts("rpool/home", "rpool", &list, None),
+            Err(CreateZfsDatasetError::DatasetExists { mountpoint, .. }) if mountpoint == "/home"
+        ));
+
+        let list = datasets(&format!("{ZFS_LIST}rpool/nix\t/nix\n"));
+        // The same dataset, already at `/nix` (and so already holding its contents)
+        assert!(check_conflicts("rpool/nix", "rpool", &list, Some(true)).unwrap());
+        assert!(matches!(
+            check_conflicts("rpool/store", "rpool", &list, None),
+            Err(CreateZfsDatasetError::NixIsDataset(name)) if name == "rpool/nix"
+        ));
+    }
+
+    #[tokio::test]
+    async fn creates_and_destroys_only_an_emptied_dataset() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake("zfs", FakeCommand::success().stdout(ZFS_LIST));
+
+        let mut action = sandbox
+            .scope(CreateZfsDataset::plan("rpool/nix", true))
+            .await?
+            .boxed();
+        assert_eq!(action.state, ActionState::Uncompleted);
+        // Created, then mounted; the dataset has no snapshots
+        sandbox.fake("zfs", FakeCommand::success());
+        sandbox.fake_once("zfs", FakeCommand::success());
+        sandbox.fake_once("zfs", FakeCommand::success().stdout("yes\n/nix\n"));
+        sandbox.execute(std::slice::from_mut(&mut action)).await?;
+        let invocations = sandbox.invocations_of("zfs");
+        assert_eq!(
+            invocations.get(invocations.len() - 2),
+            Some(&Invocation::new(
+                "zfs",
+                [
+                    "create",
+                    "-o",
+                    "mountpoint=/nix",
+                    "-o",
+                    "com.sun:auto-snapshot=false",
+                    "rpool/nix"
+                ]
+            ))
+        );
+
+        // The uninstall left something behind
+        let nix = sandbox.path("/nix");
+        std::fs::create_dir_all(nix.join("store"))?;
+        let mut kept = action.clone();
+        sandbox.revert(std::slice::from_mut(&mut kept)).await?;
+        assert!(!sandbox
+            .invocations_of("zfs")
+            .iter()
+            .any(|invocation| invocation.args.first().map(String::as_str) == Some("destroy")));
+
+        std::fs::remove_dir(nix.join("store"))?;
+        sandbox.revert(std::slice::from_mut(&mut action)).await?;
+        assert_eq!(
+            sandbox.invocations_of("zfs").last(),
+            Some(&Invocation::new("zfs", ["destroy", "rpool/nix"]))
+        );
+        assert!(!nix.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unmounted_or_snapshotted_datasets() -> eyre::Result<()> {
+        assert!(is_mounted_at_nix("yes\n/nix\n"));
+        assert!(!is_mounted_at_nix("no\n/nix\n"));
+        assert!(!is_mounted_at_nix("yes\n/mnt/nix\n"));
+
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake("zfs", FakeCommand::success().stdout(ZFS_LIST));
+        let mut action = sandbox
+            .scope(CreateZfsDataset::plan("rpool/nix", false))
+            .await?
+            .boxed();
+
+        // Still unmounted after `zfs mount`, the store must not land on the root dataset
+        sandbox.fake("zfs", FakeCommand::success().stdout("no\n/nix\n"));
+        let err = sandbox
+            .execute(std::slice::from_mut(&mut action))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ActionErrorKind::Custom(e)
+                if matches!(e.downcast_ref(), Some(CreateZfsDatasetError::NotMounted(_)))
+        ));
+        assert!(sandbox
+            .invocations_of("zfs")
+            .contains(&Invocation::new("zfs", ["mount", "rpool/nix"])));
+
+        // Someone took a snapshot since, destroying the dataset would destroy it too
+        sandbox.fake(
+            "zfs",
+            FakeCommand::success().stdout("rpool/nix@before-upgrade\n"),
+        );
+        action.state = ActionState::Completed;
+        let (reverted, warnings) =
+            warning::collect(sandbox.revert(std::slice::from_mut(&mut action))).await;
+        reverted?;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::ZfsDatasetKept);
+        assert!(!sandbox
+            .invocations_of("zfs")
+            .iter()
+            .any(|invocation| invocation.args.first().map(String::as_str) == Some("destroy")));
+        Ok(())
+    }
+
+    #[test]
+    fn plan_checks_are_expected_errors() {
+        let missing_pool: ActionErrorKind =
+            CreateZfsDatasetError::PoolNotFound("tank".into()).into();
+        assert!(missing_pool.expected().is_some());
+        let unmounted: ActionErrorKind =
+            CreateZfsDatasetError::NotMounted("tank/nix".into()).into();
+        assert!(unmounted.expected().is_none());
+    }
+
+    #[tokio::test]
+    async fn existing_dataset_at_nix_is_kept() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake(
+            "zfs",
+            FakeCommand::success().stdout(format!("{ZFS_LIST}rpool/nix\t/nix\n")),
+        );
+        let action = sandbox
+            .scope(CreateZfsDataset::plan("rpool/nix", false))
+            .await?;
+        assert_eq!(action.state, ActionState::Skipped);
+        Ok(())
+    }
+}
@@ -7,7 +7,7 @@ use tracing::{span, Span};
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription, StatefulAction};
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
 
 /**
 Ensure SeamOS's `/nix` folder exists.
@@ -46,7 +46,11 @@ impl Action for EnsureSteamosNixDirectory {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "ensure_steamos_nix_directory",)
+        span!(
+            tracing::Level::DEBUG,
+            "ensure_steamos_nix_directory",
+            path = "/nix"
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
@@ -60,6 +64,14 @@ impl Action for EnsureSteamosNixDirectory {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![
+            PrivilegedOperation::command("steamos-readonly", ["disable"]),
+            PrivilegedOperation::write("/nix", None),
+            PrivilegedOperation::command("steamos-readonly", ["enable"]),
+        ]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         execute_command(
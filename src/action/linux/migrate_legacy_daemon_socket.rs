@@ -0,0 +1,287 @@
+use std::path::PathBuf;
+
+use tracing::{span, Span};
+
+use crate::action::{ActionError, ActionErrorKind, ActionTag};
+use crate::backup::{Backup, BackupStore};
+use crate::daemon_socket::{scan_legacy_sockets, LegacyUnit, UnitMigration};
+use crate::util::{host_path, OnMissing};
+
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
+
+/**
+Move the Nix daemon's socket unit off `/var/run`, removing sockets left behind there
+
+Units which are rewritten are backed up first, and restored from their backups on revert.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "migrate_legacy_daemon_socket")]
+pub struct MigrateLegacyDaemonSocket {
+    units: Vec<LegacyUnit>,
+    stale_sockets: Vec<PathBuf>,
+    /// Where rewritten units are backed up, receipts from before backups restore them from `units`
+    #[serde(default)]
+    backups: Option<BackupStore>,
+    #[serde(default)]
+    backed_up: Vec<Backup>,
+}
+
+impl MigrateLegacyDaemonSocket {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(backups: BackupStore) -> Result<StatefulAction<Self>, ActionError> {
+        let scan = scan_legacy_sockets().await.map_err(Self::error)?;
+        let this = Self {
+            units: scan.units,
+            stale_sockets: scan.stale_sockets,
+            backups: Some(backups),
+            backed_up: vec![],
+        };
+
+        if this.units.is_empty() && this.stale_sockets.is_empty() {
+            tracing::debug!("No daemon socket units or sockets under `/var/run`");
+            return Ok(StatefulAction::skipped(this));
+        }
+
+        Ok(StatefulAction::uncompleted(this))
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "migrate_legacy_daemon_socket")]
+impl Action for MigrateLegacyDaemonSocket {
+    fn action_tag() -> ActionTag {
+        ActionTag("migrate_legacy_daemon_socket")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Migrate the Nix daemon socket from `/var/run` to `/run`".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "migrate_legacy_daemon_socket",
+            units = self.units.len(),
+            stale_sockets = self.stale_sockets.len(),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![];
+        for LegacyUnit { path, migration } in &self.units {
+            explanation.push(match migration {
+                UnitMigration::Abandon { target } => format!(
+                    "Remove `{}`, which links to a unit listening under `/var/run` (`{}`)",
+                    path.display(),
+                    target.display()
+                ),
+                UnitMigration::Rewrite { .. } => {
+                    format!("Rewrite `{}` to listen under `/run`", path.display())
+                },
+            });
+        }
+        for socket in &self.stale_sockets {
+            explanation.push(format!("Remove the stale socket `{}`", socket.display()));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self
+            .units
+            .iter()
+            .map(|LegacyUnit { path, migration }| match migration {
+                UnitMigration::Abandon { .. } => PrivilegedOperation::remove(path),
+                UnitMigration::Rewrite { .. } => PrivilegedOperation::write(path, None),
+            })
+            .collect::<Vec<_>>();
+        operations.extend(self.stale_sockets.iter().map(PrivilegedOperation::remove));
+        if let Some(backups) = &self.backups {
+            if self
+                .units
+                .iter()
+                .any(|unit| matches!(unit.migration, UnitMigration::Rewrite { .. }))
+            {
+                operations.push(PrivilegedOperation::write(backups.dir(), None));
+            }
+        }
+        operations
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.backed_up.clear();
+        for LegacyUnit { path, migration } in &self.units {
+            match migration {
+                UnitMigration::Abandon { .. } => {
+                    crate::util::remove_file(&host_path(path), OnMissing::Ignore)
+                        .await
+                        .map_err(|e| ActionErrorKind::Remove(path.clone(), e))
+                        .map_err(Self::error)?;
+                },
+                UnitMigration::Rewrite { migrated, .. } => {
+                    if let Some(backups) = &self.backups {
+                        if let Some(backup) = backups.back_up(path).await.map_err(Self::error)? {
+                            self.backed_up.push(backup);
+                        }
+                    }
+                    tokio::fs::write(host_path(path), migrated)
+                        .await
+                        .map_err(|e| ActionErrorKind::Write(path.clone(), e))
+                        .map_err(Self::error)?;
+                },
+            }
+        }
+
+        for socket in &self.stale_sockets {
+            crate::util::remove_file(&host_path(socket), OnMissing::Ignore)
+                .await
+                .map_err(|e| ActionErrorKind::Remove(socket.clone(), e))
+                .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        if self.units.is_empty() {
+            return vec![];
+        }
+        vec![ActionDescription::new(
+            "Restore the Nix daemon socket units which listened under `/var/run`".to_string(),
+            self.units
+                .iter()
+                .map(|LegacyUnit { path, .. }| format!("Restore `{}`", path.display()))
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        // Stale sockets had no listener, there is nothing to bring back
+        for LegacyUnit { path, migration } in &self.units {
+            let restored = match migration {
+                UnitMigration::Abandon { target } => {
+                    if let Err(e) =
+                        crate::util::remove_file(&host_path(path), OnMissing::Ignore).await
+                    {
+                        errors.push(Self::error(ActionErrorKind::Remove(path.clone(), e)));
+                        continue;
+                    }
+                    tokio::fs::symlink(target, host_path(path))
+                        .await
+                        .map_err(|e| ActionErrorKind::Symlink(target.clone(), path.clone(), e))
+                },
+                UnitMigration::Rewrite { original, .. } => {
+                    let backup = self.backed_up.iter().find(|backup| backup.path == *path);
+                    match (&self.backups, backup) {
+                        (Some(backups), Some(backup)) => {
+                            backups.restore(backup).await.map_err(ActionErrorKind::from)
+                        },
+                        _ => tokio::fs::write(host_path(path), original)
+                            .await
+                            .map_err(|e| ActionErrorKind::Write(path.clone(), e)),
+                    }
+                },
+            };
+            if let Err(e) = restored {
+                errors.push(Self::error(e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixListener;
+
+    use crate::action::ActionState;
+    use crate::backup::BackupStore;
+    use crate::test_harness::SandboxContext;
+
+    use super::MigrateLegacyDaemonSocket;
+
+    const LEGACY_UNIT: &str = "[Socket]\nListenStream=/var/run/nix-daemon.socket\n";
+    const STATE_DIR: &str = "/nix/var/nix-installer";
+
+    #[tokio::test]
+    async fn migrates_and_restores_units() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let profile_unit = sandbox.path("/nix/store/aaaa-nix/lib/systemd/system/nix-daemon.socket");
+        std::fs::create_dir_all(profile_unit.parent().unwrap())?;
+        std::fs::write(&profile_unit, LEGACY_UNIT)?;
+        std::fs::create_dir_all(sandbox.path("/etc/systemd/system/sockets.target.wants"))?;
+        sandbox.symlink(
+            "/nix/store/aaaa-nix/lib/systemd/system/nix-daemon.socket",
+            "/etc/systemd/system/nix-daemon.socket",
+        )?;
+        std::fs::write(
+            sandbox.path("/etc/systemd/system/sockets.target.wants/nix-daemon.socket"),
+            LEGACY_UNIT,
+        )?;
+        std::fs::create_dir_all(sandbox.path("/var/run"))?;
+        let _listener = UnixListener::bind(sandbox.path("/var/run/nix-daemon.socket"))?;
+
+        let mut action = sandbox
+            .scope(MigrateLegacyDaemonSocket::plan(BackupStore::new(STATE_DIR)))
+            .await?;
+        assert_eq!(action.state, ActionState::Uncompleted);
+        sandbox.scope(action.try_execute()).await?;
+
+        assert!(!sandbox
+            .path("/etc/systemd/system/nix-daemon.socket")
+            .is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(
+                sandbox.path("/etc/systemd/system/sockets.target.wants/nix-daemon.socket")
+            )?,
+            "[Socket]\nListenStream=/run/nix-daemon.socket\n"
+        );
+        assert!(!sandbox.path("/var/run/nix-daemon.socket").exists());
+        // Only the rewritten unit was backed up, the link is restored from its target
+        let backed_up = &action.action.backed_up;
+        assert_eq!(backed_up.len(), 1);
+        assert_eq!(
+            backed_up[0].path,
+            std::path::Path::new("/etc/systemd/system/sockets.target.wants/nix-daemon.socket")
+        );
+        assert_eq!(
+            std::fs::read_to_string(
+                sandbox.path(BackupStore::new(STATE_DIR).dir().join(&backed_up[0].hash))
+            )?,
+            LEGACY_UNIT
+        );
+        // Nothing left to migrate
+        assert_eq!(
+            sandbox
+                .scope(MigrateLegacyDaemonSocket::plan(BackupStore::new(STATE_DIR)))
+                .await?
+                .state,
+            ActionState::Skipped
+        );
+
+        sandbox.scope(action.try_revert()).await?;
+        assert_eq!(
+            std::fs::read_link(sandbox.path("/etc/systemd/system/nix-daemon.socket"))?,
+            std::path::PathBuf::from("/nix/store/aaaa-nix/lib/systemd/system/nix-daemon.socket")
+        );
+        assert_eq!(
+            std::fs::read_to_string(
+                sandbox.path("/etc/systemd/system/sockets.target.wants/nix-daemon.socket")
+            )?,
+            LEGACY_UNIT
+        );
+        Ok(())
+    }
+}
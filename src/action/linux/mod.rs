@@ -1,11 +1,25 @@
+pub(crate) mod configure_wsl_daemon_launcher;
+pub(crate) mod create_store_bind_mount;
+pub(crate) mod create_zfs_dataset;
 pub(crate) mod ensure_steamos_nix_directory;
+pub(crate) mod migrate_legacy_daemon_socket;
+pub(crate) mod persist_steamos_units;
 pub(crate) mod provision_selinux;
+pub(crate) mod provision_selinux_file_contexts;
+pub(crate) mod relink_steamos_units;
 pub(crate) mod revert_clean_steamos_nix_offload;
 pub(crate) mod start_systemd_unit;
 pub(crate) mod systemctl_daemon_reload;
 
+pub use configure_wsl_daemon_launcher::ConfigureWslDaemonLauncher;
+pub use create_store_bind_mount::{CreateStoreBindMount, CreateStoreBindMountError};
+pub use create_zfs_dataset::{CreateZfsDataset, CreateZfsDatasetError};
 pub use ensure_steamos_nix_directory::EnsureSteamosNixDirectory;
+pub use migrate_legacy_daemon_socket::MigrateLegacyDaemonSocket;
+pub use persist_steamos_units::PersistSteamosUnits;
 pub use provision_selinux::ProvisionSelinux;
+pub use provision_selinux_file_contexts::{FileContext, ProvisionSelinuxFileContexts};
+pub use relink_steamos_units::{RelinkSteamosUnits, SteamosUnit, SteamosUnitSource};
 pub use revert_clean_steamos_nix_offload::RevertCleanSteamosNixOffload;
 pub use start_systemd_unit::{StartSystemdUnit, StartSystemdUnitError};
 pub use systemctl_daemon_reload::SystemctlDaemonReload;
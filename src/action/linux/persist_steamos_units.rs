@@ -0,0 +1,490 @@
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use super::relink_steamos_units::{SteamosUnit, SYSTEMD_SYSTEM_DIR};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::execute_command;
+use crate::util::{host_path, OnMissing};
+
+/// Where the Nix units are kept, on the `/home` partition SteamOS updates leave alone
+pub(crate) const STEAMOS_UNITS_PERSISTENCE: &str = "/home/.steamos/offload/nix-installer";
+/// The unit restoring the Nix units at boot, when an update removed them
+pub(crate) const RELINK_UNITS_SERVICE: &str = "nix-relink-units.service";
+
+/// The units the links among `paths` in `.wants` and `.requires` directories enable
+pub(crate) fn enabled_units(paths: &[PathBuf]) -> Vec<String> {
+    let mut units: Vec<String> = vec![];
+    for path in paths {
+        let in_dependency_dir = path
+            .parent()
+            .and_then(|parent| parent.extension())
+            .is_some_and(|extension| extension == "wants" || extension == "requires");
+        let Some(unit) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if in_dependency_dir && !units.iter().any(|known| known == unit) {
+            units.push(unit.to_string());
+        }
+    }
+    units
+}
+
+/**
+Keep a copy of the Nix units in `/etc/systemd/system` under `/home/.steamos/offload`, and a unit
+restoring them at boot
+
+A SteamOS update replaces `/etc` with the one in the new image, keeping only what
+`/etc/atomic-update.conf.d` lists. The unit restoring the others is listed there, and runs when any
+of them is missing.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "persist_steamos_units")]
+pub struct PersistSteamosUnits {
+    directory: PathBuf,
+    /// The units to keep (such as `nix-daemon.socket`), those not installed are skipped
+    units: Vec<String>,
+    /// The units and the links enabling them which were kept, relative to `/etc/systemd/system`,
+    /// once executed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    persisted: Vec<PathBuf>,
+}
+
+impl PersistSteamosUnits {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(units: Vec<String>) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(Self {
+            directory: STEAMOS_UNITS_PERSISTENCE.into(),
+            units,
+            persisted: vec![],
+        }
+        .into())
+    }
+
+    fn units_dir(&self) -> PathBuf {
+        self.directory.join("units")
+    }
+
+    fn script_path(&self) -> PathBuf {
+        self.directory.join("relink-units")
+    }
+
+    /// The paths of the installed units, and the links enabling them, relative to
+    /// `/etc/systemd/system`
+    async fn installed_paths(&self) -> Result<Vec<PathBuf>, ActionErrorKind> {
+        let dir = host_path(SYSTEMD_SYSTEM_DIR);
+        let exists = |path: &Path| std::fs::symlink_metadata(dir.join(path)).is_ok();
+
+        let mut paths = self
+            .units
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| exists(path))
+            .collect::<Vec<_>>();
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .map_err(|e| ActionErrorKind::ReadDir(SYSTEMD_SYSTEM_DIR.into(), e))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| ActionErrorKind::ReadDir(SYSTEMD_SYSTEM_DIR.into(), e))?
+        {
+            let dependency_dir = PathBuf::from(entry.file_name());
+            if !matches!(
+                dependency_dir.extension().and_then(|e| e.to_str()),
+                Some("wants" | "requires")
+            ) {
+                continue;
+            }
+            paths.extend(
+                self.units
+                    .iter()
+                    .map(|unit| dependency_dir.join(unit))
+                    .filter(|path| exists(path)),
+            );
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// A script copying each missing unit back, then starting the enabled ones
+    fn relink_script(&self) -> String {
+        let units = self
+            .persisted
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            r#"#!/bin/sh
+# Restore the Nix units a SteamOS update removed, written by `nix-installer`
+set -eu
+system={SYSTEMD_SYSTEM_DIR}
+kept={units_dir}
+units='{units}'
+enabled='{enabled}'
+restored=0
+for unit in $units; do
+    if [ ! -e "$system/$unit" ] && [ ! -L "$system/$unit" ]; then
+        mkdir -p "$(dirname "$system/$unit")"
+        cp -P "$kept/$unit" "$system/$unit"
+        restored=1
+    fi
+done
+if [ "$restored" = 1 ]; then
+    systemctl daemon-reload
+    [ -z "$enabled" ] || systemctl start --no-block $enabled
+fi
+"#,
+            units_dir = self.units_dir().display(),
+            enabled = enabled_units(&self.persisted).join(" "),
+        )
+    }
+
+    /// The unit running the script at boot, when any of the kept units is missing
+    fn relink_service(&self) -> String {
+        let conditions = self
+            .persisted
+            .iter()
+            .map(|path| {
+                format!(
+                    "ConditionPathExists=|!{SYSTEMD_SYSTEM_DIR}/{}\n",
+                    path.display()
+                )
+            })
+            .collect::<String>();
+        format!(
+            "\
+            [Unit]\n\
+            Description=Restore the Nix units a SteamOS update removed\n\
+            DefaultDependencies=no\n\
+            RequiresMountsFor={directory}\n\
+            {conditions}\
+            \n\
+            [Service]\n\
+            Type=oneshot\n\
+            ExecStart=/bin/sh {script}\n\
+            \n\
+            [Install]\n\
+            WantedBy=sysinit.target\n\
+            ",
+            directory = self.directory.display(),
+            script = self.script_path().display(),
+        )
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "persist_steamos_units")]
+impl Action for PersistSteamosUnits {
+    fn action_tag() -> ActionTag {
+        ActionTag("persist_steamos_units")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Keep a copy of the Nix units in `{}` to restore after SteamOS updates",
+            self.directory.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "persist_steamos_units",
+            directory = tracing::field::display(self.directory.display()),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                format!(
+                    "Copy {} and the links enabling them to `{}`",
+                    self.units
+                        .iter()
+                        .map(|unit| format!("`{unit}`"))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    self.units_dir().display()
+                ),
+                format!(
+                    "Create `{}`, which copies back any missing after an update",
+                    self.script_path().display()
+                ),
+                format!("Create `{SYSTEMD_SYSTEM_DIR}/{RELINK_UNITS_SERVICE}` to run it at boot"),
+                format!("Run `systemctl enable {RELINK_UNITS_SERVICE}`"),
+            ],
+        )]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![
+            PrivilegedOperation::write(&self.directory, None),
+            PrivilegedOperation::write(
+                Path::new(SYSTEMD_SYSTEM_DIR).join(RELINK_UNITS_SERVICE),
+                None,
+            ),
+            PrivilegedOperation::command("systemctl", ["enable", RELINK_UNITS_SERVICE]),
+        ]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let units_dir = self.units_dir();
+        tokio::fs::create_dir_all(host_path(&self.directory))
+            .await
+            .map_err(|e| {
+                Self::error(ActionErrorKind::CreateDirectory(self.directory.clone(), e))
+            })?;
+        crate::util::remove_dir_all(&host_path(&units_dir), OnMissing::Ignore)
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Remove(units_dir.clone(), e)))?;
+
+        let paths = self.installed_paths().await.map_err(Self::error)?;
+        for path in &paths {
+            SteamosUnit::read(Path::new(SYSTEMD_SYSTEM_DIR), path)
+                .await
+                .map_err(Self::error)?
+                .write(&units_dir)
+                .await
+                .map_err(Self::error)?;
+        }
+        self.persisted = paths;
+
+        let script = self.script_path();
+        tokio::fs::write(host_path(&script), self.relink_script())
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Write(script.clone(), e)))?;
+        tokio::fs::set_permissions(host_path(&script), std::fs::Permissions::from_mode(0o755))
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::SetPermissions(0o755, script.clone(), e)))?;
+
+        let service = Path::new(SYSTEMD_SYSTEM_DIR).join(RELINK_UNITS_SERVICE);
+        tokio::fs::write(host_path(&service), self.relink_service())
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Write(service.clone(), e)))?;
+
+        // Not started, nothing is missing yet
+        execute_command(
+            Command::new("systemctl")
+                .process_group(0)
+                .args(["enable", RELINK_UNITS_SERVICE])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!(
+                "Remove the copy of the Nix units in `{}`",
+                self.directory.display()
+            ),
+            vec![
+                format!("Run `systemctl disable {RELINK_UNITS_SERVICE}`"),
+                format!("Remove `{SYSTEMD_SYSTEM_DIR}/{RELINK_UNITS_SERVICE}`"),
+                format!("Remove `{}`", self.directory.display()),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        if let Err(e) = execute_command(
+            Command::new("systemctl")
+                .process_group(0)
+                .args(["disable", RELINK_UNITS_SERVICE])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        {
+            errors.push(Self::error(e));
+        }
+
+        let service = Path::new(SYSTEMD_SYSTEM_DIR).join(RELINK_UNITS_SERVICE);
+        if let Err(e) = crate::util::remove_file(&host_path(&service), OnMissing::Ignore).await {
+            errors.push(Self::error(ActionErrorKind::Remove(service, e)));
+        }
+        if let Err(e) =
+            crate::util::remove_dir_all(&host_path(&self.directory), OnMissing::Ignore).await
+        {
+            errors.push(Self::error(ActionErrorKind::Remove(
+                self.directory.clone(),
+                e,
+            )));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    use super::{PersistSteamosUnits, RELINK_UNITS_SERVICE, STEAMOS_UNITS_PERSISTENCE};
+    use crate::action::linux::relink_steamos_units::{recorded_steamos_units, RelinkSteamosUnits};
+    use crate::action::ActionState;
+    use crate::test_harness::{Invocation, SandboxContext};
+
+    const PROFILE_UNITS: &str = "/nix/var/nix/profiles/default/lib/systemd/system";
+
+    #[tokio::test]
+    async fn units_removed_by_an_update_are_restored() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let system = sandbox.path("/etc/systemd/system");
+        symlink(
+            format!("{PROFILE_UNITS}/nix-daemon.service"),
+            system.join("nix-daemon.service"),
+        )?;
+        symlink(
+            format!("{PROFILE_UNITS}/nix-daemon.socket"),
+            system.join("nix-daemon.socket"),
+        )?;
+        std::fs::write(
+            system.join("ensure-symlinked-units-resolve.service"),
+            "[Unit]\n",
+        )?;
+        for (dir, unit) in [
+            ("sockets.target.wants", "nix-daemon.socket"),
+            (
+                "sysinit.target.wants",
+                "ensure-symlinked-units-resolve.service",
+            ),
+        ] {
+            std::fs::create_dir_all(system.join(dir))?;
+            symlink(
+                format!("/etc/systemd/system/{unit}"),
+                system.join(dir).join(unit),
+            )?;
+        }
+        // Units of the system itself are left alone
+        symlink(
+            "/usr/lib/systemd/system/sshd.service",
+            system.join("sysinit.target.wants/sshd.service"),
+        )?;
+
+        let units = [
+            "nix.mount",
+            "nix-daemon.service",
+            "nix-daemon.socket",
+            "ensure-symlinked-units-resolve.service",
+        ];
+        let action = sandbox
+            .scope(PersistSteamosUnits::plan(
+                units.iter().map(ToString::to_string).collect(),
+            ))
+            .await?;
+        let mut actions = [action.boxed()];
+        sandbox.execute(&mut actions).await?;
+
+        let kept = sandbox.path(STEAMOS_UNITS_PERSISTENCE).join("units");
+        assert_eq!(
+            std::fs::read_link(kept.join("sockets.target.wants/nix-daemon.socket"))?,
+            PathBuf::from("/etc/systemd/system/nix-daemon.socket")
+        );
+        assert_eq!(
+            std::fs::read_to_string(kept.join("ensure-symlinked-units-resolve.service"))?,
+            "[Unit]\n"
+        );
+        assert!(!kept.join("sysinit.target.wants/sshd.service").exists());
+        let service = std::fs::read_to_string(system.join(RELINK_UNITS_SERVICE))?;
+        assert!(
+            service.contains(
+                "ConditionPathExists=|!/etc/systemd/system/sockets.target.wants/nix-daemon.socket\n"
+            ),
+            "{service}"
+        );
+        let script =
+            std::fs::read_to_string(sandbox.path(STEAMOS_UNITS_PERSISTENCE).join("relink-units"))?;
+        assert!(
+            script.contains("enabled='nix-daemon.socket ensure-symlinked-units-resolve.service'\n"),
+            "{script}"
+        );
+        assert_eq!(
+            sandbox.invocations_of("systemctl"),
+            [Invocation::new(
+                "systemctl",
+                ["enable", RELINK_UNITS_SERVICE]
+            )]
+        );
+
+        // An update replaces `/etc`, the copies are what the receipt points to
+        let receipt = serde_json::json!({ "actions": [serde_json::to_value(&actions[0])?] });
+        std::fs::remove_dir_all(&system)?;
+        std::fs::create_dir_all(&system)?;
+        let recorded = sandbox.scope(recorded_steamos_units(&receipt)).await?;
+        assert!(recorded.persisted);
+        assert_eq!(recorded.units.len(), 5);
+
+        let relink = sandbox
+            .scope(RelinkSteamosUnits::plan(
+                recorded.units.clone(),
+                recorded.enable.clone(),
+            ))
+            .await?;
+        assert_eq!(relink.state, ActionState::Uncompleted);
+        let mut relink = [relink.boxed()];
+        sandbox.execute(&mut relink).await?;
+        assert_eq!(
+            std::fs::read_link(system.join("nix-daemon.socket"))?,
+            PathBuf::from(format!("{PROFILE_UNITS}/nix-daemon.socket"))
+        );
+        assert_eq!(
+            std::fs::read_link(
+                system.join("sysinit.target.wants/ensure-symlinked-units-resolve.service")
+            )?,
+            PathBuf::from("/etc/systemd/system/ensure-symlinked-units-resolve.service")
+        );
+        assert_eq!(
+            sandbox.invocations_of("systemctl").last(),
+            Some(&Invocation::new(
+                "systemctl",
+                [
+                    "enable",
+                    "--now",
+                    "--no-block",
+                    "nix-daemon.socket",
+                    "ensure-symlinked-units-resolve.service"
+                ]
+            ))
+        );
+        let relink = sandbox
+            .scope(RelinkSteamosUnits::plan(recorded.units, recorded.enable))
+            .await?;
+        assert_eq!(relink.state, ActionState::Completed);
+
+        sandbox.revert(&mut actions).await?;
+        assert!(!sandbox.path(STEAMOS_UNITS_PERSISTENCE).exists());
+        assert!(!system.join(RELINK_UNITS_SERVICE).exists());
+        assert_eq!(
+            sandbox.invocations_of("systemctl").last(),
+            Some(&Invocation::new(
+                "systemctl",
+                ["disable", RELINK_UNITS_SERVICE]
+            ))
+        );
+        Ok(())
+    }
+}
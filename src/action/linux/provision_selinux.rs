@@ -6,8 +6,14 @@ use tracing::{span, Span};
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription, StatefulAction};
-use crate::util::OnMissing;
+use crate::action::base::CreateDirectory;
+use crate::action::common::{
+    ConfigureDaemonUser, ConfigureDeterminateNixdInitService, ConfigureUpstreamInitService,
+    ProvisionDeterminateNixd, ProvisionNix,
+};
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
+use crate::util::{host_path, OnMissing};
+use crate::warning::{self, WarningKind};
 
 pub const SELINUX_POLICY_PP_CONTENT: &[u8] = include_bytes!("selinux/nix.pp");
 pub const DETERMINATE_SELINUX_POLICY_PP_CONTENT: &[u8] =
@@ -67,6 +73,20 @@ impl Action for ProvisionSelinux {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![
+            // A policy left by an earlier install is removed first
+            PrivilegedOperation::command("semodule", ["--remove", "nix"]),
+            PrivilegedOperation::remove(&self.policy_path),
+            PrivilegedOperation::write(&self.policy_path, None),
+            PrivilegedOperation::command(
+                "semodule",
+                ["--install", &self.policy_path.display().to_string()],
+            ),
+            PrivilegedOperation::command("restorecon", ["-FR", "/nix"]),
+        ]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         if self.policy_path.exists() {
@@ -106,20 +126,137 @@ impl Action for ProvisionSelinux {
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             "Remove the SELinux policy for Nix".into(),
-            vec![],
+            vec![
+                "This is done once the Nix store and daemon are removed, as what remains of them is denied without it".into(),
+            ],
         )]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        if self.policy_path.exists() {
-            remove_existing_policy(&self.policy_path)
+        if !self.policy_path.exists() {
+            return Ok(());
+        }
+
+        // Whatever is left of `/nix` must not keep a type only the policy defines
+        if host_path("/nix").exists() {
+            if let Err(e) =
+                execute_command(Command::new("chcon").args(["-R", "-h", "-t", "default_t", "/nix"]))
+                    .await
+            {
+                tracing::warn!(%e, "Could not relabel `/nix` before removing the SELinux policy for Nix");
+            }
+        }
+
+        let mut command = Command::new("semodule");
+        command.args(["--remove", "nix"]);
+        let output = crate::command_output(&mut command)
+            .await
+            .map_err(|e| ActionErrorKind::command(&command, e))
+            .map_err(Self::error)?;
+        if !output.status.success() {
+            match SemoduleFailure::classify(&String::from_utf8_lossy(&output.stderr)) {
+                SemoduleFailure::NotInstalled => {
+                    tracing::debug!("The `nix` SELinux module was already removed")
+                },
+                SemoduleFailure::Locked => {
+                    warning::warn(
+                        WarningKind::SelinuxModuleLeftInPlace,
+                        format!(
+                            "The SELinux policy store is locked by another process, so the `nix` module was left in place. \
+                            Once it is released, remove it with `sudo semodule --remove nix && sudo rm {}`",
+                            self.policy_path.display()
+                        ),
+                    );
+                    return Ok(());
+                },
+                SemoduleFailure::Other => {
+                    return Err(Self::error(ActionErrorKind::command_output(
+                        &command, output,
+                    )))
+                },
+            }
+        }
+
+        crate::util::remove_file(&self.policy_path, OnMissing::Ignore)
+            .await
+            .map_err(|e| ActionErrorKind::Remove(self.policy_path.clone(), e))
+            .map_err(Self::error)?;
+
+        if host_path("/nix").exists() {
+            execute_command(Command::new("restorecon").args(["-FR", "/nix"]))
                 .await
                 .map_err(Self::error)?;
         }
 
+        verify_removed().await;
+
         Ok(())
     }
+
+    fn revert_after(&self) -> Vec<ActionTag> {
+        vec![
+            ConfigureUpstreamInitService::action_tag(),
+            ConfigureDeterminateNixdInitService::action_tag(),
+            ConfigureDaemonUser::action_tag(),
+            ProvisionDeterminateNixd::action_tag(),
+            ProvisionNix::action_tag(),
+            // Such as `/nix` itself
+            CreateDirectory::action_tag(),
+        ]
+    }
+}
+
+/// Why `semodule --remove nix` failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SemoduleFailure {
+    /// Another process holds the policy store, such as a package manager updating the policy
+    Locked,
+    /// The module is not loaded, such as when it was removed by hand
+    NotInstalled,
+    Other,
+}
+
+impl SemoduleFailure {
+    fn classify(stderr: &str) -> Self {
+        let stderr = stderr.to_lowercase();
+        if stderr.contains("lock") {
+            Self::Locked
+        } else if stderr.contains("no such file or directory")
+            || stderr.contains("not found")
+            || stderr.contains("unable to remove module")
+        {
+            Self::NotInstalled
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// Warn if the `nix` module is still loaded after it was removed
+async fn verify_removed() {
+    let mode = match crate::command_output(&mut Command::new("getenforce")).await {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        },
+        _ => "unknown".into(),
+    };
+    match crate::command_output(Command::new("semodule").arg("-l")).await {
+        Ok(output) if output.status.success() => {
+            let loaded = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.split_whitespace().next() == Some("nix"));
+            if loaded {
+                tracing::warn!(
+                    %mode,
+                    "The `nix` SELinux module is still loaded, remove it with `sudo semodule --remove nix`"
+                );
+            } else {
+                tracing::debug!(%mode, "The `nix` SELinux module was removed");
+            }
+        },
+        _ => tracing::debug!(%mode, "Could not list the SELinux modules"),
+    }
 }
 
 async fn remove_existing_policy(policy_path: &Path) -> Result<(), ActionErrorKind> {
@@ -133,3 +270,57 @@ async fn remove_existing_policy(policy_path: &Path) -> Result<(), ActionErrorKin
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{ProvisionSelinux, SemoduleFailure};
+    use crate::test_harness::{FakeCommand, SandboxContext};
+
+    #[test]
+    fn classifies_semodule_failures() {
+        assert_eq!(
+            SemoduleFailure::classify(
+                "libsemanage.semanage_get_active_lock: Could not get direct transaction lock at /var/lib/selinux/targeted/semanage.trans.LOCK. (Resource temporarily unavailable).\nsemodule:  Failed!\n"
+            ),
+            SemoduleFailure::Locked
+        );
+        assert_eq!(
+            SemoduleFailure::classify(
+                "libsemanage.semanage_direct_remove_key: Unable to remove module nix at priority 400. (No such file or directory).\nsemodule:  Failed!\n"
+            ),
+            SemoduleFailure::NotInstalled
+        );
+        assert_eq!(
+            SemoduleFailure::classify("semodule: Out of memory!\n"),
+            SemoduleFailure::Other
+        );
+    }
+
+    #[tokio::test]
+    async fn a_locked_policy_store_only_warns() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let policy_path = sandbox.path("/usr/share/selinux/packages/nix.pp");
+        let mut action = sandbox
+            .scope(ProvisionSelinux::plan(policy_path.clone(), b"policy"))
+            .await?;
+        sandbox.scope(action.try_execute()).await?;
+
+        sandbox.fake_once(
+            "semodule",
+            FakeCommand::failure(1).stderr(
+                "libsemanage.semanage_get_active_lock: Could not get direct transaction lock at /var/lib/selinux/targeted/semanage.trans.LOCK.\n",
+            ),
+        );
+        sandbox.scope(action.try_revert()).await?;
+        assert!(policy_path.exists(), "The policy should be left for later");
+        assert!(sandbox.invocations_of("getenforce").is_empty());
+
+        sandbox.fake_once(
+            "semodule",
+            FakeCommand::failure(1).stderr("semodule:  Failed!\n"),
+        );
+        action.state = crate::action::ActionState::Completed;
+        assert!(sandbox.scope(action.try_revert()).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::action::{ActionError, ActionErrorKind, ActionTag};
+use crate::execute_command;
+
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
+
+pub const SELINUX_FILE_CONTEXTS: &str = include_str!("selinux/nix.fc");
+pub const DETERMINATE_SELINUX_FILE_CONTEXTS: &str = include_str!("selinux/determinate-nix.fc");
+
+/// A file context rule, labelling paths matching `regex` with `context_type`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct FileContext {
+    pub regex: String,
+    pub context_type: String,
+}
+
+/// The rules of a `.fc` file, lines like `/nix/store/[^/]+/s?bin(/.*)?  system_u:object_r:bin_t:s0`
+pub fn parse_file_contexts(fc: &str) -> Vec<FileContext> {
+    fc.lines()
+        .filter_map(|line| {
+            let (regex, context) = line.trim().split_once(char::is_whitespace)?;
+            // `user:role:type:level`
+            let context_type = context.trim().split(':').nth(2)?;
+            Some(FileContext {
+                regex: regex.to_string(),
+                context_type: context_type.to_string(),
+            })
+        })
+        .collect()
+}
+
+/**
+Register the file contexts of the SELinux policy for Nix with `semanage fcontext`, without loading
+the policy module
+
+For policies the `nix` module cannot be loaded into, like Amazon Linux 2023's, see
+[`ProvisionSelinux`](crate::action::linux::ProvisionSelinux) otherwise.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "provision_selinux_file_contexts")]
+pub struct ProvisionSelinuxFileContexts {
+    contexts: Vec<FileContext>,
+    /// Why the policy module is not loaded, shown when explaining
+    reason: String,
+}
+
+impl ProvisionSelinuxFileContexts {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        file_contexts: &str,
+        reason: String,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let this = Self {
+            contexts: parse_file_contexts(file_contexts),
+            reason,
+        };
+
+        // Like `ProvisionSelinux`, `restorecon` must run even if the rules are in place.
+
+        Ok(StatefulAction::uncompleted(this))
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "provision_selinux_file_contexts")]
+impl Action for ProvisionSelinuxFileContexts {
+    fn action_tag() -> ActionTag {
+        ActionTag("provision_selinux_file_contexts")
+    }
+    fn tracing_synopsis(&self) -> String {
+        "Register SELinux file contexts for Nix".to_string()
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "provision_selinux_file_contexts",
+            contexts = self.contexts.len(),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![
+            self.reason.clone(),
+            "Paths in `/nix` are labelled with `semanage fcontext` rules instead of the `nix` policy module:".to_string(),
+        ];
+        explanation.extend(self.contexts.iter().map(
+            |FileContext {
+                 regex,
+                 context_type,
+             }| format!("`{regex}` as `{context_type}`"),
+        ));
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self
+            .contexts
+            .iter()
+            .flat_map(
+                |FileContext {
+                     regex,
+                     context_type,
+                 }| {
+                    // A rule left by an earlier install is updated instead
+                    ["--add", "--modify"].map(|verb| {
+                        PrivilegedOperation::command(
+                            "semanage",
+                            [
+                                "fcontext",
+                                verb,
+                                "--type",
+                                context_type.as_str(),
+                                regex.as_str(),
+                            ],
+                        )
+                    })
+                },
+            )
+            .collect::<Vec<_>>();
+        operations.push(PrivilegedOperation::command("restorecon", ["-FR", "/nix"]));
+        operations
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        for FileContext {
+            regex,
+            context_type,
+        } in &self.contexts
+        {
+            // A rule left by an earlier install is updated instead
+            let added = execute_command(
+                Command::new("semanage")
+                    .args(["fcontext", "--add", "--type", context_type])
+                    .arg(regex),
+            )
+            .await;
+            if added.is_err() {
+                execute_command(
+                    Command::new("semanage")
+                        .args(["fcontext", "--modify", "--type", context_type])
+                        .arg(regex),
+                )
+                .await
+                .map_err(Self::error)?;
+            }
+        }
+
+        execute_command(Command::new("restorecon").args(["-FR", "/nix"]))
+            .await
+            .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            "Remove the SELinux file contexts for Nix".into(),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        for FileContext { regex, .. } in &self.contexts {
+            if let Err(err) = execute_command(
+                Command::new("semanage")
+                    .args(["fcontext", "--delete"])
+                    .arg(regex),
+            )
+            .await
+            {
+                errors.push(Self::error(err));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        parse_file_contexts, FileContext, ProvisionSelinuxFileContexts,
+        DETERMINATE_SELINUX_FILE_CONTEXTS, SELINUX_FILE_CONTEXTS,
+    };
+    use crate::test_harness::{FakeCommand, SandboxContext};
+
+    #[test]
+    fn parses_policy_file_contexts() {
+        let contexts = parse_file_contexts(SELINUX_FILE_CONTEXTS);
+        assert_eq!(contexts.len(), 8);
+        assert_eq!(
+            contexts[0],
+            FileContext {
+                regex: "/nix/store/[^/]+/s?bin(/.*)?".into(),
+                context_type: "bin_t".into(),
+            }
+        );
+        // Blank lines are skipped
+        let determinate = parse_file_contexts(DETERMINATE_SELINUX_FILE_CONTEXTS);
+        assert_eq!(determinate.len(), 13);
+        assert!(determinate.contains(&FileContext {
+            regex: "/usr/local/bin/determinate-nixd".into(),
+            context_type: "bin_t".into(),
+        }));
+    }
+
+    #[tokio::test]
+    async fn registers_and_removes_contexts() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        // The second rule was left behind by an earlier install
+        sandbox.fake_once("semanage", FakeCommand::success());
+        sandbox.fake_once("semanage", FakeCommand::failure(1));
+
+        let mut action = sandbox
+            .scope(ProvisionSelinuxFileContexts::plan(
+                "/nix/store/[^/]+/s?bin(/.*)?\tsystem_u:object_r:bin_t:s0\n\
+                 /nix/var/nix/daemon-socket(/.*)?\tsystem_u:object_r:var_run_t:s0\n",
+                "Amazon Linux 2023 was detected".into(),
+            ))
+            .await?;
+        sandbox.scope(action.try_execute()).await?;
+
+        let semanage = sandbox
+            .invocations_of("semanage")
+            .into_iter()
+            .map(|v| v.args.join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            semanage,
+            [
+                "fcontext --add --type bin_t /nix/store/[^/]+/s?bin(/.*)?",
+                "fcontext --add --type var_run_t /nix/var/nix/daemon-socket(/.*)?",
+                "fcontext --modify --type var_run_t /nix/var/nix/daemon-socket(/.*)?",
+            ]
+        );
+        assert_eq!(sandbox.invocations_of("restorecon").len(), 1);
+
+        sandbox.scope(action.try_revert()).await?;
+        assert_eq!(
+            sandbox
+                .invocations_of("semanage")
+                .iter()
+                .filter(|v| v.args[1] == "--delete")
+                .count(),
+            2
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use super::persist_steamos_units::enabled_units;
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::execute_command;
+use crate::util::host_path;
+
+pub(crate) const SYSTEMD_SYSTEM_DIR: &str = "/etc/systemd/system";
+
+/// A unit file, or a link enabling one, in `/etc/systemd/system`
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct SteamosUnit {
+    /// Relative to `/etc/systemd/system`, such as `sockets.target.wants/nix-daemon.socket`
+    pub(crate) path: PathBuf,
+    pub(crate) source: SteamosUnitSource,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SteamosUnitSource {
+    /// A symbolic link to this path
+    Link(PathBuf),
+    /// A file with these contents
+    Contents(String),
+}
+
+impl SteamosUnit {
+    /// Read the unit at `path` in `dir`, keeping a symbolic link a link
+    pub(crate) async fn read(dir: &Path, path: &Path) -> Result<Self, ActionErrorKind> {
+        let full = dir.join(path);
+        let host = host_path(&full);
+        let metadata = tokio::fs::symlink_metadata(&host)
+            .await
+            .map_err(|e| ActionErrorKind::GettingMetadata(full.clone(), e))?;
+        let source = if metadata.is_symlink() {
+            SteamosUnitSource::Link(
+                tokio::fs::read_link(&host)
+                    .await
+                    .map_err(|e| ActionErrorKind::ReadSymlink(full.clone(), e))?,
+            )
+        } else {
+            SteamosUnitSource::Contents(
+                tokio::fs::read_to_string(&host)
+                    .await
+                    .map_err(|e| ActionErrorKind::Read(full.clone(), e))?,
+            )
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Write the unit to `dir`, creating the directory it is in
+    pub(crate) async fn write(&self, dir: &Path) -> Result<(), ActionErrorKind> {
+        let full = dir.join(&self.path);
+        if let Some(parent) = full.parent() {
+            tokio::fs::create_dir_all(host_path(parent))
+                .await
+                .map_err(|e| ActionErrorKind::CreateDirectory(parent.to_path_buf(), e))?;
+        }
+        match &self.source {
+            SteamosUnitSource::Link(target) => tokio::fs::symlink(target, host_path(&full))
+                .await
+                .map_err(|e| ActionErrorKind::Symlink(target.clone(), full, e)),
+            SteamosUnitSource::Contents(contents) => tokio::fs::write(host_path(&full), contents)
+                .await
+                .map_err(|e| ActionErrorKind::Write(full, e)),
+        }
+    }
+
+    /// If the unit is absent from `dir`, a link whose target is missing is not
+    pub(crate) fn is_missing(&self, dir: &Path) -> bool {
+        std::fs::symlink_metadata(host_path(dir.join(&self.path))).is_err()
+    }
+}
+
+/// The Nix units a `steam-deck` receipt installed in `/etc/systemd/system`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RecordedSteamosUnits {
+    pub(crate) units: Vec<SteamosUnit>,
+    /// The units the install enabled
+    pub(crate) enable: Vec<String>,
+    /// If the units were read from the copies the install keeps, which include the Nix daemon's
+    /// units and the links enabling them
+    pub(crate) persisted: bool,
+}
+
+/// Read the Nix units `receipt` installed, from the copies kept under `/home/.steamos/offload` or,
+/// for an install made before those were kept, from the units the receipt wrote
+pub(crate) async fn recorded_steamos_units(
+    receipt: &serde_json::Value,
+) -> Result<RecordedSteamosUnits, ActionErrorKind> {
+    let actions = receipt
+        .get("actions")
+        .and_then(|actions| actions.as_array())
+        .into_iter()
+        .flatten()
+        .filter(|action| action["state"].as_str() == Some("Completed"))
+        .filter_map(|action| action.get("action"))
+        .collect::<Vec<_>>();
+
+    let persist = actions
+        .iter()
+        .find(|action| action["action_name"].as_str() == Some("persist_steamos_units"));
+    if let Some(persist) = persist {
+        let directory = persist["directory"].as_str().map(PathBuf::from);
+        let persisted: Vec<PathBuf> =
+            serde_json::from_value(persist["persisted"].clone()).unwrap_or_default();
+        if let Some(directory) = directory {
+            let units_dir = directory.join("units");
+            let mut units = vec![];
+            for path in &persisted {
+                units.push(SteamosUnit::read(&units_dir, path).await?);
+            }
+            return Ok(RecordedSteamosUnits {
+                units,
+                enable: enabled_units(&persisted),
+                persisted: true,
+            });
+        }
+    }
+
+    let units = actions
+        .iter()
+        .filter(|action| action["action_name"].as_str() == Some("create_file"))
+        .filter_map(|action| {
+            let path = Path::new(action["path"].as_str()?)
+                .strip_prefix(SYSTEMD_SYSTEM_DIR)
+                .ok()?;
+            Some(SteamosUnit {
+                path: path.to_path_buf(),
+                source: SteamosUnitSource::Contents(action["buf"].as_str()?.to_string()),
+            })
+        })
+        .collect::<Vec<_>>();
+    let enable = actions
+        .iter()
+        .filter(|action| {
+            action["action_name"].as_str() == Some("start_systemd_unit")
+                && action["enable"].as_bool() == Some(true)
+        })
+        .filter_map(|action| action["unit"].as_str())
+        .filter(|unit| units.iter().any(|known| known.path == Path::new(unit)))
+        .map(ToString::to_string)
+        .collect();
+    Ok(RecordedSteamosUnits {
+        units,
+        enable,
+        persisted: false,
+    })
+}
+
+/**
+Restore the Nix units a SteamOS update removed from `/etc/systemd/system`, then enable and start
+them again
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "relink_steamos_units")]
+pub struct RelinkSteamosUnits {
+    /// The units missing from `/etc/systemd/system`
+    pub(crate) units: Vec<SteamosUnit>,
+    /// The units to enable and start once restored
+    enable: Vec<String>,
+}
+
+impl RelinkSteamosUnits {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        units: Vec<SteamosUnit>,
+        enable: Vec<String>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let units = units
+            .into_iter()
+            .filter(|unit| unit.is_missing(Path::new(SYSTEMD_SYSTEM_DIR)))
+            .collect::<Vec<_>>();
+        if units.is_empty() {
+            tracing::debug!("The Nix units are all in `{SYSTEMD_SYSTEM_DIR}`");
+            return Ok(StatefulAction::completed(Self { units, enable }));
+        }
+        Ok(Self { units, enable }.into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "relink_steamos_units")]
+impl Action for RelinkSteamosUnits {
+    fn action_tag() -> ActionTag {
+        ActionTag("relink_steamos_units")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Restore the Nix units in `{SYSTEMD_SYSTEM_DIR}`")
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "relink_steamos_units",
+            units = self.units.len(),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = self
+            .units
+            .iter()
+            .map(|unit| {
+                let path = Path::new(SYSTEMD_SYSTEM_DIR).join(&unit.path);
+                match &unit.source {
+                    SteamosUnitSource::Link(target) => {
+                        format!("Symlink `{}` to `{}`", target.display(), path.display())
+                    },
+                    SteamosUnitSource::Contents(_) => format!("Create `{}`", path.display()),
+                }
+            })
+            .collect::<Vec<_>>();
+        explanation.push("Run `systemctl daemon-reload`".to_string());
+        if !self.enable.is_empty() {
+            explanation.push(format!(
+                "Run `systemctl enable --now --no-block {}`",
+                self.enable.join(" ")
+            ));
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self
+            .units
+            .iter()
+            .map(|unit| {
+                PrivilegedOperation::write(Path::new(SYSTEMD_SYSTEM_DIR).join(&unit.path), None)
+            })
+            .collect::<Vec<_>>();
+        operations.push(PrivilegedOperation::command("systemctl", ["daemon-reload"]));
+        if !self.enable.is_empty() {
+            operations.push(PrivilegedOperation::command(
+                "systemctl",
+                ["enable", "--now", "--no-block"]
+                    .into_iter()
+                    .map(String::from)
+                    .chain(self.enable.iter().cloned()),
+            ));
+        }
+        operations
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        for unit in &self.units {
+            unit.write(Path::new(SYSTEMD_SYSTEM_DIR))
+                .await
+                .map_err(Self::error)?;
+        }
+
+        execute_command(
+            Command::new("systemctl")
+                .process_group(0)
+                .arg("daemon-reload")
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)?;
+
+        if !self.enable.is_empty() {
+            // Without `--no-block` this waits on units ordered after the ones being started
+            execute_command(
+                Command::new("systemctl")
+                    .process_group(0)
+                    .args(["enable", "--now", "--no-block"])
+                    .args(&self.enable)
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // The restored units belong to the install, which removes them on uninstall
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{recorded_steamos_units, SteamosUnitSource};
+
+    #[tokio::test]
+    async fn receipts_without_copies_restore_the_units_they_wrote() -> eyre::Result<()> {
+        let receipt: serde_json::Value = serde_json::from_str(include_str!(
+            "../../../tests/fixtures/linux/steam-deck.json"
+        ))?;
+        let recorded = recorded_steamos_units(&receipt).await?;
+        assert!(!recorded.persisted);
+        assert_eq!(
+            recorded
+                .units
+                .iter()
+                .map(|unit| unit.path.clone())
+                .collect::<Vec<_>>(),
+            [
+                PathBuf::from("nix-directory.service"),
+                PathBuf::from("nix.mount"),
+                PathBuf::from("ensure-symlinked-units-resolve.service"),
+            ]
+        );
+        assert!(recorded.units.iter().all(|unit| matches!(
+            &unit.source,
+            SteamosUnitSource::Contents(contents) if contents.starts_with("[Unit]")
+        )));
+        // `nix.mount` is only started, it is required by the daemon's units rather than enabled
+        assert_eq!(recorded.enable, ["ensure-symlinked-units-resolve.service"]);
+        Ok(())
+    }
+}
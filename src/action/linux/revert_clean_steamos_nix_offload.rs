@@ -4,7 +4,7 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 
-use crate::action::{Action, ActionDescription, StatefulAction};
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
 use crate::util::OnMissing;
 
 const OFFLOAD_PATH: &str = "/home/.steamos/offload/nix";
@@ -40,13 +40,21 @@ impl Action for RevertCleanSteamosNixOffload {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "revert_clean_steamos_nix_offload",)
+        span!(
+            tracing::Level::DEBUG,
+            "revert_clean_steamos_nix_offload",
+            path = OFFLOAD_PATH,
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
         vec![]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         // noop
@@ -4,7 +4,7 @@ use tracing::{span, Span};
 use crate::action::{ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription};
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
 
 /**
 Start a given systemd unit
@@ -70,6 +70,19 @@ impl Action for StartSystemdUnit {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        match self.enable {
+            true => vec![PrivilegedOperation::command(
+                "systemctl",
+                ["enable", "--now", self.unit.as_str()],
+            )],
+            false => vec![PrivilegedOperation::command(
+                "systemctl",
+                ["start", self.unit.as_str()],
+            )],
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self { unit, enable } = self;
@@ -1,12 +1,10 @@
-use std::path::Path;
-
 use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription, StatefulAction};
+use crate::action::{Action, ActionDescription, PrivilegedOperation, StatefulAction};
 
 /**
 Run `systemctl daemon-reload` (on both execute and revert)
@@ -17,11 +15,11 @@ pub struct SystemctlDaemonReload;
 impl SystemctlDaemonReload {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan() -> Result<StatefulAction<Self>, ActionError> {
-        if !Path::new("/run/systemd/system").exists() {
+        if !crate::util::host_path("/run/systemd/system").exists() {
             return Err(Self::error(ActionErrorKind::SystemdMissing));
         }
 
-        if which::which("systemctl").is_err() {
+        if crate::util::which("systemctl").is_err() {
             return Err(Self::error(ActionErrorKind::SystemdMissing));
         }
 
@@ -47,6 +45,10 @@ impl Action for SystemctlDaemonReload {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::command("systemctl", ["daemon-reload"])]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         execute_command(
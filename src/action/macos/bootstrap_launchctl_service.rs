@@ -6,7 +6,7 @@ use tracing::{span, Span};
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription};
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
 
 use super::{service_is_disabled, DARWIN_LAUNCHD_DOMAIN};
 
@@ -36,8 +36,7 @@ impl BootstrapLaunchctlService {
             command.stdin(std::process::Stdio::null());
             command.stdout(std::process::Stdio::piped());
             command.stderr(std::process::Stdio::piped());
-            let command_output = command
-                .output()
+            let command_output = crate::command_output(&mut command)
                 .await
                 .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
             // We presume that success means it's found
@@ -86,6 +85,35 @@ impl Action for BootstrapLaunchctlService {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let service_target = format!("{DARWIN_LAUNCHD_DOMAIN}/{}", self.service);
+        let mut operations = vec![];
+        if self.is_disabled {
+            operations.push(PrivilegedOperation::command(
+                "launchctl",
+                ["enable", &service_target],
+            ));
+        }
+        if self.is_present {
+            operations.push(PrivilegedOperation::command(
+                "launchctl",
+                ["bootout", &service_target],
+            ));
+        }
+        operations.extend([
+            PrivilegedOperation::service(&self.service),
+            PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "bootstrap",
+                    DARWIN_LAUNCHD_DOMAIN,
+                    &self.path.display().to_string(),
+                ],
+            ),
+        ]);
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
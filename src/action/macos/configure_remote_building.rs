@@ -1,5 +1,7 @@
 use crate::action::base::{create_or_insert_into_file, CreateOrInsertIntoFile};
-use crate::action::{Action, ActionDescription, ActionError, ActionTag, StatefulAction};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionTag, PrivilegedOperation, StatefulAction,
+};
 
 use std::path::Path;
 use tracing::{span, Instrument, Span};
@@ -18,12 +20,13 @@ pub struct ConfigureRemoteBuilding {
 
 impl ConfigureRemoteBuilding {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan() -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(annotation: Option<String>) -> Result<StatefulAction<Self>, ActionError> {
+        let annotation = crate::settings::annotation_comment(annotation.as_deref());
         let shell_buf = format!(
             r#"
 # Set up Nix only on SSH connections
 # See: https://github.com/DeterminateSystems/nix-installer/pull/714
-if [ -e '{PROFILE_NIX_FILE_SHELL}' ] && [ -n "${{SSH_CONNECTION}}" ] && [ "${{SHLVL}}" -eq 1 ]; then
+{annotation}if [ -e '{PROFILE_NIX_FILE_SHELL}' ] && [ -n "${{SSH_CONNECTION}}" ] && [ "${{SHLVL}}" -eq 1 ]; then
     . '{PROFILE_NIX_FILE_SHELL}'
 fi
 # End Nix
@@ -67,7 +70,14 @@ impl Action for ConfigureRemoteBuilding {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "configure_remote_building",)
+        span!(
+            tracing::Level::DEBUG,
+            "configure_remote_building",
+            path = self
+                .create_or_insert_into_file
+                .as_ref()
+                .map(|v| tracing::field::display(v.inner().path.display())),
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
@@ -81,6 +91,13 @@ impl Action for ConfigureRemoteBuilding {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.create_or_insert_into_file
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+            .collect()
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let span = tracing::Span::current().clone();
@@ -110,4 +127,9 @@ impl Action for ConfigureRemoteBuilding {
 
         Ok(())
     }
+
+    fn dependencies(&self) -> Option<Vec<ActionTag>> {
+        // The snippet only loads Nix once it is there, and nothing else writes `/etc/zshenv`
+        Some(vec![])
+    }
 }
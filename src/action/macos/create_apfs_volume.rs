@@ -1,4 +1,5 @@
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
 use tokio::process::Command;
@@ -6,9 +7,16 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
+use crate::warning::{self, WarningKind};
 
-use crate::action::{Action, ActionDescription};
-use crate::os::darwin::{DiskUtilApfsListOutput, DiskUtilInfoOutput};
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
+use crate::os::darwin::{DiskUtilApfsListOutput, DiskUtilApfsListVolume, DiskUtilInfoOutput};
+
+/// APFS allocates in blocks of this size, so a quota or reserve may be rounded to it
+const APFS_BLOCK_SIZE: u64 = 4096;
+
+/// The share of its quota a volume may use before `status` and `self-test` warn it is nearly full
+pub(crate) const QUOTA_WARNING_SHARE: f64 = 0.9;
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "create_apfs_volume")]
@@ -16,6 +24,95 @@ pub struct CreateApfsVolume {
     disk: PathBuf,
     name: String,
     case_sensitive: bool,
+    /// The most the volume may use of its container, absent from receipts from before it could be set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota: Option<VolumeSize>,
+    /// The space of the container set aside for the volume
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reserve: Option<VolumeSize>,
+}
+
+/// A size for the quota or reserve of an APFS volume, like `50G` or `512MiB`
+///
+/// Units are powers of 1000 (`K`, `M`, `G`, `T`, optionally followed by `B`) as `diskutil` takes
+/// them, or of 1024 with an `i` (`KiB`, `MiB`, `GiB`, `TiB`). A number without a unit is bytes.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize,
+)]
+#[serde(transparent)]
+pub struct VolumeSize(u64);
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("TiB", 1 << 40),
+    ("TB", 1_000_000_000_000),
+    ("GiB", 1 << 30),
+    ("GB", 1_000_000_000),
+    ("MiB", 1 << 20),
+    ("MB", 1_000_000),
+    ("KiB", 1 << 10),
+    ("KB", 1_000),
+];
+
+impl VolumeSize {
+    pub fn from_bytes(bytes: u64) -> Self {
+        Self(bytes)
+    }
+
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for VolumeSize {
+    type Err = CreateApfsVolumeError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || CreateApfsVolumeError::InvalidSize(input.to_string());
+        let trimmed = input.trim();
+        let split = trimmed
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+        let number = number.parse::<f64>().map_err(|_| invalid())?;
+        let unit = unit.trim().to_ascii_lowercase();
+        let unit = unit.strip_suffix('b').unwrap_or(&unit);
+        let multiplier: u64 = match unit {
+            "" => 1,
+            "k" => 1_000,
+            "m" => 1_000_000,
+            "g" => 1_000_000_000,
+            "t" => 1_000_000_000_000,
+            "ki" => 1 << 10,
+            "mi" => 1 << 20,
+            "gi" => 1 << 30,
+            "ti" => 1 << 40,
+            _ => return Err(invalid()),
+        };
+        let bytes = (number * multiplier as f64).round();
+        if !bytes.is_finite() || bytes < 1.0 || bytes >= u64::MAX as f64 {
+            return Err(invalid());
+        }
+        Ok(Self(bytes as u64))
+    }
+}
+
+impl std::fmt::Display for VolumeSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match SIZE_UNITS
+            .iter()
+            .find(|(_, multiplier)| self.0.is_multiple_of(*multiplier))
+        {
+            Some((unit, multiplier)) => write!(f, "{}{unit}", self.0 / multiplier),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+/// The quota and reserve to create an APFS volume with, none by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VolumeLimits {
+    pub quota: Option<VolumeSize>,
+    pub reserve: Option<VolumeSize>,
 }
 
 impl CreateApfsVolume {
@@ -24,7 +121,25 @@ impl CreateApfsVolume {
         disk: impl AsRef<Path>,
         name: String,
         case_sensitive: bool,
+        limits: VolumeLimits,
     ) -> Result<StatefulAction<Self>, ActionError> {
+        let VolumeLimits { quota, reserve } = limits;
+        if let (Some(quota), Some(reserve)) = (quota, reserve) {
+            if reserve > quota {
+                return Err(Self::error(CreateApfsVolumeError::ReserveAboveQuota {
+                    quota,
+                    reserve,
+                }));
+            }
+        }
+        let this = Self {
+            disk: disk.as_ref().to_path_buf(),
+            name,
+            case_sensitive,
+            quota,
+            reserve,
+        };
+
         let output =
             execute_command(Command::new("/usr/sbin/diskutil").args(["apfs", "list", "-plist"]))
                 .await
@@ -32,23 +147,179 @@ impl CreateApfsVolume {
 
         let parsed: DiskUtilApfsListOutput =
             plist::from_bytes(&output.stdout).map_err(Self::error)?;
-        for container in parsed.containers {
-            for volume in container.volumes {
-                if volume.name.as_ref() == Some(&name) {
-                    return Ok(StatefulAction::completed(Self {
-                        disk: disk.as_ref().to_path_buf(),
-                        name,
-                        case_sensitive,
-                    }));
+        for container in &parsed.containers {
+            for volume in &container.volumes {
+                if volume.name.as_ref() == Some(&this.name) {
+                    return Ok(StatefulAction::completed(this));
                 }
             }
         }
 
-        Ok(StatefulAction::uncompleted(Self {
-            disk: disk.as_ref().to_path_buf(),
-            name,
-            case_sensitive,
-        }))
+        this.check_free_space(&parsed).map_err(Self::error)?;
+        Ok(StatefulAction::uncompleted(this))
+    }
+
+    /// Check the container on `disk` has room for the reserve, and warn if the quota is more
+    /// than the container has free
+    fn check_free_space(
+        &self,
+        listed: &DiskUtilApfsListOutput,
+    ) -> Result<(), CreateApfsVolumeError> {
+        if self.quota.is_none() && self.reserve.is_none() {
+            return Ok(());
+        }
+        let disk = self.disk.display().to_string();
+        let disk = disk.strip_prefix("/dev/").unwrap_or(&disk);
+        let Some(free) = listed
+            .containers
+            .iter()
+            .find(|container| container.container_reference.as_deref() == Some(disk))
+            .and_then(|container| container.capacity_free)
+        else {
+            tracing::debug!(disk, "Could not find the free space of the APFS container");
+            return Ok(());
+        };
+        let free = VolumeSize(free);
+        if let Some(reserve) = self.reserve {
+            if reserve > free {
+                return Err(CreateApfsVolumeError::ReserveAboveFreeSpace {
+                    disk: disk.to_string(),
+                    reserve,
+                    free,
+                });
+            }
+        }
+        if let Some(quota) = self.quota {
+            if quota > free {
+                warning::warn(
+                    WarningKind::VolumeQuotaAboveFreeSpace,
+                    format!("The quota of {quota} for the Nix Store volume is more than the {free} free on `{disk}`, so the volume may fill the disk before reaching it"),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The arguments of `diskutil` which add the volume
+    fn add_volume_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "apfs".to_string(),
+            "addVolume".to_string(),
+            self.disk.display().to_string(),
+            if !self.case_sensitive {
+                "APFS"
+            } else {
+                "Case-sensitive APFS"
+            }
+            .to_string(),
+            self.name.clone(),
+        ];
+        // `diskutil` takes sizes with a unit, `B` being bytes
+        if let Some(quota) = self.quota {
+            args.extend(["-quota".to_string(), format!("{}B", quota.bytes())]);
+        }
+        if let Some(reserve) = self.reserve {
+            args.extend(["-reserve".to_string(), format!("{}B", reserve.bytes())]);
+        }
+        args.push("-nomount".to_string());
+        args
+    }
+
+    /// ` with a quota of 50GB and a reserve of 10GB`, or nothing without either
+    fn limits_description(&self) -> String {
+        let limits = [("quota", self.quota), ("reserve", self.reserve)]
+            .into_iter()
+            .filter_map(|(limit, size)| Some(format!("a {limit} of {}", size?)))
+            .collect::<Vec<_>>();
+        match limits.is_empty() {
+            true => String::new(),
+            false => format!(" with {}", limits.join(" and ")),
+        }
+    }
+
+    /// Check the volume `diskutil` created has the quota and reserve it was asked for
+    async fn verify_limits(&self) -> Result<(), CreateApfsVolumeError> {
+        let volume = apfs_volume(&self.name)
+            .await
+            .map_err(|e| CreateApfsVolumeError::Listing(self.name.clone(), Box::new(e)))?;
+        for (limit, expected, actual) in [
+            (
+                "quota",
+                self.quota,
+                volume.as_ref().and_then(|volume| volume.capacity_quota),
+            ),
+            (
+                "reserve",
+                self.reserve,
+                volume.as_ref().and_then(|volume| volume.capacity_reserve),
+            ),
+        ] {
+            let Some(expected) = expected else {
+                continue;
+            };
+            if !limit_applied(expected, actual) {
+                return Err(CreateApfsVolumeError::LimitNotApplied {
+                    name: self.name.clone(),
+                    limit,
+                    expected,
+                    actual: actual.filter(|actual| *actual > 0).map(VolumeSize),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// If `diskutil apfs list` reporting `actual` means the limit `expected` was set, allowing for
+/// rounding to the block size
+fn limit_applied(expected: VolumeSize, actual: Option<u64>) -> bool {
+    actual.is_some_and(|actual| actual > 0 && actual.abs_diff(expected.bytes()) <= APFS_BLOCK_SIZE)
+}
+
+/// The APFS volume named `name`, as `diskutil apfs list` reports it
+pub(crate) async fn apfs_volume(
+    name: &str,
+) -> Result<Option<DiskUtilApfsListVolume>, ActionErrorKind> {
+    let output = execute_command(
+        Command::new("/usr/sbin/diskutil")
+            .process_group(0)
+            .args(["apfs", "list", "-plist"])
+            .stdin(std::process::Stdio::null()),
+    )
+    .await?;
+    let parsed: DiskUtilApfsListOutput = plist::from_bytes(&output.stdout)?;
+    Ok(parsed
+        .containers
+        .into_iter()
+        .flat_map(|container| container.volumes)
+        .find(|volume| volume.name.as_deref() == Some(name)))
+}
+
+/// How much of its quota an APFS volume uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QuotaUsage {
+    pub(crate) in_use: VolumeSize,
+    pub(crate) quota: VolumeSize,
+}
+
+impl QuotaUsage {
+    /// The usage of `volume`, if it has a quota
+    pub(crate) fn of(volume: &DiskUtilApfsListVolume) -> Option<Self> {
+        let quota = volume.capacity_quota.filter(|quota| *quota > 0)?;
+        Some(Self {
+            in_use: VolumeSize(volume.capacity_in_use.unwrap_or_default()),
+            quota: VolumeSize(quota),
+        })
+    }
+
+    /// If it uses at least [`QUOTA_WARNING_SHARE`] of its quota
+    pub(crate) fn nearly_full(&self) -> bool {
+        self.in_use.bytes() as f64 >= self.quota.bytes() as f64 * QUOTA_WARNING_SHARE
+    }
+
+    /// The percentage of the quota in use
+    pub(crate) fn percent(&self) -> u64 {
+        (self.in_use.bytes() as f64 / self.quota.bytes() as f64 * 100.0).round() as u64
     }
 }
 
@@ -60,19 +331,22 @@ impl Action for CreateApfsVolume {
     }
     fn tracing_synopsis(&self) -> String {
         format!(
-            "Create an APFS volume on `{}` named `{}`",
+            "Create an APFS volume on `{}` named `{}`{}",
             self.disk.display(),
-            self.name
+            self.name,
+            self.limits_description(),
         )
     }
 
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "create_volume",
+            "create_apfs_volume",
             disk = %self.disk.display(),
             name = %self.name,
             case_sensitive = %self.case_sensitive,
+            quota = ?self.quota,
+            reserve = ?self.reserve,
         )
     }
 
@@ -80,34 +354,28 @@ impl Action for CreateApfsVolume {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::command(
+            "/usr/sbin/diskutil",
+            self.add_volume_args(),
+        )]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self {
-            disk,
-            name,
-            case_sensitive,
-        } = self;
-
         execute_command(
             Command::new("/usr/sbin/diskutil")
                 .process_group(0)
-                .args([
-                    "apfs",
-                    "addVolume",
-                    &format!("{}", disk.display()),
-                    if !*case_sensitive {
-                        "APFS"
-                    } else {
-                        "Case-sensitive APFS"
-                    },
-                    name,
-                    "-nomount",
-                ])
+                .args(self.add_volume_args())
                 .stdin(std::process::Stdio::null()),
         )
         .await
         .map_err(Self::error)?;
 
+        if self.quota.is_some() || self.reserve.is_some() {
+            self.verify_limits().await.map_err(Self::error)?;
+        }
+
         Ok(())
     }
 
@@ -164,8 +432,7 @@ impl Action for CreateApfsVolume {
             command.stdin(std::process::Stdio::null());
             tracing::debug!(%retry_tokens, command = ?command.as_std(), "Waiting for volume deletion to succeed");
 
-            let output = command
-                .output()
+            let output = crate::command_output(&mut command)
                 .await
                 .map_err(|e| ActionErrorKind::command(&command, e))
                 .map_err(Self::error)?;
@@ -186,3 +453,217 @@ impl Action for CreateApfsVolume {
         Ok(())
     }
 }
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateApfsVolumeError {
+    #[error(
+        "`{0}` is not a size, use a number of bytes or one with a unit like `50G` or `512MiB`"
+    )]
+    InvalidSize(String),
+    #[error("The reserve of {reserve} for the Nix Store volume is more than its quota of {quota}")]
+    ReserveAboveQuota {
+        quota: VolumeSize,
+        reserve: VolumeSize,
+    },
+    #[error("The reserve of {reserve} for the Nix Store volume is more than the {free} free on `{disk}`")]
+    ReserveAboveFreeSpace {
+        disk: String,
+        reserve: VolumeSize,
+        free: VolumeSize,
+    },
+    #[error("Listing the APFS volumes to check `{0}` was created as asked")]
+    Listing(String, #[source] Box<ActionErrorKind>),
+    #[error("The APFS volume `{name}` was created without its {limit} of {expected}{}", match actual {
+        Some(actual) => format!(", `diskutil` reports {actual}"),
+        None => String::new(),
+    })]
+    LimitNotApplied {
+        name: String,
+        limit: &'static str,
+        expected: VolumeSize,
+        actual: Option<VolumeSize>,
+    },
+}
+
+impl From<CreateApfsVolumeError> for ActionErrorKind {
+    fn from(val: CreateApfsVolumeError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CreateApfsVolume, CreateApfsVolumeError, QuotaUsage, VolumeLimits, VolumeSize};
+    use crate::{
+        action::{Action, ActionErrorKind},
+        os::darwin::DiskUtilApfsListVolume,
+        test_harness::{FakeCommand, SandboxContext},
+        warning::WarningKind,
+    };
+
+    /// An `apfs list` of the container `disk3` with `free` bytes free and no volumes
+    fn container_with_free_space(free: u64) -> String {
+        format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><plist version="1.0"><dict>"#,
+                "<key>Containers</key><array><dict>",
+                "<key>ContainerReference</key><string>disk3</string>",
+                "<key>CapacityFree</key><integer>{}</integer>",
+                "<key>Volumes</key><array/>",
+                "</dict></array></dict></plist>",
+            ),
+            free
+        )
+    }
+
+    fn size(input: &str) -> VolumeSize {
+        input.parse().expect("a valid size")
+    }
+
+    #[test]
+    fn sizes_are_parsed_with_units() {
+        assert_eq!(size("4096").bytes(), 4096);
+        assert_eq!(size("50G").bytes(), 50_000_000_000);
+        assert_eq!(size("50gb").bytes(), 50_000_000_000);
+        assert_eq!(size("100GiB").bytes(), 100 << 30);
+        assert_eq!(size("1.5T").bytes(), 1_500_000_000_000);
+        assert_eq!(size(" 512 MiB ").bytes(), 512 << 20);
+        for invalid in ["", "G", "-5G", "0", "12 parsecs", "1.2.3G"] {
+            assert!(
+                matches!(
+                    invalid.parse::<VolumeSize>(),
+                    Err(CreateApfsVolumeError::InvalidSize(_))
+                ),
+                "{invalid:?}"
+            );
+        }
+
+        assert_eq!(size("100GiB").to_string(), "100GiB");
+        assert_eq!(size("50G").to_string(), "50GB");
+        assert_eq!(size("1.5T").to_string(), "1500GB");
+        assert_eq!(size("4097").to_string(), "4097B");
+    }
+
+    #[tokio::test]
+    async fn limits_are_passed_to_diskutil() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake(
+            "diskutil",
+            FakeCommand::success().stdout(container_with_free_space(1 << 40)),
+        );
+        let plain = sandbox
+            .scope(CreateApfsVolume::plan(
+                "disk3",
+                "Nix Store".into(),
+                false,
+                VolumeLimits::default(),
+            ))
+            .await?;
+        assert_eq!(
+            plain.inner().add_volume_args(),
+            [
+                "apfs",
+                "addVolume",
+                "disk3",
+                "APFS",
+                "Nix Store",
+                "-nomount"
+            ]
+        );
+
+        let limited = sandbox
+            .scope(CreateApfsVolume::plan(
+                "disk3",
+                "Nix Store".into(),
+                true,
+                VolumeLimits {
+                    quota: Some(size("100GiB")),
+                    reserve: Some(size("10G")),
+                },
+            ))
+            .await?;
+        assert_eq!(
+            limited.inner().add_volume_args(),
+            [
+                "apfs",
+                "addVolume",
+                "disk3",
+                "Case-sensitive APFS",
+                "Nix Store",
+                "-quota",
+                "107374182400B",
+                "-reserve",
+                "10000000000B",
+                "-nomount"
+            ]
+        );
+        assert!(limited
+            .inner()
+            .tracing_synopsis()
+            .ends_with("with a quota of 100GiB and a reserve of 10GB"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_are_checked_against_the_container() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake(
+            "diskutil",
+            FakeCommand::success().stdout(container_with_free_space(50_000_000_000)),
+        );
+        let plan = |quota: Option<&str>, reserve: Option<&str>| {
+            sandbox.scope(crate::warning::collect(CreateApfsVolume::plan(
+                "/dev/disk3",
+                "Nix Store".into(),
+                false,
+                VolumeLimits {
+                    quota: quota.map(size),
+                    reserve: reserve.map(size),
+                },
+            )))
+        };
+        let custom = |result: Result<_, crate::action::ActionError>| match result
+            .map(|_| ())
+            .expect_err("an error")
+            .kind()
+        {
+            ActionErrorKind::Custom(e) => e.to_string(),
+            kind => panic!("{kind:?}"),
+        };
+
+        let (result, warnings) = plan(Some("40G"), Some("10G")).await;
+        assert!(result.is_ok());
+        assert!(warnings.is_empty());
+
+        let (result, _) = plan(Some("10G"), Some("20G")).await;
+        assert!(custom(result).contains("more than its quota"));
+
+        let (result, _) = plan(None, Some("60G")).await;
+        assert!(custom(result).contains("50GB free"));
+
+        let (result, warnings) = plan(Some("60G"), None).await;
+        assert!(result.is_ok());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::VolumeQuotaAboveFreeSpace);
+        Ok(())
+    }
+
+    #[test]
+    fn usage_near_the_quota_is_flagged() {
+        let volume = |quota, in_use| DiskUtilApfsListVolume {
+            name: Some("Nix Store".into()),
+            file_vault: Some(false),
+            capacity_quota: Some(quota),
+            capacity_reserve: Some(0),
+            capacity_in_use: Some(in_use),
+        };
+        assert_eq!(QuotaUsage::of(&volume(0, 1000)), None);
+        let usage = QuotaUsage::of(&volume(1000, 500)).expect("a quota");
+        assert!(!usage.nearly_full());
+        assert_eq!(usage.percent(), 50);
+        assert!(QuotaUsage::of(&volume(1000, 950))
+            .expect("a quota")
+            .nearly_full());
+    }
+}
@@ -15,9 +15,10 @@ use crate::action::{
     common::place_nix_configuration::NIX_CONF_FOLDER,
     macos::{
         CreateApfsVolume, CreateSyntheticObjects, EnableOwnership, EncryptApfsVolume,
-        UnmountApfsVolume,
+        UnmountApfsVolume, VolumeLimits,
     },
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
 
 pub const VOLUME_MOUNT_SERVICE_NAME: &str = "systems.determinate.nix-store";
@@ -51,6 +52,7 @@ impl CreateDeterminateNixVolume {
         disk: impl AsRef<Path>,
         name: String,
         case_sensitive: bool,
+        limits: VolumeLimits,
         force: bool,
         use_ec2_instance_store: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
@@ -72,7 +74,7 @@ impl CreateDeterminateNixVolume {
 
         let create_synthetic_objects = CreateSyntheticObjects::plan().await.map_err(Self::error)?;
 
-        let create_volume = CreateApfsVolume::plan(disk, name.clone(), case_sensitive)
+        let create_volume = CreateApfsVolume::plan(disk, name.clone(), case_sensitive, limits)
             .await
             .map_err(Self::error)?;
 
@@ -173,6 +175,25 @@ impl Action for CreateDeterminateNixVolume {
         vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self.create_directory.privileged_operations();
+        operations.append(&mut self.create_or_append_synthetic_conf.privileged_operations());
+        operations.append(&mut self.create_synthetic_objects.privileged_operations());
+        operations.append(&mut self.unmount_volume.privileged_operations());
+        operations.append(&mut self.create_volume.privileged_operations());
+        operations.append(&mut self.create_fstab_entry.privileged_operations());
+        operations.append(&mut self.encrypt_volume.privileged_operations());
+        operations.push(PrivilegedOperation::command(
+            "/usr/local/bin/determinate-nixd",
+            ["init", "--stop-after", "mount"],
+        ));
+        operations.append(&mut self.setup_volume_daemon.privileged_operations());
+        operations.append(&mut self.bootstrap_volume.privileged_operations());
+        operations.append(&mut self.kickstart_launchctl_service.privileged_operations());
+        operations.append(&mut self.enable_ownership.privileged_operations());
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         self.create_directory
@@ -201,8 +222,7 @@ impl Action for CreateDeterminateNixVolume {
             command.stderr(std::process::Stdio::null());
             command.stdout(std::process::Stdio::null());
             tracing::debug!(%retry_tokens, command = ?command.as_std(), "Checking for Nix Store volume existence");
-            let output = command
-                .output()
+            let output = crate::command_output(&mut command)
                 .await
                 .map_err(|e| ActionErrorKind::command(&command, e))
                 .map_err(Self::error)?;
@@ -233,8 +253,7 @@ impl Action for CreateDeterminateNixVolume {
         command.stderr(std::process::Stdio::piped());
         command.stdout(std::process::Stdio::piped());
         tracing::trace!(command = ?command.as_std(), "Mounting /nix");
-        let output = command
-            .output()
+        let output = crate::command_output(&mut command)
             .await
             .map_err(|e| ActionErrorKind::command(&command, e))
             .map_err(Self::error)?;
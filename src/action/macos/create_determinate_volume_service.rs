@@ -12,7 +12,10 @@ use tokio::{
 };
 
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
+    },
     execute_command,
 };
 
@@ -133,6 +136,21 @@ impl Action for CreateDeterminateVolumeService {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = vec![];
+        if self.needs_bootout {
+            operations.push(PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "bootout",
+                    &format!("{DARWIN_LAUNCHD_DOMAIN}/{}", self.mount_service_label),
+                ],
+            ));
+        }
+        operations.push(PrivilegedOperation::write(&self.path, None));
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
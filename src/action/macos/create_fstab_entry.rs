@@ -5,8 +5,10 @@ use uuid::Uuid;
 
 use super::get_disk_info_for_label;
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
+use crate::util::host_path;
 
 const FSTAB_PATH: &str = "/etc/fstab";
 
@@ -57,6 +59,10 @@ impl Action for CreateFstabEntry {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::write(FSTAB_PATH, None)]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let fstab_path = Path::new(FSTAB_PATH);
@@ -72,7 +78,7 @@ impl Action for CreateFstabEntry {
             },
         };
 
-        let fstab_buf = tokio::fs::read_to_string(FSTAB_PATH)
+        let fstab_buf = tokio::fs::read_to_string(host_path(fstab_path))
             .await
             .or_else(|e| match e.kind() {
                 std::io::ErrorKind::NotFound => Ok(String::new()),
@@ -113,7 +119,7 @@ impl Action for CreateFstabEntry {
 
         let updated_buf = current_fstab_lines.join("\n");
 
-        write_atomic(fstab_path, &updated_buf)
+        write_atomic(&host_path(fstab_path), &updated_buf)
             .await
             .map_err(Self::error)?;
         Ok(())
@@ -134,7 +140,7 @@ impl Action for CreateFstabEntry {
     async fn revert(&mut self) -> Result<(), ActionError> {
         let fstab_path = Path::new(FSTAB_PATH);
 
-        let fstab_buf = tokio::fs::read_to_string(FSTAB_PATH)
+        let fstab_buf = tokio::fs::read_to_string(host_path(fstab_path))
             .await
             .or_else(|e| match e.kind() {
                 std::io::ErrorKind::NotFound => Ok(String::new()),
@@ -167,7 +173,7 @@ impl Action for CreateFstabEntry {
             current_fstab_lines.push("");
         }
 
-        write_atomic(fstab_path, &current_fstab_lines.join("\n"))
+        write_atomic(&host_path(fstab_path), &current_fstab_lines.join("\n"))
             .await
             .map_err(Self::error)?;
 
@@ -175,6 +181,19 @@ impl Action for CreateFstabEntry {
     }
 }
 
+/// The UUID of the volume the `/nix` line of `fstab` mounts, if it mounts one by UUID
+pub(crate) fn fstab_volume_uuid(fstab: &str) -> Option<Uuid> {
+    fstab
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .find(|line| line.split_whitespace().nth(1) == Some("/nix"))?
+        .split_whitespace()
+        .next()?
+        .strip_prefix("UUID=")?
+        .parse()
+        .ok()
+}
+
 fn fstab_entry(uuid: &Uuid) -> String {
     format!("UUID={uuid} /nix apfs rw,noatime,noauto,nobrowse,nosuid,owners # Added by the Determinate Nix Installer")
 }
@@ -9,7 +9,11 @@ use tokio::{
 };
 
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+        StatefulAction,
+    },
+    drift::WrittenFile,
     execute_command,
 };
 
@@ -23,6 +27,9 @@ pub struct CreateNixHookService {
     path: PathBuf,
     service_label: String,
     needs_bootout: bool,
+    /// The plist, once executed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    written_file: Option<WrittenFile>,
 }
 
 impl CreateNixHookService {
@@ -34,6 +41,7 @@ impl CreateNixHookService {
             ),
             service_label: "systems.determinate.nix-installer.nix-hook".into(),
             needs_bootout: false,
+            written_file: None,
         };
 
         // If the service is currently loaded or running, we need to unload it during execute (since we will then recreate it and reload it)
@@ -117,12 +125,28 @@ impl Action for CreateNixHookService {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = vec![];
+        if self.needs_bootout {
+            operations.push(PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "bootout",
+                    &format!("{DARWIN_LAUNCHD_DOMAIN}/{}", self.service_label),
+                ],
+            ));
+        }
+        operations.push(PrivilegedOperation::write(&self.path, None));
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
             path,
             service_label,
             needs_bootout,
+            written_file,
         } = self;
 
         if *needs_bootout {
@@ -146,6 +170,9 @@ impl Action for CreateNixHookService {
         file.write_all(&buf)
             .await
             .map_err(|e| Self::error(ActionErrorKind::Write(path.to_owned(), e)))?;
+        let written = WrittenFile::whole(path.as_path(), &buf);
+        crate::managed_files::register(Self::action_tag(), &written);
+        *written_file = Some(written);
 
         Ok(())
     }
@@ -2,9 +2,10 @@ use crate::action::{
     base::{create_or_insert_into_file, CreateOrInsertIntoFile},
     macos::{
         BootstrapLaunchctlService, CreateApfsVolume, CreateSyntheticObjects, EnableOwnership,
-        EncryptApfsVolume, UnmountApfsVolume,
+        EncryptApfsVolume, UnmountApfsVolume, VolumeLimits,
     },
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
 use std::{
     path::{Path, PathBuf},
@@ -47,6 +48,7 @@ impl CreateNixVolume {
         disk: impl AsRef<Path>,
         name: String,
         case_sensitive: bool,
+        limits: VolumeLimits,
         encrypt: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let disk = disk.as_ref();
@@ -63,7 +65,7 @@ impl CreateNixVolume {
 
         let create_synthetic_objects = CreateSyntheticObjects::plan().await.map_err(Self::error)?;
 
-        let create_volume = CreateApfsVolume::plan(disk, name.clone(), case_sensitive)
+        let create_volume = CreateApfsVolume::plan(disk, name.clone(), case_sensitive, limits)
             .await
             .map_err(Self::error)?;
 
@@ -169,6 +171,22 @@ impl Action for CreateNixVolume {
         vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self.create_or_append_synthetic_conf.privileged_operations();
+        operations.append(&mut self.create_synthetic_objects.privileged_operations());
+        operations.append(&mut self.unmount_volume.privileged_operations());
+        operations.append(&mut self.create_volume.privileged_operations());
+        operations.append(&mut self.create_fstab_entry.privileged_operations());
+        if let Some(encrypt_volume) = &self.encrypt_volume {
+            operations.append(&mut encrypt_volume.privileged_operations());
+        }
+        operations.append(&mut self.setup_volume_daemon.privileged_operations());
+        operations.append(&mut self.bootstrap_volume.privileged_operations());
+        operations.append(&mut self.kickstart_launchctl_service.privileged_operations());
+        operations.append(&mut self.enable_ownership.privileged_operations());
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         self.create_or_append_synthetic_conf
@@ -193,8 +211,7 @@ impl Action for CreateNixVolume {
             command.stderr(std::process::Stdio::null());
             command.stdout(std::process::Stdio::null());
             tracing::debug!(%retry_tokens, command = ?command.as_std(), "Checking for Nix Store volume existence");
-            let output = command
-                .output()
+            let output = crate::command_output(&mut command)
                 .await
                 .map_err(|e| ActionErrorKind::command(&command, e))
                 .map_err(Self::error)?;
@@ -3,7 +3,9 @@ use tracing::{span, Span};
 
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription, ActionError, ActionTag, StatefulAction};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionTag, PrivilegedOperation, StatefulAction,
+};
 
 /// Create the synthetic objects defined in `/etc/synthetic.conf`
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
@@ -27,7 +29,11 @@ impl Action for CreateSyntheticObjects {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "create_synthetic_objects",)
+        span!(
+            tracing::Level::DEBUG,
+            "create_synthetic_objects",
+            path = "/etc/synthetic.conf",
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
@@ -37,6 +43,18 @@ impl Action for CreateSyntheticObjects {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        ["-t", "-B"]
+            .into_iter()
+            .map(|flag| {
+                PrivilegedOperation::command(
+                    "/System/Library/Filesystems/apfs.fs/Contents/Resources/apfs.util",
+                    [flag],
+                )
+            })
+            .collect()
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         // Yup we literally call both and ignore the error! Reasoning: https://github.com/NixOS/nix/blob/95331cb9c99151cbd790ceb6ddaf49fc1c0da4b3/scripts/create-darwin-volume.sh#L261
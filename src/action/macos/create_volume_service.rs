@@ -14,7 +14,7 @@ use tokio::{
 use crate::{
     action::{
         macos::DARWIN_LAUNCHD_DOMAIN, Action, ActionDescription, ActionError, ActionErrorKind,
-        ActionTag, StatefulAction,
+        ActionTag, PrivilegedOperation, StatefulAction,
     },
     execute_command,
 };
@@ -184,6 +184,21 @@ impl Action for CreateVolumeService {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = vec![];
+        if self.needs_bootout {
+            operations.push(PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "bootout",
+                    &format!("{DARWIN_LAUNCHD_DOMAIN}/{}", self.mount_service_label),
+                ],
+            ));
+        }
+        operations.push(PrivilegedOperation::write(&self.path, None));
+        operations
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let Self {
@@ -257,7 +272,7 @@ impl Action for CreateVolumeService {
 }
 
 /// This function must be able to operate at both plan and execute time.
-async fn generate_mount_plist(
+pub(crate) async fn generate_mount_plist(
     mount_service_label: &str,
     apfs_volume_label: &str,
     uuid: uuid::Uuid,
@@ -268,6 +283,7 @@ async fn generate_mount_plist(
     // The official Nix scripts uppercase the UUID, so we do as well for compatibility.
     let uuid_string = uuid.to_string().to_uppercase();
     let mount_command = if encrypt {
+        let mount_point = crate::util::path_to_string_lossy(mount_point);
         let encrypted_command = format!("/usr/bin/security find-generic-password -s {apfs_volume_label_with_quotes} -w |  /usr/sbin/diskutil apfs unlockVolume {apfs_volume_label_with_quotes} -mountpoint {mount_point:?} -stdinpassphrase");
         vec!["/bin/sh".into(), "-c".into(), encrypted_command]
     } else {
@@ -275,7 +291,7 @@ async fn generate_mount_plist(
             "/usr/sbin/diskutil".into(),
             "mount".into(),
             "-mountPoint".into(),
-            mount_point.display().to_string(),
+            crate::util::path_to_string_lossy(mount_point),
             uuid_string,
         ]
     };
@@ -297,6 +313,29 @@ pub struct LaunchctlMountPlist {
     program_arguments: Vec<String>,
 }
 
+impl LaunchctlMountPlist {
+    /// The UUID of the volume this mounts, unless it mounts by label (like an encrypted volume)
+    pub(crate) fn volume_uuid(&self) -> Option<uuid::Uuid> {
+        match self.program_arguments.as_slice() {
+            [diskutil, mount, .., uuid] if diskutil == "/usr/sbin/diskutil" && mount == "mount" => {
+                uuid.parse().ok()
+            },
+            _ => None,
+        }
+    }
+
+    /// This plist mounting the volume with `uuid` instead, if it mounts by UUID
+    pub(crate) fn with_volume_uuid(mut self, uuid: uuid::Uuid) -> Self {
+        if self.volume_uuid().is_some() {
+            if let Some(last) = self.program_arguments.last_mut() {
+                // Uppercased like `generate_mount_plist` does
+                *last = uuid.to_string().to_uppercase();
+            }
+        }
+        self
+    }
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum CreateVolumeServiceError {
@@ -6,7 +6,7 @@ use tracing::{span, Span};
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription};
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
 use crate::os::darwin::DiskUtilInfoOutput;
 
 /**
@@ -50,6 +50,16 @@ impl Action for EnableOwnership {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::command(
+            "/usr/sbin/diskutil",
+            [
+                "enableOwnership".to_string(),
+                self.path.display().to_string(),
+            ],
+        )]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let should_enable_ownership = {
@@ -3,7 +3,7 @@ use std::time::Duration;
 use crate::{
     action::{
         macos::NIX_VOLUME_MOUNTD_DEST, Action, ActionDescription, ActionError, ActionErrorKind,
-        ActionState, ActionTag, StatefulAction,
+        ActionState, ActionTag, PrivilegedOperation, StatefulAction,
     },
     execute_command,
     os::darwin::DiskUtilApfsListOutput,
@@ -18,15 +18,80 @@ use tracing::{span, Span};
 
 use super::CreateApfsVolume;
 
+/// The keychain service the volume passphrase is stored under
+pub(crate) const KEYCHAIN_SERVICE: &str = "Nix Store";
+/// The keychain the volume passphrase is stored in, readable at boot
+pub(crate) const SYSTEM_KEYCHAIN: &str = "/Library/Keychains/System.keychain";
+
+/// A random volume passphrase
+pub(crate) fn generate_passphrase() -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                            abcdefghijklmnopqrstuvwxyz\
+                                0123456789)(*&^%$#@!~";
+    const PASSWORD_LEN: usize = 32;
+    let mut rng = rand::thread_rng();
+
+    (0..PASSWORD_LEN)
+        .map(|_| {
+            let idx = rng.gen_range(0..CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect()
+}
+
+/// The `security add-generic-password` arguments storing `passphrase` for the volume `name` on
+/// `disk` under `service`, readable by the services which unlock the volume
+pub(crate) fn add_passphrase_args(
+    determinate_nix: bool,
+    name: &str,
+    disk: &Path,
+    service: &str,
+    passphrase: &str,
+) -> Vec<String> {
+    let mut args = vec![
+        "add-generic-password".to_string(),
+        "-a".to_string(),
+        name.to_string(),
+        "-s".to_string(),
+        service.to_string(),
+        "-l".to_string(),
+        format!("{} encryption password", disk.display()),
+        "-D".to_string(),
+        "Encrypted volume password".to_string(),
+        "-j".to_string(),
+        format!("Added automatically by the Nix installer for use by {NIX_VOLUME_MOUNTD_DEST}"),
+        "-w".to_string(),
+        passphrase.to_string(),
+        "-T".to_string(),
+        "/System/Library/CoreServices/APFSUserAgent".to_string(),
+        "-T".to_string(),
+        "/System/Library/CoreServices/CSUserAgent".to_string(),
+        "-T".to_string(),
+        "/usr/bin/security".to_string(),
+    ];
+    if determinate_nix {
+        args.extend([
+            "-T".to_string(),
+            "/usr/local/bin/determinate-nixd".to_string(),
+        ]);
+    }
+    args.push(SYSTEM_KEYCHAIN.to_string());
+    args
+}
+
 /**
 Encrypt an APFS volume
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(tag = "action_name", rename = "encrypt_apfs_volume")]
 pub struct EncryptApfsVolume {
-    determinate_nix: bool,
-    disk: PathBuf,
+    pub(crate) determinate_nix: bool,
+    pub(crate) disk: PathBuf,
     name: String,
+    /// When `nix-installer repair rotate-volume-key` last rotated the passphrase, in seconds since
+    /// the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    passphrase_rotated: Option<u64>,
 }
 
 impl EncryptApfsVolume {
@@ -66,6 +131,7 @@ impl EncryptApfsVolume {
                     determinate_nix,
                     name,
                     disk,
+                    passphrase_rotated: None,
                 }));
             }
 
@@ -112,6 +178,7 @@ impl EncryptApfsVolume {
                         determinate_nix,
                         disk,
                         name,
+                        passphrase_rotated: None,
                     }));
                 }
             }
@@ -121,6 +188,7 @@ impl EncryptApfsVolume {
             determinate_nix,
             name,
             disk,
+            passphrase_rotated: None,
         }))
     }
 }
@@ -154,24 +222,36 @@ impl Action for EncryptApfsVolume {
     #[tracing::instrument(level = "debug", skip_all, fields(
         disk = %self.disk.display(),
     ))]
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let name = self.name.as_str();
+        let security_args = add_passphrase_args(
+            self.determinate_nix,
+            name,
+            &self.disk,
+            KEYCHAIN_SERVICE,
+            "<generated password>",
+        );
+
+        vec![
+            PrivilegedOperation::command("/usr/sbin/diskutil", ["mount", name]),
+            PrivilegedOperation::command("/usr/bin/security", security_args),
+            PrivilegedOperation::command(
+                "/usr/sbin/diskutil",
+                [
+                    "apfs",
+                    "encryptVolume",
+                    name,
+                    "-user",
+                    "disk",
+                    "-stdinpassphrase",
+                ],
+            ),
+            PrivilegedOperation::command("/usr/sbin/diskutil", ["unmount", "force", name]),
+        ]
+    }
+
     async fn execute(&mut self) -> Result<(), ActionError> {
-        // Generate a random password.
-        let password: String = {
-            const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                                abcdefghijklmnopqrstuvwxyz\
-                                    0123456789)(*&^%$#@!~";
-            const PASSWORD_LEN: usize = 32;
-            let mut rng = rand::thread_rng();
-
-            (0..PASSWORD_LEN)
-                .map(|_| {
-                    let idx = rng.gen_range(0..CHARSET.len());
-                    CHARSET[idx] as char
-                })
-                .collect()
-        };
-
-        let disk_str = &self.disk.to_str().expect("Could not turn disk into string"); /* Should not reasonably ever fail */
+        let password = generate_passphrase();
 
         let mut retry_tokens: usize = 60;
         loop {
@@ -181,8 +261,7 @@ impl Action for EncryptApfsVolume {
             command.stdin(std::process::Stdio::null());
             tracing::debug!(%retry_tokens, command = ?command.as_std(), "Waiting for volume mounting to succeed");
 
-            let output = command
-                .output()
+            let output = crate::command_output(&mut command)
                 .await
                 .map_err(|e| ActionErrorKind::command(&command, e))
                 .map_err(Self::error)?;
@@ -201,38 +280,17 @@ impl Action for EncryptApfsVolume {
         }
 
         // Add the password to the user keychain so they can unlock it later.
-        let mut cmd = Command::new("/usr/bin/security");
-        cmd.process_group(0).args([
-            "add-generic-password",
-            "-a",
-            self.name.as_str(),
-            "-s",
-            "Nix Store",
-            "-l",
-            format!("{} encryption password", disk_str).as_str(),
-            "-D",
-            "Encrypted volume password",
-            "-j",
-            format!("Added automatically by the Nix installer for use by {NIX_VOLUME_MOUNTD_DEST}")
-                .as_str(),
-            "-w",
-            password.as_str(),
-            "-T",
-            "/System/Library/CoreServices/APFSUserAgent",
-            "-T",
-            "/System/Library/CoreServices/CSUserAgent",
-            "-T",
-            "/usr/bin/security",
-        ]);
-
-        if self.determinate_nix {
-            cmd.args(["-T", "/usr/local/bin/determinate-nixd"]);
-        }
-
-        cmd.arg("/Library/Keychains/System.keychain");
-
-        // Add the password to the user keychain so they can unlock it later.
-        execute_command(&mut cmd).await.map_err(Self::error)?;
+        execute_command(Command::new("/usr/bin/security").process_group(0).args(
+            add_passphrase_args(
+                self.determinate_nix,
+                &self.name,
+                &self.disk,
+                KEYCHAIN_SERVICE,
+                &password,
+            ),
+        ))
+        .await
+        .map_err(Self::error)?;
 
         // Encrypt the mounted volume
         {
@@ -315,7 +373,7 @@ impl Action for EncryptApfsVolume {
         disk = %self.disk.display(),
     ))]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        let disk_str = self.disk.to_str().expect("Could not turn disk into string"); /* Should not reasonably ever fail */
+        let disk_str = self.disk.display();
 
         // TODO: This seems very rough and unsafe
         execute_command(
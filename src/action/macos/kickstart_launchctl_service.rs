@@ -5,7 +5,7 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 
-use crate::action::{Action, ActionDescription};
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
 use crate::execute_command;
 
 /**
@@ -92,6 +92,17 @@ impl Action for KickstartLaunchctlService {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::command(
+            "launchctl",
+            [
+                "kickstart".to_string(),
+                "-k".to_string(),
+                format!("{}/{}", self.domain, self.service),
+            ],
+        )]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         super::retry_kickstart(&self.domain, &self.service)
@@ -118,8 +129,7 @@ impl Action for KickstartLaunchctlService {
         command.stdin(std::process::Stdio::null());
         let command_str = format!("{:?}", command.as_std());
 
-        let output = command
-            .output()
+        let output = crate::command_output(&mut command)
             .await
             .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
 
@@ -14,16 +14,19 @@ pub(crate) mod create_volume_service;
 pub(crate) mod enable_ownership;
 pub(crate) mod encrypt_apfs_volume;
 pub(crate) mod kickstart_launchctl_service;
+pub(crate) mod repair_volume_mount;
+pub(crate) mod rotate_volume_key;
 pub(crate) mod set_tmutil_exclusion;
 pub(crate) mod set_tmutil_exclusions;
 pub(crate) mod unmount_apfs_volume;
 
+use std::collections::HashMap;
 use std::path::Path;
 use std::time::Duration;
 
 pub use bootstrap_launchctl_service::BootstrapLaunchctlService;
 pub use configure_remote_building::ConfigureRemoteBuilding;
-pub use create_apfs_volume::CreateApfsVolume;
+pub use create_apfs_volume::{CreateApfsVolume, VolumeLimits, VolumeSize};
 pub use create_determinate_nix_volume::CreateDeterminateNixVolume;
 pub use create_determinate_volume_service::CreateDeterminateVolumeService;
 pub use create_nix_hook_service::CreateNixHookService;
@@ -33,6 +36,8 @@ pub use create_volume_service::CreateVolumeService;
 pub use enable_ownership::{EnableOwnership, EnableOwnershipError};
 pub use encrypt_apfs_volume::EncryptApfsVolume;
 pub use kickstart_launchctl_service::KickstartLaunchctlService;
+pub use repair_volume_mount::RepairVolumeMount;
+pub use rotate_volume_key::{RotateVolumeKey, RotateVolumeKeyError};
 use serde::Deserialize;
 pub use set_tmutil_exclusion::SetTmutilExclusion;
 pub use set_tmutil_exclusions::SetTmutilExclusions;
@@ -60,8 +65,7 @@ pub(crate) async fn get_disk_info_for_label(
     let command_str = format!("{:?}", command.as_std());
 
     tracing::trace!(command = command_str, "Executing");
-    let output = command
-        .output()
+    let output = crate::command_output(&mut command)
         .await
         .map_err(|e| ActionErrorKind::command(&command, e))?;
 
@@ -89,7 +93,7 @@ pub(crate) async fn get_disk_info_for_label(
 #[serde(rename_all = "PascalCase")]
 pub(crate) struct DiskUtilApfsInfoOutput {
     #[serde(rename = "VolumeUUID")]
-    volume_uuid: Uuid,
+    pub(crate) volume_uuid: Uuid,
     pub(crate) file_vault: bool,
 }
 
@@ -100,6 +104,10 @@ struct DiskUtilApfsInfoError {
     error_message: String,
 }
 
+/// If `service` is disabled in `domain`, according to `launchctl print-disabled`
+///
+/// If the output cannot be parsed at all the service is reported disabled, since enabling it again
+/// is harmless but leaving it disabled means the daemon never starts.
 #[tracing::instrument]
 pub(crate) async fn service_is_disabled(
     domain: &str,
@@ -116,11 +124,77 @@ pub(crate) async fn service_is_disabled(
     )
     .await?;
     let utf8_output = String::from_utf8_lossy(&output.stdout);
-    let is_disabled = utf8_output.contains(&format!("\"{service}\" => disabled"));
+    let is_disabled = match parse_print_disabled(&utf8_output) {
+        Some(services) => services.get(service).copied().unwrap_or(false),
+        None => {
+            tracing::warn!(
+                output = %utf8_output,
+                "Could not parse `launchctl print-disabled {domain}`, enabling `{service}` in case it is disabled"
+            );
+            true
+        },
+    };
     tracing::trace!(is_disabled, "Service disabled status");
     Ok(is_disabled)
 }
 
+/// Parse the output of `launchctl print-disabled` into whether each service is disabled
+///
+/// Older macOS prints `"service" => true` for a disabled service, newer prints `"service" =>
+/// disabled`, and the quoting and spacing around them varies between releases. Returns `None` if
+/// nothing in `output` looks like a list of disabled services.
+fn parse_print_disabled(output: &str) -> Option<HashMap<String, bool>> {
+    let mut services = HashMap::new();
+    let mut found_section = false;
+    let mut section: Option<String> = None;
+    for line in output.lines() {
+        let line = line.trim();
+        if let Some(header) = line.strip_suffix('{') {
+            let header = header.trim().trim_end_matches('=').trim().to_lowercase();
+            found_section |= header.starts_with("disabled");
+            section = Some(header);
+            continue;
+        }
+        if line.starts_with('}') {
+            section = None;
+            continue;
+        }
+        // Other sections, such as `login item associations`, use the same `=>` syntax
+        if section
+            .as_deref()
+            .is_some_and(|section| !section.starts_with("disabled"))
+        {
+            continue;
+        }
+        let Some((name, state)) = line.split_once("=>") else {
+            continue;
+        };
+        let name = unquote(name);
+        let is_disabled = match unquote(state.trim_end_matches([',', ';'])) {
+            state if state.eq_ignore_ascii_case("disabled") || state == "true" => true,
+            state if state.eq_ignore_ascii_case("enabled") || state == "false" => false,
+            _ => continue,
+        };
+        if !name.is_empty() {
+            services.insert(name.to_string(), is_disabled);
+        }
+    }
+    (found_section || !services.is_empty()).then_some(services)
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .or_else(|| {
+            value
+                .strip_prefix('\'')
+                .and_then(|value| value.strip_suffix('\''))
+        })
+        .unwrap_or(value)
+}
+
 /// Waits for the Nix Store mountpoint to exist, up to `retry_tokens * 100ms` amount of time.
 #[tracing::instrument]
 pub(crate) async fn wait_for_nix_store_dir() -> Result<(), ActionErrorKind> {
@@ -132,8 +206,7 @@ pub(crate) async fn wait_for_nix_store_dir() -> Result<(), ActionErrorKind> {
         command.stderr(std::process::Stdio::null());
         command.stdout(std::process::Stdio::null());
         tracing::debug!(%retry_tokens, command = ?command.as_std(), "Checking for Nix Store mount path existence");
-        let output = command
-            .output()
+        let output = crate::command_output(&mut command)
             .await
             .map_err(|e| ActionErrorKind::command(&command, e))?;
         if output.status.success() {
@@ -186,8 +259,7 @@ pub(crate) async fn retry_bootstrap(
         command.stdout(std::process::Stdio::null());
         tracing::debug!(%retry_tokens, command = ?command.as_std(), "Waiting for bootstrap to succeed");
 
-        let output = command
-            .output()
+        let output = crate::command_output(&mut command)
             .await
             .map_err(|e| ActionErrorKind::command(&command, e))?;
 
@@ -239,8 +311,7 @@ pub(crate) async fn retry_bootout(domain: &str, service_name: &str) -> Result<()
         command.stdout(std::process::Stdio::null());
         tracing::debug!(%retry_tokens, command = ?command.as_std(), "Waiting for bootout to succeed");
 
-        let output = command
-            .output()
+        let output = crate::command_output(&mut command)
             .await
             .map_err(|e| ActionErrorKind::command(&command, e))?;
 
@@ -279,8 +350,7 @@ pub(crate) async fn retry_kickstart(
         command.stdout(std::process::Stdio::null());
         tracing::debug!(%retry_tokens, command = ?command.as_std(), "Waiting for kickstart to succeed");
 
-        let output = command
-            .output()
+        let output = crate::command_output(&mut command)
             .await
             .map_err(|e| ActionErrorKind::command(&command, e))?;
 
@@ -297,3 +367,55 @@ pub(crate) async fn retry_kickstart(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::parse_print_disabled;
+
+    #[test]
+    fn parses_print_disabled_from_each_macos() {
+        for (release, output) in [
+            ("12", include_str!("./print-disabled.sample.macos12.txt")),
+            ("13", include_str!("./print-disabled.sample.macos13.txt")),
+            ("14", include_str!("./print-disabled.sample.macos14.txt")),
+            ("15", include_str!("./print-disabled.sample.macos15.txt")),
+        ] {
+            let services = parse_print_disabled(output)
+                .unwrap_or_else(|| panic!("macOS {release} output did not parse"));
+            assert_eq!(
+                services.get("org.nixos.nix-daemon"),
+                Some(&true),
+                "macOS {release}"
+            );
+            assert_eq!(
+                services.get("systems.determinate.nix-store"),
+                Some(&false),
+                "macOS {release}"
+            );
+            assert_eq!(
+                services.get("com.apple.ftp-proxy"),
+                Some(&true),
+                "macOS {release}"
+            );
+            // `login item associations` are not services
+            assert!(
+                !services.contains_key("com.example.helper"),
+                "macOS {release}"
+            );
+        }
+    }
+
+    #[test]
+    fn unparseable_print_disabled_is_none() {
+        assert_eq!(parse_print_disabled(""), None);
+        assert_eq!(
+            parse_print_disabled("Unrecognized subcommand: print-disabled"),
+            None
+        );
+        // No disabled services at all is still understood
+        assert_eq!(
+            parse_print_disabled("disabled services = {\n}\n"),
+            Some(Default::default())
+        );
+    }
+}
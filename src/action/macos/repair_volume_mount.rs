@@ -0,0 +1,469 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{span, Span};
+use uuid::Uuid;
+
+use super::create_fstab_entry::{fstab_volume_uuid, CreateFstabEntry};
+use super::create_volume_service::LaunchctlMountPlist;
+use super::{
+    get_disk_info_for_label, retry_bootout, retry_bootstrap, retry_kickstart,
+    wait_for_nix_store_dir, DARWIN_LAUNCHD_DOMAIN,
+};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::util::host_path;
+
+const FSTAB_PATH: &str = "/etc/fstab";
+
+/// The Nix volume an install created, and the services which mount and use it
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct NixVolume {
+    pub(crate) label: String,
+    pub(crate) mount_service_label: String,
+    pub(crate) mount_service_path: PathBuf,
+    pub(crate) daemon_service_label: String,
+}
+
+/// The Nix volume a `receipt` created, if any
+pub(crate) fn nix_volume_of(receipt: &serde_json::Value) -> Option<NixVolume> {
+    receipt
+        .get("actions")?
+        .as_array()?
+        .iter()
+        .filter_map(|action| action.get("action"))
+        .find_map(|action| {
+            let (mount_service_label, mount_service_path, daemon_service_label) =
+                match action.get("action_name")?.as_str()? {
+                    "create_nix_volume" => (
+                        super::create_nix_volume::NIX_VOLUME_MOUNTD_NAME,
+                        super::create_nix_volume::NIX_VOLUME_MOUNTD_DEST,
+                        "org.nixos.nix-daemon",
+                    ),
+                    "create_determinate_nix_volume" => (
+                        super::create_determinate_nix_volume::VOLUME_MOUNT_SERVICE_NAME,
+                        super::create_determinate_nix_volume::VOLUME_MOUNT_SERVICE_DEST,
+                        "systems.determinate.nix-daemon",
+                    ),
+                    _ => return None,
+                };
+            Some(NixVolume {
+                label: action.get("name")?.as_str()?.to_string(),
+                mount_service_label: mount_service_label.to_string(),
+                mount_service_path: mount_service_path.into(),
+                daemon_service_label: daemon_service_label.to_string(),
+            })
+        })
+}
+
+/// The UUIDs the Nix volume is mounted by which are not its own, such as after restoring a Mac from a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaleVolumeUuids {
+    /// The UUID the volume has now
+    pub(crate) current: Uuid,
+    /// The UUID in `/etc/fstab`, if it is stale
+    pub(crate) fstab: Option<Uuid>,
+    /// The UUID in the mount service, if it is stale
+    pub(crate) mount_service: Option<Uuid>,
+}
+
+impl StaleVolumeUuids {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fstab.is_none() && self.mount_service.is_none()
+    }
+}
+
+impl std::fmt::Display for StaleVolumeUuids {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stale = self
+            .fstab
+            .iter()
+            .chain(self.mount_service.iter())
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        write!(f, "{} instead of {}", stale.join(" and "), self.current)
+    }
+}
+
+/// Compare the UUIDs `/etc/fstab` and the mount service mount `volume` by with the one it has
+///
+/// A mount service which mounts by label, as for an encrypted volume or Determinate Nix, has no
+/// UUID to be stale.
+pub(crate) async fn stale_volume_uuids(
+    volume: &NixVolume,
+) -> Result<StaleVolumeUuids, ActionErrorKind> {
+    let current = get_disk_info_for_label(&volume.label)
+        .await?
+        .ok_or_else(|| RepairVolumeMountError::NoVolume(volume.label.clone()))?
+        .volume_uuid;
+
+    let fstab = match tokio::fs::read_to_string(host_path(FSTAB_PATH)).await {
+        Ok(fstab) => fstab_volume_uuid(&fstab),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(ActionErrorKind::Read(FSTAB_PATH.into(), e)),
+    };
+    let mount_service = mount_service_plist(&volume.mount_service_path)
+        .await?
+        .and_then(|plist| plist.volume_uuid());
+
+    Ok(StaleVolumeUuids {
+        current,
+        fstab: fstab.filter(|uuid| *uuid != current),
+        mount_service: mount_service.filter(|uuid| *uuid != current),
+    })
+}
+
+/// The mount service at `path`, or `None` if there is none or it is not one written for a Nix volume
+async fn mount_service_plist(path: &Path) -> Result<Option<LaunchctlMountPlist>, ActionErrorKind> {
+    match tokio::fs::read(host_path(path)).await {
+        Ok(buf) => Ok(plist::from_bytes(&buf).ok()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ActionErrorKind::Read(path.to_path_buf(), e)),
+    }
+}
+
+/**
+Mount the Nix volume by the UUID it has now, after it changed (such as when a Mac is restored
+from a Time Machine backup, or migrated with Migration Assistant)
+
+The `/etc/fstab` entry and the mount service are rewritten with the new UUID, the mount service is
+loaded again, and once `/nix` is mounted the Nix daemon is restarted.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "repair_volume_mount")]
+pub struct RepairVolumeMount {
+    volume: NixVolume,
+    pub(crate) volume_uuid: Uuid,
+    update_fstab_entry: Option<StatefulAction<CreateFstabEntry>>,
+    /// The mount service with the new UUID, if it mounts by a stale one
+    mount_service: Option<LaunchctlMountPlist>,
+}
+
+impl RepairVolumeMount {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(volume: NixVolume) -> Result<StatefulAction<Self>, ActionError> {
+        let stale = stale_volume_uuids(&volume).await.map_err(Self::error)?;
+        if stale.is_empty() {
+            tracing::debug!("The Nix volume is already mounted by its UUID");
+            return Ok(StatefulAction::completed(Self {
+                volume,
+                volume_uuid: stale.current,
+                update_fstab_entry: None,
+                mount_service: None,
+            }));
+        }
+
+        let update_fstab_entry = match stale.fstab {
+            Some(_) => Some(
+                CreateFstabEntry::plan(volume.label.clone())
+                    .await
+                    .map_err(Self::error)?,
+            ),
+            None => None,
+        };
+        let mount_service = match stale.mount_service {
+            Some(_) => mount_service_plist(&volume.mount_service_path)
+                .await
+                .map_err(Self::error)?
+                .map(|plist| plist.with_volume_uuid(stale.current)),
+            None => None,
+        };
+
+        Ok(Self {
+            volume,
+            volume_uuid: stale.current,
+            update_fstab_entry,
+            mount_service,
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "repair_volume_mount")]
+impl Action for RepairVolumeMount {
+    fn action_tag() -> ActionTag {
+        ActionTag("repair_volume_mount")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Mount the APFS volume `{}` by its current UUID `{}`",
+            self.volume.label, self.volume_uuid
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "repair_volume_mount",
+            label = self.volume.label,
+            volume_uuid = %self.volume_uuid,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![];
+        if self.update_fstab_entry.is_some() {
+            explanation.push(format!("Update the `/nix` entry in `{FSTAB_PATH}`"));
+        }
+        if self.mount_service.is_some() {
+            explanation.push(format!(
+                "Update `{}`",
+                self.volume.mount_service_path.display()
+            ));
+        }
+        explanation.extend([
+            format!(
+                "Load `{}` again and wait for `/nix` to be mounted",
+                self.volume.mount_service_label
+            ),
+            format!("Restart `{}`", self.volume.daemon_service_label),
+        ]);
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let mut operations = self
+            .update_fstab_entry
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+            .collect::<Vec<_>>();
+        if self.mount_service.is_some() {
+            operations.push(PrivilegedOperation::write(
+                &self.volume.mount_service_path,
+                None,
+            ));
+        }
+        operations.extend([
+            PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "bootout".to_string(),
+                    format!(
+                        "{DARWIN_LAUNCHD_DOMAIN}/{}",
+                        self.volume.mount_service_label
+                    ),
+                ],
+            ),
+            PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "bootstrap".to_string(),
+                    DARWIN_LAUNCHD_DOMAIN.to_string(),
+                    self.volume.mount_service_path.display().to_string(),
+                ],
+            ),
+            PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "kickstart".to_string(),
+                    "-k".to_string(),
+                    format!(
+                        "{DARWIN_LAUNCHD_DOMAIN}/{}",
+                        self.volume.daemon_service_label
+                    ),
+                ],
+            ),
+        ]);
+        operations
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        if let Some(update_fstab_entry) = &mut self.update_fstab_entry {
+            update_fstab_entry
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+
+        let path = &self.volume.mount_service_path;
+        if let Some(mount_service) = &self.mount_service {
+            let mut buf = Vec::new();
+            plist::to_writer_xml(&mut buf, mount_service).map_err(Self::error)?;
+            tokio::fs::write(host_path(path), buf)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Write(path.clone(), e)))?;
+        }
+
+        // `launchd` only reads the plist when it is loaded
+        retry_bootout(DARWIN_LAUNCHD_DOMAIN, &self.volume.mount_service_label)
+            .await
+            .map_err(Self::error)?;
+        retry_bootstrap(
+            DARWIN_LAUNCHD_DOMAIN,
+            &self.volume.mount_service_label,
+            path,
+        )
+        .await
+        .map_err(Self::error)?;
+        wait_for_nix_store_dir().await.map_err(Self::error)?;
+
+        // The daemon started while `/nix` was missing, and gave up
+        retry_kickstart(DARWIN_LAUNCHD_DOMAIN, &self.volume.daemon_service_label)
+            .await
+            .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // The stale UUIDs mounted nothing, there is nothing worth restoring
+        Ok(())
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum RepairVolumeMountError {
+    #[error("No APFS volume labelled `{0}` was found, it cannot be mounted by its UUID")]
+    NoVolume(String),
+}
+
+impl From<RepairVolumeMountError> for ActionErrorKind {
+    fn from(val: RepairVolumeMountError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        fstab_volume_uuid, nix_volume_of, stale_volume_uuids, NixVolume, RepairVolumeMount,
+    };
+    use crate::action::macos::create_nix_volume::NIX_VOLUME_MOUNTD_DEST;
+    use crate::action::macos::create_volume_service::{generate_mount_plist, LaunchctlMountPlist};
+    use crate::action::ActionState;
+    use crate::test_harness::{FakeCommand, Invocation, SandboxContext};
+
+    const ORIGINAL_UUID: &str = "3A1B5C7D-9E2F-4A6B-8C0D-1E3F5A7B9C2D";
+    const RESTORED_UUID: &str = "C4D6E8F0-2A4B-4C6D-8E0F-A2B4C6D8E0F1";
+    const FSTAB: &str =
+        "# Static information about the filesystems.\nLABEL=Backups /Volumes/Backups apfs rw\n";
+
+    /// `diskutil info -plist` for the Nix volume, trimmed to what is read
+    fn diskutil_info(uuid: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>FileVault</key>
+	<false/>
+	<key>VolumeName</key>
+	<string>Nix Store</string>
+	<key>VolumeUUID</key>
+	<string>{uuid}</string>
+</dict>
+</plist>
+"#
+        )
+    }
+
+    fn volume() -> NixVolume {
+        let receipt = serde_json::json!({
+            "actions": [
+                { "action": { "action_name": "create_directory", "path": "/nix" }, "state": "Completed" },
+                { "action": { "action_name": "create_nix_volume", "name": "Nix Store" }, "state": "Completed" },
+            ],
+        });
+        nix_volume_of(&receipt).expect("a Nix volume")
+    }
+
+    async fn write_installed(sandbox: &SandboxContext, encrypt: bool) -> eyre::Result<()> {
+        std::fs::create_dir_all(sandbox.path("/etc"))?;
+        std::fs::write(
+            sandbox.path("/etc/fstab"),
+            format!("{FSTAB}UUID={ORIGINAL_UUID} /nix apfs rw,noatime,noauto,nobrowse,nosuid,owners # Added by the Determinate Nix Installer\n"),
+        )?;
+        let plist = generate_mount_plist(
+            "org.nixos.darwin-store",
+            "Nix Store",
+            ORIGINAL_UUID.parse()?,
+            "/nix".as_ref(),
+            encrypt,
+        )
+        .await?;
+        let path = sandbox.path(NIX_VOLUME_MOUNTD_DEST);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        plist::to_file_xml(path, &plist)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detects_uuids_left_from_before_a_restore() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        write_installed(&sandbox, false).await?;
+
+        sandbox.fake(
+            "diskutil",
+            FakeCommand::success().stdout(diskutil_info(ORIGINAL_UUID)),
+        );
+        let stale = sandbox.scope(stale_volume_uuids(&volume())).await?;
+        assert!(stale.is_empty(), "{stale:?}");
+
+        sandbox.fake(
+            "diskutil",
+            FakeCommand::success().stdout(diskutil_info(RESTORED_UUID)),
+        );
+        let stale = sandbox.scope(stale_volume_uuids(&volume())).await?;
+        assert_eq!(stale.current, RESTORED_UUID.parse()?);
+        assert_eq!(stale.fstab, Some(ORIGINAL_UUID.parse()?));
+        assert_eq!(stale.mount_service, Some(ORIGINAL_UUID.parse()?));
+
+        // An encrypted volume is mounted by its label, only `/etc/fstab` has a UUID
+        write_installed(&sandbox, true).await?;
+        let stale = sandbox.scope(stale_volume_uuids(&volume())).await?;
+        assert_eq!(stale.fstab, Some(ORIGINAL_UUID.parse()?));
+        assert_eq!(stale.mount_service, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remounts_by_the_current_uuid() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        write_installed(&sandbox, false).await?;
+        sandbox.fake(
+            "diskutil",
+            FakeCommand::success().stdout(diskutil_info(RESTORED_UUID)),
+        );
+        sandbox.fake("launchctl", FakeCommand::success());
+
+        let action = sandbox.scope(RepairVolumeMount::plan(volume())).await?;
+        assert_eq!(action.state, ActionState::Uncompleted);
+        let mut actions = [action.boxed()];
+        sandbox.execute(&mut actions).await?;
+
+        let fstab = std::fs::read_to_string(sandbox.path("/etc/fstab"))?;
+        assert!(fstab.starts_with(FSTAB), "{fstab}");
+        assert_eq!(fstab_volume_uuid(&fstab), Some(RESTORED_UUID.parse()?));
+        let plist: LaunchctlMountPlist = plist::from_file(sandbox.path(NIX_VOLUME_MOUNTD_DEST))?;
+        let expected = generate_mount_plist(
+            "org.nixos.darwin-store",
+            "Nix Store",
+            RESTORED_UUID.parse()?,
+            "/nix".as_ref(),
+            false,
+        )
+        .await?;
+        assert_eq!(plist, expected);
+        // The daemon is restarted once `/nix` is mounted
+        assert_eq!(
+            sandbox.invocations_of("launchctl").last(),
+            Some(&Invocation::new(
+                "launchctl",
+                ["kickstart", "-k", "system/org.nixos.nix-daemon"]
+            ))
+        );
+
+        let action = sandbox.scope(RepairVolumeMount::plan(volume())).await?;
+        assert_eq!(action.state, ActionState::Completed);
+        Ok(())
+    }
+}
@@ -0,0 +1,690 @@
+use std::path::PathBuf;
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use super::encrypt_apfs_volume::{
+    add_passphrase_args, generate_passphrase, KEYCHAIN_SERVICE, SYSTEM_KEYCHAIN,
+};
+use super::repair_volume_mount::NixVolume;
+use super::{
+    retry_bootout, retry_bootstrap, retry_kickstart, wait_for_nix_store_dir, DARWIN_LAUNCHD_DOMAIN,
+};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
+};
+use crate::{command_output, execute_command, execute_command_with_stdin};
+
+/// The keychain service the new passphrase is kept under until the rotation is done, so it is not
+/// lost if the volume takes it but the `Nix Store` item cannot be updated
+pub(crate) const STAGING_KEYCHAIN_SERVICE: &str = "Nix Store (rotating)";
+
+/// How far a rotation got, and so what undoing it takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rotated {
+    /// The new passphrase is in the staging keychain item
+    Staged,
+    /// The volume takes the new passphrase
+    VolumeChanged,
+    /// The `Nix Store` keychain item holds the new passphrase
+    KeychainUpdated,
+}
+
+/**
+Rotate the passphrase of the encrypted Nix volume
+
+The new passphrase is staged in the System keychain, the volume is changed to it and checked to
+unlock with it, the `Nix Store` keychain item is updated, and the volume is unmounted and mounted by
+its mount service, the way it is at boot. A failure at any step puts the old passphrase back on the
+volume and in the keychain.
+
+If the `Nix Store` item is missing but an earlier rotation left its staged passphrase behind, the
+`Nix Store` item is first recreated from the staged one, once the volume is checked to unlock with it.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "action_name", rename = "rotate_volume_key")]
+pub struct RotateVolumeKey {
+    volume: NixVolume,
+    /// The disk the volume is on, which the keychain item is labelled with
+    disk: PathBuf,
+    /// If `determinate-nixd` reads the passphrase, and so is allowed to
+    determinate_nix: bool,
+    /// If the `Nix Store` keychain item is missing, and is recreated from the staged passphrase
+    #[serde(default)]
+    recreate_keychain_item: bool,
+}
+
+impl RotateVolumeKey {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        volume: NixVolume,
+        disk: PathBuf,
+        determinate_nix: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let mut this = Self {
+            volume,
+            disk,
+            determinate_nix,
+            recreate_keychain_item: false,
+        };
+
+        if !this.has_passphrase(KEYCHAIN_SERVICE).await? {
+            if !this.has_passphrase(STAGING_KEYCHAIN_SERVICE).await? {
+                return Err(Self::error(RotateVolumeKeyError::NoPassphrase(
+                    this.volume.label,
+                )));
+            }
+            tracing::warn!(
+                "The `{KEYCHAIN_SERVICE}` passphrase for the Nix volume `{}` is missing from the System keychain, it will be recreated from `{STAGING_KEYCHAIN_SERVICE}`",
+                this.volume.label
+            );
+            this.recreate_keychain_item = true;
+        }
+
+        Ok(this.into())
+    }
+
+    /// If the System keychain has a passphrase for the volume under `service`
+    async fn has_passphrase(&self, service: &str) -> Result<bool, ActionError> {
+        let mut command = Command::new("/usr/bin/security");
+        command
+            .process_group(0)
+            .args(["find-generic-password", "-a", &self.volume.label])
+            .args(["-s", service, SYSTEM_KEYCHAIN])
+            .stdin(std::process::Stdio::null());
+        let output = command_output(&mut command)
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
+        Ok(output.status.success())
+    }
+
+    /// Recreate the `Nix Store` item from the staged passphrase, if the volume unlocks with it,
+    /// leaving the staged item in place until the `Nix Store` item holds it
+    async fn recreate_keychain_item(&self) -> Result<(), ActionErrorKind> {
+        let staged = self.find_passphrase(STAGING_KEYCHAIN_SERVICE).await?;
+        self.verify_passphrase(&staged).await.map_err(|e| {
+            RotateVolumeKeyError::StagedPassphraseRejected(self.volume.label.clone(), Box::new(e))
+        })?;
+        self.store_passphrase(KEYCHAIN_SERVICE, &staged, false)
+            .await?;
+        self.delete_passphrase(STAGING_KEYCHAIN_SERVICE).await
+    }
+
+    /// The passphrase stored under `service`
+    async fn find_passphrase(&self, service: &str) -> Result<String, ActionErrorKind> {
+        let output = execute_command(
+            Command::new("/usr/bin/security")
+                .process_group(0)
+                .args(["find-generic-password", "-a", &self.volume.label])
+                .args(["-s", service, "-w", SYSTEM_KEYCHAIN])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(|_| RotateVolumeKeyError::NoPassphrase(self.volume.label.clone()))?;
+        let passphrase = String::from_utf8_lossy(&output.stdout);
+        Ok(passphrase.trim_end_matches('\n').to_string())
+    }
+
+    /// Store `passphrase` under `service`, replacing the item there if `update`
+    async fn store_passphrase(
+        &self,
+        service: &str,
+        passphrase: &str,
+        update: bool,
+    ) -> Result<(), ActionErrorKind> {
+        let mut command = Command::new("/usr/bin/security");
+        command.process_group(0).stdin(std::process::Stdio::null());
+        let mut args = add_passphrase_args(
+            self.determinate_nix,
+            &self.volume.label,
+            &self.disk,
+            service,
+            passphrase,
+        );
+        if update {
+            args.insert(1, "-U".to_string());
+        }
+        command.args(args);
+        execute_command(&mut command).await?;
+        Ok(())
+    }
+
+    async fn delete_passphrase(&self, service: &str) -> Result<(), ActionErrorKind> {
+        execute_command(
+            Command::new("/usr/bin/security")
+                .process_group(0)
+                .args(["delete-generic-password", "-a", &self.volume.label])
+                .args(["-s", service, SYSTEM_KEYCHAIN])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn change_passphrase(&self, old: &str, new: &str) -> Result<(), ActionErrorKind> {
+        execute_command_with_stdin(
+            Command::new("/usr/sbin/diskutil")
+                .process_group(0)
+                .args(["apfs", "changePassphrase", &self.volume.label])
+                .args([
+                    "-user",
+                    "disk",
+                    "-oldStdinpassphrase",
+                    "-newStdinpassphrase",
+                ]),
+            format!("{old}\n{new}\n").as_bytes(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Check the volume unlocks with `passphrase`, without unlocking it
+    async fn verify_passphrase(&self, passphrase: &str) -> Result<(), ActionErrorKind> {
+        execute_command_with_stdin(
+            Command::new("/usr/sbin/diskutil")
+                .process_group(0)
+                .args(["apfs", "unlockVolume", &self.volume.label])
+                .args(["-verify", "-stdinpassphrase"]),
+            format!("{passphrase}\n").as_bytes(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Unmount the volume and have its mount service unlock and mount it with the passphrase in
+    /// the keychain, as at boot, then restart the Nix daemon
+    async fn remount(&self) -> Result<(), ActionErrorKind> {
+        execute_command(
+            Command::new("/usr/sbin/diskutil")
+                .process_group(0)
+                .args(["unmount", "force", &self.volume.label])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await?;
+        retry_bootout(DARWIN_LAUNCHD_DOMAIN, &self.volume.mount_service_label).await?;
+        retry_bootstrap(
+            DARWIN_LAUNCHD_DOMAIN,
+            &self.volume.mount_service_label,
+            &self.volume.mount_service_path,
+        )
+        .await?;
+        wait_for_nix_store_dir().await?;
+        retry_kickstart(DARWIN_LAUNCHD_DOMAIN, &self.volume.daemon_service_label).await?;
+        Ok(())
+    }
+
+    async fn rotate(
+        &self,
+        old: &str,
+        new: &str,
+        done: &mut Rotated,
+    ) -> Result<(), ActionErrorKind> {
+        self.change_passphrase(old, new).await?;
+        *done = Rotated::VolumeChanged;
+        self.verify_passphrase(new).await?;
+        self.store_passphrase(KEYCHAIN_SERVICE, new, true).await?;
+        *done = Rotated::KeychainUpdated;
+        self.remount().await
+    }
+
+    /// Put the `old` passphrase back wherever the rotation got to replacing it
+    async fn roll_back(&self, done: Rotated, old: &str, new: &str) -> Vec<ActionErrorKind> {
+        let mut errors = vec![];
+        if done >= Rotated::VolumeChanged {
+            if let Err(err) = self.change_passphrase(new, old).await {
+                errors.push(err);
+            }
+        }
+        if done >= Rotated::KeychainUpdated {
+            if let Err(err) = self.store_passphrase(KEYCHAIN_SERVICE, old, true).await {
+                errors.push(err);
+            }
+            // The volume may have been left unmounted
+            if let Err(err) = self.remount().await {
+                errors.push(err);
+            }
+        }
+        // Kept while the volume may still need the new passphrase
+        if errors.is_empty() {
+            if let Err(err) = self.delete_passphrase(STAGING_KEYCHAIN_SERVICE).await {
+                errors.push(err);
+            }
+        }
+        errors
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "rotate_volume_key")]
+impl Action for RotateVolumeKey {
+    fn action_tag() -> ActionTag {
+        ActionTag("rotate_volume_key")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Rotate the passphrase of the encrypted volume `{}`",
+            self.volume.label
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "rotate_volume_key",
+            volume = self.volume.label,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let label = &self.volume.label;
+        let mut explanation = vec![];
+        if self.recreate_keychain_item {
+            explanation.push(format!(
+                "Recreate the missing `{KEYCHAIN_SERVICE}` passphrase in the System keychain from `{STAGING_KEYCHAIN_SERVICE}`, once `{label}` is checked to unlock with it"
+            ));
+        }
+        explanation.extend([
+                format!("Store a new passphrase as `{STAGING_KEYCHAIN_SERVICE}` in the System keychain"),
+                format!("Run `diskutil apfs changePassphrase {label}` from the `{KEYCHAIN_SERVICE}` passphrase"),
+                format!("Run `diskutil apfs unlockVolume {label} -verify` with the new passphrase"),
+                format!("Update the `{KEYCHAIN_SERVICE}` passphrase in the System keychain"),
+                format!(
+                    "Unmount `{label}` and have `{}` mount it, as at boot",
+                    self.volume.mount_service_label
+                ),
+                format!("Restart `{}`", self.volume.daemon_service_label),
+                format!("Remove `{STAGING_KEYCHAIN_SERVICE}` from the System keychain"),
+        ]);
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        let label = self.volume.label.as_str();
+        let domain = DARWIN_LAUNCHD_DOMAIN;
+        let mount_service = format!("{domain}/{}", self.volume.mount_service_label);
+        vec![
+            PrivilegedOperation::command(
+                "/usr/bin/security",
+                add_passphrase_args(
+                    self.determinate_nix,
+                    label,
+                    &self.disk,
+                    STAGING_KEYCHAIN_SERVICE,
+                    "<generated password>",
+                ),
+            ),
+            PrivilegedOperation::command(
+                "/usr/sbin/diskutil",
+                ["apfs", "changePassphrase", label, "-user", "disk"],
+            ),
+            PrivilegedOperation::command(
+                "/usr/bin/security",
+                add_passphrase_args(
+                    self.determinate_nix,
+                    label,
+                    &self.disk,
+                    KEYCHAIN_SERVICE,
+                    "<generated password>",
+                ),
+            ),
+            PrivilegedOperation::command("/usr/sbin/diskutil", ["unmount", "force", label]),
+            PrivilegedOperation::command("launchctl", ["bootout", mount_service.as_str()]),
+            PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "bootstrap".to_string(),
+                    domain.to_string(),
+                    self.volume.mount_service_path.display().to_string(),
+                ],
+            ),
+            PrivilegedOperation::command(
+                "launchctl",
+                [
+                    "kickstart".to_string(),
+                    "-k".to_string(),
+                    format!("{domain}/{}", self.volume.daemon_service_label),
+                ],
+            ),
+            PrivilegedOperation::command(
+                "/usr/bin/security",
+                [
+                    "delete-generic-password",
+                    "-a",
+                    label,
+                    "-s",
+                    STAGING_KEYCHAIN_SERVICE,
+                    SYSTEM_KEYCHAIN,
+                ],
+            ),
+        ]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        if self.recreate_keychain_item {
+            self.recreate_keychain_item().await.map_err(Self::error)?;
+            self.recreate_keychain_item = false;
+        }
+
+        let old = self
+            .find_passphrase(KEYCHAIN_SERVICE)
+            .await
+            .map_err(Self::error)?;
+        let new = generate_passphrase();
+        self.store_passphrase(STAGING_KEYCHAIN_SERVICE, &new, false)
+            .await
+            .map_err(Self::error)?;
+
+        let mut done = Rotated::Staged;
+        if let Err(err) = self.rotate(&old, &new, &mut done).await {
+            tracing::warn!("Rotating the passphrase failed, restoring the old one");
+            let rollback_errors = self.roll_back(done, &old, &new).await;
+            if rollback_errors.is_empty() {
+                return Err(Self::error(err));
+            }
+            return Err(Self::error(ActionErrorKind::Multiple(
+                std::iter::once(err).chain(rollback_errors).collect(),
+            )));
+        }
+
+        // The `Nix Store` item holds the new passphrase now
+        if let Err(err) = self.delete_passphrase(STAGING_KEYCHAIN_SERVICE).await {
+            tracing::warn!(
+                "Could not remove `{STAGING_KEYCHAIN_SERVICE}` from the System keychain, it can be removed by hand: {err}"
+            );
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // Uninstalling removes the volume and its passphrase, whichever one it is
+        Ok(())
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum RotateVolumeKeyError {
+    #[error("No passphrase for the Nix volume `{0}` was found in the System keychain, so it is not encrypted or cannot be unlocked at boot, and its passphrase cannot be changed without the current one")]
+    NoPassphrase(String),
+    #[error("The Nix volume `{0}` does not unlock with the passphrase staged by an earlier rotation, so the missing `Nix Store` item in the System keychain cannot be recreated from it")]
+    StagedPassphraseRejected(String, #[source] Box<ActionErrorKind>),
+}
+
+impl From<RotateVolumeKeyError> for ActionErrorKind {
+    fn from(val: RotateVolumeKeyError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RotateVolumeKey, STAGING_KEYCHAIN_SERVICE};
+    use crate::action::macos::encrypt_apfs_volume::KEYCHAIN_SERVICE;
+    use crate::action::macos::repair_volume_mount::{nix_volume_of, NixVolume};
+    use crate::action::{Action, ActionErrorKind, ActionState};
+    use crate::test_harness::{FakeCommand, Invocation, SandboxContext};
+
+    fn volume() -> NixVolume {
+        let receipt = serde_json::json!({
+            "actions": [
+                { "action": { "action_name": "create_nix_volume", "name": "Nix Store" }, "state": "Completed" },
+            ],
+        });
+        nix_volume_of(&receipt).expect("a Nix volume")
+    }
+
+    /// The `security` subcommand and service of each keychain call, such as
+    /// `("add-generic-password -U", "Nix Store")`
+    fn keychain_calls(sandbox: &SandboxContext) -> Vec<(String, String)> {
+        sandbox
+            .invocations_of("security")
+            .into_iter()
+            .map(|invocation| {
+                let mut subcommand = invocation.args[0].clone();
+                if invocation.args.get(1).map(String::as_str) == Some("-U") {
+                    subcommand.push_str(" -U");
+                }
+                let service = invocation
+                    .args
+                    .iter()
+                    .position(|arg| arg == "-s")
+                    .map(|index| invocation.args[index + 1].clone())
+                    .unwrap_or_default();
+                (subcommand, service)
+            })
+            .collect()
+    }
+
+    fn calls(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(subcommand, service)| (subcommand.to_string(), service.to_string()))
+            .collect()
+    }
+
+    /// The `diskutil` subcommands run, such as `apfs changePassphrase`
+    fn volume_calls(sandbox: &SandboxContext) -> Vec<String> {
+        sandbox
+            .invocations_of("diskutil")
+            .into_iter()
+            .map(|invocation| invocation.args[..2].join(" "))
+            .collect()
+    }
+
+    async fn planned(sandbox: &SandboxContext) -> eyre::Result<RotateVolumeKey> {
+        sandbox.fake(
+            "security",
+            FakeCommand::success().stdout("old-passphrase\n"),
+        );
+        sandbox.fake("launchctl", FakeCommand::success());
+        let action = sandbox
+            .scope(RotateVolumeKey::plan(volume(), "disk3".into(), false))
+            .await?;
+        assert_eq!(action.state, ActionState::Uncompleted);
+        Ok(action.action)
+    }
+
+    #[tokio::test]
+    async fn rotates_then_proves_the_boot_unlock() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let mut action = planned(&sandbox).await?;
+        sandbox.scope(action.execute()).await?;
+
+        assert_eq!(
+            keychain_calls(&sandbox),
+            calls(&[
+                ("find-generic-password", KEYCHAIN_SERVICE),
+                ("find-generic-password", KEYCHAIN_SERVICE),
+                ("add-generic-password", STAGING_KEYCHAIN_SERVICE),
+                ("add-generic-password -U", KEYCHAIN_SERVICE),
+                ("delete-generic-password", STAGING_KEYCHAIN_SERVICE),
+            ])
+        );
+        assert_eq!(
+            volume_calls(&sandbox),
+            [
+                "apfs changePassphrase",
+                "apfs unlockVolume",
+                "unmount force",
+                "info /nix"
+            ]
+        );
+        assert_eq!(
+            sandbox.invocations_of("launchctl").last(),
+            Some(&Invocation::new(
+                "launchctl",
+                ["kickstart", "-k", "system/org.nixos.nix-daemon"]
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_verification_restores_the_old_passphrase() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let mut action = planned(&sandbox).await?;
+        sandbox
+            .fake_once("diskutil", FakeCommand::success())
+            .fake_once("diskutil", FakeCommand::failure(1));
+        assert!(sandbox.scope(action.execute()).await.is_err());
+
+        // The volume takes the old passphrase again, which the keychain never stopped holding
+        assert_eq!(
+            volume_calls(&sandbox),
+            [
+                "apfs changePassphrase",
+                "apfs unlockVolume",
+                "apfs changePassphrase"
+            ]
+        );
+        assert_eq!(
+            keychain_calls(&sandbox),
+            calls(&[
+                ("find-generic-password", KEYCHAIN_SERVICE),
+                ("find-generic-password", KEYCHAIN_SERVICE),
+                ("add-generic-password", STAGING_KEYCHAIN_SERVICE),
+                ("delete-generic-password", STAGING_KEYCHAIN_SERVICE),
+            ])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_remount_restores_the_old_credentials() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let mut action = planned(&sandbox).await?;
+        sandbox
+            .fake_once("diskutil", FakeCommand::success())
+            .fake_once("diskutil", FakeCommand::success())
+            .fake_once("diskutil", FakeCommand::failure(1));
+        assert!(sandbox.scope(action.execute()).await.is_err());
+
+        assert_eq!(
+            volume_calls(&sandbox),
+            [
+                "apfs changePassphrase",
+                "apfs unlockVolume",
+                "unmount force",
+                "apfs changePassphrase",
+                "unmount force",
+                "info /nix"
+            ]
+        );
+        assert_eq!(
+            keychain_calls(&sandbox),
+            calls(&[
+                ("find-generic-password", KEYCHAIN_SERVICE),
+                ("find-generic-password", KEYCHAIN_SERVICE),
+                ("add-generic-password", STAGING_KEYCHAIN_SERVICE),
+                ("add-generic-password -U", KEYCHAIN_SERVICE),
+                ("add-generic-password -U", KEYCHAIN_SERVICE),
+                ("delete-generic-password", STAGING_KEYCHAIN_SERVICE),
+            ])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn new_passphrase_is_kept_if_the_volume_cannot_be_changed_back() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let mut action = planned(&sandbox).await?;
+        sandbox
+            .fake_once("diskutil", FakeCommand::success())
+            .fake_once("diskutil", FakeCommand::failure(1))
+            .fake_once("diskutil", FakeCommand::failure(1));
+        let err = sandbox.scope(action.execute()).await.unwrap_err();
+        assert!(
+            matches!(err.kind(), ActionErrorKind::Multiple(errors) if errors.len() == 2),
+            "{err:?}"
+        );
+
+        // The staged item still holds the passphrase the volume takes
+        assert_eq!(
+            keychain_calls(&sandbox).last(),
+            Some(&(
+                "add-generic-password".to_string(),
+                STAGING_KEYCHAIN_SERVICE.to_string()
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_keychain_item_is_recreated_from_the_staged_passphrase() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox
+            .fake_once("security", FakeCommand::failure(44))
+            .fake(
+                "security",
+                FakeCommand::success().stdout("staged-passphrase\n"),
+            );
+        sandbox.fake("launchctl", FakeCommand::success());
+        let mut action = sandbox
+            .scope(RotateVolumeKey::plan(volume(), "disk3".into(), false))
+            .await?;
+        assert!(action.action.execute_description()[0].explanation[0].starts_with("Recreate"));
+        sandbox.scope(action.action.execute()).await?;
+
+        // The `Nix Store` item is back before the rotation reads it
+        assert_eq!(
+            keychain_calls(&sandbox)[..6],
+            calls(&[
+                ("find-generic-password", KEYCHAIN_SERVICE),
+                ("find-generic-password", STAGING_KEYCHAIN_SERVICE),
+                ("find-generic-password", STAGING_KEYCHAIN_SERVICE),
+                ("add-generic-password", KEYCHAIN_SERVICE),
+                ("delete-generic-password", STAGING_KEYCHAIN_SERVICE),
+                ("find-generic-password", KEYCHAIN_SERVICE),
+            ])
+        );
+        assert_eq!(
+            volume_calls(&sandbox)[..2],
+            ["apfs unlockVolume", "apfs changePassphrase"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn staged_passphrase_the_volume_rejects_is_left_alone() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox
+            .fake_once("security", FakeCommand::failure(44))
+            .fake(
+                "security",
+                FakeCommand::success().stdout("staged-passphrase\n"),
+            )
+            .fake("diskutil", FakeCommand::failure(1));
+        let mut action = sandbox
+            .scope(RotateVolumeKey::plan(volume(), "disk3".into(), false))
+            .await?;
+        assert!(sandbox.scope(action.action.execute()).await.is_err());
+
+        assert_eq!(volume_calls(&sandbox), ["apfs unlockVolume"]);
+        assert!(!keychain_calls(&sandbox)
+            .iter()
+            .any(|(subcommand, _)| subcommand != "find-generic-password"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_passphrase_at_all_is_an_error() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake("security", FakeCommand::failure(44));
+        let err = sandbox
+            .scope(RotateVolumeKey::plan(volume(), "disk3".into(), false))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Nix Store") || format!("{err:?}").contains("NoPassphrase")
+        );
+        Ok(())
+    }
+}
@@ -6,7 +6,7 @@ use tracing::{span, Span};
 use crate::action::{ActionError, ActionTag, StatefulAction};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription};
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
 
 /**
 Set a time machine exclusion on a path.
@@ -65,6 +65,13 @@ impl Action for SetTmutilExclusion {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::command(
+            "tmutil",
+            ["addexclusion".to_string(), self.path.display().to_string()],
+        )]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         execute_command(
@@ -3,10 +3,12 @@ use std::path::PathBuf;
 use tracing::{span, Span};
 
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, PrivilegedOperation,
+    StatefulAction,
 };
 
-use super::SetTmutilExclusion;
+use super::{CreateDeterminateNixVolume, CreateNixVolume, SetTmutilExclusion};
+use crate::action::common::{ProvisionDeterminateNixd, ProvisionNix};
 
 /**
 Set a time machine exclusion on several paths.
@@ -69,7 +71,11 @@ impl Action for SetTmutilExclusions {
     }
 
     fn tracing_span(&self) -> Span {
-        span!(tracing::Level::DEBUG, "set_tmutil_exclusions",)
+        span!(
+            tracing::Level::DEBUG,
+            "set_tmutil_exclusions",
+            exclusions = self.set_tmutil_exclusions.len(),
+        )
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
@@ -89,6 +95,13 @@ impl Action for SetTmutilExclusions {
         )]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        self.set_tmutil_exclusions
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+            .collect()
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         // Just do sequential since parallelizing this will have little benefit
@@ -130,4 +143,14 @@ impl Action for SetTmutilExclusions {
             Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
         }
     }
+
+    fn dependencies(&self) -> Option<Vec<ActionTag>> {
+        // Only what creates the excluded paths, so exclusions are set while users are created
+        Some(vec![
+            ProvisionDeterminateNixd::action_tag(),
+            CreateNixVolume::action_tag(),
+            CreateDeterminateNixVolume::action_tag(),
+            ProvisionNix::action_tag(),
+        ])
+    }
 }
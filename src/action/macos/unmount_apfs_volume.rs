@@ -6,7 +6,7 @@ use tracing::{span, Span};
 use crate::action::{ActionError, ActionTag, StatefulAction};
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription};
+use crate::action::{Action, ActionDescription, PrivilegedOperation};
 use crate::os::darwin::DiskUtilInfoOutput;
 
 /**
@@ -66,7 +66,7 @@ impl Action for UnmountApfsVolume {
     fn tracing_span(&self) -> Span {
         span!(
             tracing::Level::DEBUG,
-            "unmount_volume",
+            "unmount_apfs_volume",
             disk = tracing::field::display(self.disk.display()),
             name = self.name,
         )
@@ -76,6 +76,13 @@ impl Action for UnmountApfsVolume {
         vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        vec![PrivilegedOperation::command(
+            "/usr/sbin/diskutil",
+            ["unmount", "force", self.name.as_str()],
+        )]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         let currently_mounted = {
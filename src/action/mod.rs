@@ -43,6 +43,9 @@ ideal.
 
 A custom [`Action`] can be created then used in a custom [`Planner`](crate::planner::Planner):
 
+(Custom [`Action`]s can be executed and reverted without root in tests by enabling the `test-harness`
+feature, see `nix_installer::test_harness` for details.)
+
 Note: if the struct has no fields, don't add the `serde` attribute to the struct.
 
 ```rust,no_run
@@ -52,7 +55,7 @@ use nix_installer::{
     InstallPlan,
     settings::{CommonSettings, InstallSettingsError},
     planner::{Planner, PlannerError},
-    action::{Action, ActionError, StatefulAction, ActionDescription},
+    action::{Action, ActionError, StatefulAction, ActionDescription, PrivilegedOperation},
 };
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
@@ -102,6 +105,11 @@ impl Action for MyAction {
          vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
     }
 
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        // Operations done as root in `execute` ...
+        vec![]
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         // Revert steps...
@@ -169,6 +177,7 @@ impl Planner for MyPlanner {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.common.ssl_cert_file.clone(),
+            self.common.proxy.clone(),
         )?)
     }
 
@@ -211,11 +220,18 @@ pub mod macos;
 mod stateful;
 
 pub use stateful::{ActionState, StatefulAction};
-use std::{error::Error, os::unix::process::ExitStatusExt as _, process::Output};
+use std::{
+    error::Error,
+    os::unix::process::ExitStatusExt as _,
+    path::{Path, PathBuf},
+    process::Output,
+};
 use tokio::task::JoinError;
 use tracing::Span;
 
-use crate::{error::HasExpectedErrors, settings::UrlOrPathError, CertificateError};
+use crate::{
+    error::HasExpectedErrors, messages::message, settings::UrlOrPathError, CertificateError,
+};
 
 /// An action which can be reverted or completed, with an action state
 ///
@@ -224,7 +240,7 @@ use crate::{error::HasExpectedErrors, settings::UrlOrPathError, CertificateError
 /// Instead of calling [`execute`][Action::execute] or [`revert`][Action::revert], you should prefer [`try_execute`][StatefulAction::try_execute] and [`try_revert`][StatefulAction::try_revert]
 #[async_trait::async_trait]
 #[typetag::serde(tag = "action_name")]
-pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
+pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone + AsAny {
     fn action_tag() -> ActionTag
     where
         Self: Sized;
@@ -250,6 +266,15 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
     ///
     /// This is called by [`InstallPlan::describe_uninstall`](crate::InstallPlan::describe_uninstall) through [`StatefulAction::describe_revert`] which will skip output if the action is completed.
     fn revert_description(&self) -> Vec<ActionDescription>;
+    /// The operations this action would perform as root during execution, for security review
+    ///
+    /// Values only known during execution, like the store path of Nix, are `<placeholders>`. Commands
+    /// which only query state, like `systemctl is-active`, are left out.
+    ///
+    /// If this action calls sub-[`Action`]s, care should be taken to use [`StatefulAction::privileged_operations`] on those actions, not [`privileged_operations`][Action::privileged_operations].
+    ///
+    /// This is called by [`InstallPlan::privileged_operations`](crate::InstallPlan::privileged_operations) through [`StatefulAction::privileged_operations`] which will skip output if the action is completed.
+    fn privileged_operations(&self) -> Vec<PrivilegedOperation>;
     /// Perform any execution steps
     ///
     /// If this action calls sub-[`Action`]s, care should be taken to call [`try_execute`][StatefulAction::try_execute], not [`execute`][Action::execute], so that [`ActionState`] is handled correctly and tracing is done.
@@ -262,6 +287,24 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
     ///
     /// This is called by [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) through [`StatefulAction::try_revert`] which handles tracing as well as if the action needs to revert based on its `action_state`.
     async fn revert(&mut self) -> Result<(), ActionError>;
+    /// The [`action_tag`][Action::action_tag]s of the actions which must revert before this one
+    ///
+    /// [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) reverts the actions of a plan last to first, except that an action is held back until every action of the plan named here has reverted.
+    fn revert_after(&self) -> Vec<ActionTag> {
+        vec![]
+    }
+    /// The [`action_tag`][Action::action_tag]s of the earlier actions of the plan which must execute before this one, or `None` for all of them
+    ///
+    /// [`InstallPlan::install`](crate::InstallPlan::install) starts an action once every earlier action it depends on has completed, so actions which name their dependencies can execute alongside each other. Only actions before this one in the plan are waited for, so a plan is never reordered.
+    fn dependencies(&self) -> Option<Vec<ActionTag>> {
+        None
+    }
+    /// If the last [`revert`][Action::revert] left something in place for a later uninstall to retry, like a user which could not be deleted
+    ///
+    /// [`StatefulAction::try_revert`] then leaves the action [`Progress`](ActionState::Progress) rather than [`Uncompleted`](ActionState::Uncompleted), so a receipt recording it reverts it again.
+    fn revert_incomplete(&self) -> bool {
+        false
+    }
 
     fn stateful(self) -> StatefulAction<Self>
     where
@@ -285,6 +328,19 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
 
 dyn_clone::clone_trait_object!(Action);
 
+/// Access to the concrete type behind a `dyn Action`, implemented for every [`Action`]
+///
+/// Prefer [`StatefulAction::downcast_ref`], calling `as_any` on a `Box<dyn Action>` gives the `Box` itself.
+pub trait AsAny: std::any::Any {
+    fn as_any(&self) -> &dyn std::any::Any;
+}
+
+impl<T: std::any::Any> AsAny for T {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
 /**
 A description of an [`Action`], intended for humans to review
 */
@@ -303,6 +359,114 @@ impl ActionDescription {
     }
 }
 
+/**
+An operation an [`Action`] performs as root, listed so an install can be reviewed (and restricted)
+before it runs
+
+Serialized with a `kind`, like `{ "kind": "command", "program": "useradd", "args": [...] }`.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PrivilegedOperation {
+    /// An external command, `args` may contain `<placeholders>`
+    Command { program: String, args: Vec<String> },
+    /// A file written, or a directory or symlink created, with its mode if one is set
+    Write {
+        path: PathBuf,
+        #[serde(default, with = "octal_mode")]
+        mode: Option<u32>,
+    },
+    /// A file or directory removed
+    Remove { path: PathBuf },
+    /// A service registered with the init system
+    Service { name: String },
+}
+
+impl PrivilegedOperation {
+    pub fn command<S: Into<String>>(
+        program: impl Into<String>,
+        args: impl IntoIterator<Item = S>,
+    ) -> Self {
+        Self::Command {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn write(path: impl Into<PathBuf>, mode: impl Into<Option<u32>>) -> Self {
+        Self::Write {
+            path: path.into(),
+            mode: mode.into(),
+        }
+    }
+
+    pub fn remove(path: impl Into<PathBuf>) -> Self {
+        Self::Remove { path: path.into() }
+    }
+
+    pub fn service(name: impl Into<String>) -> Self {
+        Self::Service { name: name.into() }
+    }
+
+    /// If `allowed`, an entry of an allowlist, permits this operation
+    ///
+    /// Commands match by program name, wherever the program is. Paths match `allowed`'s path, or
+    /// any path inside it. Services match by name.
+    pub fn is_allowed_by(&self, allowed: &Self) -> bool {
+        match (self, allowed) {
+            (
+                Self::Command { program, .. },
+                Self::Command {
+                    program: allowed, ..
+                },
+            ) => Path::new(program).file_name() == Path::new(allowed).file_name(),
+            (Self::Write { path, .. }, Self::Write { path: allowed, .. })
+            | (Self::Remove { path }, Self::Remove { path: allowed }) => path.starts_with(allowed),
+            (Self::Service { name }, Self::Service { name: allowed }) => name == allowed,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for PrivilegedOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Command { program, args } if args.is_empty() => write!(f, "Run `{program}`"),
+            Self::Command { program, args } => write!(f, "Run `{program} {}`", args.join(" ")),
+            Self::Write {
+                path,
+                mode: Some(mode),
+            } => write!(f, "Write `{}` (mode {mode:#o})", path.display()),
+            Self::Write { path, mode: None } => write!(f, "Write `{}`", path.display()),
+            Self::Remove { path } => write!(f, "Remove `{}`", path.display()),
+            Self::Service { name } => write!(f, "Register service `{name}`"),
+        }
+    }
+}
+
+/// Modes as octal strings like `"0755"`, since decimal ones are hard to review
+mod octal_mode {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        mode: &Option<u32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match mode {
+            Some(mode) => serializer.serialize_str(&format!("{mode:04o}")),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u32>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|mode| u32::from_str_radix(&mode, 8).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
 /// A 'tag' name an action has that corresponds to the one we serialize in [`typetag]`
 pub struct ActionTag(pub &'static str);
 
@@ -418,13 +582,16 @@ pub enum ActionErrorKind {
     /// The symlink already exists
     #[error("`{0}` already exists, consider removing it with `rm {0}`")]
     SymlinkExists(std::path::PathBuf),
-    #[error("`{0}` exists with a different uid ({1}) than planned ({2}), consider updating it with `chown {2} {0}` (you may need to do this recursively with the `-R` flag)")]
+    #[error("{}", message!(ErrorPathUserMismatch, path = .0.display(), existing = .1, planned = .2))]
     PathUserMismatch(std::path::PathBuf, u32, u32),
-    #[error("`{0}` exists with a different gid ({1}) than planned ({2}), consider updating it with `chgrp {2} {0}` (you may need to do this recursively with the `-R` flag)")]
+    #[error("{}", message!(ErrorPathGroupMismatch, path = .0.display(), existing = .1, planned = .2))]
     PathGroupMismatch(std::path::PathBuf, u32, u32),
-    #[error("`{0}` exists with a different mode ({existing_mode:o}) than planned ({planned_mode:o}), consider updating it with `chmod {planned_mode:o} {0}` (you may need to do this recursively with the `-R` flag)",
-        existing_mode = .1 & 0o777,
-        planned_mode = .2 & 0o777)]
+    #[error("{}", message!(
+        ErrorPathModeMismatch,
+        path = .0.display(),
+        existing = format!("{:o}", .1 & 0o777),
+        planned = format!("{:o}", .2 & 0o777),
+    ))]
     PathModeMismatch(std::path::PathBuf, u32, u32),
     #[error("Path `{0}` exists, but is not a file, consider removing it with `rm {0}`")]
     PathWasNotFile(std::path::PathBuf),
@@ -564,6 +731,12 @@ pub enum ActionErrorKind {
         "Could not find a supported command to create users in PATH; please install `useradd` or `adduser`"
     )]
     MissingUserCreationCommand,
+    #[error(
+        "Could not find a supported command to modify users in PATH; please install `usermod`"
+    )]
+    MissingUserModificationCommand,
+    #[error("{}", message!(ErrorMissingUserShell, shell = .0.display()))]
+    MissingUserShell(std::path::PathBuf),
     #[error("Could not find a supported command to create groups in PATH; please install `groupadd` or `addgroup`")]
     MissingGroupCreationCommand,
     #[error("Could not find a supported command to add users to groups in PATH; please install `gpasswd` or `addgroup`")]
@@ -576,10 +749,7 @@ pub enum ActionErrorKind {
     MissingGroupDeletionCommand,
     #[error("Could not find a supported command to remove users from groups in PATH; please install `gpasswd` or `deluser`")]
     MissingRemoveUserFromGroupCommand,
-    #[error("\
-        Could not detect systemd; you may be able to get up and running without systemd with `nix-installer install linux --init none`.\n\
-        See https://github.com/DeterminateSystems/nix-installer#without-systemd-linux-only for documentation on usage and drawbacks.\
-        ")]
+    #[error("{}", message!(ErrorSystemdMissing))]
     SystemdMissing,
     #[error("`{command}` failed, message: {message}")]
     DiskUtilInfoError { command: String, message: String },
@@ -593,9 +763,21 @@ pub enum ActionErrorKind {
     ),
     #[error("Unknown url scheme")]
     UnknownUrlScheme,
+    #[error("The Nix package has the SHA-256 `{actual}`, but `{expected}` was expected")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("{}", message!(ErrorProxyRejectedCredentials, url = .0))]
+    ProxyRejectedCredentials(reqwest::Url),
 }
 
 impl ActionErrorKind {
+    /// An error fetching `url`, singling out a proxy refusing the credentials
+    pub(crate) fn fetch(url: &reqwest::Url, error: reqwest::Error) -> Self {
+        if crate::proxy::rejected_credentials(&error) {
+            Self::ProxyRejectedCredentials(url.clone())
+        } else {
+            Self::Reqwest(error)
+        }
+    }
     pub fn command(command: &tokio::process::Command, error: std::io::Error) -> Self {
         Self::Command {
             #[cfg(feature = "diagnostics")]
@@ -621,6 +803,12 @@ impl HasExpectedErrors for ActionErrorKind {
             | Self::PathGroupMismatch(_, _, _)
             | Self::PathModeMismatch(_, _, _) => Some(Box::new(self)),
             Self::SystemdMissing => Some(Box::new(self)),
+            Self::MissingUserShell(_) => Some(Box::new(self)),
+            Self::ChecksumMismatch { .. } => Some(Box::new(self)),
+            Self::ProxyRejectedCredentials(_) => Some(Box::new(self)),
+            Self::Custom(e) => e
+                .downcast_ref::<linux::CreateZfsDatasetError>()
+                .and_then(|e| e.expected()),
             _ => None,
         }
     }
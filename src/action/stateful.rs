@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use tracing::{Instrument, Span};
 
-use super::{Action, ActionDescription, ActionError, ActionTag};
+use super::{Action, ActionDescription, ActionError, ActionTag, PrivilegedOperation};
 
 /// A wrapper around an [`Action`](crate::action::Action) which tracks the [`ActionState`] and
 /// handles some tracing output
@@ -27,6 +27,18 @@ impl StatefulAction<Box<dyn Action>> {
     pub fn inner_typetag_name(&self) -> &'static str {
         self.action.typetag_name()
     }
+    /// The inner action, if it is a `T`
+    pub fn downcast_ref<T: Action>(&self) -> Option<&T> {
+        // Deref the `Box` first, otherwise the `Box` itself would be the `Any`
+        (*self.action).as_any().downcast_ref()
+    }
+    /// A copy of this action with its state, if the inner action is a `T`
+    pub fn downcast<T: Action + Clone>(&self) -> Option<StatefulAction<T>> {
+        Some(StatefulAction {
+            action: self.downcast_ref::<T>()?.clone(),
+            state: self.state,
+        })
+    }
     pub fn tracing_synopsis(&self) -> String {
         self.action.tracing_synopsis()
     }
@@ -52,6 +64,15 @@ impl StatefulAction<Box<dyn Action>> {
             _ => self.action.revert_description(),
         }
     }
+    /// The operations this action would perform as root during execution
+    pub fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => {
+                vec![]
+            },
+            _ => self.action.privileged_operations(),
+        }
+    }
     /// Perform any execution steps
     ///
     /// You should prefer this ([`try_execute`][StatefulAction::try_execute]) over [`execute`][Action::execute] as it handles [`ActionState`] and does tracing
@@ -101,7 +122,11 @@ impl StatefulAction<Box<dyn Action>> {
                 tracing::debug!("Reverting: {}", self.action.tracing_synopsis());
                 self.action.revert().await?;
                 tracing::debug!("Reverted: {}", self.action.tracing_synopsis());
-                self.state = ActionState::Uncompleted;
+                // Left to retry, see `Action::revert_incomplete`
+                self.state = match self.action.revert_incomplete() {
+                    true => ActionState::Progress,
+                    false => ActionState::Uncompleted,
+                };
                 Ok(())
             },
         }
@@ -154,6 +179,13 @@ where
         }
         self.action.revert_description()
     }
+    /// The operations this action would perform as root during execution
+    pub fn privileged_operations(&self) -> Vec<PrivilegedOperation> {
+        match self.state {
+            ActionState::Completed | ActionState::Skipped => vec![],
+            _ => self.action.privileged_operations(),
+        }
+    }
     /// Perform any execution steps
     ///
     /// You should prefer this ([`try_execute`][StatefulAction::try_execute]) over [`execute`][Action::execute] as it handles [`ActionState`] and does tracing
@@ -179,7 +211,12 @@ where
                     "Executing: {}",
                     self.action.tracing_synopsis()
                 );
-                self.action.execute().instrument(span.clone()).await?;
+                crate::report::sub_action(
+                    A::action_tag(),
+                    self.action.tracing_synopsis(),
+                    self.action.execute().instrument(span.clone()),
+                )
+                .await?;
                 self.state = ActionState::Completed;
                 tracing::debug!(
                     parent: &span,
@@ -215,13 +252,22 @@ where
                     "Reverting: {}",
                     self.action.tracing_synopsis()
                 );
-                self.action.revert().instrument(span.clone()).await?;
+                crate::report::sub_action(
+                    A::action_tag(),
+                    self.action.tracing_synopsis(),
+                    self.action.revert().instrument(span.clone()),
+                )
+                .await?;
                 tracing::debug!(
                     parent: &span,
                     "Reverted: {}",
                     self.action.tracing_synopsis()
                 );
-                self.state = ActionState::Uncompleted;
+                // Left to retry, see `Action::revert_incomplete`
+                self.state = match self.action.revert_incomplete() {
+                    true => ActionState::Progress,
+                    false => ActionState::Uncompleted,
+                };
                 Ok(())
             },
         }
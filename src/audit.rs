@@ -0,0 +1,1027 @@
+/*! Auditing an existing Nix install before migrating it to `nix-installer`
+
+`nix-installer plan audit-existing` compares what the upstream shell installer put in place (the
+daemon unit or plist, shell profile snippets, `nix.conf`, build users, and channels) with what a
+freshly computed plan would create, putting each artifact in one [`ArtifactStatus`]:
+
+* `identical`: present, and `nix-installer` would put the same thing in place
+* `different`: present, but `nix-installer` would change it (with a line diff for text files)
+* `unmanaged`: present, but `nix-installer` would not own it, so it stays behind after an uninstall
+* `missing`: absent, and `nix-installer` would create it
+
+An audit only reads, it never writes anywhere: the plan is computed but never executed, and
+artifacts are inspected with read-only filesystem calls.
+*/
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use indexmap::IndexMap;
+use nix_config_parser::NixConfig;
+
+use crate::{
+    action::{Action, StatefulAction},
+    error::HasExpectedErrors,
+    planner::{BuiltinPlanner, PlannerError},
+    util::host_path,
+};
+
+/// Files the shell installer creates or edits, see `scripts/install-multi-user.sh` in the Nix repository
+#[cfg(target_os = "linux")]
+const SHELL_INSTALLER_FILES: &[&str] = &[
+    "/etc/systemd/system/nix-daemon.service",
+    "/etc/systemd/system/nix-daemon.socket",
+    "/etc/tmpfiles.d/nix-daemon.conf",
+    "/etc/profile.d/nix.sh",
+    "/etc/bashrc",
+    "/etc/bash.bashrc",
+    "/etc/zshrc",
+    "/etc/zsh/zshrc",
+    "/etc/fish/conf.d/nix.fish",
+    "/etc/nix/nix.conf",
+    "/root/.nix-channels",
+    "/root/.nix-defexpr",
+    "/root/.nix-profile",
+];
+#[cfg(target_os = "macos")]
+const SHELL_INSTALLER_FILES: &[&str] = &[
+    "/Library/LaunchDaemons/org.nixos.nix-daemon.plist",
+    "/Library/LaunchDaemons/org.nixos.darwin-store.plist",
+    "/etc/bashrc",
+    "/etc/bash.bashrc",
+    "/etc/zshrc",
+    "/etc/fish/conf.d/nix.fish",
+    "/etc/nix/nix.conf",
+    "/etc/synthetic.conf",
+    "/etc/fstab",
+    "/var/root/.nix-channels",
+    "/var/root/.nix-defexpr",
+    "/var/root/.nix-profile",
+];
+
+/// The build group the shell installer creates
+const SHELL_INSTALLER_GROUP: &str = "nixbld";
+/// The build users the shell installer creates are this prefix followed by a number
+#[cfg(target_os = "linux")]
+const SHELL_INSTALLER_USER_PREFIX: &str = "nixbld";
+#[cfg(target_os = "macos")]
+const SHELL_INSTALLER_USER_PREFIX: &str = "_nixbld";
+const SHELL_INSTALLER_USER_COUNT: u32 = 32;
+
+/// A shell profile hook written by an installer other than `nix-installer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ForeignShellHook {
+    /// What wrote the hook, such as `the Nix 2.x shell installer`
+    pub(crate) origin: &'static str,
+    /// Its lines, without trailing whitespace or blank lines
+    lines: &'static [&'static str],
+}
+
+/// The hooks earlier installers wrote to files like `/etc/profile.d/nix.sh`, which `nix-installer`
+/// may take over when a file holds nothing else
+pub(crate) const FOREIGN_SHELL_HOOKS: &[ForeignShellHook] = &[
+    ForeignShellHook {
+        origin: "the Nix 2.x shell installer",
+        lines: &[
+            "# Nix",
+            "if [ -e '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh' ]; then",
+            "  . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'",
+            "fi",
+            "# End Nix",
+        ],
+    },
+    // Multi-user installs from before `nix-daemon.sh` sourced the single-user script
+    ForeignShellHook {
+        origin: "a Nix 1.x multi-user install",
+        lines: &[
+            "# Nix",
+            "if [ -e '/nix/var/nix/profiles/default/etc/profile.d/nix.sh' ]; then",
+            "  . '/nix/var/nix/profiles/default/etc/profile.d/nix.sh'",
+            "fi",
+            "# End Nix",
+        ],
+    },
+];
+
+/// The foreign hook `content` consists of, if it holds exactly one and nothing else
+pub(crate) fn recognize_foreign_shell_hook(content: &str) -> Option<&'static ForeignShellHook> {
+    let lines = content
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    FOREIGN_SHELL_HOOKS
+        .iter()
+        .find(|hook| hook.lines == lines.as_slice())
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, strum::Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ArtifactKind {
+    File,
+    User,
+    Group,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactStatus {
+    Identical,
+    Different,
+    Unmanaged,
+    Missing,
+}
+
+/// An artifact of an existing install, or of the plan, and how they compare
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AuditedArtifact {
+    pub kind: ArtifactKind,
+    /// The path of a file, or the name of a user or group
+    pub name: String,
+    pub status: ArtifactStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// A line diff from what exists to what `nix-installer` would put in place
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AuditReport {
+    pub planner: String,
+    pub artifacts: Vec<AuditedArtifact>,
+}
+
+impl AuditReport {
+    pub fn with_status(&self, status: ArtifactStatus) -> impl Iterator<Item = &AuditedArtifact> {
+        self.artifacts
+            .iter()
+            .filter(move |artifact| artifact.status == status)
+    }
+}
+
+/// Plan an install with `planner` and compare it to what the shell installer left on this system
+///
+/// Planning is done with `force` and `shared_store_ok` set, since the existing files and Nix store
+/// are what is being audited.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn audit_existing(mut planner: BuiltinPlanner) -> Result<AuditReport, AuditError> {
+    let settings = planner.common_settings_mut();
+    settings.force = true;
+    settings.shared_store_ok = true;
+
+    let planner_name = planner.typetag_name();
+    let actions = planner.boxed().plan().await?;
+    audit_actions(planner_name, &actions).await
+}
+
+pub(crate) async fn audit_actions(
+    planner: &str,
+    actions: &[StatefulAction<Box<dyn Action>>],
+) -> Result<AuditReport, AuditError> {
+    let plan = serde_json::to_value(actions).map_err(AuditError::SerializingPlan)?;
+    let mut planned = vec![];
+    find_planned(&plan, &mut planned);
+
+    let mut artifacts = vec![];
+
+    let mut file_paths: Vec<PathBuf> = vec![];
+    for path in planned
+        .iter()
+        .filter_map(Planned::path)
+        .map(Path::to_path_buf)
+        .chain(SHELL_INSTALLER_FILES.iter().map(PathBuf::from))
+    {
+        if !file_paths.contains(&path) {
+            file_paths.push(path);
+        }
+    }
+    for path in file_paths {
+        let planned_file = planned.iter().find(|v| v.path() == Some(&path));
+        artifacts.extend(audit_file(&path, planned_file).await?);
+    }
+
+    let passwd = read_database("/etc/passwd").await?;
+    let group = read_database("/etc/group").await?;
+
+    let mut group_names = vec![SHELL_INSTALLER_GROUP.to_string()];
+    let mut user_names = BTreeSet::new();
+    for item in &planned {
+        match item {
+            Planned::Group { name, .. } if !group_names.contains(name) => {
+                group_names.push(name.clone())
+            },
+            Planned::User { name, .. } => {
+                user_names.insert(name.clone());
+            },
+            _ => (),
+        }
+    }
+    for name in &group_names {
+        // Build users which were added to a build group, whatever they are named
+        if let Some(members) = group.get(name).and_then(|fields| fields.get(2)) {
+            user_names.extend(
+                members
+                    .split(',')
+                    .filter(|member| !member.is_empty())
+                    .map(String::from),
+            );
+        }
+    }
+    user_names.extend(
+        passwd
+            .keys()
+            .filter(|name| is_shell_installer_user(name))
+            .cloned(),
+    );
+    user_names.extend(
+        (1..=SHELL_INSTALLER_USER_COUNT).map(|n| format!("{SHELL_INSTALLER_USER_PREFIX}{n}")),
+    );
+
+    for name in group_names {
+        let planned_gid = planned.iter().find_map(|v| match v {
+            Planned::Group { name: planned, gid } if *planned == name => Some(*gid),
+            _ => None,
+        });
+        let existing_gid = lookup_group(&group, &name);
+        if let Some(artifact) = audit_entry(
+            ArtifactKind::Group,
+            name,
+            existing_gid.map(|gid| format!("gid = {gid}\n")),
+            planned_gid.map(|gid| format!("gid = {gid}\n")),
+        ) {
+            artifacts.push(artifact);
+        }
+    }
+
+    // Sort numbered users numerically rather than `nixbld1`, `nixbld10`, ...
+    let mut user_names = user_names.into_iter().collect::<Vec<_>>();
+    user_names.sort_by_key(|name| {
+        let digits = name.len() - name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        let (prefix, number) = name.split_at(name.len() - digits);
+        (prefix.to_string(), number.parse::<u64>().unwrap_or(0))
+    });
+    for name in user_names {
+        let planned_user = planned.iter().find_map(|v| match v {
+            Planned::User {
+                name: planned,
+                uid,
+                gid,
+                shell,
+                home,
+            } if *planned == name => Some(user_record(*uid, *gid, shell, home)),
+            _ => None,
+        });
+        let existing_user = lookup_user(&passwd, &name);
+        if let Some(artifact) = audit_entry(ArtifactKind::User, name, existing_user, planned_user) {
+            artifacts.push(artifact);
+        }
+    }
+
+    Ok(AuditReport {
+        planner: planner.to_string(),
+        artifacts,
+    })
+}
+
+/// Something the plan would put in place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Planned {
+    /// A file written with exactly this content
+    File {
+        path: PathBuf,
+        content: String,
+    },
+    /// Content inserted into a file, keeping the rest of it
+    Insertion {
+        path: PathBuf,
+        content: String,
+        at_beginning: bool,
+    },
+    /// Settings merged into a `nix.conf`
+    NixConfig {
+        path: PathBuf,
+        settings: IndexMap<String, String>,
+    },
+    /// A symlink to a file in the Nix store
+    Link {
+        path: PathBuf,
+        source: PathBuf,
+    },
+    /// A file whose content is only known during the install, such as one including a volume UUID
+    Generated {
+        path: PathBuf,
+    },
+    User {
+        name: String,
+        uid: u32,
+        gid: u32,
+        shell: PathBuf,
+        home: PathBuf,
+    },
+    Group {
+        name: String,
+        gid: u32,
+    },
+}
+
+impl Planned {
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match self {
+            Planned::File { path, .. }
+            | Planned::Insertion { path, .. }
+            | Planned::NixConfig { path, .. }
+            | Planned::Link { path, .. }
+            | Planned::Generated { path } => Some(path),
+            Planned::User { .. } | Planned::Group { .. } => None,
+        }
+    }
+}
+
+/// Search a serialized plan for the artifacts its actions would create, in the order they would be created
+pub(crate) fn find_planned(plan: &serde_json::Value, found: &mut Vec<Planned>) {
+    match plan {
+        serde_json::Value::Object(map) => {
+            let string = |key: &str| map.get(key).and_then(|v| v.as_str()).map(String::from);
+            let path = |key: &str| string(key).map(PathBuf::from);
+            let number = |key: &str| {
+                map.get(key)
+                    .and_then(|v| v.as_u64())
+                    .and_then(|v| u32::try_from(v).ok())
+            };
+            match map.get("action_name").and_then(|v| v.as_str()) {
+                Some("create_file") => {
+                    if let (Some(path), Some(content)) = (path("path"), string("buf")) {
+                        found.push(Planned::File { path, content })
+                    }
+                },
+                Some("create_or_insert_into_file") => {
+                    if let (Some(path), Some(content)) = (path("path"), string("buf")) {
+                        found.push(Planned::Insertion {
+                            path,
+                            content,
+                            at_beginning: string("position").as_deref() == Some("Beginning"),
+                        })
+                    }
+                },
+                Some("create_or_merge_nix_config") => {
+                    let settings = map
+                        .get("pending_nix_config")
+                        .and_then(|v| v.get("settings"))
+                        .and_then(|v| serde_json::from_value(v.clone()).ok());
+                    if let (Some(path), Some(settings)) = (path("path"), settings) {
+                        found.push(Planned::NixConfig { path, settings })
+                    }
+                },
+                Some("configure_init_service") => {
+                    if let (Some(path), Some(source)) = (path("service_dest"), path("service_src"))
+                    {
+                        found.push(Planned::Link { path, source })
+                    }
+                    for socket_file in map
+                        .get("socket_files")
+                        .and_then(|v| v.as_array())
+                        .into_iter()
+                        .flatten()
+                    {
+                        let Some(path) = socket_file.get("dest").and_then(|v| v.as_str()) else {
+                            continue;
+                        };
+                        let path = PathBuf::from(path);
+                        let src = socket_file.get("src");
+                        if let Some(source) =
+                            src.and_then(|v| v.get("Path")).and_then(|v| v.as_str())
+                        {
+                            found.push(Planned::Link {
+                                path,
+                                source: PathBuf::from(source),
+                            })
+                        } else if let Some(content) =
+                            src.and_then(|v| v.get("Literal")).and_then(|v| v.as_str())
+                        {
+                            found.push(Planned::File {
+                                path,
+                                content: content.to_string(),
+                            })
+                        }
+                    }
+                },
+                Some("create_volume_service") | Some("create_nix_hook_service") => {
+                    if let Some(path) = path("path") {
+                        found.push(Planned::Generated { path })
+                    }
+                },
+                Some("create_fstab_entry") => found.push(Planned::Generated {
+                    path: PathBuf::from("/etc/fstab"),
+                }),
+                Some("create_user") => {
+                    if let (Some(name), Some(uid), Some(gid)) =
+                        (string("name"), number("uid"), number("gid"))
+                    {
+                        let defaults = crate::action::base::UserShellAndHome::default();
+                        found.push(Planned::User {
+                            name,
+                            uid,
+                            gid,
+                            shell: path("shell").unwrap_or(defaults.shell),
+                            home: path("home").unwrap_or(defaults.home),
+                        })
+                    }
+                },
+                Some("create_group") => {
+                    if let (Some(name), Some(gid)) = (string("name"), number("gid")) {
+                        found.push(Planned::Group { name, gid })
+                    }
+                },
+                _ => (),
+            }
+            for value in map.values() {
+                find_planned(value, found);
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for value in values {
+                find_planned(value, found);
+            }
+        },
+        _ => (),
+    }
+}
+
+async fn audit_file(
+    path: &Path,
+    planned: Option<&Planned>,
+) -> Result<Option<AuditedArtifact>, AuditError> {
+    let host_path = host_path(path);
+    let metadata = match tokio::fs::symlink_metadata(&host_path).await {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(AuditError::Read(path.to_path_buf(), e)),
+    };
+    let artifact = |status, detail: Option<String>, diff| {
+        Some(AuditedArtifact {
+            kind: ArtifactKind::File,
+            name: path.display().to_string(),
+            status,
+            detail,
+            diff,
+        })
+    };
+
+    let (metadata, planned) = match (metadata, planned) {
+        (None, None) => return Ok(None),
+        (None, Some(_)) => return Ok(artifact(ArtifactStatus::Missing, None, None)),
+        (Some(_), None) => {
+            return Ok(artifact(
+                ArtifactStatus::Unmanaged,
+                Some("`nix-installer` would not manage or remove this".into()),
+                None,
+            ))
+        },
+        (Some(metadata), Some(planned)) => (metadata, planned),
+    };
+
+    Ok(match planned {
+        Planned::Link { source, .. } if metadata.is_symlink() => {
+            let target = tokio::fs::read_link(&host_path)
+                .await
+                .map_err(|e| AuditError::Read(path.to_path_buf(), e))?;
+            if target == *source {
+                artifact(ArtifactStatus::Identical, None, None)
+            } else {
+                artifact(
+                    ArtifactStatus::Different,
+                    Some(format!(
+                        "Links to `{}`, `nix-installer` would link to `{}`",
+                        target.display(),
+                        source.display()
+                    )),
+                    None,
+                )
+            }
+        },
+        Planned::Link { source, .. } => {
+            let existing = read_text(path).await?;
+            let linked = read_text(source).await.ok();
+            artifact(
+                ArtifactStatus::Different,
+                Some(format!(
+                    "A regular file, `nix-installer` would link to `{}`",
+                    source.display()
+                )),
+                linked
+                    .filter(|linked| *linked != existing)
+                    .map(|linked| diff_lines(&existing, &linked)),
+            )
+        },
+        Planned::Generated { .. } => artifact(
+            ArtifactStatus::Different,
+            Some(
+                "`nix-installer` would replace this with content generated during the install"
+                    .into(),
+            ),
+            None,
+        ),
+        Planned::File { content, .. } => {
+            let existing = read_text(path).await?;
+            if existing == *content {
+                artifact(ArtifactStatus::Identical, None, None)
+            } else {
+                artifact(
+                    ArtifactStatus::Different,
+                    None,
+                    Some(diff_lines(&existing, content)),
+                )
+            }
+        },
+        Planned::Insertion {
+            content,
+            at_beginning,
+            ..
+        } => {
+            let existing = read_text(path).await?;
+            if existing.contains(content.as_str()) {
+                artifact(ArtifactStatus::Identical, None, None)
+            } else if let Some(hook) = recognize_foreign_shell_hook(&existing) {
+                artifact(
+                    ArtifactStatus::Different,
+                    Some(format!(
+                        "Written by {}, `nix-installer` would take it over and restore it on uninstall",
+                        hook.origin
+                    )),
+                    Some(diff_lines(&existing, content)),
+                )
+            } else {
+                let expected = match at_beginning {
+                    true => format!("{content}{existing}"),
+                    false => format!("{existing}{content}"),
+                };
+                artifact(
+                    ArtifactStatus::Different,
+                    Some("`nix-installer` would insert its own snippet".into()),
+                    Some(diff_lines(&existing, &expected)),
+                )
+            }
+        },
+        Planned::NixConfig { settings, .. } => {
+            let existing = read_text(path).await?;
+            match NixConfig::parse_string(existing.clone(), Some(path)) {
+                Ok(existing_config) => {
+                    let existing_settings = existing_config.into_settings();
+                    let mut merged = existing_settings.clone();
+                    for (name, value) in settings {
+                        merged.insert(name.clone(), value.clone());
+                    }
+                    if merged == existing_settings {
+                        artifact(ArtifactStatus::Identical, None, None)
+                    } else {
+                        artifact(
+                            ArtifactStatus::Different,
+                            Some("`nix-installer` would change these settings".into()),
+                            Some(diff_lines(
+                                &render_settings(&existing_settings),
+                                &render_settings(&merged),
+                            )),
+                        )
+                    }
+                },
+                Err(e) => artifact(
+                    ArtifactStatus::Different,
+                    Some(format!(
+                        "Could not be parsed ({e}), `nix-installer` would replace it"
+                    )),
+                    Some(diff_lines(&existing, &render_settings(settings))),
+                ),
+            }
+        },
+        Planned::User { .. } | Planned::Group { .. } => None,
+    })
+}
+
+/// Compare an existing user or group with the planned one, both rendered as `field = value` lines
+fn audit_entry(
+    kind: ArtifactKind,
+    name: String,
+    existing: Option<String>,
+    planned: Option<String>,
+) -> Option<AuditedArtifact> {
+    let (status, detail, diff) = match (existing, planned) {
+        (None, None) => return None,
+        (Some(_), None) => (
+            ArtifactStatus::Unmanaged,
+            Some("`nix-installer` would not manage or remove this".to_string()),
+            None,
+        ),
+        (None, Some(_)) => (ArtifactStatus::Missing, None, None),
+        (Some(existing), Some(planned)) if existing == planned => {
+            (ArtifactStatus::Identical, None, None)
+        },
+        (Some(existing), Some(planned)) => (
+            ArtifactStatus::Different,
+            None,
+            Some(diff_lines(&existing, &planned)),
+        ),
+    };
+    Some(AuditedArtifact {
+        kind,
+        name,
+        status,
+        detail,
+        diff,
+    })
+}
+
+fn is_shell_installer_user(name: &str) -> bool {
+    name.strip_prefix(SHELL_INSTALLER_USER_PREFIX)
+        .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn user_record(uid: u32, gid: u32, shell: &Path, home: &Path) -> String {
+    format!(
+        "uid = {uid}\ngid = {gid}\nshell = {}\nhome = {}\n",
+        shell.display(),
+        home.display()
+    )
+}
+
+/// Read a `:` separated database like `/etc/passwd`, keyed by the first field
+pub(crate) async fn read_database(path: &str) -> Result<HashMap<String, Vec<String>>, AuditError> {
+    let contents = match tokio::fs::read_to_string(host_path(path)).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(AuditError::Read(PathBuf::from(path), e)),
+    };
+    Ok(contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':').map(String::from);
+            let name = fields.next().filter(|name| !name.is_empty())?;
+            Some((name, fields.collect()))
+        })
+        .collect())
+}
+
+fn lookup_user(passwd: &HashMap<String, Vec<String>>, name: &str) -> Option<String> {
+    // `x:uid:gid:comment:home:shell`
+    if let Some(fields) = passwd.get(name) {
+        let field = |idx: usize| fields.get(idx).map(String::as_str).unwrap_or_default();
+        return Some(format!(
+            "uid = {}\ngid = {}\nshell = {}\nhome = {}\n",
+            field(1),
+            field(2),
+            field(5),
+            field(4)
+        ));
+    }
+    // macOS keeps users in Directory Services rather than `/etc/passwd`
+    #[cfg(target_os = "macos")]
+    if let Ok(Some(user)) = nix::unistd::User::from_name(name) {
+        return Some(user_record(
+            user.uid.as_raw(),
+            user.gid.as_raw(),
+            &user.shell,
+            &user.dir,
+        ));
+    }
+    None
+}
+
+fn lookup_group(group: &HashMap<String, Vec<String>>, name: &str) -> Option<String> {
+    // `x:gid:members`
+    if let Some(gid) = group.get(name).and_then(|fields| fields.get(1)) {
+        return Some(gid.clone());
+    }
+    #[cfg(target_os = "macos")]
+    if let Ok(Some(group)) = nix::unistd::Group::from_name(name) {
+        return Some(group.gid.as_raw().to_string());
+    }
+    None
+}
+
+/// The UID of the user `name`, if it exists
+pub(crate) fn lookup_uid(passwd: &HashMap<String, Vec<String>>, name: &str) -> Option<u32> {
+    if let Some(uid) = passwd.get(name).and_then(|fields| fields.get(1)) {
+        return uid.parse().ok();
+    }
+    #[cfg(target_os = "macos")]
+    if let Ok(Some(user)) = nix::unistd::User::from_name(name) {
+        return Some(user.uid.as_raw());
+    }
+    None
+}
+
+/// The GID of the group `name`, if it exists
+pub(crate) fn lookup_gid(group: &HashMap<String, Vec<String>>, name: &str) -> Option<u32> {
+    if let Some(gid) = group.get(name).and_then(|fields| fields.get(1)) {
+        return gid.parse().ok();
+    }
+    #[cfg(target_os = "macos")]
+    if let Ok(Some(group)) = nix::unistd::Group::from_name(name) {
+        return Some(group.gid.as_raw());
+    }
+    None
+}
+
+async fn read_text(path: &Path) -> Result<String, AuditError> {
+    let buf = tokio::fs::read(host_path(path))
+        .await
+        .map_err(|e| AuditError::Read(path.to_path_buf(), e))?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn render_settings(settings: &IndexMap<String, String>) -> String {
+    settings
+        .iter()
+        .map(|(name, value)| format!("{name} = {value}\n"))
+        .collect()
+}
+
+/// A line diff from `existing` to `planned`, each line prefixed by `-` (removed), `+` (added), or ` ` (kept)
+fn diff_lines(existing: &str, planned: &str) -> String {
+    let old = existing.lines().collect::<Vec<_>>();
+    let new = planned.lines().collect::<Vec<_>>();
+
+    // The length of the longest common subsequence of `old[i..]` and `new[j..]`
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push_str(&format!(" {}\n", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push_str(&format!("-{}\n", old[i]));
+            i += 1;
+        } else {
+            diff.push_str(&format!("+{}\n", new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[non_exhaustive]
+#[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
+pub enum AuditError {
+    #[error("Planning the install to compare against")]
+    Planner(
+        #[from]
+        #[source]
+        PlannerError,
+    ),
+    #[error("Serializing the plan")]
+    SerializingPlan(#[source] serde_json::Error),
+    #[error("Reading `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+}
+
+impl HasExpectedErrors for AuditError {
+    fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
+        match self {
+            AuditError::Planner(planner_error) => planner_error.expected(),
+            AuditError::SerializingPlan(_) => None,
+            AuditError::Read(_, _) => None,
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::{collections::BTreeMap, path::Path};
+
+    use crate::{test_harness::SandboxContext, InstallPlan};
+
+    use super::{
+        audit_actions, diff_lines, recognize_foreign_shell_hook, ArtifactKind, ArtifactStatus,
+        AuditReport,
+    };
+
+    const LINUX: &str = include_str!("../tests/fixtures/linux/linux.json");
+    const NIX_2_HOOK: &str =
+        include_str!("../tests/fixtures/shell-installer/nix-2.x-profile.d-nix.sh");
+    const NIX_1_HOOK: &str =
+        include_str!("../tests/fixtures/shell-installer/nix-1.x-profile.d-nix.sh");
+
+    const SHELL_INSTALLER_SNIPPET: &str = "\n# Nix\nif [ -e '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh' ]; then\n  . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\nfi\n# End Nix\n";
+    const PLANNED_SNIPPET: &str = "\n# Nix\nif [ -e '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh' ]; then\n    . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\nfi\n# End Nix\n\n        \n";
+
+    /// A host the shell installer was run on
+    fn shell_installer_host(sandbox: &SandboxContext) -> std::io::Result<()> {
+        let write = |path: &str, contents: &str| {
+            let path = sandbox.path(path);
+            std::fs::create_dir_all(path.parent().expect("Fixture paths have parents"))?;
+            std::fs::write(path, contents)
+        };
+        write(
+            "/etc/nix/nix.conf",
+            "build-users-group = nixbld\nexperimental-features = nix-command flakes\n",
+        )?;
+        write(
+            "/etc/bashrc",
+            &format!("# System bashrc\n{SHELL_INSTALLER_SNIPPET}"),
+        )?;
+        write("/etc/profile.d/nix.sh", PLANNED_SNIPPET)?;
+        write(
+            "/root/.nix-channels",
+            "https://nixos.org/channels/nixpkgs-unstable nixpkgs\n",
+        )?;
+        std::fs::create_dir_all(sandbox.path("/etc/systemd/system"))?;
+        std::os::unix::fs::symlink(
+            "/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service",
+            sandbox.path("/etc/systemd/system/nix-daemon.service"),
+        )?;
+        std::os::unix::fs::symlink(
+            "/nix/store/aaaa-nix-2.18.1/lib/systemd/system/nix-daemon.socket",
+            sandbox.path("/etc/systemd/system/nix-daemon.socket"),
+        )?;
+
+        let mut passwd = String::from("root:x:0:0:root:/root:/bin/bash\n");
+        let mut members = vec![];
+        // One more build user than `nix-installer` plans for
+        for n in 1..=33 {
+            passwd.push_str(&format!(
+                "nixbld{n}:x:{}:30000:Nix build user {n}:/var/empty:/sbin/nologin\n",
+                30000 + n
+            ));
+            members.push(format!("nixbld{n}"));
+        }
+        write("/etc/passwd", &passwd)?;
+        write(
+            "/etc/group",
+            &format!("root:x:0:\nnixbld:x:30000:{}\n", members.join(",")),
+        )?;
+        Ok(())
+    }
+
+    fn statuses(report: &AuditReport) -> BTreeMap<String, ArtifactStatus> {
+        report
+            .artifacts
+            .iter()
+            .map(|artifact| (artifact.name.clone(), artifact.status))
+            .collect()
+    }
+
+    /// Every file under `root`, with its contents (or link target)
+    fn snapshot(root: &Path) -> BTreeMap<String, String> {
+        walkdir::WalkDir::new(root)
+            .into_iter()
+            .filter_map(Result::ok)
+            .map(|entry| {
+                let contents = if entry.path_is_symlink() {
+                    format!("-> {:?}", std::fs::read_link(entry.path()).ok())
+                } else if entry.file_type().is_file() {
+                    std::fs::read_to_string(entry.path()).unwrap_or_default()
+                } else {
+                    "directory".to_string()
+                };
+                (entry.path().display().to_string(), contents)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn categorizes_shell_installer_artifacts() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        shell_installer_host(&sandbox)?;
+        let plan: InstallPlan = serde_json::from_str(LINUX)?;
+
+        let before = snapshot(sandbox.root());
+        let report = sandbox.scope(audit_actions("linux", &plan.actions)).await?;
+        assert_eq!(before, snapshot(sandbox.root()), "An audit must not write");
+
+        let statuses = statuses(&report);
+        assert_eq!(
+            statuses["/etc/systemd/system/nix-daemon.service"],
+            ArtifactStatus::Identical
+        );
+        assert_eq!(
+            statuses["/etc/systemd/system/nix-daemon.socket"],
+            ArtifactStatus::Different
+        );
+        assert_eq!(statuses["/etc/profile.d/nix.sh"], ArtifactStatus::Identical);
+        assert_eq!(statuses["/etc/bashrc"], ArtifactStatus::Different);
+        assert_eq!(statuses["/etc/nix/nix.conf"], ArtifactStatus::Different);
+        assert_eq!(statuses["/etc/zshrc"], ArtifactStatus::Missing);
+        assert_eq!(statuses["/root/.nix-channels"], ArtifactStatus::Unmanaged);
+        assert_eq!(statuses["nixbld"], ArtifactStatus::Identical);
+        assert_eq!(statuses["nixbld1"], ArtifactStatus::Identical);
+        assert_eq!(statuses["nixbld33"], ArtifactStatus::Unmanaged);
+
+        let nix_conf = report
+            .artifacts
+            .iter()
+            .find(|artifact| artifact.name == "/etc/nix/nix.conf")
+            .expect("nix.conf is audited");
+        let diff = nix_conf.diff.as_deref().expect("nix.conf has a diff");
+        assert!(diff.contains(" build-users-group = nixbld\n"), "{diff}");
+        assert!(diff.contains("+auto-optimise-store = true\n"), "{diff}");
+
+        let bashrc = report
+            .artifacts
+            .iter()
+            .find(|artifact| artifact.name == "/etc/bashrc")
+            .expect("bashrc is audited");
+        assert_eq!(bashrc.kind, ArtifactKind::File);
+        let diff = bashrc.diff.as_deref().expect("bashrc has a diff");
+        assert!(
+            diff.contains("+    . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\n"),
+            "{diff}"
+        );
+        assert!(diff.contains(" # System bashrc\n"), "{diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn recognizes_foreign_shell_hooks() {
+        let origin = |content: &str| recognize_foreign_shell_hook(content).map(|hook| hook.origin);
+        assert_eq!(origin(NIX_2_HOOK), Some("the Nix 2.x shell installer"));
+        assert_eq!(origin(NIX_1_HOOK), Some("a Nix 1.x multi-user install"));
+        assert_eq!(
+            origin(SHELL_INSTALLER_SNIPPET),
+            Some("the Nix 2.x shell installer")
+        );
+        // Our own hook, and a hook next to anything else, are not taken over
+        assert_eq!(origin(PLANNED_SNIPPET), None);
+        assert_eq!(origin(&format!("# System bashrc\n{NIX_2_HOOK}")), None);
+        assert_eq!(
+            origin(&NIX_2_HOOK.replace("fi\n", "fi\nexport FOO=1\n")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_a_foreign_hook_which_would_be_taken_over() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        shell_installer_host(&sandbox)?;
+        std::fs::write(sandbox.path("/etc/profile.d/nix.sh"), NIX_2_HOOK)?;
+        let plan: InstallPlan = serde_json::from_str(LINUX)?;
+
+        let report = sandbox.scope(audit_actions("linux", &plan.actions)).await?;
+        let profile = report
+            .artifacts
+            .iter()
+            .find(|artifact| artifact.name == "/etc/profile.d/nix.sh")
+            .expect("`/etc/profile.d/nix.sh` is audited");
+        assert_eq!(profile.status, ArtifactStatus::Different);
+        assert!(profile
+            .detail
+            .as_deref()
+            .is_some_and(|detail| detail.contains("the Nix 2.x shell installer")));
+        let diff = profile.diff.as_deref().expect("a diff");
+        assert!(
+            diff.contains("-  . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\n"),
+            "{diff}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_users_with_different_fields() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        shell_installer_host(&sandbox)?;
+        std::fs::write(
+            sandbox.path("/etc/passwd"),
+            "nixbld1:x:30001:30000:Nix build user 1:/var/empty:/bin/false\n",
+        )?;
+        let plan: InstallPlan = serde_json::from_str(LINUX)?;
+
+        let report = sandbox.scope(audit_actions("linux", &plan.actions)).await?;
+        let nixbld1 = report
+            .artifacts
+            .iter()
+            .find(|artifact| artifact.name == "nixbld1")
+            .expect("nixbld1 is audited");
+        assert_eq!(nixbld1.kind, ArtifactKind::User);
+        assert_eq!(nixbld1.status, ArtifactStatus::Different);
+        assert_eq!(
+            nixbld1.diff.as_deref(),
+            Some(" uid = 30001\n gid = 30000\n-shell = /bin/false\n+shell = /sbin/nologin\n home = /var/empty\n")
+        );
+        assert_eq!(statuses(&report)["nixbld2"], ArtifactStatus::Missing);
+        Ok(())
+    }
+
+    #[test]
+    fn diffs_lines() {
+        assert_eq!(diff_lines("a\nb\nc\n", "a\nc\nd\n"), " a\n-b\n c\n+d\n");
+        assert_eq!(diff_lines("", "a\n"), "+a\n");
+        assert_eq!(diff_lines("a\n", "a\n"), " a\n");
+    }
+}
@@ -0,0 +1,472 @@
+/*! Backups of the files `nix-installer` replaces or modifies
+
+Before an action changes a file it did not create, it backs up the original with a
+[`BackupStore`], and records the [`Backup`] in the receipt to restore it on revert. Content is
+stored in the `backups` directory of the [state directory](crate::state_dir), named by its
+SHA-256 hash, so the same content backed up twice is only stored once. Each backup is also listed
+in `backups/index.json`, which `nix-installer restore-backups` reads to restore them outside of an
+uninstall.
+
+Files like `nix.conf` can hold access tokens, so a stored copy keeps the mode and owner of the
+original rather than being readable by anyone who can read the state directory. The store is
+capped in size, backing up a file which would grow it past the cap is an error.
+*/
+
+use std::{
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use nix::unistd::{Gid, Uid};
+
+use crate::{
+    action::ActionErrorKind,
+    replace_file::{replace_file, Attributes, ReplaceFileError},
+    util::host_path,
+};
+
+/// The directory in the state directory which holds the backups
+pub(crate) const BACKUPS_DIR: &str = "backups";
+/// The list of every backup taken, in the order they were taken
+const INDEX: &str = "index.json";
+/// How large the stored backups may grow, far more than the configuration files backed up need
+pub(crate) const DEFAULT_CAP: u64 = 64 * 1024 * 1024;
+
+/// Where backups are stored, and how large they may grow
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct BackupStore {
+    dir: PathBuf,
+    cap: u64,
+}
+
+/// The original of a file replaced or modified by `nix-installer`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Backup {
+    /// The file which was backed up
+    pub path: PathBuf,
+    /// The SHA-256 hash of its content, which names the stored copy
+    pub hash: String,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// When it was backed up, in seconds since the Unix epoch
+    pub created: u64,
+}
+
+impl BackupStore {
+    /// The store in `state_dir`
+    pub fn new(state_dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: state_dir.as_ref().join(BACKUPS_DIR),
+            cap: DEFAULT_CAP,
+        }
+    }
+
+    pub fn with_cap(mut self, cap: u64) -> Self {
+        self.cap = cap;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn blob(&self, hash: &str) -> PathBuf {
+        host_path(self.dir.join(hash))
+    }
+
+    /// Back up the file at `path`, if there is one
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %path.display()))]
+    pub async fn back_up(&self, path: &Path) -> Result<Option<Backup>, BackupError> {
+        let host = host_path(path);
+        let metadata = match tokio::fs::symlink_metadata(&host).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(BackupError::Read(path.to_path_buf(), e)),
+        };
+        if !metadata.is_file() {
+            return Err(BackupError::NotAFile(path.to_path_buf()));
+        }
+
+        let content = tokio::fs::read(&host)
+            .await
+            .map_err(|e| BackupError::Read(path.to_path_buf(), e))?;
+        let backup = Backup {
+            path: path.to_path_buf(),
+            hash: sha256(&content),
+            size: content.len() as u64,
+            mode: metadata.permissions().mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|v| v.as_secs())
+                .unwrap_or_default(),
+        };
+
+        let dir = host_path(&self.dir);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| BackupError::Write(self.dir.clone(), e))?;
+        // Only `root` lists what was backed up, the copies themselves keep their original modes
+        tokio::fs::set_permissions(&dir, PermissionsExt::from_mode(0o700))
+            .await
+            .map_err(|e| BackupError::Write(self.dir.clone(), e))?;
+
+        let blob = self.blob(&backup.hash);
+        if !blob.exists() {
+            let used = self.used().await?;
+            if used + backup.size > self.cap {
+                return Err(BackupError::CapExceeded {
+                    path: backup.path,
+                    size: backup.size,
+                    used,
+                    cap: self.cap,
+                    dir: self.dir.clone(),
+                });
+            }
+            write_atomically(&blob, &content, backup.mode, backup.uid, backup.gid).await?;
+        }
+
+        let mut index = self.list().await?;
+        index.push(backup.clone());
+        let index_json = serde_json::to_vec_pretty(&index)
+            .map_err(|e| BackupError::Index(self.dir.join(INDEX), e))?;
+        write_atomically(
+            &host_path(self.dir.join(INDEX)),
+            &index_json,
+            0o600,
+            Uid::current().as_raw(),
+            Gid::current().as_raw(),
+        )
+        .await?;
+
+        tracing::debug!(hash = %backup.hash, "Backed up `{}`", path.display());
+        Ok(Some(backup))
+    }
+
+    /// Put `backup` back in place, with its original mode and owner
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %backup.path.display()))]
+    pub async fn restore(&self, backup: &Backup) -> Result<(), BackupError> {
+        let blob = self.blob(&backup.hash);
+        let content = match tokio::fs::read(&blob).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(BackupError::Missing {
+                    path: backup.path.clone(),
+                    blob,
+                })
+            },
+            Err(e) => return Err(BackupError::Read(blob, e)),
+        };
+        let found = sha256(&content);
+        if found != backup.hash {
+            return Err(BackupError::Corrupt {
+                path: backup.path.clone(),
+                blob,
+                expected: backup.hash.clone(),
+                found,
+            });
+        }
+
+        write_atomically(
+            &host_path(&backup.path),
+            &content,
+            backup.mode,
+            backup.uid,
+            backup.gid,
+        )
+        .await?;
+        tracing::debug!(hash = %backup.hash, "Restored `{}`", backup.path.display());
+        Ok(())
+    }
+
+    /// Every backup taken, oldest first
+    pub async fn list(&self) -> Result<Vec<Backup>, BackupError> {
+        let index = host_path(self.dir.join(INDEX));
+        let buf = match tokio::fs::read(&index).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(BackupError::Read(self.dir.join(INDEX), e)),
+        };
+        serde_json::from_slice(&buf).map_err(|e| BackupError::Index(self.dir.join(INDEX), e))
+    }
+
+    /// The bytes taken by the stored copies
+    pub async fn used(&self) -> Result<u64, BackupError> {
+        let mut used = 0;
+        let mut entries = match tokio::fs::read_dir(host_path(&self.dir)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(BackupError::Read(self.dir.clone(), e)),
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| BackupError::Read(self.dir.clone(), e))?
+        {
+            if entry.file_name() == INDEX {
+                continue;
+            }
+            let metadata = entry
+                .metadata()
+                .await
+                .map_err(|e| BackupError::Read(entry.path(), e))?;
+            used += metadata.len();
+        }
+        Ok(used)
+    }
+}
+
+/// The most recent backup of each path in `backups`, in the order they were first backed up
+pub(crate) fn latest(backups: &[Backup]) -> Vec<&Backup> {
+    let mut latest: Vec<&Backup> = vec![];
+    for backup in backups {
+        match latest.iter_mut().find(|v| v.path == backup.path) {
+            Some(existing) => *existing = backup,
+            None => latest.push(backup),
+        }
+    }
+    latest
+}
+
+/// Each file an action in `receipt` backed up, with where it was backed up to
+pub(crate) fn backup_locations(receipt: &serde_json::Value) -> Vec<(PathBuf, PathBuf)> {
+    let mut found = vec![];
+    collect_locations(receipt, &mut found);
+    found
+}
+
+fn collect_locations(value: &serde_json::Value, found: &mut Vec<(PathBuf, PathBuf)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let (
+                Some(serde_json::Value::String(path)),
+                Some(serde_json::Value::String(location)),
+            ) = (map.get("path"), map.get("backup_location"))
+            {
+                found.push((path.into(), location.into()));
+            }
+            for value in map.values() {
+                collect_locations(value, found);
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for value in values {
+                collect_locations(value, found);
+            }
+        },
+        _ => (),
+    }
+}
+
+pub(crate) fn sha256(content: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, content)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Write `content` to `path` with `mode` and owner, so it is never seen with a different mode or owner
+async fn write_atomically(
+    path: &Path,
+    content: &[u8],
+    mode: u32,
+    uid: u32,
+    gid: u32,
+) -> Result<(), BackupError> {
+    let attributes = Attributes {
+        mode,
+        uid: Some(Uid::from_raw(uid)),
+        gid: Some(Gid::from_raw(gid)),
+    };
+    replace_file(path, content, attributes).await?;
+    Ok(())
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("Cannot back up `{0}`, only regular files can be backed up")]
+    NotAFile(PathBuf),
+    #[error("Reading `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Writing `{0}`")]
+    Write(PathBuf, #[source] std::io::Error),
+    #[error(transparent)]
+    Replace(#[from] ReplaceFileError),
+    #[error("Backing up `{path}` ({size} bytes) would grow the backups in `{dir}` past their cap of {cap} bytes ({used} bytes are used); remove backups which are no longer needed from `{dir}` and try again", path = .path.display(), dir = .dir.display())]
+    CapExceeded {
+        path: PathBuf,
+        size: u64,
+        used: u64,
+        cap: u64,
+        dir: PathBuf,
+    },
+    #[error("The backup of `{path}` is missing, it should be at `{blob}`", path = .path.display(), blob = .blob.display())]
+    Missing { path: PathBuf, blob: PathBuf },
+    #[error("The backup of `{path}` at `{blob}` is corrupt, its SHA-256 hash is {found} rather than {expected}", path = .path.display(), blob = .blob.display())]
+    Corrupt {
+        path: PathBuf,
+        blob: PathBuf,
+        expected: String,
+        found: String,
+    },
+    #[error("Parsing the backup index `{0}`")]
+    Index(PathBuf, #[source] serde_json::Error),
+}
+
+impl From<BackupError> for ActionErrorKind {
+    fn from(val: BackupError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use super::{latest, BackupError, BackupStore};
+    use crate::test_harness::SandboxContext;
+
+    const STATE_DIR: &str = "/nix/var/nix-installer";
+
+    #[tokio::test]
+    async fn backs_up_and_restores_with_permissions() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let path = Path::new("/etc/secret.conf");
+        std::fs::write(sandbox.path(path), "access-tokens = github.com=hunter2\n")?;
+        std::fs::set_permissions(sandbox.path(path), PermissionsExt::from_mode(0o600))?;
+        let store = BackupStore::new(STATE_DIR);
+
+        let backup = sandbox
+            .scope(store.back_up(path))
+            .await?
+            .expect("the file exists");
+        assert_eq!(backup.mode, 0o600);
+        // The stored copy is no more readable than the original
+        let blob = sandbox.path(store.dir().join(&backup.hash));
+        assert_eq!(
+            std::fs::metadata(&blob)?.permissions().mode() & 0o777,
+            0o600
+        );
+
+        std::fs::write(sandbox.path(path), "replaced")?;
+        std::fs::set_permissions(sandbox.path(path), PermissionsExt::from_mode(0o644))?;
+        sandbox.scope(store.restore(&backup)).await?;
+        assert_eq!(
+            std::fs::read_to_string(sandbox.path(path))?,
+            "access-tokens = github.com=hunter2\n"
+        );
+        assert_eq!(
+            std::fs::metadata(sandbox.path(path))?.permissions().mode() & 0o777,
+            0o600
+        );
+
+        // A missing file has nothing to back up
+        assert_eq!(
+            sandbox
+                .scope(store.back_up(Path::new("/etc/missing.conf")))
+                .await?,
+            None
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn identical_content_is_stored_once() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::write(sandbox.path("/etc/a.conf"), "same")?;
+        std::fs::write(sandbox.path("/etc/b.conf"), "same")?;
+        let store = BackupStore::new(STATE_DIR);
+
+        let a = sandbox
+            .scope(store.back_up(Path::new("/etc/a.conf")))
+            .await?;
+        let b = sandbox
+            .scope(store.back_up(Path::new("/etc/b.conf")))
+            .await?;
+        assert_eq!(a.map(|v| v.hash), b.map(|v| v.hash));
+        assert_eq!(sandbox.scope(store.used()).await?, 4);
+        assert_eq!(sandbox.scope(store.list()).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backups_are_capped() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::write(sandbox.path("/etc/a.conf"), "0123456789")?;
+        std::fs::write(sandbox.path("/etc/b.conf"), "abcdefghij")?;
+        let store = BackupStore::new(STATE_DIR).with_cap(15);
+
+        sandbox
+            .scope(store.back_up(Path::new("/etc/a.conf")))
+            .await?;
+        let err = sandbox
+            .scope(store.back_up(Path::new("/etc/b.conf")))
+            .await
+            .expect_err("the second backup exceeds the cap");
+        assert!(matches!(
+            err,
+            BackupError::CapExceeded {
+                size: 10,
+                used: 10,
+                cap: 15,
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("/etc/b.conf"));
+        // Nothing is recorded for the backup which was refused
+        assert_eq!(sandbox.scope(store.list()).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_backups_are_not_restored() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let path = Path::new("/etc/a.conf");
+        std::fs::write(sandbox.path(path), "original")?;
+        let store = BackupStore::new(STATE_DIR);
+
+        let backup = sandbox
+            .scope(store.back_up(path))
+            .await?
+            .expect("the file exists");
+        std::fs::write(sandbox.path(store.dir().join(&backup.hash)), "tampered")?;
+        std::fs::write(sandbox.path(path), "replaced")?;
+
+        let err = sandbox
+            .scope(store.restore(&backup))
+            .await
+            .expect_err("the stored copy no longer matches its hash");
+        assert!(matches!(err, BackupError::Corrupt { .. }));
+        assert_eq!(std::fs::read_to_string(sandbox.path(path))?, "replaced");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn latest_backup_of_each_path() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let store = BackupStore::new(STATE_DIR);
+        for (path, content) in [
+            ("/etc/a.conf", "a1"),
+            ("/etc/b.conf", "b1"),
+            ("/etc/a.conf", "a2"),
+        ] {
+            std::fs::write(sandbox.path(path), content)?;
+            sandbox.scope(store.back_up(Path::new(path))).await?;
+        }
+
+        let backups = sandbox.scope(store.list()).await?;
+        let latest = latest(&backups);
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].path, Path::new("/etc/a.conf"));
+        assert_eq!(latest[0].hash, backups[2].hash);
+        assert_eq!(latest[1].path, Path::new("/etc/b.conf"));
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+/*! What this `nix-installer` was built with
+
+Packagers build `nix-installer` with different features and payloads, which change how it behaves,
+so `nix-installer --build-info` prints them for bug reports. The same [`BuildInfo`] is recorded in
+the receipt and sent with diagnostics.
+
+It is captured by the `build.rs` at build time, except for the hash of the embedded
+`determinate-nixd`, which is computed the first time it is asked for.
+*/
+
+use once_cell::sync::Lazy;
+
+/// The cargo features enabled, comma delimited
+pub const FEATURES: &str = env!("NIX_INSTALLER_BUILD_FEATURES");
+/// The target triple built for
+pub const TARGET: &str = env!("NIX_INSTALLER_BUILD_TARGET");
+/// The output of `rustc --version` for the compiler used
+pub const RUSTC: &str = env!("NIX_INSTALLER_BUILD_RUSTC");
+/// The git revision built, or `unknown` if it was built outside of a checkout
+pub const GIT_REVISION: &str = env!("NIX_INSTALLER_BUILD_GIT_REVISION");
+/// The version of the embedded `determinate-nixd`, if the packager set `DETERMINATE_NIXD_VERSION`
+pub const DETERMINATE_NIXD_VERSION: Option<&str> =
+    option_env!("NIX_INSTALLER_BUILD_DETERMINATE_NIXD_VERSION");
+
+static CURRENT: Lazy<BuildInfo> = Lazy::new(|| BuildInfo {
+    version: env!("CARGO_PKG_VERSION").into(),
+    features: FEATURES
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .map(String::from)
+        .collect(),
+    target: TARGET.into(),
+    rustc: RUSTC.into(),
+    git_revision: GIT_REVISION.into(),
+    determinate_nixd: crate::settings::DETERMINATE_NIXD_BINARY.map(|binary| {
+        DeterminateNixdPayload {
+            version: DETERMINATE_NIXD_VERSION.map(String::from),
+            sha256: crate::backup::sha256(binary),
+            size: binary.len() as u64,
+        }
+    }),
+});
+
+/// How a `nix-installer` was built
+#[derive(Debug, Clone, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// The cargo features enabled, sorted
+    pub features: Vec<String>,
+    pub target: String,
+    pub rustc: String,
+    pub git_revision: String,
+    /// The embedded `determinate-nixd`, absent if it was built without one
+    pub determinate_nixd: Option<DeterminateNixdPayload>,
+}
+
+/// The `determinate-nixd` binary embedded in `nix-installer`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct DeterminateNixdPayload {
+    /// Absent unless the packager set `DETERMINATE_NIXD_VERSION`
+    pub version: Option<String>,
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl BuildInfo {
+    /// How this `nix-installer` was built
+    pub fn current() -> &'static BuildInfo {
+        &CURRENT
+    }
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            version,
+            features,
+            target,
+            rustc,
+            git_revision,
+            determinate_nixd,
+        } = self;
+        writeln!(f, "nix-installer {version}")?;
+        let features = match features.is_empty() {
+            true => "(none)".to_string(),
+            false => features.join(", "),
+        };
+        writeln!(f, "features: {features}")?;
+        writeln!(f, "target: {target}")?;
+        writeln!(f, "rustc: {rustc}")?;
+        writeln!(f, "git revision: {git_revision}")?;
+        match determinate_nixd {
+            Some(DeterminateNixdPayload {
+                version,
+                sha256,
+                size,
+            }) => write!(
+                f,
+                "determinate-nixd: embedded, version {}, sha256 {sha256}, {size} bytes",
+                version.as_deref().unwrap_or("unknown"),
+            ),
+            None => write!(f, "determinate-nixd: not embedded"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BuildInfo;
+
+    #[test]
+    fn current_is_populated() {
+        let current = BuildInfo::current();
+        assert_eq!(current.version, env!("CARGO_PKG_VERSION"));
+        assert!(!current.target.is_empty());
+        assert!(current.rustc.starts_with("rustc "), "{}", current.rustc);
+        assert!(!current.git_revision.is_empty());
+        #[cfg(feature = "diagnostics")]
+        assert!(current
+            .features
+            .iter()
+            .any(|feature| feature == "diagnostics"));
+        let mut sorted = current.features.clone();
+        sorted.sort();
+        assert_eq!(current.features, sorted);
+        assert_eq!(
+            current.determinate_nixd.is_some(),
+            crate::settings::DETERMINATE_NIXD_BINARY.is_some()
+        );
+        if let Some(payload) = &current.determinate_nixd {
+            assert_eq!(payload.sha256.len(), 64);
+        }
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn diagnostics_report_the_current_build() -> Result<(), crate::diagnostics::DiagnosticError> {
+        use crate::diagnostics::{DiagnosticAction, DiagnosticData, DiagnosticStatus};
+
+        let report = DiagnosticData::new(None, None, "linux".into(), vec![], None, None)?
+            .report(DiagnosticAction::Install, DiagnosticStatus::Pending);
+        assert_eq!(&report.build_info, BuildInfo::current());
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+/// How a plan is described by `--format`
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DescriptionFormat {
+    /// Prose, for reviewing
+    #[default]
+    Human,
+    /// A JSON document, for tools
+    Json,
+}
+
+impl std::fmt::Display for DescriptionFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = match self {
+            DescriptionFormat::Human => "human",
+            DescriptionFormat::Json => "json",
+        };
+        write!(f, "{}", format)
+    }
+}
@@ -3,9 +3,11 @@ use std::error::Error;
 use std::io::IsTerminal;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{
-    filter::Directive, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+    filter::Directive, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
 };
 
+use crate::cli::run_log::{self, RunLog};
+
 #[derive(Clone, Default, Debug, clap::ValueEnum)]
 pub enum Logger {
     #[default]
@@ -40,6 +42,11 @@ pub struct Instrumentation {
     /// See https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
     #[clap(long = "log-directive", global = true, env = "NIX_INSTALLER_LOG_DIRECTIVES", value_delimiter = ',', num_args = 0..)]
     pub log_directives: Vec<Directive>,
+    /// Trace everything the actions with this tag (such as `create_or_insert_into_file`) do, may be repeated
+    ///
+    /// Everything else is logged at the configured level.
+    #[clap(long = "trace-actions", global = true, env = "NIX_INSTALLER_TRACE_ACTIONS", value_delimiter = ',', action = clap::ArgAction::Append, value_parser = action_tag_parser)]
+    pub trace_actions: Vec<String>,
 }
 
 impl Instrumentation {
@@ -52,29 +59,38 @@ impl Instrumentation {
         .to_string()
     }
 
+    /// Log to the console at the configured level, and to the [run log](crate::cli::run_log) at `DEBUG`
     pub fn setup(&self) -> eyre::Result<()> {
         let filter_layer = self.filter_layer()?;
 
         let registry = tracing_subscriber::registry()
-            .with(filter_layer)
-            .with(ErrorLayer::default());
+            .with(ErrorLayer::default())
+            .with(run_log::layer(RunLog::global().clone()));
 
         match self.logger {
             Logger::Compact => {
                 let fmt_layer = self.fmt_layer_compact();
-                registry.with(fmt_layer).try_init()?
+                registry
+                    .with(fmt_layer.with_filter(filter_layer))
+                    .try_init()?
             },
             Logger::Full => {
                 let fmt_layer = self.fmt_layer_full();
-                registry.with(fmt_layer).try_init()?
+                registry
+                    .with(fmt_layer.with_filter(filter_layer))
+                    .try_init()?
             },
             Logger::Pretty => {
                 let fmt_layer = self.fmt_layer_pretty();
-                registry.with(fmt_layer).try_init()?
+                registry
+                    .with(fmt_layer.with_filter(filter_layer))
+                    .try_init()?
             },
             Logger::Json => {
                 let fmt_layer = self.fmt_layer_json();
-                registry.with(fmt_layer).try_init()?
+                registry
+                    .with(fmt_layer.with_filter(filter_layer))
+                    .try_init()?
             },
         }
 
@@ -150,6 +166,91 @@ impl Instrumentation {
             filter_layer = filter_layer.add_directive(directive_clone);
         }
 
+        for directive in self.trace_action_directives()? {
+            filter_layer = filter_layer.add_directive(directive);
+        }
+
         Ok(filter_layer)
     }
+
+    /// Directives enabling `TRACE` within the spans of the actions named by `--trace-actions`
+    ///
+    /// Action spans are named after their tag, and a span directive also covers the actions nested
+    /// within it. Only events from `nix-installer` itself are traced, not those of the HTTP stack.
+    pub fn trace_action_directives(&self) -> eyre::Result<Vec<Directive>> {
+        self.trace_actions
+            .iter()
+            .map(|tag| {
+                format!("{}[{tag}]=trace", env!("CARGO_PKG_NAME").replace('-', "_"))
+                    .parse::<Directive>()
+                    .wrap_err_with(|| format!("Tracing action `{tag}`"))
+            })
+            .collect()
+    }
+}
+
+fn action_tag_parser(input: &str) -> Result<String, String> {
+    if !input.is_empty()
+        && input
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        Ok(input.to_string())
+    } else {
+        Err(format!(
+            "`{input}` is not an action tag, such as `create_or_insert_into_file`"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::Instrumentation;
+
+    #[derive(Debug, Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        instrumentation: Instrumentation,
+    }
+
+    #[test]
+    fn trace_actions_become_span_directives() -> eyre::Result<()> {
+        let cli = Cli::try_parse_from([
+            "nix-installer",
+            "--trace-actions",
+            "create_or_insert_into_file",
+            "--trace-actions",
+            "configure_init_service,provision_nix",
+        ])?;
+        let directives = cli
+            .instrumentation
+            .trace_action_directives()?
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            directives,
+            [
+                "nix_installer[create_or_insert_into_file]=trace",
+                "nix_installer[configure_init_service]=trace",
+                "nix_installer[provision_nix]=trace",
+            ]
+        );
+
+        let filter = cli.instrumentation.filter_layer()?.to_string();
+        assert!(
+            filter.contains("nix_installer[provision_nix]=trace"),
+            "{filter}"
+        );
+
+        assert!(Cli::try_parse_from([
+            "nix-installer",
+            "--trace-actions",
+            "provision_nix]=trace,h2"
+        ])
+        .is_err());
+        Ok(())
+    }
 }
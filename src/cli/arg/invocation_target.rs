@@ -0,0 +1,22 @@
+/// What `--emit-invocation` formats the invocation for
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum InvocationTarget {
+    /// A shell command, as for a Packer provisioner
+    #[default]
+    Shell,
+    /// `#cloud-config` user data, as for Terraform's `user_data`
+    CloudInit,
+    /// A step using `DeterminateSystems/nix-installer-action`
+    GithubAction,
+}
+
+impl std::fmt::Display for InvocationTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let target = match self {
+            InvocationTarget::Shell => "shell",
+            InvocationTarget::CloudInit => "cloud-init",
+            InvocationTarget::GithubAction => "github-action",
+        };
+        write!(f, "{}", target)
+    }
+}
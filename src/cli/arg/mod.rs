@@ -1,2 +1,6 @@
+mod format;
 mod instrumentation;
+mod invocation_target;
+pub(crate) use format::DescriptionFormat;
 pub(crate) use instrumentation::Instrumentation;
+pub(crate) use invocation_target::InvocationTarget;
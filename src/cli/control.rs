@@ -0,0 +1,834 @@
+/*! Driving `install` and `uninstall` from another program over a unix socket
+
+With `--control-socket <path>`, `nix-installer` listens on `<path>` for a single controller, such as
+a graphical frontend, and asks it rather than the terminal. Each message is one line of JSON with a
+`type`. `nix-installer` sends:
+
+* `prompt`: an `id`, the `description` the terminal would show (without colors), the `choices`
+  offered (some of `yes`, `no`, and `explain`), and the `default`
+* `progress`: the same `completed`, `total`, `elapsed_ms`, and `event` as the `progress` document of
+  `--report-to` (see [`ProgressEvent`])
+* `result`: a `status` of `success`, `failure`, `declined`, or `cancelled`, and the `error` if it failed
+
+The controller sends:
+
+* `answer`: the `id` of a prompt and the `choice` made
+* `cancel`: stop at the next action, as if `nix-installer` got `SIGINT`
+
+A controller which disconnects or requests cancellation declines every prompt, and cancels any
+install or uninstall in progress. A prompt unanswered for [`ANSWER_TIMEOUT`] is declined too. As
+the controller answers for root, the socket is only created in a directory owned by root and
+writable by nobody else, and only root may connect to it.
+*/
+
+use std::{
+    collections::HashMap,
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use nix::unistd::Uid;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
+    sync::{
+        broadcast::Sender,
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use crate::{cli::interaction, report::ProgressEvent};
+
+use super::interaction::PromptChoice;
+
+/// How long to wait for a controller to connect
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a prompt may go unanswered before it is declined
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How long a single message may take to send before the controller is considered gone
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A message sent to the controller
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ControlMessage {
+    Prompt {
+        id: u64,
+        description: String,
+        choices: Vec<PromptChoice>,
+        default: PromptChoice,
+    },
+    Progress {
+        completed: usize,
+        total: usize,
+        elapsed_ms: u64,
+        event: ProgressEvent,
+    },
+    Result {
+        status: ControlOutcome,
+        error: Option<String>,
+    },
+}
+
+/// A request received from the controller
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ControlRequest {
+    Answer { id: u64, choice: PromptChoice },
+    Cancel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ControlOutcome {
+    Success,
+    Failure,
+    Declined,
+    Cancelled,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    writer: tokio::sync::Mutex<Option<OwnedWriteHalf>>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<PromptChoice>>>,
+    /// Set once the controller requested cancellation or disconnected
+    cancelled: AtomicBool,
+    cancel_channel: Mutex<Option<Sender<()>>>,
+}
+
+impl Shared {
+    async fn send(&self, message: &ControlMessage) -> bool {
+        let mut line = match serde_json::to_string(message) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::debug!(%e, "Failed to serialize control message");
+                return false;
+            },
+        };
+        line.push('\n');
+        let mut writer = self.writer.lock().await;
+        let Some(stream) = writer.as_mut() else {
+            return false;
+        };
+        match tokio::time::timeout(WRITE_TIMEOUT, stream.write_all(line.as_bytes())).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                tracing::warn!(%e, "Lost the controller");
+                *writer = None;
+                false
+            },
+            Err(_) => {
+                tracing::warn!("Controller stopped reading messages, treating it as gone");
+                *writer = None;
+                false
+            },
+        }
+    }
+
+    /// Decline every prompt from now on, and cancel what is in progress
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        // Dropping the senders declines the waiting prompts
+        self.pending
+            .lock()
+            .expect("Control prompts lock poisoned")
+            .clear();
+        if let Some(cancel_channel) = &*self
+            .cancel_channel
+            .lock()
+            .expect("Control cancel lock poisoned")
+        {
+            cancel_channel.send(()).ok();
+        }
+    }
+}
+
+/// A connected controller
+#[derive(Debug)]
+pub(crate) struct Control {
+    shared: Arc<Shared>,
+    next_id: AtomicU64,
+    started: Instant,
+    answer_timeout: Duration,
+    progress: Mutex<Option<(UnboundedSender<ProgressEvent>, JoinHandle<()>)>>,
+}
+
+impl Control {
+    /// Listen on `path` and wait for a controller to connect
+    #[tracing::instrument(level = "debug", skip_all, fields(path = %path.display()))]
+    pub(crate) async fn listen(path: &Path) -> Result<Self, ControlSocketError> {
+        Self::listen_owned_by(path, Uid::from_raw(0)).await
+    }
+
+    async fn listen_owned_by(path: &Path, owner: Uid) -> Result<Self, ControlSocketError> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => return Err(ControlSocketError::NoParent(path.to_path_buf())),
+        };
+        check_socket_dir(dir, owner)?;
+
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                tracing::debug!("Removing stale control socket `{}`", path.display());
+                tokio::fs::remove_file(path)
+                    .await
+                    .map_err(|e| ControlSocketError::Bind(path.to_path_buf(), e))?;
+            },
+            Ok(_) => return Err(ControlSocketError::Exists(path.to_path_buf())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(ControlSocketError::Bind(path.to_path_buf(), e)),
+        }
+
+        let listener = UnixListener::bind(path)
+            .map_err(|e| ControlSocketError::Bind(path.to_path_buf(), e))?;
+        let accepted = async {
+            tokio::fs::set_permissions(path, PermissionsExt::from_mode(0o600))
+                .await
+                .map_err(|e| ControlSocketError::Bind(path.to_path_buf(), e))?;
+            tracing::info!(
+                "Waiting for a controller to connect to `{}`",
+                path.display()
+            );
+            match tokio::time::timeout(ACCEPT_TIMEOUT, listener.accept()).await {
+                Ok(Ok((stream, _))) => Ok(stream),
+                Ok(Err(e)) => Err(ControlSocketError::Accept(path.to_path_buf(), e)),
+                Err(_) => Err(ControlSocketError::NoController(
+                    path.to_path_buf(),
+                    ACCEPT_TIMEOUT.as_secs(),
+                )),
+            }
+        }
+        .await;
+        // Nobody else may connect once a controller has, or if none did
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            tracing::debug!(%e, "Could not remove control socket `{}`", path.display());
+        }
+
+        Ok(Self::new(accepted?))
+    }
+
+    /// Take prompts and send progress over an already connected `stream`
+    pub(crate) fn new(stream: UnixStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        let shared = Arc::new(Shared {
+            writer: tokio::sync::Mutex::new(Some(writer)),
+            ..Default::default()
+        });
+        tokio::spawn(read_requests(reader, shared.clone()));
+        Self {
+            shared,
+            next_id: AtomicU64::new(0),
+            started: Instant::now(),
+            answer_timeout: ANSWER_TIMEOUT,
+            progress: Mutex::new(None),
+        }
+    }
+
+    /// Send a cancellation requested by the controller (or its disconnection) on `cancel_channel`
+    pub(crate) fn cancel_with(&self, cancel_channel: Sender<()>) {
+        if self.shared.cancelled.load(Ordering::SeqCst) {
+            cancel_channel.send(()).ok();
+        }
+        *self
+            .shared
+            .cancel_channel
+            .lock()
+            .expect("Control cancel lock poisoned") = Some(cancel_channel);
+    }
+
+    /// Ask the controller `question`, declining if it is gone or does not answer in time
+    pub(crate) async fn prompt(
+        &self,
+        question: &str,
+        default: PromptChoice,
+        currently_explaining: bool,
+    ) -> PromptChoice {
+        let mut choices = vec![PromptChoice::Yes, PromptChoice::No];
+        if !currently_explaining {
+            choices.push(PromptChoice::Explain);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut pending = self
+                .shared
+                .pending
+                .lock()
+                .expect("Control prompts lock poisoned");
+            // Checked while holding the lock, so a cancellation cannot miss this prompt
+            if self.shared.cancelled.load(Ordering::SeqCst) {
+                return PromptChoice::No;
+            }
+            pending.insert(id, sender);
+        }
+
+        let message = ControlMessage::Prompt {
+            id,
+            description: strip_ansi(question),
+            choices: choices.clone(),
+            default,
+        };
+        if !self.shared.send(&message).await {
+            self.shared.cancel();
+            return PromptChoice::No;
+        }
+
+        match tokio::time::timeout(self.answer_timeout, receiver).await {
+            Ok(Ok(choice)) if choices.contains(&choice) => choice,
+            Ok(Ok(choice)) => {
+                tracing::warn!(
+                    ?choice,
+                    "Controller answered with a choice it was not offered"
+                );
+                PromptChoice::No
+            },
+            // The controller cancelled or disconnected
+            Ok(Err(_)) => PromptChoice::No,
+            Err(_) => {
+                tracing::warn!(
+                    "Controller did not answer within {} seconds",
+                    self.answer_timeout.as_secs()
+                );
+                self.shared
+                    .pending
+                    .lock()
+                    .expect("Control prompts lock poisoned")
+                    .remove(&id);
+                PromptChoice::No
+            },
+        }
+    }
+
+    /// A channel for [`InstallPlan::install_with_progress`](crate::InstallPlan::install_with_progress), each event is sent as a `progress` message
+    pub(crate) fn progress(&self) -> UnboundedSender<ProgressEvent> {
+        let mut progress = self
+            .progress
+            .lock()
+            .expect("Control progress lock poisoned");
+        if let Some((sender, _)) = &*progress {
+            return sender.clone();
+        }
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let task = tokio::spawn(forward_progress(
+            self.shared.clone(),
+            self.started,
+            receiver,
+        ));
+        *progress = Some((sender.clone(), task));
+        sender
+    }
+
+    /// Send the `result` message for the outcome of the command, after any outstanding progress
+    pub(crate) async fn finish(&self, result: &eyre::Result<ExitCode>) {
+        let (status, error) = match result {
+            _ if self.shared.cancelled.load(Ordering::SeqCst) => (ControlOutcome::Cancelled, None),
+            Ok(exit_code) if *exit_code == ExitCode::SUCCESS => (ControlOutcome::Success, None),
+            // Expected failures were already explained on stderr
+            Ok(_) => (ControlOutcome::Failure, None),
+            Err(e) => (ControlOutcome::Failure, Some(format!("{e:#}"))),
+        };
+        self.send_result(status, error).await
+    }
+
+    /// Send a `declined` result, for when a prompt was answered `no`
+    pub(crate) async fn declined(&self) {
+        let status = match self.shared.cancelled.load(Ordering::SeqCst) {
+            true => ControlOutcome::Cancelled,
+            false => ControlOutcome::Declined,
+        };
+        self.send_result(status, None).await
+    }
+
+    async fn send_result(&self, status: ControlOutcome, error: Option<String>) {
+        let progress = self
+            .progress
+            .lock()
+            .expect("Control progress lock poisoned")
+            .take();
+        if let Some((sender, task)) = progress {
+            drop(sender);
+            task.await.ok();
+        }
+        self.shared
+            .send(&ControlMessage::Result { status, error })
+            .await;
+        if let Some(mut stream) = self.shared.writer.lock().await.take() {
+            stream.shutdown().await.ok();
+        }
+    }
+}
+
+/// Ask `control` if there is one, otherwise the terminal
+pub(crate) async fn prompt(
+    control: Option<&Control>,
+    question: impl AsRef<str>,
+    default: PromptChoice,
+    currently_explaining: bool,
+) -> eyre::Result<PromptChoice> {
+    match control {
+        Some(control) => Ok(control
+            .prompt(question.as_ref(), default, currently_explaining)
+            .await),
+        None => interaction::prompt(question, default, currently_explaining).await,
+    }
+}
+
+/// Tell `control`, if there is one, that a prompt was declined, then exit with `message`
+pub(crate) async fn exit_declined(control: Option<&Control>, message: impl AsRef<str>) -> ! {
+    if let Some(control) = control {
+        control.declined().await;
+    }
+    interaction::clean_exit_with_message(message).await
+}
+
+/// Dispatch each request from the controller until it disconnects
+async fn read_requests(reader: OwnedReadHalf, shared: Arc<Shared>) {
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                tracing::warn!("Controller disconnected, cancelling");
+                break;
+            },
+            Err(e) => {
+                tracing::warn!(%e, "Lost the controller, cancelling");
+                break;
+            },
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::Answer { id, choice }) => {
+                let sender = shared
+                    .pending
+                    .lock()
+                    .expect("Control prompts lock poisoned")
+                    .remove(&id);
+                match sender {
+                    Some(sender) => {
+                        sender.send(choice).ok();
+                    },
+                    None => {
+                        tracing::debug!(id, "Controller answered a prompt which is not waiting")
+                    },
+                }
+            },
+            Ok(ControlRequest::Cancel) => {
+                tracing::warn!("Controller requested cancellation");
+                shared.cancel();
+            },
+            Err(e) => tracing::warn!(%e, "Ignoring a malformed request from the controller"),
+        }
+    }
+    shared.cancel();
+}
+
+/// Send a `progress` message for each event, until the channel closes
+async fn forward_progress(
+    shared: Arc<Shared>,
+    started: Instant,
+    mut receiver: UnboundedReceiver<ProgressEvent>,
+) {
+    while let Some(event) = receiver.recv().await {
+        let (completed, total) = match &event {
+            ProgressEvent::ActionStarted { index, total, .. }
+            | ProgressEvent::SubActionStarted { index, total, .. }
+            | ProgressEvent::SubActionFinished { index, total, .. } => (*index, *total),
+            ProgressEvent::ActionFinished { index, total, .. }
+            | ProgressEvent::Warning { index, total, .. } => (index + 1, *total),
+        };
+        shared
+            .send(&ControlMessage::Progress {
+                completed,
+                total,
+                elapsed_ms: started.elapsed().as_millis().try_into().unwrap_or(u64::MAX),
+                event,
+            })
+            .await;
+    }
+}
+
+/// Refuse a directory for the socket which anyone but `owner` could swap the socket out of
+fn check_socket_dir(dir: &Path, owner: Uid) -> Result<(), ControlSocketError> {
+    let metadata = std::fs::metadata(dir)
+        .map_err(|e| ControlSocketError::DirMetadata(dir.to_path_buf(), e))?;
+    if metadata.uid() != owner.as_raw() {
+        return Err(ControlSocketError::DirNotRootOwned(
+            dir.to_path_buf(),
+            metadata.uid(),
+        ));
+    }
+    let mode = metadata.mode() & 0o777;
+    if mode & 0o022 != 0 {
+        return Err(ControlSocketError::DirWritable(dir.to_path_buf(), mode));
+    }
+    Ok(())
+}
+
+/// Remove the terminal color and style escapes from `text`
+fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' && chars.peek() == Some(&'[') {
+            chars.next();
+            // Parameters and intermediates, up to the final byte
+            for c in chars.by_ref() {
+                if ('\u{40}'..='\u{7e}').contains(&c) {
+                    break;
+                }
+            }
+            continue;
+        }
+        stripped.push(c);
+    }
+    stripped
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ControlSocketError {
+    #[error("The control socket `{0}` must be in a directory")]
+    NoParent(PathBuf),
+    #[error("Getting the metadata of `{0}`, the directory of the control socket")]
+    DirMetadata(PathBuf, #[source] std::io::Error),
+    #[error(
+        "The directory of the control socket, `{0}`, must be owned by root, it is owned by UID {1}"
+    )]
+    DirNotRootOwned(PathBuf, u32),
+    #[error("The directory of the control socket, `{0}`, must only be writable by its owner, its mode is `{1:#o}`")]
+    DirWritable(PathBuf, u32),
+    #[error("`{0}` exists and is not a socket, refusing to replace it with the control socket")]
+    Exists(PathBuf),
+    #[error("Listening on the control socket `{0}`")]
+    Bind(PathBuf, #[source] std::io::Error),
+    #[error("Accepting a controller on `{0}`")]
+    Accept(PathBuf, #[source] std::io::Error),
+    #[error("No controller connected to `{0}` within {1} seconds")]
+    NoController(PathBuf, u64),
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::fs::PermissionsExt, process::ExitCode, time::Duration};
+
+    use nix::unistd::Uid;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixStream,
+    };
+
+    use super::{
+        check_socket_dir, strip_ansi, Control, ControlMessage, ControlOutcome, ControlRequest,
+        ControlSocketError,
+    };
+    use crate::{
+        cli::interaction::PromptChoice,
+        report::{ActionOutcome, ProgressEvent},
+    };
+
+    /// The controller end of a socketpair, reading messages and sending requests
+    struct FakeController {
+        lines: tokio::io::Lines<BufReader<tokio::net::unix::OwnedReadHalf>>,
+        writer: tokio::net::unix::OwnedWriteHalf,
+    }
+
+    impl FakeController {
+        fn pair() -> std::io::Result<(Control, Self)> {
+            let (ours, theirs) = UnixStream::pair()?;
+            let (reader, writer) = theirs.into_split();
+            Ok((
+                Control::new(ours),
+                Self {
+                    lines: BufReader::new(reader).lines(),
+                    writer,
+                },
+            ))
+        }
+
+        async fn receive(&mut self) -> eyre::Result<Option<ControlMessage>> {
+            match self.lines.next_line().await? {
+                Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+                None => Ok(None),
+            }
+        }
+
+        async fn request(&mut self, request: &ControlRequest) -> eyre::Result<()> {
+            let mut line = serde_json::to_string(request)?;
+            line.push('\n');
+            self.writer.write_all(line.as_bytes()).await?;
+            Ok(())
+        }
+    }
+
+    fn started(index: usize) -> ProgressEvent {
+        ProgressEvent::ActionStarted {
+            index,
+            total: 2,
+            action: "create_file".into(),
+            synopsis: "Create `/etc/nix/nix.conf`".into(),
+        }
+    }
+
+    fn finished(index: usize) -> ProgressEvent {
+        ProgressEvent::ActionFinished {
+            index,
+            total: 2,
+            action: "create_file".into(),
+            synopsis: "Create `/etc/nix/nix.conf`".into(),
+            duration_ms: 3,
+            status: ActionOutcome::Completed,
+        }
+    }
+
+    #[tokio::test]
+    async fn drives_a_whole_install() -> eyre::Result<()> {
+        let (control, mut controller) = FakeController::pair()?;
+
+        let controller = tokio::spawn(async move {
+            let mut received = vec![];
+            while let Some(message) = controller.receive().await? {
+                match &message {
+                    ControlMessage::Prompt { id, choices, .. }
+                        if choices.contains(&PromptChoice::Explain) =>
+                    {
+                        controller
+                            .request(&ControlRequest::Answer {
+                                id: *id,
+                                choice: PromptChoice::Explain,
+                            })
+                            .await?
+                    },
+                    ControlMessage::Prompt { id, .. } => {
+                        controller
+                            .request(&ControlRequest::Answer {
+                                id: *id,
+                                choice: PromptChoice::Yes,
+                            })
+                            .await?
+                    },
+                    _ => (),
+                }
+                received.push(message);
+            }
+            eyre::Ok(received)
+        });
+
+        let question = "\u{1b}[1mInstall Nix\u{1b}[0m";
+        assert_eq!(
+            control.prompt(question, PromptChoice::Yes, false).await,
+            PromptChoice::Explain
+        );
+        assert_eq!(
+            control.prompt(question, PromptChoice::Yes, true).await,
+            PromptChoice::Yes
+        );
+        let progress = control.progress();
+        for event in [started(0), finished(0), started(1), finished(1)] {
+            progress.send(event)?;
+        }
+        drop(progress);
+        control.finish(&Ok(ExitCode::SUCCESS)).await;
+
+        let received = controller.await??;
+        assert_eq!(received.len(), 7, "{received:#?}");
+        assert_eq!(
+            received[0],
+            ControlMessage::Prompt {
+                id: 0,
+                description: "Install Nix".into(),
+                choices: vec![PromptChoice::Yes, PromptChoice::No, PromptChoice::Explain],
+                default: PromptChoice::Yes,
+            }
+        );
+        assert!(matches!(
+            &received[1],
+            ControlMessage::Prompt { id: 1, choices, .. } if choices.len() == 2
+        ));
+        assert!(matches!(
+            &received[2],
+            ControlMessage::Progress {
+                completed: 0,
+                total: 2,
+                event: ProgressEvent::ActionStarted { index: 0, .. },
+                ..
+            }
+        ));
+        assert!(matches!(
+            &received[5],
+            ControlMessage::Progress {
+                completed: 2,
+                total: 2,
+                event: ProgressEvent::ActionFinished { index: 1, .. },
+                ..
+            }
+        ));
+        assert_eq!(
+            received[6],
+            ControlMessage::Result {
+                status: ControlOutcome::Success,
+                error: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancel_requests_cancel_and_decline() -> eyre::Result<()> {
+        let (control, mut controller) = FakeController::pair()?;
+        let (cancel_channel, mut cancelled) = tokio::sync::broadcast::channel(1);
+        control.cancel_with(cancel_channel);
+
+        controller.request(&ControlRequest::Cancel).await?;
+        tokio::time::timeout(Duration::from_secs(10), cancelled.recv()).await??;
+
+        assert_eq!(
+            control.prompt("Proceed?", PromptChoice::Yes, false).await,
+            PromptChoice::No
+        );
+        control.finish(&Ok(ExitCode::FAILURE)).await;
+        assert_eq!(
+            controller.receive().await?,
+            Some(ControlMessage::Result {
+                status: ControlOutcome::Cancelled,
+                error: None,
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disconnecting_declines_and_cancels() -> eyre::Result<()> {
+        let (control, mut controller) = FakeController::pair()?;
+        let (cancel_channel, mut cancelled) = tokio::sync::broadcast::channel(1);
+
+        let disconnect = tokio::spawn(async move {
+            let prompt = controller.receive().await?;
+            assert!(matches!(prompt, Some(ControlMessage::Prompt { .. })));
+            drop(controller);
+            eyre::Ok(())
+        });
+
+        assert_eq!(
+            control.prompt("Proceed?", PromptChoice::Yes, false).await,
+            PromptChoice::No
+        );
+        disconnect.await??;
+        // Cancelled before the install began, so it is cancelled as soon as it does
+        control.cancel_with(cancel_channel);
+        tokio::time::timeout(Duration::from_secs(10), cancelled.recv()).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unanswered_prompts_are_declined() -> eyre::Result<()> {
+        let (mut control, mut controller) = FakeController::pair()?;
+        control.answer_timeout = Duration::from_millis(50);
+
+        assert_eq!(
+            control.prompt("Proceed?", PromptChoice::Yes, false).await,
+            PromptChoice::No
+        );
+        // A late answer, or one which was never offered, is ignored
+        controller
+            .request(&ControlRequest::Answer {
+                id: 0,
+                choice: PromptChoice::Yes,
+            })
+            .await?;
+        let customize = tokio::spawn(async move {
+            while let Some(message) = controller.receive().await? {
+                if let ControlMessage::Prompt { id, .. } = message {
+                    controller
+                        .request(&ControlRequest::Answer {
+                            id,
+                            choice: PromptChoice::Customize,
+                        })
+                        .await?;
+                }
+            }
+            eyre::Ok(())
+        });
+        control.answer_timeout = Duration::from_secs(10);
+        assert_eq!(
+            control.prompt("Proceed?", PromptChoice::Yes, false).await,
+            PromptChoice::No
+        );
+        control.declined().await;
+        customize.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listens_in_owned_directories_only() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::set_permissions(dir.path(), PermissionsExt::from_mode(0o755))?;
+        let owner = Uid::effective();
+        let someone_else = Uid::from_raw(owner.as_raw() + 1);
+
+        assert!(matches!(
+            check_socket_dir(dir.path(), someone_else),
+            Err(ControlSocketError::DirNotRootOwned(..))
+        ));
+        check_socket_dir(dir.path(), owner)?;
+        std::fs::set_permissions(dir.path(), PermissionsExt::from_mode(0o775))?;
+        assert!(matches!(
+            check_socket_dir(dir.path(), owner),
+            Err(ControlSocketError::DirWritable(_, 0o775))
+        ));
+        std::fs::set_permissions(dir.path(), PermissionsExt::from_mode(0o700))?;
+
+        let path = dir.path().join("control.sock");
+        let listening = tokio::spawn({
+            let path = path.clone();
+            async move { Control::listen_owned_by(&path, owner).await }
+        });
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let control = listening.await??;
+        assert!(!path.exists(), "The socket is removed once connected");
+
+        let mut lines = BufReader::new(stream).lines();
+        control.declined().await;
+        let result: ControlMessage = serde_json::from_str(
+            &lines
+                .next_line()
+                .await?
+                .ok_or_else(|| eyre::eyre!("No result"))?,
+        )?;
+        assert_eq!(
+            result,
+            ControlMessage::Result {
+                status: ControlOutcome::Declined,
+                error: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn strips_colors() {
+        assert_eq!(
+            strip_ansi("\u{1b}[1m\u{1b}[32mNix\u{1b}[39m\u{1b}[0m will be installed"),
+            "Nix will be installed"
+        );
+        assert_eq!(strip_ansi("Plain `text`"), "Plain `text`");
+    }
+}
@@ -4,11 +4,16 @@ use std::io::{stdin, stdout, BufRead, Write};
 use eyre::{eyre, WrapErr};
 use owo_colors::OwoColorize;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+use crate::messages::message;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PromptChoice {
     Yes,
     No,
     Explain,
+    /// Change which optional parts are planned, only offered by [`prompt_customizable`]
+    Customize,
 }
 
 // Do not try to get clever!
@@ -19,6 +24,24 @@ pub(crate) async fn prompt(
     question: impl AsRef<str>,
     default: PromptChoice,
     currently_explaining: bool,
+) -> eyre::Result<PromptChoice> {
+    prompt_impl(question.as_ref(), default, currently_explaining, false)
+}
+
+/// Like [`prompt`], also offering [`PromptChoice::Customize`]
+pub(crate) async fn prompt_customizable(
+    question: impl AsRef<str>,
+    default: PromptChoice,
+    currently_explaining: bool,
+) -> eyre::Result<PromptChoice> {
+    prompt_impl(question.as_ref(), default, currently_explaining, true)
+}
+
+fn prompt_impl(
+    question: &str,
+    default: PromptChoice,
+    currently_explaining: bool,
+    customizable: bool,
 ) -> eyre::Result<PromptChoice> {
     let stdout = stdout();
     let terminfo = term::terminfo::TermInfo::from_env().unwrap_or_else(|_| {
@@ -35,10 +58,10 @@ pub(crate) async fn prompt(
         "\
         {question}\n\
         \n\
-        {are_you_sure} ({yes}/{no}{maybe_explain}): \
+        {are_you_sure} ({yes}/{no}{maybe_explain}{maybe_customize}): \
     ",
-        question = question.as_ref(),
-        are_you_sure = "Proceed?".bold(),
+        question = question,
+        are_you_sure = message!(PromptProceed).bold(),
         no = if default == PromptChoice::No {
             "[N]o"
         } else {
@@ -63,6 +86,7 @@ pub(crate) async fn prompt(
         } else {
             "".into()
         },
+        maybe_customize = if customizable { "/[c]ustomize" } else { "" },
     );
 
     term.write_all(with_confirm.as_bytes())?;
@@ -70,15 +94,62 @@ pub(crate) async fn prompt(
 
     let input = read_line()?;
 
-    let r = match &*input.to_lowercase() {
+    Ok(parse_choice(&input, default, customizable))
+}
+
+fn parse_choice(input: &str, default: PromptChoice, customizable: bool) -> PromptChoice {
+    match &*input.to_lowercase() {
         "y" | "yes" => PromptChoice::Yes,
         "n" | "no" => PromptChoice::No,
         "e" | "explain" => PromptChoice::Explain,
+        "c" | "customize" if customizable => PromptChoice::Customize,
         "" => default,
         _ => PromptChoice::No,
-    };
+    }
+}
 
-    Ok(r)
+/// Show `labels` as a numbered list of what is `enabled`, flipping the entries whose numbers the
+/// user enters, until they enter nothing
+pub(crate) fn toggle_list(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    labels: &[String],
+    enabled: &mut [bool],
+) -> eyre::Result<()> {
+    loop {
+        writeln!(output, "{}", message!(CustomizeHeading).bold())?;
+        for (index, (label, enabled)) in labels.iter().zip(enabled.iter()).enumerate() {
+            let mark = if *enabled { "x" } else { " " };
+            writeln!(output, "  {number}. [{mark}] {label}", number = index + 1)?;
+        }
+        write!(output, "{}", message!(CustomizeInput))?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input
+            .read_line(&mut line)
+            .context("unable to read from stdin for customization")?
+            == 0
+        {
+            return Ok(());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        for entry in line.split([',', ' ']).filter(|entry| !entry.is_empty()) {
+            match entry.parse::<usize>() {
+                Ok(number) if (1..=enabled.len()).contains(&number) => {
+                    enabled[number - 1] = !enabled[number - 1]
+                },
+                _ => writeln!(
+                    output,
+                    "{}",
+                    message!(CustomizeInvalid, entry = entry).yellow()
+                )?,
+            }
+        }
+    }
 }
 
 pub(crate) fn read_line() -> eyre::Result<String> {
@@ -97,3 +168,46 @@ pub(crate) async fn clean_exit_with_message(message: impl AsRef<str>) -> ! {
     eprintln!("{}", message.as_ref());
     std::process::exit(0)
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{parse_choice, toggle_list, PromptChoice};
+
+    #[test]
+    fn customize_is_only_chosen_when_offered() {
+        assert_eq!(
+            parse_choice("c", PromptChoice::Yes, true),
+            PromptChoice::Customize
+        );
+        assert_eq!(
+            parse_choice("Customize", PromptChoice::Yes, true),
+            PromptChoice::Customize
+        );
+        assert_eq!(
+            parse_choice("c", PromptChoice::Yes, false),
+            PromptChoice::No
+        );
+        assert_eq!(parse_choice("", PromptChoice::Yes, true), PromptChoice::Yes);
+    }
+
+    #[test]
+    fn toggles_the_entered_numbers_until_nothing_is_entered() -> eyre::Result<()> {
+        let labels = vec!["bash".to_string(), "zsh".into(), "fish".into()];
+        let mut enabled = vec![true, true, false];
+        let mut input = Cursor::new("2\n1, 3 9 x\n1\n\nignored\n");
+        let mut output = Vec::new();
+
+        toggle_list(&mut input, &mut output, &labels, &mut enabled)?;
+
+        assert_eq!(enabled, vec![true, false, true]);
+        let output = String::from_utf8(output)?;
+        assert!(output.contains("2. [ ] zsh"), "{output}");
+        assert!(output.contains("`9`"), "{output}");
+        assert!(output.contains("`x`"), "{output}");
+        // Stops at the empty line, leaving the rest
+        assert_eq!(input.position() as usize, "2\n1, 3 9 x\n1\n\n".len());
+        Ok(())
+    }
+}
@@ -3,16 +3,28 @@
 */
 
 pub(crate) mod arg;
+mod control;
 mod interaction;
+pub(crate) mod run_log;
 pub(crate) mod subcommand;
 
-use clap::Parser;
+use clap::{ArgAction, CommandFactory, Parser};
 use eyre::WrapErr;
 use owo_colors::OwoColorize;
-use std::{ffi::CString, process::ExitCode};
+use std::{
+    ffi::{CString, OsStr, OsString},
+    path::PathBuf,
+    process::ExitCode,
+};
 use tokio::sync::broadcast::{Receiver, Sender};
 
-use self::subcommand::NixInstallerSubcommand;
+use self::{run_log::RunLog, subcommand::NixInstallerSubcommand};
+use crate::{
+    build_info::BuildInfo,
+    deprecation::{self, Deprecation, DEPRECATED},
+    messages::{self, message, Catalog},
+    settings::ORIGINAL_CWD_ENV,
+};
 
 #[async_trait::async_trait]
 pub trait CommandExecute {
@@ -25,31 +37,132 @@ The Determinate Nix installer
 A fast, friendly, and reliable tool to help you use Nix with Flakes everywhere.
 */
 #[derive(Debug, Parser)]
-#[clap(version)]
+#[clap(version, disable_version_flag = true, arg_required_else_help = true)]
 pub struct NixInstallerCli {
     #[clap(flatten)]
     pub instrumentation: arg::Instrumentation,
 
+    /// Print version, with `--verbose` also how it was built
+    #[clap(short = 'V', long, action = ArgAction::SetTrue)]
+    pub version: bool,
+
+    /// Print how this `nix-installer` was built: its features, target, compiler, git revision, and
+    /// embedded `determinate-nixd`
+    #[clap(long, alias = "features", action = ArgAction::SetTrue)]
+    pub build_info: bool,
+
+    /// A JSON file of message ids to text, replacing the built-in user-facing messages
+    ///
+    /// Log output is not affected. See the `nix_installer::messages` documentation for the ids.
+    #[clap(long, env = "NIX_INSTALLER_MESSAGES", global = true)]
+    pub messages: Option<PathBuf>,
+
+    /// Fail instead of warning when a deprecated flag or environment variable is used
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_DENY_DEPRECATED",
+        action = ArgAction::SetTrue,
+        default_value = "false",
+        global = true
+    )]
+    pub deny_deprecated: bool,
+
     #[clap(subcommand)]
-    pub subcommand: NixInstallerSubcommand,
+    pub subcommand: Option<NixInstallerSubcommand>,
 }
 
 #[async_trait::async_trait]
 impl CommandExecute for NixInstallerCli {
     #[tracing::instrument(level = "trace", skip_all)]
     async fn execute(self) -> eyre::Result<ExitCode> {
+        if let Some(version_output) = self.version_output() {
+            println!("{version_output}");
+            return Ok(ExitCode::SUCCESS);
+        }
+
         let Self {
             instrumentation: _,
+            version: _,
+            build_info: _,
+            messages,
+            deny_deprecated,
             subcommand,
         } = self;
+        let Some(subcommand) = subcommand else {
+            NixInstallerCli::command()
+                .error(
+                    clap::error::ErrorKind::MissingSubcommand,
+                    "A subcommand is required",
+                )
+                .exit()
+        };
+
+        if let Some(messages) = messages {
+            let catalog = Catalog::from_file(&messages)
+                .wrap_err_with(|| format!("Loading messages from `{}`", messages.display()))?;
+            let missing = catalog.missing();
+            if !missing.is_empty() {
+                tracing::debug!(
+                    missing = missing.len(),
+                    "Message table does not override every message, defaults will be used"
+                );
+            }
+            messages::set_catalog(catalog)?;
+        }
+
+        match check_deprecated(deny_deprecated, std::env::args_os(), std::env::vars_os()) {
+            Ok(deprecations) => deprecation::record(deprecations),
+            Err(denied) => {
+                eprintln!("{}", denied.red());
+                return Ok(ExitCode::FAILURE);
+            },
+        }
+
+        // The subcommands which change the system keep a log of the run, see `run_log`
+        let logged_command = match &subcommand {
+            NixInstallerSubcommand::Install(_) => Some("install"),
+            NixInstallerSubcommand::Repair(_) => Some("repair"),
+            NixInstallerSubcommand::Uninstall(_) => Some("uninstall"),
+            _ => None,
+        };
+        if let Some(command) = logged_command {
+            RunLog::global().record(command, None);
+        }
 
-        match subcommand {
+        let result = match subcommand {
             NixInstallerSubcommand::Plan(plan) => plan.execute().await,
             NixInstallerSubcommand::SelfTest(self_test) => self_test.execute().await,
             NixInstallerSubcommand::Install(install) => install.execute().await,
             NixInstallerSubcommand::Repair(repair) => repair.execute().await,
             NixInstallerSubcommand::Uninstall(revert) => revert.execute().await,
             NixInstallerSubcommand::SplitReceipt(split_receipt) => split_receipt.execute().await,
+            NixInstallerSubcommand::RestoreBackups(restore_backups) => {
+                restore_backups.execute().await
+            },
+            NixInstallerSubcommand::Inspect(inspect) => inspect.execute().await,
+            NixInstallerSubcommand::Status(status) => status.execute().await,
+        };
+
+        if logged_command.is_some() {
+            if let Some(path) = RunLog::global().persist().await {
+                if !matches!(result, Ok(code) if code == ExitCode::SUCCESS) {
+                    eprintln!("{}", message!(RunLogSaved, path = path.display()));
+                }
+            }
+        }
+        result
+    }
+}
+
+impl NixInstallerCli {
+    /// What `--version` or `--build-info` print, if either was given
+    fn version_output(&self) -> Option<String> {
+        if self.build_info || (self.version && self.instrumentation.verbose > 0) {
+            Some(BuildInfo::current().to_string())
+        } else if self.version {
+            Some(format!("nix-installer {}", env!("CARGO_PKG_VERSION")))
+        } else {
+            None
         }
     }
 }
@@ -91,12 +204,7 @@ pub fn is_root() -> bool {
 
 pub fn ensure_root() -> eyre::Result<()> {
     if !is_root() {
-        eprintln!(
-            "{}",
-            "`nix-installer` needs to run as `root`, attempting to escalate now via `sudo`..."
-                .yellow()
-                .dimmed()
-        );
+        eprintln!("{}", message!(RootEscalating).yellow().dimmed());
         let sudo_cstring = CString::new("sudo").wrap_err("Making C string of `sudo`")?;
         let set_home_cstring =
             CString::new("--set-home").wrap_err("Making C string of `--set-home`")?;
@@ -106,32 +214,18 @@ pub fn ensure_root() -> eyre::Result<()> {
         arg_vec_cstring.push(sudo_cstring.clone());
         arg_vec_cstring.push(set_home_cstring);
 
-        let mut env_list = vec![];
-        for (key, value) in std::env::vars() {
-            let preserve = match key.as_str() {
-                // Rust logging/backtrace bits we use
-                "RUST_LOG" | "RUST_BACKTRACE" => true,
-                // CI
-                "GITHUB_PATH" => true,
-                // Used for detecting what command to suggest for sourcing Nix
-                "SHELL" => true,
-                // Proxy settings (automatically picked up by Reqwest)
-                "HTTP_PROXY" | "http_proxy" | "HTTPS_PROXY" | "https_proxy" => true,
-                // Our own environments
-                key if key.starts_with("NIX_INSTALLER") => true,
-                _ => false,
-            };
-            if preserve {
-                env_list.push(format!("{key}={value}"));
-            }
-        }
+        let env_list = sudo_env(std::env::vars(), std::env::current_dir().ok());
 
         #[cfg(feature = "diagnostics")]
-        if is_ci::cached() {
-            // Normally `sudo` would erase those envs, so we detect and pass that along specifically to avoid having to pass around
-            // a bunch of environment variables
-            env_list.push("NIX_INSTALLER_CI=1".to_string());
-        }
+        let env_list = {
+            let mut env_list = env_list;
+            if is_ci::cached() {
+                // Normally `sudo` would erase those envs, so we detect and pass that along specifically to avoid having to pass around
+                // a bunch of environment variables
+                env_list.push("NIX_INSTALLER_CI=1".to_string());
+            }
+            env_list
+        };
 
         if !env_list.is_empty() {
             arg_vec_cstring
@@ -154,3 +248,191 @@ pub fn ensure_root() -> eyre::Result<()> {
     }
     Ok(())
 }
+
+/// The deprecated forms used in `args` or `vars`, or the message refusing them with `deny`
+fn check_deprecated<A>(
+    deny: bool,
+    args: A,
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+) -> Result<Vec<Deprecation>, String>
+where
+    A: IntoIterator,
+    A::Item: AsRef<OsStr>,
+{
+    let deprecations = deprecation::detect(DEPRECATED, args, vars);
+    if deny && !deprecations.is_empty() {
+        let deprecations = deprecations
+            .iter()
+            .map(|deprecation| format!("* {deprecation}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Err(message!(DeprecatedDenied, deprecations = deprecations));
+    }
+    Ok(deprecations)
+}
+
+/// The environment to keep when re-running `nix-installer` with `sudo`, recording `cwd` so relative
+/// paths in arguments still resolve against it if `sudo` starts elsewhere
+fn sudo_env(vars: impl Iterator<Item = (String, String)>, cwd: Option<PathBuf>) -> Vec<String> {
+    let mut env_list = vec![];
+    let mut has_original_cwd = false;
+    for (key, value) in vars {
+        let preserve = match key.as_str() {
+            // Rust logging/backtrace bits we use
+            "RUST_LOG" | "RUST_BACKTRACE" => true,
+            // CI
+            "GITHUB_PATH" | "GITHUB_OUTPUT" => true,
+            // Used for detecting what command to suggest for sourcing Nix
+            "SHELL" => true,
+            // Proxy settings (automatically picked up by Reqwest)
+            "HTTP_PROXY" | "http_proxy" | "HTTPS_PROXY" | "https_proxy" | "ALL_PROXY"
+            | "all_proxy" | "NO_PROXY" | "no_proxy" => true,
+            // Our own environments
+            key if key.starts_with("NIX_INSTALLER") => true,
+            _ => false,
+        };
+        has_original_cwd |= key == ORIGINAL_CWD_ENV;
+        if preserve {
+            env_list.push(format!("{key}={value}"));
+        }
+    }
+    // An outer `nix-installer` already recorded where it was started
+    if let (false, Some(cwd)) = (has_original_cwd, cwd) {
+        env_list.push(format!("{ORIGINAL_CWD_ENV}={}", cwd.display()));
+    }
+    env_list
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::{check_deprecated, sudo_env, NixInstallerCli};
+    use crate::{
+        build_info::BuildInfo, cli::subcommand::NixInstallerSubcommand, deprecation::Form,
+        warning::WarningKind, BuiltinPlanner,
+    };
+
+    #[test]
+    fn build_info_flag_renders_the_build_info() -> eyre::Result<()> {
+        let current = BuildInfo::current();
+        for args in [
+            &["nix-installer", "--build-info"][..],
+            &["nix-installer", "--features"],
+            &["nix-installer", "--version", "--verbose"],
+        ] {
+            let cli = NixInstallerCli::try_parse_from(args)?;
+            let output = cli.version_output().unwrap_or_default();
+            for field in [
+                &current.version,
+                &current.target,
+                &current.rustc,
+                &current.git_revision,
+            ] {
+                assert!(output.contains(field.as_str()), "{args:?}: {output}");
+            }
+            for feature in &current.features {
+                assert!(output.contains(feature.as_str()), "{args:?}: {output}");
+            }
+            assert!(output.contains("determinate-nixd: "), "{args:?}: {output}");
+        }
+
+        let cli = NixInstallerCli::try_parse_from(["nix-installer", "--version"])?;
+        assert_eq!(
+            cli.version_output(),
+            Some(format!("nix-installer {}", env!("CARGO_PKG_VERSION")))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn deprecated_flags_still_parse_with_a_warning() -> eyre::Result<()> {
+        let args = [
+            "nix-installer",
+            "install",
+            "linux",
+            "--daemon-user-count",
+            "4",
+        ];
+        let cli = NixInstallerCli::try_parse_from(args)?;
+        let Some(NixInstallerSubcommand::Install(install)) = &cli.subcommand else {
+            panic!("Parsed {cli:?}");
+        };
+        let Some(BuiltinPlanner::Linux(linux)) = &install.planner else {
+            panic!("Parsed {install:?}");
+        };
+        assert_eq!(linux.settings.nix_build_user_count, 4);
+
+        let deprecations = check_deprecated(cli.deny_deprecated, args, [])
+            .map_err(|denied| eyre::eyre!(denied))?;
+        assert_eq!(deprecations.len(), 1);
+        assert_eq!(deprecations[0].form, Form::Flag);
+        assert_eq!(deprecations[0].replacement, "--nix-build-user-count");
+        let warning = deprecations[0].warning();
+        assert_eq!(warning.kind, WarningKind::Deprecated);
+        assert!(
+            warning.message.contains("`--daemon-user-count`"),
+            "{warning}"
+        );
+
+        let args = [
+            "nix-installer",
+            "install",
+            "linux",
+            "--nix-build-user-count",
+            "4",
+        ];
+        assert_eq!(check_deprecated(true, args, []), Ok(vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn deny_deprecated_refuses_deprecated_flags() -> eyre::Result<()> {
+        let args = [
+            "nix-installer",
+            "--deny-deprecated",
+            "install",
+            "linux",
+            "--daemon-user-count=4",
+        ];
+        let cli = NixInstallerCli::try_parse_from(args)?;
+        assert!(cli.deny_deprecated);
+        let Err(denied) = check_deprecated(cli.deny_deprecated, args, []) else {
+            panic!("`--deny-deprecated` allowed `--daemon-user-count`");
+        };
+        assert!(denied.contains("`--nix-build-user-count`"), "{denied}");
+        Ok(())
+    }
+
+    #[test]
+    fn sudo_keeps_the_original_cwd() {
+        let vars = [
+            ("PATH", "/usr/bin"),
+            ("HOME", "/home/ana"),
+            ("NO_PROXY", "mirror.internal"),
+            ("NIX_INSTALLER_NO_CONFIRM", "true"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        assert_eq!(
+            sudo_env(vars.clone().into_iter(), Some("/home/ana/src".into())),
+            [
+                "NO_PROXY=mirror.internal",
+                "NIX_INSTALLER_NO_CONFIRM=true",
+                "NIX_INSTALLER_ORIGINAL_CWD=/home/ana/src",
+            ]
+        );
+
+        // A `nix-installer` started by another one keeps the first one's
+        let nested = vars
+            .into_iter()
+            .chain([("NIX_INSTALLER_ORIGINAL_CWD".to_string(), "/srv".to_string())]);
+        assert_eq!(
+            sudo_env(nested, Some("/root".into())),
+            [
+                "NO_PROXY=mirror.internal",
+                "NIX_INSTALLER_NO_CONFIRM=true",
+                "NIX_INSTALLER_ORIGINAL_CWD=/srv",
+            ]
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{ArgAction, Parser};
+use eyre::WrapErr;
+use owo_colors::OwoColorize;
+
+use crate::{
+    cli::CommandExecute,
+    drift::{DriftStatus, FileDrift, WrittenFile},
+    managed_files::files_of_receipt,
+    messages::message,
+    plan::RECEIPT_LOCATION,
+};
+
+/// Report the files `nix-installer` wrote which have changed since it wrote them
+///
+/// Each file is compared with the SHA-256 recorded in the receipt when it was written. For a file
+/// `nix-installer` only added a block to, like `/etc/bashrc`, only that block is compared. Exits
+/// nonzero if any has changed.
+#[derive(Debug, Parser)]
+pub struct Inspect {
+    /// Emit the report as JSON
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_INSPECT_JSON",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub json: bool,
+
+    #[clap(default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Inspect {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self { json, receipt } = self;
+
+        let receipt = std::fs::read(&receipt).wrap_err("Reading receipt")?;
+        let value: serde_json::Value =
+            serde_json::from_slice(&receipt).wrap_err("Parsing receipt")?;
+        let report = inspect(files_of_receipt(&receipt, &value)).await?;
+        let changed = report
+            .iter()
+            .filter(|drift| drift.status != DriftStatus::Unchanged)
+            .collect::<Vec<_>>();
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else if changed.is_empty() {
+            println!(
+                "{}",
+                message!(InspectUnchanged, count = report.len()).green()
+            );
+        } else {
+            let files = changed
+                .iter()
+                .map(|drift| format!("* {}", describe(drift)))
+                .collect::<Vec<_>>()
+                .join("\n");
+            println!(
+                "{}",
+                message!(
+                    InspectChanged,
+                    count = changed.len(),
+                    total = report.len(),
+                    files = files
+                )
+                .yellow()
+            );
+        }
+
+        Ok(match changed.is_empty() {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        })
+    }
+}
+
+/// Check each of the `written` files
+async fn inspect(written: Vec<WrittenFile>) -> eyre::Result<Vec<FileDrift>> {
+    let mut report = vec![];
+    for written in written {
+        report.push(
+            written
+                .check()
+                .await
+                .wrap_err_with(|| format!("Reading `{}`", written.path.display()))?,
+        );
+    }
+    Ok(report)
+}
+
+fn describe(drift: &FileDrift) -> String {
+    let path = drift.path.display();
+    match (drift.status, drift.fenced) {
+        (DriftStatus::Unchanged, _) => format!("`{path}` is unchanged"),
+        (DriftStatus::Modified, false) => format!("`{path}` was modified"),
+        (DriftStatus::Modified, true) => {
+            format!("`{path}` has a modified `nix-installer` managed block")
+        },
+        (DriftStatus::BlockRemoved, _) => {
+            format!("`{path}` no longer has its `nix-installer` managed block")
+        },
+        (DriftStatus::PermissionsChanged, _) => {
+            format!("`{path}` no longer has the mode or group it was given")
+        },
+        (DriftStatus::Missing, _) => format!("`{path}` is missing"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::inspect;
+    use crate::{
+        drift::{DriftStatus, WrittenFile},
+        test_harness::SandboxContext,
+    };
+
+    #[tokio::test]
+    async fn reports_modified_files() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let block = "# Nix\n. '/nix/nix-daemon.sh'\n# End Nix\n";
+        for (path, content) in [
+            ("/etc/nix/nix.conf", "build-users-group = nixbld\n"),
+            ("/etc/bashrc", block),
+            ("/etc/zshrc", block),
+            (
+                "/etc/tmpfiles.d/nix-daemon.conf",
+                "d /nix/var/nix/daemon-socket\n",
+            ),
+        ] {
+            std::fs::create_dir_all(sandbox.path(path).parent().unwrap())?;
+            std::fs::write(sandbox.path(path), content)?;
+        }
+        let receipt = serde_json::json!({
+            "actions": [
+                { "action": { "written_file": WrittenFile::whole("/etc/nix/nix.conf", b"build-users-group = nixbld\n") } },
+                { "action": { "written_file": WrittenFile::fenced("/etc/bashrc", block) } },
+                { "action": { "written_file": WrittenFile::fenced("/etc/zshrc", block) } },
+                { "action": { "written_files": [
+                    WrittenFile::whole("/etc/tmpfiles.d/nix-daemon.conf", b"d /nix/var/nix/daemon-socket\n"),
+                    WrittenFile::whole("/etc/systemd/system/nix-daemon.socket", b"[Socket]\n"),
+                ] } },
+            ],
+        });
+
+        std::fs::write(sandbox.path("/etc/nix/nix.conf"), "sandbox = false\n")?;
+        // Outside of the block, so not a change to what `nix-installer` manages
+        std::fs::write(
+            sandbox.path("/etc/bashrc"),
+            format!("alias ll='ls -l'\n{block}"),
+        )?;
+        std::fs::write(sandbox.path("/etc/zshrc"), "# Nix\n# End Nix\n")?;
+
+        let report = sandbox
+            .scope(inspect(crate::drift::written_files(&receipt)))
+            .await?;
+        let statuses = report
+            .iter()
+            .map(|drift| (drift.path.to_str().unwrap(), drift.status, drift.fenced))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                ("/etc/nix/nix.conf", DriftStatus::Modified, false),
+                ("/etc/bashrc", DriftStatus::Unchanged, true),
+                ("/etc/zshrc", DriftStatus::Modified, true),
+                (
+                    "/etc/tmpfiles.d/nix-daemon.conf",
+                    DriftStatus::Unchanged,
+                    false
+                ),
+                (
+                    "/etc/systemd/system/nix-daemon.socket",
+                    DriftStatus::Missing,
+                    false
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,24 +1,35 @@
 use std::{
+    io::Write,
+    num::NonZeroUsize,
+    ops::ControlFlow,
     os::unix::prelude::PermissionsExt,
     path::{Path, PathBuf},
     process::ExitCode,
+    time::SystemTime,
 };
 
 use crate::{
     action::ActionState,
     cli::{
+        arg::DescriptionFormat,
+        control::{self, Control},
         ensure_root,
         interaction::{self, PromptChoice},
-        signal_channel,
-        subcommand::split_receipt::{PHASE1_RECEIPT_LOCATION, PHASE2_RECEIPT_LOCATION},
-        CommandExecute,
+        run_log::RunLog,
+        signal_channel, CommandExecute,
     },
+    deprecation::{self, Deprecation},
     error::HasExpectedErrors,
+    event_log::{EventLog, Operation},
+    messages::message,
+    phase::Phase,
     plan::RECEIPT_LOCATION,
-    planner::Planner,
+    planner::{optional::OptionalPart, Planner},
+    report::{fan_out, Reporter},
     settings::CommonSettings,
     util::OnMissing,
-    BuiltinPlanner, InstallPlan, NixInstallerError,
+    warning::Warning,
+    BuiltinPlanner, ExtraPlan, InstallPlan, NixInstallerError, PrivilegedOperations,
 };
 use clap::{ArgAction, Parser};
 use color_eyre::{
@@ -26,12 +37,7 @@ use color_eyre::{
     Section,
 };
 use owo_colors::OwoColorize;
-
-const EXISTING_INCOMPATIBLE_PLAN_GUIDANCE: &str = "\
-    If you are trying to upgrade Nix, try running `sudo -i nix upgrade-nix` instead.\n\
-    If you are trying to install Nix over an existing install (from an incompatible `nix-installer` install), try running `/nix/nix-installer uninstall` then try to install again.\n\
-    If you are using `nix-installer` in an automated curing process and seeing this message, consider pinning the version you use via https://github.com/DeterminateSystems/nix-installer#accessing-other-versions.\
-";
+use url::Url;
 
 /**
 Install Nix using a planner
@@ -66,10 +72,109 @@ pub struct Install {
     )]
     pub explain: bool,
 
+    /// Check the system and print the plan with an explanation of each action, without changing anything
+    ///
+    /// Exits with a failure if planning or the pre-install checks fail
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_DRY_RUN",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true,
+        conflicts_with_all = ["json", "report_to", "control_socket", "event_log"]
+    )]
+    pub dry_run: bool,
+
+    /// How `--dry-run` prints the plan, as `human` prose or a `json` document
+    ///
+    /// The JSON document lists each action with its state, descriptions, and sub-actions
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_FORMAT",
+        default_value_t = Default::default(),
+        global = true,
+        requires = "dry_run"
+    )]
+    pub format: DescriptionFormat,
+
+    /// A URL to POST the plan, progress, and result of the install to, for collecting outcomes across a fleet
+    ///
+    /// If `NIX_INSTALLER_REPORT_AUTHORIZATION` is set, it is sent as the `Authorization` header
+    #[clap(long, env = "NIX_INSTALLER_REPORT_TO", global = true)]
+    pub report_to: Option<Url>,
+
+    /// A path to append a line of JSON to for each step of the install as it happens, for auditing
+    ///
+    /// A revert after a failed install is appended too
+    #[clap(long, env = "NIX_INSTALLER_EVENT_LOG", global = true)]
+    pub event_log: Option<PathBuf>,
+
     /// A path to a non-default installer plan
     #[clap(env = "NIX_INSTALLER_PLAN")]
     pub plan: Option<PathBuf>,
 
+    /// A path to a list of additional actions to run after the planner's actions
+    ///
+    /// Only actions built into `nix-installer` can be used
+    #[clap(long, env = "NIX_INSTALLER_EXTRA_PLAN", global = true)]
+    pub extra_plan: Option<PathBuf>,
+
+    /// A path to a list of operations the install may perform as root, as emitted by `nix-installer plan --privileged-operations`
+    ///
+    /// The install stops before making any change if the plan has an operation not in the list
+    #[clap(long, env = "NIX_INSTALLER_ALLOWED_OPERATIONS", global = true)]
+    pub allowed_operations: Option<PathBuf>,
+
+    /// When Nix is already installed with the same planner and settings, run the self-test before reporting it healthy
+    ///
+    /// If the self-test fails, exit with code 3
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_VERIFY_EXISTING",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub verify_existing: bool,
+
+    /// How many actions of the plan may execute at once, when they don't depend on each other
+    ///
+    /// `1` executes the actions one at a time, in order
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_PARALLELISM",
+        default_value_t = crate::plan::DEFAULT_PARALLELISM,
+        global = true
+    )]
+    pub parallelism: NonZeroUsize,
+
+    /// Only execute the actions of this phase of the install, recording them in the receipt for the next phase to resume from
+    ///
+    /// The earlier phases must have completed. `finish`, like installing without a phase, executes everything left
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_PHASE",
+        global = true,
+        conflicts_with = "dry_run"
+    )]
+    pub phase: Option<Phase>,
+
+    /// Print the outcome of the install as a JSON object on stdout
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_JSON",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub json: bool,
+
+    /// A path to listen on for a controller, such as a graphical frontend, which answers the prompts and follows the progress
+    ///
+    /// Its directory must be owned by root and writable by nobody else
+    #[clap(long, env = "NIX_INSTALLER_CONTROL_SOCKET", global = true)]
+    pub control_socket: Option<PathBuf>,
+
     #[clap(subcommand)]
     pub planner: Option<BuiltinPlanner>,
 }
@@ -78,25 +183,60 @@ pub struct Install {
 impl CommandExecute for Install {
     #[tracing::instrument(level = "trace", skip_all)]
     async fn execute(self) -> eyre::Result<ExitCode> {
+        ensure_root()?;
+
+        let control = match &self.control_socket {
+            Some(control_socket) => Some(Control::listen(control_socket).await?),
+            None => None,
+        };
+        let result = self.run(control.as_ref()).await;
+        if let Some(control) = &control {
+            control.finish(&result).await;
+        }
+        result
+    }
+}
+
+impl Install {
+    async fn run(self, control: Option<&Control>) -> eyre::Result<ExitCode> {
         let Self {
             no_confirm,
             plan,
             planner,
             settings,
             explain,
+            dry_run,
+            format,
+            report_to,
+            event_log,
+            extra_plan,
+            allowed_operations,
+            verify_existing,
+            parallelism,
+            phase,
+            json,
+            control_socket: _,
         } = self;
 
-        ensure_root()?;
+        // `--ci` may be given to the planner subcommand, or before it
+        let ci = planner
+            .as_ref()
+            .map_or(settings.ci, |planner| planner.common_settings().ci);
+        let offline = planner.as_ref().map_or(settings.offline, |planner| {
+            planner.common_settings().offline
+        });
+        let no_confirm = no_confirm || ci;
+        let github_output = match ci {
+            true => std::env::var_os("GITHUB_OUTPUT").map(PathBuf::from),
+            false => None,
+        };
 
         let existing_receipt: Option<InstallPlan> = match Path::new(RECEIPT_LOCATION).exists() {
             true => {
                 tracing::trace!("Reading existing receipt");
-                let install_plan_string = tokio::fs::read_to_string(&RECEIPT_LOCATION)
-                    .await
-                    .wrap_err("Reading plan")?;
                 Some(
-                    serde_json::from_str(&install_plan_string).wrap_err_with(|| {
-                        format!("Unable to parse existing receipt `{RECEIPT_LOCATION}`, it may be from an incompatible version of `nix-installer`. Try running `/nix/nix-installer uninstall`, then installing again.")
+                    crate::plan::read_receipt(RECEIPT_LOCATION).wrap_err_with(|| {
+                        message!(InstallReceiptUnparsable, receipt = RECEIPT_LOCATION)
                     })?,
                 )
             },
@@ -108,36 +248,31 @@ impl CommandExecute for Install {
             false => format!("curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix/tag/v{} | sh -s -- uninstall", env!("CARGO_PKG_VERSION")),
         };
 
+        // Only a plan made here can be planned again with other settings
+        let mut customizable_planner = None;
         let mut install_plan = match (planner, plan) {
             (Some(planner), None) => {
                 let chosen_planner: Box<dyn Planner> = planner.clone().boxed();
 
                 match existing_receipt {
                     Some(existing_receipt) => {
-                        if let Err(e) = existing_receipt.check_compatible() {
-                            eprintln!(
-                                "{}",
-                                format!("\
-                                    {e}\n\
-                                    \n\
-                                    Found existing plan in `{RECEIPT_LOCATION}` which was created by a version incompatible `nix-installer`.\n\
-                                    {EXISTING_INCOMPATIBLE_PLAN_GUIDANCE}\n\
-                                ").red()
-                            );
-                            return Ok(ExitCode::FAILURE)
-                        }
-                        if existing_receipt.planner.typetag_name() != chosen_planner.typetag_name() {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used a different planner, try uninstalling the existing install with `{uninstall_command}`").red());
-                            return Ok(ExitCode::FAILURE)
-                        }
-                        if existing_receipt.planner.settings().map_err(|e| eyre!(e))? != chosen_planner.settings().map_err(|e| eyre!(e))? {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
-                            return Ok(ExitCode::FAILURE)
+                        match check_existing_receipt(
+                            existing_receipt,
+                            chosen_planner.as_ref(),
+                            verify_existing,
+                            json,
+                            github_output.as_deref(),
+                            extra_plan.is_some(),
+                            &uninstall_command,
+                        )
+                        .await?
+                        {
+                            ControlFlow::Continue(existing_receipt) => existing_receipt,
+                            ControlFlow::Break(exit_code) => return Ok(exit_code),
                         }
-                        eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}`, with the same settings, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").red());
-                        return Ok(ExitCode::SUCCESS)
                     },
                     None => {
+                        customizable_planner = Some(planner.clone());
                         let res = planner.plan().await;
                         match res {
                             Ok(plan) => plan,
@@ -147,16 +282,13 @@ impl CommandExecute for Install {
                                     return Ok(ExitCode::FAILURE);
                                 }
                                 return Err(err)?;
-                            }
+                            },
                         }
                     },
                 }
             },
             (None, Some(plan_path)) => {
-                let install_plan_string = tokio::fs::read_to_string(&plan_path)
-                .await
-                .wrap_err("Reading plan")?;
-                serde_json::from_str(&install_plan_string)?
+                crate::plan::read_receipt(&plan_path).wrap_err("Reading plan")?
             },
             (None, None) => {
                 let builtin_planner = BuiltinPlanner::from_common_settings(settings.clone())
@@ -165,33 +297,23 @@ impl CommandExecute for Install {
 
                 match existing_receipt {
                     Some(existing_receipt) => {
-                        if let Err(e) = existing_receipt.check_compatible() {
-                            eprintln!(
-                                "{}",
-                                format!("\
-                                    {e}\n\
-                                    \n\
-                                    Found existing plan in `{RECEIPT_LOCATION}` which was created by a version incompatible `nix-installer`.\n\
-                                    {EXISTING_INCOMPATIBLE_PLAN_GUIDANCE}\n\
-                                ").red()
-                            );
-                            return Ok(ExitCode::FAILURE)
-                        }
-                        if existing_receipt.planner.typetag_name() != builtin_planner.typetag_name() {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used a different planner, try uninstalling the existing install with `{uninstall_command}`").red());
-                            return Ok(ExitCode::FAILURE)
-                        }
-                        if existing_receipt.planner.settings().map_err(|e| eyre!(e))? != builtin_planner.settings().map_err(|e| eyre!(e))? {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}` which used different planner settings, try uninstalling the existing install with `{uninstall_command}`").red());
-                            return Ok(ExitCode::FAILURE)
-                        }
-                        if existing_receipt.actions.iter().all(|v| v.state == ActionState::Completed) {
-                            eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}`, with the same settings, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").yellow());
-                            return Ok(ExitCode::SUCCESS)
+                        match check_existing_receipt(
+                            existing_receipt,
+                            builtin_planner.clone().boxed().as_ref(),
+                            verify_existing,
+                            json,
+                            github_output.as_deref(),
+                            extra_plan.is_some(),
+                            &uninstall_command,
+                        )
+                        .await?
+                        {
+                            ControlFlow::Continue(existing_receipt) => existing_receipt,
+                            ControlFlow::Break(exit_code) => return Ok(exit_code),
                         }
-                        existing_receipt
                     },
                     None => {
+                        customizable_planner = Some(builtin_planner.clone());
                         let res = builtin_planner.plan().await;
                         match res {
                             Ok(plan) => plan,
@@ -201,50 +323,177 @@ impl CommandExecute for Install {
                                     return Ok(ExitCode::FAILURE);
                                 }
                                 return Err(err)?;
-                            }
+                            },
                         }
                     },
                 }
             },
-            (Some(_), Some(_)) => return Err(eyre!("`--plan` conflicts with passing a planner, a planner creates plans, so passing an existing plan doesn't make sense")),
+            (Some(_), Some(_)) => return Err(eyre!(message!(InstallPlanConflictsWithPlanner))),
         };
 
-        if let Err(err) = install_plan.pre_install_check().await {
-            if let Some(expected) = err.expected() {
-                eprintln!("{}", expected.red());
-                return Ok(ExitCode::FAILURE);
+        if let ControlFlow::Break(exit_code) = prepare_plan(
+            &mut install_plan,
+            extra_plan.as_deref(),
+            allowed_operations.as_deref(),
+        )
+        .await?
+        {
+            return Ok(exit_code);
+        }
+
+        if dry_run {
+            match format {
+                DescriptionFormat::Human => {
+                    let description = install_plan
+                        .describe_dry_run()
+                        .await
+                        .map_err(|e| eyre!(e))?;
+                    println!("{description}");
+                },
+                DescriptionFormat::Json => {
+                    let description = install_plan
+                        .describe_install_structured()
+                        .await
+                        .map_err(|e| eyre!(e))?;
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&description)
+                            .wrap_err("Serializing the plan description")?
+                    );
+                },
             }
-            Err(err)?
+            return Ok(ExitCode::SUCCESS);
         }
 
         if !no_confirm {
             let mut currently_explaining = explain;
             loop {
-                match interaction::prompt(
-                    install_plan
-                        .describe_install(currently_explaining)
-                        .await
-                        .map_err(|e| eyre!(e))?,
-                    PromptChoice::Yes,
-                    currently_explaining,
-                )
-                .await?
-                {
+                let description = install_plan
+                    .describe_install(currently_explaining)
+                    .await
+                    .map_err(|e| eyre!(e))?;
+                let choice = match customizable_planner {
+                    // Customizing needs the terminal
+                    Some(_) if control.is_none() => {
+                        interaction::prompt_customizable(
+                            description,
+                            PromptChoice::Yes,
+                            currently_explaining,
+                        )
+                        .await?
+                    },
+                    _ => {
+                        control::prompt(
+                            control,
+                            description,
+                            PromptChoice::Yes,
+                            currently_explaining,
+                        )
+                        .await?
+                    },
+                };
+                match choice {
                     PromptChoice::Yes => break,
                     PromptChoice::Explain => currently_explaining = true,
                     PromptChoice::No => {
-                        interaction::clean_exit_with_message(
-                            "Okay, not continuing with the installation. Bye!",
+                        control::exit_declined(control, message!(InstallDeclined)).await
+                    },
+                    PromptChoice::Customize => {
+                        let Some(planner) = customizable_planner.as_mut() else {
+                            continue;
+                        };
+                        customize(planner)?;
+                        install_plan = match planner.clone().plan().await {
+                            Ok(plan) => plan,
+                            Err(err) => {
+                                if let Some(expected) = err.expected() {
+                                    eprintln!("{}", expected.red());
+                                    return Ok(ExitCode::FAILURE);
+                                }
+                                return Err(err)?;
+                            },
+                        };
+                        if let ControlFlow::Break(exit_code) = prepare_plan(
+                            &mut install_plan,
+                            extra_plan.as_deref(),
+                            allowed_operations.as_deref(),
                         )
-                        .await
+                        .await?
+                        {
+                            return Ok(exit_code);
+                        }
                     },
                 }
             }
         }
 
+        let mut reporter = match report_to {
+            Some(_) if offline => {
+                tracing::warn!("Not reporting install outcome, `--offline` forbids network access");
+                None
+            },
+            Some(report_to) => {
+                match Reporter::new(
+                    report_to,
+                    std::env::var("NIX_INSTALLER_REPORT_AUTHORIZATION").ok(),
+                    settings.proxy.clone(),
+                    settings.ssl_cert_file.as_deref(),
+                )
+                .await
+                {
+                    Ok(reporter) if ci => Some(reporter.without_retries()),
+                    Ok(reporter) => Some(reporter),
+                    Err(e) => {
+                        tracing::warn!("Not reporting install outcome: {e}");
+                        None
+                    },
+                }
+            },
+            None => None,
+        };
+        if let Some(reporter) = &reporter {
+            reporter.plan(&install_plan).await;
+        }
+        let mut event_log = event_log.map(EventLog::new);
+        if let Some(event_log) = &mut event_log {
+            event_log.started(Operation::Install, &install_plan).await;
+        }
+
         let (tx, rx1) = signal_channel().await?;
+        if let Some(control) = control {
+            control.cancel_with(tx.clone());
+        }
 
-        match install_plan.install(rx1).await {
+        let progress = fan_out(
+            reporter
+                .as_mut()
+                .map(Reporter::progress)
+                .into_iter()
+                .chain(control.map(Control::progress))
+                .chain(event_log.as_mut().map(EventLog::progress))
+                .collect(),
+        );
+        RunLog::global().record("install", Some(crate::state_dir::of_plan(&install_plan)));
+        let install_result = match phase {
+            Some(phase) => {
+                install_plan
+                    .install_phase(phase, rx1, progress, parallelism)
+                    .await
+            },
+            None => {
+                install_plan
+                    .install_with_parallelism(rx1, progress, parallelism)
+                    .await
+            },
+        };
+        if let Some(reporter) = reporter {
+            reporter.result(&install_result).await;
+        }
+        if let Some(event_log) = &mut event_log {
+            event_log.finished(&install_result).await;
+        }
+
+        match install_result {
             Err(err) => {
                 // Attempt to copy self to the store if possible, but since the install failed, this might not work, that's ok.
                 copy_self_to_nix_dir().await.ok();
@@ -260,10 +509,11 @@ impl CommandExecute for Install {
                         tracing::error!("{:?}", error);
                     };
 
-                    eprintln!("{}", "Installation failure, offering to revert...".red());
+                    eprintln!("{}", message!(InstallFailureOfferingRevert).red());
                     let mut currently_explaining = explain;
                     loop {
-                        match interaction::prompt(
+                        match control::prompt(
+                            control,
                             install_plan
                                 .describe_uninstall(currently_explaining)
                                 .await
@@ -275,16 +525,29 @@ impl CommandExecute for Install {
                         {
                             PromptChoice::Yes => break,
                             PromptChoice::Explain => currently_explaining = true,
+                            // Not offered by `prompt`
+                            PromptChoice::Customize => (),
                             PromptChoice::No => {
-                                interaction::clean_exit_with_message(
-                                    "Okay, didn't do anything! Bye!",
-                                )
-                                .await
+                                control::exit_declined(control, message!(InstallRevertDeclined))
+                                    .await
                             },
                         }
                     }
                     let rx2 = tx.subscribe();
-                    let res = install_plan.uninstall(rx2).await;
+                    if let Some(event_log) = &mut event_log {
+                        event_log.started(Operation::Uninstall, &install_plan).await;
+                    }
+                    let progress = fan_out(
+                        control
+                            .map(Control::progress)
+                            .into_iter()
+                            .chain(event_log.as_mut().map(EventLog::progress))
+                            .collect(),
+                    );
+                    let res = install_plan.uninstall_with_progress(rx2, progress).await;
+                    if let Some(event_log) = &mut event_log {
+                        event_log.finished(&res).await;
+                    }
 
                     match res {
                         Err(NixInstallerError::ActionRevert(errs)) => {
@@ -306,8 +569,7 @@ impl CommandExecute for Install {
                                 "\
                                 {message}\n\
                                 ",
-                                message =
-                                    "Partial Nix install was uninstalled successfully!".bold(),
+                                message = message!(InstallPartialInstallReverted).bold(),
                             );
                         },
                     }
@@ -322,39 +584,103 @@ impl CommandExecute for Install {
                 }
             },
             Ok(_) => {
+                if let Some((completed, next)) =
+                    phase.and_then(|phase| Some((phase, phase.next()?)))
+                {
+                    // So the install can be reverted from the machine before it is finished
+                    copy_self_to_nix_dir().await.ok();
+                    let message = message!(InstallPhaseCompleted, phase = completed, next = next);
+                    if json {
+                        eprintln!("{message}");
+                    } else {
+                        println!("{message}");
+                    }
+                    emit_result(
+                        &InstallResult {
+                            outcome: Outcome::PhaseCompleted,
+                            receipt_date: Some(utc_date(SystemTime::now())),
+                            installer_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                            problems: vec![],
+                            warnings: install_plan.warnings().to_vec(),
+                            deprecated: deprecation::used().to_vec(),
+                            phase: Some(PhaseResult { completed, next }),
+                        },
+                        json,
+                        github_output.as_deref(),
+                    )?;
+                    return Ok(ExitCode::SUCCESS);
+                }
+
                 copy_self_to_nix_dir()
                     .await
                     .wrap_err("Copying `nix-installer` to `/nix/nix-installer`")?;
 
-                let phase1_receipt_path = Path::new(PHASE1_RECEIPT_LOCATION);
-                if phase1_receipt_path.exists() {
-                    tracing::debug!("Removing pre-existing uninstall phase 1 receipt at {PHASE1_RECEIPT_LOCATION} after successful install");
-                    crate::util::remove_file(phase1_receipt_path, OnMissing::Ignore)
-                        .await
-                        .wrap_err_with(|| format!("Failed to remove uninstall phase 1 receipt at {PHASE1_RECEIPT_LOCATION}"))?;
-                }
-
-                let phase2_receipt_path = Path::new(PHASE2_RECEIPT_LOCATION);
-                if phase2_receipt_path.exists() {
-                    tracing::debug!("Removing pre-existing uninstall phase 2 receipt at {PHASE2_RECEIPT_LOCATION} after successful install");
-                    crate::util::remove_file(phase2_receipt_path, OnMissing::Ignore)
-                        .await
-                        .wrap_err_with(|| format!("Failed to remove uninstall phase 2 receipt at {PHASE2_RECEIPT_LOCATION}"))?;
+                // Including those left by releases which kept them elsewhere
+                let state_dir = crate::state_dir::of_plan(&install_plan);
+                for phase_receipt_path in [
+                    crate::state_dir::PHASE1_RECEIPT,
+                    crate::state_dir::PHASE2_RECEIPT,
+                ]
+                .into_iter()
+                .flat_map(|name| crate::state_dir::read_locations(&state_dir, name))
+                {
+                    if phase_receipt_path.exists() {
+                        tracing::debug!("Removing pre-existing uninstall phase receipt at {} after successful install", phase_receipt_path.display());
+                        crate::util::remove_file(&phase_receipt_path, OnMissing::Ignore)
+                            .await
+                            .wrap_err_with(|| {
+                                format!(
+                                    "Failed to remove uninstall phase receipt at {}",
+                                    phase_receipt_path.display()
+                                )
+                            })?;
+                    }
                 }
 
-                println!(
+                let shell_reminder = match std::env::var("SHELL") {
+                    Ok(val) if val.contains("fish") => {
+                        ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish"
+                    },
+                    Ok(_) | Err(_) => ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh",
+                };
+                let mut success = format!(
                     "\
                     {success}\n\
-                    To get started using Nix, open a new shell or run `{shell_reminder}`\n\
+                    {get_started}\n\
                     ",
-                    success = "Nix was installed successfully!".green().bold(),
-                    shell_reminder = match std::env::var("SHELL") {
-                        Ok(val) if val.contains("fish") =>
-                            ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish".bold(),
-                        Ok(_) | Err(_) =>
-                            ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh".bold(),
-                    },
+                    success = message!(InstallSuccess).green().bold(),
+                    get_started = message!(InstallGetStarted, command = shell_reminder.bold()),
                 );
+                for (path, backup) in
+                    crate::backup::backup_locations(&serde_json::to_value(&install_plan)?)
+                {
+                    success.push_str(&message!(
+                        InstallBackedUp,
+                        path = path.display(),
+                        backup = backup.display()
+                    ));
+                    success.push('\n');
+                }
+                success.push_str(&crate::warning::describe(install_plan.warnings()));
+                if json {
+                    // Leave stdout to the result
+                    eprintln!("{success}");
+                } else {
+                    println!("{success}");
+                }
+                emit_result(
+                    &InstallResult {
+                        outcome: Outcome::Installed,
+                        receipt_date: Some(utc_date(SystemTime::now())),
+                        installer_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                        problems: vec![],
+                        warnings: install_plan.warnings().to_vec(),
+                        deprecated: deprecation::used().to_vec(),
+                        phase: None,
+                    },
+                    json,
+                    github_output.as_deref(),
+                )?;
             },
         }
 
@@ -362,6 +688,77 @@ impl CommandExecute for Install {
     }
 }
 
+/// Extend `install_plan` with the extra plan, check it against the allowed operations, and run its
+/// pre-install checks, breaking with the exit code if any of those fail as expected
+async fn prepare_plan(
+    install_plan: &mut InstallPlan,
+    extra_plan: Option<&Path>,
+    allowed_operations: Option<&Path>,
+) -> eyre::Result<ControlFlow<ExitCode>> {
+    if let Some(extra_plan) = extra_plan {
+        let extended = match ExtraPlan::from_file(extra_plan).await {
+            Ok(extra_plan) => install_plan.extend_with(extra_plan.into_actions()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = extended {
+            if let Some(expected) = err.expected() {
+                eprintln!("{}", expected.red());
+                return Ok(ControlFlow::Break(ExitCode::FAILURE));
+            }
+            Err(err)?
+        }
+    }
+
+    if let Some(allowed_operations) = allowed_operations {
+        let checked = match PrivilegedOperations::from_file(allowed_operations).await {
+            Ok(allowed) => install_plan.check_allowed_operations(&allowed),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = checked {
+            if let Some(expected) = err.expected() {
+                eprintln!("{}", expected.red());
+                return Ok(ControlFlow::Break(ExitCode::FAILURE));
+            }
+            Err(err)?
+        }
+    }
+
+    if let Err(err) = install_plan.pre_install_check().await {
+        if let Some(expected) = err.expected() {
+            eprintln!("{}", expected.red());
+            return Ok(ControlFlow::Break(ExitCode::FAILURE));
+        }
+        Err(err)?
+    }
+
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Let the user toggle the [`OptionalPart`]s of `planner`, changing its settings to match
+fn customize(planner: &mut BuiltinPlanner) -> eyre::Result<()> {
+    let parts = OptionalPart::of(planner);
+    let labels = parts
+        .iter()
+        .map(|part| format!("{} ({})", part.description(), part.flag()))
+        .collect::<Vec<_>>();
+    let mut enabled = parts
+        .iter()
+        .map(|part| part.is_enabled(planner))
+        .collect::<Vec<_>>();
+    interaction::toggle_list(
+        &mut std::io::stdin().lock(),
+        &mut std::io::stdout(),
+        &labels,
+        &mut enabled,
+    )?;
+    for (part, enabled) in parts.iter().zip(enabled) {
+        if part.is_enabled(planner) != enabled {
+            part.set_enabled(planner, enabled);
+        }
+    }
+    Ok(())
+}
+
 #[tracing::instrument(level = "debug")]
 async fn copy_self_to_nix_dir() -> Result<(), std::io::Error> {
     let path = std::env::current_exe()?;
@@ -369,3 +766,416 @@ async fn copy_self_to_nix_dir() -> Result<(), std::io::Error> {
     tokio::fs::set_permissions("/nix/nix-installer", PermissionsExt::from_mode(0o0755)).await?;
     Ok(())
 }
+
+/// Exit code when Nix is already installed with the same planner and settings, but fails `--verify-existing`
+const EXIT_EXISTING_UNHEALTHY: u8 = 3;
+
+/// How the install recorded in an existing receipt relates to the one being asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ExistingReceipt {
+    Incompatible(String),
+    DifferentPlanner,
+    DifferentSettings,
+    /// The same planner and settings, and every action completed
+    Completed,
+    /// The same planner and settings, but the install never finished
+    Incomplete,
+}
+
+/// What `install` concluded, as printed by `--json`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Outcome {
+    Installed,
+    AlreadyInstalled,
+    /// Already installed with the same planner and settings, but failing `--verify-existing`
+    AlreadyInstalledUnhealthy,
+    /// An existing install conflicts with the one asked for
+    Conflict,
+    /// A phase of a phased install completed, but not the install
+    PhaseCompleted,
+}
+
+impl Outcome {
+    fn exit_code(self) -> ExitCode {
+        match self {
+            Outcome::Installed | Outcome::AlreadyInstalled | Outcome::PhaseCompleted => {
+                ExitCode::SUCCESS
+            },
+            Outcome::AlreadyInstalledUnhealthy => ExitCode::from(EXIT_EXISTING_UNHEALTHY),
+            Outcome::Conflict => ExitCode::FAILURE,
+        }
+    }
+}
+
+/// The object printed by `--json`, and written to `$GITHUB_OUTPUT` by `--ci`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+struct InstallResult {
+    outcome: Outcome,
+    /// When the receipt was written, as `YYYY-MM-DD` (UTC)
+    receipt_date: Option<String>,
+    /// The version of `nix-installer` which wrote the receipt
+    installer_version: Option<String>,
+    problems: Vec<String>,
+    /// Raised while planning and installing, left out when there are none
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<Warning>,
+    /// The deprecated flags and environment variables used, left out when there are none
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    deprecated: Vec<Deprecation>,
+    /// With `--phase`, unless it finished the install
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase: Option<PhaseResult>,
+}
+
+/// The phase an `install --phase` completed, and the one to run next
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+struct PhaseResult {
+    completed: Phase,
+    next: Phase,
+}
+
+/// Print `result` if `json`, and write it to the `github_output` file if any
+fn emit_result(
+    result: &InstallResult,
+    json: bool,
+    github_output: Option<&Path>,
+) -> eyre::Result<()> {
+    let serialized = serde_json::to_string(result).wrap_err("Serializing the install result")?;
+    if json {
+        println!("{serialized}");
+    }
+    if let Some(github_output) = github_output {
+        write_github_output(github_output, &serialized).wrap_err_with(|| {
+            format!(
+                "Writing the install result to `{}`",
+                github_output.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Append `result` as the `result` output of the step, to the file GitHub Actions names in `$GITHUB_OUTPUT`
+fn write_github_output(path: &Path, result: &str) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "result={result}")
+}
+
+fn compare_receipt(
+    existing_receipt: &InstallPlan,
+    planner: &dyn Planner,
+) -> eyre::Result<ExistingReceipt> {
+    if let Err(e) = existing_receipt.check_compatible() {
+        return Ok(ExistingReceipt::Incompatible(e.to_string()));
+    }
+    if existing_receipt.planner.typetag_name() != planner.typetag_name() {
+        return Ok(ExistingReceipt::DifferentPlanner);
+    }
+    if existing_receipt.planner.settings().map_err(|e| eyre!(e))?
+        != planner.settings().map_err(|e| eyre!(e))?
+    {
+        return Ok(ExistingReceipt::DifferentSettings);
+    }
+    if existing_receipt
+        .actions
+        .iter()
+        .all(|v| v.state == ActionState::Completed)
+    {
+        Ok(ExistingReceipt::Completed)
+    } else {
+        Ok(ExistingReceipt::Incomplete)
+    }
+}
+
+/// The outcome of finding `existing` with `problems`, or `None` if its install should be resumed
+fn existing_outcome(existing: &ExistingReceipt, problems: &[String]) -> Option<Outcome> {
+    match existing {
+        ExistingReceipt::Incomplete if problems.is_empty() => None,
+        ExistingReceipt::Completed if problems.is_empty() => Some(Outcome::AlreadyInstalled),
+        ExistingReceipt::Completed => Some(Outcome::AlreadyInstalledUnhealthy),
+        _ => Some(Outcome::Conflict),
+    }
+}
+
+/// Decide what to do about the install recorded in an existing receipt
+///
+/// Continues with the receipt if its install should be resumed, otherwise breaks with the exit code to finish with.
+async fn check_existing_receipt(
+    existing_receipt: InstallPlan,
+    planner: &dyn Planner,
+    verify_existing: bool,
+    json: bool,
+    github_output: Option<&Path>,
+    extra_plan: bool,
+    uninstall_command: &str,
+) -> eyre::Result<ControlFlow<ExitCode, InstallPlan>> {
+    let existing = compare_receipt(&existing_receipt, planner)?;
+    let problems = match &existing {
+        ExistingReceipt::Incompatible(error) => vec![message!(
+            InstallExistingPlanIncompatible,
+            error = error,
+            receipt = RECEIPT_LOCATION
+        )],
+        ExistingReceipt::DifferentPlanner => vec![message!(
+            InstallExistingPlanDifferentPlanner,
+            receipt = RECEIPT_LOCATION,
+            uninstall_command = uninstall_command
+        )],
+        ExistingReceipt::DifferentSettings => vec![message!(
+            InstallExistingPlanDifferentSettings,
+            receipt = RECEIPT_LOCATION,
+            uninstall_command = uninstall_command
+        )],
+        ExistingReceipt::Completed if verify_existing => {
+            match crate::self_test::self_test().await {
+                Ok(()) => vec![],
+                Err(failures) => failures.iter().map(ToString::to_string).collect(),
+            }
+        },
+        ExistingReceipt::Completed => vec![],
+        ExistingReceipt::Incomplete if extra_plan => vec![message!(
+            InstallExtraPlanWithExistingReceipt,
+            receipt = RECEIPT_LOCATION,
+            uninstall_command = uninstall_command
+        )],
+        ExistingReceipt::Incomplete => vec![],
+    };
+
+    let Some(outcome) = existing_outcome(&existing, &problems) else {
+        return Ok(ControlFlow::Continue(existing_receipt));
+    };
+
+    let receipt_date = tokio::fs::metadata(RECEIPT_LOCATION)
+        .await
+        .and_then(|v| v.modified())
+        .ok()
+        .map(utc_date);
+    let installer_version = existing_receipt.version.to_string();
+    let date = receipt_date.as_deref().unwrap_or("an unknown date");
+    match outcome {
+        Outcome::AlreadyInstalled => eprintln!(
+            "{}",
+            message!(
+                InstallExistingPlanCompleted,
+                date = date,
+                version = installer_version
+            )
+        ),
+        Outcome::AlreadyInstalledUnhealthy => eprintln!(
+            "{}",
+            message!(
+                InstallExistingPlanUnhealthy,
+                date = date,
+                version = installer_version,
+                problems = problems
+                    .iter()
+                    .map(|v| format!("  {v}"))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                uninstall_command = uninstall_command
+            )
+            .yellow()
+        ),
+        Outcome::Conflict | Outcome::Installed | Outcome::PhaseCompleted => {
+            for problem in &problems {
+                eprintln!("{}", problem.red());
+            }
+        },
+    }
+
+    emit_result(
+        &InstallResult {
+            outcome,
+            receipt_date,
+            installer_version: Some(installer_version),
+            problems,
+            warnings: vec![],
+            deprecated: deprecation::used().to_vec(),
+            phase: None,
+        },
+        json,
+        github_output,
+    )?;
+
+    Ok(ControlFlow::Break(outcome.exit_code()))
+}
+
+/// The `YYYY-MM-DD` (UTC) date of `time`
+pub(super) fn utc_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|v| v.as_secs() / 86_400)
+        .unwrap_or_default() as i64;
+    // Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        process::ExitCode,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::deprecation::{Deprecation, DEPRECATED};
+    use crate::warning::{Warning, WarningKind};
+    use crate::InstallPlan;
+
+    use super::{
+        compare_receipt, emit_result, existing_outcome, utc_date, ExistingReceipt, InstallResult,
+        Outcome,
+    };
+
+    const LINUX: &str = include_str!("../../../tests/fixtures/linux/linux.json");
+
+    /// The Linux fixture, as written by this version, with `edit` applied
+    fn receipt(edit: impl FnOnce(&mut serde_json::Value)) -> eyre::Result<InstallPlan> {
+        let mut receipt: serde_json::Value = serde_json::from_str(LINUX)?;
+        receipt["version"] = env!("CARGO_PKG_VERSION").into();
+        edit(&mut receipt);
+        Ok(serde_json::from_value(receipt)?)
+    }
+
+    #[test]
+    fn healthy_install_is_already_installed() -> eyre::Result<()> {
+        let existing = receipt(|_| ())?;
+        let requested = receipt(|_| ())?;
+
+        let comparison = compare_receipt(&existing, requested.planner.as_ref())?;
+        assert_eq!(comparison, ExistingReceipt::Completed);
+        let outcome = existing_outcome(&comparison, &[]);
+        assert_eq!(outcome, Some(Outcome::AlreadyInstalled));
+        assert_eq!(Outcome::AlreadyInstalled.exit_code(), ExitCode::SUCCESS);
+        Ok(())
+    }
+
+    #[test]
+    fn matching_but_broken_install_is_unhealthy() -> eyre::Result<()> {
+        let existing = receipt(|_| ())?;
+        let requested = receipt(|_| ())?;
+
+        let comparison = compare_receipt(&existing, requested.planner.as_ref())?;
+        let outcome = existing_outcome(&comparison, &["Shell `bash` failed self-test".to_string()]);
+        assert_eq!(outcome, Some(Outcome::AlreadyInstalledUnhealthy));
+        assert_eq!(
+            Outcome::AlreadyInstalledUnhealthy.exit_code(),
+            ExitCode::from(3)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn conflicting_settings_are_a_conflict() -> eyre::Result<()> {
+        let existing = receipt(|_| ())?;
+        let requested = receipt(|receipt| {
+            receipt["planner"]["settings"]["nix_build_user_count"] = 16.into();
+        })?;
+
+        let comparison = compare_receipt(&existing, requested.planner.as_ref())?;
+        assert_eq!(comparison, ExistingReceipt::DifferentSettings);
+        let outcome = existing_outcome(&comparison, &["different settings".to_string()]);
+        assert_eq!(outcome, Some(Outcome::Conflict));
+        assert_eq!(Outcome::Conflict.exit_code(), ExitCode::FAILURE);
+
+        // An unfinished install with the same settings is resumed instead
+        let unfinished = receipt(|receipt| {
+            receipt["actions"][0]["state"] = "Uncompleted".into();
+        })?;
+        let comparison = compare_receipt(&unfinished, existing.planner.as_ref())?;
+        assert_eq!(comparison, ExistingReceipt::Incomplete);
+        assert_eq!(existing_outcome(&comparison, &[]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn receipt_dates_are_utc() {
+        assert_eq!(utc_date(SystemTime::UNIX_EPOCH), "1970-01-01");
+        assert_eq!(
+            utc_date(SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_251_199)),
+            "2024-02-29"
+        );
+    }
+
+    #[test]
+    fn ci_appends_the_result_to_github_output() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let github_output = dir.path().join("set_output");
+        std::fs::write(&github_output, "cache-hit=true\n")?;
+
+        emit_result(
+            &InstallResult {
+                outcome: Outcome::AlreadyInstalled,
+                receipt_date: Some("2024-02-29".into()),
+                installer_version: Some("3.0.0".into()),
+                problems: vec![],
+                warnings: vec![],
+                deprecated: vec![],
+                phase: None,
+            },
+            false,
+            Some(&github_output),
+        )?;
+        assert_eq!(
+            std::fs::read_to_string(&github_output)?,
+            "cache-hit=true\n\
+            result={\"outcome\":\"already-installed\",\"receipt_date\":\"2024-02-29\",\"installer_version\":\"3.0.0\",\"problems\":[]}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn the_result_carries_warnings() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let github_output = dir.path().join("set_output");
+
+        emit_result(
+            &InstallResult {
+                outcome: Outcome::Installed,
+                receipt_date: Some("2024-02-29".into()),
+                installer_version: Some("3.0.0".into()),
+                problems: vec![],
+                warnings: vec![Warning::new(
+                    WarningKind::UnknownNixSetting,
+                    "Nix 2.24 does not know the setting `frobnicate`",
+                )],
+                deprecated: vec![Deprecation::from(&DEPRECATED[0])],
+                phase: None,
+            },
+            false,
+            Some(&github_output),
+        )?;
+        let written = std::fs::read_to_string(&github_output)?;
+        let result: serde_json::Value =
+            serde_json::from_str(written.trim_end().trim_start_matches("result="))?;
+        assert_eq!(
+            result["warnings"],
+            serde_json::json!([{
+                "kind": "unknown_nix_setting",
+                "message": "Nix 2.24 does not know the setting `frobnicate`",
+            }])
+        );
+        assert_eq!(
+            result["deprecated"],
+            serde_json::json!([{
+                "form": "flag",
+                "name": "--daemon-user-count",
+                "replacement": "--nix-build-user-count",
+                "removed_in": "0.40.0",
+            }])
+        );
+        Ok(())
+    }
+}
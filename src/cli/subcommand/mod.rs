@@ -1,15 +1,24 @@
+mod emit_invocation;
+mod inspect;
 mod install;
+mod nix_in_use;
 mod plan;
 mod repair;
+mod restore_backups;
+mod scheduled_uninstall;
 mod self_test;
 mod split_receipt;
+mod status;
 mod uninstall;
 
+use inspect::Inspect;
 use install::Install;
 use plan::Plan;
 use repair::Repair;
+use restore_backups::RestoreBackups;
 use self_test::SelfTest;
 use split_receipt::SplitReceipt;
+use status::Status;
 use uninstall::Uninstall;
 
 #[allow(clippy::large_enum_variant)]
@@ -21,4 +30,7 @@ pub enum NixInstallerSubcommand {
     SelfTest(SelfTest),
     Plan(Plan),
     SplitReceipt(SplitReceipt),
+    RestoreBackups(RestoreBackups),
+    Inspect(Inspect),
+    Status(Status),
 }
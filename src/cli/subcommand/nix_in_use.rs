@@ -0,0 +1,355 @@
+//! Keeping `nix-installer uninstall` from holding `/nix` busy itself
+//!
+//! Removing the Nix store fails with `EBUSY` (or leaves a volume which cannot be unmounted) while a
+//! process has its current directory inside `/nix`, or is running a binary from it. Often that
+//! process is `nix-installer` itself, or the shell which started it:
+//!
+//! * If `nix-installer`'s current directory is inside `/nix`, it changes to `/`.
+//! * If `nix-installer` runs from `/nix` (like `/nix/nix-installer`), it copies itself to a
+//!   temporary directory and re-executes that copy with the same arguments. The copy is removed
+//!   when the uninstall finishes.
+//! * If a shell (or any other ancestor process) has its current directory inside `/nix`, that
+//!   cannot be fixed from here, so the uninstall stops before reverting anything.
+
+use std::{
+    ffi::{CString, OsStr},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::WrapErr;
+use rand::Rng;
+
+#[cfg(not(target_os = "macos"))]
+use crate::util::host_path;
+
+const NIX_DIR: &str = "/nix";
+/// Set for a re-executed copy of `nix-installer`, naming the copy so it can remove itself
+const REEXEC_COPY_ENV: &str = "NIX_INSTALLER_REEXEC_COPY";
+/// Ancestors are walked up to `init`, but never further than this
+const MAX_ANCESTORS: usize = 32;
+
+/// A process, other than `nix-installer`, whose current directory is inside `/nix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BusyAncestor {
+    pub(crate) pid: i32,
+    pub(crate) name: String,
+    pub(crate) cwd: PathBuf,
+}
+
+pub(crate) fn is_in_nix(path: &Path) -> bool {
+    path.starts_with(NIX_DIR)
+}
+
+/// Change the current directory to `/` if it is inside `/nix`
+pub(crate) fn leave_nix_directory() -> std::io::Result<()> {
+    if let Ok(current_dir) = std::env::current_dir() {
+        if is_in_nix(&current_dir) {
+            tracing::debug!("Changing current directory to be outside of `/nix`");
+            std::env::set_current_dir("/")?;
+        }
+    }
+    Ok(())
+}
+
+/// The closest ancestor of this process whose current directory is inside `/nix`
+pub(crate) async fn busy_ancestor() -> Option<BusyAncestor> {
+    busy_ancestor_from(nix::unistd::getppid().as_raw()).await
+}
+
+async fn busy_ancestor_from(mut pid: i32) -> Option<BusyAncestor> {
+    for _ in 0..MAX_ANCESTORS {
+        if pid <= 1 {
+            break;
+        }
+        // Processes we cannot inspect (they may have exited already) end the walk
+        let (name, parent) = process_info(pid).await?;
+        if let Some(cwd) = process_cwd(pid).await {
+            if is_in_nix(&cwd) {
+                return Some(BusyAncestor { pid, name, cwd });
+            }
+        }
+        pid = parent;
+    }
+    None
+}
+
+/// The name and parent of `pid`
+#[cfg(not(target_os = "macos"))]
+async fn process_info(pid: i32) -> Option<(String, i32)> {
+    let stat = tokio::fs::read_to_string(host_path(format!("/proc/{pid}/stat")))
+        .await
+        .ok()?;
+    parse_proc_stat(&stat)
+}
+
+#[cfg(not(target_os = "macos"))]
+async fn process_cwd(pid: i32) -> Option<PathBuf> {
+    tokio::fs::read_link(host_path(format!("/proc/{pid}/cwd")))
+        .await
+        .ok()
+}
+
+#[cfg(target_os = "macos")]
+async fn process_info(pid: i32) -> Option<(String, i32)> {
+    let output = crate::command_output(
+        tokio::process::Command::new("/bin/ps")
+            .args(["-o", "ppid=,comm=", "-p", &pid.to_string()])
+            .stdin(std::process::Stdio::null())
+            .process_group(0),
+    )
+    .await
+    .ok()?;
+    parse_ps(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(target_os = "macos")]
+async fn process_cwd(pid: i32) -> Option<PathBuf> {
+    let output = crate::command_output(
+        tokio::process::Command::new("/usr/sbin/lsof")
+            .args(["-a", "-d", "cwd", "-F", "n", "-p", &pid.to_string()])
+            .stdin(std::process::Stdio::null())
+            .process_group(0),
+    )
+    .await
+    .ok()?;
+    parse_lsof_cwd(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The name and parent from `/proc/$PID/stat`, where the name is in parentheses and may contain
+/// spaces or parentheses itself (like `(tmux: server)`)
+#[cfg(any(test, not(target_os = "macos")))]
+fn parse_proc_stat(stat: &str) -> Option<(String, i32)> {
+    let (start, end) = (stat.find('(')?, stat.rfind(')')?);
+    let name = stat.get(start + 1..end)?.to_string();
+    let mut fields = stat.get(end + 1..)?.split_whitespace();
+    let _state = fields.next()?;
+    let parent = fields.next()?.parse().ok()?;
+    Some((name, parent))
+}
+
+/// The parent and name from `ps -o ppid=,comm=`
+#[cfg(any(test, target_os = "macos"))]
+fn parse_ps(ps: &str) -> Option<(String, i32)> {
+    let (parent, name) = ps.trim().split_once(char::is_whitespace)?;
+    let name = Path::new(name.trim())
+        .file_name()?
+        .to_string_lossy()
+        .to_string();
+    Some((name, parent.parse().ok()?))
+}
+
+/// The current directory from `lsof -a -d cwd -F n`, which prints fields one per line, each
+/// prefixed by its name (`p` for the process, `f` for the descriptor, `n` for the path)
+#[cfg(any(test, target_os = "macos"))]
+fn parse_lsof_cwd(lsof: &str) -> Option<PathBuf> {
+    lsof.lines()
+        .find_map(|line| line.strip_prefix('n'))
+        .map(PathBuf::from)
+}
+
+/// Re-execute a copy of `nix-installer` from outside `/nix`, if it is running from inside it
+///
+/// Only returns if no re-execution was needed.
+pub(crate) async fn reexec_outside_nix() -> eyre::Result<()> {
+    let Ok(current_exe) = std::env::current_exe() else {
+        return Ok(());
+    };
+    if !is_in_nix(&current_exe) {
+        return Ok(());
+    }
+    tracing::debug!(
+        "Detected uninstall from `{}`, copying executable and re-executing",
+        current_exe.display()
+    );
+
+    let temp = Some(std::env::temp_dir())
+        .filter(|v| !is_in_nix(v))
+        .unwrap_or_else(|| PathBuf::from("/tmp"));
+    let random_trailer: String = {
+        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                                abcdefghijklmnopqrstuvwxyz\
+                                0123456789";
+        const TRAILER_LEN: usize = 16;
+        let mut rng = rand::thread_rng();
+
+        (0..TRAILER_LEN)
+            .map(|_| {
+                let idx = rng.gen_range(0..CHARSET.len());
+                CHARSET[idx] as char
+            })
+            .collect()
+    };
+    let temp_exe = temp.join(format!("nix-installer-{random_trailer}"));
+    tokio::fs::copy(&current_exe, &temp_exe)
+        .await
+        .wrap_err("Copying nix-installer to tempdir")?;
+
+    let args = reexec_args(std::env::args_os())?;
+    let env = reexec_env(std::env::vars_os(), &temp_exe)?;
+    let temp_exe_cstring = c_string(temp_exe.as_os_str())?;
+
+    tracing::trace!("Execve'ing `{temp_exe_cstring:?} {args:?}`");
+    let err = nix::unistd::execve(&temp_exe_cstring, &args, &env).unwrap_err();
+    let _ = std::fs::remove_file(&temp_exe);
+    Err(err).wrap_err("Executing copied `nix-installer`")
+}
+
+/// Arguments are passed through unchanged, even those which are not valid UTF-8
+fn reexec_args(args: impl Iterator<Item = std::ffi::OsString>) -> eyre::Result<Vec<CString>> {
+    args.map(|arg| c_string(&arg)).collect()
+}
+
+/// The environment, along with [`REEXEC_COPY_ENV`] naming the copy
+fn reexec_env(
+    vars: impl Iterator<Item = (std::ffi::OsString, std::ffi::OsString)>,
+    copy: &Path,
+) -> eyre::Result<Vec<CString>> {
+    vars.filter(|(k, _)| k != REEXEC_COPY_ENV)
+        .chain(std::iter::once((
+            REEXEC_COPY_ENV.into(),
+            copy.as_os_str().to_os_string(),
+        )))
+        .map(|(k, v)| {
+            let mut var = k;
+            var.push("=");
+            var.push(v);
+            c_string(&var)
+        })
+        .collect()
+}
+
+fn c_string(value: &OsStr) -> eyre::Result<CString> {
+    CString::new(value.as_bytes())
+        .wrap_err_with(|| format!("Making `{}` into a C string", value.to_string_lossy()))
+}
+
+/// The copy made by [`reexec_outside_nix`], which is removed once dropped
+#[derive(Debug)]
+pub(crate) struct ReexecCopy(PathBuf);
+
+impl ReexecCopy {
+    /// The running executable, if it is a copy made by [`reexec_outside_nix`]
+    pub(crate) fn current() -> Option<Self> {
+        let copy = PathBuf::from(std::env::var_os(REEXEC_COPY_ENV)?);
+        let current_exe = std::env::current_exe().ok()?;
+        // Only ever remove ourselves, not whatever the variable happens to name (on macOS, the
+        // temporary directory is behind a symlink)
+        let is_current =
+            current_exe == copy || current_exe.canonicalize().ok()? == copy.canonicalize().ok()?;
+        is_current.then_some(Self(copy))
+    }
+}
+
+impl Drop for ReexecCopy {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::debug!(
+                "Could not remove the re-executed copy of `nix-installer` at `{}`: {e}",
+                self.0.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt, path::Path};
+
+    use super::*;
+
+    #[test]
+    fn detects_nix_paths() {
+        assert!(is_in_nix(Path::new("/nix")));
+        assert!(is_in_nix(Path::new("/nix/store/aaaa-nix-2.25.0/bin")));
+        assert!(is_in_nix(Path::new("/nix/nix-installer")));
+        assert!(!is_in_nix(Path::new("/nixpkgs")));
+        assert!(!is_in_nix(Path::new("/home/user/nix")));
+    }
+
+    #[test]
+    fn parses_process_listings() {
+        assert_eq!(
+            parse_proc_stat("4242 (tmux: server) S 1 4242 4242 0 -1 4194560 1209"),
+            Some(("tmux: server".to_string(), 1))
+        );
+        assert_eq!(
+            parse_proc_stat("77 (a) b) R 76 77 77"),
+            Some(("a) b".to_string(), 76))
+        );
+        assert_eq!(parse_proc_stat("garbage"), None);
+
+        assert_eq!(parse_ps("  812 /bin/zsh\n"), Some(("zsh".to_string(), 812)));
+        assert_eq!(parse_ps("812 -zsh"), Some(("-zsh".to_string(), 812)));
+        assert_eq!(parse_ps(""), None);
+
+        assert_eq!(
+            parse_lsof_cwd("p812\nfcwd\nn/nix/store\n"),
+            Some(PathBuf::from("/nix/store"))
+        );
+        assert_eq!(parse_lsof_cwd("p812\n"), None);
+    }
+
+    #[test]
+    fn reexec_keeps_arguments_and_names_the_copy() -> eyre::Result<()> {
+        let args = reexec_args(
+            [
+                OsString::from("/nix/nix-installer"),
+                OsString::from("uninstall"),
+                OsString::from_vec(b"/tmp/r\xffceipt.json".to_vec()),
+            ]
+            .into_iter(),
+        )?;
+        assert_eq!(args[2].as_bytes(), b"/tmp/r\xffceipt.json");
+
+        let env = reexec_env(
+            [
+                (OsString::from("PATH"), OsString::from("/usr/bin")),
+                // A stale marker from an earlier re-execution is replaced
+                (OsString::from(REEXEC_COPY_ENV), OsString::from("/tmp/old")),
+            ]
+            .into_iter(),
+            Path::new("/tmp/nix-installer-abc"),
+        )?;
+        assert_eq!(
+            env,
+            [
+                CString::new("PATH=/usr/bin")?,
+                CString::new(format!("{REEXEC_COPY_ENV}=/tmp/nix-installer-abc"))?,
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[tokio::test]
+    async fn finds_the_shell_holding_nix() -> eyre::Result<()> {
+        let sandbox = crate::test_harness::SandboxContext::new()?;
+        // `sudo` (200) was started by a shell (100) sitting in the Nix store
+        for (pid, stat, cwd) in [
+            (200, "200 (sudo) S 100 200 100 0", "/home/user"),
+            (100, "100 (zsh) S 1 100 100 0", "/nix/store"),
+        ] {
+            let proc = sandbox.path(format!("/proc/{pid}"));
+            std::fs::create_dir_all(&proc)?;
+            std::fs::write(proc.join("stat"), stat)?;
+            sandbox.symlink(cwd, proc.join("cwd"))?;
+        }
+
+        assert_eq!(
+            sandbox.scope(busy_ancestor_from(200)).await,
+            Some(BusyAncestor {
+                pid: 100,
+                name: "zsh".into(),
+                cwd: PathBuf::from("/nix/store"),
+            })
+        );
+
+        std::fs::remove_file(sandbox.path("/proc/100/cwd"))?;
+        std::os::unix::fs::symlink("/root", sandbox.path("/proc/100/cwd"))?;
+        assert_eq!(sandbox.scope(busy_ancestor_from(200)).await, None);
+        // Processes which cannot be inspected end the walk
+        assert_eq!(sandbox.scope(busy_ancestor_from(300)).await, None);
+        Ok(())
+    }
+}
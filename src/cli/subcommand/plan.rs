@@ -1,13 +1,33 @@
-use std::{path::PathBuf, process::ExitCode};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use crate::{cli::ensure_root, error::HasExpectedErrors, BuiltinPlanner};
-use clap::Parser;
+use crate::{
+    audit::{audit_existing, ArtifactStatus, AuditReport},
+    cli::{
+        arg::{DescriptionFormat, InvocationTarget},
+        ensure_root,
+    },
+    cross_target,
+    error::HasExpectedErrors,
+    replace_file::{replace_file, Attributes},
+    settings::PlatformDefaults,
+    BuiltinPlanner, InstallPlan, NixInstallerError,
+};
+use clap::{ArgAction, Parser};
 
 use eyre::WrapErr;
+use nix::unistd::{access, AccessFlags, Gid, Uid};
 use owo_colors::OwoColorize;
+use serde::Serialize;
+use target_lexicon::Triple;
 
 use crate::cli::CommandExecute;
 
+use super::emit_invocation;
+
 /**
 Emit a JSON install plan that can be manually edited before execution
 
@@ -16,33 +36,220 @@ Primarily intended for development, debugging, and handling install cases.
 #[derive(Debug, Parser)]
 pub struct Plan {
     #[clap(subcommand)]
-    pub planner: Option<BuiltinPlanner>,
-    /// Where to write the generated plan (in JSON format)
+    pub subcommand: Option<PlanSubcommand>,
+    /// Where to write the generated plan (in JSON format) [default: stdout]
+    ///
+    /// The file is replaced atomically, and given to the user who ran `sudo`, if any.
     #[clap(
         long = "out-file",
-        env = "NIX_INSTALLER_PLAN_OUT_FILE",
-        default_value = "/dev/stdout"
+        visible_alias = "out",
+        env = "NIX_INSTALLER_PLAN_OUT_FILE"
+    )]
+    pub output: Option<PathBuf>,
+    /// Emit the operations the plan performs as root instead of the plan, for security review
+    ///
+    /// The output can be passed to `nix-installer install --allowed-operations`
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_PLAN_PRIVILEGED_OPERATIONS",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub privileged_operations: bool,
+    /// Emit a description of the plan instead of the plan, as `human` prose or a `json` document
+    ///
+    /// The JSON document lists each action with its state, descriptions, and sub-actions
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_PLAN_FORMAT",
+        conflicts_with = "privileged_operations"
+    )]
+    pub format: Option<DescriptionFormat>,
+    /// Emit the non-interactive `nix-installer install` reproducing the plan instead of the plan, as a `shell` command, `cloud-init` user data, or a `github-action` step
+    ///
+    /// Only the settings which differ from the planner's defaults are spelled out. Secrets, such as
+    /// proxy credentials, are referenced as environment variables instead of being inlined. The
+    /// target is given as `--emit-invocation=cloud-init`, `--emit-invocation` alone is `shell`.
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_PLAN_EMIT_INVOCATION",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "shell",
+        conflicts_with_all = ["privileged_operations", "format"]
     )]
-    pub output: PathBuf,
+    pub emit_invocation: Option<InvocationTarget>,
+    /// Plan for the platform this target triple names instead of the host, without inspecting it
+    ///
+    /// Settings normally found by inspecting the host are taken from their flags or left as
+    /// placeholders, and actions which need the host are marked as requiring host probing.
+    /// `nix-installer install` refuses the plan on another platform, or with anything left to probe.
+    #[clap(long, env = "NIX_INSTALLER_PLAN_TARGET")]
+    pub target: Option<Triple>,
 }
 
 #[async_trait::async_trait]
 impl CommandExecute for Plan {
     #[tracing::instrument(level = "debug", skip_all, fields())]
     async fn execute(self) -> eyre::Result<ExitCode> {
-        let Self { planner, output } = self;
+        let Self {
+            subcommand,
+            output,
+            privileged_operations,
+            format,
+            emit_invocation,
+            target,
+        } = self;
+
+        // Nothing is probed when planning for another platform
+        if target.is_none() {
+            ensure_root()?;
+        }
+
+        // Before planning, which can take a while, rather than failing after
+        let output = PlanOutput::new(output)?;
+
+        let planner = match subcommand {
+            Some(PlanSubcommand::AuditExisting(audit)) => return audit.execute(output).await,
+            Some(PlanSubcommand::Planner(planner)) => Some(planner),
+            None => None,
+        };
+
+        let Some(target) = target else {
+            let planner = match planner {
+                Some(planner) => planner,
+                None => BuiltinPlanner::default().await?,
+            };
+            let emit_invocation = emit_invocation.map(|emit| (emit, planner.clone()));
+            return write_plan(
+                planner.plan().await,
+                output,
+                privileged_operations,
+                format,
+                emit_invocation,
+            )
+            .await;
+        };
+
+        // The flags were given the host's defaults, which are not the target's
+        let host_defaults = PlatformDefaults::current();
+        let (planner, _) = cross_target::scope(target.clone(), async {
+            match planner {
+                Some(mut planner) => {
+                    planner.common_settings_mut().retarget(&host_defaults);
+                    Ok(planner)
+                },
+                None => BuiltinPlanner::default().await,
+            }
+        })
+        .await;
+        let planner = planner?;
+        let emit_invocation = emit_invocation.map(|emit| (emit, planner.clone()));
+        let res = planner.plan_for_target(target.clone()).await;
+        // Settings are described against the target's defaults
+        let (written, _) = cross_target::scope(
+            target,
+            write_plan(res, output, privileged_operations, format, emit_invocation),
+        )
+        .await;
+        written
+    }
+}
+
+/// Write the plan `res` planned, what it is in `format`, or the invocation of its planner reproducing it
+async fn write_plan(
+    res: Result<InstallPlan, NixInstallerError>,
+    output: PlanOutput,
+    privileged_operations: bool,
+    format: Option<DescriptionFormat>,
+    emit_invocation: Option<(InvocationTarget, BuiltinPlanner)>,
+) -> eyre::Result<ExitCode> {
+    let install_plan = match res {
+        Ok(plan) => plan,
+        Err(err) => {
+            if let Some(expected) = err.expected() {
+                eprintln!("{}", expected.red());
+                return Ok(ExitCode::FAILURE);
+            }
+            return Err(err)?;
+        },
+    };
+
+    if let Some((target, planner)) = emit_invocation {
+        let args = emit_invocation::planner_args(&planner).await?;
+        let invocation = emit_invocation::render(planner.typetag_name(), &args, target);
+        output
+            .write_with(|writer| writer.write_all(invocation.as_bytes()))
+            .await
+            .wrap_err("Writing invocation")?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    match (privileged_operations, format) {
+        (true, _) => output
+            .write_json(&install_plan.privileged_operations())
+            .await
+            .wrap_err("Writing privileged operations")?,
+        (false, Some(DescriptionFormat::Human)) => {
+            let description = install_plan.describe_install(true).await?;
+            output
+                .write_with(|writer| writer.write_all(description.as_bytes()))
+                .await
+                .wrap_err("Writing plan description")?
+        },
+        (false, Some(DescriptionFormat::Json)) => output
+            .write_json(&install_plan.describe_install_structured().await?)
+            .await
+            .wrap_err("Writing plan description")?,
+        (false, None) => output
+            .write_json(&install_plan)
+            .await
+            .wrap_err("Writing plan")?,
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum PlanSubcommand {
+    #[clap(flatten)]
+    Planner(BuiltinPlanner),
+    AuditExisting(AuditExisting),
+}
+
+/**
+Compare an existing install by the shell installer with what `nix-installer` would manage
+
+Each artifact is reported as identical, different (with a diff for text files), unmanaged (which
+`nix-installer` would not own), or missing (which `nix-installer` would create). Nothing is
+changed on the system.
+*/
+#[derive(Debug, Parser)]
+pub struct AuditExisting {
+    /// Emit the audit as JSON
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_AUDIT_JSON",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub json: bool,
+
+    #[clap(subcommand)]
+    pub planner: Option<BuiltinPlanner>,
+}
 
-        ensure_root()?;
+impl AuditExisting {
+    async fn execute(self, output: PlanOutput) -> eyre::Result<ExitCode> {
+        let Self { json, planner } = self;
 
         let planner = match planner {
             Some(planner) => planner,
             None => BuiltinPlanner::default().await?,
         };
 
-        let res = planner.plan().await;
-
-        let install_plan = match res {
-            Ok(plan) => plan,
+        let report = match audit_existing(planner).await {
+            Ok(report) => report,
             Err(err) => {
                 if let Some(expected) = err.expected() {
                     eprintln!("{}", expected.red());
@@ -52,11 +259,226 @@ impl CommandExecute for Plan {
             },
         };
 
-        let json = serde_json::to_string_pretty(&install_plan)?;
-        tokio::fs::write(output, format!("{json}\n"))
-            .await
-            .wrap_err("Writing plan")?;
+        match json {
+            true => output.write_json(&report).await,
+            false => {
+                let description = describe_audit(&report);
+                output
+                    .write_with(|writer| writer.write_all(description.as_bytes()))
+                    .await
+            },
+        }
+        .wrap_err("Writing audit")?;
 
         Ok(ExitCode::SUCCESS)
     }
 }
+
+/// Where `nix-installer plan` writes its output
+#[derive(Debug)]
+enum PlanOutput {
+    Stdout,
+    File {
+        path: PathBuf,
+        /// The user who ran `sudo`, who should be able to read their own plan
+        uid: Option<Uid>,
+        gid: Option<Gid>,
+    },
+}
+
+impl PlanOutput {
+    /// Check `path` can be written, `None` (or `-`, or `/dev/stdout`) is stdout
+    fn new(path: Option<PathBuf>) -> eyre::Result<Self> {
+        Self::new_owned_by(
+            path,
+            std::env::var("SUDO_UID").ok().as_deref(),
+            std::env::var("SUDO_GID").ok().as_deref(),
+        )
+    }
+
+    fn new_owned_by(
+        path: Option<PathBuf>,
+        sudo_uid: Option<&str>,
+        sudo_gid: Option<&str>,
+    ) -> eyre::Result<Self> {
+        let path = match path {
+            Some(path) if path != Path::new("-") && path != Path::new("/dev/stdout") => path,
+            _ => return Ok(Self::Stdout),
+        };
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        access(parent, AccessFlags::W_OK).wrap_err_with(|| {
+            format!(
+                "`{}` is not a writable directory, so `{}` cannot be written",
+                parent.display(),
+                path.display()
+            )
+        })?;
+        Ok(Self::File {
+            path,
+            uid: sudo_uid.and_then(|uid| uid.parse().ok()).map(Uid::from_raw),
+            gid: sudo_gid.and_then(|gid| gid.parse().ok()).map(Gid::from_raw),
+        })
+    }
+
+    async fn write_json(&self, value: &impl Serialize) -> eyre::Result<()> {
+        self.write_with(|writer| {
+            serde_json::to_writer_pretty(&mut *writer, value)?;
+            writer.write_all(b"\n")
+        })
+        .await
+    }
+
+    /// Stream to stdout, or replace the file once `write` is done with it
+    async fn write_with(
+        &self,
+        write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+    ) -> eyre::Result<()> {
+        match self {
+            Self::Stdout => {
+                let result = {
+                    let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+                    write(&mut stdout).and_then(|()| stdout.flush())
+                };
+                ignore_broken_pipe(result).wrap_err("Writing to stdout")
+            },
+            Self::File { path, uid, gid } => {
+                let mut buf = vec![];
+                write(&mut buf)?;
+                replace_file(
+                    path,
+                    &buf,
+                    Attributes {
+                        mode: 0o644,
+                        uid: *uid,
+                        gid: *gid,
+                    },
+                )
+                .await
+                .wrap_err_with(|| format!("Writing `{}`", path.display()))?;
+                Ok(())
+            },
+        }
+    }
+}
+
+/// A reader which stopped reading, as with `nix-installer plan | head`, is not an error
+fn ignore_broken_pipe(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+            tracing::debug!("Stdout was closed before the output was written");
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+fn describe_audit(report: &AuditReport) -> String {
+    let mut buf = format!(
+        "Audit of the existing install against the `{}` planner\n",
+        report.planner
+    );
+    for (status, heading) in [
+        (
+            ArtifactStatus::Different,
+            "Different, `nix-installer` would change",
+        ),
+        (
+            ArtifactStatus::Unmanaged,
+            "Unmanaged, `nix-installer` would not own",
+        ),
+        (
+            ArtifactStatus::Missing,
+            "Missing, `nix-installer` would create",
+        ),
+        (ArtifactStatus::Identical, "Identical"),
+    ] {
+        let artifacts = report.with_status(status).collect::<Vec<_>>();
+        if artifacts.is_empty() {
+            continue;
+        }
+        buf.push_str(&format!("\n{}:\n", heading.bold()));
+        for artifact in artifacts {
+            buf.push_str(&format!("* {} `{}`\n", artifact.kind, artifact.name));
+            if let Some(detail) = &artifact.detail {
+                buf.push_str(&format!("  {detail}\n"));
+            }
+            if let Some(diff) = &artifact.diff {
+                for line in diff.lines() {
+                    let line = match line.chars().next() {
+                        Some('+') => line.green().to_string(),
+                        Some('-') => line.red().to_string(),
+                        _ => line.to_string(),
+                    };
+                    buf.push_str(&format!("    {line}\n"));
+                }
+            }
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, os::unix::fs::MetadataExt, os::unix::net::UnixStream};
+
+    use nix::unistd::{Gid, Uid};
+
+    use super::{ignore_broken_pipe, PlanOutput};
+
+    #[tokio::test]
+    async fn plan_file_is_given_to_the_sudo_user() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("plan.json");
+        // Only root can give the file away
+        let (uid, gid) = match Uid::effective().is_root() {
+            true => (Uid::from_raw(12345), Gid::from_raw(12345)),
+            false => (Uid::effective(), Gid::effective()),
+        };
+
+        let output = PlanOutput::new_owned_by(
+            Some(path.clone()),
+            Some(&uid.to_string()),
+            Some(&gid.to_string()),
+        )?;
+        output
+            .write_json(&serde_json::json!({ "plan": true }))
+            .await?;
+
+        let metadata = std::fs::metadata(&path)?;
+        assert_eq!(metadata.uid(), uid.as_raw());
+        assert_eq!(metadata.gid(), gid.as_raw());
+        assert_eq!(std::fs::read_to_string(&path)?, "{\n  \"plan\": true\n}\n");
+        Ok(())
+    }
+
+    #[test]
+    fn unwritable_output_directory_is_refused_before_planning() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let missing = dir.path().join("missing").join("plan.json");
+        assert!(PlanOutput::new_owned_by(Some(missing), None, None).is_err());
+        assert!(matches!(
+            PlanOutput::new_owned_by(Some("-".into()), None, None)?,
+            PlanOutput::Stdout
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn closed_stdout_is_not_an_error() -> eyre::Result<()> {
+        let (mut writer, reader) = UnixStream::pair()?;
+        drop(reader);
+        let result = writer.write_all(&[b'{'; 1 << 20]);
+        assert_eq!(
+            result.as_ref().map_err(|e| e.kind()),
+            Err(std::io::ErrorKind::BrokenPipe)
+        );
+        ignore_broken_pipe(result)?;
+
+        let other = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(ignore_broken_pipe(Err(other)).is_err());
+        Ok(())
+    }
+}
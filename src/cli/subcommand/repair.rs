@@ -1,4 +1,5 @@
 use std::io::IsTerminal as _;
+use std::path::PathBuf;
 use std::process::ExitCode;
 use std::time::SystemTime;
 
@@ -8,13 +9,29 @@ use serde::{Deserialize, Serialize};
 use target_lexicon::OperatingSystem;
 use tokio::process::Command;
 
-use crate::action::base::{AddUserToGroup, CreateGroup, CreateUser};
-use crate::action::common::{ConfigureShellProfile, CreateUsersAndGroups};
+use crate::action::base::{
+    AddUserToGroup, CreateGroup, CreateUser, RestoreDefaultProfile, UserShellAndHome,
+};
+use crate::action::common::configure_init_service::UnitScope;
+use crate::action::common::{
+    ConfigureDeterminateNixdInitService, ConfigureShellProfile, ConfigureUpstreamInitService,
+    CreateUsersAndGroups, ProvisionDeterminateNixd,
+};
+use crate::action::linux::relink_steamos_units::{recorded_steamos_units, SYSTEMD_SYSTEM_DIR};
+use crate::action::linux::RelinkSteamosUnits;
+use crate::action::macos::create_apfs_volume::apfs_volume;
+use crate::action::macos::repair_volume_mount::nix_volume_of;
+use crate::action::macos::{RepairVolumeMount, RotateVolumeKey};
 use crate::action::{Action, ActionState, StatefulAction};
 use crate::cli::interaction::PromptChoice;
-use crate::cli::{ensure_root, CommandExecute};
+use crate::cli::subcommand::status::completed_actions;
+use crate::cli::{ensure_root, run_log::RunLog, CommandExecute};
+use crate::distribution::{self, ArtifactKind, Distribution, Inconsistency};
+use crate::os::ostree::OstreeStatus;
 use crate::plan::RECEIPT_LOCATION;
 use crate::planner::{PlannerError, ShellProfileLocations};
+use crate::profile::RecordedStorePaths;
+use crate::settings::{DaemonResources, DaemonUser, InitSystem};
 use crate::{execute_command, InstallPlan};
 
 /// The base UID that we temporarily move build users to while migrating macOS to the new range.
@@ -44,6 +61,8 @@ pub struct Repair {
 #[derive(Clone, Debug, Subcommand, serde::Deserialize, serde::Serialize)]
 pub enum RepairKind {
     /// Update the shell profiles to make Nix usable after system upgrades.
+    ///
+    /// On ostree, the deployment booted after an OS update is recorded in the receipt.
     Hooks,
     /// Recover from the macOS 15 Sequoia update taking over _nixbld users.
     ///
@@ -107,6 +126,48 @@ pub enum RepairKind {
         )]
         move_existing_users: bool,
     },
+    /// Rebuild the default profile (`/nix/var/nix/profiles/default`) when it no longer resolves to `nix`.
+    ///
+    /// The `nix` package is taken from the install receipt, or found in `/nix/store`.
+    DefaultProfile {
+        /// The `nix` store path to rebuild the profile from, required when `/nix/store` contains
+        /// several `nix` packages
+        #[cfg_attr(
+            feature = "cli",
+            clap(long, env = "NIX_INSTALLER_USE_STORE_PATH", global = true)
+        )]
+        use_store_path: Option<PathBuf>,
+    },
+    /// Mount the Nix volume by its current UUID on macOS, after restoring from a backup changed it.
+    ///
+    /// A Mac restored from Time Machine, or migrated with Migration Assistant, has a Nix volume
+    /// with a new UUID, so `/nix` is not mounted at boot. The `/etc/fstab` entry and the mount
+    /// service are updated, the volume is mounted, and the Nix daemon is restarted.
+    VolumeMount,
+    /// Converge an install left part Determinate Nix and part upstream Nix, by an interrupted
+    /// migration between them, to the distribution recorded in the receipt.
+    ///
+    /// The daemon of the other distribution is stopped and its service removed, the daemon of the
+    /// recorded one is configured and started, and the Determinate settings in
+    /// `/etc/nix/nix.conf` are added or removed.
+    Distribution,
+    /// Restore the Nix units a SteamOS update removed from `/etc/systemd/system`.
+    ///
+    /// Installs keep a copy of the units under `/home/.steamos/offload` and restore them at boot on
+    /// their own. This restores them on demand, and for installs made before those copies were
+    /// kept, from the units recorded in the receipt.
+    Steamos,
+    /// Rotate the passphrase of the encrypted Nix volume on macOS.
+    ///
+    /// A new passphrase is generated, the volume is changed to it and checked to unlock with it,
+    /// and the `Nix Store` item in the System keychain is updated. The volume is then unmounted and
+    /// mounted by its mount service, as at boot, before the rotation is declared done. If any step
+    /// fails, the old passphrase is put back on the volume and in the keychain.
+    ///
+    /// A missing `Nix Store` item is recreated from the passphrase an earlier rotation left staged
+    /// in the keychain. A volume which is not encrypted is left alone.
+    #[command(alias = "rotate-volume-password")]
+    RotateVolumeKey,
 }
 
 impl Repair {
@@ -124,6 +185,11 @@ impl CommandExecute for Repair {
         ensure_root()?;
 
         let mut repair_actions = Vec::new();
+        let mut default_profile_store_paths = None;
+        let mut volume_mount_repair = None;
+        let mut distribution_repair = None;
+        let mut steamos_repair = None;
+        let mut volume_key_rotation = None;
         let (prompt_before_repairing, brief_repair_summary) = match command {
             RepairKind::Hooks => (
                 false,
@@ -157,6 +223,232 @@ impl CommandExecute for Repair {
                 );
                 (!self.no_confirm, brief_summary)
             },
+            RepairKind::DefaultProfile { ref use_store_path } => {
+                match crate::profile::verify_default_profile().await {
+                    Ok(store_path) if use_store_path.is_none() => {
+                        tracing::info!(
+                            "Nothing to do! The default profile resolves to `{}`",
+                            store_path.display()
+                        );
+                        return Ok(ExitCode::SUCCESS);
+                    },
+                    Ok(_) => (),
+                    Err(err) => tracing::warn!("{err}"),
+                }
+
+                let recorded = recorded_store_paths().await;
+                let nix_store_path = crate::profile::choose_nix_store_path(
+                    use_store_path.as_deref(),
+                    recorded.nix.as_deref(),
+                )?;
+                let nss_ca_cert_store_path =
+                    crate::profile::choose_nss_ca_cert_store_path(recorded.nss_ca_cert.as_deref());
+
+                let brief_summary = format!(
+                    "Will rebuild the default profile `{}` from `{}`",
+                    crate::profile::DEFAULT_PROFILE,
+                    nix_store_path.display()
+                );
+                default_profile_store_paths = Some((nix_store_path, nss_ca_cert_store_path));
+                (!self.no_confirm, brief_summary)
+            },
+            RepairKind::VolumeMount => {
+                if !matches!(
+                    OperatingSystem::host(),
+                    OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin
+                ) {
+                    return Err(color_eyre::eyre::eyre!(
+                        "The `volume-mount` repair command is only available on macOS"
+                    ));
+                }
+
+                let volume = receipt_value()
+                    .await
+                    .as_ref()
+                    .and_then(nix_volume_of)
+                    .ok_or_else(|| {
+                        color_eyre::eyre::eyre!(
+                            "No Nix volume was found in the receipt at {RECEIPT_LOCATION}"
+                        )
+                    })?;
+                let label = volume.label.clone();
+                let repair = RepairVolumeMount::plan(volume).await?;
+                if repair.state == ActionState::Completed {
+                    tracing::info!("Nothing to do! The Nix volume is mounted by its UUID");
+                    return Ok(ExitCode::SUCCESS);
+                }
+
+                // The receipt records the volume by its label, so it stays accurate
+                let brief_summary = format!(
+                    "Will mount the Nix volume `{label}` by its current UUID `{}`, then restart the Nix daemon",
+                    repair.inner().volume_uuid
+                );
+                volume_mount_repair = Some(repair);
+                (!self.no_confirm, brief_summary)
+            },
+            RepairKind::Distribution => {
+                let receipt = receipt_value().await.ok_or_else(|| {
+                    color_eyre::eyre::eyre!("No receipt was found at {RECEIPT_LOCATION}")
+                })?;
+                let recorded = Distribution::of_receipt(&receipt).ok_or_else(|| {
+                    color_eyre::eyre::eyre!(
+                        "The receipt at {RECEIPT_LOCATION} does not record whether Determinate Nix was installed"
+                    )
+                })?;
+                let service = completed_actions(&receipt, "configure_init_service")
+                    .into_iter()
+                    .next();
+                if let Some(service) = service {
+                    if serde_json::from_value::<UnitScope>(service["scope"].clone())
+                        .is_ok_and(|scope| !scope.is_system())
+                    {
+                        return Err(color_eyre::eyre::eyre!(
+                            "The `distribution` repair command does not support a Nix daemon in a systemd user manager"
+                        ));
+                    }
+                }
+                let init = service
+                    .and_then(|service| serde_json::from_value(service["init"].clone()).ok())
+                    .unwrap_or(InitSystem::None);
+                let daemon_user = service.and_then(|service| {
+                    serde_json::from_value::<DaemonUser>(service["daemon_user"].clone()).ok()
+                });
+                let resources = recorded_resources(service);
+
+                let found =
+                    distribution::inconsistencies(&distribution::artifacts(init), recorded, init);
+                if found.is_empty() {
+                    tracing::info!("Nothing to do! The install is consistently {recorded}");
+                    return Ok(ExitCode::SUCCESS);
+                }
+
+                let brief_summary = format!(
+                    "Will converge the install to {recorded}, as recorded in the receipt, since:\n{}",
+                    found
+                        .iter()
+                        .map(|inconsistency| format!("* {inconsistency}"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                );
+                distribution_repair = Some((recorded, init, daemon_user, resources, found));
+                (!self.no_confirm, brief_summary)
+            },
+            RepairKind::Steamos => {
+                let receipt = receipt_value().await.ok_or_else(|| {
+                    color_eyre::eyre::eyre!("No receipt was found at {RECEIPT_LOCATION}")
+                })?;
+                if receipt["planner"]["planner"].as_str() != Some("steam-deck") {
+                    return Err(color_eyre::eyre::eyre!(
+                        "The `steamos` repair command is only available for installs made with the `steam-deck` planner"
+                    ));
+                }
+                let recorded = recorded_steamos_units(&receipt).await?;
+                let relink = RelinkSteamosUnits::plan(recorded.units, recorded.enable).await?;
+
+                // Without the copies, the daemon's units are configured again from the receipt
+                let daemon_service =
+                    std::path::Path::new(SYSTEMD_SYSTEM_DIR).join("nix-daemon.service");
+                let configure_daemon = if !recorded.persisted && !daemon_service.exists() {
+                    let service = completed_actions(&receipt, "configure_init_service")
+                        .into_iter()
+                        .next();
+                    let daemon_user = service.and_then(|service| {
+                        serde_json::from_value::<DaemonUser>(service["daemon_user"].clone()).ok()
+                    });
+                    let service_mode = service
+                        .and_then(|service| {
+                            serde_json::from_value(service["service_mode"].clone()).ok()
+                        })
+                        .unwrap_or_default();
+                    Some(
+                        ConfigureUpstreamInitService::plan(InitSystem::Systemd, true, daemon_user)
+                            .await?
+                            .with_service_mode(service_mode)
+                            .with_resources(recorded_resources(service)),
+                    )
+                } else {
+                    None
+                };
+
+                if relink.state == ActionState::Completed && configure_daemon.is_none() {
+                    tracing::info!(
+                        "Nothing to do! The Nix units are all in `{SYSTEMD_SYSTEM_DIR}`"
+                    );
+                    return Ok(ExitCode::SUCCESS);
+                }
+
+                let mut restored = relink
+                    .inner()
+                    .units
+                    .iter()
+                    .map(|unit| format!("* `{}`", unit.path.display()))
+                    .collect::<Vec<_>>();
+                if configure_daemon.is_some() {
+                    restored.push("* The Nix daemon's units".to_string());
+                }
+                let brief_summary = format!(
+                    "Will restore the Nix units a SteamOS update removed from `{SYSTEMD_SYSTEM_DIR}`:\n{}",
+                    restored.join("\n")
+                );
+                steamos_repair = Some((relink, configure_daemon));
+                (!self.no_confirm, brief_summary)
+            },
+            RepairKind::RotateVolumeKey => {
+                if !matches!(
+                    OperatingSystem::host(),
+                    OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin
+                ) {
+                    return Err(color_eyre::eyre::eyre!(
+                        "The `rotate-volume-key` repair command is only available on macOS"
+                    ));
+                }
+
+                let receipt = receipt_value().await.ok_or_else(|| {
+                    color_eyre::eyre::eyre!("No receipt was found at {RECEIPT_LOCATION}")
+                })?;
+                let volume = nix_volume_of(&receipt).ok_or_else(|| {
+                    color_eyre::eyre::eyre!(
+                        "No Nix volume was found in the receipt at {RECEIPT_LOCATION}"
+                    )
+                })?;
+                let Some(encrypt) = completed_actions(&receipt, "encrypt_apfs_volume")
+                    .into_iter()
+                    .next()
+                else {
+                    tracing::info!(
+                        "Nothing to do! The Nix volume `{}` was not encrypted by the install, so it has no passphrase to rotate",
+                        volume.label
+                    );
+                    return Ok(ExitCode::SUCCESS);
+                };
+                match apfs_volume(&volume.label).await {
+                    Ok(Some(listed)) if listed.file_vault == Some(false) => {
+                        tracing::info!(
+                            "Nothing to do! The Nix volume `{}` is no longer encrypted, so it has no passphrase to rotate",
+                            volume.label
+                        );
+                        return Ok(ExitCode::SUCCESS);
+                    },
+                    Ok(_) => (),
+                    Err(err) => {
+                        tracing::debug!(%err, "Could not check if the Nix volume is encrypted")
+                    },
+                }
+                let disk = encrypt["disk"].as_str().map(PathBuf::from).ok_or_else(|| {
+                    color_eyre::eyre::eyre!(
+                        "The receipt at {RECEIPT_LOCATION} does not record the disk of the Nix volume"
+                    )
+                })?;
+                let determinate_nix = encrypt["determinate_nix"].as_bool().unwrap_or(false);
+
+                let label = volume.label.clone();
+                let rotation = RotateVolumeKey::plan(volume, disk, determinate_nix).await?;
+                let brief_summary = format!(
+                    "Will rotate the passphrase of the encrypted Nix volume `{label}` and update it in the System keychain, then unmount the volume and mount it again as at boot, restarting the Nix daemon"
+                );
+                volume_key_rotation = Some(rotation);
+                (!self.no_confirm, brief_summary)
+            },
         };
 
         if prompt_before_repairing {
@@ -175,7 +467,7 @@ impl CommandExecute for Repair {
                         )
                         .await
                     },
-                    PromptChoice::Explain => (),
+                    PromptChoice::Explain | PromptChoice::Customize => (),
                 }
             }
         } else {
@@ -185,18 +477,34 @@ impl CommandExecute for Repair {
         // TODO(cole-h): if we add another repair command, make this whole thing more generic
         let updated_receipt = match command.clone() {
             RepairKind::Hooks => {
-                let reconfigure = ConfigureShellProfile::plan(ShellProfileLocations::default())
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed();
+                let annotation = recorded_managed_file_annotation().await;
+                let receipt = get_existing_receipt().await;
+                let on_ostree = receipt
+                    .as_ref()
+                    .is_some_and(|receipt| receipt.planner.typetag_name() == "ostree");
+                let reconfigure = ConfigureShellProfile::plan(
+                    match on_ostree {
+                        true => crate::planner::ostree::persistent_shell_profile_locations(),
+                        false => ShellProfileLocations::default(),
+                    },
+                    annotation.clone(),
+                    // Profiles are only taken over during an install
+                    None,
+                    recorded_flag("use_xdg_base_directories").await,
+                    recorded_flag("user_profile").await,
+                )
+                .await
+                .map_err(PlannerError::Action)?
+                .boxed();
                 repair_actions.push(reconfigure);
 
                 match OperatingSystem::host() {
                     OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
-                        let reconfigure = crate::action::macos::ConfigureRemoteBuilding::plan()
-                            .await
-                            .map_err(PlannerError::Action)?
-                            .boxed();
+                        let reconfigure =
+                            crate::action::macos::ConfigureRemoteBuilding::plan(annotation)
+                                .await
+                                .map_err(PlannerError::Action)?
+                                .boxed();
                         repair_actions.push(reconfigure);
                     },
                     _ => {
@@ -204,7 +512,24 @@ impl CommandExecute for Repair {
                     },
                 }
 
-                None
+                // Record the deployment the hooks were repaired on, which `status` compares against
+                match receipt {
+                    Some(mut receipt) if on_ostree => {
+                        let booted = OstreeStatus::query().await.and_then(|status| {
+                            status.booted().map(|booted| booted.checksum.clone())
+                        });
+                        match (booted, receipt.host_snapshot.as_mut()) {
+                            (Some(booted), Some(snapshot))
+                                if snapshot.ostree_deployment != booted =>
+                            {
+                                snapshot.ostree_deployment = booted;
+                                Some(receipt)
+                            },
+                            _ => None,
+                        }
+                    },
+                    _ => None,
+                }
             },
             RepairKind::Sequoia {
                 nix_build_user_prefix,
@@ -241,6 +566,7 @@ impl CommandExecute for Repair {
                 let user_count = maybe_users_and_groups_from_receipt.user_count;
                 let group_name = maybe_users_and_groups_from_receipt.group_name;
                 let group_gid = maybe_users_and_groups_from_receipt.group_gid;
+                let shell_and_home = maybe_users_and_groups_from_receipt.shell_and_home;
                 let receipt_action_idx_create_group =
                     maybe_users_and_groups_from_receipt.receipt_action_idx_create_group;
 
@@ -330,6 +656,7 @@ impl CommandExecute for Repair {
                         group_name.clone(),
                         group_gid,
                         format!("Nix build user {idx}"),
+                        shell_and_home.clone(),
                         false,
                     )
                     .await?;
@@ -370,6 +697,7 @@ impl CommandExecute for Repair {
                         nix_build_user_count: user_count,
                         nix_build_user_prefix: user_prefix.clone(),
                         nix_build_user_id_base: user_base,
+                        nix_build_user_shell_and_home: shell_and_home,
                         create_group,
                         create_users: create_users.clone(),
                         add_users_to_groups,
@@ -391,6 +719,107 @@ impl CommandExecute for Repair {
 
                 maybe_updated_receipt
             },
+            RepairKind::DefaultProfile { .. } => {
+                // Chosen above, before prompting
+                let (nix_store_path, nss_ca_cert_store_path) =
+                    default_profile_store_paths
+                        .take()
+                        .ok_or_else(|| color_eyre::eyre::eyre!("No `nix` store path was chosen"))?;
+                let mut restore =
+                    RestoreDefaultProfile::plan(nix_store_path, nss_ca_cert_store_path).await?;
+                // A profile that resolves is only rebuilt when a store path was explicitly requested
+                restore.state = ActionState::Uncompleted;
+                repair_actions.push(restore.boxed());
+
+                None
+            },
+            RepairKind::VolumeMount => {
+                // Planned above, before prompting
+                let repair = volume_mount_repair
+                    .take()
+                    .ok_or_else(|| color_eyre::eyre::eyre!("The volume mount was not checked"))?;
+                repair_actions.push(repair.boxed());
+
+                None
+            },
+            RepairKind::Distribution => {
+                // Checked above, before prompting
+                let (recorded, init, daemon_user, resources, found) = distribution_repair
+                    .take()
+                    .ok_or_else(|| color_eyre::eyre::eyre!("The distribution was not checked"))?;
+                distribution::remove_foreign(&found, recorded, init).await?;
+
+                let missing_binary = found.iter().any(|inconsistency| {
+                    matches!(
+                        inconsistency,
+                        Inconsistency::Missing {
+                            kind: ArtifactKind::Binary,
+                            ..
+                        }
+                    )
+                });
+                if missing_binary {
+                    repair_actions.push(ProvisionDeterminateNixd::plan().await?.boxed());
+                }
+                if found.iter().any(|inconsistency| {
+                    matches!(
+                        inconsistency.kind(),
+                        ArtifactKind::Service | ArtifactKind::Socket
+                    )
+                }) {
+                    let configure = match recorded {
+                        Distribution::Determinate => {
+                            ConfigureDeterminateNixdInitService::plan(init, true)
+                                .await?
+                                .with_resources(resources)
+                                .boxed()
+                        },
+                        Distribution::Upstream => {
+                            ConfigureUpstreamInitService::plan(init, true, daemon_user)
+                                .await?
+                                .with_resources(resources)
+                                .boxed()
+                        },
+                    };
+                    repair_actions.push(configure);
+                }
+
+                None
+            },
+            RepairKind::Steamos => {
+                // Planned above, before prompting
+                let (relink, configure_daemon) = steamos_repair
+                    .take()
+                    .ok_or_else(|| color_eyre::eyre::eyre!("The SteamOS units were not checked"))?;
+                // The daemon's units go first, the restored units start it
+                if let Some(configure_daemon) = configure_daemon {
+                    repair_actions.push(configure_daemon.boxed());
+                }
+                repair_actions.push(relink.boxed());
+
+                None
+            },
+            RepairKind::RotateVolumeKey => {
+                // Planned above, before prompting
+                let rotation = volume_key_rotation.take().ok_or_else(|| {
+                    color_eyre::eyre::eyre!("The volume passphrase was not checked")
+                })?;
+                repair_actions.push(rotation.boxed());
+
+                // Only written once the rotation succeeds
+                let rotated = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs();
+                match get_existing_receipt().await {
+                    Some(receipt) => Some(record_passphrase_rotation(receipt, rotated)?),
+                    None => {
+                        tracing::warn!(
+                            "The receipt could not be parsed, so it will not record the rotation"
+                        );
+                        None
+                    },
+                }
+            },
         };
 
         for mut action in repair_actions {
@@ -401,13 +830,25 @@ impl CommandExecute for Repair {
             action.state = ActionState::Completed;
         }
 
+        if let RepairKind::DefaultProfile { .. } = command {
+            let store_path = crate::profile::verify_default_profile()
+                .await
+                .wrap_err("The default profile is still broken after repairing it")?;
+            tracing::info!(
+                "The default profile now resolves to `{}`",
+                store_path.display()
+            );
+        }
+
         if let Some(updated_receipt) = updated_receipt {
             let timestamp_millis = SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)?
                 .as_millis();
 
-            let mut old_receipt = std::path::PathBuf::from(RECEIPT_LOCATION);
-            old_receipt.set_extension(format!("pre-repair.{timestamp_millis}.json"));
+            let state_dir = crate::state_dir::of_plan(&updated_receipt);
+            RunLog::global().record("repair", Some(state_dir.clone()));
+            tokio::fs::create_dir_all(&state_dir).await?;
+            let old_receipt = state_dir.join(format!("receipt.pre-repair.{timestamp_millis}.json"));
             tokio::fs::copy(RECEIPT_LOCATION, &old_receipt).await?;
             tracing::info!("Backed up pre-repair receipt to {}", old_receipt.display());
 
@@ -453,27 +894,110 @@ async fn get_existing_receipt() -> Option<InstallPlan> {
     match std::path::Path::new(RECEIPT_LOCATION).exists() {
         true => {
             tracing::debug!("Reading existing receipt");
-            let install_plan_string = tokio::fs::read_to_string(RECEIPT_LOCATION).await.ok();
+            let install_plan = crate::plan::read_receipt(RECEIPT_LOCATION);
 
-            match install_plan_string {
-                Some(s) => match serde_json::from_str::<InstallPlan>(s.as_str()) {
-                    Ok(plan) => {
-                        tracing::debug!(plan_version = %plan.version, "Able to parse receipt");
-                        Some(plan)
-                    },
-                    Err(e) => {
-                        tracing::debug!(?e);
-                        tracing::warn!("Could not parse receipt. Your receipt will not be updated to account for the new UIDs");
-                        None
-                    },
+            match install_plan {
+                Ok(plan) => {
+                    tracing::debug!(plan_version = %plan.version, "Able to parse receipt");
+                    Some(plan)
+                },
+                Err(e) => {
+                    tracing::debug!(?e);
+                    tracing::warn!("Could not parse receipt. Your receipt will not be updated to account for the new UIDs");
+                    None
                 },
-                _ => None,
             }
         },
         false => None,
     }
 }
 
+/// How the receipt's `configure_init_service` action scheduled the daemon, none if it predates
+/// [`DaemonResources`]
+fn recorded_resources(service: Option<&serde_json::Value>) -> DaemonResources {
+    service
+        .and_then(|service| serde_json::from_value(service["resources"].clone()).ok())
+        .unwrap_or_default()
+}
+
+/// `receipt` with `rotated` (in seconds since the Unix epoch) recorded as when the Nix volume's
+/// passphrase was last rotated
+fn record_passphrase_rotation(receipt: InstallPlan, rotated: u64) -> eyre::Result<InstallPlan> {
+    fn record(value: &mut serde_json::Value, rotated: u64) -> bool {
+        match value {
+            serde_json::Value::Object(object) => {
+                if object.get("action_name").and_then(|name| name.as_str())
+                    == Some("encrypt_apfs_volume")
+                {
+                    object.insert("passphrase_rotated".into(), rotated.into());
+                    return true;
+                }
+                object.values_mut().any(|value| record(value, rotated))
+            },
+            serde_json::Value::Array(values) => {
+                values.iter_mut().any(|value| record(value, rotated))
+            },
+            _ => false,
+        }
+    }
+
+    let mut value = serde_json::to_value(&receipt)?;
+    if !record(&mut value, rotated) {
+        return Err(eyre::eyre!(
+            "The receipt does not record encrypting the Nix volume"
+        ));
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// The store paths recorded in the receipt, read loosely since an older or newer receipt is still useful here
+#[tracing::instrument]
+async fn recorded_store_paths() -> RecordedStorePaths {
+    match receipt_value().await {
+        Some(receipt) => RecordedStorePaths::from_receipt(&receipt),
+        None => RecordedStorePaths::default(),
+    }
+}
+
+/// The `--managed-file-annotation` the receipt was planned with, so repaired hooks carry it too
+async fn recorded_managed_file_annotation() -> Option<String> {
+    receipt_value()
+        .await?
+        .pointer("/planner/settings/managed_file_annotation")?
+        .as_str()
+        .map(ToString::to_string)
+}
+
+/// The boolean `setting` the receipt was planned with, so repaired hooks are written the same way
+async fn recorded_flag(setting: &str) -> bool {
+    receipt_value()
+        .await
+        .and_then(|receipt| {
+            receipt
+                .pointer(&format!("/planner/settings/{setting}"))?
+                .as_bool()
+        })
+        .unwrap_or_default()
+}
+
+/// The receipt as JSON, which older receipts this version can't deserialize still parse as
+async fn receipt_value() -> Option<serde_json::Value> {
+    let receipt = match crate::plan::receipt_reader(RECEIPT_LOCATION) {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            tracing::debug!(%e, "Could not read receipt");
+            return None;
+        },
+    };
+    match serde_json::from_reader::<_, serde_json::Value>(receipt) {
+        Ok(receipt) => Some(receipt),
+        Err(e) => {
+            tracing::debug!(%e, "Could not parse receipt");
+            None
+        },
+    }
+}
+
 #[tracing::instrument(skip_all)]
 fn find_users_and_groups(
     existing_receipt: Option<InstallPlan>,
@@ -487,23 +1011,11 @@ fn find_users_and_groups(
                 let action_tag = stateful_action.inner_typetag_name();
                 tracing::trace!("Found {action_tag} in receipt");
 
-                if action_tag == CreateUsersAndGroups::action_tag().0 {
-                    tracing::debug!(
-                        "Found {} in receipt, preparing to roundtrip to extract the real type",
-                        CreateUsersAndGroups::action_tag().0
-                    );
-                    // NOTE(cole-h): this round-trip is kinda jank... but Action is not
-                    // object-safe, and I can't think of any other way to get the
-                    // concrete `CreateUsersAndGroups` type out of a `Box<dyn Action>`.
-                    let action = &stateful_action.action;
-                    let create_users_and_groups_json =
-                        serde_json::to_string(action).with_context(|| {
-                            format!("round-tripping {action_tag} json to extract real type")
-                        })?;
-                    let create_users_and_groups: CreateUsersAndGroups =
-                        serde_json::from_str(&create_users_and_groups_json).with_context(|| {
-                            format!("round-tripping {action_tag} json to extract real type")
-                        })?;
+                if let Some(create_users_and_groups) =
+                    stateful_action.downcast_ref::<CreateUsersAndGroups>()
+                {
+                    tracing::debug!("Found {action_tag} in receipt");
+                    let create_users_and_groups = create_users_and_groups.clone();
 
                     maybe_create_users_and_groups_idx_action =
                         Some((receipt, idx, create_users_and_groups));
@@ -530,6 +1042,7 @@ struct UsersAndGroupsMeta {
     user_count: u32,
     group_name: String,
     group_gid: Option<u32>,
+    shell_and_home: UserShellAndHome,
     receipt_action_idx_create_group: Option<(InstallPlan, usize, StatefulAction<CreateGroup>)>,
 }
 
@@ -555,6 +1068,7 @@ async fn maybe_users_and_groups_from_receipt(
                 user_count,
                 group_name,
                 group_gid: Some(group_gid),
+                shell_and_home: action.nix_build_user_shell_and_home,
                 receipt_action_idx_create_group: Some((
                     receipt,
                     create_users_and_groups_idx,
@@ -567,6 +1081,7 @@ async fn maybe_users_and_groups_from_receipt(
             user_count: nix_build_user_count,
             group_name: nix_build_group_name.to_string(),
             group_gid: None,
+            shell_and_home: UserShellAndHome::default(),
             receipt_action_idx_create_group: None,
         }),
     }
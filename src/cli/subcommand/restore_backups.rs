@@ -0,0 +1,195 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+
+use clap::{ArgAction, Parser};
+use color_eyre::eyre::WrapErr;
+use owo_colors::OwoColorize;
+
+use crate::{
+    backup::{self, Backup, BackupStore},
+    cli::{ensure_root, interaction, interaction::PromptChoice, CommandExecute},
+    messages::message,
+    plan::RECEIPT_LOCATION,
+    settings::state_dir_validator,
+    state_dir,
+};
+
+use super::install::utc_date;
+
+/// Restore the files `nix-installer` backed up before replacing or modifying them
+///
+/// For emergencies, such as a modified configuration file leaving the host unusable, this restores
+/// backups outside of an uninstall. Without `--path`, the most recent backup of every file which
+/// was backed up is restored.
+#[derive(Debug, Parser)]
+pub struct RestoreBackups {
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_NO_CONFIRM",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub no_confirm: bool,
+
+    /// Only restore the most recent backup of this file
+    #[clap(long)]
+    pub path: Option<PathBuf>,
+
+    /// List every backup, oldest first, rather than restoring any
+    #[clap(long, action(ArgAction::SetTrue), default_value = "false")]
+    pub list: bool,
+
+    /// The state directory holding the backups [default: the one recorded in the receipt]
+    #[clap(long, env = "NIX_INSTALLER_STATE_DIR", value_parser = state_dir_validator)]
+    pub state_dir: Option<PathBuf>,
+
+    /// The receipt the state directory is read from
+    #[clap(long, default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for RestoreBackups {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        ensure_root()?;
+
+        let state_dir = match &self.state_dir {
+            Some(state_dir) => state_dir.clone(),
+            None => receipt_state_dir(&self.receipt),
+        };
+        let store = BackupStore::new(&state_dir);
+        let backups = store
+            .list()
+            .await
+            .wrap_err_with(|| format!("Listing the backups in `{}`", store.dir().display()))?;
+        let backups = backups
+            .into_iter()
+            .filter(|backup| self.path.as_ref().map_or(true, |path| backup.path == *path))
+            .collect::<Vec<_>>();
+
+        if backups.is_empty() {
+            eprintln!(
+                "{}",
+                message!(RestoreBackupsNone, dir = store.dir().display()).yellow()
+            );
+            // Asking for a particular file which was never backed up is a mistake
+            return Ok(match self.path {
+                Some(_) => ExitCode::FAILURE,
+                None => ExitCode::SUCCESS,
+            });
+        }
+
+        if self.list {
+            for backup in &backups {
+                println!("{}", describe(backup));
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        let to_restore = backup::latest(&backups);
+        if !self.no_confirm {
+            let files = to_restore
+                .iter()
+                .map(|backup| format!("* {}", describe(backup)))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let prompt = message!(RestoreBackupsPrompt, files = files);
+            loop {
+                match interaction::prompt(&prompt, PromptChoice::Yes, true).await? {
+                    PromptChoice::Yes => break,
+                    PromptChoice::No => {
+                        interaction::clean_exit_with_message(message!(RestoreBackupsDeclined)).await
+                    },
+                    PromptChoice::Explain | PromptChoice::Customize => (),
+                }
+            }
+        }
+
+        restore(&store, &to_restore).await?;
+
+        println!(
+            "{}",
+            message!(RestoreBackupsSuccess, count = to_restore.len())
+                .green()
+                .bold()
+        );
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// The state directory recorded in `receipt`, or the default if it cannot be read
+fn receipt_state_dir(receipt: &Path) -> PathBuf {
+    crate::plan::receipt_reader(receipt)
+        .ok()
+        .and_then(|reader| serde_json::from_reader::<_, serde_json::Value>(reader).ok())
+        .map(|receipt| state_dir::of_receipt_json(&receipt))
+        .unwrap_or_else(|| PathBuf::from(state_dir::DEFAULT_STATE_DIR))
+}
+
+/// Restore every one of `backups`, stopping at the first which cannot be
+async fn restore(store: &BackupStore, backups: &[&Backup]) -> eyre::Result<()> {
+    for backup in backups {
+        store
+            .restore(backup)
+            .await
+            .wrap_err_with(|| format!("Restoring `{}`", backup.path.display()))?;
+        tracing::info!("Restored `{}`", backup.path.display());
+    }
+    Ok(())
+}
+
+fn describe(backup: &Backup) -> String {
+    format!(
+        "`{}` backed up {} ({} bytes, SHA-256 {})",
+        backup.path.display(),
+        utc_date(SystemTime::UNIX_EPOCH + Duration::from_secs(backup.created)),
+        backup.size,
+        backup.hash,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::restore;
+    use crate::{
+        backup::{latest, BackupStore},
+        state_dir::DEFAULT_STATE_DIR,
+        test_harness::SandboxContext,
+    };
+
+    #[tokio::test]
+    async fn restores_the_most_recent_backups() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let store = BackupStore::new(DEFAULT_STATE_DIR);
+        for (path, content) in [
+            ("/etc/nix.conf", "first"),
+            ("/etc/nix.conf", "second"),
+            ("/etc/other.conf", "other"),
+        ] {
+            std::fs::write(sandbox.path(path), content)?;
+            sandbox.scope(store.back_up(Path::new(path))).await?;
+        }
+        std::fs::write(sandbox.path("/etc/nix.conf"), "broken")?;
+        std::fs::write(sandbox.path("/etc/other.conf"), "broken")?;
+
+        let backups = sandbox.scope(store.list()).await?;
+        sandbox.scope(restore(&store, &latest(&backups))).await?;
+
+        assert_eq!(
+            std::fs::read_to_string(sandbox.path("/etc/nix.conf"))?,
+            "second"
+        );
+        assert_eq!(
+            std::fs::read_to_string(sandbox.path("/etc/other.conf"))?,
+            "other"
+        );
+        Ok(())
+    }
+}
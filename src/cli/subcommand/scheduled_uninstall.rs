@@ -0,0 +1,325 @@
+//! Deferring the destruction of the Nix store to the next boot
+//!
+//! Some hosts (for example, macOS machines running endpoint security software which pins files
+//! in `/nix`) cannot detach the Nix store while the system is running. For those, `uninstall
+//! --schedule-at-reboot` reverts everything else live, then installs a one-shot boot task which
+//! reverts the phase 2 receipt (see `split-receipt`) before anything opens `/nix`, and then
+//! removes itself.
+
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use color_eyre::eyre::WrapErr;
+
+use crate::{util::host_path, InstallPlan};
+
+/// Holds a copy of `nix-installer` and the phase 2 receipt, since `/nix` may not be mounted at boot
+pub(crate) const SCHEDULED_UNINSTALL_DIR: &str = "/var/lib/nix-installer/scheduled-uninstall";
+const SCHEDULED_UNINSTALL_BINARY: &str = "nix-installer";
+const SCHEDULED_UNINSTALL_RECEIPT: &str = "uninstall-phase2.json";
+
+#[cfg(target_os = "macos")]
+const BOOT_TASK_LABEL: &str = "systems.determinate.nix-installer.scheduled-uninstall";
+#[cfg(target_os = "macos")]
+const BOOT_TASK_PATH: &str =
+    "/Library/LaunchDaemons/systems.determinate.nix-installer.scheduled-uninstall.plist";
+#[cfg(target_os = "macos")]
+const BOOT_TASK_LOG: &str = "/var/log/nix-installer-scheduled-uninstall.log";
+
+#[cfg(not(target_os = "macos"))]
+const BOOT_TASK_PATH: &str = "/etc/systemd/system/nix-installer-scheduled-uninstall.service";
+#[cfg(not(target_os = "macos"))]
+const BOOT_TASK_WANTS_PATH: &str =
+    "/etc/systemd/system/sysinit.target.wants/nix-installer-scheduled-uninstall.service";
+
+/// The files installed by [`schedule`]
+#[derive(Debug)]
+pub(crate) struct ScheduledUninstall {
+    pub(crate) boot_task: PathBuf,
+    pub(crate) receipt: PathBuf,
+}
+
+/// Install a boot task which reverts `phase2_plan` on the next boot
+///
+/// Scheduling again replaces any pending boot task.
+pub(crate) async fn schedule(
+    phase2_plan: &impl serde::Serialize,
+) -> eyre::Result<ScheduledUninstall> {
+    let dir = host_path(SCHEDULED_UNINSTALL_DIR);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .wrap_err_with(|| format!("Creating `{}`", dir.display()))?;
+
+    // `/nix/nix-installer` goes away along with the rest of the store, so keep our own copy
+    let binary = dir.join(SCHEDULED_UNINSTALL_BINARY);
+    let current_exe = std::env::current_exe().wrap_err("Finding the current executable")?;
+    tokio::fs::copy(&current_exe, &binary)
+        .await
+        .wrap_err_with(|| format!("Copying `nix-installer` to `{}`", binary.display()))?;
+
+    let receipt = dir.join(SCHEDULED_UNINSTALL_RECEIPT);
+    let receipt_json = serde_json::to_string_pretty(phase2_plan)?;
+    tokio::fs::write(&receipt, format!("{receipt_json}\n"))
+        .await
+        .wrap_err_with(|| format!("Writing `{}`", receipt.display()))?;
+
+    let boot_task = install_boot_task().await?;
+
+    Ok(ScheduledUninstall { boot_task, receipt })
+}
+
+/// Remove any pending boot task, returning `false` if nothing was scheduled
+///
+/// Nothing else is restored, the Nix store remains in place along with the phase 2 receipt.
+pub(crate) async fn cancel() -> eyre::Result<bool> {
+    let boot_task = host_path(BOOT_TASK_PATH);
+    let dir = host_path(SCHEDULED_UNINSTALL_DIR);
+    let was_scheduled = boot_task.exists() || dir.exists();
+
+    remove_boot_task().await?;
+    if dir.exists() {
+        tokio::fs::remove_dir_all(&dir)
+            .await
+            .wrap_err_with(|| format!("Removing `{}`", dir.display()))?;
+    }
+
+    Ok(was_scheduled)
+}
+
+/// Run from the boot task: revert the phase 2 receipt, then remove the boot task
+///
+/// Safe to run repeatedly, a missing receipt means there is nothing left to do. The boot task is
+/// removed even if the revert fails so a broken uninstall is not retried on every boot, in that
+/// case the receipt is left in place for a manual `nix-installer uninstall`.
+pub(crate) async fn run(receipt: &Path) -> eyre::Result<ExitCode> {
+    if !receipt.exists() {
+        tracing::info!(
+            "Scheduled uninstall receipt `{}` does not exist, nothing to do",
+            receipt.display()
+        );
+        cancel().await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut plan = crate::plan::read_receipt(receipt).wrap_err("Reading receipt")?;
+
+    let res = plan.uninstall(None).await;
+    remove_boot_task().await?;
+
+    match res {
+        Ok(()) => {
+            cancel().await?;
+            tracing::info!("Scheduled uninstall of the Nix store completed");
+            Ok(ExitCode::SUCCESS)
+        },
+        Err(err) => {
+            // Record what was reverted so a manual uninstall picks up where this left off
+            let receipt_json = serde_json::to_string_pretty(&plan)?;
+            tokio::fs::write(receipt, format!("{receipt_json}\n"))
+                .await
+                .wrap_err_with(|| format!("Writing `{}`", receipt.display()))?;
+            tracing::error!(
+                "Scheduled uninstall of the Nix store failed, complete it with `{} uninstall {}`: {err:?}",
+                host_path(SCHEDULED_UNINSTALL_DIR)
+                    .join(SCHEDULED_UNINSTALL_BINARY)
+                    .display(),
+                receipt.display(),
+            );
+            Ok(ExitCode::FAILURE)
+        },
+    }
+}
+
+/// A human readable summary of what `phase2_plan` will do at the next boot
+pub(crate) fn describe_deferred(phase2_plan: &InstallPlan) -> String {
+    let deferred = phase2_plan
+        .actions
+        .iter()
+        .flat_map(|action| action.describe_revert())
+        .map(|desc| format!("* {}", desc.description))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "\
+        Deferred until the next boot:\n\
+        {deferred}\n\
+        ",
+        deferred = if deferred.is_empty() {
+            "Nothing".to_string()
+        } else {
+            deferred
+        },
+    )
+}
+
+/// The arguments the boot task runs, so the scheduled copy of `nix-installer` reverts the scheduled receipt
+fn boot_task_arguments() -> Vec<String> {
+    let dir = PathBuf::from(SCHEDULED_UNINSTALL_DIR);
+    vec![
+        dir.join(SCHEDULED_UNINSTALL_BINARY).display().to_string(),
+        "uninstall".into(),
+        "--no-confirm".into(),
+        "--run-scheduled-uninstall".into(),
+        dir.join(SCHEDULED_UNINSTALL_RECEIPT).display().to_string(),
+    ]
+}
+
+#[cfg(target_os = "macos")]
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ScheduledUninstallPlist {
+    label: String,
+    program_arguments: Vec<String>,
+    run_at_load: bool,
+    standard_error_path: String,
+    standard_out_path: String,
+}
+
+#[cfg(target_os = "macos")]
+async fn install_boot_task() -> eyre::Result<PathBuf> {
+    let plist = ScheduledUninstallPlist {
+        label: BOOT_TASK_LABEL.into(),
+        program_arguments: boot_task_arguments(),
+        run_at_load: true,
+        standard_error_path: BOOT_TASK_LOG.into(),
+        standard_out_path: BOOT_TASK_LOG.into(),
+    };
+
+    let mut buf = Vec::new();
+    plist::to_writer_xml(&mut buf, &plist)?;
+
+    // Deliberately not bootstrapped, the daemon should only run at the next boot
+    let path = host_path(BOOT_TASK_PATH);
+    tokio::fs::write(&path, buf)
+        .await
+        .wrap_err_with(|| format!("Writing `{}`", path.display()))?;
+
+    Ok(path)
+}
+
+#[cfg(not(target_os = "macos"))]
+async fn install_boot_task() -> eyre::Result<PathBuf> {
+    let exec_start = boot_task_arguments().join(" ");
+    // Runs as early as possible once the receipt is readable, and before `/nix` can be mounted
+    let unit = format!(
+        "\
+        [Unit]\n\
+        Description=Remove the Nix store (scheduled by nix-installer uninstall --schedule-at-reboot)\n\
+        DefaultDependencies=no\n\
+        RequiresMountsFor={SCHEDULED_UNINSTALL_DIR}\n\
+        After=systemd-remount-fs.service\n\
+        Before=nix.mount local-fs.target sysinit.target shutdown.target\n\
+        Conflicts=shutdown.target\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        ExecStart={exec_start}\n\
+        \n\
+        [Install]\n\
+        WantedBy=sysinit.target\n\
+        "
+    );
+
+    let path = host_path(BOOT_TASK_PATH);
+    tokio::fs::write(&path, unit)
+        .await
+        .wrap_err_with(|| format!("Writing `{}`", path.display()))?;
+
+    // Equivalent to `systemctl enable`, which may not be usable (e.g. in a container)
+    let wants = host_path(BOOT_TASK_WANTS_PATH);
+    if let Some(parent) = wants.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .wrap_err_with(|| format!("Creating `{}`", parent.display()))?;
+    }
+    if wants.symlink_metadata().is_ok() {
+        tokio::fs::remove_file(&wants)
+            .await
+            .wrap_err_with(|| format!("Removing `{}`", wants.display()))?;
+    }
+    tokio::fs::symlink(BOOT_TASK_PATH, &wants)
+        .await
+        .wrap_err_with(|| format!("Linking `{}`", wants.display()))?;
+
+    Ok(path)
+}
+
+async fn remove_boot_task() -> eyre::Result<()> {
+    #[cfg(target_os = "macos")]
+    let paths = [host_path(BOOT_TASK_PATH)];
+    #[cfg(not(target_os = "macos"))]
+    let paths = [host_path(BOOT_TASK_WANTS_PATH), host_path(BOOT_TASK_PATH)];
+
+    for path in paths {
+        if path.symlink_metadata().is_ok() {
+            tokio::fs::remove_file(&path)
+                .await
+                .wrap_err_with(|| format!("Removing `{}`", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_harness::SandboxContext;
+
+    #[tokio::test]
+    async fn schedules_and_cancels() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+
+        let scheduled = sandbox
+            .scope(schedule(&serde_json::json!({ "actions": [] })))
+            .await?;
+        assert_eq!(scheduled.boot_task, sandbox.path(BOOT_TASK_PATH));
+        assert!(scheduled.boot_task.exists());
+        assert!(scheduled.receipt.exists());
+        assert!(sandbox
+            .path(SCHEDULED_UNINSTALL_DIR)
+            .join(SCHEDULED_UNINSTALL_BINARY)
+            .exists());
+        #[cfg(not(target_os = "macos"))]
+        {
+            let unit = std::fs::read_to_string(&scheduled.boot_task)?;
+            assert!(unit.contains("DefaultDependencies=no"));
+            assert!(unit.contains("--run-scheduled-uninstall"));
+            assert!(sandbox
+                .path(BOOT_TASK_WANTS_PATH)
+                .symlink_metadata()
+                .is_ok());
+        }
+
+        // Scheduling again replaces the pending boot task
+        sandbox
+            .scope(schedule(&serde_json::json!({ "actions": [] })))
+            .await?;
+
+        assert!(sandbox.scope(cancel()).await?);
+        assert!(!scheduled.boot_task.exists());
+        assert!(!sandbox.path(SCHEDULED_UNINSTALL_DIR).exists());
+        assert!(!sandbox.scope(cancel()).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_without_receipt_cleans_up() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let scheduled = sandbox
+            .scope(schedule(&serde_json::json!({ "actions": [] })))
+            .await?;
+        std::fs::remove_file(&scheduled.receipt)?;
+
+        let exit = sandbox.scope(run(&scheduled.receipt)).await?;
+        assert_eq!(exit, ExitCode::SUCCESS);
+        assert!(!scheduled.boot_task.exists());
+        assert!(!sandbox.path(SCHEDULED_UNINSTALL_DIR).exists());
+
+        Ok(())
+    }
+}
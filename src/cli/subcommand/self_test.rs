@@ -1,20 +1,63 @@
-use std::process::ExitCode;
+use std::{path::PathBuf, process::ExitCode};
 
-use clap::Parser;
+use clap::{ArgAction, Parser};
+use eyre::eyre;
+use owo_colors::OwoColorize;
+use url::Url;
 
-use crate::{cli::CommandExecute, NixInstallerError};
+use crate::{
+    cli::CommandExecute,
+    network_probe::{diagnose, NetworkReport, ProbeOutcome, DEFAULT_PROBE_URL},
+    self_test::SelfTestCheck,
+    settings::UrlOrPath,
+    NixInstallerError,
+};
 
 /// Run a self test of Nix to ensure that an install is working
 #[derive(Debug, Parser)]
-pub struct SelfTest {}
+pub struct SelfTest {
+    /// Also verify the contents of every store path, which reads the whole store
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_SELF_TEST_VERIFY_STORE",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub verify_store: bool,
+
+    #[clap(subcommand)]
+    pub subcommand: Option<SelfTestSubcommand>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum SelfTestSubcommand {
+    Network(Network),
+}
 
 #[async_trait::async_trait]
 impl CommandExecute for SelfTest {
     #[tracing::instrument(level = "debug", skip_all, fields())]
     async fn execute(self) -> eyre::Result<ExitCode> {
-        crate::self_test::self_test()
-            .await
-            .map_err(NixInstallerError::SelfTest)?;
+        let Self {
+            verify_store,
+            subcommand,
+        } = self;
+        if let Some(SelfTestSubcommand::Network(network)) = subcommand {
+            return network.execute().await;
+        }
+
+        let mut checks = crate::self_test::self_test_report().await;
+        if verify_store {
+            checks.push(crate::self_test::verify_store_contents().await);
+        }
+        print!("{}", describe_checks(&checks));
+        let failures = checks
+            .into_iter()
+            .filter_map(|check| check.result.err())
+            .collect::<Vec<_>>();
+        if !failures.is_empty() {
+            return Err(NixInstallerError::SelfTest(failures))?;
+        }
 
         tracing::info!(
             shells = ?crate::self_test::Shell::discover()
@@ -26,3 +69,119 @@ impl CommandExecute for SelfTest {
         Ok(ExitCode::SUCCESS)
     }
 }
+
+fn describe_checks(checks: &[SelfTestCheck]) -> String {
+    let mut buf = String::new();
+    for check in checks {
+        let outcome = match &check.result {
+            Ok(()) => "passed".green().to_string(),
+            Err(_) => "failed".red().to_string(),
+        };
+        buf.push_str(&format!("  {outcome} {}\n", check.name));
+    }
+    buf
+}
+
+/**
+Diagnose why fetching Nix fails, probing the network one layer at a time
+
+Runs DNS, TCP, TLS, HTTP, proxy, and clock probes with the same client configuration as the
+install's fetch, then names the first failing layer along with what to try next. Nothing needs to
+be installed, and nothing on the system is changed.
+*/
+#[derive(Debug, Parser)]
+pub struct Network {
+    /// The URL to probe, defaults to the binary cache Nix fetches from once installed
+    #[clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_URL")]
+    pub nix_package_url: Option<UrlOrPath>,
+
+    /// The proxy to use (if any); valid proxy bases are `https://$URL`, `http://$URL` and `socks5://$URL`
+    #[clap(long, env = "NIX_INSTALLER_PROXY")]
+    pub proxy: Option<Url>,
+
+    /// An SSL cert to trust in addition to the system's
+    #[clap(long, env = "NIX_INSTALLER_SSL_CERT_FILE")]
+    pub ssl_cert_file: Option<PathBuf>,
+
+    /// Emit the outcome of each probe as JSON
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_JSON",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub json: bool,
+}
+
+impl Network {
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self {
+            nix_package_url,
+            proxy,
+            ssl_cert_file,
+            json,
+        } = self;
+
+        let url = match nix_package_url {
+            None => Url::parse(DEFAULT_PROBE_URL)?,
+            Some(UrlOrPath::Url(url)) if matches!(url.scheme(), "https" | "http") => url,
+            Some(other) => {
+                return Err(eyre!(
+                    "`{other}` is not fetched over the network, pass an `https` or `http` URL"
+                ))
+            },
+        };
+        if let Some(proxy) = &proxy {
+            if !matches!(proxy.scheme(), "https" | "http" | "socks5") {
+                return Err(eyre!("Unknown proxy scheme `{}`", proxy.scheme()));
+            }
+        }
+
+        let report = diagnose(&url, proxy.as_ref(), ssl_cert_file.as_deref()).await;
+        match json {
+            true => println!("{}", serde_json::to_string_pretty(&report)?),
+            false => print!("{}", describe_report(&report)),
+        }
+
+        match report.first_failure() {
+            Some(_) => Ok(ExitCode::FAILURE),
+            None => Ok(ExitCode::SUCCESS),
+        }
+    }
+}
+
+fn describe_report(report: &NetworkReport) -> String {
+    let mut buf = format!("Probing `{}`", report.url);
+    if let Some(proxy) = &report.proxy {
+        buf.push_str(&format!(" through `{proxy}`"));
+    }
+    buf.push('\n');
+    for probe in &report.probes {
+        let layer = format!("{:<6}", probe.layer.to_string());
+        let line = match &probe.outcome {
+            ProbeOutcome::Passed { detail } => format!("{} {layer} {detail}", "passed ".green()),
+            ProbeOutcome::Failed { detail, .. } => format!("{} {layer} {detail}", "failed ".red()),
+            ProbeOutcome::Skipped { detail } => {
+                format!("{} {layer} {}", "skipped".yellow(), detail.dimmed())
+            },
+        };
+        buf.push_str(&format!("  {line}\n"));
+    }
+    buf.push('\n');
+    match report.first_failure() {
+        Some(probe) => {
+            let hint = match &probe.outcome {
+                ProbeOutcome::Failed { hint, .. } => hint.as_str(),
+                _ => "",
+            };
+            buf.push_str(&format!(
+                "{}\n{hint}\n",
+                format!("The {} layer failed first.", probe.layer)
+                    .red()
+                    .bold()
+            ));
+        },
+        None => buf.push_str(&format!("{}\n", "Every probe passed.".green())),
+    }
+    buf
+}
@@ -1,10 +1,14 @@
-use std::{path::PathBuf, process::ExitCode, time::SystemTime};
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::SystemTime,
+};
 
 use crate::{
     action::{Action, ActionState, StatefulAction},
     cli::{ensure_root, interaction::PromptChoice},
     plan::RECEIPT_LOCATION,
-    InstallPlan,
+    state_dir, InstallPlan,
 };
 use clap::{ArgAction, Parser};
 use color_eyre::eyre::WrapErr;
@@ -13,16 +17,14 @@ use owo_colors::OwoColorize;
 
 use crate::cli::CommandExecute;
 
-pub(crate) const PHASE1_RECEIPT_LOCATION: &str = "/nix/uninstall-phase1.json";
-pub(crate) const PHASE2_RECEIPT_LOCATION: &str = "/nix/uninstall-phase2.json";
-
 /// Split an existing receipt into two phases, one that cleans up the Nix store (phase 2), and
 /// one that does everything else (phase 1).
 ///
-/// This will produce two modified receipts -- a phase 1 receipt and a phase 2 receipt. If you
-/// run `/nix/nix-installer uninstall /nix/uninstall-phase1.json` (the default phase 1
-/// location), it will clean up everything but the Nix store and allow you to reinstall with a
-/// newer version. If you run `/nix/nix-installer uninstall /nix/uninstall-phase2.json`, then it
+/// This will produce two modified receipts -- a phase 1 receipt and a phase 2 receipt, written to
+/// the state directory (`/nix/var/nix-installer` unless the install was given `--state-dir`) by
+/// default. If you run `/nix/nix-installer uninstall /nix/var/nix-installer/uninstall-phase1.json`,
+/// it will clean up everything but the Nix store and allow you to reinstall with a newer version.
+/// If you run `/nix/nix-installer uninstall /nix/var/nix-installer/uninstall-phase2.json`, then it
 /// will complete the uninstall by cleaning up the Nix store.
 #[derive(Debug, Parser)]
 pub struct SplitReceipt {
@@ -36,10 +38,12 @@ pub struct SplitReceipt {
     pub no_confirm: bool,
     #[clap(default_value = RECEIPT_LOCATION)]
     pub receipt: PathBuf,
-    #[clap(long, default_value = PHASE1_RECEIPT_LOCATION)]
-    pub phase1_output: PathBuf,
-    #[clap(long, default_value = PHASE2_RECEIPT_LOCATION)]
-    pub phase2_output: PathBuf,
+    /// Where to write the phase 1 receipt [default: `uninstall-phase1.json` in the state directory]
+    #[clap(long)]
+    pub phase1_output: Option<PathBuf>,
+    /// Where to write the phase 2 receipt [default: `uninstall-phase2.json` in the state directory]
+    #[clap(long)]
+    pub phase2_output: Option<PathBuf>,
     // NOTE(cole-h): an escape hatch in case we somehow run into a case where the "receipt is
     // valid and we can actually parse it into structs" step does the wrong thing; hidden so
     // that users aren't tempted to use it themselves, but we can suggest it as a break-glass
@@ -48,6 +52,20 @@ pub struct SplitReceipt {
     pub force_naive_json_method: bool,
 }
 
+impl SplitReceipt {
+    /// Where the phase 1 and phase 2 receipts are written, for an install using `state_dir`
+    fn outputs(&self, state_dir: &Path) -> (PathBuf, PathBuf) {
+        (
+            self.phase1_output
+                .clone()
+                .unwrap_or_else(|| state_dir.join(state_dir::PHASE1_RECEIPT)),
+            self.phase2_output
+                .clone()
+                .unwrap_or_else(|| state_dir.join(state_dir::PHASE2_RECEIPT)),
+        )
+    }
+}
+
 #[async_trait::async_trait]
 impl CommandExecute for SplitReceipt {
     #[tracing::instrument(level = "debug", skip_all)]
@@ -58,9 +76,17 @@ impl CommandExecute for SplitReceipt {
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
 
+        let install_receipt_string = tokio::fs::read_to_string(&self.receipt)
+            .await
+            .wrap_err("Reading receipt")?;
+        let state_dir = serde_json::from_str(&install_receipt_string)
+            .map(|receipt| state_dir::of_receipt_json(&receipt))
+            .unwrap_or_else(|_| PathBuf::from(state_dir::DEFAULT_STATE_DIR));
+        let (phase1_output, phase2_output) = self.outputs(&state_dir);
+
         let original_receipt_location = PathBuf::from(RECEIPT_LOCATION);
-        let backed_up_receipt_location = original_receipt_location
-            .with_file_name(format!(".original-receipt.{timestamp_millis}.json"));
+        let backed_up_receipt_location =
+            state_dir.join(format!("original-receipt.{timestamp_millis}.json"));
 
         let brief_summary = format!("\n\
                This will split your existing receipt at {receipt} into two phases (phase 1: {phase1}, phase 2: {phase2}) \
@@ -72,8 +98,8 @@ impl CommandExecute for SplitReceipt {
                If you want a clean uninstallation, you should run phase 2 after phase 1.\
                ",
            receipt = self.receipt.display().bold(),
-           phase1 = self.phase1_output.display().bold(),
-           phase2 = self.phase2_output.display().bold(),
+           phase1 = phase1_output.display().bold(),
+           phase2 = phase2_output.display().bold(),
            backup_location = backed_up_receipt_location.display().bold(),
            except = "except".italic(),
         );
@@ -90,19 +116,20 @@ impl CommandExecute for SplitReceipt {
                         )
                         .await
                     },
-                    PromptChoice::Explain => (),
+                    PromptChoice::Explain | PromptChoice::Customize => (),
                 }
             }
         } else {
             tracing::info!("{}", brief_summary);
         }
 
-        let install_receipt_string = tokio::fs::read_to_string(&self.receipt)
-            .await
-            .wrap_err("Reading receipt")?;
-
         if self.force_naive_json_method {
-            two_phased_cannot_parse_receipt_perfectly(&self, &install_receipt_string).await?;
+            two_phased_cannot_parse_receipt_perfectly(
+                &install_receipt_string,
+                &phase1_output,
+                &phase2_output,
+            )
+            .await?;
         } else {
             let maybe_compatible_plan =
                 serde_json::from_str::<InstallPlan>(&install_receipt_string)
@@ -116,15 +143,23 @@ impl CommandExecute for SplitReceipt {
                     });
             match maybe_compatible_plan {
                 Some(plan) => {
-                    two_phased_can_parse_receipt_perfectly(&self, plan).await?;
+                    two_phased_can_parse_receipt_perfectly(plan, &phase1_output, &phase2_output)
+                        .await?;
                 },
                 None => {
-                    two_phased_cannot_parse_receipt_perfectly(&self, &install_receipt_string)
-                        .await?;
+                    two_phased_cannot_parse_receipt_perfectly(
+                        &install_receipt_string,
+                        &phase1_output,
+                        &phase2_output,
+                    )
+                    .await?;
                 },
             }
         }
 
+        tokio::fs::create_dir_all(&state_dir)
+            .await
+            .wrap_err_with(|| format!("Creating state directory {}", state_dir.display()))?;
         tokio::fs::rename(original_receipt_location, &backed_up_receipt_location).await?;
         tracing::info!(
             "Backed up original, untouched receipt to {}",
@@ -141,8 +176,8 @@ impl CommandExecute for SplitReceipt {
                 Phase 2: {phase2}\n\
                 You can now uninstall starting with:\n\
                 /nix/nix-installer uninstall {phase1}",
-                phase1 = self.phase1_output.display(),
-                phase2 = self.phase2_output.display()
+                phase1 = phase1_output.display(),
+                phase2 = phase2_output.display()
             )
             .green()
             .bold(),
@@ -155,16 +190,35 @@ impl CommandExecute for SplitReceipt {
 /// If the receipt can be parsed by this version of the installer, then we can use the actual
 /// types as they will have the same fields.
 async fn two_phased_can_parse_receipt_perfectly(
-    uninstall_args: &SplitReceipt,
     plan: InstallPlan,
+    phase1_output: &Path,
+    phase2_output: &Path,
 ) -> eyre::Result<()> {
     tracing::debug!("Using the 'can actually parse receipt perfectly' method to split the receipt");
 
+    let (phase1_plan, phase2_plan) = split_plan(plan)?;
+
+    crate::plan::write_receipt(&phase1_plan, phase1_output).await?;
+    crate::plan::write_receipt(&phase2_plan, phase2_output).await?;
+
+    Ok(())
+}
+
+/// Split `plan` into a phase 1 plan which reverts everything except the Nix store, and a phase 2
+/// plan which reverts only the Nix store
+pub(crate) fn split_plan(plan: InstallPlan) -> eyre::Result<(InstallPlan, InstallPlan)> {
     let mut phase1_plan = plan;
     let mut phase2_plan = InstallPlan {
         version: phase1_plan.version.clone(),
+        receipt_schema: phase1_plan.receipt_schema,
         actions: Vec::new(),
         planner: phase1_plan.planner.clone(),
+        host_snapshot: phase1_plan.host_snapshot.clone(),
+        build_info: phase1_plan.build_info.clone(),
+        cross_target: phase1_plan.cross_target.clone(),
+        invocation: phase1_plan.invocation.clone(),
+        warnings: phase1_plan.warnings.clone(),
+        managed_files: vec![],
         #[cfg(feature = "diagnostics")]
         diagnostic_data: phase1_plan.diagnostic_data.clone(),
     };
@@ -173,8 +227,7 @@ async fn two_phased_can_parse_receipt_perfectly(
         let inner_typetag_name = action.inner_typetag_name();
         match inner_typetag_name {
             action_tag if action_tag == crate::action::common::ProvisionNix::action_tag().0 => {
-                let action_unjson =
-                    roundtrip_to_extract_type::<crate::action::common::ProvisionNix>(action)?;
+                let action_unjson = extract_type::<crate::action::common::ProvisionNix>(action)?;
 
                 tracing::debug!(
                     "Marking provision_nix as skipped so we don't undo it until phase 2"
@@ -195,8 +248,7 @@ async fn two_phased_can_parse_receipt_perfectly(
                 }
             },
             action_tag if action_tag == crate::action::base::CreateDirectory::action_tag().0 => {
-                let action_unjson =
-                    roundtrip_to_extract_type::<crate::action::base::CreateDirectory>(action)?;
+                let action_unjson = extract_type::<crate::action::base::CreateDirectory>(action)?;
 
                 // NOTE(cole-h): we check if it stars with /nix, in case we start creating more
                 // directories in the "toplevel" actions
@@ -218,9 +270,26 @@ async fn two_phased_can_parse_receipt_perfectly(
                     }
                 }
             },
+            action_tag if action_tag == crate::action::linux::CreateZfsDataset::action_tag().0 => {
+                tracing::debug!(
+                    "Marking create_zfs_dataset as skipped so we don't undo it until phase 2"
+                );
+
+                phase2_plan.actions.push(action.clone());
+                action.state = ActionState::Skipped;
+            },
+            action_tag
+                if action_tag == crate::action::linux::CreateStoreBindMount::action_tag().0 =>
+            {
+                tracing::debug!(
+                    "Marking create_store_bind_mount as skipped so we don't undo it until phase 2"
+                );
+
+                phase2_plan.actions.push(action.clone());
+                action.state = ActionState::Skipped;
+            },
             action_tag if action_tag == crate::action::macos::CreateNixVolume::action_tag().0 => {
-                let action_unjson =
-                    roundtrip_to_extract_type::<crate::action::macos::CreateNixVolume>(action)?;
+                let action_unjson = extract_type::<crate::action::macos::CreateNixVolume>(action)?;
 
                 tracing::debug!("Marking create_volume, encrypt_volume (if it happened), unmount_volume as skipped so we don't undo it until phase 2");
 
@@ -251,9 +320,8 @@ async fn two_phased_can_parse_receipt_perfectly(
                 if action_tag
                     == crate::action::macos::CreateDeterminateNixVolume::action_tag().0 =>
             {
-                let action_unjson = roundtrip_to_extract_type::<
-                    crate::action::macos::CreateDeterminateNixVolume,
-                >(action)?;
+                let action_unjson =
+                    extract_type::<crate::action::macos::CreateDeterminateNixVolume>(action)?;
 
                 tracing::debug!("Marking create_volume, encrypt_volume, unmount_volume as skipped so we don't undo it until phase 2");
 
@@ -282,18 +350,16 @@ async fn two_phased_can_parse_receipt_perfectly(
         }
     }
 
-    crate::plan::write_receipt(&phase1_plan, &uninstall_args.phase1_output).await?;
-    crate::plan::write_receipt(&phase2_plan, &uninstall_args.phase2_output).await?;
-
-    Ok(())
+    Ok((phase1_plan, phase2_plan))
 }
 
 /// If the receipt cannot be parsed or is not compatible with this version of the installer, we
 /// fall back to naive JSON poking. Since the structure is version-specific, we have to be
 /// careful that we account for this.
 async fn two_phased_cannot_parse_receipt_perfectly(
-    uninstall_args: &SplitReceipt,
     receipt_str: &str,
+    phase1_output: &Path,
+    phase2_output: &Path,
 ) -> eyre::Result<()> {
     tracing::debug!("Using the 'cannot parse receipt perfectly' method to split the receipt");
 
@@ -395,6 +461,22 @@ async fn two_phased_cannot_parse_receipt_perfectly(
                     }
                 }
             },
+            // CreateZfsDataset and CreateStoreBindMount; Linux-only
+            "create_zfs_dataset" | "create_store_bind_mount" => {
+                tracing::debug!(
+                    "Marking {action_name} as skipped so we don't undo it until phase 2"
+                );
+
+                {
+                    phase2_plan
+                        .actions
+                        .push(serde_json::Value::Object(entry.clone()));
+                }
+
+                {
+                    entry["state"] = skipped_json_value.clone();
+                }
+            },
             s if (
                 // CreateNixVolume on >= 0.28.0; macOS-only
                 s == "create_nix_volume" && *receipt_version >= semver::Version::new(0, 28, 0)
@@ -455,23 +537,64 @@ async fn two_phased_cannot_parse_receipt_perfectly(
             _s => {},
         }
     }
-    crate::plan::write_receipt(&phase1_plan, &uninstall_args.phase1_output).await?;
-    crate::plan::write_receipt(&phase2_plan, &uninstall_args.phase2_output).await?;
+    crate::plan::write_receipt(&phase1_plan, phase1_output).await?;
+    crate::plan::write_receipt(&phase2_plan, phase2_output).await?;
 
     Ok(())
 }
 
-fn roundtrip_to_extract_type<T: serde::de::DeserializeOwned>(
+fn extract_type<T: Action + Clone>(
     action: &StatefulAction<Box<dyn Action>>,
 ) -> eyre::Result<StatefulAction<T>> {
-    let action_name = std::any::type_name::<T>();
-    let action_json = serde_json::to_string(action).with_context(|| {
-        format!("serde_json::to_string'ing {action_name} json to extract real type")
-    })?;
-    let action_unjson: StatefulAction<T> =
-        serde_json::from_str(&action_json).with_context(|| {
-            format!("serde_json::from_str'ing {action_name} json to extract real type")
-        })?;
-
-    Ok(action_unjson)
+    action.downcast::<T>().ok_or_else(|| {
+        eyre::eyre!(
+            "{} was not a {}",
+            action.inner_typetag_name(),
+            std::any::type_name::<T>()
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{two_phased_can_parse_receipt_perfectly, SplitReceipt};
+    use crate::{plan::RECEIPT_LOCATION, state_dir, test_harness::SandboxContext, InstallPlan};
+
+    const LINUX: &str = include_str!("../../../tests/fixtures/linux/linux.json");
+
+    #[tokio::test]
+    async fn phase_receipts_are_written_to_the_state_dir() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/nix"))?;
+        let plan: InstallPlan = serde_json::from_str(LINUX)?;
+        let split_receipt = SplitReceipt {
+            no_confirm: true,
+            receipt: RECEIPT_LOCATION.into(),
+            phase1_output: None,
+            phase2_output: None,
+            force_naive_json_method: false,
+        };
+
+        let (phase1_output, phase2_output) = split_receipt.outputs(&state_dir::of_plan(&plan));
+        sandbox
+            .scope(two_phased_can_parse_receipt_perfectly(
+                plan,
+                &phase1_output,
+                &phase2_output,
+            ))
+            .await?;
+
+        assert!(sandbox
+            .path("/nix/var/nix-installer/uninstall-phase1.json")
+            .is_file());
+        assert!(sandbox
+            .path("/nix/var/nix-installer/uninstall-phase2.json")
+            .is_file());
+        // Nothing is written to `/nix` itself, as releases before the state directory did
+        let nix_entries = std::fs::read_dir(sandbox.path("/nix"))?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(nix_entries, vec!["var"]);
+        Ok(())
+    }
 }
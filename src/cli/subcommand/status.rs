@@ -0,0 +1,780 @@
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{ArgAction, Parser};
+use owo_colors::OwoColorize;
+use tokio::process::Command;
+
+use crate::{
+    action::base::verify_store::{verify_installed_store, StoreVerification},
+    action::common::configure_init_service::{SocketFile, UnitScope},
+    action::macos::{
+        create_apfs_volume::{apfs_volume, QuotaUsage},
+        repair_volume_mount::nix_volume_of,
+    },
+    cli::CommandExecute,
+    distribution::{self, Distribution},
+    drift::{written_files, DriftStatus, WrittenFile},
+    host_snapshot::{NOT_APPLICABLE, UNKNOWN},
+    messages::message,
+    os::ostree::OstreeStatus,
+    plan::RECEIPT_LOCATION,
+    settings::InitSystem,
+    util::host_path,
+};
+
+/// Actions which put `/nix` on its own volume, dataset, or bind mount
+const MOUNTING_ACTIONS: &[&str] = &[
+    "create_nix_volume",
+    "create_determinate_nix_volume",
+    "create_zfs_dataset",
+    "create_store_bind_mount",
+];
+
+/// Report whether the Nix installed by `nix-installer` is still healthy
+///
+/// Reads the install receipt, then checks that the Nix daemon is loaded and active, that
+/// `/nix/store` exists (and `/nix` is mounted, if the install mounted it), that the shell
+/// profiles still have the blocks which load Nix, and that no artifacts of the other distribution
+/// (Determinate or upstream Nix) are mixed in. On an ostree system, also checks whether the OS was
+/// updated to another deployment since the install. On macOS, warns when the Nix volume uses most
+/// of its quota. Exits nonzero if any check fails.
+#[derive(Debug, Parser)]
+pub struct Status {
+    /// Emit the report as JSON
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_STATUS_JSON",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub json: bool,
+
+    /// Also verify the contents of every store path, which reads the whole store
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_STATUS_VERIFY_STORE",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub verify_store: bool,
+
+    #[clap(default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+}
+
+/// The outcome of `nix-installer status`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct StatusReport {
+    /// The version of `nix-installer` which wrote the receipt
+    pub version: Option<String>,
+    pub planner: Option<String>,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Check {
+    pub name: CheckName,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckName {
+    Receipt,
+    Daemon,
+    Store,
+    ShellProfile,
+    Distribution,
+    OstreeDeployment,
+    StoreContents,
+    VolumeQuota,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+    /// The install works, but needs attention soon, which does not make it unhealthy
+    Warning,
+    /// The check does not apply to this install, or the receipt did not say enough to run it
+    Skipped,
+}
+
+impl Check {
+    fn new(name: CheckName, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl StatusReport {
+    pub fn healthy(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Failed)
+    }
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Status {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self {
+            json,
+            verify_store,
+            receipt,
+        } = self;
+
+        let report = status(&receipt, verify_store).await;
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            let checks = report
+                .checks
+                .iter()
+                .map(|check| {
+                    let line = format!("* {}", check.detail);
+                    match check.status {
+                        CheckStatus::Ok => line.green().to_string(),
+                        CheckStatus::Failed => line.red().to_string(),
+                        CheckStatus::Warning => line.yellow().to_string(),
+                        CheckStatus::Skipped => line.dimmed().to_string(),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let planner = report.planner.as_deref().unwrap_or("unknown");
+            let version = report.version.as_deref().unwrap_or("unknown");
+            let summary = match report.healthy() {
+                true => message!(
+                    StatusHealthy,
+                    planner = planner,
+                    version = version,
+                    checks = checks
+                ),
+                false => message!(
+                    StatusUnhealthy,
+                    planner = planner,
+                    version = version,
+                    checks = checks
+                ),
+            };
+            println!("{summary}");
+        }
+
+        Ok(match report.healthy() {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        })
+    }
+}
+
+/// Check the install recorded in the receipt at `receipt`, and the contents of its store if
+/// `verify_store`
+async fn status(receipt: &Path, verify_store: bool) -> StatusReport {
+    let parsed = crate::plan::receipt_reader(receipt)
+        .map_err(|e| e.to_string())
+        .and_then(|reader| {
+            serde_json::from_reader::<_, serde_json::Value>(reader).map_err(|e| e.to_string())
+        });
+    let receipt_check = match &parsed {
+        Ok(_) => Check::new(
+            CheckName::Receipt,
+            CheckStatus::Ok,
+            format!("The receipt `{}` was read", receipt.display()),
+        ),
+        Err(e) => Check::new(
+            CheckName::Receipt,
+            CheckStatus::Failed,
+            format!("The receipt `{}` could not be read: {e}", receipt.display()),
+        ),
+    };
+    let receipt = parsed.ok();
+    let receipt = receipt.as_ref();
+
+    let mut checks = vec![
+        receipt_check,
+        check_daemon(receipt).await,
+        check_store(receipt),
+        check_shell_profile(receipt).await,
+        check_distribution(receipt),
+        check_ostree_deployment(receipt).await,
+    ];
+    if let Some(volume) = receipt.and_then(nix_volume_of) {
+        checks.push(check_volume_quota(&volume.label).await);
+    }
+    if verify_store {
+        checks.push(check_store_contents(receipt).await);
+    }
+
+    StatusReport {
+        version: receipt
+            .and_then(|receipt| receipt["version"].as_str())
+            .map(ToString::to_string),
+        planner: receipt
+            .and_then(|receipt| receipt["planner"]["planner"].as_str())
+            .map(ToString::to_string),
+        checks,
+    }
+}
+
+/// Check how much of its quota the Nix volume labelled `label` uses, if it has one
+async fn check_volume_quota(label: &str) -> Check {
+    let volume = match apfs_volume(label).await {
+        Ok(Some(volume)) => volume,
+        Ok(None) => {
+            return Check::new(
+                CheckName::VolumeQuota,
+                CheckStatus::Failed,
+                format!("The Nix volume `{label}` was not found"),
+            )
+        },
+        Err(e) => {
+            return Check::new(
+                CheckName::VolumeQuota,
+                CheckStatus::Skipped,
+                format!("The Nix volume `{label}` could not be listed: {e}"),
+            )
+        },
+    };
+    let Some(usage) = QuotaUsage::of(&volume) else {
+        return Check::new(
+            CheckName::VolumeQuota,
+            CheckStatus::Skipped,
+            format!("The Nix volume `{label}` has no quota"),
+        );
+    };
+    let detail = format!(
+        "The Nix volume `{label}` uses {} of its {} quota ({}%)",
+        usage.in_use,
+        usage.quota,
+        usage.percent()
+    );
+    match usage.nearly_full() {
+        true => Check::new(CheckName::VolumeQuota, CheckStatus::Warning, detail),
+        false => Check::new(CheckName::VolumeQuota, CheckStatus::Ok, detail),
+    }
+}
+
+/// The completed actions named `action_name` in `receipt`, however deeply they are nested
+pub(crate) fn completed_actions<'a>(
+    receipt: &'a serde_json::Value,
+    action_name: &str,
+) -> Vec<&'a serde_json::Value> {
+    fn collect<'a>(
+        value: &'a serde_json::Value,
+        action_name: &str,
+        found: &mut Vec<&'a serde_json::Value>,
+    ) {
+        match value {
+            serde_json::Value::Object(map) => {
+                if let Some(action) = map.get("action") {
+                    if map.get("state").and_then(|state| state.as_str()) == Some("Completed")
+                        && action["action_name"].as_str() == Some(action_name)
+                    {
+                        found.push(action);
+                    }
+                }
+                for value in map.values() {
+                    collect(value, action_name, found);
+                }
+            },
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    collect(value, action_name, found);
+                }
+            },
+            _ => (),
+        }
+    }
+    let mut found = vec![];
+    collect(receipt, action_name, &mut found);
+    found
+}
+
+async fn check_daemon(receipt: Option<&serde_json::Value>) -> Check {
+    let skipped = |detail: &str| Check::new(CheckName::Daemon, CheckStatus::Skipped, detail);
+    let Some(receipt) = receipt else {
+        return skipped("The Nix daemon was not checked, the receipt could not be read");
+    };
+    let Some(service) = completed_actions(receipt, "configure_init_service")
+        .into_iter()
+        .next()
+    else {
+        return skipped("The Nix daemon was not checked, the install did not configure one");
+    };
+    let init = serde_json::from_value::<InitSystem>(service["init"].clone());
+    match init {
+        Ok(InitSystem::Systemd) => {
+            let sockets =
+                serde_json::from_value::<Vec<SocketFile>>(service["socket_files"].clone())
+                    .unwrap_or_default();
+            let mut units = sockets
+                .into_iter()
+                .map(|socket| socket.name)
+                .collect::<Vec<_>>();
+            if units.is_empty() {
+                units.extend(
+                    service["service_dest"]
+                        .as_str()
+                        .and_then(|dest| Path::new(dest).file_name())
+                        .map(|name| name.to_string_lossy().into_owned()),
+                );
+            }
+            let scope =
+                serde_json::from_value::<UnitScope>(service["scope"].clone()).unwrap_or_default();
+            let mut inactive = vec![];
+            for unit in &units {
+                if !systemd_unit_is_active(&scope, unit).await {
+                    inactive.push(format!("`{unit}`"));
+                }
+            }
+            match inactive.is_empty() {
+                true => Check::new(
+                    CheckName::Daemon,
+                    CheckStatus::Ok,
+                    format!(
+                        "The Nix daemon is active ({})",
+                        units
+                            .iter()
+                            .map(|unit| format!("`{unit}`"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                ),
+                false => Check::new(
+                    CheckName::Daemon,
+                    CheckStatus::Failed,
+                    format!(
+                        "The Nix daemon is not active, {} is not running (try `systemctl start`)",
+                        inactive.join(", ")
+                    ),
+                ),
+            }
+        },
+        Ok(InitSystem::Launchd) => {
+            let Some(name) = service["service_name"].as_str() else {
+                return skipped("The Nix daemon was not checked, the receipt does not name it");
+            };
+            let target = format!("{}/{name}", crate::action::macos::DARWIN_LAUNCHD_DOMAIN);
+            match launchd_service_is_loaded(&target).await {
+                true => Check::new(
+                    CheckName::Daemon,
+                    CheckStatus::Ok,
+                    format!("The Nix daemon is loaded (`{target}`)"),
+                ),
+                false => Check::new(
+                    CheckName::Daemon,
+                    CheckStatus::Failed,
+                    format!("The Nix daemon is not loaded, `launchctl print {target}` failed"),
+                ),
+            }
+        },
+        Ok(InitSystem::None) => {
+            skipped("The Nix daemon was not checked, the install did not use an init system")
+        },
+        Err(_) => skipped("The Nix daemon was not checked, the receipt's init system is unknown"),
+    }
+}
+
+async fn systemd_unit_is_active(scope: &UnitScope, unit: &str) -> bool {
+    let mut command = Command::new("systemctl");
+    command.args(scope.systemctl_args());
+    command.arg("is-active");
+    command.arg(unit);
+    match crate::command_output(&mut command).await {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).starts_with("active"),
+        Err(e) => {
+            tracing::debug!(%unit, "Running `systemctl is-active`: {e}");
+            false
+        },
+    }
+}
+
+async fn launchd_service_is_loaded(target: &str) -> bool {
+    let mut command = Command::new("launchctl");
+    command.process_group(0);
+    command.arg("print");
+    command.arg(target);
+    command.stdin(std::process::Stdio::null());
+    match crate::command_output(&mut command).await {
+        Ok(output) => output.status.success(),
+        Err(e) => {
+            tracing::debug!(%target, "Running `launchctl print`: {e}");
+            false
+        },
+    }
+}
+
+async fn check_store_contents(receipt: Option<&serde_json::Value>) -> Check {
+    if !host_path("/nix/store").is_dir() {
+        return Check::new(
+            CheckName::StoreContents,
+            CheckStatus::Skipped,
+            "The contents of `/nix/store` were not verified, it does not exist",
+        );
+    }
+    match verify_installed_store(receipt).await {
+        StoreVerification::TimedOut(budget) => Check::new(
+            CheckName::StoreContents,
+            CheckStatus::Skipped,
+            format!(
+                "The contents of `/nix/store` were not verified, it took longer than {} minutes",
+                budget.as_secs() / 60
+            ),
+        ),
+        verification => match verification.into_result() {
+            Ok(()) => Check::new(
+                CheckName::StoreContents,
+                CheckStatus::Ok,
+                "The contents of every store path match their hash",
+            ),
+            Err(e) => Check::new(CheckName::StoreContents, CheckStatus::Failed, e.to_string()),
+        },
+    }
+}
+
+fn check_store(receipt: Option<&serde_json::Value>) -> Check {
+    let store = host_path("/nix/store");
+    if !store.is_dir() {
+        return Check::new(
+            CheckName::Store,
+            CheckStatus::Failed,
+            "`/nix/store` does not exist",
+        );
+    }
+
+    let mounting = receipt.and_then(|receipt| {
+        MOUNTING_ACTIONS
+            .iter()
+            .find_map(|name| completed_actions(receipt, name).into_iter().next())
+    });
+    let Some(mounting) = mounting else {
+        return Check::new(CheckName::Store, CheckStatus::Ok, "`/nix/store` exists");
+    };
+    let mounted = match mounting["store_root"].as_str() {
+        // A bind mount is on the same device as its source, so compare with that instead
+        Some(store_root) => {
+            crate::action::linux::create_store_bind_mount::is_bind_mounted(Path::new(store_root))
+                .unwrap_or(false)
+        },
+        None => match (
+            std::fs::metadata(host_path("/nix")),
+            std::fs::metadata(host_path("/")),
+        ) {
+            (Ok(nix), Ok(root)) => nix.dev() != root.dev(),
+            _ => false,
+        },
+    };
+    match mounted {
+        true => Check::new(
+            CheckName::Store,
+            CheckStatus::Ok,
+            "`/nix/store` exists, and `/nix` is mounted",
+        ),
+        false => Check::new(
+            CheckName::Store,
+            CheckStatus::Failed,
+            "`/nix/store` exists, but `/nix` is not mounted",
+        ),
+    }
+}
+
+async fn check_shell_profile(receipt: Option<&serde_json::Value>) -> Check {
+    let skipped = |detail: &str| Check::new(CheckName::ShellProfile, CheckStatus::Skipped, detail);
+    let Some(receipt) = receipt else {
+        return skipped("The shell profiles were not checked, the receipt could not be read");
+    };
+    let mut hooks = vec![];
+    for profile in completed_actions(receipt, "configure_shell_profile") {
+        for inserted in completed_actions(profile, "create_or_insert_into_file") {
+            // Receipts from before hashes were recorded only have the block inserted
+            let recorded = written_files(inserted).into_iter().next().or_else(|| {
+                let path = inserted["path"].as_str()?;
+                WrittenFile::fenced(path, inserted["buf"].as_str()?)
+            });
+            hooks.extend(recorded);
+        }
+    }
+    if hooks.is_empty() {
+        return skipped("The shell profiles were not checked, the install did not modify any");
+    }
+
+    let mut removed = vec![];
+    for hook in &hooks {
+        let status = match hook.check().await {
+            Ok(drift) => drift.status,
+            Err(e) => {
+                tracing::debug!("Reading `{}`: {e}", hook.path.display());
+                DriftStatus::Missing
+            },
+        };
+        if matches!(status, DriftStatus::BlockRemoved | DriftStatus::Missing) {
+            removed.push(format!("`{}`", hook.path.display()));
+        }
+    }
+    match removed.is_empty() {
+        true => Check::new(
+            CheckName::ShellProfile,
+            CheckStatus::Ok,
+            format!("The {} shell profile hook(s) are in place", hooks.len()),
+        ),
+        false => Check::new(
+            CheckName::ShellProfile,
+            CheckStatus::Failed,
+            format!(
+                "The shell profile hooks are missing from {} (try `nix-installer repair`)",
+                removed.join(", ")
+            ),
+        ),
+    }
+}
+
+fn check_distribution(receipt: Option<&serde_json::Value>) -> Check {
+    let skipped = |detail: &str| Check::new(CheckName::Distribution, CheckStatus::Skipped, detail);
+    let Some(receipt) = receipt else {
+        return skipped("The distribution was not checked, the receipt could not be read");
+    };
+    let Some(recorded) = Distribution::of_receipt(receipt) else {
+        return skipped("The distribution was not checked, the receipt does not record it");
+    };
+    let service = completed_actions(receipt, "configure_init_service")
+        .into_iter()
+        .next();
+    let init =
+        match service {
+            Some(service) => {
+                // The units of a user manager are in its user's home, which both distributions share
+                if serde_json::from_value::<UnitScope>(service["scope"].clone())
+                    .is_ok_and(|scope| !scope.is_system())
+                {
+                    return skipped(
+                        "The distribution was not checked, the daemon runs in a user manager",
+                    );
+                }
+                match serde_json::from_value::<InitSystem>(service["init"].clone()) {
+                    Ok(init) => init,
+                    Err(_) => return skipped(
+                        "The distribution was not checked, the receipt's init system is unknown",
+                    ),
+                }
+            },
+            None => InitSystem::None,
+        };
+
+    let found = distribution::inconsistencies(&distribution::artifacts(init), recorded, init);
+    match found.is_empty() {
+        true => Check::new(
+            CheckName::Distribution,
+            CheckStatus::Ok,
+            format!("The install is consistently {recorded}"),
+        ),
+        false => Check::new(
+            CheckName::Distribution,
+            CheckStatus::Failed,
+            format!(
+                "The install is {recorded}, but {} (try `nix-installer repair distribution`)",
+                found
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ),
+    }
+}
+
+/// Whether the ostree deployment Nix was installed on is still the booted one
+async fn check_ostree_deployment(receipt: Option<&serde_json::Value>) -> Check {
+    let skipped =
+        |detail: &str| Check::new(CheckName::OstreeDeployment, CheckStatus::Skipped, detail);
+    let Some(receipt) = receipt else {
+        return skipped("The OS deployment was not checked, the receipt could not be read");
+    };
+    if receipt["planner"]["planner"].as_str() != Some("ostree") {
+        return skipped("The OS deployment was not checked, Nix was not installed on ostree");
+    }
+    let Some(recorded) = receipt["host_snapshot"]["ostree_deployment"]
+        .as_str()
+        .filter(|recorded| ![UNKNOWN, NOT_APPLICABLE].contains(recorded))
+    else {
+        return skipped("The OS deployment was not checked, the receipt does not record it");
+    };
+    let Some(booted) = OstreeStatus::query()
+        .await
+        .and_then(|status| status.booted().map(|booted| booted.checksum.clone()))
+    else {
+        return skipped("The OS deployment was not checked, `ostree admin status` failed");
+    };
+
+    match booted == recorded {
+        true => Check::new(
+            CheckName::OstreeDeployment,
+            CheckStatus::Ok,
+            format!("The booted deployment `{booted}` is the one Nix was installed on"),
+        ),
+        false => Check::new(
+            CheckName::OstreeDeployment,
+            CheckStatus::Failed,
+            format!(
+                "The OS was updated since Nix was installed on deployment `{recorded}`, the booted deployment is `{booted}` (try `nix-installer repair hooks`)"
+            ),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{completed_actions, status, CheckName, CheckStatus};
+    use crate::test_harness::{FakeCommand, Invocation, SandboxContext};
+
+    const LINUX: &str = include_str!("../../../tests/fixtures/linux/linux.json");
+    const OSTREE_STATUS: &str = include_str!("../../../tests/fixtures/ostree/normal.json");
+
+    #[tokio::test]
+    async fn reports_an_inactive_daemon_and_removed_hooks() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let receipt_path = sandbox.path("/nix/receipt.json");
+        std::fs::create_dir_all(sandbox.path("/nix/store"))?;
+        std::fs::write(&receipt_path, LINUX)?;
+        let receipt: serde_json::Value = serde_json::from_str(LINUX)?;
+        for inserted in completed_actions(&receipt, "create_or_insert_into_file") {
+            let path = sandbox.path(inserted["path"].as_str().unwrap());
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, inserted["buf"].as_str().unwrap())?;
+        }
+        std::fs::create_dir_all(sandbox.path("/etc/systemd/system"))?;
+        for unit in ["nix-daemon.service", "nix-daemon.socket"] {
+            std::os::unix::fs::symlink(
+                format!("/nix/var/nix/profiles/default/lib/systemd/system/{unit}"),
+                sandbox.path(format!("/etc/systemd/system/{unit}")),
+            )?;
+        }
+
+        sandbox.fake("systemctl", FakeCommand::success().stdout("active\n"));
+        let report = sandbox.scope(status(&receipt_path, false)).await;
+        assert!(report.healthy(), "{report:#?}");
+        assert_eq!(report.planner.as_deref(), Some("linux"));
+        assert_eq!(report.version.as_deref(), Some("0.32.2"));
+        assert!(sandbox
+            .invocations_of("systemctl")
+            .contains(&Invocation::new(
+                "systemctl",
+                ["is-active", "nix-daemon.socket"]
+            )));
+
+        sandbox.fake("systemctl", FakeCommand::failure(3).stdout("inactive\n"));
+        std::fs::write(sandbox.path("/etc/zshrc"), "alias ll='ls -l'\n")?;
+        let report = sandbox.scope(status(&receipt_path, false)).await;
+        assert!(!report.healthy());
+        let statuses = report
+            .checks
+            .iter()
+            .map(|check| (check.name, check.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                (CheckName::Receipt, CheckStatus::Ok),
+                (CheckName::Daemon, CheckStatus::Failed),
+                (CheckName::Store, CheckStatus::Ok),
+                (CheckName::ShellProfile, CheckStatus::Failed),
+                (CheckName::Distribution, CheckStatus::Ok),
+                (CheckName::OstreeDeployment, CheckStatus::Skipped),
+            ]
+        );
+        assert!(report.checks[3].detail.contains("`/etc/zshrc`"));
+
+        // A `determinate-nixd` left behind by an interrupted migration
+        std::fs::create_dir_all(sandbox.path("/usr/local/bin"))?;
+        std::fs::write(sandbox.path("/usr/local/bin/determinate-nixd"), "")?;
+        let report = sandbox.scope(status(&receipt_path, false)).await;
+        assert_eq!(report.checks[4].status, CheckStatus::Failed);
+        assert!(report.checks[4].detail.contains(
+            "`/usr/local/bin/determinate-nixd` is the `determinate-nixd` binary of Determinate Nix"
+        ));
+
+        // Nothing but the store can be checked without a receipt
+        let report = sandbox
+            .scope(status(&sandbox.path("/nix/missing.json"), false))
+            .await;
+        assert_eq!(report.checks[0].status, CheckStatus::Failed);
+        assert_eq!(report.checks[1].status, CheckStatus::Skipped);
+        assert_eq!(report.checks[3].status, CheckStatus::Skipped);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn an_os_update_is_reported_on_ostree() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let receipt_path = sandbox.path("/nix/receipt.json");
+        std::fs::create_dir_all(sandbox.path("/nix"))?;
+        std::fs::create_dir_all(sandbox.path("/run"))?;
+        std::fs::write(sandbox.path("/run/ostree-booted"), "")?;
+        sandbox.fake("ostree", FakeCommand::success().stdout(OSTREE_STATUS));
+        let booted = "3e9a6a0a6b2f5c7d2e0f7f7a6d1b5a0c9e8f4d3c2b1a09f8e7d6c5b4a3928170";
+
+        let mut receipt: serde_json::Value = serde_json::from_str(LINUX)?;
+        receipt["planner"]["planner"] = "ostree".into();
+        receipt["host_snapshot"] = serde_json::json!({ "ostree_deployment": booted });
+        std::fs::write(&receipt_path, serde_json::to_string(&receipt)?)?;
+        let report = sandbox.scope(status(&receipt_path, false)).await;
+        assert_eq!(report.checks[5].name, CheckName::OstreeDeployment);
+        assert_eq!(report.checks[5].status, CheckStatus::Ok);
+
+        receipt["host_snapshot"]["ostree_deployment"] = "b5d1e0c4".into();
+        std::fs::write(&receipt_path, serde_json::to_string(&receipt)?)?;
+        let report = sandbox.scope(status(&receipt_path, false)).await;
+        assert_eq!(report.checks[5].status, CheckStatus::Failed);
+        assert!(report.checks[5]
+            .detail
+            .contains("nix-installer repair hooks"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifying_the_store_reports_modified_paths() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let receipt_path = sandbox.path("/nix/receipt.json");
+        std::fs::create_dir_all(sandbox.path("/nix/store"))?;
+        let mut receipt: serde_json::Value = serde_json::from_str(LINUX)?;
+        receipt["planner"]["settings"]["nix_package_url"] =
+            "https://releases.nixos.org/nix/nix-2.3.16/nix-2.3.16-x86_64-linux.tar.xz".into();
+        std::fs::write(&receipt_path, serde_json::to_string(&receipt)?)?;
+        sandbox.fake("systemctl", FakeCommand::success().stdout("active\n"));
+        sandbox.fake(
+            "nix-store",
+            FakeCommand::failure(1).stderr(
+                "path '/nix/store/aaaa-hello.drv' was modified! expected hash 'sha256:1', got 'sha256:2'\n",
+            ),
+        );
+
+        let report = sandbox.scope(status(&receipt_path, false)).await;
+        assert!(report
+            .checks
+            .iter()
+            .all(|check| check.name != CheckName::StoreContents));
+
+        // Nix 2.3 has no `nix store verify`
+        let report = sandbox.scope(status(&receipt_path, true)).await;
+        let contents = report.checks.last().unwrap();
+        assert_eq!(contents.name, CheckName::StoreContents);
+        assert_eq!(contents.status, CheckStatus::Failed);
+        assert!(contents.detail.contains("`/nix/store/aaaa-hello.drv`"));
+        assert_eq!(
+            sandbox.invocations_of("nix-store")[0].args,
+            ["--verify", "--check-contents"]
+        );
+        Ok(())
+    }
+}
@@ -1,21 +1,41 @@
 use std::{
-    ffi::CString,
     path::{Path, PathBuf},
     process::ExitCode,
 };
 
 use crate::{
-    cli::{ensure_root, interaction::PromptChoice, signal_channel},
+    action::{
+        base::{remove_user_data, RemoveUserData, UserData},
+        common::{CreateNixTree, ProvisionNix},
+        ActionState, StatefulAction,
+    },
+    cli::{
+        control::{self, Control},
+        ensure_root,
+        interaction::PromptChoice,
+        run_log::RunLog,
+        signal_channel,
+    },
     error::HasExpectedErrors,
+    event_log::{EventLog, Operation},
+    homed,
+    messages::message,
     plan::{current_version, RECEIPT_LOCATION},
+    report::fan_out,
+    warning::{Warning, WarningKind},
     InstallPlan, NixInstallerError,
 };
 use clap::{ArgAction, Parser};
 use color_eyre::eyre::{eyre, WrapErr};
 use owo_colors::OwoColorize;
-use rand::Rng;
 
-use crate::cli::{interaction, CommandExecute};
+use crate::cli::CommandExecute;
+
+use super::{
+    nix_in_use::{busy_ancestor, leave_nix_directory, reexec_outside_nix, ReexecCopy},
+    scheduled_uninstall,
+    split_receipt::split_plan,
+};
 
 /// Uninstall a previously `nix-installer` installed Nix
 #[derive(Debug, Parser)]
@@ -38,6 +58,87 @@ pub struct Uninstall {
     )]
     pub explain: bool,
 
+    /// Revert everything except the Nix store now, and remove the Nix store early during the next boot
+    ///
+    /// For hosts where the Nix store cannot be detached while in use.
+    #[clap(
+        long,
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        conflicts_with = "cancel_scheduled_uninstall"
+    )]
+    pub schedule_at_reboot: bool,
+
+    /// Revert everything except the Nix store, keeping its contents for a later reinstall
+    ///
+    /// The rest of the uninstall is recorded in `uninstall-phase2.json` in the state directory.
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_KEEP_STORE",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        conflicts_with_all = ["schedule_at_reboot", "cancel_scheduled_uninstall", "run_scheduled_uninstall", "impact_report"]
+    )]
+    pub keep_store: bool,
+
+    /// Remove a boot task installed by `--schedule-at-reboot`, leaving the Nix store in place
+    #[clap(long, action(ArgAction::SetTrue), default_value = "false")]
+    pub cancel_scheduled_uninstall: bool,
+
+    /// Used by the boot task installed by `--schedule-at-reboot`
+    #[clap(
+        long,
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        hide = true,
+        conflicts_with_all = ["schedule_at_reboot", "cancel_scheduled_uninstall"]
+    )]
+    pub run_scheduled_uninstall: bool,
+
+    /// A path to listen on for a controller, such as a graphical frontend, which answers the prompts and follows the progress
+    ///
+    /// Its directory must be owned by root and writable by nobody else
+    #[clap(long, env = "NIX_INSTALLER_CONTROL_SOCKET", global = true)]
+    pub control_socket: Option<PathBuf>,
+
+    /// A path to append a line of JSON to for each step of the uninstall as it happens, for auditing
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_EVENT_LOG",
+        global = true,
+        conflicts_with = "impact_report"
+    )]
+    pub event_log: Option<PathBuf>,
+
+    /// Report what uninstalling would remove or stop on this host, then exit without uninstalling
+    #[clap(
+        long,
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        conflicts_with_all = ["schedule_at_reboot", "cancel_scheduled_uninstall", "run_scheduled_uninstall"]
+    )]
+    pub impact_report: bool,
+
+    /// Print the impact report as JSON
+    #[clap(
+        long,
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        requires = "impact_report"
+    )]
+    pub json: bool,
+
+    /// Also remove the Nix data of users which uninstalling leaves behind, like `~/.nix-profile`
+    /// and `~/.nix-defexpr`, which can't be recovered
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_PURGE_USER_DATA",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        conflicts_with_all = ["cancel_scheduled_uninstall", "run_scheduled_uninstall", "impact_report"]
+    )]
+    pub purge_user_data: bool,
+
     #[clap(default_value = RECEIPT_LOCATION)]
     pub receipt: PathBuf,
 }
@@ -50,167 +151,754 @@ impl CommandExecute for Uninstall {
             no_confirm,
             receipt,
             explain,
+            schedule_at_reboot,
+            keep_store,
+            cancel_scheduled_uninstall,
+            run_scheduled_uninstall,
+            control_socket,
+            event_log,
+            impact_report,
+            json,
+            purge_user_data,
         } = self;
+        // Before leaving the Nix directory, which a relative path is resolved against
+        let event_log = event_log.map(EventLog::new);
+
+        // Only reads, so it does not need root, or to leave the Nix directory
+        if impact_report {
+            return report_impact(&receipt, json).await;
+        }
 
         ensure_root()?;
 
-        if let Ok(current_dir) = std::env::current_dir() {
-            let mut components = current_dir.components();
-            let should_be_root = components.next();
-            let maybe_nix = components.next();
-            if should_be_root == Some(std::path::Component::RootDir)
-                && maybe_nix == Some(std::path::Component::Normal(std::ffi::OsStr::new("nix")))
-            {
-                tracing::debug!("Changing current directory to be outside of `/nix`");
-                std::env::set_current_dir("/").wrap_err("Uninstall process was run from `/nix` folder, but could not change directory away from `/nix`, please change the current directory and try again.")?;
+        if cancel_scheduled_uninstall {
+            if scheduled_uninstall::cancel().await? {
+                println!(
+                    "\
+                    {success}\n\
+                    {detail}\n\
+                    ",
+                    success = message!(UninstallScheduledCancelled).green().bold(),
+                    detail = message!(UninstallScheduledCancelledDetail),
+                );
+            } else {
+                println!("{}", message!(UninstallNothingScheduled));
             }
+            return Ok(ExitCode::SUCCESS);
         }
 
-        // During install, `nix-installer` will store a copy of itself in `/nix/nix-installer`
-        // If the user opted to run that particular copy of `nix-installer` to do this uninstall,
-        // well, we have a problem, since the binary would delete itself.
-        // Instead, detect if we're in that location, if so, move the binary and `execv` it.
-        if let Ok(current_exe) = std::env::current_exe() {
-            if current_exe.as_path() == Path::new("/nix/nix-installer") {
-                tracing::debug!(
-                    "Detected uninstall from `/nix/nix-installer`, moving executable and re-executing"
-                );
-                let temp = std::env::temp_dir();
-                let random_trailer: String = {
-                    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                                        abcdefghijklmnopqrstuvwxyz\
-                                            0123456789";
-                    const PASSWORD_LEN: usize = 16;
-                    let mut rng = rand::thread_rng();
-
-                    (0..PASSWORD_LEN)
-                        .map(|_| {
-                            let idx = rng.gen_range(0..CHARSET.len());
-                            CHARSET[idx] as char
-                        })
-                        .collect()
-                };
-                let temp_exe = temp.join(format!("nix-installer-{random_trailer}"));
-                tokio::fs::copy(&current_exe, &temp_exe)
-                    .await
-                    .wrap_err("Copying nix-installer to tempdir")?;
-                let args = std::env::args();
-                let mut arg_vec_cstring = vec![];
-                for arg in args {
-                    arg_vec_cstring.push(CString::new(arg).wrap_err("Making arg into C string")?);
-                }
-                let temp_exe_cstring = CString::new(temp_exe.to_string_lossy().into_owned())
-                    .wrap_err("Making C string of executable path")?;
+        if run_scheduled_uninstall {
+            return scheduled_uninstall::run(&receipt).await;
+        }
 
-                tracing::trace!("Execv'ing `{temp_exe_cstring:?} {arg_vec_cstring:?}`");
-                nix::unistd::execv(&temp_exe_cstring, &arg_vec_cstring)
-                    .wrap_err("Executing copied `nix-installer`")?;
+        // Removed once the uninstall finishes, if this is a copy made by `reexec_outside_nix`
+        let _reexec_copy = ReexecCopy::current();
+
+        leave_nix_directory().wrap_err_with(|| message!(UninstallRunFromNixDirectory))?;
+
+        // A store kept busy is exactly what `--schedule-at-reboot` defers removing, and `--keep-store` keeps
+        if !schedule_at_reboot && !keep_store {
+            if let Some(ancestor) = busy_ancestor().await {
+                eprintln!(
+                    "{}",
+                    message!(
+                        UninstallAncestorInNixDirectory,
+                        process = ancestor.name,
+                        pid = ancestor.pid,
+                        directory = ancestor.cwd.display(),
+                    )
+                    .red()
+                );
+                return Ok(ExitCode::FAILURE);
             }
         }
 
-        let install_receipt_string = tokio::fs::read_to_string(receipt)
-            .await
-            .wrap_err("Reading receipt")?;
+        // During install, `nix-installer` will store a copy of itself in `/nix/nix-installer`
+        // If the user opted to run that particular copy of `nix-installer` to do this uninstall,
+        // well, we have a problem, since the binary would delete itself.
+        reexec_outside_nix().await?;
 
-        let mut plan: InstallPlan = match serde_json::from_str(&install_receipt_string) {
-            Ok(plan) => plan,
-            Err(plan_err) => {
-                #[derive(serde::Deserialize)]
-                struct MinimalPlan {
-                    version: semver::Version,
-                }
-                let minimal_plan: Result<MinimalPlan, _> =
-                    serde_json::from_str(&install_receipt_string);
-                match minimal_plan {
-                    Ok(minimal_plan) => {
-                        return Err(plan_err).wrap_err_with(|| {
-                            let plan_version = minimal_plan.version;
-                            let current_version = current_version().map(|v| v.to_string()).unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_string());
-                            format!(
-                            "\
-                            Unable to parse plan, this plan was created by `nix-installer` version `{plan_version}`, this is `nix-installer` version `{current_version}`\n\
-                            To uninstall, either run  `/nix/nix-installer uninstall` or `curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix/tag/v{plan_version} | sh -s -- uninstall`\
-                            ").red().to_string()
-                        });
-                    },
-                    Err(_minimal_plan_err) => return Err(plan_err)?,
-                }
-            },
+        let control = match &control_socket {
+            Some(control_socket) => Some(Control::listen(control_socket).await?),
+            None => None,
         };
-
-        if let Err(e) = plan.check_compatible() {
-            let version = plan.version;
-            eprintln!(
-                "{}",
-                format!("\
-                    {e}\n\
-                    \n\
-                    Found existing plan in `{RECEIPT_LOCATION}` which was created by a version incompatible `nix-installer`.\n\
-                    \n
-                    To uninstall, either run `/nix/nix-installer uninstall` or `curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix/tag/v${version} | sh -s -- uninstall`\n\
-                    \n\
-                ").red()
-            );
-            return Ok(ExitCode::FAILURE);
+        let result = run(
+            &receipt,
+            no_confirm,
+            explain,
+            schedule_at_reboot,
+            keep_store,
+            purge_user_data,
+            control.as_ref(),
+            event_log,
+        )
+        .await;
+        if let Some(control) = &control {
+            control.finish(&result).await;
         }
+        result
+    }
+}
 
-        if let Err(err) = plan.pre_uninstall_check().await {
+/// Print what uninstalling the plan in `receipt` would do, without doing it
+async fn report_impact(receipt: &Path, json: bool) -> eyre::Result<ExitCode> {
+    let plan = read_plan(receipt)?;
+    let impact = match plan.uninstall_impact_of(receipt).await {
+        Ok(impact) => impact,
+        Err(err) => {
             if let Some(expected) = err.expected() {
                 eprintln!("{}", expected.red());
                 return Ok(ExitCode::FAILURE);
             }
-            Err(err)?
-        }
-
-        if !no_confirm {
-            let mut currently_explaining = explain;
-            loop {
-                match interaction::prompt(
-                    plan.describe_uninstall(currently_explaining)
-                        .await
-                        .map_err(|e| eyre!(e))?,
-                    PromptChoice::Yes,
-                    currently_explaining,
-                )
-                .await?
-                {
-                    PromptChoice::Yes => break,
-                    PromptChoice::Explain => currently_explaining = true,
-                    PromptChoice::No => {
-                        interaction::clean_exit_with_message(
-                            "Okay, not continuing with the uninstallation. Bye!",
+            return Err(err)?;
+        },
+    };
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&impact)?),
+        false => print!("{impact}"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Read the plan in `receipt`, explaining a receipt from an incompatible version
+fn read_plan(receipt: &Path) -> eyre::Result<InstallPlan> {
+    let plan = match crate::plan::read_receipt(receipt) {
+        Ok(plan) => plan,
+        Err(plan_err) => {
+            #[derive(serde::Deserialize)]
+            struct MinimalPlan {
+                version: semver::Version,
+            }
+            let minimal_plan = crate::plan::receipt_reader(receipt)
+                .ok()
+                .and_then(|receipt| serde_json::from_reader::<_, MinimalPlan>(receipt).ok());
+            match minimal_plan {
+                Some(minimal_plan) => {
+                    return Err(plan_err).wrap_err_with(|| {
+                        let plan_version = minimal_plan.version;
+                        let current_version = current_version()
+                            .map(|v| v.to_string())
+                            .unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_string());
+                        message!(
+                            UninstallReceiptVersionMismatch,
+                            plan_version = plan_version,
+                            current_version = current_version
                         )
-                        .await
-                    },
-                }
+                        .red()
+                        .to_string()
+                    });
+                },
+                None => return Err(plan_err)?,
             }
+        },
+    };
+    Ok(plan)
+}
+
+/// Uninstall the plan in `receipt`, once it is known that this process will not hold the Nix store busy
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    receipt: &Path,
+    no_confirm: bool,
+    explain: bool,
+    schedule_at_reboot: bool,
+    keep_store: bool,
+    purge_user_data: bool,
+    control: Option<&Control>,
+    mut event_log: Option<EventLog>,
+) -> eyre::Result<ExitCode> {
+    let plan = read_plan(receipt)?;
+    RunLog::global().record("uninstall", Some(crate::state_dir::of_plan(&plan)));
+
+    if let Err(e) = plan.check_compatible() {
+        eprintln!(
+            "{}",
+            message!(
+                UninstallExistingPlanIncompatible,
+                error = e,
+                receipt = RECEIPT_LOCATION,
+                plan_version = plan.version,
+            )
+            .red()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+
+    if let Err(err) = plan.pre_uninstall_check().await {
+        if let Some(expected) = err.expected() {
+            eprintln!("{}", expected.red());
+            return Ok(ExitCode::FAILURE);
         }
+        Err(err)?
+    }
 
-        let (_tx, rx) = signal_channel().await?;
+    // Found before anything is reverted, and only removed with `--purge-user-data`
+    let nix_kept = keep_store
+        || plan.actions.iter().any(|action| {
+            action
+                .downcast_ref::<CreateNixTree>()
+                .is_some_and(CreateNixTree::shared_store)
+        });
+    let leftovers = leftover_user_data(nix_kept).await;
 
-        let res = plan.uninstall(rx).await;
-        match res {
-            Err(err @ NixInstallerError::ActionRevert(_)) => {
-                tracing::error!("Uninstallation complete, some errors encountered");
-                return Err(err)?;
-            },
-            Err(err) => {
-                if let Some(expected) = err.expected() {
-                    println!("{}", expected.red());
-                    return Ok(ExitCode::FAILURE);
-                }
-                return Err(err)?;
-            },
-            _ => (),
+    // When scheduling or keeping the store, only the phase 1 plan is reverted now
+    let (mut plan, deferred, kept) = if schedule_at_reboot || keep_store {
+        let (phase1_plan, phase2_plan) = split_plan(plan)?;
+        if phase2_plan.actions.is_empty() {
+            let message = match schedule_at_reboot {
+                true => message!(UninstallNothingToDefer),
+                false => message!(UninstallNothingToKeep),
+            };
+            eprintln!("{}", message.red());
+            return Ok(ExitCode::FAILURE);
+        }
+        match schedule_at_reboot {
+            true => (phase1_plan, Some(phase2_plan), None),
+            false => (phase1_plan, None, Some(phase2_plan)),
+        }
+    } else {
+        (plan, None, None)
+    };
+    let left_in_place = match purge_user_data {
+        true => {
+            plan.actions
+                .extend(leftovers.purged.iter().cloned().map(StatefulAction::boxed));
+            leftovers.describe_unscanned()
+        },
+        false => leftovers.describe_left_in_place(),
+    };
+
+    if !no_confirm {
+        let mut currently_explaining = explain;
+        loop {
+            let mut description = plan
+                .describe_uninstall(currently_explaining)
+                .await
+                .map_err(|e| eyre!(e))?;
+            if let Some(deferred) = &deferred {
+                description.push('\n');
+                description.push_str(&scheduled_uninstall::describe_deferred(deferred));
+            }
+            if let Some(kept) = &kept {
+                description.push('\n');
+                description.push_str(&describe_kept(kept));
+            }
+            if !left_in_place.is_empty() {
+                description.push('\n');
+                description.push_str(&left_in_place);
+            }
+            match control::prompt(
+                control,
+                description,
+                PromptChoice::Yes,
+                currently_explaining,
+            )
+            .await?
+            {
+                PromptChoice::Yes => break,
+                PromptChoice::Explain => currently_explaining = true,
+                // Not offered by `prompt`
+                PromptChoice::Customize => (),
+                PromptChoice::No => {
+                    control::exit_declined(control, message!(UninstallDeclined)).await
+                },
+            }
         }
+    }
+
+    let (tx, rx) = signal_channel().await?;
+    if let Some(control) = control {
+        control.cancel_with(tx);
+    }
+
+    if let Some(event_log) = &mut event_log {
+        event_log.started(Operation::Uninstall, &plan).await;
+    }
+    let progress = fan_out(
+        control
+            .map(Control::progress)
+            .into_iter()
+            .chain(event_log.as_mut().map(EventLog::progress))
+            .collect(),
+    );
+    // Those in the receipt were raised when installing
+    let warned_before = plan.warnings().len();
+    let res = plan.uninstall_with_progress(rx, progress).await;
+    if let Some(event_log) = &mut event_log {
+        event_log.finished(&res).await;
+    }
+    match res {
+        Err(err @ NixInstallerError::ActionRevert(_)) => {
+            tracing::error!("Uninstallation complete, some errors encountered");
+            return Err(err)?;
+        },
+        Err(err) => {
+            if let Some(expected) = err.expected() {
+                println!("{}", expected.red());
+                return Ok(ExitCode::FAILURE);
+            }
+            return Err(err)?;
+        },
+        _ => (),
+    }
+
+    if let Some(kept) = kept {
+        let kept_receipt = keep_store_receipt(&kept).await?;
+        println!(
+            "\
+                {success}\n\
+                {detail}\n\
+                {warnings}\
+                ",
+            success = message!(UninstallStoreKept).green().bold(),
+            detail = message!(UninstallStoreKeptDetail, receipt = kept_receipt.display()),
+            warnings = crate::warning::describe(&plan.warnings()[warned_before..]),
+        );
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(deferred) = deferred {
+        let scheduled = scheduled_uninstall::schedule(&deferred).await?;
+        // The phase 2 receipt describes what remains, should the scheduled uninstall be cancelled
+        crate::plan::write_receipt(&deferred, Path::new(RECEIPT_LOCATION)).await?;
 
         println!(
             "\
+                {success}\n\
+                \n\
+                {deferred_description}\n\
+                Boot task: {boot_task}\n\
+                Receipt: {receipt}\n\
+                \n\
+                {detail}\n\
+                {deferred_cleanups}\
+                {warnings}\
+                ",
+            success = message!(UninstallScheduled).green().bold(),
+            deferred_description = scheduled_uninstall::describe_deferred(&deferred),
+            boot_task = scheduled.boot_task.display(),
+            receipt = scheduled.receipt.display(),
+            detail = message!(UninstallScheduledDetail),
+            deferred_cleanups = homed::describe_deferred(&plan.warnings()[warned_before..]),
+            warnings = describe_warnings(&plan.warnings()[warned_before..]),
+        );
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Last, as it may hold the receipts needed to finish a split uninstall
+    if store_removed(&plan) {
+        remove_state_dir(&crate::state_dir::of_plan(&plan)).await?;
+    }
+
+    println!(
+        "\
             {success}\n\
+            {left_in_place}\
+            {deferred_cleanups}\
+            {warnings}\
             ",
-            success = "Nix was uninstalled successfully!".green().bold(),
+        success = message!(UninstallSuccess).green().bold(),
+        deferred_cleanups = homed::describe_deferred(&plan.warnings()[warned_before..]),
+        warnings = describe_warnings(&plan.warnings()[warned_before..]),
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The `Warnings` section of the summary, without the deferred per-user cleanups listed before it
+fn describe_warnings(warnings: &[Warning]) -> String {
+    let warnings = warnings
+        .iter()
+        .filter(|warning| warning.kind != WarningKind::InactiveHome)
+        .cloned()
+        .collect::<Vec<_>>();
+    crate::warning::describe(&warnings)
+}
+
+/// The Nix data of users which uninstalling leaves behind, see [`leftover_user_data`]
+#[derive(Debug, Default)]
+struct LeftoverUserData {
+    /// Removing the data of each user, for `--purge-user-data`
+    purged: Vec<StatefulAction<RemoveUserData>>,
+    /// Users whose homes were not looked into, as systemd-homed has not activated them
+    unscanned: Vec<String>,
+}
+
+impl LeftoverUserData {
+    /// A section listing what is left in place, empty if there is nothing
+    fn describe_left_in_place(&self) -> String {
+        if self.purged.is_empty() {
+            return self.describe_unscanned();
+        }
+        let mut buf =
+            "Will NOT be removed (pass `--purge-user-data` to remove them too):\n".to_string();
+        for action in &self.purged {
+            let paths = action
+                .inner()
+                .data()
+                .iter()
+                .map(|data| format!("`{}`", data.path.display()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            buf.push_str(&format!("* `{}`: {paths}\n", action.inner().user()));
+        }
+        buf.push('\n');
+        buf.push_str(&self.describe_unscanned());
+        buf
+    }
+
+    /// A section naming the users whose homes were not looked into, empty if there are none
+    fn describe_unscanned(&self) -> String {
+        if self.unscanned.is_empty() {
+            return String::new();
+        }
+        format!(
+            "Not looked into, as systemd-homed has not activated their homes: {}\n\n",
+            self.unscanned
+                .iter()
+                .map(|user| format!("`{user}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+/// Look for the Nix data of users which uninstalling leaves behind, without changing anything
+///
+/// Only root and regular users are looked at. Their per-user profiles and garbage collector roots in
+/// `/nix/var/nix` are only left behind when `nix_kept`.
+async fn leftover_user_data(nix_kept: bool) -> LeftoverUserData {
+    let homed_users = homed::homed_users().await;
+    let mut leftovers = LeftoverUserData::default();
+    let mut found: Vec<(String, PathBuf, Vec<UserData>)> = vec![];
+
+    for (user, home) in user_homes().await {
+        if homed_users
+            .iter()
+            .any(|homed_user| homed_user.name == user && !homed_user.is_active())
+        {
+            leftovers.unscanned.push(user);
+            continue;
+        }
+        let data = remove_user_data::IN_HOME
+            .iter()
+            .map(|(relative, kind)| UserData {
+                path: home.join(relative),
+                kind: *kind,
+            })
+            .filter(|data| {
+                crate::util::host_path(&data.path)
+                    .symlink_metadata()
+                    .is_ok()
+            })
+            .collect::<Vec<_>>();
+        found.push((user, home, data));
+    }
+
+    if nix_kept {
+        for (dir, kind) in remove_user_data::IN_NIX {
+            let Ok(entries) = std::fs::read_dir(crate::util::host_path(dir)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let user = entry.file_name().to_string_lossy().to_string();
+                let data = UserData {
+                    path: Path::new(dir).join(&user),
+                    kind: *kind,
+                };
+                match found.iter_mut().find(|(name, _, _)| *name == user) {
+                    Some((_, _, user_data)) => user_data.push(data),
+                    // Such as a system user, or one since deleted
+                    None => found.push((user, PathBuf::new(), vec![data])),
+                }
+            }
+        }
+    }
+
+    for (user, home, data) in found {
+        if data.is_empty() {
+            continue;
+        }
+        match RemoveUserData::plan(user, home, data).await {
+            Ok(action) => leftovers.purged.push(action),
+            Err(e) => tracing::debug!(%e, "Could not plan removing the Nix data of a user"),
+        }
+    }
+    leftovers
+}
+
+/// Root and the regular users, with their homes
+async fn user_homes() -> Vec<(String, PathBuf)> {
+    #[cfg(target_os = "macos")]
+    {
+        let output = crate::command_output(
+            tokio::process::Command::new("/usr/bin/dscl")
+                .process_group(0)
+                .args([".", "-list", "/Users", "UniqueID"])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await;
+        match output {
+            Ok(output) if output.status.success() => parse_dscl_unique_ids(&output.stdout)
+                .into_iter()
+                .filter_map(|user| {
+                    let home = nix::unistd::User::from_name(&user).ok()??.dir;
+                    Some((user, home))
+                })
+                .collect(),
+            Ok(output) => {
+                tracing::debug!(
+                    stderr = %String::from_utf8_lossy(&output.stderr),
+                    "Could not list the users"
+                );
+                vec![]
+            },
+            Err(e) => {
+                tracing::debug!(%e, "Could not list the users");
+                vec![]
+            },
+        }
+    }
+    #[cfg(not(target_os = "macos"))]
+    match tokio::fs::read(crate::util::host_path("/etc/passwd")).await {
+        Ok(content) => parse_passwd_homes(&content),
+        Err(e) => {
+            tracing::debug!(%e, "Could not read `/etc/passwd` for the homes of users");
+            vec![]
+        },
+    }
+}
+
+/// The first UID of regular users
+#[cfg(not(target_os = "macos"))]
+const FIRST_USER_UID: u32 = 1000;
+#[cfg(target_os = "macos")]
+const FIRST_USER_UID: u32 = 501;
+/// The UID of `nobody`, which is no regular user
+#[cfg(not(target_os = "macos"))]
+const NOBODY_UID: u32 = 65534;
+
+/// Root and the regular users in `content`, in the format of `/etc/passwd`, with their homes
+#[cfg(not(target_os = "macos"))]
+fn parse_passwd_homes(content: &[u8]) -> Vec<(String, PathBuf)> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    content
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty() && !line.starts_with(b"#"))
+        .filter_map(|line| {
+            let fields = line.split(|byte| *byte == b':').collect::<Vec<_>>();
+            let [user, _, uid, _, _, home, ..] = fields.as_slice() else {
+                return None;
+            };
+            let uid = String::from_utf8_lossy(uid).parse::<u32>().ok()?;
+            if uid != 0 && !(FIRST_USER_UID..NOBODY_UID).contains(&uid) {
+                return None;
+            }
+            if user.is_empty() || home.is_empty() {
+                return None;
+            }
+            Some((
+                String::from_utf8_lossy(user).to_string(),
+                PathBuf::from(OsStr::from_bytes(home)),
+            ))
+        })
+        .collect()
+}
+
+/// Root and the regular users in the output of `dscl . -list /Users UniqueID`
+#[cfg(target_os = "macos")]
+fn parse_dscl_unique_ids(content: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter_map(|line| {
+            let (user, uid) = line.trim().split_once(char::is_whitespace)?;
+            let uid = uid.trim().parse::<u32>().ok()?;
+            (uid == 0 || uid >= FIRST_USER_UID).then(|| user.to_string())
+        })
+        .collect()
+}
+
+/// A human readable summary of what `--keep-store` leaves in place
+fn describe_kept(phase2_plan: &InstallPlan) -> String {
+    let kept = phase2_plan
+        .actions
+        .iter()
+        .flat_map(|action| action.describe_revert())
+        .map(|desc| format!("* {}", desc.description))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "\
+        Kept for a later reinstall (`--keep-store`):\n\
+        {kept}\n\
+        "
+    )
+}
+
+/// Record the Nix store left by `--keep-store` in the phase 2 receipt of the state directory,
+/// replacing the receipt of the install which has now been uninstalled
+///
+/// The phase 2 receipt marks the store as this installer's for a reinstall, and reverting it
+/// finishes the uninstall.
+async fn keep_store_receipt(phase2_plan: &InstallPlan) -> eyre::Result<PathBuf> {
+    let phase2_receipt =
+        crate::state_dir::of_plan(phase2_plan).join(crate::state_dir::PHASE2_RECEIPT);
+    crate::plan::write_receipt(phase2_plan, &phase2_receipt).await?;
+    crate::util::remove_file(
+        &crate::util::host_path(RECEIPT_LOCATION),
+        crate::util::OnMissing::Ignore,
+    )
+    .await
+    .wrap_err_with(|| format!("Removing the receipt `{RECEIPT_LOCATION}`"))?;
+    Ok(phase2_receipt)
+}
+
+/// If uninstalling `plan` removed the Nix store, rather than leaving it for a phase 2 receipt
+fn store_removed(plan: &InstallPlan) -> bool {
+    plan.actions.iter().any(|action| {
+        // A phase 2 receipt split without parsing it holds the `CreateNixTree` on its own
+        let removes_store = action.downcast_ref::<ProvisionNix>().is_some()
+            || action.downcast_ref::<CreateNixTree>().is_some();
+        removes_store && action.state == ActionState::Uncompleted
+    })
+}
+
+async fn remove_state_dir(state_dir: &Path) -> eyre::Result<()> {
+    let state_dir = crate::util::host_path(state_dir);
+    match tokio::fs::remove_dir_all(&state_dir).await {
+        Ok(()) => {
+            tracing::debug!("Removed the state directory `{}`", state_dir.display());
+            Ok(())
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e)
+            .wrap_err_with(|| format!("Removing the state directory `{}`", state_dir.display())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{keep_store_receipt, leftover_user_data};
+    use crate::{
+        action::{ActionState, StatefulAction},
+        cli::subcommand::split_receipt::split_plan,
+        plan::RECEIPT_LOCATION,
+        test_harness::SandboxContext,
+        InstallPlan,
+    };
+
+    const LINUX: &str = include_str!("../../../tests/fixtures/linux/linux.json");
+
+    #[tokio::test]
+    async fn keeping_the_store_leaves_a_receipt_for_the_rest() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/nix"))?;
+        std::fs::write(sandbox.path(RECEIPT_LOCATION), LINUX)?;
+        let (phase1_plan, phase2_plan) = split_plan(serde_json::from_str::<InstallPlan>(LINUX)?)?;
+        let reverted = phase1_plan
+            .actions
+            .iter()
+            .filter(|action| action.state != ActionState::Skipped)
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        for tag in [
+            "create_users_and_group",
+            "configure_nix",
+            "create_upstream_init_service",
+        ] {
+            assert!(reverted.contains(&tag), "`{tag}` is not reverted");
+        }
+        assert!(!reverted.contains(&"provision_nix"));
+
+        let kept_receipt = sandbox.scope(keep_store_receipt(&phase2_plan)).await?;
+        assert_eq!(
+            kept_receipt,
+            std::path::Path::new("/nix/var/nix-installer/uninstall-phase2.json")
         );
+        assert!(!sandbox.path(RECEIPT_LOCATION).exists());
+        let kept: InstallPlan =
+            serde_json::from_str(&std::fs::read_to_string(sandbox.path(&kept_receipt))?)?;
+        let kept_tags = kept
+            .actions
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        assert!(kept_tags.contains(&"provision_nix"));
+        // Left to be reverted by a later uninstall
+        assert!(kept
+            .actions
+            .iter()
+            .all(|action| action.state == ActionState::Completed));
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[tokio::test]
+    async fn user_data_is_only_removed_when_purged() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/etc"))?;
+        std::fs::write(
+            sandbox.path("/etc/passwd"),
+            "\
+                root:x:0:0:root:/root:/bin/bash\n\
+                daemon:x:1:1:daemon:/usr/sbin:/usr/sbin/nologin\n\
+                alice:x:1000:1000:Alice:/home/alice:/bin/bash\n\
+                nobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin\n",
+        )?;
+        std::fs::create_dir_all(sandbox.path("/home/alice/.nix-defexpr/channels"))?;
+        std::fs::write(sandbox.path("/home/alice/.nix-channels"), "")?;
+        std::os::unix::fs::symlink(
+            "/nix/var/nix/profiles/per-user/alice/profile",
+            sandbox.path("/home/alice/.nix-profile"),
+        )?;
+        std::fs::create_dir_all(sandbox.path("/usr/sbin/.cache/nix"))?;
+        std::fs::create_dir_all(sandbox.path("/nix/var/nix/profiles/per-user/alice"))?;
+
+        let leftovers = sandbox.scope(leftover_user_data(false)).await;
+        assert_eq!(leftovers.purged.len(), 1);
+        let alice = leftovers.purged[0].inner();
+        assert_eq!(alice.user(), "alice");
+        // Removed along with `/nix`
+        assert_eq!(alice.data().len(), 3);
+        // Looking is read only
+        assert!(sandbox.path("/home/alice/.nix-channels").exists());
+        let left_in_place = leftovers.describe_left_in_place();
+        assert!(left_in_place.contains("`--purge-user-data`"));
+        assert!(left_in_place.contains("* `alice`: "));
+        assert!(left_in_place.contains("`/home/alice/.nix-channels`"));
+
+        let leftovers = sandbox.scope(leftover_user_data(true)).await;
+        let alice = leftovers.purged[0].inner();
+        assert_eq!(alice.data().len(), 4);
+        let description = crate::action::Action::revert_description(alice);
+        assert!(description[0].description.contains("can't be recovered"));
+        assert!(description[0]
+            .explanation
+            .iter()
+            .any(|line| line.contains("losing the channels they subscribed to")));
 
-        Ok(ExitCode::SUCCESS)
+        let mut actions = leftovers
+            .purged
+            .into_iter()
+            .map(StatefulAction::boxed)
+            .collect::<Vec<_>>();
+        sandbox.revert(&mut actions).await?;
+        assert!(!sandbox.path("/home/alice/.nix-channels").exists());
+        assert!(!sandbox.path("/home/alice/.nix-defexpr").exists());
+        assert!(sandbox
+            .path("/home/alice/.nix-profile")
+            .symlink_metadata()
+            .is_err());
+        assert!(!sandbox
+            .path("/nix/var/nix/profiles/per-user/alice")
+            .exists());
+        assert!(sandbox.path("/home/alice").exists());
+        // Not a regular user
+        assert!(sandbox.path("/usr/sbin/.cache/nix").exists());
+        Ok(())
     }
 }
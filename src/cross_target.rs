@@ -0,0 +1,255 @@
+/*! Plans for a platform other than the host
+
+`nix-installer plan --target <triple>` plans for the platform the triple names without inspecting
+the host, so golden plans for several platforms can be made on one machine. While planning:
+
+* No command is run, a command an action would run to inspect the host fails instead.
+* Settings which are normally probed from the host (like the macOS `root_disk`) are taken from their
+  flags, or left as a [`PLACEHOLDER`] and listed in [`CrossTarget::placeholders`].
+* An action which cannot be planned without inspecting the host is planned as a
+  [`RequiresHostProbing`](crate::action::common::RequiresHostProbing) marker rather than failing.
+
+The plan records its [`CrossTarget`], and `nix-installer install` refuses it on a host of another
+platform, or with anything left to probe.
+*/
+
+use std::{
+    future::Future,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use target_lexicon::{Architecture, OperatingSystem, Triple};
+
+use crate::{
+    action::{common::RequiresHostProbing, Action, ActionError, StatefulAction},
+    messages::message,
+    planner::PlannerError,
+};
+
+/// The value of a setting which would have been probed from the host
+pub const PLACEHOLDER: &str = "<probed on the host>";
+
+tokio::task_local! {
+    static TARGET: Arc<Target>;
+}
+
+#[derive(Debug)]
+struct Target {
+    triple: Triple,
+    placeholders: Mutex<Vec<String>>,
+}
+
+/// The platform a plan was made for without inspecting the host
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct CrossTarget {
+    /// The target triple, such as `aarch64-apple-darwin`
+    pub triple: String,
+    /// The settings which would have been probed from the host, and were left as a placeholder or
+    /// assumed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub placeholders: Vec<String>,
+}
+
+impl CrossTarget {
+    /// Check a plan for this target can be installed on the host, with the `actions` it planned
+    pub(crate) fn check_host(
+        &self,
+        actions: &[StatefulAction<Box<dyn Action>>],
+    ) -> Result<(), CrossTargetError> {
+        let target = Triple::from_str(&self.triple)
+            .map_err(|_| CrossTargetError::InvalidTriple(self.triple.clone()))?;
+        if !same_platform(&target, &Triple::host()) {
+            return Err(CrossTargetError::HostMismatch {
+                target: self.triple.clone(),
+                host: Triple::host(),
+            });
+        }
+        let mut unprobed = self.placeholders.clone();
+        unprobed.extend(
+            actions
+                .iter()
+                .filter(|action| action.inner_typetag_name() == "requires_host_probing")
+                .map(|action| action.tracing_synopsis()),
+        );
+        if !unprobed.is_empty() {
+            return Err(CrossTargetError::Unprobed {
+                target: self.triple.clone(),
+                unprobed,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// If `a` and `b` run the same planners and binaries
+fn same_platform(a: &Triple, b: &Triple) -> bool {
+    let darwin = |os: OperatingSystem| {
+        matches!(os, OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin)
+    };
+    let same_os = a.operating_system == b.operating_system
+        || (darwin(a.operating_system) && darwin(b.operating_system));
+    same_os && a.architecture == b.architecture
+}
+
+/// Run `fut` planning for `triple` rather than the host, returning the settings it left as
+/// placeholders alongside its output
+pub(crate) async fn scope<F: Future>(triple: Triple, fut: F) -> (F::Output, Vec<String>) {
+    let target = Arc::new(Target {
+        triple,
+        placeholders: Mutex::new(vec![]),
+    });
+    let output = TARGET.scope(target.clone(), fut).await;
+    let placeholders = std::mem::take(
+        &mut *target
+            .placeholders
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    );
+    (output, placeholders)
+}
+
+/// The platform being planned for, if it is not the host
+pub fn current() -> Option<Triple> {
+    TARGET.try_with(|target| target.triple.clone()).ok()
+}
+
+/// If planning for a platform other than the host, where nothing may be probed
+pub fn is_active() -> bool {
+    TARGET.try_with(|_| ()).is_ok()
+}
+
+/// The architecture being planned for, the host's unless planning for another platform
+pub fn architecture() -> Architecture {
+    current().map_or_else(Architecture::host, |triple| triple.architecture)
+}
+
+/// The operating system being planned for, the host's unless planning for another platform
+pub fn operating_system() -> OperatingSystem {
+    current().map_or_else(OperatingSystem::host, |triple| triple.operating_system)
+}
+
+/// The triple being planned for, the host's unless planning for another platform
+pub fn triple() -> Triple {
+    current().unwrap_or_else(Triple::host)
+}
+
+/// If the platform being planned for runs the binaries bundled into `nix-installer`, which were built
+/// for the platform `nix-installer` was built for
+pub(crate) fn runs_bundled() -> bool {
+    same_platform(&triple(), &target_lexicon::HOST)
+}
+
+/// Record that `setting` would have been probed from the host, and was left as a placeholder or
+/// assumed
+pub(crate) fn placeholder(setting: &str) {
+    let _ = TARGET.try_with(|target| {
+        let mut placeholders = target
+            .placeholders
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !placeholders.iter().any(|known| known == setting) {
+            placeholders.push(setting.to_string());
+        }
+    });
+}
+
+/// The error a command gets instead of running while planning for another platform
+pub(crate) fn command_error(command: &tokio::process::Command) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!(
+            "`{}` inspects the host, which is not done while planning for another platform",
+            command.as_std().get_program().to_string_lossy()
+        ),
+    )
+}
+
+/// Plan an action with `planned`, which while planning for another platform is marked as requiring
+/// host probing if it could not be planned, and as not yet done otherwise
+pub(crate) async fn plan_action<A: Action + 'static>(
+    planned: impl Future<Output = Result<StatefulAction<A>, ActionError>>,
+) -> Result<StatefulAction<Box<dyn Action>>, PlannerError> {
+    match (planned.await, is_active()) {
+        (Ok(action), false) => Ok(action.boxed()),
+        (Err(err), false) => Err(PlannerError::Action(err)),
+        // The host's state says nothing about the target's
+        (Ok(mut action), true) => {
+            action.state = crate::action::ActionState::Uncompleted;
+            Ok(action.boxed())
+        },
+        (Err(err), true) => {
+            tracing::debug!(%err, "Marking an action as requiring host probing");
+            Ok(RequiresHostProbing::plan(A::action_tag(), &err).boxed())
+        },
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CrossTargetError {
+    /// The plan was made for a platform other than the host
+    #[error("{}", message!(ErrorCrossTargetHostMismatch, target = .target, host = .host))]
+    HostMismatch { target: String, host: Triple },
+    /// The plan was made for the host's platform, but without inspecting it
+    #[error("{}", message!(ErrorCrossTargetUnprobed, target = .target, unprobed = .unprobed.join("\n* ")))]
+    Unprobed {
+        target: String,
+        unprobed: Vec<String>,
+    },
+    /// The plan records a target triple this `nix-installer` cannot parse
+    #[error("The plan was made for `{0}`, which is not a target triple")]
+    InvalidTriple(String),
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use target_lexicon::Triple;
+
+    use super::{same_platform, CrossTarget, CrossTargetError};
+
+    #[test]
+    fn plans_are_refused_on_other_platforms() {
+        let host = Triple::host();
+        let other = match host.operating_system {
+            target_lexicon::OperatingSystem::Linux => "aarch64-apple-darwin",
+            _ => "x86_64-unknown-linux-gnu",
+        };
+        let cross = CrossTarget {
+            triple: other.into(),
+            placeholders: vec![],
+        };
+        assert!(matches!(
+            cross.check_host(&[]),
+            Err(CrossTargetError::HostMismatch { .. })
+        ));
+
+        let native = CrossTarget {
+            triple: host.to_string(),
+            placeholders: vec![],
+        };
+        assert!(native.check_host(&[]).is_ok());
+        let unprobed = CrossTarget {
+            placeholders: vec!["root_disk".into()],
+            ..native
+        };
+        assert!(matches!(
+            unprobed.check_host(&[]),
+            Err(CrossTargetError::Unprobed { unprobed, .. }) if unprobed == ["root_disk"]
+        ));
+    }
+
+    #[test]
+    fn darwin_triples_name_one_platform() -> eyre::Result<()> {
+        let macosx = Triple::from_str("aarch64-apple-macosx14.0.0")?;
+        let darwin = Triple::from_str("aarch64-apple-darwin")?;
+        assert!(same_platform(&macosx, &darwin));
+        assert!(!same_platform(
+            &darwin,
+            &Triple::from_str("x86_64-apple-darwin")?
+        ));
+        Ok(())
+    }
+}
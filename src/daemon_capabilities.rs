@@ -0,0 +1,243 @@
+/*! What the daemon of the Nix releases `nix-installer` can install supports
+
+A `--nix-package-url` may pin a Nix much older than the bundled one, whose daemon cannot run under
+the units planned for a current one: started by a socket unit it does not expect, it exits and is
+started again, over and over. The planned init configuration is checked against this table, and
+adjusted to what the daemon supports or refused with what it lacks. `--verify-store` also picks the
+command which verifies the store by it, and `--use-xdg-base-directories` is refused for releases
+which would ignore the setting.
+
+# Updating the table
+
+Add a row for a release series which changes what its daemon supports, with every capability it
+has. Series without a row have the capabilities of the latest row before them.
+*/
+
+use crate::{
+    nix_settings::{series_at_least, series_of, series_to_install},
+    planner::PlannerError,
+    settings::CommonSettings,
+};
+
+/// What the daemon of a Nix release series supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DaemonCapabilities {
+    /// Takes its listening socket from systemd socket activation
+    pub(crate) socket_activation: bool,
+    /// Runs under `determinate-nixd`, which hands it a second socket next to its own
+    pub(crate) multiple_sockets: bool,
+    /// Serves a single client over stdin and stdout with `nix-daemon --stdio`, as the TCP
+    /// listener runs it
+    pub(crate) stdio: bool,
+    /// Verifies the store with `nix store verify`, older releases only with `nix-store --verify`
+    pub(crate) store_verify: bool,
+    /// Honors `use-xdg-base-directories`, keeping per-user profiles under `$XDG_STATE_HOME`
+    pub(crate) xdg_base_directories: bool,
+}
+
+/// The earliest release series `nix-installer` can configure a daemon for
+pub(crate) const OLDEST_SERIES: &str = "1.11";
+
+/// The capabilities of each release series from the first one with them, earliest first
+const CAPABILITIES: &[(&str, DaemonCapabilities)] = &[
+    (
+        OLDEST_SERIES,
+        DaemonCapabilities {
+            socket_activation: false,
+            multiple_sockets: false,
+            stdio: false,
+            store_verify: false,
+            xdg_base_directories: false,
+        },
+    ),
+    (
+        "2.0",
+        DaemonCapabilities {
+            socket_activation: false,
+            multiple_sockets: false,
+            stdio: true,
+            store_verify: false,
+            xdg_base_directories: false,
+        },
+    ),
+    (
+        "2.4",
+        DaemonCapabilities {
+            socket_activation: true,
+            multiple_sockets: false,
+            stdio: true,
+            store_verify: true,
+            xdg_base_directories: false,
+        },
+    ),
+    (
+        "2.14",
+        DaemonCapabilities {
+            socket_activation: true,
+            multiple_sockets: false,
+            stdio: true,
+            store_verify: true,
+            xdg_base_directories: true,
+        },
+    ),
+    (
+        "2.25",
+        DaemonCapabilities {
+            socket_activation: true,
+            multiple_sockets: true,
+            stdio: true,
+            store_verify: true,
+            xdg_base_directories: true,
+        },
+    ),
+];
+
+/// The capabilities of the daemon of the Nix `series` (such as `2.25`), `None` if it is older
+/// than [`OLDEST_SERIES`]
+pub(crate) fn capabilities(series: &str) -> Option<DaemonCapabilities> {
+    CAPABILITIES
+        .iter()
+        .rev()
+        .find(|(first, _)| series_at_least(series, first))
+        .map(|(_, capabilities)| *capabilities)
+}
+
+/// The earliest release series with `capability`
+pub(crate) fn first_series_with(capability: fn(&DaemonCapabilities) -> bool) -> &'static str {
+    CAPABILITIES
+        .iter()
+        .find(|(_, capabilities)| capability(capabilities))
+        .map(|(series, _)| *series)
+        .expect("The latest release series has every capability")
+}
+
+/// The release series of the Nix `settings` installs, named by the `--nix-package-url` or the
+/// `--nix-version`
+///
+/// `None` if neither names it, and an error if they name different ones.
+pub(crate) fn series_to_configure(
+    settings: &CommonSettings,
+) -> Result<Option<String>, PlannerError> {
+    let named = series_to_install(settings.nix_package_url.as_ref());
+    let hinted = settings.nix_version.as_deref().and_then(series_of);
+    match (named, hinted) {
+        (Some(named), Some(hinted)) if named != hinted => Err(PlannerError::NixVersionMismatch {
+            installed: named,
+            hinted,
+        }),
+        (named, hinted) => Ok(named.or(hinted)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        capabilities, first_series_with, series_to_configure, DaemonCapabilities, CAPABILITIES,
+        OLDEST_SERIES,
+    };
+    use crate::{
+        nix_settings::{series_at_least, BUNDLED_NIX_SERIES},
+        planner::PlannerError,
+        settings::{CommonSettings, UrlOrPath},
+    };
+
+    #[test]
+    fn table_is_well_formed() {
+        assert_eq!(CAPABILITIES[0].0, OLDEST_SERIES);
+        for pair in CAPABILITIES.windows(2) {
+            assert!(
+                !series_at_least(pair[0].0, pair[1].0),
+                "{} is listed after {}",
+                pair[1].0,
+                pair[0].0
+            );
+        }
+        // The bundled Nix runs under every configuration the installer plans
+        assert_eq!(
+            capabilities(BUNDLED_NIX_SERIES),
+            Some(DaemonCapabilities {
+                socket_activation: true,
+                multiple_sockets: true,
+                stdio: true,
+                store_verify: true,
+                xdg_base_directories: true,
+            })
+        );
+    }
+
+    #[test]
+    fn capabilities_of_representative_series() {
+        assert_eq!(capabilities("1.10"), None);
+        let cases = [
+            ("1.11", false, false, false, false, false),
+            ("2.0", false, false, true, false, false),
+            ("2.3", false, false, true, false, false),
+            ("2.4", true, false, true, true, false),
+            ("2.13", true, false, true, true, false),
+            ("2.14", true, false, true, true, true),
+            ("2.18", true, false, true, true, true),
+            ("2.24", true, false, true, true, true),
+            ("2.25", true, true, true, true, true),
+            ("2.100", true, true, true, true, true),
+            ("3.0", true, true, true, true, true),
+        ];
+        for (
+            series,
+            socket_activation,
+            multiple_sockets,
+            stdio,
+            store_verify,
+            xdg_base_directories,
+        ) in cases
+        {
+            assert_eq!(
+                capabilities(series),
+                Some(DaemonCapabilities {
+                    socket_activation,
+                    multiple_sockets,
+                    stdio,
+                    store_verify,
+                    xdg_base_directories,
+                }),
+                "{series}"
+            );
+        }
+        assert_eq!(first_series_with(|c| c.socket_activation), "2.4");
+        assert_eq!(first_series_with(|c| c.multiple_sockets), "2.25");
+        assert_eq!(first_series_with(|c| c.stdio), "2.0");
+        assert_eq!(first_series_with(|c| c.store_verify), "2.4");
+        assert_eq!(first_series_with(|c| c.xdg_base_directories), "2.14");
+    }
+
+    #[tokio::test]
+    async fn series_comes_from_the_package_or_the_hint() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        assert_eq!(
+            series_to_configure(&settings)?.as_deref(),
+            Some(BUNDLED_NIX_SERIES)
+        );
+
+        settings.nix_package_url = Some(UrlOrPath::Url(
+            "https://releases.nixos.org/nix/nix-2.3.16/nix-2.3.16-x86_64-linux.tar.xz".parse()?,
+        ));
+        assert_eq!(series_to_configure(&settings)?.as_deref(), Some("2.3"));
+        settings.nix_version = Some("2.3.16".into());
+        assert_eq!(series_to_configure(&settings)?.as_deref(), Some("2.3"));
+
+        // A tarball whose name carries no version needs the hint
+        settings.nix_package_url = Some(UrlOrPath::Path("/srv/mirror/nix.tar.xz".into()));
+        assert_eq!(series_to_configure(&settings)?.as_deref(), Some("2.3"));
+        settings.nix_version = None;
+        assert_eq!(series_to_configure(&settings)?, None);
+
+        // The hint must agree with the package
+        settings.nix_package_url = None;
+        settings.nix_version = Some("2.3.16".into());
+        assert!(matches!(
+            series_to_configure(&settings),
+            Err(PlannerError::NixVersionMismatch { installed, hinted })
+                if installed == BUNDLED_NIX_SERIES && hinted == "2.3"
+        ));
+        Ok(())
+    }
+}
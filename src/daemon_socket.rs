@@ -0,0 +1,534 @@
+/*! Locating the Nix daemon socket on Linux
+
+Older `nix-daemon.socket` units, and hand-written overrides of them, listen on paths under
+`/var/run`, which systemd only keeps as a legacy alias of `/run`. On hosts where `/var/run` is a
+real directory rather than a symlink to `/run`, a socket left behind there is a second, stale
+endpoint which clients can still find after the unit moves.
+*/
+
+use std::{
+    collections::HashSet,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{action::ActionErrorKind, util::host_path};
+
+/// Where the installer places (and `systemctl enable` links) the daemon's socket unit
+pub(crate) const SOCKET_UNITS: &[&str] = &[
+    "/etc/systemd/system/nix-daemon.socket",
+    "/etc/systemd/system/sockets.target.wants/nix-daemon.socket",
+];
+/// Sockets older units listened on, which may outlive the unit
+pub(crate) const LEGACY_SOCKETS: &[&str] = &["/var/run/nix-daemon.socket"];
+/// Where `nix` connects unless `NIX_DAEMON_SOCKET_PATH` says otherwise
+pub(crate) const DEFAULT_CLIENT_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
+const LEGACY_RUN_DIR: &str = "/var/run";
+const RUN_DIR: &str = "/run";
+/// Units are rarely more than a link into the default profile, which is itself a link
+const MAX_LINKS: usize = 8;
+
+/// The existing unit at one of [`SOCKET_UNITS`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ExistingUnit {
+    Missing,
+    /// A symlink, along with the content it resolves to (if it resolves)
+    Symlink {
+        target: PathBuf,
+        content: Option<String>,
+    },
+    File {
+        content: String,
+    },
+}
+
+/// What to do about a unit which still listens under `/var/run`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "migration", rename_all = "snake_case")]
+pub(crate) enum UnitMigration {
+    /// Remove the link, the installer places a current unit in its place
+    Abandon { target: PathBuf },
+    /// Rewrite the unit's `ListenStream=` paths to `/run`
+    Rewrite { original: String, migrated: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub(crate) struct LegacyUnit {
+    pub(crate) path: PathBuf,
+    pub(crate) migration: UnitMigration,
+}
+
+/// How `/var/run` relates to `/run` on this host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LegacyRunDir {
+    Missing,
+    /// A symlink to `/run`, so anything under it is also under `/run`
+    AliasOfRun,
+    /// A directory of its own, so anything under it is separate from `/run`
+    Directory,
+}
+
+/// Everything left over from units listening under `/var/run`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct LegacySocketScan {
+    pub(crate) units: Vec<LegacyUnit>,
+    pub(crate) stale_sockets: Vec<PathBuf>,
+}
+
+/// The absolute paths in a unit's `ListenStream=` lines (ports and abstract sockets are skipped)
+pub(crate) fn listen_streams(unit: &str) -> Vec<PathBuf> {
+    unit.lines()
+        .filter_map(|line| line.trim().strip_prefix("ListenStream="))
+        .map(str::trim)
+        .filter(|value| value.starts_with('/'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// `path` moved from `/var/run` to `/run`, if it was under `/var/run`
+pub(crate) fn migrated_path(path: &Path) -> Option<PathBuf> {
+    let rest = path.strip_prefix(LEGACY_RUN_DIR).ok()?;
+    Some(Path::new(RUN_DIR).join(rest))
+}
+
+/// Rewrite a unit's `ListenStream=` paths from `/var/run` to `/run`, leaving everything else as-is
+pub(crate) fn migrate_unit(unit: &str) -> String {
+    unit.split_inclusive('\n')
+        .map(|line| {
+            let Some(value) = line.trim_start().strip_prefix("ListenStream=") else {
+                return line.to_string();
+            };
+            match migrated_path(Path::new(value.trim())) {
+                Some(migrated) => {
+                    let ending = &line[line.trim_end().len()..];
+                    format!("ListenStream={}{ending}", migrated.display())
+                },
+                None => line.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Decide what to do about an existing unit
+///
+/// Links to another of [`SOCKET_UNITS`] (as `systemctl enable` creates) follow whatever happens
+/// to their target, and units which do not resolve are left alone.
+pub(crate) fn unit_migration(existing: &ExistingUnit) -> Option<UnitMigration> {
+    let references_legacy = |content: &str| {
+        listen_streams(content)
+            .iter()
+            .any(|v| migrated_path(v).is_some())
+    };
+    match existing {
+        ExistingUnit::Missing => None,
+        ExistingUnit::Symlink { target, .. }
+            if SOCKET_UNITS.iter().any(|v| target == Path::new(v)) =>
+        {
+            None
+        },
+        ExistingUnit::Symlink {
+            target,
+            content: Some(content),
+        } if references_legacy(content) => Some(UnitMigration::Abandon {
+            target: target.clone(),
+        }),
+        ExistingUnit::Symlink { .. } => None,
+        ExistingUnit::File { content } if references_legacy(content) => {
+            Some(UnitMigration::Rewrite {
+                original: content.clone(),
+                migrated: migrate_unit(content),
+            })
+        },
+        ExistingUnit::File { .. } => None,
+    }
+}
+
+/// Pick the sockets which are stale once nothing listens under `/var/run`
+///
+/// When `/var/run` is an alias of `/run` the "legacy" socket is the current one, so nothing is stale.
+pub(crate) fn stale_sockets(run_dir: LegacyRunDir, existing_sockets: &[PathBuf]) -> Vec<PathBuf> {
+    match run_dir {
+        LegacyRunDir::Missing | LegacyRunDir::AliasOfRun => vec![],
+        LegacyRunDir::Directory => existing_sockets
+            .iter()
+            .filter(|v| v.starts_with(LEGACY_RUN_DIR))
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Find the units and sockets left over from units listening under `/var/run`
+#[tracing::instrument(level = "debug")]
+pub(crate) async fn scan_legacy_sockets() -> Result<LegacySocketScan, ActionErrorKind> {
+    let mut scan = LegacySocketScan::default();
+    let mut candidates: Vec<PathBuf> = LEGACY_SOCKETS.iter().map(PathBuf::from).collect();
+
+    for unit in SOCKET_UNITS {
+        let existing = existing_unit(Path::new(unit)).await?;
+        if let ExistingUnit::Symlink {
+            content: Some(content),
+            ..
+        }
+        | ExistingUnit::File { content } = &existing
+        {
+            candidates.extend(
+                listen_streams(content)
+                    .into_iter()
+                    .filter(|v| migrated_path(v).is_some()),
+            );
+        }
+        if let Some(migration) = unit_migration(&existing) {
+            tracing::debug!(unit, ?migration, "Unit listens under `/var/run`");
+            scan.units.push(LegacyUnit {
+                path: PathBuf::from(unit),
+                migration,
+            });
+        }
+    }
+
+    let mut existing_sockets = vec![];
+    for candidate in candidates {
+        if is_socket(&candidate).await? && !existing_sockets.contains(&candidate) {
+            existing_sockets.push(candidate);
+        }
+    }
+    scan.stale_sockets = stale_sockets(legacy_run_dir().await?, &existing_sockets);
+
+    Ok(scan)
+}
+
+/// Check that exactly one daemon socket exists, and it is the one `nix` connects to
+///
+/// Returns the socket, or `None` if no socket unit is installed or the daemon was never started.
+#[tracing::instrument(level = "debug")]
+pub(crate) async fn verify_daemon_socket() -> Result<Option<PathBuf>, DaemonSocketError> {
+    let unit = PathBuf::from(SOCKET_UNITS[0]);
+    let content = match existing_unit(&unit).await {
+        Ok(ExistingUnit::File { content })
+        | Ok(ExistingUnit::Symlink {
+            content: Some(content),
+            ..
+        }) => content,
+        Ok(_) => return Ok(None),
+        Err(e) => return Err(DaemonSocketError::Unit(unit, Box::new(e))),
+    };
+
+    let client = std::env::var_os("NIX_DAEMON_SOCKET_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CLIENT_SOCKET));
+    let listened = listen_streams(&content);
+    let normalize = |path: &Path| migrated_path(path).unwrap_or_else(|| path.to_path_buf());
+    if !listened.iter().any(|v| normalize(v) == normalize(&client)) {
+        return Err(DaemonSocketError::ClientMismatch {
+            unit,
+            client,
+            listened,
+        });
+    }
+
+    let mut seen = HashSet::new();
+    let mut active = vec![];
+    let candidates = std::iter::once(client.clone())
+        .chain(listened)
+        .chain(LEGACY_SOCKETS.iter().map(PathBuf::from));
+    for candidate in candidates {
+        let metadata = match tokio::fs::metadata(host_path(&candidate)).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(DaemonSocketError::Read(candidate, e)),
+        };
+        // `/var/run/x` and `/run/x` are the same socket when `/var/run` links to `/run`
+        if metadata.file_type().is_socket() && seen.insert((metadata.dev(), metadata.ino())) {
+            active.push(candidate);
+        }
+    }
+
+    match active.len() {
+        0 => Ok(None),
+        1 => Ok(active.pop()),
+        _ => Err(DaemonSocketError::MultipleSockets(active)),
+    }
+}
+
+async fn existing_unit(unit: &Path) -> Result<ExistingUnit, ActionErrorKind> {
+    let host_unit = host_path(unit);
+    let metadata = match tokio::fs::symlink_metadata(&host_unit).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ExistingUnit::Missing),
+        Err(e) => return Err(ActionErrorKind::Read(unit.to_path_buf(), e)),
+    };
+    if !metadata.file_type().is_symlink() {
+        let content = tokio::fs::read_to_string(&host_unit)
+            .await
+            .map_err(|e| ActionErrorKind::Read(unit.to_path_buf(), e))?;
+        return Ok(ExistingUnit::File { content });
+    }
+
+    let target = read_link(unit).await?;
+    // Link targets are host paths, so follow them one at a time
+    let mut link = target.clone();
+    for _ in 0..MAX_LINKS {
+        match tokio::fs::symlink_metadata(host_path(&link)).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => link = read_link(&link).await?,
+            Ok(_) => {
+                let content = tokio::fs::read_to_string(host_path(&link))
+                    .await
+                    .map_err(|e| ActionErrorKind::Read(link.clone(), e))?;
+                return Ok(ExistingUnit::Symlink {
+                    target,
+                    content: Some(content),
+                });
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(ActionErrorKind::Read(link, e)),
+        }
+    }
+    Ok(ExistingUnit::Symlink {
+        target,
+        content: None,
+    })
+}
+
+/// The absolute target of the symlink at `link`
+async fn read_link(link: &Path) -> Result<PathBuf, ActionErrorKind> {
+    let target = tokio::fs::read_link(host_path(link))
+        .await
+        .map_err(|e| ActionErrorKind::ReadSymlink(link.to_path_buf(), e))?;
+    Ok(match target.is_absolute() {
+        true => target,
+        false => link.parent().unwrap_or(Path::new("/")).join(target),
+    })
+}
+
+async fn is_socket(path: &Path) -> Result<bool, ActionErrorKind> {
+    match tokio::fs::symlink_metadata(host_path(path)).await {
+        Ok(metadata) => Ok(metadata.file_type().is_socket()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(ActionErrorKind::Read(path.to_path_buf(), e)),
+    }
+}
+
+async fn legacy_run_dir() -> Result<LegacyRunDir, ActionErrorKind> {
+    let legacy = Path::new(LEGACY_RUN_DIR);
+    match tokio::fs::symlink_metadata(host_path(legacy)).await {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            let target = read_link(legacy).await?;
+            // Usually `../run`, which `read_link` leaves as `/var/../run`
+            let target = target
+                .strip_prefix("/var/..")
+                .map(|rest| Path::new("/").join(rest))
+                .unwrap_or(target);
+            match target == Path::new(RUN_DIR) {
+                true => Ok(LegacyRunDir::AliasOfRun),
+                false => Ok(LegacyRunDir::Directory),
+            }
+        },
+        Ok(_) => Ok(LegacyRunDir::Directory),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(LegacyRunDir::Missing),
+        Err(e) => Err(ActionErrorKind::Read(legacy.to_path_buf(), e)),
+    }
+}
+
+#[non_exhaustive]
+#[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
+pub enum DaemonSocketError {
+    #[error("Reading `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Inspecting the socket unit `{0}`")]
+    Unit(PathBuf, #[source] Box<ActionErrorKind>),
+    #[error("Nix clients connect to `{client}`, but `{unit}` listens on {}, set `NIX_DAEMON_SOCKET_PATH` or rerun the installer to replace the unit", .listened.iter().map(|v| format!("`{}`", v.display())).collect::<Vec<_>>().join(", "))]
+    ClientMismatch {
+        unit: PathBuf,
+        client: PathBuf,
+        listened: Vec<PathBuf>,
+    },
+    #[error("Found more than one Nix daemon socket, rerun the installer to remove the stale ones:\n{}", .0.iter().map(|v| format!("  {}", v.display())).collect::<Vec<_>>().join("\n"))]
+    MultipleSockets(Vec<PathBuf>),
+}
+
+impl From<DaemonSocketError> for ActionErrorKind {
+    fn from(val: DaemonSocketError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::unix::net::UnixListener,
+        path::{Path, PathBuf},
+    };
+
+    use crate::test_harness::SandboxContext;
+
+    use super::{
+        migrate_unit, scan_legacy_sockets, stale_sockets, unit_migration, verify_daemon_socket,
+        DaemonSocketError, ExistingUnit, LegacyRunDir, LegacyUnit, UnitMigration,
+    };
+
+    const LEGACY_UNIT: &str =
+        "[Socket]\nListenStream=/var/run/nix-daemon.socket\n\n[Install]\nWantedBy=sockets.target\n";
+    const CURRENT_UNIT: &str =
+        "[Socket]\nListenStream=/nix/var/nix/daemon-socket/socket\n\n[Install]\nWantedBy=sockets.target\n";
+
+    #[test]
+    fn unit_migration_decisions() {
+        let profile_unit =
+            PathBuf::from("/nix/store/aaaa-nix/lib/systemd/system/nix-daemon.socket");
+
+        assert_eq!(unit_migration(&ExistingUnit::Missing), None);
+        assert_eq!(
+            unit_migration(&ExistingUnit::File {
+                content: CURRENT_UNIT.into()
+            }),
+            None
+        );
+        assert_eq!(
+            unit_migration(&ExistingUnit::File {
+                content: LEGACY_UNIT.into()
+            }),
+            Some(UnitMigration::Rewrite {
+                original: LEGACY_UNIT.into(),
+                migrated: "[Socket]\nListenStream=/run/nix-daemon.socket\n\n[Install]\nWantedBy=sockets.target\n".into(),
+            })
+        );
+        assert_eq!(
+            unit_migration(&ExistingUnit::Symlink {
+                target: profile_unit.clone(),
+                content: Some(LEGACY_UNIT.into()),
+            }),
+            Some(UnitMigration::Abandon {
+                target: profile_unit.clone()
+            })
+        );
+        assert_eq!(
+            unit_migration(&ExistingUnit::Symlink {
+                target: profile_unit.clone(),
+                content: Some(CURRENT_UNIT.into()),
+            }),
+            None
+        );
+        // Dangling links are not ours to judge
+        assert_eq!(
+            unit_migration(&ExistingUnit::Symlink {
+                target: profile_unit,
+                content: None,
+            }),
+            None
+        );
+        // `systemctl enable` links follow the unit they point to
+        assert_eq!(
+            unit_migration(&ExistingUnit::Symlink {
+                target: PathBuf::from("/etc/systemd/system/nix-daemon.socket"),
+                content: Some(LEGACY_UNIT.into()),
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn migrate_unit_only_touches_legacy_listen_streams() {
+        assert_eq!(
+            migrate_unit("[Socket]\nListenStream=/var/run/nix/socket\r\nListenStream=/nix/var/nix/daemon-socket/socket\nListenStream=8080\nDescription=/var/run/nix/socket"),
+            "[Socket]\nListenStream=/run/nix/socket\r\nListenStream=/nix/var/nix/daemon-socket/socket\nListenStream=8080\nDescription=/var/run/nix/socket"
+        );
+    }
+
+    #[test]
+    fn stale_socket_decisions() {
+        let existing = vec![
+            PathBuf::from("/var/run/nix-daemon.socket"),
+            PathBuf::from("/run/nix-daemon.socket"),
+        ];
+        assert!(stale_sockets(LegacyRunDir::Missing, &existing).is_empty());
+        assert!(stale_sockets(LegacyRunDir::AliasOfRun, &existing).is_empty());
+        assert_eq!(
+            stale_sockets(LegacyRunDir::Directory, &existing),
+            vec![PathBuf::from("/var/run/nix-daemon.socket")]
+        );
+        assert!(stale_sockets(LegacyRunDir::Directory, &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn scan_finds_legacy_units_and_sockets() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/etc/systemd/system/sockets.target.wants"))?;
+        std::fs::write(
+            sandbox.path("/etc/systemd/system/nix-daemon.socket"),
+            "[Socket]\nListenStream=/var/run/nix/daemon.sock\n",
+        )?;
+        std::os::unix::fs::symlink(
+            "/etc/systemd/system/nix-daemon.socket",
+            sandbox.path("/etc/systemd/system/sockets.target.wants/nix-daemon.socket"),
+        )?;
+        std::fs::create_dir_all(sandbox.path("/var/run/nix"))?;
+        let _listener = UnixListener::bind(sandbox.path("/var/run/nix/daemon.sock"))?;
+        // Not a socket, so not ours to remove
+        std::fs::write(sandbox.path("/var/run/nix-daemon.socket"), "")?;
+
+        let scan = sandbox.scope(scan_legacy_sockets()).await?;
+        assert_eq!(
+            scan.units,
+            vec![LegacyUnit {
+                path: PathBuf::from("/etc/systemd/system/nix-daemon.socket"),
+                migration: UnitMigration::Rewrite {
+                    original: "[Socket]\nListenStream=/var/run/nix/daemon.sock\n".into(),
+                    migrated: "[Socket]\nListenStream=/run/nix/daemon.sock\n".into(),
+                },
+            }]
+        );
+        assert_eq!(
+            scan.stale_sockets,
+            vec![PathBuf::from("/var/run/nix/daemon.sock")]
+        );
+
+        // Once `/var/run` is an alias of `/run` the socket is the live one
+        std::fs::remove_dir_all(sandbox.path("/var/run"))?;
+        std::os::unix::fs::symlink("../run", sandbox.path("/var/run"))?;
+        assert!(sandbox
+            .scope(scan_legacy_sockets())
+            .await?
+            .stale_sockets
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_requires_one_socket_matching_the_client() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        // Nothing installed, nothing to check
+        assert_eq!(sandbox.scope(verify_daemon_socket()).await?, None);
+
+        std::fs::create_dir_all(sandbox.path("/etc/systemd/system"))?;
+        std::fs::write(
+            sandbox.path("/etc/systemd/system/nix-daemon.socket"),
+            CURRENT_UNIT,
+        )?;
+        std::fs::create_dir_all(sandbox.path("/nix/var/nix/daemon-socket"))?;
+        let _listener = UnixListener::bind(sandbox.path("/nix/var/nix/daemon-socket/socket"))?;
+        assert_eq!(
+            sandbox.scope(verify_daemon_socket()).await?,
+            Some(PathBuf::from("/nix/var/nix/daemon-socket/socket"))
+        );
+
+        std::fs::create_dir_all(sandbox.path("/var/run"))?;
+        let _stale = UnixListener::bind(sandbox.path("/var/run/nix-daemon.socket"))?;
+        let err = sandbox.scope(verify_daemon_socket()).await.unwrap_err();
+        assert!(
+            matches!(&err, DaemonSocketError::MultipleSockets(v) if v.len() == 2),
+            "{err:?}"
+        );
+
+        std::fs::write(
+            sandbox.path("/etc/systemd/system/nix-daemon.socket"),
+            LEGACY_UNIT,
+        )?;
+        let err = sandbox.scope(verify_daemon_socket()).await.unwrap_err();
+        assert!(
+            matches!(&err, DaemonSocketError::ClientMismatch { client, .. } if client == Path::new("/nix/var/nix/daemon-socket/socket")),
+            "{err:?}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+/*! Deprecated flags and environment variables
+
+Provisioning code pins the flags it passes, so a renamed flag keeps working through its alias, and
+nothing points out the old spelling until the release which drops it breaks the pipeline. Every
+deprecated form is listed in [`DEPRECATED`], with its replacement and the release which will stop
+accepting it.
+
+When `nix-installer` starts, it looks for them in its arguments and environment. Each one used is
+raised as a [`WarningKind::Deprecated`] warning, so it is listed in the prompt, the summary, and the
+JSON result, and is recorded in the [`Invocation`] block of the receipts planned by that run. With
+`--deny-deprecated` it fails instead, so CI can enforce the migration before the removal does.
+*/
+
+use std::{
+    ffi::{OsStr, OsString},
+    sync::OnceLock,
+};
+
+use crate::warning::{Warning, WarningKind};
+
+static USED: OnceLock<Vec<Deprecation>> = OnceLock::new();
+
+/// The deprecated forms `nix-installer` still accepts
+pub const DEPRECATED: &[Deprecated] = &[Deprecated {
+    form: Form::Flag,
+    name: "--daemon-user-count",
+    replacement: "--nix-build-user-count",
+    removed_in: "0.40.0",
+}];
+
+/// A deprecated flag or environment variable, and what replaces it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deprecated {
+    pub form: Form,
+    /// The flag, including its leading `--`, or the environment variable
+    pub name: &'static str,
+    pub replacement: &'static str,
+    /// The first release which will not accept it
+    pub removed_in: &'static str,
+}
+
+/// Whether a [`Deprecated`] form is a flag or an environment variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Form {
+    Flag,
+    Env,
+}
+
+/// A deprecated form which was used
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Deprecation {
+    pub form: Form,
+    pub name: String,
+    pub replacement: String,
+    pub removed_in: String,
+}
+
+impl Deprecation {
+    /// The [`WarningKind::Deprecated`] warning about it
+    pub fn warning(&self) -> Warning {
+        Warning::new(WarningKind::Deprecated, self.to_string())
+    }
+}
+
+impl From<&Deprecated> for Deprecation {
+    fn from(deprecated: &Deprecated) -> Self {
+        Self {
+            form: deprecated.form,
+            name: deprecated.name.into(),
+            replacement: deprecated.replacement.into(),
+            removed_in: deprecated.removed_in.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Deprecation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            form,
+            name,
+            replacement,
+            removed_in,
+        } = self;
+        let form = match form {
+            Form::Flag => "flag",
+            Form::Env => "environment variable",
+        };
+        write!(
+            f,
+            "The `{name}` {form} is deprecated and will be removed in nix-installer {removed_in}, use `{replacement}` instead"
+        )
+    }
+}
+
+/// How `nix-installer` was invoked when a plan was made, recorded in its receipt
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Invocation {
+    /// The deprecated forms which were used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deprecated: Vec<Deprecation>,
+}
+
+impl Invocation {
+    /// The invocation of this `nix-installer`, see [`record`]
+    pub fn current() -> Self {
+        Self {
+            deprecated: used().to_vec(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deprecated.is_empty()
+    }
+}
+
+/// The forms of `table` used in `args` or `vars`, in the order of `table`
+///
+/// Flags are only looked for before a `--`, as what follows is not parsed as flags.
+pub fn detect<A, V>(table: &[Deprecated], args: A, vars: V) -> Vec<Deprecation>
+where
+    A: IntoIterator,
+    A::Item: AsRef<OsStr>,
+    V: IntoIterator<Item = (OsString, OsString)>,
+{
+    let args = args.into_iter().collect::<Vec<_>>();
+    let flags = args
+        .iter()
+        .skip(1)
+        .map(|arg| arg.as_ref().to_string_lossy())
+        .take_while(|arg| arg != "--")
+        .collect::<Vec<_>>();
+    let vars = vars.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+
+    table
+        .iter()
+        .filter(|deprecated| match deprecated.form {
+            Form::Flag => flags.iter().any(|flag| {
+                flag.strip_prefix(deprecated.name)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+            }),
+            Form::Env => vars.iter().any(|key| key == deprecated.name),
+        })
+        .map(Deprecation::from)
+        .collect()
+}
+
+/// Record the deprecated forms this `nix-installer` was invoked with, and log them
+///
+/// Only the first call records anything.
+pub(crate) fn record(deprecations: Vec<Deprecation>) {
+    for deprecation in &deprecations {
+        tracing::warn!("{deprecation}");
+    }
+    let _ = USED.set(deprecations);
+}
+
+/// The deprecated forms this `nix-installer` was invoked with, see [`record`]
+pub fn used() -> &'static [Deprecation] {
+    USED.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// The [`WarningKind::Deprecated`] warnings about the forms this `nix-installer` was invoked with
+pub(crate) fn warnings() -> Vec<Warning> {
+    used().iter().map(Deprecation::warning).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+
+    use super::{detect, Deprecated, Deprecation, Form, Invocation, DEPRECATED};
+
+    const TABLE: &[Deprecated] = &[
+        Deprecated {
+            form: Form::Flag,
+            name: "--daemon-user-count",
+            replacement: "--nix-build-user-count",
+            removed_in: "0.40.0",
+        },
+        Deprecated {
+            form: Form::Env,
+            name: "NIX_INSTALLER_DAEMON_USER_COUNT",
+            replacement: "NIX_INSTALLER_NIX_BUILD_USER_COUNT",
+            removed_in: "0.40.0",
+        },
+    ];
+
+    fn vars(keys: &[&str]) -> Vec<(OsString, OsString)> {
+        keys.iter()
+            .map(|key| (OsString::from(key), OsString::from("4")))
+            .collect()
+    }
+
+    #[test]
+    fn deprecated_forms_are_detected() {
+        let args = ["nix-installer", "install", "--daemon-user-count=4"];
+        assert_eq!(
+            detect(TABLE, args, vars(&["NIX_INSTALLER_DAEMON_USER_COUNT"])),
+            vec![Deprecation::from(&TABLE[0]), Deprecation::from(&TABLE[1])]
+        );
+
+        let args = ["nix-installer", "install", "--daemon-user-count", "4"];
+        assert_eq!(
+            detect(TABLE, args, vars(&["NIX_INSTALLER_NIX_BUILD_USER_COUNT"])),
+            vec![Deprecation::from(&TABLE[0])]
+        );
+    }
+
+    #[test]
+    fn replacements_and_lookalikes_are_not_deprecated() {
+        for args in [
+            &["nix-installer", "install", "--nix-build-user-count", "4"][..],
+            &["nix-installer", "install", "--daemon-user-counts", "4"],
+            &["nix-installer", "install", "--", "--daemon-user-count"],
+        ] {
+            assert_eq!(
+                detect(TABLE, args, vars(&["NIX_INSTALLER_DAEMON_USER"])),
+                vec![],
+                "{args:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn the_table_has_replacements_and_removals() {
+        for deprecated in DEPRECATED {
+            assert_ne!(deprecated.name, deprecated.replacement);
+            assert!(semver::Version::parse(deprecated.removed_in).is_ok());
+            if deprecated.form == Form::Flag {
+                assert!(deprecated.name.starts_with("--"), "{deprecated:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn invocations_serialize_structured_notices() -> eyre::Result<()> {
+        let invocation = Invocation {
+            deprecated: vec![Deprecation::from(&TABLE[0])],
+        };
+        assert_eq!(
+            serde_json::to_value(&invocation)?,
+            serde_json::json!({
+                "deprecated": [{
+                    "form": "flag",
+                    "name": "--daemon-user-count",
+                    "replacement": "--nix-build-user-count",
+                    "removed_in": "0.40.0",
+                }],
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Invocation::default())?,
+            serde_json::json!({})
+        );
+        Ok(())
+    }
+}
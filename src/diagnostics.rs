@@ -11,8 +11,8 @@ use os_release::OsRelease;
 use reqwest::Url;
 
 use crate::{
-    action::ActionError, parse_ssl_cert, planner::PlannerError, settings::InstallSettingsError,
-    CertificateError, NixInstallerError,
+    action::ActionError, build_info::BuildInfo, planner::PlannerError,
+    settings::InstallSettingsError, CertificateError, NixInstallerError,
 };
 
 /// The static of an action attempt
@@ -42,6 +42,8 @@ pub struct DiagnosticReport {
     pub os_version: String,
     pub triple: String,
     pub is_ci: bool,
+    /// How this `nix-installer` was built, the same as recorded in the receipt
+    pub build_info: BuildInfo,
     pub action: DiagnosticAction,
     pub status: DiagnosticStatus,
     /// Generally this includes the [`strum::IntoStaticStr`] representation of the error, we take special care not to include parameters of the error (which may include secrets)
@@ -59,8 +61,15 @@ pub struct DiagnosticData {
     os_version: String,
     triple: String,
     is_ci: bool,
+    /// Absent from receipts written before it was recorded
+    #[serde(default)]
+    build_info: BuildInfo,
     endpoint: Option<Url>,
     ssl_cert_file: Option<PathBuf>,
+    /// Not recorded, as it may carry credentials, so diagnostics sent after reading a receipt only
+    /// go through the proxies of `HTTPS_PROXY` and similar
+    #[serde(skip)]
+    proxy: Option<Url>,
     /// Generally this includes the [`strum::IntoStaticStr`] representation of the error, we take special care not to include parameters of the error (which may include secrets)
     failure_chain: Option<Vec<String>>,
 }
@@ -72,6 +81,7 @@ impl DiagnosticData {
         planner: String,
         configured_settings: Vec<String>,
         ssl_cert_file: Option<PathBuf>,
+        proxy: Option<Url>,
     ) -> Result<Self, DiagnosticError> {
         let endpoint = match endpoint {
             Some(endpoint) => diagnostic_endpoint_parser(&endpoint)?,
@@ -93,7 +103,9 @@ impl DiagnosticData {
             os_version,
             triple: target_lexicon::HOST.to_string(),
             is_ci,
+            build_info: BuildInfo::current().clone(),
             ssl_cert_file: ssl_cert_file.and_then(|v| v.canonicalize().ok()),
+            proxy,
             failure_chain: None,
         })
     }
@@ -143,8 +155,10 @@ impl DiagnosticData {
             os_version,
             triple,
             is_ci,
+            build_info,
             endpoint: _,
             ssl_cert_file: _,
+            proxy: _,
             failure_chain,
         } = self;
         DiagnosticReport {
@@ -156,6 +170,7 @@ impl DiagnosticData {
             os_version: os_version.clone(),
             triple: triple.clone(),
             is_ci: *is_ci,
+            build_info: build_info.clone(),
             action,
             status,
             failure_chain: failure_chain.clone(),
@@ -185,14 +200,11 @@ impl DiagnosticData {
         match endpoint.scheme() {
             "https" | "http" => {
                 tracing::debug!("Sending diagnostic to `{endpoint}`");
-                let mut buildable_client = reqwest::Client::builder();
-                if let Some(ssl_cert_file) = &self.ssl_cert_file {
-                    let ssl_cert = parse_ssl_cert(ssl_cert_file).await.ok();
-                    if let Some(ssl_cert) = ssl_cert {
-                        buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                    }
-                }
-                let client = buildable_client.build().map_err(DiagnosticError::Reqwest)?;
+                let client =
+                    crate::http_client_builder(self.proxy.as_ref(), self.ssl_cert_file.as_deref())
+                        .await?
+                        .build()
+                        .map_err(DiagnosticError::Reqwest)?;
 
                 let res = client
                     .post(endpoint.clone())
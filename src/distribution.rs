@@ -0,0 +1,621 @@
+/*! Telling Determinate Nix and upstream Nix installs apart
+
+The two distributions differ in the `determinate-nixd` binary, the daemon's service and sockets,
+and the Determinate settings in `/etc/nix/nix.conf`. A migration between them swaps all of these,
+so one which is interrupted part way leaves a mix behind: say, the upstream `launchd` service still
+loaded while `nix.conf` already points at the Determinate `netrc-file`. The artifacts on disk are
+compared with the distribution the receipt recorded, and converged back to it.
+*/
+
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+
+use crate::{
+    action::{
+        common::{
+            configure_determinate_nixd_init_service::{
+                DARWIN_NIXD_DAEMON_DEST, DARWIN_NIXD_SERVICE_NAME,
+            },
+            configure_upstream_init_service::{
+                DARWIN_LAUNCHD_SERVICE_NAME, DARWIN_NIX_DAEMON_DEST, SERVICE_DEST,
+            },
+            place_nix_configuration::NIX_CONF,
+            provision_determinate_nixd::DETERMINATE_NIXD_BINARY_PATH,
+        },
+        macos::DARWIN_LAUNCHD_DOMAIN,
+        ActionErrorKind,
+    },
+    settings::InitSystem,
+    util::{host_path, OnMissing},
+};
+
+/// The socket of the daemon, which both distributions install, with different contents
+const NIX_DAEMON_SOCKET_DEST: &str = "/etc/systemd/system/nix-daemon.socket";
+/// The socket `determinate-nixd` itself listens on
+const DETERMINATE_NIXD_SOCKET_DEST: &str = "/etc/systemd/system/determinate-nixd.socket";
+
+/// A distribution of Nix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Distribution {
+    Determinate,
+    Upstream,
+}
+
+impl Distribution {
+    /// The distribution `receipt` was installed as, from its `--determinate` setting
+    pub(crate) fn of_receipt(receipt: &serde_json::Value) -> Option<Self> {
+        match receipt
+            .pointer("/planner/settings/determinate_nix")?
+            .as_bool()?
+        {
+            true => Some(Self::Determinate),
+            false => Some(Self::Upstream),
+        }
+    }
+
+    /// The `launchd` label of the daemon
+    fn launchd_label(self) -> &'static str {
+        match self {
+            Self::Determinate => DARWIN_NIXD_SERVICE_NAME,
+            Self::Upstream => DARWIN_LAUNCHD_SERVICE_NAME,
+        }
+    }
+
+    /// The artifacts an install of this distribution has, which must exist
+    fn required(self, init: InitSystem) -> Vec<(ArtifactKind, &'static str)> {
+        let mut required = vec![];
+        if self == Self::Determinate {
+            required.push((ArtifactKind::Binary, DETERMINATE_NIXD_BINARY_PATH));
+        }
+        match (self, init) {
+            (Self::Determinate, InitSystem::Launchd) => {
+                required.push((ArtifactKind::Service, DARWIN_NIXD_DAEMON_DEST));
+            },
+            (Self::Upstream, InitSystem::Launchd) => {
+                required.push((ArtifactKind::Service, DARWIN_NIX_DAEMON_DEST));
+            },
+            (_, InitSystem::Systemd) => {
+                required.push((ArtifactKind::Service, SERVICE_DEST));
+                required.push((ArtifactKind::Socket, NIX_DAEMON_SOCKET_DEST));
+                if self == Self::Determinate {
+                    required.push((ArtifactKind::Socket, DETERMINATE_NIXD_SOCKET_DEST));
+                }
+            },
+            (_, InitSystem::None) => (),
+        }
+        required
+    }
+}
+
+impl std::fmt::Display for Distribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Determinate => write!(f, "Determinate Nix"),
+            Self::Upstream => write!(f, "upstream Nix"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ArtifactKind {
+    Binary,
+    Service,
+    Socket,
+    NixConf,
+}
+
+impl std::fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Binary => write!(f, "the `determinate-nixd` binary"),
+            Self::Service => write!(f, "the daemon service"),
+            Self::Socket => write!(f, "a daemon socket"),
+            Self::NixConf => write!(f, "the Nix configuration"),
+        }
+    }
+}
+
+/// A file on disk, and the distribution it belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Artifact {
+    pub(crate) kind: ArtifactKind,
+    pub(crate) path: PathBuf,
+    pub(crate) distribution: Distribution,
+}
+
+impl Artifact {
+    fn new(kind: ArtifactKind, path: impl Into<PathBuf>, distribution: Distribution) -> Self {
+        Self {
+            kind,
+            path: path.into(),
+            distribution,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Inconsistency {
+    /// `artifact` belongs to the other distribution than the recorded one
+    Foreign(Artifact),
+    /// An artifact the recorded distribution requires does not exist
+    Missing {
+        kind: ArtifactKind,
+        path: PathBuf,
+        distribution: Distribution,
+    },
+}
+
+impl Inconsistency {
+    pub(crate) fn kind(&self) -> ArtifactKind {
+        match self {
+            Self::Foreign(artifact) => artifact.kind,
+            Self::Missing { kind, .. } => *kind,
+        }
+    }
+}
+
+impl std::fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Foreign(artifact) => write!(
+                f,
+                "`{}` is {} of {}",
+                artifact.path.display(),
+                artifact.kind,
+                artifact.distribution
+            ),
+            Self::Missing {
+                kind,
+                path,
+                distribution,
+            } => write!(
+                f,
+                "{kind} of {distribution} is missing from `{}`",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Compare the `artifacts` found with those an install of `recorded` using `init` has
+pub(crate) fn inconsistencies(
+    artifacts: &[Artifact],
+    recorded: Distribution,
+    init: InitSystem,
+) -> Vec<Inconsistency> {
+    let mut found = artifacts
+        .iter()
+        .filter(|artifact| artifact.distribution != recorded)
+        .cloned()
+        .map(Inconsistency::Foreign)
+        .collect::<Vec<_>>();
+    for (kind, path) in recorded.required(init) {
+        // A path holding the other distribution's file is already reported
+        if !artifacts
+            .iter()
+            .any(|artifact| artifact.path == Path::new(path))
+        {
+            found.push(Inconsistency::Missing {
+                kind,
+                path: path.into(),
+                distribution: recorded,
+            });
+        }
+    }
+    found
+}
+
+/// The distribution artifacts on disk, for an install using `init`
+pub(crate) fn artifacts(init: InitSystem) -> Vec<Artifact> {
+    let mut found = vec![];
+    if host_path(DETERMINATE_NIXD_BINARY_PATH).exists() {
+        found.push(Artifact::new(
+            ArtifactKind::Binary,
+            DETERMINATE_NIXD_BINARY_PATH,
+            Distribution::Determinate,
+        ));
+    }
+    match init {
+        InitSystem::Launchd => {
+            for distribution in [Distribution::Determinate, Distribution::Upstream] {
+                let plist = match distribution {
+                    Distribution::Determinate => DARWIN_NIXD_DAEMON_DEST,
+                    Distribution::Upstream => DARWIN_NIX_DAEMON_DEST,
+                };
+                if host_path(plist).exists() {
+                    found.push(Artifact::new(ArtifactKind::Service, plist, distribution));
+                }
+            }
+        },
+        InitSystem::Systemd => {
+            for (kind, unit) in [
+                (ArtifactKind::Service, SERVICE_DEST),
+                (ArtifactKind::Socket, NIX_DAEMON_SOCKET_DEST),
+                (ArtifactKind::Socket, DETERMINATE_NIXD_SOCKET_DEST),
+            ] {
+                let path = host_path(unit);
+                if path.exists() || path.is_symlink() {
+                    found.push(Artifact::new(kind, unit, unit_distribution(&path)));
+                }
+            }
+        },
+        InitSystem::None => (),
+    }
+    if let Ok(nix_conf) = std::fs::read_to_string(host_path(NIX_CONF)) {
+        found.push(Artifact::new(
+            ArtifactKind::NixConf,
+            NIX_CONF,
+            nix_conf_distribution(&nix_conf),
+        ));
+    }
+    found
+}
+
+/// The distribution a systemd unit belongs to, the Determinate units name themselves
+fn unit_distribution(path: &Path) -> Distribution {
+    // The upstream units link into the default profile, which may be gone
+    match std::fs::read_to_string(path) {
+        Ok(unit) if unit.contains("Determinate") => Distribution::Determinate,
+        _ => Distribution::Upstream,
+    }
+}
+
+/// The Determinate `netrc-file`, which only a Determinate `nix.conf` sets
+fn determinate_netrc_file() -> String {
+    crate::settings::determinate_nix_settings()
+        .settings()
+        .get("netrc-file")
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn nix_conf_distribution(nix_conf: &str) -> Distribution {
+    let netrc_file = determinate_netrc_file();
+    match nix_conf
+        .lines()
+        .filter_map(setting_of)
+        .any(|(name, value)| name == "netrc-file" && value == netrc_file)
+    {
+        true => Distribution::Determinate,
+        false => Distribution::Upstream,
+    }
+}
+
+/// The name and value set by a line of `nix.conf`
+fn setting_of(line: &str) -> Option<(&str, &str)> {
+    let line = line.split('#').next()?;
+    let (name, value) = line.split_once('=')?;
+    Some((name.trim(), value.trim()))
+}
+
+/// `nix_conf` with the Determinate settings added for `distribution` Determinate, or its
+/// Determinate `netrc-file` removed for upstream
+///
+/// Only the settings which tell the distributions apart are touched, everything else is kept as
+/// written. The FlakeHub cache is left in `extra-substituters` for upstream, since it may have
+/// been added by hand.
+pub(crate) fn converge_nix_conf(nix_conf: &str, distribution: Distribution) -> String {
+    let netrc_file = determinate_netrc_file();
+    let mut lines = nix_conf.lines().map(String::from).collect::<Vec<_>>();
+    match distribution {
+        Distribution::Upstream => {
+            lines.retain(|line| setting_of(line) != Some(("netrc-file", netrc_file.as_str())))
+        },
+        Distribution::Determinate => {
+            let determinate = crate::settings::determinate_nix_settings();
+            for (name, value) in determinate.settings() {
+                let existing = lines
+                    .iter()
+                    .position(|line| setting_of(line).is_some_and(|(set, _)| set == name));
+                match existing {
+                    // Settings holding lists are combined, as when installing
+                    Some(idx) if name == "extra-substituters" => {
+                        let (_, set) = setting_of(&lines[idx]).unwrap_or_default();
+                        let mut values = set.split_whitespace().collect::<Vec<_>>();
+                        for value in value.split_whitespace() {
+                            if !values.contains(&value) {
+                                values.push(value);
+                            }
+                        }
+                        lines[idx] = format!("{name} = {}", values.join(" "));
+                    },
+                    Some(idx) => lines[idx] = format!("{name} = {value}"),
+                    None => lines.push(format!("{name} = {value}")),
+                }
+            }
+        },
+    }
+    let mut nix_conf = lines.join("\n");
+    nix_conf.push('\n');
+    nix_conf
+}
+
+/// Stop the daemon of the other distribution and remove the `inconsistencies` found on disk, then
+/// converge `nix.conf` to `recorded`
+///
+/// The daemon of `recorded` is left for its init service action to configure and start.
+pub(crate) async fn remove_foreign(
+    inconsistencies: &[Inconsistency],
+    recorded: Distribution,
+    init: InitSystem,
+) -> Result<(), DistributionError> {
+    for inconsistency in inconsistencies {
+        let Inconsistency::Foreign(artifact) = inconsistency else {
+            continue;
+        };
+        let path = host_path(&artifact.path);
+        match artifact.kind {
+            ArtifactKind::NixConf => {
+                let nix_conf = tokio::fs::read_to_string(&path)
+                    .await
+                    .map_err(|e| DistributionError::Read(artifact.path.clone(), e))?;
+                tokio::fs::write(&path, converge_nix_conf(&nix_conf, recorded))
+                    .await
+                    .map_err(|e| DistributionError::Write(artifact.path.clone(), e))?;
+                continue;
+            },
+            ArtifactKind::Service if init == InitSystem::Launchd => {
+                let target = format!(
+                    "{DARWIN_LAUNCHD_DOMAIN}/{}",
+                    artifact.distribution.launchd_label()
+                );
+                // A service which is not loaded has nothing to boot out
+                let mut command = Command::new("launchctl");
+                command.process_group(0).args(["bootout", &target]);
+                command.stdin(std::process::Stdio::null());
+                if let Err(e) = crate::command_output(&mut command).await {
+                    tracing::debug!(%target, "Running `launchctl bootout`: {e}");
+                }
+            },
+            ArtifactKind::Service | ArtifactKind::Socket if init == InitSystem::Systemd => {
+                if let Some(unit) = artifact.path.file_name() {
+                    let mut command = Command::new("systemctl");
+                    command
+                        .process_group(0)
+                        .args(["disable", "--now"])
+                        .arg(unit);
+                    command.stdin(std::process::Stdio::null());
+                    if let Err(e) = crate::command_output(&mut command).await {
+                        tracing::debug!(?unit, "Running `systemctl disable --now`: {e}");
+                    }
+                }
+            },
+            _ => (),
+        }
+        crate::util::remove_file(&path, OnMissing::Ignore)
+            .await
+            .map_err(|e| DistributionError::Remove(artifact.path.clone(), e))?;
+    }
+    Ok(())
+}
+
+#[non_exhaustive]
+#[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
+pub enum DistributionError {
+    #[error("Reading `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Writing `{0}`")]
+    Write(PathBuf, #[source] std::io::Error),
+    #[error("Removing `{0}`, which belongs to the other distribution of Nix")]
+    Remove(PathBuf, #[source] std::io::Error),
+}
+
+impl From<DistributionError> for ActionErrorKind {
+    fn from(val: DistributionError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        converge_nix_conf, inconsistencies, remove_foreign, Artifact, ArtifactKind, Distribution,
+        Inconsistency,
+    };
+    use crate::settings::InitSystem;
+    use crate::test_harness::{FakeCommand, Invocation, SandboxContext};
+
+    const NIXD_PLIST: &str = "/Library/LaunchDaemons/systems.determinate.nix-daemon.plist";
+    const NIX_PLIST: &str = "/Library/LaunchDaemons/org.nixos.nix-daemon.plist";
+    const NIXD_BINARY: &str = "/usr/local/bin/determinate-nixd";
+    const SERVICE: &str = "/etc/systemd/system/nix-daemon.service";
+    const SOCKET: &str = "/etc/systemd/system/nix-daemon.socket";
+    const NIXD_SOCKET: &str = "/etc/systemd/system/determinate-nixd.socket";
+
+    fn determinate(kind: ArtifactKind, path: &str) -> Artifact {
+        Artifact::new(kind, path, Distribution::Determinate)
+    }
+
+    fn upstream(kind: ArtifactKind, path: &str) -> Artifact {
+        Artifact::new(kind, path, Distribution::Upstream)
+    }
+
+    fn missing(kind: ArtifactKind, path: &str, distribution: Distribution) -> Inconsistency {
+        Inconsistency::Missing {
+            kind,
+            path: path.into(),
+            distribution,
+        }
+    }
+
+    #[test]
+    fn mixed_artifacts_are_inconsistent() {
+        use ArtifactKind::*;
+        use Distribution::*;
+
+        let launchd_determinate = [
+            determinate(Binary, NIXD_BINARY),
+            determinate(Service, NIXD_PLIST),
+            determinate(NixConf, "/etc/nix/nix.conf"),
+        ];
+        let systemd_upstream = [
+            upstream(Service, SERVICE),
+            upstream(Socket, SOCKET),
+            upstream(NixConf, "/etc/nix/nix.conf"),
+        ];
+        let cases: Vec<(Vec<Artifact>, Distribution, InitSystem, Vec<Inconsistency>)> = vec![
+            (
+                launchd_determinate.to_vec(),
+                Determinate,
+                InitSystem::Launchd,
+                vec![],
+            ),
+            (
+                systemd_upstream.to_vec(),
+                Upstream,
+                InitSystem::Systemd,
+                vec![],
+            ),
+            // Migrating to Determinate stopped after swapping `nix.conf`
+            (
+                vec![
+                    upstream(Service, NIX_PLIST),
+                    determinate(NixConf, "/etc/nix/nix.conf"),
+                ],
+                Determinate,
+                InitSystem::Launchd,
+                vec![
+                    Inconsistency::Foreign(upstream(Service, NIX_PLIST)),
+                    missing(Binary, NIXD_BINARY, Determinate),
+                    missing(Service, NIXD_PLIST, Determinate),
+                ],
+            ),
+            // Migrating to upstream stopped before removing the binary and the old plist
+            (
+                vec![
+                    determinate(Binary, NIXD_BINARY),
+                    determinate(Service, NIXD_PLIST),
+                    upstream(Service, NIX_PLIST),
+                    upstream(NixConf, "/etc/nix/nix.conf"),
+                ],
+                Upstream,
+                InitSystem::Launchd,
+                vec![
+                    Inconsistency::Foreign(determinate(Binary, NIXD_BINARY)),
+                    Inconsistency::Foreign(determinate(Service, NIXD_PLIST)),
+                ],
+            ),
+            // The upstream units in place, the Determinate socket and settings added
+            (
+                vec![
+                    determinate(Binary, NIXD_BINARY),
+                    upstream(Service, SERVICE),
+                    upstream(Socket, SOCKET),
+                    determinate(Socket, NIXD_SOCKET),
+                    determinate(NixConf, "/etc/nix/nix.conf"),
+                ],
+                Determinate,
+                InitSystem::Systemd,
+                vec![
+                    Inconsistency::Foreign(upstream(Service, SERVICE)),
+                    Inconsistency::Foreign(upstream(Socket, SOCKET)),
+                ],
+            ),
+            (
+                systemd_upstream.to_vec(),
+                Determinate,
+                InitSystem::Systemd,
+                vec![
+                    Inconsistency::Foreign(upstream(Service, SERVICE)),
+                    Inconsistency::Foreign(upstream(Socket, SOCKET)),
+                    Inconsistency::Foreign(upstream(NixConf, "/etc/nix/nix.conf")),
+                    missing(Binary, NIXD_BINARY, Determinate),
+                    missing(Socket, NIXD_SOCKET, Determinate),
+                ],
+            ),
+            // Without an init system, only the binary and `nix.conf` tell them apart
+            (
+                vec![determinate(NixConf, "/etc/nix/nix.conf")],
+                Upstream,
+                InitSystem::None,
+                vec![Inconsistency::Foreign(determinate(
+                    NixConf,
+                    "/etc/nix/nix.conf",
+                ))],
+            ),
+        ];
+        for (artifacts, recorded, init, expected) in cases {
+            assert_eq!(
+                inconsistencies(&artifacts, recorded, init),
+                expected,
+                "{artifacts:?} recorded as {recorded} with {init}"
+            );
+        }
+    }
+
+    #[test]
+    fn nix_conf_converges_to_the_distribution() {
+        let upstream = "build-users-group = nixbld\nextra-substituters = https://example.com\n";
+        let determinate = converge_nix_conf(upstream, Distribution::Determinate);
+        assert_eq!(
+            determinate,
+            "build-users-group = nixbld\n\
+            extra-substituters = https://example.com https://cache.flakehub.com\n\
+            netrc-file = /nix/var/determinate/netrc\n"
+        );
+        assert_eq!(
+            super::nix_conf_distribution(&determinate),
+            Distribution::Determinate
+        );
+        assert_eq!(
+            converge_nix_conf(&determinate, Distribution::Determinate),
+            determinate
+        );
+
+        let reverted = converge_nix_conf(&determinate, Distribution::Upstream);
+        assert_eq!(
+            super::nix_conf_distribution(&reverted),
+            Distribution::Upstream
+        );
+        assert!(reverted.contains("https://cache.flakehub.com"));
+        assert!(reverted.starts_with("build-users-group = nixbld\n"));
+    }
+
+    #[tokio::test]
+    async fn foreign_units_are_stopped_and_removed() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/etc/systemd/system"))?;
+        std::fs::create_dir_all(sandbox.path("/etc/nix"))?;
+        std::fs::write(sandbox.path(NIXD_SOCKET), "Description=Determinate Nixd\n")?;
+        std::fs::write(
+            sandbox.path("/etc/nix/nix.conf"),
+            "netrc-file = /nix/var/determinate/netrc\n",
+        )?;
+        sandbox.fake("systemctl", FakeCommand::success());
+
+        let artifacts = sandbox
+            .scope(async { super::artifacts(InitSystem::Systemd) })
+            .await;
+        let found = inconsistencies(&artifacts, Distribution::Upstream, InitSystem::Systemd);
+        assert_eq!(
+            found
+                .iter()
+                .filter(|found| matches!(found, Inconsistency::Foreign(_)))
+                .count(),
+            2,
+            "{found:?}"
+        );
+        sandbox
+            .scope(remove_foreign(
+                &found,
+                Distribution::Upstream,
+                InitSystem::Systemd,
+            ))
+            .await?;
+
+        assert!(!sandbox.path(NIXD_SOCKET).exists());
+        assert_eq!(
+            std::fs::read_to_string(sandbox.path("/etc/nix/nix.conf"))?,
+            "\n"
+        );
+        assert_eq!(
+            sandbox.invocations_of("systemctl"),
+            [Invocation::new(
+                "systemctl",
+                ["disable", "--now", "determinate-nixd.socket"]
+            )]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+/*! Detecting changes to the files `nix-installer` wrote
+
+When an action writing a file executes, it records a [`WrittenFile`] in its action state, holding
+the SHA-256 of exactly what it wrote. A file `nix-installer` only inserted a block into, like
+`/etc/bashrc`, records the first and last lines of the block (its fence), and only the fenced
+block is hashed, so edits elsewhere in the file are not reported.
+
+[`written_files`] finds every [`WrittenFile`] in a receipt, however deeply the action recording
+it is nested, and [`WrittenFile::check`] compares one against what is on disk now.
+`nix-installer inspect` reports the files which have changed.
+*/
+
+use std::{
+    ops::Range,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::util::{host_path, LossyPath};
+
+/// What an action wrote to a file
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct WrittenFile {
+    #[serde_as(as = "LossyPath")]
+    pub path: PathBuf,
+    /// The SHA-256 of the content written, or of the fenced block
+    pub sha256: String,
+    /// Absent when the whole file was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fence: Option<Fence>,
+    /// The mode the file was given, when it was configured rather than a default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// The group the file was given, when it was configured rather than a default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+}
+
+/// The first and last lines of a block inserted into a file
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Fence {
+    pub begin: String,
+    pub end: String,
+}
+
+/// How a [`WrittenFile`] compares to what is on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftStatus {
+    Unchanged,
+    /// The file, or the fenced block, differs from what was written
+    Modified,
+    /// A fenced block is no longer in the file
+    BlockRemoved,
+    /// The content is unchanged, but the configured mode or group is not
+    PermissionsChanged,
+    Missing,
+}
+
+/// The result of [`WrittenFile::check`]
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct FileDrift {
+    #[serde_as(as = "LossyPath")]
+    pub path: PathBuf,
+    pub status: DriftStatus,
+    /// If only a block `nix-installer` inserted was compared, rather than the whole file
+    pub fenced: bool,
+}
+
+impl WrittenFile {
+    /// A file whose whole content is `content`
+    pub(crate) fn whole(path: impl Into<PathBuf>, content: &[u8]) -> Self {
+        Self {
+            path: path.into(),
+            sha256: crate::backup::sha256(content),
+            fence: None,
+            mode: None,
+            gid: None,
+        }
+    }
+
+    /// Also expect the file to keep `mode` and, if set, the group `gid`
+    pub(crate) fn with_permissions(mut self, mode: u32, gid: Option<u32>) -> Self {
+        self.mode = Some(mode);
+        self.gid = gid;
+        self
+    }
+
+    /// A file `block` was inserted into, `None` if `block` has no lines to fence it with
+    pub(crate) fn fenced(path: impl Into<PathBuf>, block: &str) -> Option<Self> {
+        let mut lines = block.lines().filter(|line| !line.trim().is_empty());
+        let begin = lines.next()?;
+        let end = lines.next_back().unwrap_or(begin);
+        let fence = Fence {
+            begin: begin.to_string(),
+            end: end.to_string(),
+        };
+        let region = fence.region(block)?;
+        Some(Self {
+            path: path.into(),
+            sha256: crate::backup::sha256(region.as_bytes()),
+            fence: Some(fence),
+            mode: None,
+            gid: None,
+        })
+    }
+
+    /// If `content`, the current content of the file, still holds what was written
+    pub(crate) fn status_of(&self, content: &[u8]) -> DriftStatus {
+        let compared = match &self.fence {
+            None => content,
+            Some(fence) => match fence.region(&String::from_utf8_lossy(content)) {
+                Some(region) => return self.compare(region.as_bytes()),
+                None => return DriftStatus::BlockRemoved,
+            },
+        };
+        self.compare(compared)
+    }
+
+    fn compare(&self, content: &[u8]) -> DriftStatus {
+        match crate::backup::sha256(content) == self.sha256 {
+            true => DriftStatus::Unchanged,
+            false => DriftStatus::Modified,
+        }
+    }
+
+    /// `content` without the fenced block, if the block is still as it was written
+    pub(crate) fn remove_from(&self, content: &str) -> Option<String> {
+        let range = self.fence.as_ref()?.range(content)?;
+        if self.compare(content[range.clone()].as_bytes()) != DriftStatus::Unchanged {
+            return None;
+        }
+        let before = content[..range.start].trim_end_matches('\n');
+        let after = content[range.end..].trim_start_matches('\n');
+        Some(match (before.is_empty(), after.is_empty()) {
+            (true, _) => after.to_string(),
+            (false, true) => format!("{before}\n"),
+            (false, false) => format!("{before}\n\n{after}"),
+        })
+    }
+
+    /// If the file at `path` has the recorded mode and group, if any were
+    async fn permissions_match(&self, path: &Path) -> Result<bool, std::io::Error> {
+        if self.mode.is_none() && self.gid.is_none() {
+            return Ok(true);
+        }
+        let metadata = tokio::fs::metadata(path).await?;
+        Ok(self.mode.is_none_or(|mode| metadata.mode() & 0o777 == mode)
+            && self.gid.is_none_or(|gid| metadata.gid() == gid))
+    }
+
+    /// Compare what was written with what is on disk now
+    pub async fn check(&self) -> Result<FileDrift, std::io::Error> {
+        let path = host_path(&self.path);
+        let status = match tokio::fs::read(&path).await {
+            Ok(content) => match self.status_of(&content) {
+                DriftStatus::Unchanged if !self.permissions_match(&path).await? => {
+                    DriftStatus::PermissionsChanged
+                },
+                status => status,
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => DriftStatus::Missing,
+            Err(e) => return Err(e),
+        };
+        Ok(FileDrift {
+            path: self.path.clone(),
+            status,
+            fenced: self.fence.is_some(),
+        })
+    }
+}
+
+impl Fence {
+    /// The lines of `content` from the first `begin` line to the `end` line after it
+    fn region<'a>(&self, content: &'a str) -> Option<&'a str> {
+        self.range(content).map(|range| &content[range])
+    }
+
+    fn range(&self, content: &str) -> Option<Range<usize>> {
+        let mut start = None;
+        let mut offset = 0;
+        for line in content.split_inclusive('\n') {
+            let trimmed = line.trim_end_matches(['\n', '\r']);
+            if start.is_none() && trimmed == self.begin {
+                start = Some(offset);
+            }
+            if let Some(start) = start {
+                if trimmed == self.end {
+                    return Some(start..offset + trimmed.len());
+                }
+            }
+            offset += line.len();
+        }
+        None
+    }
+}
+
+/// Every [`WrittenFile`] recorded in `receipt`, in the order the actions recording them appear
+pub fn written_files(receipt: &serde_json::Value) -> Vec<WrittenFile> {
+    let mut found = vec![];
+    collect(receipt, &mut found);
+    found
+}
+
+fn collect(value: &serde_json::Value, found: &mut Vec<WrittenFile>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                match key.as_str() {
+                    "written_file" => {
+                        found.extend(serde_json::from_value::<WrittenFile>(value.clone()).ok())
+                    },
+                    "written_files" => found.extend(
+                        serde_json::from_value::<Vec<WrittenFile>>(value.clone())
+                            .unwrap_or_default(),
+                    ),
+                    _ => collect(value, found),
+                }
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for value in values {
+                collect(value, found);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// If the file at `path` was changed since `written`
+///
+/// Receipts from before hashes were recorded have no `written`, so nothing is reported.
+pub(crate) async fn modified_since(path: &Path, written: Option<&WrittenFile>) -> bool {
+    let Some(written) = written else {
+        return false;
+    };
+    match tokio::fs::read(path).await {
+        Ok(content) => written.status_of(&content) != DriftStatus::Unchanged,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{written_files, DriftStatus, WrittenFile};
+
+    const BLOCK: &str = "\n# Nix\nif [ -e '/nix/nix-daemon.sh' ]; then\n  . '/nix/nix-daemon.sh'\nfi\n# End Nix\n\n";
+
+    #[test]
+    fn fenced_files_only_compare_the_block() {
+        let written = WrittenFile::fenced("/etc/bashrc", BLOCK).unwrap();
+        let fence = written.fence.as_ref().unwrap();
+        assert_eq!(fence.begin, "# Nix");
+        assert_eq!(fence.end, "# End Nix");
+
+        let edited_elsewhere = format!("alias ll='ls -l'\n{BLOCK}export EDITOR=vi\n");
+        assert_eq!(
+            written.status_of(edited_elsewhere.as_bytes()),
+            DriftStatus::Unchanged
+        );
+        let edited_inside =
+            edited_elsewhere.replace("nix-daemon.sh'\nfi", "nix-daemon.sh'\n  :\nfi");
+        assert_eq!(
+            written.status_of(edited_inside.as_bytes()),
+            DriftStatus::Modified
+        );
+        assert_eq!(
+            written.status_of(b"alias ll='ls -l'\n"),
+            DriftStatus::BlockRemoved
+        );
+    }
+
+    #[test]
+    fn finds_written_files_anywhere_in_a_receipt() {
+        let nix_conf = WrittenFile::whole("/etc/nix/nix.conf", b"build-users-group = nixbld\n");
+        let bashrc = WrittenFile::fenced("/etc/bashrc", BLOCK).unwrap();
+        let receipt = serde_json::json!({
+            "actions": [
+                {
+                    "action": {
+                        "action_name": "configure_nix",
+                        "place_nix_configuration": {
+                            "action": { "create_or_merge_nix_config": { "action": {
+                                "written_file": nix_conf,
+                            }}},
+                        },
+                    },
+                    "state": "Completed",
+                },
+                { "action": { "written_files": [bashrc] }, "state": "Completed" },
+                { "action": { "written_file": null }, "state": "Uncompleted" },
+            ],
+        });
+        assert_eq!(written_files(&receipt), vec![nix_conf, bashrc]);
+    }
+}
@@ -3,7 +3,12 @@ use std::{error::Error, path::PathBuf};
 use semver::Version;
 
 use crate::{
-    action::ActionError, planner::PlannerError, self_test::SelfTestError,
+    action::ActionError,
+    messages::message,
+    plan::{ExtraPlanError, PrivilegedOperationsError},
+    plan_builder::InstallPlanBuilderError,
+    planner::PlannerError,
+    self_test::SelfTestError,
     settings::InstallSettingsError,
 };
 
@@ -35,6 +40,9 @@ pub enum NixInstallerError {
     /// An error while writing the [`InstallPlan`](crate::InstallPlan)
     #[error("Recording install receipt")]
     RecordingReceipt(PathBuf, #[source] std::io::Error),
+    /// An error while replacing the [`InstallPlan`](crate::InstallPlan) with its new content
+    #[error("Recording install receipt `{0}`")]
+    WritingReceipt(PathBuf, #[source] crate::replace_file::ReplaceFileError),
     /// An error while writing copying the binary into the `/nix` folder
     #[error("Copying `nix-installer` binary into `/nix`")]
     CopyingSelf(
@@ -50,8 +58,14 @@ pub enum NixInstallerError {
         serde_json::Error,
     ),
     /// An error occurring when a signal is issued along [`InstallPlan::install`](crate::InstallPlan::install)'s `cancel_channel` argument
-    #[error("Cancelled by user")]
+    #[error("{}", message!(ErrorCancelled))]
     Cancelled,
+    /// A phase of a phased install was run before an earlier one completed
+    #[error("{}", message!(ErrorPhaseOutOfOrder, phase = .phase, incomplete = .incomplete))]
+    PhaseOutOfOrder {
+        phase: crate::phase::Phase,
+        incomplete: crate::phase::Phase,
+    },
     /// Semver error
     #[error("Semantic Versioning error")]
     SemVer(
@@ -66,6 +80,34 @@ pub enum NixInstallerError {
         #[source]
         PlannerError,
     ),
+    /// An error with an [`ExtraPlan`](crate::ExtraPlan)
+    #[error("Extra plan error")]
+    ExtraPlan(
+        #[from]
+        #[source]
+        ExtraPlanError,
+    ),
+    /// An error with an [`InstallPlanBuilder`](crate::InstallPlanBuilder)
+    #[error("Plan builder error")]
+    PlanBuilder(
+        #[from]
+        #[source]
+        InstallPlanBuilderError,
+    ),
+    /// An error with a list of [`PrivilegedOperations`](crate::PrivilegedOperations)
+    #[error("Privileged operations error")]
+    PrivilegedOperations(
+        #[from]
+        #[source]
+        PrivilegedOperationsError,
+    ),
+    /// An error while comparing a plan with the host
+    #[error("Inspecting the host")]
+    Audit(
+        #[from]
+        #[source]
+        crate::audit::AuditError,
+    ),
     /// Install setting error
     #[error("Install setting error")]
     InstallSettings(
@@ -83,14 +125,17 @@ pub enum NixInstallerError {
         crate::diagnostics::DiagnosticError,
     ),
     /// Could not parse the value as a version requirement in order to ensure it's compatible
-    #[error("Could not parse `{0}` as a version requirement in order to ensure it's compatible")]
+    #[error("{}", message!(ErrorInvalidVersionRequirement, requirement = .0))]
     InvalidVersionRequirement(String, semver::Error),
     /// Could not parse `nix-installer`'s version as a valid version according to Semantic Versioning, therefore the plan version compatibility cannot be checked
-    #[error("Could not parse `nix-installer`'s version `{0}` as a valid version according to Semantic Versioning, therefore the plan version compatibility cannot be checked")]
+    #[error("{}", message!(ErrorInvalidCurrentVersion, version = .0))]
     InvalidCurrentVersion(String, semver::Error),
     /// This version of `nix-installer` is not compatible with this plan's version
-    #[error("`nix-installer` version `{}` is not compatible with this plan's version `{}`", .binary, .plan)]
+    #[error("{}", message!(ErrorIncompatibleVersion, binary = .binary, plan = .plan))]
     IncompatibleVersion { binary: Version, plan: Version },
+    /// The plan was made for another platform, see [`cross_target`](crate::cross_target)
+    #[error(transparent)]
+    CrossTarget(#[from] crate::cross_target::CrossTargetError),
 }
 
 pub(crate) trait HasExpectedErrors: std::error::Error + Sized + Send + Sync {
@@ -104,17 +149,26 @@ impl HasExpectedErrors for NixInstallerError {
             NixInstallerError::ActionRevert(_) => None,
             this @ NixInstallerError::SelfTest(_) => Some(Box::new(this)),
             NixInstallerError::RecordingReceipt(_, _) => None,
+            NixInstallerError::WritingReceipt(_, _) => None,
             NixInstallerError::CopyingSelf(_) => None,
             NixInstallerError::SerializingReceipt(_) => None,
             this @ NixInstallerError::Cancelled => Some(Box::new(this)),
+            this @ NixInstallerError::PhaseOutOfOrder { .. } => Some(Box::new(this)),
             NixInstallerError::SemVer(_) => None,
             NixInstallerError::Planner(planner_error) => planner_error.expected(),
+            NixInstallerError::ExtraPlan(extra_plan_error) => extra_plan_error.expected(),
+            NixInstallerError::PlanBuilder(plan_builder_error) => plan_builder_error.expected(),
+            NixInstallerError::PrivilegedOperations(privileged_operations_error) => {
+                privileged_operations_error.expected()
+            },
+            NixInstallerError::Audit(audit_error) => audit_error.expected(),
             NixInstallerError::InstallSettings(_) => None,
             this @ NixInstallerError::InvalidVersionRequirement(_, _) => Some(Box::new(this)),
             this @ NixInstallerError::InvalidCurrentVersion(_, _) => Some(Box::new(this)),
             this @ NixInstallerError::IncompatibleVersion { binary: _, plan: _ } => {
                 Some(Box::new(this))
             },
+            this @ NixInstallerError::CrossTarget(_) => Some(Box::new(this)),
             #[cfg(feature = "diagnostics")]
             NixInstallerError::Diagnostic(_) => None,
         }
@@ -0,0 +1,346 @@
+/*! Recording what an install or uninstall did, event by event, to a file
+
+`nix-installer install --event-log <path>` (and `uninstall`) appends one line of JSON to `<path>` for
+each [`LoggedEvent`], tagged with the `operation` and a `timestamp_ms` (milliseconds since the Unix
+epoch):
+
+* `started`: the planner and how many actions the plan has
+* the [`ProgressEvent`]s of each action and sub-action as it starts and finishes, and the warnings
+  each raised
+* `finished`: the outcome, and the chain of errors which caused a failure
+
+Unlike the receipt, which only holds the final state of each action, the log keeps every step. It
+is appended to, so an install reverted after failing, or a later uninstall, adds to the same file.
+Each event is written out before the next one, so a crash still leaves the events up to it.
+Unlike `--report-to`, nothing is redacted: the log stays on the host.
+
+Logging never changes the outcome: if the file cannot be written, a warning is logged and the
+install goes on.
+*/
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+use crate::{
+    report::{InstallOutcome, ProgressEvent},
+    InstallPlan, NixInstallerError,
+};
+
+/// What was being done when an event was logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Install,
+    Uninstall,
+}
+
+/// An event recorded in the log
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LoggedEvent {
+    Started {
+        planner: String,
+        actions: usize,
+    },
+    Finished {
+        status: InstallOutcome,
+        /// The kinds of the errors which caused a failure, as in the `result` of `--report-to`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        failure_chain: Option<Vec<String>>,
+        /// Each error which caused a failure, outermost first
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        errors: Vec<String>,
+    },
+    #[serde(untagged)]
+    Progress(ProgressEvent),
+}
+
+/// A line of the log
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct EventRecord {
+    pub timestamp_ms: u64,
+    pub operation: Operation,
+    #[serde(flatten)]
+    pub event: LoggedEvent,
+}
+
+#[derive(Debug)]
+struct Writer {
+    path: PathBuf,
+    /// Set once writing failed, so the warning is only logged once
+    failed: AtomicBool,
+}
+
+impl Writer {
+    /// Append `event` to the log, written out before returning
+    async fn append(&self, operation: Operation, event: LoggedEvent) {
+        let record = EventRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|since| since.as_millis().try_into().unwrap_or(u64::MAX))
+                .unwrap_or_default(),
+            operation,
+            event,
+        };
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::debug!(%e, "Failed to serialize event log entry");
+                return;
+            },
+        };
+        line.push('\n');
+        if let Err(e) = self.write(line.as_bytes()).await {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "Could not write to the event log `{}`, continuing without it: {e}",
+                    self.path.display()
+                );
+            } else {
+                tracing::debug!(%e, "Could not write to the event log");
+            }
+        }
+    }
+
+    async fn write(&self, line: &[u8]) -> std::io::Result<()> {
+        // Opened for each event, so a log which becomes writable again picks up from there
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line).await?;
+        file.flush().await
+    }
+}
+
+/// Appends the events of installs and uninstalls to a file, one line of JSON each
+#[derive(Debug)]
+pub struct EventLog {
+    writer: Arc<Writer>,
+    operation: Operation,
+    progress: Option<(UnboundedSender<ProgressEvent>, JoinHandle<()>)>,
+}
+
+impl EventLog {
+    /// Log to `path`, relative to the current directory if it is not absolute
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let path = match std::env::current_dir() {
+            Ok(current_dir) if path.is_relative() => current_dir.join(path),
+            _ => path.to_path_buf(),
+        };
+        Self {
+            writer: Arc::new(Writer {
+                path,
+                failed: AtomicBool::new(false),
+            }),
+            operation: Operation::Install,
+            progress: None,
+        }
+    }
+
+    /// Log the `started` event of `operation` on `plan`, which the events until the next `started` are part of
+    pub async fn started(&mut self, operation: Operation, plan: &InstallPlan) {
+        self.finish_progress().await;
+        self.operation = operation;
+        self.writer
+            .append(
+                operation,
+                LoggedEvent::Started {
+                    planner: plan.planner.typetag_name().to_string(),
+                    actions: plan.actions.len(),
+                },
+            )
+            .await;
+    }
+
+    /// A channel for [`InstallPlan::install_with_progress`] or [`InstallPlan::uninstall_with_progress`], each event is logged
+    pub fn progress(&mut self) -> UnboundedSender<ProgressEvent> {
+        if let Some((sender, _)) = &self.progress {
+            return sender.clone();
+        }
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let task = tokio::spawn(log_progress(self.writer.clone(), self.operation, receiver));
+        self.progress = Some((sender.clone(), task));
+        sender
+    }
+
+    /// Log the `finished` event, after any outstanding progress
+    pub async fn finished(&mut self, result: &Result<(), NixInstallerError>) {
+        self.finish_progress().await;
+        let (status, failure_chain, errors) = match result {
+            Ok(()) => (InstallOutcome::Success, None, vec![]),
+            Err(NixInstallerError::Cancelled) => (InstallOutcome::Cancelled, None, vec![]),
+            Err(err) => (
+                InstallOutcome::Failure,
+                Some(crate::report::failure_chain(err)),
+                error_chain(err),
+            ),
+        };
+        self.writer
+            .append(
+                self.operation,
+                LoggedEvent::Finished {
+                    status,
+                    failure_chain,
+                    errors,
+                },
+            )
+            .await;
+    }
+
+    /// Wait for the progress sent so far to be logged
+    async fn finish_progress(&mut self) {
+        if let Some((sender, task)) = self.progress.take() {
+            drop(sender);
+            let _ = task.await;
+        }
+    }
+}
+
+async fn log_progress(
+    writer: Arc<Writer>,
+    operation: Operation,
+    mut receiver: UnboundedReceiver<ProgressEvent>,
+) {
+    while let Some(event) = receiver.recv().await {
+        writer.append(operation, LoggedEvent::Progress(event)).await;
+    }
+}
+
+/// The message of `err` and of each error it was caused by
+fn error_chain(err: &NixInstallerError) -> Vec<String> {
+    let mut errors = vec![err.to_string()];
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        errors.push(err.to_string());
+        source = err.source();
+    }
+    errors
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::report::ActionOutcome;
+
+    async fn empty_plan() -> eyre::Result<InstallPlan> {
+        let planner = crate::planner::BuiltinPlanner::default().await?;
+        Ok(serde_json::from_value(serde_json::json!({
+            "planner": planner.boxed(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [],
+        }))?)
+    }
+
+    fn read_log(path: &Path) -> eyre::Result<Vec<EventRecord>> {
+        std::fs::read_to_string(path)?
+            .lines()
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn events_are_appended_as_they_happen() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("events.jsonl");
+        let plan = empty_plan().await?;
+
+        let mut log = EventLog::new(&path);
+        log.started(Operation::Install, &plan).await;
+        log.progress().send(ProgressEvent::ActionStarted {
+            index: 0,
+            total: 1,
+            action: "create_directory".into(),
+            synopsis: "Create directory `/nix`".into(),
+        })?;
+        log.progress().send(ProgressEvent::ActionFinished {
+            index: 0,
+            total: 1,
+            action: "create_directory".into(),
+            synopsis: "Create directory `/nix`".into(),
+            duration_ms: 3,
+            status: ActionOutcome::Failed,
+        })?;
+        log.finished(&Err(NixInstallerError::RecordingReceipt(
+            "/nix/receipt.json".into(),
+            std::io::Error::other("disk full"),
+        )))
+        .await;
+        log.started(Operation::Uninstall, &plan).await;
+        log.finished(&Ok(())).await;
+
+        let records = read_log(&path)?;
+        let events = records
+            .iter()
+            .map(|record| (record.operation, &record.event))
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 6, "{events:?}");
+        assert!(matches!(
+            events[0],
+            (Operation::Install, LoggedEvent::Started { actions: 0, .. })
+        ));
+        assert!(matches!(
+            events[2],
+            (
+                Operation::Install,
+                LoggedEvent::Progress(ProgressEvent::ActionFinished {
+                    status: ActionOutcome::Failed,
+                    ..
+                })
+            )
+        ));
+        let (_, LoggedEvent::Finished { status, errors, .. }) = events[3] else {
+            panic!("{:?}", events[3]);
+        };
+        assert_eq!(*status, InstallOutcome::Failure);
+        assert_eq!(errors, &["Recording install receipt", "disk full"]);
+        assert!(matches!(
+            events[5],
+            (
+                Operation::Uninstall,
+                LoggedEvent::Finished {
+                    status: InstallOutcome::Success,
+                    ..
+                }
+            )
+        ));
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].timestamp_ms <= pair[1].timestamp_ms));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn an_unwritable_log_is_skipped() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("events.jsonl");
+        let mut log = EventLog::new(&path);
+        log.started(Operation::Install, &empty_plan().await?).await;
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444))?;
+        if std::fs::OpenOptions::new().append(true).open(&path).is_ok() {
+            // Running as root, which may write it anyway
+            return Ok(());
+        }
+        log.finished(&Ok(())).await;
+        assert!(log.writer.failed.load(Ordering::Relaxed));
+        assert_eq!(read_log(&path)?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+/*! Home directories managed by `systemd-homed`
+
+`systemd-homed` keeps each home it manages in an image of its own (usually LUKS encrypted), which is
+only mounted while its user is logged in. Until then the home is missing or an empty mount point, so
+editing a file in it either fails or writes under the mount point, hidden once the home is
+activated. Activating a home needs its user's password, which `nix-installer` never asks for.
+
+Per-user file operations look up their user with [`homed_user`] first, and skip the files of an
+inactive home with a [`WarningKind::InactiveHome`] warning naming the users and what to do once
+they log in.
+*/
+
+use std::path::PathBuf;
+
+use tokio::process::Command;
+
+use crate::warning::{self, Warning, WarningKind};
+
+/// A user whose home is managed by `systemd-homed`, as `homectl list` shows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HomedUser {
+    pub(crate) name: String,
+    /// Such as `active`, `inactive`, `absent`, or `locked`
+    pub(crate) state: String,
+    pub(crate) home: PathBuf,
+}
+
+impl HomedUser {
+    /// If the home is mounted and unlocked, so its files can be edited
+    pub(crate) fn is_active(&self) -> bool {
+        self.state == "active"
+    }
+}
+
+/// The users whose homes are managed by `systemd-homed`, none where it is not running
+pub(crate) async fn homed_users() -> Vec<HomedUser> {
+    // `homectl` would list the homes of the machine planning, which the target machine does not
+    // share, so its homes are treated as ordinary ones
+    if crate::cross_target::is_active() {
+        return vec![];
+    }
+    let mut command = Command::new("homectl");
+    command.args(["list", "--no-legend", "--no-pager"]);
+    command.stdin(std::process::Stdio::null());
+    match crate::command_output(&mut command).await {
+        Ok(output) if output.status.success() => {
+            parse_homectl_list(&String::from_utf8_lossy(&output.stdout))
+        },
+        // Not installed, or `systemd-homed` is not running
+        Ok(output) => {
+            tracing::trace!(
+                stderr = %String::from_utf8_lossy(&output.stderr),
+                "`homectl list` failed, assuming no homes are managed by systemd-homed"
+            );
+            vec![]
+        },
+        Err(e) => {
+            tracing::trace!(%e, "Could not run `homectl`, assuming no homes are managed by systemd-homed");
+            vec![]
+        },
+    }
+}
+
+/// The user `name`, if `systemd-homed` manages its home
+pub(crate) async fn homed_user(name: &str) -> Option<HomedUser> {
+    homed_users()
+        .await
+        .into_iter()
+        .find(|homed_user| homed_user.name == name)
+}
+
+/// The user `name`, if `systemd-homed` manages its home and it is not active
+pub(crate) async fn inactive_home(name: &str) -> Option<HomedUser> {
+    homed_user(name)
+        .await
+        .filter(|homed_user| !homed_user.is_active())
+}
+
+/// Parse `homectl list --no-legend`, with the columns `NAME UID GID STATE REALNAME HOME SHELL`
+///
+/// The real name may have spaces or be empty, so the home and shell are read from the end.
+fn parse_homectl_list(output: &str) -> Vec<HomedUser> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let [name, uid, _gid, state, ..] = fields[..] else {
+                return None;
+            };
+            // A legend, printed by versions which ignore `--no-legend`
+            uid.parse::<u32>().ok()?;
+            let [.., home, _shell] = fields[4..] else {
+                return None;
+            };
+            Some(HomedUser {
+                name: name.to_string(),
+                state: state.to_string(),
+                home: PathBuf::from(home),
+            })
+        })
+        .collect()
+}
+
+/// Warn that `skipped` was not done for `user`, whose home is inactive, and what to do `then`, once
+/// they log in
+pub(crate) fn warn_inactive(user: &HomedUser, skipped: &str, then: &str) {
+    warning::warn(
+        WarningKind::InactiveHome,
+        format!(
+            "The home of `{name}` (`{home}`) is managed by systemd-homed and is {state}, so {skipped}. `nix-installer` does not activate homes, once `{name}` logs in, {then}",
+            name = user.name,
+            home = user.home.display(),
+            state = user.state,
+        ),
+    );
+}
+
+/// A section listing the per-user cleanups left for when their users log in, empty if there are
+/// none
+pub(crate) fn describe_deferred(warnings: &[Warning]) -> String {
+    let deferred = warnings
+        .iter()
+        .filter(|warning| warning.kind == WarningKind::InactiveHome)
+        .collect::<Vec<_>>();
+    if deferred.is_empty() {
+        return String::new();
+    }
+    let mut buf = "Deferred until their users log in:\n".to_string();
+    for warning in deferred {
+        buf.push_str(&format!("* {}\n", warning.message));
+    }
+    buf.push('\n');
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{describe_deferred, homed_user, inactive_home, parse_homectl_list, HomedUser};
+    use crate::{
+        test_harness::{FakeCommand, SandboxContext},
+        warning::{Warning, WarningKind},
+    };
+
+    const LIST: &str = "\
+        alice 60100 60100 active   Alice Liddell /home/alice /bin/bash\n\
+        bob   60200 60200 inactive Bob           /home/bob   /usr/bin/zsh\n\
+        carol 60300 60300 absent                 /home/carol /bin/bash\n";
+
+    #[test]
+    fn parses_homectl_list() {
+        assert_eq!(
+            parse_homectl_list(LIST),
+            vec![
+                HomedUser {
+                    name: "alice".into(),
+                    state: "active".into(),
+                    home: PathBuf::from("/home/alice"),
+                },
+                HomedUser {
+                    name: "bob".into(),
+                    state: "inactive".into(),
+                    home: PathBuf::from("/home/bob"),
+                },
+                HomedUser {
+                    name: "carol".into(),
+                    state: "absent".into(),
+                    home: PathBuf::from("/home/carol"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn legends_are_ignored() {
+        let list = format!(
+            "NAME  UID   GID   STATE    REALNAME HOME SHELL\n{LIST}\n3 home areas listed.\n"
+        );
+        assert_eq!(parse_homectl_list(&list), parse_homectl_list(LIST));
+        assert_eq!(parse_homectl_list("No home areas.\n"), vec![]);
+    }
+
+    #[tokio::test]
+    async fn only_inactive_homes_are_skipped() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake("homectl", FakeCommand::success().stdout(LIST));
+        sandbox
+            .scope(async {
+                assert!(inactive_home("alice").await.is_none());
+                assert_eq!(
+                    inactive_home("bob")
+                        .await
+                        .map(|homed_user| homed_user.state),
+                    Some("inactive".to_string())
+                );
+                assert!(inactive_home("carol").await.is_some());
+                // Not managed by systemd-homed
+                assert!(inactive_home("dave").await.is_none());
+            })
+            .await;
+        Ok(())
+    }
+
+    #[test]
+    fn deferred_cleanups_are_listed_apart() {
+        let warnings = [
+            Warning::new(WarningKind::RosettaShell, "Rosetta"),
+            Warning::new(WarningKind::InactiveHome, "The home of `bob`"),
+        ];
+        let deferred = describe_deferred(&warnings);
+        assert!(deferred.starts_with("Deferred until their users log in:\n"));
+        assert!(deferred.contains("* The home of `bob`\n"));
+        assert!(!deferred.contains("Rosetta"));
+        assert_eq!(describe_deferred(&warnings[..1]), "");
+    }
+
+    #[tokio::test]
+    async fn nothing_is_homed_without_homed() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake(
+            "homectl",
+            FakeCommand::failure(1).stderr(
+                "Failed to list homes: Unit dbus-org.freedesktop.home1.service not found.\n",
+            ),
+        );
+        assert!(sandbox.scope(homed_user("bob")).await.is_none());
+        Ok(())
+    }
+}
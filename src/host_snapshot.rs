@@ -0,0 +1,407 @@
+/*! A record of what the host looked like at install time, kept in the receipt for support triage
+
+Issues are often filed weeks after an install, once the machine has been upgraded. A
+[`HostSnapshot`] is collected while planning and stored in `/nix/receipt.json`, so the facts which
+most often explain a failure are still at hand. `nix-installer uninstall --explain` shows it.
+
+The set is deliberately small, and holds no identifying data (no hostname, user names, serial
+numbers, or network details):
+
+* `os_name` and `os_version`: from `/etc/os-release`, or `sw_vers` on macOS
+* `kernel_version` and `architecture`: from `uname`
+* `init`: PID 1 (with the systemd version when it is systemd), or `launchd` on macOS
+* `filesystem` and `free_disk`: of the filesystem `/nix` is (or will be) on
+* `total_memory`
+* `selinux`: `enforcing`, `permissive`, or `disabled` (Linux only)
+* `filevault`: `on` or `off` (macOS only)
+* `rosetta`: `translated` if the installer ran under Rosetta, or `native` (Apple silicon only)
+* `virtualization`: the container or hypervisor detected, or `none`
+* `ostree_deployment`: the checksum of the booted deployment, on an ostree system like Fedora IoT
+
+Collecting a fact never fails the install, anything which cannot be determined is recorded as
+[`UNKNOWN`]. Facts which do not apply to the host are recorded as [`NOT_APPLICABLE`].
+
+Diagnostics are unaffected, they only ever send the OS name, version, and triple they already did.
+*/
+
+use std::path::Path;
+
+use crate::util::host_path;
+
+/// The value of a fact which could not be determined
+pub const UNKNOWN: &str = "unknown";
+/// The value of a fact which does not apply to the host (like SELinux on macOS)
+pub const NOT_APPLICABLE: &str = "not applicable";
+
+/// The host at install time, see the [module documentation](self) for what each fact holds
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct HostSnapshot {
+    pub os_name: String,
+    pub os_version: String,
+    pub kernel_version: String,
+    pub architecture: String,
+    pub init: String,
+    pub filesystem: String,
+    pub total_memory: String,
+    pub free_disk: String,
+    pub selinux: String,
+    pub filevault: String,
+    pub rosetta: String,
+    pub virtualization: String,
+    pub ostree_deployment: String,
+}
+
+impl Default for HostSnapshot {
+    fn default() -> Self {
+        Self {
+            os_name: UNKNOWN.into(),
+            os_version: UNKNOWN.into(),
+            kernel_version: UNKNOWN.into(),
+            architecture: UNKNOWN.into(),
+            init: UNKNOWN.into(),
+            filesystem: UNKNOWN.into(),
+            total_memory: UNKNOWN.into(),
+            free_disk: UNKNOWN.into(),
+            selinux: UNKNOWN.into(),
+            filevault: UNKNOWN.into(),
+            rosetta: UNKNOWN.into(),
+            virtualization: UNKNOWN.into(),
+            ostree_deployment: UNKNOWN.into(),
+        }
+    }
+}
+
+impl HostSnapshot {
+    /// Collect a snapshot of this host, where Nix will be installed to `install_target` (`/nix`)
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn collect(install_target: &Path) -> Self {
+        let uname = nix::sys::utsname::uname().ok();
+        let on = existing_ancestor(install_target);
+        let mut snapshot = Self {
+            kernel_version: known(
+                uname
+                    .as_ref()
+                    .map(|v| v.release().to_string_lossy().to_string()),
+            ),
+            architecture: known(
+                uname
+                    .as_ref()
+                    .map(|v| v.machine().to_string_lossy().to_string()),
+            ),
+            filesystem: known(on.as_deref().and_then(filesystem_type)),
+            free_disk: known(on.as_deref().and_then(free_disk).map(format_bytes)),
+            ..Default::default()
+        };
+        collect_os(&mut snapshot).await;
+        snapshot
+    }
+
+    /// The facts as `(name, value)` pairs, in a stable order
+    pub fn facts(&self) -> [(&'static str, &str); 13] {
+        let Self {
+            os_name,
+            os_version,
+            kernel_version,
+            architecture,
+            init,
+            filesystem,
+            total_memory,
+            free_disk,
+            selinux,
+            filevault,
+            rosetta,
+            virtualization,
+            ostree_deployment,
+        } = self;
+        [
+            ("os_name", os_name),
+            ("os_version", os_version),
+            ("kernel_version", kernel_version),
+            ("architecture", architecture),
+            ("init", init),
+            ("filesystem", filesystem),
+            ("total_memory", total_memory),
+            ("free_disk", free_disk),
+            ("selinux", selinux),
+            ("filevault", filevault),
+            ("rosetta", rosetta),
+            ("virtualization", virtualization),
+            ("ostree_deployment", ostree_deployment),
+        ]
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn collect_os(snapshot: &mut HostSnapshot) {
+    let (os_name, os_version) = match os_release::OsRelease::new() {
+        Ok(os_release) => (Some(os_release.name), Some(os_release.version)),
+        Err(_) => (None, None),
+    };
+    snapshot.os_name = known(os_name);
+    snapshot.os_version = known(os_version);
+    snapshot.init = known(linux_init().await);
+    snapshot.total_memory = known(
+        nix::sys::sysinfo::sysinfo()
+            .ok()
+            .map(|v| format_bytes(v.ram_total())),
+    );
+    snapshot.selinux = match std::fs::read_to_string(host_path("/sys/fs/selinux/enforce")) {
+        Ok(enforce) => known(selinux_mode(&enforce)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => "disabled".into(),
+        Err(_) => UNKNOWN.into(),
+    };
+    snapshot.filevault = NOT_APPLICABLE.into();
+    snapshot.rosetta = NOT_APPLICABLE.into();
+    snapshot.virtualization = known(linux_virtualization().await);
+    snapshot.ostree_deployment = match host_path(crate::os::ostree::OSTREE_BOOTED).exists() {
+        true => known(
+            crate::os::ostree::OstreeStatus::query()
+                .await
+                .and_then(|status| status.booted().map(|booted| booted.checksum.clone())),
+        ),
+        false => NOT_APPLICABLE.into(),
+    };
+}
+
+#[cfg(target_os = "macos")]
+async fn collect_os(snapshot: &mut HostSnapshot) {
+    use sysctl::{Ctl, Sysctl};
+
+    snapshot.os_name = known(stdout_of("/usr/bin/sw_vers", &["-productName"]).await);
+    snapshot.os_version = known(stdout_of("/usr/bin/sw_vers", &["-productVersion"]).await);
+    snapshot.init = "launchd".into();
+    snapshot.total_memory = known(
+        Ctl::new("hw.memsize")
+            .and_then(|ctl| ctl.value_string())
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(format_bytes),
+    );
+    snapshot.selinux = NOT_APPLICABLE.into();
+    snapshot.ostree_deployment = NOT_APPLICABLE.into();
+    snapshot.filevault = known(
+        stdout_of("/usr/bin/fdesetup", &["isactive"])
+            .await
+            .and_then(|v| match v.as_str() {
+                "true" => Some("on".to_string()),
+                "false" => Some("off".to_string()),
+                _ => None,
+            }),
+    );
+    // Only Apple silicon has Rosetta, and so `sysctl.proc_translated`
+    let translated = Ctl::new("sysctl.proc_translated").and_then(|ctl| ctl.value_string());
+    snapshot.rosetta = match translated {
+        Ok(translated) if translated.trim() == "1" => "translated".into(),
+        Ok(_) => "native".into(),
+        Err(sysctl::SysctlError::NotFound(_)) => NOT_APPLICABLE.into(),
+        Err(_) => UNKNOWN.into(),
+    };
+    snapshot.virtualization = known(
+        Ctl::new("kern.hv_vmm_present")
+            .and_then(|ctl| ctl.value_string())
+            .ok()
+            .and_then(|v| match v.trim() {
+                "1" => Some("vm".to_string()),
+                "0" => Some("none".to_string()),
+                _ => None,
+            }),
+    );
+}
+
+/// PID 1, along with the systemd version if it is systemd
+#[cfg(target_os = "linux")]
+async fn linux_init() -> Option<String> {
+    let init = std::fs::read_to_string(host_path("/proc/1/comm")).ok()?;
+    let init = init.trim();
+    if init != "systemd" {
+        return (!init.is_empty()).then(|| init.to_string());
+    }
+    Some(
+        stdout_of("systemctl", &["--version"])
+            .await
+            .as_deref()
+            .and_then(systemd_version)
+            .unwrap_or_else(|| init.to_string()),
+    )
+}
+
+/// The container or hypervisor this host runs in, preferring `systemd-detect-virt`'s opinion
+#[cfg(target_os = "linux")]
+async fn linux_virtualization() -> Option<String> {
+    if let Some(detected) = stdout_of("systemd-detect-virt", &[]).await {
+        return Some(detected);
+    }
+    if std::env::var("WSL_DISTRO_NAME").is_ok() {
+        Some("wsl".into())
+    } else if host_path("/.dockerenv").exists() {
+        Some("docker".into())
+    } else if host_path("/run/.containerenv").exists() {
+        Some("podman".into())
+    } else {
+        None
+    }
+}
+
+/// The trimmed stdout of a command, if it printed anything
+///
+/// Some commands (like `systemd-detect-virt` or `fdesetup isactive`) exit non-zero for a negative
+/// answer, so the exit status is ignored.
+async fn stdout_of(program: &str, args: &[&str]) -> Option<String> {
+    let output = crate::command_output(
+        tokio::process::Command::new(program)
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .process_group(0),
+    )
+    .await
+    .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!stdout.is_empty()).then_some(stdout)
+}
+
+fn known(fact: Option<String>) -> String {
+    fact.filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| UNKNOWN.into())
+}
+
+/// The closest ancestor of `path` which exists, since `/nix` usually does not yet while planning
+fn existing_ancestor(path: &Path) -> Option<std::path::PathBuf> {
+    host_path(path)
+        .ancestors()
+        .find(|v| v.exists())
+        .map(std::path::Path::to_path_buf)
+}
+
+#[cfg(target_os = "linux")]
+fn filesystem_type(path: &Path) -> Option<String> {
+    let statfs = nix::sys::statfs::statfs(path).ok()?;
+    Some(linux_filesystem_name(statfs.filesystem_type().0 as u64))
+}
+
+#[cfg(target_os = "macos")]
+fn filesystem_type(path: &Path) -> Option<String> {
+    let statfs = nix::sys::statfs::statfs(path).ok()?;
+    Some(statfs.filesystem_type_name().to_string())
+}
+
+fn free_disk(path: &Path) -> Option<u64> {
+    let statvfs = nix::sys::statvfs::statvfs(path).ok()?;
+    (statvfs.blocks_available() as u64).checked_mul(statvfs.fragment_size() as u64)
+}
+
+/// Names for the filesystem magic numbers from `statfs(2)` which `/nix` is plausibly on
+#[cfg(any(test, target_os = "linux"))]
+fn linux_filesystem_name(magic: u64) -> String {
+    let name = match magic {
+        0xEF53 => "ext4",
+        0x9123683E => "btrfs",
+        0x58465342 => "xfs",
+        0x2FC12FC1 => "zfs",
+        0xCA451A4E => "bcachefs",
+        0xF2F52010 => "f2fs",
+        0x01021994 => "tmpfs",
+        0x794C7630 => "overlayfs",
+        0x65735546 => "fuse",
+        0x6969 => "nfs",
+        0xFF534D42 => "cifs",
+        0x01021997 => "v9fs",
+        0x52654973 => "reiserfs",
+        0x3153464A => "jfs",
+        0x4D44 => "vfat",
+        _ => return format!("{magic:#x}"),
+    };
+    name.to_string()
+}
+
+/// The version from the first line of `systemctl --version`, like `systemd 255 (255.4-1ubuntu8)`
+#[cfg(any(test, target_os = "linux"))]
+fn systemd_version(output: &str) -> Option<String> {
+    let mut words = output.lines().next()?.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("systemd"), Some(version)) => Some(format!("systemd {version}")),
+        _ => None,
+    }
+}
+
+/// The mode named by the contents of `/sys/fs/selinux/enforce`
+#[cfg(any(test, target_os = "linux"))]
+fn selinux_mode(enforce: &str) -> Option<String> {
+    match enforce.trim() {
+        "1" => Some("enforcing".into()),
+        "0" => Some("permissive".into()),
+        _ => None,
+    }
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1024 * 1024 * 1024) as f64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        format_bytes, linux_filesystem_name, selinux_mode, systemd_version, HostSnapshot, UNKNOWN,
+    };
+
+    #[test]
+    fn parses_facts() {
+        assert_eq!(linux_filesystem_name(0xEF53), "ext4");
+        assert_eq!(linux_filesystem_name(0x9123683E), "btrfs");
+        assert_eq!(linux_filesystem_name(0x12345678), "0x12345678");
+        assert_eq!(
+            systemd_version("systemd 255 (255.4-1ubuntu8)\n+PAM +AUDIT +SELINUX\n").as_deref(),
+            Some("systemd 255")
+        );
+        assert_eq!(systemd_version("not systemd\n"), None);
+        assert_eq!(selinux_mode("1\n").as_deref(), Some("enforcing"));
+        assert_eq!(selinux_mode("0").as_deref(), Some("permissive"));
+        assert_eq!(selinux_mode(""), None);
+        assert_eq!(format_bytes(16 * 1024 * 1024 * 1024), "16.0 GiB");
+        assert_eq!(format_bytes(512 * 1024 * 1024), "0.5 GiB");
+    }
+
+    #[test]
+    fn missing_facts_are_unknown() -> eyre::Result<()> {
+        // Receipts written before a fact was collected lack it
+        let snapshot: HostSnapshot = serde_json::from_str(r#"{ "os_name": "NixOS" }"#)?;
+        assert_eq!(snapshot.os_name, "NixOS");
+        assert_eq!(snapshot.free_disk, UNKNOWN);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn collects_in_a_bare_sandbox() -> eyre::Result<()> {
+        use crate::test_harness::{FakeCommand, SandboxContext};
+
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/proc/1"))?;
+        std::fs::write(sandbox.path("/proc/1/comm"), "systemd\n")?;
+        std::fs::create_dir_all(sandbox.path("/sys/fs/selinux"))?;
+        std::fs::write(sandbox.path("/sys/fs/selinux/enforce"), "0")?;
+        sandbox.fake(
+            "systemctl",
+            FakeCommand::success().stdout("systemd 256 (256.8)\n"),
+        );
+        // No `systemd-detect-virt` opinion, and no container markers
+        sandbox.fake("systemd-detect-virt", FakeCommand::failure(1));
+
+        let snapshot = sandbox
+            .scope(HostSnapshot::collect(&sandbox.path("/nix")))
+            .await;
+        assert_eq!(snapshot.init, "systemd 256");
+        assert_eq!(snapshot.selinux, "permissive");
+        assert_eq!(snapshot.filevault, super::NOT_APPLICABLE);
+        assert_eq!(snapshot.rosetta, super::NOT_APPLICABLE);
+        assert_eq!(snapshot.ostree_deployment, super::NOT_APPLICABLE);
+        if std::env::var("WSL_DISTRO_NAME").is_err() {
+            assert_eq!(snapshot.virtualization, UNKNOWN);
+        }
+        // `/nix` does not exist in the sandbox, so its parent's filesystem is used
+        assert_ne!(snapshot.filesystem, UNKNOWN);
+        assert_ne!(snapshot.free_disk, UNKNOWN);
+        Ok(())
+    }
+}
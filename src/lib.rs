@@ -70,25 +70,86 @@ match plan.install(None).await {
 # }
 ```
 
+To install the plan of a planner with a few actions removed or added, edit it with
+[`InstallPlan::builder`], which refuses to remove actions others depend on:
+
+```rust,no_run
+use nix_installer::{action::{base::CreateDirectory, Action}, InstallPlan, planner::Planner};
+
+# async fn edited_install() -> color_eyre::Result<()> {
+#[cfg(target_os = "linux")]
+let planner = nix_installer::planner::linux::Linux::default().await?;
+#[cfg(target_os = "macos")]
+let planner = nix_installer::planner::macos::Macos::default().await?;
+
+let mut plan = InstallPlan::builder(planner)
+    // The image ships its own flake registry and profile
+    .remove_action("install_default_profile_flakes")
+    .insert_after(
+        "configure_nix",
+        CreateDirectory::plan("/nix/var/ci-cache", None, None, 0o0755, true)
+            .await?
+            .boxed(),
+    )
+    .build()
+    .await?;
+plan.install(None).await?;
+#
+# Ok(())
+# }
+```
+
 */
 
 pub mod action;
+pub mod audit;
+mod backup;
+pub mod build_info;
 #[cfg(feature = "cli")]
 pub mod cli;
+pub mod cross_target;
+mod daemon_capabilities;
+mod daemon_socket;
+pub mod deprecation;
 #[cfg(feature = "diagnostics")]
 pub mod diagnostics;
+mod distribution;
+pub mod drift;
 mod error;
+pub mod event_log;
+mod homed;
+pub mod host_snapshot;
+pub mod managed_files;
+pub mod messages;
+pub mod network_probe;
+mod nix_settings;
 mod os;
+pub mod phase;
 mod plan;
+mod plan_builder;
 pub mod planner;
+mod profile;
+mod proxy;
+mod replace_file;
+pub mod report;
 pub mod self_test;
 pub mod settings;
+mod stale_build_users;
+mod state_dir;
+#[cfg(any(test, feature = "test-harness"))]
+pub mod test_harness;
+pub mod uninstall_impact;
 mod util;
+pub mod warning;
 
 use std::{ffi::OsStr, path::Path, process::Output};
 
 pub use error::NixInstallerError;
-pub use plan::InstallPlan;
+pub use plan::{
+    ActionSummary, ExtraPlan, ExtraPlanError, InstallPlan, PlanDescription, PrivilegedOperations,
+    PrivilegedOperationsError,
+};
+pub use plan_builder::{InstallPlanBuilder, InstallPlanBuilderError};
 use planner::BuiltinPlanner;
 
 use reqwest::Certificate;
@@ -99,10 +160,27 @@ use crate::action::{Action, ActionErrorKind};
 #[tracing::instrument(level = "debug", skip_all, fields(command = %format!("{:?}", command.as_std())))]
 async fn execute_command(command: &mut Command) -> Result<Output, ActionErrorKind> {
     tracing::trace!("Executing");
-    let output = command
-        .output()
+    let output = command_output(command)
         .await
         .map_err(|e| ActionErrorKind::command(command, e))?;
+    checked_output(command, output)
+}
+
+/// Like [`execute_command`], writing `stdin` to the command, such as a passphrase which must not
+/// appear in its arguments
+#[tracing::instrument(level = "debug", skip_all, fields(command = %format!("{:?}", command.as_std())))]
+async fn execute_command_with_stdin(
+    command: &mut Command,
+    stdin: &[u8],
+) -> Result<Output, ActionErrorKind> {
+    tracing::trace!("Executing");
+    let output = command_output_with_stdin(command, stdin)
+        .await
+        .map_err(|e| ActionErrorKind::command(command, e))?;
+    checked_output(command, output)
+}
+
+fn checked_output(command: &Command, output: Output) -> Result<Output, ActionErrorKind> {
     match output.status.success() {
         true => {
             tracing::trace!(
@@ -116,6 +194,44 @@ async fn execute_command(command: &mut Command) -> Result<Output, ActionErrorKin
     }
 }
 
+/// Run `command` to completion, collecting its output
+///
+/// Prefer [`execute_command`] unless a non-zero exit is an expected outcome.
+async fn command_output(command: &mut Command) -> std::io::Result<Output> {
+    if cross_target::is_active() {
+        return Err(cross_target::command_error(command));
+    }
+    #[cfg(any(test, feature = "test-harness"))]
+    if let Some(output) = crate::test_harness::intercept(command) {
+        return Ok(output);
+    }
+    command.output().await
+}
+
+/// Run `command` to completion with `stdin` as its input, collecting its output
+async fn command_output_with_stdin(command: &mut Command, stdin: &[u8]) -> std::io::Result<Output> {
+    use tokio::io::AsyncWriteExt as _;
+
+    if cross_target::is_active() {
+        return Err(cross_target::command_error(command));
+    }
+    #[cfg(any(test, feature = "test-harness"))]
+    if let Some(output) = crate::test_harness::intercept(command) {
+        return Ok(output);
+    }
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    if let Some(mut input) = child.stdin.take() {
+        input.write_all(stdin).await?;
+        // Closed so the command sees the end of its input
+        drop(input);
+    }
+    child.wait_with_output().await
+}
+
 #[tracing::instrument(level = "debug", skip_all, fields(
     k = %k.as_ref().to_string_lossy(),
     v = %v.as_ref().to_string_lossy(),
@@ -125,6 +241,26 @@ fn set_env(k: impl AsRef<OsStr>, v: impl AsRef<OsStr>) {
     std::env::set_var(k.as_ref(), v.as_ref());
 }
 
+/// A client builder for the HTTP fetches, going through `proxy` and trusting `ssl_cert_file`
+///
+/// Everything fetching over HTTP builds its client from this, so `self-test network` probes the
+/// same configuration an install fetches with.
+pub(crate) async fn http_client_builder(
+    proxy: Option<&reqwest::Url>,
+    ssl_cert_file: Option<&Path>,
+) -> Result<reqwest::ClientBuilder, CertificateError> {
+    let mut buildable_client = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        buildable_client =
+            buildable_client.proxy(crate::proxy::proxy(proxy).map_err(CertificateError::Reqwest)?);
+    }
+    if let Some(ssl_cert_file) = ssl_cert_file {
+        let ssl_cert = parse_ssl_cert(ssl_cert_file).await?;
+        buildable_client = buildable_client.add_root_certificate(ssl_cert);
+    }
+    Ok(buildable_client)
+}
+
 async fn parse_ssl_cert(ssl_cert_file: &Path) -> Result<Certificate, CertificateError> {
     let cert_buf = tokio::fs::read(ssl_cert_file)
         .await
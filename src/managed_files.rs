@@ -0,0 +1,314 @@
+/*! An index of the files `nix-installer` wrote, kept alongside the receipt
+
+Each [`WrittenFile`] is recorded in the state of the action which wrote it, so finding them means
+reading the whole receipt and walking every action in it, however deeply nested. As an action
+writes a file it [`register`]s it, and each time the receipt at
+[`RECEIPT_LOCATION`](crate::plan::RECEIPT_LOCATION) is written, [`MANAGED_FILES`] in the
+[state directory](crate::state_dir) is written after it, listing every file along with the action
+which wrote it.
+
+The index records the SHA-256 of the receipt it was written with, and is only read while that still
+matches. Should `nix-installer` stop between writing the two, or the receipt be edited, the files
+are walked from the receipt instead, as they are when there is no index at all. `inspect` and the
+impact report of `uninstall --impact-report` read the index.
+*/
+
+use std::{
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    action::ActionTag,
+    drift::{written_files, WrittenFile},
+    plan::InstallPlan,
+    replace_file::{replace_file, Attributes},
+    util::host_path,
+};
+
+/// The name of the index in the state directory
+pub const MANAGED_FILES: &str = "managed-files.json";
+
+/// The layout of [`ManagedFileIndex`], an index of another layout is not read
+pub const MANAGED_FILES_SCHEMA: u32 = 1;
+
+tokio::task_local! {
+    static REGISTRY: Arc<Mutex<Vec<ManagedFile>>>;
+}
+
+/// A file `nix-installer` wrote, and the action which wrote it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ManagedFile {
+    #[serde(flatten)]
+    pub written: WrittenFile,
+    /// The tag of the action which wrote it, such as `create_file`
+    pub action: String,
+}
+
+/// The content of [`MANAGED_FILES`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ManagedFileIndex {
+    /// See [`MANAGED_FILES_SCHEMA`]
+    pub schema: u32,
+    /// The SHA-256 of the receipt the index was written with
+    pub receipt_sha256: String,
+    /// In the order the actions writing them appear in the receipt
+    pub files: Vec<ManagedFile>,
+}
+
+/// Record that the action tagged `action` wrote `written`, if executing within [`registering`]
+pub(crate) fn register(action: ActionTag, written: &WrittenFile) {
+    let _ = REGISTRY.try_with(|registry| {
+        registry
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(ManagedFile {
+                written: written.clone(),
+                action: action.to_string(),
+            });
+    });
+}
+
+/// Run `fut`, returning the files it [`register`]ed alongside its output
+pub(crate) async fn registering<F: Future>(fut: F) -> (F::Output, Vec<ManagedFile>) {
+    let registry = Arc::new(Mutex::new(vec![]));
+    let output = REGISTRY.scope(registry.clone(), fut).await;
+    let registered = std::mem::take(
+        &mut *registry
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    );
+    (output, registered)
+}
+
+/// The index of `plan`, written to its receipt as `receipt`
+///
+/// Each file is attributed to the action which registered it, or which did according to
+/// `previous`, otherwise to the top level action recording it.
+pub(crate) fn index(
+    plan: &InstallPlan,
+    receipt: &[u8],
+    previous: &[ManagedFile],
+) -> Result<ManagedFileIndex, serde_json::Error> {
+    let mut files = vec![];
+    for action in &plan.actions {
+        for written in written_files(&serde_json::to_value(action)?) {
+            let owner = plan
+                .managed_files
+                .iter()
+                .rev()
+                .chain(previous)
+                .find(|known| known.written.path == written.path)
+                .map(|known| known.action.clone())
+                .unwrap_or_else(|| action.inner_typetag_name().to_string());
+            files.push(ManagedFile {
+                written,
+                action: owner,
+            });
+        }
+    }
+    Ok(ManagedFileIndex {
+        schema: MANAGED_FILES_SCHEMA,
+        receipt_sha256: crate::backup::sha256(receipt),
+        files,
+    })
+}
+
+/// Write the index of `plan` to its state directory, once its receipt was written as `receipt`
+/// over `previous_receipt`
+///
+/// The index is only a shortcut, so failing to write it is logged rather than returned.
+pub(crate) async fn record(plan: &InstallPlan, previous_receipt: Option<&[u8]>, receipt: &[u8]) {
+    let path = crate::state_dir::of_plan(plan).join(MANAGED_FILES);
+    let previous = previous_receipt
+        .and_then(|previous_receipt| read(&path, previous_receipt))
+        .map(|index| index.files)
+        .unwrap_or_default();
+    if let Err(e) = write(&path, plan, receipt, &previous).await {
+        tracing::debug!(
+            "Could not write the managed file index `{}`, it is walked from the receipt instead: {e}",
+            path.display()
+        );
+    }
+}
+
+async fn write(
+    path: &Path,
+    plan: &InstallPlan,
+    receipt: &[u8],
+    previous: &[ManagedFile],
+) -> std::io::Result<()> {
+    let index = index(plan, receipt, previous)?;
+    let content = serde_json::to_vec_pretty(&index)?;
+    let path = host_path(path);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    replace_file(&path, &content, Attributes::mode(0o644))
+        .await
+        .map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+/// The index at `path`, if it was written with `receipt` in the current layout
+fn read(path: &Path, receipt: &[u8]) -> Option<ManagedFileIndex> {
+    let content = std::fs::read(host_path(path)).ok()?;
+    let index = serde_json::from_slice::<ManagedFileIndex>(&content).ok()?;
+    if index.schema != MANAGED_FILES_SCHEMA {
+        tracing::debug!(
+            schema = index.schema,
+            "Ignoring a managed file index of another layout"
+        );
+        return None;
+    }
+    if index.receipt_sha256 != crate::backup::sha256(receipt) {
+        tracing::debug!("Ignoring a managed file index written with another receipt");
+        return None;
+    }
+    Some(index)
+}
+
+/// The files indexed for the receipt `receipt`, installed with the state directory `state_dir`,
+/// if the index was written with it
+pub(crate) fn indexed(state_dir: &Path, receipt: &[u8]) -> Option<Vec<ManagedFile>> {
+    read(&state_dir.join(MANAGED_FILES), receipt).map(|index| index.files)
+}
+
+/// Like [`indexed`], reading the receipt at `receipt` and the state directory recorded in the plan
+pub(crate) fn indexed_for(plan: &InstallPlan, receipt: &Path) -> Option<Vec<ManagedFile>> {
+    let content = std::fs::read(receipt).ok()?;
+    indexed(&crate::state_dir::of_plan(plan), &content)
+}
+
+/// Every file written according to `receipt`, parsed as `value`: from the index if it was written
+/// with `receipt`, otherwise walked from `value`
+pub(crate) fn files_of_receipt(receipt: &[u8], value: &serde_json::Value) -> Vec<WrittenFile> {
+    let state_dir = crate::state_dir::of_receipt_json(value);
+    match indexed(&state_dir, receipt) {
+        Some(files) => files.into_iter().map(|file| file.written).collect(),
+        None => written_files(value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        files_of_receipt, index, record, register, registering, ManagedFile, MANAGED_FILES,
+    };
+    use crate::{
+        action::{base::CreateFile, ActionTag},
+        drift::WrittenFile,
+        plan::InstallPlan,
+        test_harness::SandboxContext,
+    };
+
+    async fn plan_writing(path: &str, content: &str) -> eyre::Result<InstallPlan> {
+        let planner = crate::planner::BuiltinPlanner::default().await?;
+        let mut create_file = CreateFile::plan(path, None, None, 0o644, content.into(), false)
+            .await?
+            .boxed();
+        create_file.state = crate::action::ActionState::Completed;
+        let mut action = serde_json::to_value(&create_file)?;
+        action["action"]["written_file"] =
+            serde_json::to_value(WrittenFile::whole(path, content.as_bytes()))?;
+        Ok(serde_json::from_value(serde_json::json!({
+            "planner": planner.boxed(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [action],
+        }))?)
+    }
+
+    #[tokio::test]
+    async fn files_are_registered_within_the_scope() {
+        let written = WrittenFile::whole("/etc/nix/nix.conf", b"sandbox = true\n");
+        register(ActionTag("create_file"), &written);
+        let ((), registered) = registering(async {
+            register(ActionTag("create_or_merge_nix_config"), &written);
+        })
+        .await;
+        assert_eq!(
+            registered,
+            [ManagedFile {
+                written,
+                action: "create_or_merge_nix_config".into(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn files_are_attributed_to_the_action_registering_them() -> eyre::Result<()> {
+        let mut plan = plan_writing("/etc/nix/custom.conf", "sandbox = true\n").await?;
+        let indexed = index(&plan, b"{}", &[])?;
+        assert_eq!(indexed.files.len(), 1);
+        assert_eq!(indexed.files[0].action, "create_file");
+
+        let previous = vec![ManagedFile {
+            written: indexed.files[0].written.clone(),
+            action: "configure_shell_profile".into(),
+        }];
+        assert_eq!(
+            index(&plan, b"{}", &previous)?.files[0].action,
+            "configure_shell_profile"
+        );
+        plan.managed_files.push(ManagedFile {
+            written: indexed.files[0].written.clone(),
+            action: "create_or_insert_into_file".into(),
+        });
+        assert_eq!(
+            index(&plan, b"{}", &previous)?.files[0].action,
+            "create_or_insert_into_file"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn the_index_is_only_read_with_its_receipt() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let plan = plan_writing("/etc/nix/custom.conf", "sandbox = true\n").await?;
+        let receipt = serde_json::to_vec(&plan)?;
+        let value = serde_json::from_slice::<serde_json::Value>(&receipt)?;
+        sandbox.scope(record(&plan, None, &receipt)).await;
+        let index_path = sandbox
+            .path(crate::state_dir::DEFAULT_STATE_DIR)
+            .join(MANAGED_FILES);
+        assert!(index_path.exists());
+
+        // Listed in the index, but not the receipt, so only found through the index
+        let mut indexed =
+            serde_json::from_slice::<super::ManagedFileIndex>(&std::fs::read(&index_path)?)?;
+        indexed.files.push(ManagedFile {
+            written: WrittenFile::whole("/etc/bashrc", b""),
+            action: "create_or_insert_into_file".into(),
+        });
+        std::fs::write(&index_path, serde_json::to_vec(&indexed)?)?;
+        let paths = |files: Vec<WrittenFile>| {
+            files
+                .into_iter()
+                .map(|file| file.path.display().to_string())
+                .collect::<Vec<_>>()
+        };
+        let found = sandbox
+            .scope(async { files_of_receipt(&receipt, &value) })
+            .await;
+        assert_eq!(paths(found), ["/etc/nix/custom.conf", "/etc/bashrc"]);
+
+        // The receipt was rewritten without the index, as if interrupted between the two
+        let edited = [receipt.as_slice(), b"\n"].concat();
+        let found = sandbox
+            .scope(async { files_of_receipt(&edited, &value) })
+            .await;
+        assert_eq!(paths(found), ["/etc/nix/custom.conf"]);
+
+        // Rewriting the index keeps the actions it attributed the files to
+        indexed.files[0].action = "configure_shell_profile".into();
+        std::fs::write(&index_path, serde_json::to_vec(&indexed)?)?;
+        sandbox.scope(record(&plan, Some(&receipt), &edited)).await;
+        let rewritten =
+            serde_json::from_slice::<super::ManagedFileIndex>(&std::fs::read(&index_path)?)?;
+        assert_eq!(rewritten.receipt_sha256, crate::backup::sha256(&edited));
+        assert_eq!(rewritten.files.len(), 1);
+        assert_eq!(rewritten.files[0].action, "configure_shell_profile");
+        Ok(())
+    }
+}
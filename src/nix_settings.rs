@@ -0,0 +1,454 @@
+/*! Settings known to the Nix releases `nix-installer` installs
+
+`nix.conf` settings Nix does not recognize are only warned about when Nix runs, which is easy to
+miss, so the settings `nix-installer` writes are checked against these tables while planning.
+
+# Updating the tables
+
+When the `nix` input in `flake.nix` moves to a new release series:
+
+1. Add a table for the series, listing every key printed by
+   `nix --extra-experimental-features '<features>' config show --json | jq -r 'keys[]'` with the new
+   Nix, where `<features>` is every feature on the manual's _Experimental Features_ page. Settings
+   gated behind an experimental feature are only printed while it is enabled.
+2. Add the aliases Nix still accepts, which `config show` does not print. The `nix.conf` manual
+   page lists them under each setting as _Deprecated alias_.
+3. Point [`BUNDLED_NIX_SERIES`] at the new table, and keep the previous table as long as that
+   series can still be installed with `--nix-package-url`.
+
+`extra-` prefixed forms are never listed, they are accepted for any known setting.
+*/
+
+use crate::settings::UrlOrPath;
+
+/// The release series of the Nix bundled with this `nix-installer`
+pub(crate) const BUNDLED_NIX_SERIES: &str = "2.25";
+
+/// The earliest release series `--daemon-user` may install, running the daemon as a non-root user
+pub(crate) const DAEMON_USER_MIN_SERIES: &str = "2.25";
+
+/// Every setting known to each supported Nix release series
+const KNOWN_SETTINGS: &[(&str, &[&str])] = &[("2.25", NIX_2_25)];
+
+const NIX_2_25: &[&str] = &[
+    "abort-on-warn",
+    "accept-flake-config",
+    "access-tokens",
+    "allow-dirty",
+    "allow-import-from-derivation",
+    "allow-new-privileges",
+    "allow-symlinked-store",
+    "allow-unsafe-native-code-during-evaluation",
+    "allowed-impure-host-deps",
+    "allowed-uris",
+    "allowed-users",
+    "always-allow-substitutes",
+    "auto-allocate-uids",
+    "auto-optimise-store",
+    "bash-prompt",
+    "bash-prompt-prefix",
+    "bash-prompt-suffix",
+    "build-dir",
+    "build-hook",
+    "build-poll-interval",
+    "build-users-group",
+    "builders",
+    "builders-use-substitutes",
+    "commit-lock-file-summary",
+    "compress-build-log",
+    "connect-timeout",
+    "cores",
+    "darwin-log-sandbox-violations",
+    "debugger-on-trace",
+    "diff-hook",
+    "download-attempts",
+    "download-buffer-size",
+    "download-speed",
+    "eval-cache",
+    "eval-system",
+    "experimental-features",
+    "extra-platforms",
+    "fallback",
+    "filter-syscalls",
+    "flake-registry",
+    "fsync-metadata",
+    "fsync-store-paths",
+    "gc-reserved-space",
+    "hashed-mirrors",
+    "http-connections",
+    "http2",
+    "id-count",
+    "ignore-try",
+    "ignored-acls",
+    "impersonate-linux-26",
+    "impure-env",
+    "keep-build-log",
+    "keep-derivations",
+    "keep-env-derivations",
+    "keep-failed",
+    "keep-going",
+    "keep-outputs",
+    "log-lines",
+    "max-build-log-size",
+    "max-call-depth",
+    "max-free",
+    "max-jobs",
+    "max-silent-time",
+    "max-substitution-jobs",
+    "min-free",
+    "min-free-check-interval",
+    "nar-buffer-size",
+    "narinfo-cache-negative-ttl",
+    "narinfo-cache-positive-ttl",
+    "netrc-file",
+    "nix-path",
+    "nix-shell-always-looks-for-shell-nix",
+    "nix-shell-shebang-arguments-relative-to-script",
+    "plugin-files",
+    "post-build-hook",
+    "pre-build-hook",
+    "preallocate-contents",
+    "print-missing",
+    "pure-eval",
+    "repl-overlays",
+    "require-drop-supplementary-groups",
+    "require-sigs",
+    "restrict-eval",
+    "run-diff-hook",
+    "sandbox",
+    "sandbox-build-dir",
+    "sandbox-dev-shm-size",
+    "sandbox-fallback",
+    "sandbox-paths",
+    "secret-key-files",
+    "show-trace",
+    "ssl-cert-file",
+    "stalled-download-timeout",
+    "start-id",
+    "store",
+    "substitute",
+    "substituters",
+    "sync-before-registering",
+    "system",
+    "system-features",
+    "tarball-ttl",
+    "timeout",
+    "trace-function-calls",
+    "trace-import-from-derivation",
+    "trace-verbose",
+    "trust-tarballs-from-git-forges",
+    "trusted-public-keys",
+    "trusted-substituters",
+    "trusted-users",
+    "upgrade-nix-store-path-url",
+    "use-case-hack",
+    "use-cgroups",
+    "use-registries",
+    "use-sqlite-wal",
+    "use-xdg-base-directories",
+    "user-agent-suffix",
+    "warn-dirty",
+    "warn-large-path-threshold",
+    // Aliases
+    "binary-cache-public-keys",
+    "binary-caches",
+    "build-cores",
+    "build-extra-chroot-dirs",
+    "build-extra-sandbox-paths",
+    "build-fallback",
+    "build-max-jobs",
+    "build-max-log-size",
+    "build-max-silent-time",
+    "build-timeout",
+    "build-use-chroot",
+    "build-use-sandbox",
+    "build-use-substitutes",
+    "commit-lockfile-summary",
+    "env-keep-derivations",
+    "gc-keep-derivations",
+    "gc-keep-outputs",
+    "trusted-binary-caches",
+];
+
+/// Settings whose values are credentials
+pub(crate) const SECRET_SETTINGS: &[&str] = &["access-tokens"];
+
+/// If a `nix.conf` line sets one of the [`SECRET_SETTINGS`], or the `extra-` form of one
+pub(crate) fn sets_secret(line: &str) -> bool {
+    let Some((key, _)) = line.split_once('=') else {
+        return false;
+    };
+    let key = key.trim();
+    let key = key.strip_prefix("extra-").unwrap_or(key);
+    SECRET_SETTINGS.contains(&key)
+}
+
+/// The settings known to the Nix `series` (such as `2.25`), if it is supported
+pub(crate) fn known_settings(series: &str) -> Option<&'static [&'static str]> {
+    KNOWN_SETTINGS
+        .iter()
+        .find(|(known_series, _)| *known_series == series)
+        .map(|(_, settings)| *settings)
+}
+
+/// The release series of the Nix `nix_package_url` installs, the bundled one without a URL
+///
+/// Release tarballs are named like `nix-2.25.3-x86_64-linux.tar.xz`, `None` if the URL names no version.
+pub(crate) fn series_to_install(nix_package_url: Option<&UrlOrPath>) -> Option<String> {
+    let Some(nix_package_url) = nix_package_url else {
+        return Some(BUNDLED_NIX_SERIES.to_string());
+    };
+    let name = tarball_name(nix_package_url)?;
+    series_of(name.strip_prefix("nix-")?.split('-').next()?)
+}
+
+/// The release series of the Nix `version` (like `2.25` of `2.25.3`), `None` if it is no version
+pub(crate) fn series_of(version: &str) -> Option<String> {
+    let mut parts = version.split('.');
+    let (major, minor) = (parts.next()?, parts.next()?);
+    if [major, minor]
+        .iter()
+        .any(|part| part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()))
+    {
+        return None;
+    }
+    Some(format!("{major}.{minor}"))
+}
+
+/// The system (like `aarch64-darwin`) of the Nix `nix_package_url` installs, that of this
+/// `nix-installer` for the bundled one
+///
+/// `None` if the URL names no system.
+pub(crate) fn system_to_install(nix_package_url: Option<&UrlOrPath>) -> Option<String> {
+    let Some(nix_package_url) = nix_package_url else {
+        return Some(crate::self_test::SYSTEM.to_string());
+    };
+    let name = tarball_name(nix_package_url)?;
+    let (_version, rest) = name.strip_prefix("nix-")?.split_once('-')?;
+    let system = rest.split(".tar").next()?;
+    (!system.is_empty()).then(|| system.to_string())
+}
+
+/// The file name of the tarball at `nix_package_url`
+fn tarball_name(nix_package_url: &UrlOrPath) -> Option<String> {
+    Some(match nix_package_url {
+        UrlOrPath::Url(url) => url.path_segments()?.next_back()?.to_string(),
+        UrlOrPath::Path(path) => path.file_name()?.to_string_lossy().into_owned(),
+    })
+}
+
+/// If the release series `series` is `minimum` or later
+pub(crate) fn series_at_least(series: &str, minimum: &str) -> bool {
+    let parse = |series: &str| {
+        series
+            .split('.')
+            .map(|part| part.parse::<u32>().unwrap_or_default())
+            .collect::<Vec<_>>()
+    };
+    parse(series) >= parse(minimum)
+}
+
+/// A setting which is not in the table it was checked against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownSetting {
+    pub key: String,
+    /// The closest known setting, if any is close enough to be a likely typo
+    pub suggestion: Option<String>,
+}
+
+impl std::fmt::Display for UnknownSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}`", self.key)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, " (did you mean `{suggestion}`?)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Each of `keys` which is not a `known` setting, or the `extra-` form of one
+pub(crate) fn unknown_settings<'a>(
+    keys: impl IntoIterator<Item = &'a str>,
+    known: &[&str],
+) -> Vec<UnknownSetting> {
+    keys.into_iter()
+        .filter_map(|key| {
+            let (prefix, setting) = match key.strip_prefix("extra-") {
+                Some(setting) if !known.contains(&key) => ("extra-", setting),
+                _ => ("", key),
+            };
+            if known.contains(&setting) {
+                return None;
+            }
+            Some(UnknownSetting {
+                key: key.to_string(),
+                suggestion: nearest(setting, known).map(|nearest| format!("{prefix}{nearest}")),
+            })
+        })
+        .collect()
+}
+
+/// The known setting fewest edits away from `setting`, if it is close enough to be a likely typo
+pub(crate) fn nearest<'a>(setting: &str, known: &[&'a str]) -> Option<&'a str> {
+    // Allow roughly one edit for every four characters, so short keys don't match everything
+    let max_distance = (setting.chars().count() / 4).clamp(1, 3);
+    known
+        .iter()
+        .map(|candidate| (edit_distance(setting, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        // On a tie, prefer the longer setting, dropping a character being the likelier typo
+        .min_by_key(|(distance, candidate)| (*distance, candidate.len() < setting.len()))
+        .map(|(_, candidate)| candidate)
+}
+
+/// The Levenshtein distance between `left` and `right`
+fn edit_distance(left: &str, right: &str) -> usize {
+    let right = right.chars().collect::<Vec<_>>();
+    let mut previous = (0..=right.len()).collect::<Vec<_>>();
+    for (i, left_char) in left.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, right_char) in right.iter().enumerate() {
+            let substitution = previous[j] + usize::from(left_char != *right_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[right.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        edit_distance, known_settings, series_at_least, series_of, series_to_install,
+        system_to_install, unknown_settings, UnknownSetting, BUNDLED_NIX_SERIES, KNOWN_SETTINGS,
+    };
+    use crate::settings::UrlOrPath;
+
+    #[test]
+    fn tables_are_well_formed() {
+        assert!(known_settings(BUNDLED_NIX_SERIES).is_some());
+        assert!(known_settings("1.11").is_none());
+        for (series, settings) in KNOWN_SETTINGS {
+            let mut sorted = settings.to_vec();
+            sorted.sort();
+            sorted.dedup();
+            assert_eq!(
+                sorted.len(),
+                settings.len(),
+                "{series} lists a setting twice"
+            );
+            assert!(
+                settings
+                    .iter()
+                    .all(|setting| !setting.starts_with("extra-") || *setting == "extra-platforms"),
+                "{series} lists an `extra-` form"
+            );
+        }
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("sandbox", "sandbox"), 0);
+        assert_eq!(edit_distance("sandbx", "sandbox"), 1);
+        assert_eq!(
+            edit_distance("experimental-fetures", "experimental-features"),
+            1
+        );
+        assert_eq!(edit_distance("", "cores"), 5);
+    }
+
+    #[test]
+    fn unknown_settings_are_suggested_a_match() {
+        let known = known_settings(BUNDLED_NIX_SERIES).unwrap();
+        let unknown = unknown_settings(
+            [
+                "experimental-features",
+                "extra-experimental-features",
+                "extra-platforms",
+                "extra-extra-platforms",
+                "experimental-fetures",
+                "extra-substituter",
+                "trusted-user",
+                "frobnicate",
+            ],
+            known,
+        );
+        assert_eq!(
+            unknown,
+            vec![
+                UnknownSetting {
+                    key: "experimental-fetures".into(),
+                    suggestion: Some("experimental-features".into()),
+                },
+                UnknownSetting {
+                    key: "extra-substituter".into(),
+                    suggestion: Some("extra-substituters".into()),
+                },
+                UnknownSetting {
+                    key: "trusted-user".into(),
+                    suggestion: Some("trusted-users".into()),
+                },
+                UnknownSetting {
+                    key: "frobnicate".into(),
+                    suggestion: None,
+                },
+            ]
+        );
+        assert_eq!(
+            unknown[0].to_string(),
+            "`experimental-fetures` (did you mean `experimental-features`?)"
+        );
+        assert_eq!(unknown[3].to_string(), "`frobnicate`");
+    }
+
+    #[test]
+    fn series_to_install_comes_from_the_tarball_name() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(series_to_install(None).as_deref(), Some(BUNDLED_NIX_SERIES));
+        let url = UrlOrPath::Url(
+            "https://releases.nixos.org/nix/nix-2.24.10/nix-2.24.10-x86_64-linux.tar.xz".parse()?,
+        );
+        assert_eq!(series_to_install(Some(&url)).as_deref(), Some("2.24"));
+        let path = UrlOrPath::Path("/tmp/nix-2.26.0-aarch64-darwin.tar.xz".into());
+        assert_eq!(series_to_install(Some(&path)).as_deref(), Some("2.26"));
+        let path = UrlOrPath::Path("/tmp/nix.tar.xz".into());
+        assert_eq!(series_to_install(Some(&path)), None);
+
+        assert_eq!(series_of("2.3.16").as_deref(), Some("2.3"));
+        assert_eq!(series_of("2.25").as_deref(), Some("2.25"));
+        assert_eq!(
+            series_of("2.26.0pre20241104_1234abcd").as_deref(),
+            Some("2.26")
+        );
+        assert_eq!(series_of("2"), None);
+        assert_eq!(series_of("latest"), None);
+        assert_eq!(series_of("2.x"), None);
+
+        assert!(series_at_least("2.25", "2.25"));
+        assert!(series_at_least("2.100", "2.25"));
+        assert!(!series_at_least("2.9", "2.25"));
+        Ok(())
+    }
+
+    #[test]
+    fn system_to_install_comes_from_the_tarball_name() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            system_to_install(None).as_deref(),
+            Some(crate::self_test::SYSTEM)
+        );
+        let url = UrlOrPath::Url(
+            "https://releases.nixos.org/nix/nix-2.24.10/nix-2.24.10-x86_64-linux.tar.xz".parse()?,
+        );
+        assert_eq!(
+            system_to_install(Some(&url)).as_deref(),
+            Some("x86_64-linux")
+        );
+        let path = UrlOrPath::Path("/tmp/nix-2.26.0-aarch64-darwin.tar.xz".into());
+        assert_eq!(
+            system_to_install(Some(&path)).as_deref(),
+            Some("aarch64-darwin")
+        );
+        let path = UrlOrPath::Path("/tmp/nix.tar.xz".into());
+        assert_eq!(system_to_install(Some(&path)), None);
+        Ok(())
+    }
+}
@@ -49,6 +49,9 @@ pub struct DiskUtilApfsListOutput {
 #[derive(serde::Deserialize, Clone, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct DiskUtilApfsContainer {
+    /// The disk of the container, like `disk3`
+    pub container_reference: Option<String>,
+    pub capacity_free: Option<u64>,
     pub volumes: Vec<DiskUtilApfsListVolume>,
 }
 
@@ -57,6 +60,11 @@ pub struct DiskUtilApfsContainer {
 pub struct DiskUtilApfsListVolume {
     pub name: Option<String>,
     pub file_vault: Option<bool>,
+    /// Zero when the volume has no quota
+    pub capacity_quota: Option<u64>,
+    /// Zero when the volume has no reserve
+    pub capacity_reserve: Option<u64>,
+    pub capacity_in_use: Option<u64>,
 }
 
 #[derive(serde::Deserialize, Clone, Debug)]
@@ -1,3 +1,3 @@
 pub mod diskutil;
 
-pub use diskutil::{DiskUtilApfsListOutput, DiskUtilInfoOutput};
+pub use diskutil::{DiskUtilApfsListOutput, DiskUtilApfsListVolume, DiskUtilInfoOutput};
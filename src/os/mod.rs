@@ -1 +1,2 @@
 pub mod darwin;
+pub mod ostree;
@@ -0,0 +1,209 @@
+/*! The deployments of an ostree system, such as Fedora Silverblue, IoT, or CoreOS
+
+An ostree system boots one of several deployments, each with its own read-only `/usr` and its own
+copy of `/etc`, while `/var` is shared by all of them. An OS update stages a new deployment which
+is booted next, so only what is written to `/var`, or to `/etc` before the update merges it, is
+still there afterwards.
+*/
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::util::host_path;
+
+/// Present on a system booted into an ostree deployment
+pub(crate) const OSTREE_BOOTED: &str = "/run/ostree-booted";
+
+/// The output of `ostree admin status --json`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct OstreeStatus {
+    #[serde(default)]
+    pub deployments: Vec<Deployment>,
+}
+
+/// A deployment, listed in the order they are booted by default, so the first is booted next
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct Deployment {
+    pub osname: String,
+    pub checksum: String,
+    #[serde(default)]
+    pub serial: u32,
+    #[serde(default)]
+    pub booted: bool,
+    /// Written out when the system shuts down, merging `/etc` as it is then
+    #[serde(default)]
+    pub staged: bool,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub unlocked: Unlocked,
+}
+
+/// The state `ostree admin unlock` left the `/usr` of a deployment in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unlocked {
+    /// `/usr` is read-only
+    #[default]
+    None,
+    /// `/usr` is a writable overlay, discarded on reboot
+    Development,
+    /// `/usr` is a writable overlay, discarded on reboot
+    Transient,
+    /// `/usr` is a writable overlay kept across reboots, but not into another deployment
+    Hotfix,
+    /// A state this `nix-installer` does not know
+    #[serde(other)]
+    Other,
+}
+
+impl std::fmt::Display for Unlocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Unlocked::None => "none",
+            Unlocked::Development => "development",
+            Unlocked::Transient => "transient",
+            Unlocked::Hotfix => "hotfix",
+            Unlocked::Other => "unknown",
+        })
+    }
+}
+
+impl OstreeStatus {
+    /// The status of this system, if it was booted into an ostree deployment
+    pub async fn query() -> Option<Self> {
+        if !host_path(OSTREE_BOOTED).exists() {
+            return None;
+        }
+        let output = crate::command_output(
+            tokio::process::Command::new("ostree")
+                .process_group(0)
+                .args(["admin", "status", "--json"])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .ok()
+        .filter(|output| output.status.success())?;
+        match Self::parse(&String::from_utf8_lossy(&output.stdout)) {
+            Ok(status) => Some(status),
+            Err(err) => {
+                tracing::debug!(%err, "Could not parse `ostree admin status --json`");
+                None
+            },
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn booted(&self) -> Option<&Deployment> {
+        self.deployments.iter().find(|deployment| deployment.booted)
+    }
+
+    /// The deployment which is booted next, if it is not the booted one
+    pub fn pending(&self) -> Option<&Deployment> {
+        self.deployments
+            .first()
+            .filter(|deployment| !deployment.booted)
+    }
+}
+
+/// Where `path` ends up once every symbolic link in it is followed, like `/usr/local/bin` being
+/// `/var/usrlocal/bin`
+///
+/// Components which don't exist are kept as they are.
+pub(crate) fn resolve(path: &Path) -> PathBuf {
+    let components = |path: &Path| {
+        path.components()
+            .rev()
+            .map(|component| PathBuf::from(component.as_os_str()))
+            .collect::<Vec<_>>()
+    };
+    let mut pending = components(path);
+    let mut resolved = PathBuf::from("/");
+    // Like the kernel, give up on links which don't settle
+    let mut links = 0;
+    while let Some(component) = pending.pop() {
+        match component.components().next() {
+            Some(Component::RootDir | Component::Prefix(_)) => resolved = PathBuf::from("/"),
+            Some(Component::CurDir) | None => (),
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            },
+            Some(Component::Normal(name)) => {
+                let candidate = resolved.join(name);
+                match std::fs::read_link(host_path(&candidate)) {
+                    Ok(target) if links < 40 => {
+                        links += 1;
+                        pending.extend(components(&target));
+                    },
+                    _ => resolved = candidate,
+                }
+            },
+        }
+    }
+    resolved
+}
+
+/// If `path` is in the `/usr` of the deployment, which the next deployment replaces
+pub(crate) fn in_usr(path: &Path) -> bool {
+    resolve(path).starts_with("/usr")
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::{in_usr, resolve, OstreeStatus, Unlocked};
+    use crate::test_harness::SandboxContext;
+
+    const NORMAL: &str = include_str!("../../tests/fixtures/ostree/normal.json");
+    const UNLOCKED: &str = include_str!("../../tests/fixtures/ostree/unlocked.json");
+    const STAGED: &str = include_str!("../../tests/fixtures/ostree/staged.json");
+
+    #[test]
+    fn deployment_states_are_parsed() -> eyre::Result<()> {
+        let normal = OstreeStatus::parse(NORMAL)?;
+        let booted = normal.booted().expect("a booted deployment");
+        assert_eq!(booted.osname, "fedora-iot");
+        assert_eq!(booted.unlocked, Unlocked::None);
+        assert!(normal.pending().is_none());
+
+        let unlocked = OstreeStatus::parse(UNLOCKED)?;
+        assert_eq!(
+            unlocked.booted().map(|booted| booted.unlocked),
+            Some(Unlocked::Development)
+        );
+        assert!(unlocked.pending().is_none());
+
+        let staged = OstreeStatus::parse(STAGED)?;
+        let pending = staged.pending().expect("a pending deployment");
+        assert!(pending.staged);
+        assert_ne!(
+            Some(&pending.checksum),
+            staged.booted().map(|booted| &booted.checksum)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn symlinks_out_of_usr_are_followed() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/var/usrlocal/bin"))?;
+        std::fs::create_dir_all(sandbox.path("/usr/share/fish"))?;
+        std::os::unix::fs::symlink("../var/usrlocal", sandbox.path("/usr/local"))?;
+
+        sandbox
+            .scope(async {
+                assert_eq!(
+                    resolve(Path::new("/usr/local/bin/determinate-nixd")),
+                    PathBuf::from("/var/usrlocal/bin/determinate-nixd")
+                );
+                assert!(!in_usr(Path::new("/usr/local/share/fish")));
+                assert!(in_usr(Path::new("/usr/share/fish/vendor_conf.d")));
+                assert!(!in_usr(Path::new("/etc/fish")));
+            })
+            .await;
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+/*! The phases of `nix-installer install --phase`
+
+Remote execution systems like MDMs often stop a command after a few minutes, which a whole install
+on a slow network can exceed. An install can instead be run as several invocations, each executing
+the actions of one phase and recording them in the receipt, which the next invocation resumes from.
+
+A [`Planner`](crate::planner::Planner) decides which [`Phase`] each action of its plan executes
+in. Phases only ever move forward through a plan, so each phase ends where the next begins.
+*/
+
+use crate::{
+    action::{
+        base::{ImportSeedClosure, InstallDefaultProfileFlakes},
+        common::{ConfigureNix, CreateUsersAndGroups, ProvisionDeterminateNixd, ProvisionNix},
+        Action, ActionState, StatefulAction,
+    },
+    planner::Planner,
+};
+
+/// A phase of a phased install, in the order they execute
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Phase {
+    /// Fetch Nix and place it in the store, along with whatever the store needs to exist first
+    Fetch,
+    /// Create the build users and group
+    Provision,
+    /// Configure Nix, the shells, and the daemon
+    Configure,
+    /// Execute whatever is left, then test the install and write the finished receipt
+    Finish,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Fetch => write!(f, "fetch"),
+            Phase::Provision => write!(f, "provision"),
+            Phase::Configure => write!(f, "configure"),
+            Phase::Finish => write!(f, "finish"),
+        }
+    }
+}
+
+impl Phase {
+    /// The phase after this one, if any
+    pub fn next(self) -> Option<Phase> {
+        match self {
+            Phase::Fetch => Some(Phase::Provision),
+            Phase::Provision => Some(Phase::Configure),
+            Phase::Configure => Some(Phase::Finish),
+            Phase::Finish => None,
+        }
+    }
+
+    /// The phase which starts with an action of the built in planners, by its tag
+    ///
+    /// Other actions execute in the phase of the action before them.
+    pub fn starting_with(action_tag: &str) -> Option<Phase> {
+        if [
+            ProvisionDeterminateNixd::action_tag().0,
+            ProvisionNix::action_tag().0,
+        ]
+        .contains(&action_tag)
+        {
+            Some(Phase::Fetch)
+        } else if action_tag == CreateUsersAndGroups::action_tag().0 {
+            Some(Phase::Provision)
+        } else if action_tag == ConfigureNix::action_tag().0 {
+            Some(Phase::Configure)
+        } else if [
+            InstallDefaultProfileFlakes::action_tag().0,
+            ImportSeedClosure::action_tag().0,
+        ]
+        .contains(&action_tag)
+        {
+            Some(Phase::Finish)
+        } else {
+            None
+        }
+    }
+}
+
+/// The phase each of `actions` executes in, as `planner` decides
+///
+/// An action is never in an earlier phase than the action before it, and actions before the first
+/// the planner places are in [`Phase::Fetch`].
+pub fn phases(planner: &dyn Planner, actions: &[StatefulAction<Box<dyn Action>>]) -> Vec<Phase> {
+    let mut current = Phase::Fetch;
+    actions
+        .iter()
+        .map(|action| {
+            if let Some(phase) = planner.phase(action) {
+                current = current.max(phase);
+            }
+            current
+        })
+        .collect()
+}
+
+/// The earliest phase before `phase` which has an action which has not completed
+pub(crate) fn incomplete_before(
+    phase: Phase,
+    phases: &[Phase],
+    actions: &[StatefulAction<Box<dyn Action>>],
+) -> Option<Phase> {
+    phases
+        .iter()
+        .zip(actions)
+        .filter(|(action_phase, _)| **action_phase < phase)
+        .find(|(_, action)| !matches!(action.state, ActionState::Completed | ActionState::Skipped))
+        .map(|(action_phase, _)| *action_phase)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use super::Phase;
+    use crate::{
+        action::{base::CreateDirectory, Action, ActionState, StatefulAction},
+        plan::DEFAULT_PARALLELISM,
+        planner::{Planner, PlannerError},
+        settings::InstallSettingsError,
+        test_harness::SandboxContext,
+        InstallPlan, NixInstallerError,
+    };
+
+    /// Places each directory in the phase it is named after
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct PhasedDirectories;
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "phased_directories")]
+    impl Planner for PhasedDirectories {
+        async fn default() -> Result<Self, PlannerError> {
+            Ok(Self)
+        }
+
+        async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+            Ok(vec![])
+        }
+
+        fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+            Ok(HashMap::new())
+        }
+
+        async fn configured_settings(
+            &self,
+        ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
+            Ok(HashMap::new())
+        }
+
+        async fn platform_check(&self) -> Result<(), PlannerError> {
+            Ok(())
+        }
+
+        fn phase(&self, action: &StatefulAction<Box<dyn Action>>) -> Option<Phase> {
+            let directory = action.downcast_ref::<CreateDirectory>()?;
+            match directory.path.file_name()?.to_str()? {
+                "provision" => Some(Phase::Provision),
+                "configure" => Some(Phase::Configure),
+                "finish" => Some(Phase::Finish),
+                _ => None,
+            }
+        }
+
+        #[cfg(feature = "diagnostics")]
+        async fn diagnostic_data(
+            &self,
+        ) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
+            unimplemented!("Never planned")
+        }
+    }
+
+    async fn phased_plan(dir: &Path) -> eyre::Result<InstallPlan> {
+        let mut actions = vec![];
+        // `store` is in the phase of `fetch` before it, `users` in that of `provision`
+        for name in [
+            "fetch",
+            "store",
+            "provision",
+            "users",
+            "configure",
+            "finish",
+        ] {
+            actions.push(
+                CreateDirectory::plan(dir.join(name), None, None, 0o0755, false)
+                    .await?
+                    .boxed(),
+            );
+        }
+        Ok(serde_json::from_value(serde_json::json!({
+            "planner": PhasedDirectories.boxed(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": actions,
+        }))?)
+    }
+
+    async fn install_phase(
+        sandbox: &SandboxContext,
+        plan: &mut InstallPlan,
+        phase: Phase,
+    ) -> Result<(), NixInstallerError> {
+        sandbox
+            .scope(plan.install_phase(phase, None, None, DEFAULT_PARALLELISM))
+            .await
+    }
+
+    fn states(plan: &InstallPlan) -> Vec<ActionState> {
+        plan.actions.iter().map(|action| action.state).collect()
+    }
+
+    #[test]
+    fn builtin_plans_are_phased() -> eyre::Result<()> {
+        let receipt: InstallPlan =
+            serde_json::from_str(include_str!("../tests/fixtures/linux/linux.json"))?;
+        // `/nix`, Nix, the build users, `nix.conf`, `/etc/tmpfiles.d`, the daemon, the scratch directory
+        assert_eq!(
+            receipt.phases(),
+            [
+                Phase::Fetch,
+                Phase::Fetch,
+                Phase::Provision,
+                Phase::Configure,
+                Phase::Configure,
+                Phase::Configure,
+                Phase::Configure,
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn phases_execute_in_order() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let dir = tempfile::tempdir()?;
+        let mut plan = phased_plan(dir.path()).await?;
+        assert_eq!(
+            plan.phases(),
+            [
+                Phase::Fetch,
+                Phase::Fetch,
+                Phase::Provision,
+                Phase::Provision,
+                Phase::Configure,
+                Phase::Finish,
+            ]
+        );
+
+        // Nothing executes when an earlier phase is incomplete
+        assert!(matches!(
+            install_phase(&sandbox, &mut plan, Phase::Provision).await,
+            Err(NixInstallerError::PhaseOutOfOrder {
+                phase: Phase::Provision,
+                incomplete: Phase::Fetch,
+            })
+        ));
+        assert!(states(&plan)
+            .iter()
+            .all(|state| *state == ActionState::Uncompleted));
+
+        install_phase(&sandbox, &mut plan, Phase::Fetch).await?;
+        use ActionState::{Completed, Uncompleted};
+        assert_eq!(
+            states(&plan),
+            [
+                Completed,
+                Completed,
+                Uncompleted,
+                Uncompleted,
+                Uncompleted,
+                Uncompleted
+            ]
+        );
+        assert!(dir.path().join("store").is_dir());
+        assert!(!dir.path().join("provision").exists());
+
+        // The next invocation resumes from the receipt
+        let receipt = std::fs::read_to_string(sandbox.path(crate::plan::RECEIPT_LOCATION))?;
+        let mut plan: InstallPlan = serde_json::from_str(&receipt)?;
+        assert!(matches!(
+            install_phase(&sandbox, &mut plan, Phase::Configure).await,
+            Err(NixInstallerError::PhaseOutOfOrder {
+                incomplete: Phase::Provision,
+                ..
+            })
+        ));
+        install_phase(&sandbox, &mut plan, Phase::Provision).await?;
+        // Running a completed phase again changes nothing
+        install_phase(&sandbox, &mut plan, Phase::Fetch).await?;
+        install_phase(&sandbox, &mut plan, Phase::Configure).await?;
+        assert_eq!(
+            states(&plan),
+            [
+                Completed,
+                Completed,
+                Completed,
+                Completed,
+                Completed,
+                Uncompleted
+            ]
+        );
+        assert!(!dir.path().join("finish").exists());
+
+        // Aborting reverts every phase which completed
+        sandbox.scope(plan.uninstall(None)).await?;
+        assert!(states(&plan).iter().all(|state| *state == Uncompleted));
+        assert!(!dir.path().join("fetch").exists());
+        Ok(())
+    }
+}
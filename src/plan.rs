@@ -1,19 +1,67 @@
 use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Instant,
 };
 
 use crate::{
-    action::{Action, ActionDescription, StatefulAction},
+    action::{
+        common::DeleteStaleBuildUsers, Action, ActionDescription, ActionState, PrivilegedOperation,
+        StatefulAction,
+    },
+    build_info::BuildInfo,
+    cross_target::{self, CrossTarget},
+    deprecation::{self, Invocation},
+    error::HasExpectedErrors,
+    host_snapshot::HostSnapshot,
+    managed_files::{self, ManagedFile},
+    messages::message,
+    phase::Phase,
     planner::{BuiltinPlanner, Planner},
+    replace_file::{replace_file, Attributes},
+    report::{self, ActionOutcome, ProgressEvent},
+    uninstall_impact::UninstallImpact,
+    warning::{self, Warning},
     NixInstallerError,
 };
 use owo_colors::OwoColorize;
 use semver::{Version, VersionReq};
-use tokio::sync::broadcast::Receiver;
+use target_lexicon::Triple;
+use tokio::sync::{broadcast::Receiver, mpsc::UnboundedSender};
 
 pub const RECEIPT_LOCATION: &str = "/nix/receipt.json";
 
+/// The layout of the receipts this `nix-installer` writes
+///
+/// Receipts with an older layout are migrated to this one as they're read (see [`read_receipt`]),
+/// so a receipt written by an older `nix-installer` can still be uninstalled.
+pub const RECEIPT_SCHEMA: u32 = 4;
+
+/// How many actions [`InstallPlan::install`] executes at once, when their dependencies allow it
+pub const DEFAULT_PARALLELISM: NonZeroUsize = match NonZeroUsize::new(4) {
+    Some(parallelism) => parallelism,
+    None => unreachable!(),
+};
+
+/// An action of the plan executing, which returns it along with how it went
+type Execution<'a> = std::pin::Pin<
+    Box<
+        dyn std::future::Future<
+                Output = (
+                    &'a mut StatefulAction<Box<dyn Action>>,
+                    bool,
+                    Instant,
+                    Result<(), crate::action::ActionError>,
+                    Vec<Warning>,
+                    Vec<ManagedFile>,
+                ),
+            > + Send
+            + 'a,
+    >,
+>;
+
 /**
 A set of [`Action`]s, along with some metadata, which can be carried out to drive an install or
 revert
@@ -22,10 +70,43 @@ revert
 pub struct InstallPlan {
     pub(crate) version: Version,
 
+    /// The layout of the receipt this was read from, see [`RECEIPT_SCHEMA`], absent from receipts
+    /// written before it was recorded
+    #[serde(default)]
+    pub(crate) receipt_schema: u32,
+
     pub(crate) actions: Vec<StatefulAction<Box<dyn Action>>>,
 
     pub(crate) planner: Box<dyn Planner>,
 
+    /// Absent from receipts written before it was collected
+    #[serde(default)]
+    pub(crate) host_snapshot: Option<HostSnapshot>,
+
+    /// How the `nix-installer` which wrote this was built, absent from receipts written before it
+    /// was recorded
+    #[serde(default)]
+    pub(crate) build_info: Option<BuildInfo>,
+
+    /// Raised while planning, installing, and uninstalling, absent from receipts written before
+    /// they were collected
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) warnings: Vec<Warning>,
+
+    /// The platform the plan was made for, if it was made for another one without inspecting the
+    /// host, see [`cross_target`](crate::cross_target)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cross_target: Option<CrossTarget>,
+
+    /// How `nix-installer` was invoked when this was planned, left out when there is nothing to note
+    #[serde(default, skip_serializing_if = "Invocation::is_empty")]
+    pub(crate) invocation: Invocation,
+
+    /// The files registered by the actions executed since the receipt was last read, see
+    /// [`managed_files`](crate::managed_files)
+    #[serde(skip)]
+    pub(crate) managed_files: Vec<ManagedFile>,
+
     #[cfg(feature = "diagnostics")]
     pub(crate) diagnostic_data: Option<crate::diagnostics::DiagnosticData>,
 }
@@ -38,12 +119,21 @@ impl InstallPlan {
         let diagnostic_data = Some(planner.diagnostic_data().await?);
 
         let planner = planner.boxed();
-        let actions = planner.plan().await?;
+        let (actions, mut warnings) = warning::collect(planner.plan()).await;
+        let actions = actions?;
+        warning::merge(&mut warnings, deprecation::warnings());
 
         Ok(Self {
             planner,
             actions,
+            host_snapshot: Some(HostSnapshot::collect(Path::new("/nix")).await),
+            build_info: Some(BuildInfo::current().clone()),
+            warnings,
             version: current_version()?,
+            receipt_schema: RECEIPT_SCHEMA,
+            cross_target: None,
+            invocation: Invocation::current(),
+            managed_files: vec![],
             #[cfg(feature = "diagnostics")]
             diagnostic_data,
         })
@@ -59,18 +149,79 @@ impl InstallPlan {
         let diagnostic_data = Some(planner.diagnostic_data().await?);
 
         // Some Action `plan` calls may fail if we don't do these checks
-        planner.pre_install_check().await?;
+        let (checked, mut warnings) = warning::collect(planner.pre_install_check()).await;
+        checked?;
 
-        let actions = planner.plan().await?;
+        let (actions, planned_warnings) = warning::collect(planner.plan()).await;
+        let actions = actions?;
+        warning::merge(&mut warnings, planned_warnings);
+        warning::merge(&mut warnings, deprecation::warnings());
         Ok(Self {
             planner: planner.boxed(),
             actions,
+            host_snapshot: Some(HostSnapshot::collect(Path::new("/nix")).await),
+            build_info: Some(BuildInfo::current().clone()),
+            warnings,
             version: current_version()?,
+            receipt_schema: RECEIPT_SCHEMA,
+            cross_target: None,
+            invocation: Invocation::current(),
+            managed_files: vec![],
             #[cfg(feature = "diagnostics")]
             diagnostic_data,
         })
     }
 
+    /// Plan with `planner`, then remove or insert actions before installing, see
+    /// [`InstallPlanBuilder`](crate::InstallPlanBuilder)
+    pub fn builder<P>(planner: P) -> crate::InstallPlanBuilder<P>
+    where
+        P: Planner + 'static,
+    {
+        crate::InstallPlanBuilder::new(planner)
+    }
+
+    /// Plan with `planner` for the `target` platform rather than the host, without inspecting the
+    /// host, see [`cross_target`](crate::cross_target)
+    pub async fn plan_for_target<P>(planner: P, target: Triple) -> Result<Self, NixInstallerError>
+    where
+        P: Planner + 'static,
+    {
+        let triple = target.to_string();
+        let (planned, placeholders) = cross_target::scope(target, async {
+            planner.platform_check().await?;
+            // The pre-install checks are about the host, which the plan is not for
+            Ok::<_, NixInstallerError>(warning::collect(planner.plan()).await)
+        })
+        .await;
+        let (actions, mut warnings) = planned?;
+        let actions = actions?;
+        warning::merge(&mut warnings, deprecation::warnings());
+        Ok(Self {
+            planner: planner.boxed(),
+            actions,
+            host_snapshot: None,
+            build_info: Some(BuildInfo::current().clone()),
+            warnings,
+            version: current_version()?,
+            receipt_schema: RECEIPT_SCHEMA,
+            cross_target: Some(CrossTarget {
+                triple,
+                placeholders,
+            }),
+            invocation: Invocation::current(),
+            managed_files: vec![],
+            #[cfg(feature = "diagnostics")]
+            diagnostic_data: None,
+        })
+    }
+
+    /// The platform the plan was made for, if it was made for another one without inspecting the
+    /// host
+    pub fn cross_target(&self) -> Option<&CrossTarget> {
+        self.cross_target.as_ref()
+    }
+
     pub async fn pre_uninstall_check(&self) -> Result<(), NixInstallerError> {
         self.planner.platform_check().await?;
         self.planner.pre_uninstall_check().await?;
@@ -78,11 +229,24 @@ impl InstallPlan {
     }
 
     pub async fn pre_install_check(&self) -> Result<(), NixInstallerError> {
+        if let Some(cross_target) = &self.cross_target {
+            cross_target.check_host(&self.actions)?;
+        }
         self.planner.platform_check().await?;
         self.planner.pre_install_check().await?;
         Ok(())
     }
 
+    /// The warnings raised while planning, and while installing or uninstalling so far
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// How `nix-installer` was invoked when this was planned
+    pub fn invocation(&self) -> &Invocation {
+        &self.invocation
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn describe_install(&self, explain: bool) -> Result<String, NixInstallerError> {
         let Self {
@@ -106,123 +270,349 @@ impl InstallPlan {
         // Stabilize output order
         plan_settings.sort();
 
-        let buf = format!(
-            "\
-            Nix install plan (v{version})\n\
-            Planner: {planner}{maybe_default_setting_note}\n\
-            \n\
-            {maybe_plan_settings}\
-            Planned actions:\n\
-            {actions}\n\
-        ",
+        let mut buf = format!(
+            "Nix install plan (v{version})\nPlanner: {planner}{maybe_default_setting_note}\n\n",
             planner = planner.typetag_name(),
             maybe_default_setting_note = if plan_settings.is_empty() {
-                String::from(" (with default settings)")
-            } else {
-                String::new()
-            },
-            maybe_plan_settings = if plan_settings.is_empty() {
-                String::new()
+                " (with default settings)"
             } else {
-                format!(
-                    "\
-                    Configured settings:\n\
-                    {plan_settings}\n\
-                    \n\
-                ",
-                    plan_settings = plan_settings.join("\n")
-                )
+                ""
             },
-            actions = actions
+        );
+        if let Some(cross_target) = &self.cross_target {
+            buf.push_str(&format!(
+                "Target: {}, planned without inspecting the host\n\n",
+                cross_target.triple
+            ));
+            write_section(
+                &mut buf,
+                "Not probed",
+                cross_target
+                    .placeholders
+                    .iter()
+                    .map(|setting| format!("* {setting}")),
+            );
+        }
+        write_section(&mut buf, "Configured settings", plan_settings);
+        if planner.settings()?.get("minimal") == Some(&serde_json::Value::Bool(true)) {
+            write_section(
+                &mut buf,
+                "Left out by `--minimal`",
+                crate::settings::minimal_leaves_out(planner.typetag_name())
+                    .into_iter()
+                    .map(|part| format!("* {part}")),
+            );
+        }
+        buf.push_str(&warning::describe(&self.warnings));
+        write_section(
+            &mut buf,
+            "Stale build users to delete (`--remove-stale-build-users`)",
+            actions
                 .iter()
-                .flat_map(|v| v.describe_execute())
-                .map(|desc| {
-                    let ActionDescription {
-                        description,
-                        explanation,
-                    } = desc;
-
-                    let mut buf = String::default();
-                    buf.push_str(&format!("* {description}"));
-                    if explain {
-                        for line in explanation {
-                            buf.push_str(&format!("\n  {line}"));
-                        }
-                    }
-                    buf
-                })
-                .collect::<Vec<_>>()
-                .join("\n"),
+                .filter_map(|action| action.downcast_ref::<DeleteStaleBuildUsers>())
+                .flat_map(DeleteStaleBuildUsers::users)
+                .map(|user| format!("* {user}")),
+        );
+        buf.push_str("Planned actions:\n");
+        write_action_descriptions(
+            &mut buf,
+            actions.iter().flat_map(|v| v.describe_execute()),
+            explain,
+        );
+        buf.push('\n');
+        Ok(buf)
+    }
+
+    /// Like [`describe_install`][InstallPlan::describe_install] with explanations, also listing the actions which are already done and would be skipped
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn describe_dry_run(&self) -> Result<String, NixInstallerError> {
+        let mut buf = self.describe_install(true).await?;
+        write_section(
+            &mut buf,
+            "Already done, would be skipped",
+            self.actions
+                .iter()
+                .filter(|action| action.state != ActionState::Uncompleted)
+                .map(|action| format!("* {}", action.tracing_synopsis())),
         );
         Ok(buf)
     }
 
+    /// Like [`describe_install`][InstallPlan::describe_install], for tools rather than humans
+    ///
+    /// Every action is listed, with its state, so the ones already done can be told apart.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn describe_install_structured(&self) -> Result<PlanDescription, NixInstallerError> {
+        Ok(PlanDescription {
+            version: self.version.clone(),
+            planner: self.planner.typetag_name(),
+            settings: self.planner.settings()?.into_iter().collect(),
+            actions: self
+                .actions
+                .iter()
+                .map(|action| ActionSummary::new(action, false))
+                .collect(),
+            warnings: self.warnings.clone(),
+            cross_target: self.cross_target.clone(),
+        })
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn install(
         &mut self,
         cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> Result<(), NixInstallerError> {
+        self.install_with_progress(cancel_channel, None).await
+    }
+
+    /// Like [`install`][InstallPlan::install], sending a [`ProgressEvent`] to `progress` as each action starts and finishes
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn install_with_progress(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        progress: Option<UnboundedSender<ProgressEvent>>,
+    ) -> Result<(), NixInstallerError> {
+        self.install_with_parallelism(cancel_channel, progress, DEFAULT_PARALLELISM)
+            .await
+    }
+
+    /// Like [`install_with_progress`][InstallPlan::install_with_progress], executing up to `parallelism` actions at once
+    ///
+    /// An action starts once the actions before it which it [depends on](Action::dependencies) have completed.
+    /// Events for actions executing at the same time interleave.
+    #[tracing::instrument(level = "debug", skip_all, fields(parallelism = parallelism.get()))]
+    pub async fn install_with_parallelism(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        progress: Option<UnboundedSender<ProgressEvent>>,
+        parallelism: NonZeroUsize,
+    ) -> Result<(), NixInstallerError> {
+        let total = self.actions.len();
+        self.execute_until(total, cancel_channel, progress, parallelism)
+            .await?;
+        self.write_receipt().await?;
+
+        if let Err(err) = crate::self_test::self_test()
+            .await
+            .map_err(NixInstallerError::SelfTest)
+        {
+            #[cfg(feature = "diagnostics")]
+            if let Some(diagnostic_data) = &self.diagnostic_data {
+                diagnostic_data
+                    .clone()
+                    .failure(&err)
+                    .send(
+                        crate::diagnostics::DiagnosticAction::Install,
+                        crate::diagnostics::DiagnosticStatus::Failure,
+                    )
+                    .await;
+            }
+
+            tracing::warn!("{err:?}")
+        } else {
+            #[cfg(feature = "diagnostics")]
+            if let Some(diagnostic_data) = &self.diagnostic_data {
+                diagnostic_data
+                    .clone()
+                    .send(
+                        crate::diagnostics::DiagnosticAction::Install,
+                        crate::diagnostics::DiagnosticStatus::Success,
+                    )
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like [`install_with_parallelism`][InstallPlan::install_with_parallelism], only executing the actions of `phase`, once those of every earlier phase have completed, and recording them in the receipt
+    ///
+    /// [`Phase::Finish`] executes every action left like [`install_with_parallelism`][InstallPlan::install_with_parallelism], whichever phases completed.
+    #[tracing::instrument(level = "debug", skip_all, fields(%phase))]
+    pub async fn install_phase(
+        &mut self,
+        phase: Phase,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        progress: Option<UnboundedSender<ProgressEvent>>,
+        parallelism: NonZeroUsize,
+    ) -> Result<(), NixInstallerError> {
+        if phase == Phase::Finish {
+            return self
+                .install_with_parallelism(cancel_channel, progress, parallelism)
+                .await;
+        }
+
+        let phases = self.phases();
+        if let Some(incomplete) = crate::phase::incomplete_before(phase, &phases, &self.actions) {
+            return Err(NixInstallerError::PhaseOutOfOrder { phase, incomplete });
+        }
+        let end = phases
+            .iter()
+            .position(|action_phase| *action_phase > phase)
+            .unwrap_or(phases.len());
+        self.execute_until(end, cancel_channel, progress, parallelism)
+            .await?;
+        // The receipt is what the next phase resumes from
+        self.write_receipt().await?;
+        Ok(())
+    }
+
+    /// The [`Phase`] of a phased install each action executes in
+    pub fn phases(&self) -> Vec<Phase> {
+        crate::phase::phases(self.planner.as_ref(), &self.actions)
+    }
+
+    /// Execute the actions before `end` which have not completed yet
+    ///
+    /// If one fails or the install is cancelled, the receipt is written before returning.
+    async fn execute_until(
+        &mut self,
+        end: usize,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        progress: Option<UnboundedSender<ProgressEvent>>,
+        parallelism: NonZeroUsize,
     ) -> Result<(), NixInstallerError> {
         self.check_compatible()?;
-        self.pre_install_check().await?;
+        let (checked, warnings) = warning::collect(self.pre_install_check()).await;
+        warning::merge(&mut self.warnings, warnings);
+        checked?;
 
-        let Self { actions, .. } = self;
+        let Self {
+            actions,
+            managed_files,
+            ..
+        } = self;
+        let mut raised = vec![];
         let mut cancel_channel = cancel_channel.into();
+        let total = actions.len();
+        // A closed channel only means nobody is listening anymore, which must not stop the install
+        let send_progress = |event: ProgressEvent| {
+            if let Some(progress) = &progress {
+                let _ = progress.send(event);
+            }
+        };
 
-        // This is **deliberately sequential**.
-        // Actions which are parallelizable are represented by "group actions" like CreateUsers
-        // The plan itself represents the concept of the sequence of stages.
-        for action in actions {
+        // Actions run concurrently within this task rather than being spawned, so the sandbox,
+        // warning, and progress scopes they run in carry over.
+        // Unless an action names its dependencies, it waits for every action before it, so a plan
+        // of such actions still executes one action at a time, in order.
+        let prerequisites = prerequisites(actions);
+        let mut waiting = actions.iter_mut().map(Some).collect::<Vec<_>>();
+        let mut completed = vec![false; total];
+        let mut running: Vec<(usize, Execution<'_>)> = vec![];
+        let mut cancelled = false;
+        let mut failure = None;
+        loop {
             if let Some(ref mut cancel_channel) = cancel_channel {
-                if cancel_channel.try_recv()
-                    != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
+                if !cancelled
+                    && cancel_channel.try_recv()
+                        != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
                 {
-                    if let Err(err) = self.write_receipt().await {
-                        tracing::error!("Error saving receipt: {:?}", err);
+                    cancelled = true;
+                }
+            }
+
+            // Once an action fails or the install is cancelled, only the running actions finish
+            if !cancelled && failure.is_none() {
+                for index in 0..end {
+                    if running.len() >= parallelism.get() {
+                        break;
                     }
+                    if waiting[index].is_none()
+                        || !prerequisites[index]
+                            .iter()
+                            .all(|prerequisite| completed[*prerequisite])
+                    {
+                        continue;
+                    }
+                    let Some(action) = waiting[index].take() else {
+                        continue;
+                    };
 
-                    #[cfg(feature = "diagnostics")]
-                    if let Some(diagnostic_data) = &self.diagnostic_data {
-                        diagnostic_data
-                            .clone()
-                            .send(
-                                crate::diagnostics::DiagnosticAction::Install,
-                                crate::diagnostics::DiagnosticStatus::Cancelled,
+                    tracing::info!(
+                        "Step {} of {total}: {}",
+                        index + 1,
+                        action.tracing_synopsis()
+                    );
+                    send_progress(ProgressEvent::ActionStarted {
+                        index,
+                        total,
+                        action: action.inner_typetag_name().to_string(),
+                        synopsis: action.tracing_synopsis(),
+                    });
+                    let progress = progress.as_ref();
+                    running.push((
+                        index,
+                        Box::pin(async move {
+                            let skipped = action.state == ActionState::Completed;
+                            let started = Instant::now();
+                            let ((result, warnings), registered) = managed_files::registering(
+                                warning::collect(report::with_sub_action_progress(
+                                    progress,
+                                    index,
+                                    total,
+                                    action.try_execute(),
+                                )),
                             )
                             .await;
-                    }
-
-                    return Err(NixInstallerError::Cancelled);
+                            (action, skipped, started, result, warnings, registered)
+                        }),
+                    ));
                 }
             }
 
-            tracing::info!("Step: {}", action.tracing_synopsis());
-            if let Err(err) = action.try_execute().await {
-                if let Err(err) = self.write_receipt().await {
-                    tracing::error!("Error saving receipt: {:?}", err);
-                }
-                let err = NixInstallerError::Action(err);
-                #[cfg(feature = "diagnostics")]
-                if let Some(diagnostic_data) = &self.diagnostic_data {
-                    diagnostic_data
-                        .clone()
-                        .failure(&err)
-                        .send(
-                            crate::diagnostics::DiagnosticAction::Install,
-                            crate::diagnostics::DiagnosticStatus::Failure,
-                        )
-                        .await;
-                }
+            if running.is_empty() {
+                break;
+            }
+            let (position, (action, skipped, started, result, warnings, registered)) =
+                std::future::poll_fn(|cx| {
+                    for (position, (_, execution)) in running.iter_mut().enumerate() {
+                        if let std::task::Poll::Ready(output) = execution.as_mut().poll(cx) {
+                            return std::task::Poll::Ready((position, output));
+                        }
+                    }
+                    std::task::Poll::Pending
+                })
+                .await;
+            let (index, _) = running.swap_remove(position);
+            managed_files.extend(registered);
 
-                return Err(err);
+            send_progress(ProgressEvent::ActionFinished {
+                index,
+                total,
+                action: action.inner_typetag_name().to_string(),
+                synopsis: action.tracing_synopsis(),
+                duration_ms: started.elapsed().as_millis().try_into().unwrap_or(u64::MAX),
+                status: match (&result, skipped) {
+                    (Err(_), _) => ActionOutcome::Failed,
+                    (Ok(()), true) => ActionOutcome::Skipped,
+                    (Ok(()), false) => ActionOutcome::Completed,
+                },
+            });
+            for warning in warnings {
+                send_progress(ProgressEvent::Warning {
+                    index,
+                    total,
+                    action: action.inner_typetag_name().to_string(),
+                    warning: warning.clone(),
+                });
+                raised.push(warning);
+            }
+            match result {
+                Ok(()) => completed[index] = true,
+                // Only the first failure is returned, any other failing alongside it is logged
+                Err(err) if failure.is_some() => tracing::error!("{err:?}"),
+                Err(err) => failure = Some(err),
             }
         }
+        drop((waiting, running));
 
-        self.write_receipt().await?;
-
-        if let Err(err) = crate::self_test::self_test()
-            .await
-            .map_err(NixInstallerError::SelfTest)
-        {
+        if let Some(err) = failure {
+            warning::merge(&mut self.warnings, raised);
+            if let Err(err) = self.write_receipt().await {
+                tracing::error!("Error saving receipt: {:?}", err);
+            }
+            let err = NixInstallerError::Action(err);
             #[cfg(feature = "diagnostics")]
             if let Some(diagnostic_data) = &self.diagnostic_data {
                 diagnostic_data
@@ -235,20 +625,29 @@ impl InstallPlan {
                     .await;
             }
 
-            tracing::warn!("{err:?}")
-        } else {
+            return Err(err);
+        }
+        if cancelled {
+            warning::merge(&mut self.warnings, raised);
+            if let Err(err) = self.write_receipt().await {
+                tracing::error!("Error saving receipt: {:?}", err);
+            }
+
             #[cfg(feature = "diagnostics")]
             if let Some(diagnostic_data) = &self.diagnostic_data {
                 diagnostic_data
                     .clone()
                     .send(
                         crate::diagnostics::DiagnosticAction::Install,
-                        crate::diagnostics::DiagnosticStatus::Success,
+                        crate::diagnostics::DiagnosticStatus::Cancelled,
                     )
                     .await;
             }
+
+            return Err(NixInstallerError::Cancelled);
         }
 
+        warning::merge(&mut self.warnings, raised);
         Ok(())
     }
 
@@ -258,6 +657,7 @@ impl InstallPlan {
             version,
             planner,
             actions,
+            host_snapshot,
             ..
         } = self;
 
@@ -275,79 +675,119 @@ impl InstallPlan {
         // Stabilize output order
         plan_settings.sort();
 
-        let buf = format!(
-            "\
-            Nix uninstall plan (v{version})\n\
-            \n\
-            Planner: {planner}{maybe_default_setting_note}\n\
-            \n\
-            {maybe_plan_settings}\
-            Planned actions:\n\
-            {actions}\n\
-        ",
+        let mut buf = format!(
+            "Nix uninstall plan (v{version})\n\nPlanner: {planner}{maybe_default_setting_note}\n\n",
             planner = planner.typetag_name(),
             maybe_default_setting_note = if plan_settings.is_empty() {
-                String::from(" (with default settings)")
-            } else {
-                String::new()
-            },
-            maybe_plan_settings = if plan_settings.is_empty() {
-                String::new()
+                " (with default settings)"
             } else {
-                format!(
-                    "\
-                Configured settings:\n\
-                {plan_settings}\n\
-                \n\
-            ",
-                    plan_settings = plan_settings.join("\n")
-                )
+                ""
             },
-            actions = actions
-                .iter()
-                .rev()
-                .flat_map(|v| v.describe_revert())
-                .map(|desc| {
-                    let ActionDescription {
-                        description,
-                        explanation,
-                    } = desc;
-
-                    let mut buf = String::default();
-                    buf.push_str(&format!("* {description}"));
-                    if explain {
-                        for line in explanation {
-                            buf.push_str(&format!("\n  {line}"));
-                        }
-                    }
-                    buf
-                })
-                .collect::<Vec<_>>()
-                .join("\n"),
         );
+        write_section(&mut buf, "Configured settings", plan_settings);
+        match host_snapshot {
+            // Only shown when explaining, the facts are for support triage
+            Some(host_snapshot) if explain => write_section(
+                &mut buf,
+                "Host at install time",
+                host_snapshot
+                    .facts()
+                    .iter()
+                    .map(|(k, v)| format!("* {k}: {v}", k = k.bold())),
+            ),
+            _ => (),
+        }
+        buf.push_str("Planned actions:\n");
+        write_action_descriptions(
+            &mut buf,
+            revert_order(actions)
+                .into_iter()
+                .flat_map(|index| actions[index].describe_revert()),
+            explain,
+        );
+        buf.push('\n');
         Ok(buf)
     }
 
+    /// Like [`describe_uninstall`][InstallPlan::describe_uninstall], for tools rather than humans
+    ///
+    /// The actions are listed in the order they would be reverted.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn describe_uninstall_structured(
+        &self,
+    ) -> Result<PlanDescription, NixInstallerError> {
+        Ok(PlanDescription {
+            version: self.version.clone(),
+            planner: self.planner.typetag_name(),
+            settings: self.planner.settings()?.into_iter().collect(),
+            actions: revert_order(&self.actions)
+                .into_iter()
+                .map(|index| ActionSummary::new(&self.actions[index], true))
+                .collect(),
+            warnings: self.warnings.clone(),
+            cross_target: self.cross_target.clone(),
+        })
+    }
+
+    /// What uninstalling would remove or stop on this host, without reverting anything
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn uninstall_impact(&self) -> Result<UninstallImpact, NixInstallerError> {
+        Ok(crate::uninstall_impact::uninstall_impact(&self.actions, None).await?)
+    }
+
+    /// Like [`uninstall_impact`][InstallPlan::uninstall_impact], for the plan read from `receipt`,
+    /// comparing files with the [`managed_files`] index when it was written with `receipt`
+    pub(crate) async fn uninstall_impact_of(
+        &self,
+        receipt: &Path,
+    ) -> Result<UninstallImpact, NixInstallerError> {
+        let indexed = managed_files::indexed_for(self, receipt);
+        Ok(crate::uninstall_impact::uninstall_impact(&self.actions, indexed.as_deref()).await?)
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn uninstall(
         &mut self,
         cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> Result<(), NixInstallerError> {
+        self.uninstall_with_progress(cancel_channel, None).await
+    }
+
+    /// Like [`uninstall`][InstallPlan::uninstall], sending a [`ProgressEvent`] to `progress` as each action starts and finishes reverting
+    ///
+    /// Actions are reverted last to first, so `index` counts down from `total - 1`, except for
+    /// those held back by [`Action::revert_after`].
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn uninstall_with_progress(
+        &mut self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+        progress: Option<UnboundedSender<ProgressEvent>>,
     ) -> Result<(), NixInstallerError> {
         self.check_compatible()?;
         self.pre_uninstall_check().await?;
 
         let Self { actions, .. } = self;
+        let mut raised = vec![];
         let mut cancel_channel = cancel_channel.into();
         let mut errors = vec![];
+        let total = actions.len();
+        // A closed channel only means nobody is listening anymore, which must not stop the uninstall
+        let send_progress = |event: ProgressEvent| {
+            if let Some(progress) = &progress {
+                let _ = progress.send(event);
+            }
+        };
 
         // This is **deliberately sequential**.
-        // Actions which are parallelizable are represented by "group actions" like CreateUsers
-        // The plan itself represents the concept of the sequence of stages.
-        for action in actions.iter_mut().rev() {
+        // An action only depends on actions before it, so reverting last to first never reverts
+        // an action before one which depends on it.
+        for index in revert_order(actions) {
+            let action = &mut actions[index];
             if let Some(ref mut cancel_channel) = cancel_channel {
                 if cancel_channel.try_recv()
                     != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
                 {
+                    warning::merge(&mut self.warnings, raised);
                     if let Err(err) = self.write_receipt().await {
                         tracing::error!("Error saving receipt: {:?}", err);
                     }
@@ -366,11 +806,52 @@ impl InstallPlan {
                 }
             }
 
-            tracing::info!("Revert: {}", action.tracing_synopsis());
-            if let Err(errs) = action.try_revert().await {
+            tracing::info!(
+                "Revert {} of {total}: {}",
+                index + 1,
+                action.tracing_synopsis()
+            );
+            let skipped = action.state == ActionState::Uncompleted;
+            send_progress(ProgressEvent::ActionStarted {
+                index,
+                total,
+                action: action.inner_typetag_name().to_string(),
+                synopsis: action.tracing_synopsis(),
+            });
+            let started = Instant::now();
+            let (result, warnings) = warning::collect(report::with_sub_action_progress(
+                progress.as_ref(),
+                index,
+                total,
+                action.try_revert(),
+            ))
+            .await;
+            send_progress(ProgressEvent::ActionFinished {
+                index,
+                total,
+                action: action.inner_typetag_name().to_string(),
+                synopsis: action.tracing_synopsis(),
+                duration_ms: started.elapsed().as_millis().try_into().unwrap_or(u64::MAX),
+                status: match (&result, skipped) {
+                    (Err(_), _) => ActionOutcome::Failed,
+                    (Ok(()), true) => ActionOutcome::Skipped,
+                    (Ok(()), false) => ActionOutcome::Completed,
+                },
+            });
+            for warning in warnings {
+                send_progress(ProgressEvent::Warning {
+                    index,
+                    total,
+                    action: action.inner_typetag_name().to_string(),
+                    warning: warning.clone(),
+                });
+                raised.push(warning);
+            }
+            if let Err(errs) = result {
                 errors.push(errs);
             }
         }
+        warning::merge(&mut self.warnings, raised);
 
         if errors.is_empty() {
             #[cfg(feature = "diagnostics")]
@@ -403,79 +884,1223 @@ impl InstallPlan {
         }
     }
 
+    /// Whether this `nix-installer` can act on the plan
+    ///
+    /// A plan read from a receipt in (or migrated to) the current [`RECEIPT_SCHEMA`] is compatible
+    /// whichever version wrote it. Other plans must have been written by a compatible version.
     pub fn check_compatible(&self) -> Result<(), NixInstallerError> {
-        let self_version_string = self.version.to_string();
-        let req = VersionReq::parse(&self_version_string)
-            .map_err(|e| NixInstallerError::InvalidVersionRequirement(self_version_string, e))?;
-        let nix_installer_version = current_version()?;
-        if req.matches(&nix_installer_version) {
-            Ok(())
-        } else {
-            Err(NixInstallerError::IncompatibleVersion {
-                binary: nix_installer_version,
+        match self.receipt_schema.cmp(&RECEIPT_SCHEMA) {
+            std::cmp::Ordering::Equal => Ok(()),
+            std::cmp::Ordering::Greater => Err(NixInstallerError::IncompatibleVersion {
+                binary: current_version()?,
                 plan: self.version.clone(),
+            }),
+            std::cmp::Ordering::Less => check_version_compatible(&self.version),
+        }
+    }
+
+    /// Append `actions` after the planner's actions, so they are executed after (and reverted before) them
+    ///
+    /// Every action must be [`Uncompleted`](ActionState::Uncompleted), and the planner may refuse
+    /// actions which cannot run after its own (see [`Planner::validate_extra_actions`]).
+    pub fn extend_with(
+        &mut self,
+        actions: Vec<StatefulAction<Box<dyn Action>>>,
+    ) -> Result<(), NixInstallerError> {
+        for (index, action) in actions.iter().enumerate() {
+            if action.state != ActionState::Uncompleted {
+                return Err(ExtraPlanError::ActionNotUncompleted {
+                    index,
+                    action: action.inner_typetag_name(),
+                    state: action.state,
+                }
+                .into());
+            }
+        }
+        self.planner
+            .validate_extra_actions(&self.actions, &actions)?;
+        self.actions.extend(actions);
+        Ok(())
+    }
+
+    /// The operations [`install`](Self::install) would perform as root, in order and without repeats
+    pub fn privileged_operations(&self) -> PrivilegedOperations {
+        let mut operations: Vec<PrivilegedOperation> = vec![];
+        for operation in self
+            .actions
+            .iter()
+            .flat_map(|action| action.privileged_operations())
+        {
+            if !operations.contains(&operation) {
+                operations.push(operation);
+            }
+        }
+        PrivilegedOperations { operations }
+    }
+
+    /// Refuse the plan if it would perform an operation as root which `allowed` does not permit
+    ///
+    /// See [`PrivilegedOperation::is_allowed_by`] for how operations are matched.
+    pub fn check_allowed_operations(
+        &self,
+        allowed: &PrivilegedOperations,
+    ) -> Result<(), NixInstallerError> {
+        let not_allowed = self
+            .privileged_operations()
+            .operations
+            .into_iter()
+            .filter(|operation| {
+                !allowed
+                    .operations
+                    .iter()
+                    .any(|allowed| operation.is_allowed_by(allowed))
             })
+            .collect::<Vec<_>>();
+        if not_allowed.is_empty() {
+            Ok(())
+        } else {
+            Err(PrivilegedOperationsError::NotAllowed(not_allowed).into())
         }
     }
 
+    /// Write the receipt to [`RECEIPT_LOCATION`], followed by the index of the files it records
     pub(crate) async fn write_receipt(&self) -> Result<(), NixInstallerError> {
         let install_receipt_path = PathBuf::from(RECEIPT_LOCATION);
-        write_receipt(self, &install_receipt_path).await?;
+        let previous = tokio::fs::read(crate::util::host_path(&install_receipt_path))
+            .await
+            .ok();
+        let receipt = receipt_json(self)?;
+        write_receipt_json(&receipt, &install_receipt_path).await?;
+        managed_files::record(self, previous.as_deref(), &receipt).await;
 
         Ok(())
     }
 }
 
-pub(crate) async fn write_receipt(
-    plan: &impl serde::Serialize,
-    install_receipt_path: &Path,
-) -> Result<(), NixInstallerError> {
-    let install_receipt_path_tmp = {
-        let mut install_receipt_path_tmp = install_receipt_path.to_path_buf();
-        install_receipt_path_tmp.set_extension("tmp");
-        install_receipt_path_tmp
-    };
-    let self_json =
-        serde_json::to_string_pretty(plan).map_err(NixInstallerError::SerializingReceipt)?;
-
-    tokio::fs::create_dir_all("/nix")
-        .await
-        .map_err(|e| NixInstallerError::RecordingReceipt(PathBuf::from("/nix"), e))?;
-    tokio::fs::write(&install_receipt_path_tmp, format!("{self_json}\n"))
-        .await
-        .map_err(|e| NixInstallerError::RecordingReceipt(install_receipt_path_tmp.clone(), e))?;
-    tokio::fs::rename(&install_receipt_path_tmp, &install_receipt_path)
-        .await
-        .map_err(|e| NixInstallerError::RecordingReceipt(install_receipt_path.to_path_buf(), e))?;
-
-    Ok(())
+/// Append a titled block of `lines` followed by a blank line, or nothing if there are no `lines`
+fn write_section(buf: &mut String, title: &str, lines: impl IntoIterator<Item = String>) {
+    let mut lines = lines.into_iter().peekable();
+    if lines.peek().is_none() {
+        return;
+    }
+    buf.push_str(title);
+    buf.push_str(":\n");
+    for line in lines {
+        buf.push_str(&line);
+        buf.push('\n');
+    }
+    buf.push('\n');
 }
 
-pub fn current_version() -> Result<Version, NixInstallerError> {
-    let nix_installer_version_str = env!("CARGO_PKG_VERSION");
-    Version::from_str(nix_installer_version_str).map_err(|e| {
-        NixInstallerError::InvalidCurrentVersion(nix_installer_version_str.to_string(), e)
-    })
+/// Append each description as a bullet, followed by its explanation when `explain`ing
+///
+/// Written straight into `buf` since large plans have thousands of them.
+fn write_action_descriptions(
+    buf: &mut String,
+    descriptions: impl Iterator<Item = ActionDescription>,
+    explain: bool,
+) {
+    for (idx, description) in descriptions.enumerate() {
+        let ActionDescription {
+            description,
+            explanation,
+        } = description;
+        if idx != 0 {
+            buf.push('\n');
+        }
+        buf.push_str("* ");
+        buf.push_str(&description);
+        if explain {
+            for line in explanation {
+                buf.push_str("\n  ");
+                buf.push_str(&line);
+            }
+        }
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use semver::Version;
+/// A description of a plan for tools, as printed by `--format json`
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PlanDescription {
+    pub version: Version,
+    pub planner: &'static str,
+    /// All the settings of the planner, not only the configured ones
+    pub settings: BTreeMap<String, serde_json::Value>,
+    pub actions: Vec<ActionSummary>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+    /// The platform the plan was made for, if it was made for another one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cross_target: Option<CrossTarget>,
+}
 
-    use crate::{planner::BuiltinPlanner, InstallPlan, NixInstallerError};
+/// A planned action in a [`PlanDescription`]
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ActionSummary {
+    pub action_tag: &'static str,
+    pub synopsis: String,
+    pub state: ActionState,
+    /// What the action would do, empty if it has nothing left to do
+    pub descriptions: Vec<ActionDescription>,
+    /// The actions it is made of, not necessarily in the order they run
+    pub sub_actions: Vec<ActionSummary>,
+}
 
-    #[tokio::test]
-    async fn ensure_version_allows_compatible() -> Result<(), NixInstallerError> {
-        let planner = BuiltinPlanner::default().await?;
-        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
-        let value = serde_json::json!({
-            "planner": planner.boxed(),
-            "version": good_version,
-            "actions": [],
-        });
-        let maybe_plan: InstallPlan = serde_json::from_value(value)?;
-        maybe_plan.check_compatible()?;
-        Ok(())
+impl ActionSummary {
+    fn new(action: &StatefulAction<Box<dyn Action>>, revert: bool) -> Self {
+        let mut sub_actions = vec![];
+        // Actions hold their sub-actions in fields of their own types, so they are found in the
+        // serialized action rather than asked for
+        if let Ok(serialized) = serde_json::to_value(&action.action) {
+            find_sub_actions(&serialized, revert, &mut sub_actions);
+        }
+        Self {
+            action_tag: action.inner_typetag_name(),
+            synopsis: action.tracing_synopsis(),
+            state: action.state,
+            descriptions: match revert {
+                true => action.describe_revert(),
+                false => action.describe_execute(),
+            },
+            sub_actions,
+        }
+    }
+}
+
+/// Search a serialized action for the actions it holds, each summarizing its own sub-actions
+fn find_sub_actions(value: &serde_json::Value, revert: bool, found: &mut Vec<ActionSummary>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let is_stateful_action = map.contains_key("state")
+                && map
+                    .get("action")
+                    .is_some_and(|action| action.get("action_name").is_some());
+            if is_stateful_action {
+                if let Ok(action) =
+                    serde_json::from_value::<StatefulAction<Box<dyn Action>>>(value.clone())
+                {
+                    found.push(ActionSummary::new(&action, revert));
+                    return;
+                }
+            }
+            for value in map.values() {
+                find_sub_actions(value, revert, found);
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for value in values {
+                find_sub_actions(value, revert, found);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// Open a receipt for [`serde_json::from_reader`], so it's parsed as it's read rather than held whole in memory next to the parsed plan
+pub(crate) fn receipt_reader(
+    path: impl AsRef<Path>,
+) -> std::io::Result<std::io::BufReader<std::fs::File>> {
+    std::fs::File::open(path).map(std::io::BufReader::new)
+}
+
+/// Read the plan in the receipt at `path`, migrating it from an older [`RECEIPT_SCHEMA`]
+///
+/// Receipts in the current layout are parsed as they're read. Older ones are read whole so they
+/// can be migrated first, which only happens until they're next written.
+pub(crate) fn read_receipt(path: impl AsRef<Path>) -> std::io::Result<InstallPlan> {
+    let path = path.as_ref();
+    // Older layouts may not parse at all, or parse into something else
+    match serde_json::from_reader::<_, InstallPlan>(receipt_reader(path)?) {
+        Ok(plan) if plan.receipt_schema >= RECEIPT_SCHEMA => return Ok(plan),
+        _ => (),
+    }
+
+    let mut receipt: serde_json::Value = serde_json::from_reader(receipt_reader(path)?)?;
+    migrate(&mut receipt);
+    Ok(serde_json::from_value(receipt)?)
+}
+
+/// Upgrade `receipt` from its [`RECEIPT_SCHEMA`] to the current one
+///
+/// Receipts written before the schema was recorded are in whichever layout their content has.
+/// Receipts from a newer `nix-installer` are left alone, for [`InstallPlan::check_compatible`] to
+/// refuse.
+pub(crate) fn migrate(receipt: &mut serde_json::Value) {
+    let schema = match receipt
+        .get("receipt_schema")
+        .and_then(serde_json::Value::as_u64)
+    {
+        Some(schema) => schema as u32,
+        None => receipt_layout(receipt),
+    };
+    if schema >= RECEIPT_SCHEMA {
+        return;
+    }
+    if schema < 2 {
+        wrap_init_service(receipt);
+    }
+    if schema < 3 {
+        tag_package_urls(receipt);
+    }
+    // Schema 4 only added `receipt_schema` itself
+    receipt["receipt_schema"] = RECEIPT_SCHEMA.into();
+}
+
+/// The schema of a receipt written before it was recorded
+fn receipt_layout(receipt: &serde_json::Value) -> u32 {
+    let actions = receipt["actions"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    if actions
+        .iter()
+        .any(|action| action["action"]["action_name"] == "configure_init_service")
+    {
+        1
+    } else if receipt["planner"]["settings"]["nix_package_url"].is_string()
+        || has_untagged_url(receipt)
+    {
+        2
+    } else {
+        3
+    }
+}
+
+fn has_untagged_url(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Object(map) => {
+            (map.get("action_name")
+                .is_some_and(|name| name == "fetch_and_unpack_nix")
+                && map.contains_key("url"))
+                || map.values().any(has_untagged_url)
+        },
+        serde_json::Value::Array(values) => values.iter().any(has_untagged_url),
+        _ => false,
+    }
+}
+
+/// Schema 1 configured the init service with a top level `configure_init_service`, which schema 2
+/// wraps in a `create_upstream_init_service`
+fn wrap_init_service(receipt: &mut serde_json::Value) {
+    let Some(actions) = receipt["actions"].as_array_mut() else {
+        return;
+    };
+    for action in actions {
+        if action["action"]["action_name"] != "configure_init_service" {
+            continue;
+        }
+        let state = action["state"].clone();
+        *action = serde_json::json!({
+            "action": {
+                "action_name": "create_upstream_init_service",
+                "configure_init_service": action.take(),
+            },
+            "state": state,
+        });
+    }
+}
+
+/// Schema 2 recorded the Nix package as a bare URL, which schema 3 tags as a
+/// [`UrlOrPath`](crate::settings::UrlOrPath)
+fn tag_package_urls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if map
+                .get("action_name")
+                .is_some_and(|name| name == "fetch_and_unpack_nix")
+            {
+                if let Some(url) = map.remove("url") {
+                    map.insert("url_or_path".into(), tag_url(url));
+                }
+            }
+            if let Some(url) = map.get_mut("nix_package_url") {
+                *url = tag_url(url.take());
+            }
+            map.values_mut().for_each(tag_package_urls);
+        },
+        serde_json::Value::Array(values) => values.iter_mut().for_each(tag_package_urls),
+        _ => (),
+    }
+}
+
+fn tag_url(url: serde_json::Value) -> serde_json::Value {
+    match url {
+        serde_json::Value::String(_) => serde_json::json!({ "Url": url }),
+        other => other,
+    }
+}
+
+pub(crate) async fn write_receipt(
+    plan: &impl serde::Serialize,
+    install_receipt_path: &Path,
+) -> Result<(), NixInstallerError> {
+    write_receipt_json(&receipt_json(plan)?, install_receipt_path).await
+}
+
+/// The content of the receipt of `plan`
+fn receipt_json(plan: &impl serde::Serialize) -> Result<Vec<u8>, NixInstallerError> {
+    let mut receipt =
+        serde_json::to_vec_pretty(plan).map_err(NixInstallerError::SerializingReceipt)?;
+    receipt.push(b'\n');
+    Ok(receipt)
+}
+
+async fn write_receipt_json(
+    receipt: &[u8],
+    install_receipt_path: &Path,
+) -> Result<(), NixInstallerError> {
+    let install_receipt_path = &crate::util::host_path(install_receipt_path);
+
+    // Phase receipts are written to the state directory, which need not exist yet
+    if let Some(parent) = install_receipt_path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| NixInstallerError::RecordingReceipt(parent.to_path_buf(), e))?;
+    }
+    replace_file(install_receipt_path, receipt, Attributes::mode(0o644))
+        .await
+        .map_err(|e| NixInstallerError::WritingReceipt(install_receipt_path.to_path_buf(), e))?;
+
+    Ok(())
+}
+
+fn check_version_compatible(version: &Version) -> Result<(), NixInstallerError> {
+    let version_string = version.to_string();
+    let req = VersionReq::parse(&version_string)
+        .map_err(|e| NixInstallerError::InvalidVersionRequirement(version_string, e))?;
+    let nix_installer_version = current_version()?;
+    if req.matches(&nix_installer_version) {
+        Ok(())
+    } else {
+        Err(NixInstallerError::IncompatibleVersion {
+            binary: nix_installer_version,
+            plan: version.clone(),
+        })
+    }
+}
+
+/**
+A list of [`Action`]s to append to a planner's plan with [`InstallPlan::extend_with`]
+
+```json
+{
+  "version": "0.26.0",
+  "actions": [
+    {
+      "action": {
+        "action_name": "create_directory",
+        "path": "/etc/example",
+        "user": null,
+        "group": null,
+        "mode": 493,
+        "is_mountpoint": false,
+        "force_prune_on_revert": false
+      },
+      "state": "Uncompleted"
+    }
+  ]
+}
+```
+
+Actions are deserialized by their `action_name`, so only actions compiled into the running binary
+can be used. An extra plan cannot bring its own kinds of actions, those need a binary built with them.
+*/
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ExtraPlan {
+    pub(crate) version: Version,
+    pub(crate) actions: Vec<StatefulAction<Box<dyn Action>>>,
+}
+
+impl ExtraPlan {
+    pub async fn from_file(path: &Path) -> Result<Self, NixInstallerError> {
+        let buf = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| ExtraPlanError::Read(path.to_path_buf(), e))?;
+        Self::from_json(&buf)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, NixInstallerError> {
+        #[derive(serde::Deserialize)]
+        struct Unparsed {
+            version: Version,
+            actions: Vec<serde_json::Value>,
+        }
+
+        let Unparsed {
+            version,
+            actions: unparsed_actions,
+        } = serde_json::from_str(json).map_err(ExtraPlanError::Parse)?;
+        check_version_compatible(&version)?;
+
+        let mut actions = Vec::with_capacity(unparsed_actions.len());
+        let mut unknown = vec![];
+        let mut invalid = None;
+        for (index, unparsed_action) in unparsed_actions.into_iter().enumerate() {
+            let action_name = unparsed_action
+                .pointer("/action/action_name")
+                .and_then(|v| v.as_str())
+                .unwrap_or("<missing>")
+                .to_string();
+            match serde_json::from_value::<StatefulAction<Box<dyn Action>>>(unparsed_action) {
+                Ok(action) => actions.push(action),
+                // Keep going, so every unknown action can be listed at once
+                Err(e)
+                    if e.to_string()
+                        .starts_with(&format!("unknown variant `{action_name}`")) =>
+                {
+                    if !unknown.contains(&action_name) {
+                        unknown.push(action_name)
+                    }
+                },
+                Err(e) => {
+                    invalid.get_or_insert(ExtraPlanError::InvalidAction {
+                        index,
+                        action: action_name,
+                        error: e,
+                    });
+                },
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(ExtraPlanError::UnknownActions(unknown).into());
+        }
+        if let Some(invalid) = invalid {
+            return Err(invalid.into());
+        }
+
+        Ok(Self { version, actions })
+    }
+
+    pub fn actions(&self) -> &[StatefulAction<Box<dyn Action>>] {
+        &self.actions
+    }
+
+    pub fn into_actions(self) -> Vec<StatefulAction<Box<dyn Action>>> {
+        self.actions
+    }
+}
+
+/// An error with an [`ExtraPlan`]
+#[non_exhaustive]
+#[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
+pub enum ExtraPlanError {
+    #[error("Reading extra plan `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Parsing extra plan")]
+    Parse(#[source] serde_json::Error),
+    #[error("{}", message!(ErrorExtraPlanUnknownActions, actions = .0.iter().map(|v| format!("`{v}`")).collect::<Vec<_>>().join(", ")))]
+    UnknownActions(Vec<String>),
+    #[error("{}", message!(ErrorExtraPlanInvalidAction, index = .index, action = .action, error = .error))]
+    InvalidAction {
+        index: usize,
+        action: String,
+        error: serde_json::Error,
+    },
+    #[error("{}", message!(ErrorExtraPlanActionNotUncompleted, index = .index, action = .action, state = format!("{:?}", .state)))]
+    ActionNotUncompleted {
+        index: usize,
+        action: &'static str,
+        state: ActionState,
+    },
+}
+
+impl HasExpectedErrors for ExtraPlanError {
+    fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
+        match self {
+            ExtraPlanError::Read(_, _) => None,
+            this @ ExtraPlanError::Parse(_) => Some(Box::new(this)),
+            this @ ExtraPlanError::UnknownActions(_) => Some(Box::new(this)),
+            this @ ExtraPlanError::InvalidAction { .. } => Some(Box::new(this)),
+            this @ ExtraPlanError::ActionNotUncompleted { .. } => Some(Box::new(this)),
+        }
+    }
+}
+
+/**
+The operations an [`InstallPlan`] performs as root, from [`InstallPlan::privileged_operations`]
+
+```json
+{
+  "operations": [
+    { "kind": "write", "path": "/nix", "mode": "0755" },
+    { "kind": "command", "program": "groupadd", "args": ["-g", "30000", "--system", "nixbld"] },
+    { "kind": "service", "name": "nix-daemon.service" }
+  ]
+}
+```
+
+The same document can be passed to [`InstallPlan::check_allowed_operations`] as an allowlist,
+which may be loosened after review, like trimming a path to a directory which may be written anywhere in.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct PrivilegedOperations {
+    pub(crate) operations: Vec<PrivilegedOperation>,
+}
+
+impl PrivilegedOperations {
+    pub async fn from_file(path: &Path) -> Result<Self, NixInstallerError> {
+        let buf = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| PrivilegedOperationsError::Read(path.to_path_buf(), e))?;
+        Self::from_json(&buf)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, NixInstallerError> {
+        Ok(serde_json::from_str(json).map_err(PrivilegedOperationsError::Parse)?)
+    }
+
+    pub fn operations(&self) -> &[PrivilegedOperation] {
+        &self.operations
+    }
+}
+
+/// An error with [`PrivilegedOperations`]
+#[non_exhaustive]
+#[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
+pub enum PrivilegedOperationsError {
+    #[error("Reading allowed operations `{0}`")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Parsing allowed operations")]
+    Parse(#[source] serde_json::Error),
+    #[error("{}", message!(ErrorOperationsNotAllowed, operations = .0.iter().map(|v| format!("* {v}")).collect::<Vec<_>>().join("\n")))]
+    NotAllowed(Vec<PrivilegedOperation>),
+}
+
+impl HasExpectedErrors for PrivilegedOperationsError {
+    fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
+        match self {
+            PrivilegedOperationsError::Read(_, _) => None,
+            this @ PrivilegedOperationsError::Parse(_) => Some(Box::new(this)),
+            this @ PrivilegedOperationsError::NotAllowed(_) => Some(Box::new(this)),
+        }
+    }
+}
+
+pub fn current_version() -> Result<Version, NixInstallerError> {
+    let nix_installer_version_str = env!("CARGO_PKG_VERSION");
+    Version::from_str(nix_installer_version_str).map_err(|e| {
+        NixInstallerError::InvalidCurrentVersion(nix_installer_version_str.to_string(), e)
+    })
+}
+
+/// The indexes of the earlier actions each of `actions` must wait for before it executes
+///
+/// An action which doesn't name its [dependencies](Action::dependencies) waits for every action
+/// before it.
+pub(crate) fn prerequisites(actions: &[StatefulAction<Box<dyn Action>>]) -> Vec<Vec<usize>> {
+    actions
+        .iter()
+        .enumerate()
+        .map(|(index, action)| match action.action.dependencies() {
+            None => (0..index).collect(),
+            Some(tags) => (0..index)
+                .filter(|earlier| {
+                    tags.iter()
+                        .any(|tag| tag.0 == actions[*earlier].inner_typetag_name())
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// The indexes of `actions` in the order they revert
+///
+/// This is last to first, except that an action is moved after the last of the actions it must
+/// [revert after](Action::revert_after).
+pub(crate) fn revert_order(actions: &[StatefulAction<Box<dyn Action>>]) -> Vec<usize> {
+    let mut order = (0..actions.len()).rev().collect::<Vec<_>>();
+    for index in (0..actions.len()).rev() {
+        let after = actions[index].action.revert_after();
+        if after.is_empty() {
+            continue;
+        }
+        let Some(position) = order.iter().position(|v| *v == index) else {
+            continue;
+        };
+        let last_before = order.iter().rposition(|v| {
+            after
+                .iter()
+                .any(|tag| tag.0 == actions[*v].inner_typetag_name())
+        });
+        if let Some(last_before) = last_before.filter(|last_before| *last_before > position) {
+            order.remove(position);
+            order.insert(last_before, index);
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use clap::Parser;
+    use semver::Version;
+    use target_lexicon::Triple;
+
+    use super::{migrate, prerequisites, read_receipt, revert_order, RECEIPT_SCHEMA};
+    use crate::{
+        action::{
+            base::UserShellAndHome, Action, ActionState, PrivilegedOperation, StatefulAction,
+        },
+        cross_target,
+        host_snapshot::HostSnapshot,
+        planner::{
+            linux::Linux, macos::Macos, ostree::Ostree, steam_deck::SteamDeck, BuiltinPlanner,
+            Planner,
+        },
+        settings::CommonSettings,
+        test_harness::{FakeCommand, SandboxContext},
+        warning::WarningKind,
+        ExtraPlan, ExtraPlanError, InstallPlan, NixInstallerError, PrivilegedOperations,
+        PrivilegedOperationsError,
+    };
+
+    fn extra_action(action_name: &str, state: &str) -> serde_json::Value {
+        serde_json::json!({
+            "action": {
+                "action_name": action_name,
+                "path": "/etc/example",
+                "user": null,
+                "group": null,
+                "mode": 493,
+                "is_mountpoint": false,
+                "force_prune_on_revert": false,
+            },
+            "state": state,
+        })
+    }
+
+    async fn empty_plan() -> Result<InstallPlan, NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let value = serde_json::json!({
+            "planner": planner.boxed(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [],
+        });
+        Ok(serde_json::from_value(value)?)
+    }
+
+    #[tokio::test]
+    async fn extra_plan_is_appended() -> Result<(), NixInstallerError> {
+        let mut plan = empty_plan().await?;
+        let extra = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [extra_action("create_directory", "Uncompleted")],
+        });
+        let extra = ExtraPlan::from_json(&extra.to_string())?;
+        plan.extend_with(extra.into_actions())?;
+
+        // The extra actions are recorded in, and restored from, the receipt like any other
+        let receipt: InstallPlan = serde_json::from_str(&serde_json::to_string(&plan)?)?;
+        assert_eq!(receipt.actions.len(), 1);
+        assert_eq!(receipt.actions[0].inner_typetag_name(), "create_directory");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_run_lists_completed_actions() -> Result<(), NixInstallerError> {
+        let mut plan = empty_plan().await?;
+        plan.actions = serde_json::from_value(serde_json::json!([
+            extra_action("create_directory", "Completed"),
+            extra_action("create_directory", "Uncompleted"),
+        ]))?;
+
+        let description = plan.describe_dry_run().await?;
+        let (planned, skipped) = description
+            .split_once("Already done, would be skipped:\n")
+            .expect("a section of skipped actions");
+        assert_eq!(
+            planned.matches("Create directory `/etc/example`").count(),
+            1
+        );
+        assert_eq!(skipped.trim(), "* Create directory `/etc/example`");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn structured_descriptions_nest_sub_actions() -> eyre::Result<()> {
+        let receipt: InstallPlan =
+            serde_json::from_str(include_str!("../tests/fixtures/linux/linux.json"))?;
+
+        let install = receipt.describe_install_structured().await?;
+        assert_eq!(install.planner, "linux");
+        assert_eq!(install.actions.len(), receipt.actions.len());
+        let configure_nix = install
+            .actions
+            .iter()
+            .find(|action| action.action_tag == "configure_nix")
+            .expect("a configure_nix action");
+        assert_eq!(configure_nix.state, ActionState::Completed);
+        // Nothing is left to do
+        assert!(configure_nix.descriptions.is_empty());
+        let mut sub_actions = configure_nix
+            .sub_actions
+            .iter()
+            .map(|action| action.action_tag)
+            .collect::<Vec<_>>();
+        sub_actions.sort();
+        assert_eq!(
+            sub_actions,
+            [
+                "configure_shell_profile",
+                "place_nix_configuration",
+                "setup_default_profile"
+            ]
+        );
+
+        // Reverting describes the same actions, in the order they would be reverted
+        let uninstall = receipt.describe_uninstall_structured().await?;
+        assert_eq!(uninstall.actions[0].action_tag, "remove_directory");
+        let configure_nix = uninstall
+            .actions
+            .iter()
+            .find(|action| action.action_tag == "configure_nix")
+            .expect("a configure_nix action");
+        assert!(!configure_nix.descriptions.is_empty());
+
+        // The document is what `--format json` prints
+        let serialized = serde_json::to_value(&uninstall)?;
+        assert!(serialized["actions"][0]["state"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn selinux_policy_reverts_after_the_store() -> eyre::Result<()> {
+        let selinux = serde_json::json!({
+            "action": {
+                "action_name": "provision_selinux",
+                "policy_path": "/usr/share/selinux/packages/nix.pp",
+                "policy_content": [],
+            },
+            "state": "Completed",
+        });
+        let remove_scratch = serde_json::json!({
+            "action": { "action_name": "remove_directory", "path": "/nix/temp-install-dir" },
+            "state": "Completed",
+        });
+        // `/nix`, the SELinux policy, `/etc/tmpfiles.d`, and the scratch directory
+        let actions: Vec<StatefulAction<Box<dyn Action>>> =
+            serde_json::from_value(serde_json::json!([
+                extra_action("create_directory", "Completed"),
+                selinux,
+                extra_action("create_directory", "Completed"),
+                remove_scratch,
+            ]))?;
+        assert_eq!(revert_order(&actions), vec![3, 2, 0, 1]);
+
+        // Without an action to wait for, the policy is reverted in place
+        assert_eq!(revert_order(&actions[1..]), vec![2, 1, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn only_named_dependencies_are_waited_for() -> eyre::Result<()> {
+        let actions: Vec<StatefulAction<Box<dyn Action>>> =
+            serde_json::from_value(serde_json::json!([
+                extra_action("create_directory", "Uncompleted"),
+                {
+                    "action": {
+                        "action_name": "set_tmutil_exclusions",
+                        "set_tmutil_exclusions": [],
+                    },
+                    "state": "Uncompleted",
+                },
+                {
+                    "action": {
+                        "action_name": "configure_remote_building",
+                        "create_or_insert_into_file": null,
+                    },
+                    "state": "Uncompleted",
+                },
+                extra_action("create_directory", "Uncompleted"),
+            ]))?;
+        // Actions which don't name their dependencies wait for everything before them
+        assert_eq!(
+            prerequisites(&actions),
+            vec![vec![], vec![], vec![], vec![0, 1, 2]]
+        );
+        assert_eq!(revert_order(&actions), vec![3, 2, 1, 0]);
+
+        let sequential = actions[..1]
+            .iter()
+            .chain(&actions[3..])
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(prerequisites(&sequential), vec![vec![], vec![0]]);
+        Ok(())
+    }
+
+    #[test]
+    fn extra_plan_lists_unknown_actions() {
+        let extra = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [
+                extra_action("site_enroll", "Uncompleted"),
+                extra_action("create_directory", "Uncompleted"),
+                extra_action("site_audit", "Uncompleted"),
+                extra_action("site_enroll", "Uncompleted"),
+            ],
+        });
+        match ExtraPlan::from_json(&extra.to_string()) {
+            Err(NixInstallerError::ExtraPlan(ExtraPlanError::UnknownActions(unknown))) => {
+                assert_eq!(unknown, vec!["site_enroll", "site_audit"])
+            },
+            other => panic!("Expected unknown actions, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn extra_plan_denies_invalid_action() {
+        let mut action = extra_action("create_directory", "Uncompleted");
+        action["action"]["mode"] = serde_json::json!("rwx");
+        let extra = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [extra_action("create_directory", "Uncompleted"), action],
+        });
+        assert!(matches!(
+            ExtraPlan::from_json(&extra.to_string()),
+            Err(NixInstallerError::ExtraPlan(
+                ExtraPlanError::InvalidAction { index: 1, .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn extra_plan_denies_incompatible_version() {
+        let extra = serde_json::json!({
+            "version": "9999999999999.9999999999.99999999",
+            "actions": [],
+        });
+        assert!(matches!(
+            ExtraPlan::from_json(&extra.to_string()),
+            Err(NixInstallerError::IncompatibleVersion { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn extend_with_denies_completed_actions() -> Result<(), NixInstallerError> {
+        let mut plan = empty_plan().await?;
+        let extra = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [extra_action("create_directory", "Completed")],
+        });
+        let extra = ExtraPlan::from_json(&extra.to_string())?;
+        assert!(matches!(
+            plan.extend_with(extra.into_actions()),
+            Err(NixInstallerError::ExtraPlan(
+                ExtraPlanError::ActionNotUncompleted {
+                    index: 0,
+                    state: ActionState::Completed,
+                    ..
+                }
+            ))
+        ));
+        assert!(plan.actions.is_empty());
+        Ok(())
+    }
+
+    async fn builtin_plans(
+        sandbox: &SandboxContext,
+    ) -> Result<Vec<(String, InstallPlan)>, NixInstallerError> {
+        std::fs::create_dir_all(sandbox.path("/run/systemd/system"))?;
+        std::fs::create_dir_all(sandbox.path("/sbin"))?;
+        std::fs::write(sandbox.path("/sbin/nologin"), "")?;
+        // An `apfs list` with no existing volumes
+        sandbox.fake(
+            "diskutil",
+            FakeCommand::success().stdout(concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><plist version="1.0">"#,
+                "<dict><key>Containers</key><array/></dict></plist>",
+            )),
+        );
+
+        let settings = sandbox.scope(CommonSettings::default()).await?;
+        let planners: Vec<Box<dyn Planner>> = vec![
+            sandbox.scope(Linux::default()).await?.boxed(),
+            sandbox.scope(SteamDeck::default()).await?.boxed(),
+            sandbox.scope(Ostree::default()).await?.boxed(),
+            Macos {
+                settings,
+                encrypt: Some(false),
+                case_sensitive: false,
+                volume_label: "Nix Store".into(),
+                root_disk: Some("disk3".into()),
+                volume_quota: None,
+                volume_reserve: None,
+                use_ec2_instance_store: false,
+                no_tmutil_exclusions: false,
+                tmutil_exclude: vec![],
+                allow_rosetta_shell: false,
+            }
+            .boxed(),
+        ];
+
+        let mut plans = Vec::new();
+        for planner in planners {
+            let mut plan = empty_plan().await?;
+            plan.actions = sandbox.scope(planner.plan()).await?;
+            plans.push((planner.typetag_name().to_string(), plan));
+        }
+        Ok(plans)
+    }
+
+    #[tokio::test]
+    async fn build_users_plan_where_only_usr_sbin_nologin_exists() -> eyre::Result<()> {
+        fn shells(value: &serde_json::Value, found: &mut Vec<String>) {
+            match value {
+                serde_json::Value::Object(map) => {
+                    if map.get("action_name") == Some(&"create_user".into()) {
+                        found.extend(map["shell"].as_str().map(String::from));
+                    }
+                    map.values().for_each(|value| shells(value, found));
+                },
+                serde_json::Value::Array(values) => {
+                    values.iter().for_each(|value| shells(value, found))
+                },
+                _ => (),
+            }
+        }
+
+        // As on Debian buster, without a merged `/usr`
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/run/systemd/system"))?;
+        std::fs::create_dir_all(sandbox.path("/usr/sbin"))?;
+        std::fs::write(sandbox.path("/usr/sbin/nologin"), "")?;
+
+        let planner = sandbox.scope(Linux::default()).await?;
+        let actions = sandbox.scope(planner.plan()).await?;
+        let mut found = vec![];
+        shells(&serde_json::to_value(&actions)?, &mut found);
+        assert!(!found.is_empty());
+        assert!(
+            found.iter().all(|shell| shell == "/usr/sbin/nologin"),
+            "{found:?}"
+        );
+
+        // A planner built with the settings' defaults rather than probing, as a library might
+        let mut planner = planner;
+        planner.settings.nix_build_user_shell = UserShellAndHome::default().shell;
+        let actions = sandbox.scope(planner.plan()).await?;
+        let mut found = vec![];
+        shells(&serde_json::to_value(&actions)?, &mut found);
+        assert!(
+            found.iter().all(|shell| shell == "/usr/sbin/nologin"),
+            "{found:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plans_for_other_platforms_without_probing_the_host() -> eyre::Result<()> {
+        for (target, placeholder) in [
+            ("aarch64-apple-darwin", "root_disk"),
+            ("x86_64-unknown-linux-gnu", "selinux"),
+        ] {
+            let target = Triple::from_str(target)?;
+            let (planner, _) = cross_target::scope(target.clone(), BuiltinPlanner::default()).await;
+            let plan = planner?.plan_for_target(target.clone()).await?;
+
+            let cross = plan.cross_target().expect("The plan records its target");
+            assert_eq!(cross.triple, target.to_string());
+            assert!(
+                cross.placeholders.iter().any(|known| known == placeholder),
+                "{target}: {:?}",
+                cross.placeholders
+            );
+            assert!(plan.host_snapshot.is_none());
+            // The host's state says nothing about the target's
+            assert!(plan
+                .actions
+                .iter()
+                .all(|action| action.state == ActionState::Uncompleted));
+
+            let json = serde_json::to_value(&plan)?;
+            assert_eq!(json["cross_target"]["triple"], target.to_string());
+            if placeholder == "root_disk" {
+                // Creating the volume inspects the disks, so is left for the host
+                assert!(json["actions"].as_array().unwrap().iter().any(|action| {
+                    action["action"]["action_name"] == "requires_host_probing"
+                        && action["action"]["action"] == "create_nix_volume"
+                }));
+            }
+            let read: InstallPlan = serde_json::from_value(json)?;
+            assert_eq!(read.cross_target(), Some(cross));
+
+            // Placeholders are never installed, on the target's platform or any other
+            assert!(matches!(
+                plan.pre_install_check().await,
+                Err(NixInstallerError::CrossTarget(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn privileged_operations_of_builtin_planners() -> Result<(), NixInstallerError> {
+        let sandbox = SandboxContext::new()?;
+        for (name, plan) in builtin_plans(&sandbox).await? {
+            let operations = plan.privileged_operations();
+            assert!(!operations.operations().is_empty(), "{name}");
+            if name == "linux" {
+                assert!(operations
+                    .operations()
+                    .contains(&PrivilegedOperation::write("/nix", 0o755)));
+            }
+
+            // A manifest generated from a plan allows that plan
+            let manifest = PrivilegedOperations::from_json(&serde_json::to_string(&operations)?)?;
+            assert_eq!(manifest, operations, "{name}");
+            plan.check_allowed_operations(&manifest)?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn allowed_operations_are_enforced() -> Result<(), NixInstallerError> {
+        let sandbox = SandboxContext::new()?;
+        let (_, mut plan) = builtin_plans(&sandbox).await?.remove(0);
+        let manifest = plan.privileged_operations();
+
+        let extra = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "actions": [extra_action("create_directory", "Uncompleted")],
+        });
+        plan.extend_with(ExtraPlan::from_json(&extra.to_string())?.into_actions())?;
+        match plan.check_allowed_operations(&manifest) {
+            Err(NixInstallerError::PrivilegedOperations(
+                PrivilegedOperationsError::NotAllowed(denied),
+            )) => assert_eq!(
+                denied,
+                vec![PrivilegedOperation::write("/etc/example", 0o755)]
+            ),
+            other => panic!("Expected the extra directory to be denied, got {other:?}"),
+        }
+
+        // Paths are allowed by prefix
+        let mut manifest = manifest;
+        manifest
+            .operations
+            .push(PrivilegedOperation::write("/etc", None));
+        plan.check_allowed_operations(&manifest)?;
+        Ok(())
+    }
+
+    #[test]
+    fn privileged_operation_matching() {
+        let useradd = PrivilegedOperation::command("useradd", ["--system", "nixbld1"]);
+        assert!(useradd.is_allowed_by(&PrivilegedOperation::command(
+            "/usr/sbin/useradd",
+            Vec::<String>::new()
+        )));
+        assert!(!useradd.is_allowed_by(&PrivilegedOperation::command(
+            "groupadd",
+            Vec::<String>::new()
+        )));
+
+        let remove = PrivilegedOperation::remove("/etc/nix/nix.conf");
+        assert!(remove.is_allowed_by(&PrivilegedOperation::remove("/etc/nix")));
+        assert!(!remove.is_allowed_by(&PrivilegedOperation::remove("/etc/ni")));
+        assert!(!remove.is_allowed_by(&PrivilegedOperation::write("/etc", None)));
+
+        let service = PrivilegedOperation::service("nix-daemon.service");
+        assert!(service.is_allowed_by(&PrivilegedOperation::service("nix-daemon.service")));
+        assert!(!service.is_allowed_by(&PrivilegedOperation::service("nix-daemon")));
+    }
+
+    #[tokio::test]
+    async fn planning_warnings_survive_the_receipt() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let linux = Linux::try_parse_from([
+            "linux",
+            "--init",
+            "none",
+            "--extra-conf",
+            "frobnicate = true",
+        ])?;
+        let shell = sandbox.path(&linux.settings.nix_build_user_shell);
+        std::fs::create_dir_all(shell.parent().unwrap())?;
+        std::fs::write(shell, "")?;
+
+        let plan = sandbox.scope(InstallPlan::plan(linux)).await?;
+        assert_eq!(plan.warnings().len(), 1, "{:?}", plan.warnings());
+        assert_eq!(plan.warnings()[0].kind, WarningKind::UnknownNixSetting);
+        assert!(plan.warnings()[0].message.contains("frobnicate"));
+        let described = plan.describe_install(false).await?;
+        assert!(described.contains("Warnings:"), "{described}");
+
+        let receipt: InstallPlan = serde_json::from_str(&serde_json::to_string(&plan)?)?;
+        assert_eq!(receipt.warnings(), plan.warnings());
+        // Receipts written before warnings were collected have none
+        assert!(empty_plan().await?.warnings().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_build_users_have_their_own_section() -> Result<(), NixInstallerError> {
+        let mut plan = empty_plan().await?;
+        let section = "Stale build users to delete (`--remove-stale-build-users`):";
+        assert!(!plan.describe_install(false).await?.contains(section));
+
+        plan.actions = serde_json::from_value(serde_json::json!([{
+            "action": {
+                "action_name": "delete_stale_build_users",
+                "users": [
+                    { "name": "oldbld1", "uid": 30101, "prefix": "oldbld" },
+                    { "name": "oldbld2", "uid": 30102, "prefix": "oldbld" },
+                ],
+                "delete_users": [],
+            },
+            "state": "Uncompleted",
+        }]))?;
+        let described = plan.describe_install(false).await?;
+        assert!(
+            described.contains(&format!(
+                "{section}\n* `oldbld1` (UID 30101, prefix `oldbld`)\n* `oldbld2` (UID 30102, prefix `oldbld`)\n"
+            )),
+            "{described}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn host_snapshot_is_explained() -> Result<(), NixInstallerError> {
+        // Receipts written before the snapshot was collected have none
+        let mut plan = empty_plan().await?;
+        assert!(plan.host_snapshot.is_none());
+        assert!(!plan
+            .describe_uninstall(true)
+            .await?
+            .contains("Host at install time"));
+
+        plan.host_snapshot = Some(HostSnapshot {
+            os_name: "Ubuntu".into(),
+            ..Default::default()
+        });
+        let receipt: InstallPlan = serde_json::from_str(&serde_json::to_string(&plan)?)?;
+        assert_eq!(receipt.host_snapshot, plan.host_snapshot);
+        let explained = receipt.describe_uninstall(true).await?;
+        assert!(explained.contains("Host at install time"), "{explained}");
+        assert!(explained.contains("Ubuntu"), "{explained}");
+        assert!(!receipt
+            .describe_uninstall(false)
+            .await?
+            .contains("Host at install time"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ensure_version_allows_compatible() -> Result<(), NixInstallerError> {
+        let planner = BuiltinPlanner::default().await?;
+        let good_version = Version::parse(env!("CARGO_PKG_VERSION"))?;
+        let value = serde_json::json!({
+            "planner": planner.boxed(),
+            "version": good_version,
+            "actions": [],
+        });
+        let maybe_plan: InstallPlan = serde_json::from_value(value)?;
+        maybe_plan.check_compatible()?;
+        Ok(())
     }
 
     #[tokio::test]
@@ -491,4 +2116,130 @@ mod test {
         assert!(maybe_plan.check_compatible().is_err());
         Ok(())
     }
+
+    const LINUX: &str = include_str!("../tests/fixtures/linux/linux.json");
+    const LINUX_0_18_0: &str = include_str!("../tests/fixtures/linux/linux-0.18.0.json");
+    const LINUX_0_26_0: &str = include_str!("../tests/fixtures/linux/linux-0.26.0.json");
+
+    #[test]
+    fn older_receipts_are_migrated() -> eyre::Result<()> {
+        let current: InstallPlan = serde_json::from_str(LINUX)?;
+        let current_tags = current
+            .actions
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        let dir = tempfile::tempdir()?;
+        for (version, receipt) in [
+            ("0.18.0", LINUX_0_18_0),
+            ("0.26.0", LINUX_0_26_0),
+            ("0.32.2", LINUX),
+        ] {
+            let path = dir.path().join(format!("{version}.json"));
+            std::fs::write(&path, receipt)?;
+            let plan = read_receipt(&path)?;
+            assert_eq!(plan.receipt_schema, RECEIPT_SCHEMA, "{version}");
+            plan.check_compatible()?;
+            let tags = plan
+                .actions
+                .iter()
+                .map(|action| action.inner_typetag_name())
+                .collect::<Vec<_>>();
+            assert_eq!(tags, current_tags, "{version}");
+
+            let plan = serde_json::to_value(&plan)?;
+            let url = plan.pointer("/actions/1/action/fetch_nix/action/url_or_path");
+            let package_url = plan.pointer("/planner/settings/nix_package_url");
+            if version == "0.32.2" {
+                assert_eq!(url, Some(&serde_json::Value::Null));
+            } else {
+                let tagged = serde_json::json!({
+                    "Url": "https://releases.nixos.org/nix/nix-2.24.9/nix-2.24.9-x86_64-linux.tar.xz",
+                });
+                assert_eq!(url, Some(&tagged), "{version}");
+                assert_eq!(package_url, Some(&tagged), "{version}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn newer_receipts_are_not_migrated() -> eyre::Result<()> {
+        let mut receipt: serde_json::Value = serde_json::from_str(LINUX)?;
+        receipt["receipt_schema"] = (RECEIPT_SCHEMA + 1).into();
+        let unmigrated = receipt.clone();
+        migrate(&mut receipt);
+        assert_eq!(receipt, unmigrated);
+
+        let plan: InstallPlan = serde_json::from_value(receipt)?;
+        assert!(matches!(
+            plan.check_compatible(),
+            Err(NixInstallerError::IncompatibleVersion { .. })
+        ));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn migrated_receipts_uninstall() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let dir = tempfile::tempdir()?;
+        let store = dir.path().join("nix");
+        let service = dir.path().join("nix-daemon.service");
+        let socket = dir.path().join("nix-daemon.socket");
+        std::fs::create_dir(&store)?;
+        std::fs::write(&service, "")?;
+        std::fs::write(&socket, "")?;
+
+        // The 0.18.0 receipt, with only the store and the daemon, kept in `dir`
+        let mut receipt: serde_json::Value = serde_json::from_str(LINUX_0_18_0)?;
+        let mut init_service = receipt["actions"]
+            .as_array()
+            .and_then(|actions| {
+                actions
+                    .iter()
+                    .find(|action| action["action"]["action_name"] == "configure_init_service")
+            })
+            .cloned()
+            .ok_or_else(|| eyre::eyre!("Fixture configures no init service"))?;
+        init_service["action"]["service_dest"] = service.to_str().into();
+        init_service["action"]["socket_files"][0]["dest"] = socket.to_str().into();
+        receipt["actions"] = serde_json::json!([
+            {
+                "action": {
+                    "action_name": "create_directory",
+                    "path": store,
+                    "user": null,
+                    "group": null,
+                    "mode": 493,
+                    "is_mountpoint": false,
+                    "force_prune_on_revert": true,
+                },
+                "state": "Completed",
+            },
+            init_service,
+        ]);
+        receipt["planner"]["init"] = serde_json::json!({ "init": "None", "start_daemon": false });
+        receipt["diagnostic_data"] = serde_json::Value::Null;
+
+        // Without migrating, the receipt cannot even be read
+        assert!(serde_json::from_value::<InstallPlan>(receipt.clone()).is_err());
+
+        let path = dir.path().join("receipt.json");
+        std::fs::write(&path, serde_json::to_string(&receipt)?)?;
+        let mut plan = read_receipt(&path)?;
+        assert_eq!(
+            plan.actions[1].inner_typetag_name(),
+            "create_upstream_init_service"
+        );
+        sandbox.scope(plan.uninstall(None)).await?;
+        assert!(plan
+            .actions
+            .iter()
+            .all(|action| action.state == ActionState::Uncompleted));
+        assert!(!store.exists());
+        assert!(!service.exists());
+        assert!(!socket.exists());
+        Ok(())
+    }
 }
@@ -0,0 +1,363 @@
+/*! Editing the plan of a [`Planner`] without writing one
+
+An [`InstallPlanBuilder`] plans with a planner, then removes and inserts top level actions by their
+[`ActionTag`], before checking that each action of the built in planners still has the actions it
+needs before it. The result is an ordinary [`InstallPlan`], which is written to the receipt and
+uninstalled like any other.
+*/
+
+use crate::{
+    action::{
+        base::{ImportSeedClosure, InstallDefaultProfileFlakes, VerifyStore},
+        common::{
+            ConfigureDeterminateNixdInitService, ConfigureNix, ConfigureUpstreamInitService,
+            ProvisionDeterminateNixd, ProvisionNix,
+        },
+        Action, ActionState, ActionTag, StatefulAction,
+    },
+    error::HasExpectedErrors,
+    planner::Planner,
+    InstallPlan, NixInstallerError,
+};
+
+/// Plans with a planner, then edits its actions, see [`InstallPlan::builder`]
+#[derive(Debug)]
+pub struct InstallPlanBuilder<P> {
+    planner: P,
+    edits: Vec<Edit>,
+}
+
+#[derive(Debug)]
+enum Edit {
+    Remove(ActionTag),
+    InsertBefore(ActionTag, StatefulAction<Box<dyn Action>>),
+    InsertAfter(ActionTag, StatefulAction<Box<dyn Action>>),
+}
+
+impl<P> InstallPlanBuilder<P>
+where
+    P: Planner + 'static,
+{
+    pub(crate) fn new(planner: P) -> Self {
+        Self {
+            planner,
+            edits: vec![],
+        }
+    }
+
+    /// Remove every action tagged `tag`
+    pub fn remove_action(mut self, tag: impl Into<ActionTag>) -> Self {
+        self.edits.push(Edit::Remove(tag.into()));
+        self
+    }
+
+    /// Insert `action` before the first action tagged `tag`, so it is executed before (and
+    /// reverted after) it
+    pub fn insert_before(
+        mut self,
+        tag: impl Into<ActionTag>,
+        action: StatefulAction<Box<dyn Action>>,
+    ) -> Self {
+        self.edits.push(Edit::InsertBefore(tag.into(), action));
+        self
+    }
+
+    /// Insert `action` after the first action tagged `tag`, so it is executed after (and reverted
+    /// before) it
+    pub fn insert_after(
+        mut self,
+        tag: impl Into<ActionTag>,
+        action: StatefulAction<Box<dyn Action>>,
+    ) -> Self {
+        self.edits.push(Edit::InsertAfter(tag.into(), action));
+        self
+    }
+
+    /// Plan with the planner, then make the edits in the order they were added
+    ///
+    /// Inserted actions must be [`Uncompleted`](ActionState::Uncompleted), and the plan is refused
+    /// if an action of the built in planners is left without an action it needs before it.
+    pub async fn build(self) -> Result<InstallPlan, NixInstallerError> {
+        let Self { planner, edits } = self;
+        let mut plan = InstallPlan::plan(planner).await?;
+        apply(&mut plan.actions, edits)?;
+        Ok(plan)
+    }
+}
+
+/// Make `edits` to `actions`, then check the dependencies of the result
+fn apply(
+    actions: &mut Vec<StatefulAction<Box<dyn Action>>>,
+    edits: Vec<Edit>,
+) -> Result<(), InstallPlanBuilderError> {
+    for edit in edits {
+        match edit {
+            Edit::Remove(tag) => {
+                let before = actions.len();
+                actions.retain(|action| action.inner_typetag_name() != tag.0);
+                if actions.len() == before {
+                    return Err(InstallPlanBuilderError::NotPlanned(tag.0));
+                }
+            },
+            Edit::InsertBefore(tag, action) => {
+                let index = position(actions, &tag, &action)?;
+                actions.insert(index, action);
+            },
+            Edit::InsertAfter(tag, action) => {
+                let index = position(actions, &tag, &action)?;
+                actions.insert(index + 1, action);
+            },
+        }
+    }
+    check_dependencies(actions)
+}
+
+/// The index of the first action tagged `tag`, to insert `action` next to
+fn position(
+    actions: &[StatefulAction<Box<dyn Action>>],
+    tag: &ActionTag,
+    action: &StatefulAction<Box<dyn Action>>,
+) -> Result<usize, InstallPlanBuilderError> {
+    if action.state != ActionState::Uncompleted {
+        return Err(InstallPlanBuilderError::ActionNotUncompleted {
+            action: action.inner_typetag_name(),
+            state: action.state,
+        });
+    }
+    actions
+        .iter()
+        .position(|action| action.inner_typetag_name() == tag.0)
+        .ok_or(InstallPlanBuilderError::NotPlanned(tag.0))
+}
+
+/// The actions of the built in planners, and the actions each needs executed before it
+fn dependencies() -> Vec<(ActionTag, Vec<ActionTag>)> {
+    vec![
+        (ConfigureNix::action_tag(), vec![ProvisionNix::action_tag()]),
+        (
+            ConfigureUpstreamInitService::action_tag(),
+            vec![ConfigureNix::action_tag()],
+        ),
+        (
+            ConfigureDeterminateNixdInitService::action_tag(),
+            vec![ProvisionDeterminateNixd::action_tag()],
+        ),
+        (
+            InstallDefaultProfileFlakes::action_tag(),
+            vec![ConfigureNix::action_tag()],
+        ),
+        (
+            ImportSeedClosure::action_tag(),
+            vec![ProvisionNix::action_tag()],
+        ),
+        (VerifyStore::action_tag(), vec![ProvisionNix::action_tag()]),
+    ]
+}
+
+/// Refuse `actions` if an action of the built in planners is missing an action it needs before it
+fn check_dependencies(
+    actions: &[StatefulAction<Box<dyn Action>>],
+) -> Result<(), InstallPlanBuilderError> {
+    let dependencies = dependencies();
+    for (index, action) in actions.iter().enumerate() {
+        let tag = action.inner_typetag_name();
+        let Some((_, needs)) = dependencies
+            .iter()
+            .find(|(dependent, _)| dependent.0 == tag)
+        else {
+            continue;
+        };
+        for needed in needs {
+            if !actions[..index]
+                .iter()
+                .any(|before| before.inner_typetag_name() == needed.0)
+            {
+                return Err(InstallPlanBuilderError::MissingDependency {
+                    action: tag,
+                    dependency: needed.0,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An error building an [`InstallPlan`] with an [`InstallPlanBuilder`]
+#[non_exhaustive]
+#[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
+pub enum InstallPlanBuilderError {
+    /// No action of the plan is tagged with it
+    #[error("The plan has no `{0}` action")]
+    NotPlanned(&'static str),
+    #[error("Inserted actions must be `Uncompleted`, but `{action}` is `{state:?}`")]
+    ActionNotUncompleted {
+        action: &'static str,
+        state: ActionState,
+    },
+    #[error(
+        "`{action}` needs `{dependency}` to be executed before it, which the plan no longer has"
+    )]
+    MissingDependency {
+        action: &'static str,
+        dependency: &'static str,
+    },
+}
+
+impl HasExpectedErrors for InstallPlanBuilderError {
+    fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
+        match self {
+            this @ InstallPlanBuilderError::NotPlanned(_) => Some(Box::new(this)),
+            this @ InstallPlanBuilderError::ActionNotUncompleted { .. } => Some(Box::new(this)),
+            this @ InstallPlanBuilderError::MissingDependency { .. } => Some(Box::new(this)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{apply, Edit, InstallPlanBuilderError};
+    use crate::{
+        action::{base::CreateDirectory, Action, ActionState, StatefulAction},
+        planner::{Planner, PlannerError},
+        settings::InstallSettingsError,
+        InstallPlan,
+    };
+
+    const LINUX: &str = include_str!("../tests/fixtures/linux/linux.json");
+
+    /// Plans only `/nix`
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct NixDirectory;
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "nix_directory")]
+    impl Planner for NixDirectory {
+        async fn default() -> Result<Self, PlannerError> {
+            Ok(Self)
+        }
+
+        async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+            Ok(vec![CreateDirectory::plan(
+                "/nix", None, None, 0o0755, true,
+            )
+            .await?
+            .boxed()])
+        }
+
+        fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+            Ok(HashMap::new())
+        }
+
+        async fn configured_settings(
+            &self,
+        ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
+            Ok(HashMap::new())
+        }
+
+        async fn platform_check(&self) -> Result<(), PlannerError> {
+            Ok(())
+        }
+
+        #[cfg(feature = "diagnostics")]
+        async fn diagnostic_data(
+            &self,
+        ) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
+            Ok(crate::diagnostics::DiagnosticData::new(
+                None,
+                None,
+                self.typetag_name().into(),
+                vec![],
+                None,
+                None,
+            )?)
+        }
+    }
+
+    async fn directory(path: &str) -> eyre::Result<StatefulAction<Box<dyn Action>>> {
+        Ok(CreateDirectory::plan(path, None, None, 0o0755, true)
+            .await?
+            .boxed())
+    }
+
+    fn tags(plan: &InstallPlan) -> Vec<&'static str> {
+        plan.actions
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn built_plans_are_ordinary_receipts() -> eyre::Result<()> {
+        let plan = InstallPlan::builder(NixDirectory)
+            .insert_after("create_directory", directory("/opt/after").await?)
+            // Now in front of `/nix`, as the first `create_directory` is still `/nix`
+            .insert_before("create_directory", directory("/opt/before").await?)
+            .build()
+            .await?;
+        let paths = plan
+            .actions
+            .iter()
+            .filter_map(|action| action.downcast_ref::<CreateDirectory>())
+            .map(|directory| directory.path.display().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/opt/before", "/nix", "/opt/after"]);
+
+        let receipt: InstallPlan = serde_json::from_str(&serde_json::to_string(&plan)?)?;
+        assert_eq!(tags(&receipt), tags(&plan));
+        assert_eq!(receipt.planner.typetag_name(), "nix_directory");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removing_what_others_need_is_refused() -> eyre::Result<()> {
+        let mut plan: InstallPlan = serde_json::from_str(LINUX)?;
+        assert!(matches!(
+            apply(
+                &mut plan.actions,
+                vec![Edit::Remove("provision_nix".into())]
+            ),
+            Err(InstallPlanBuilderError::MissingDependency {
+                action: "configure_nix",
+                dependency: "provision_nix",
+            })
+        ));
+
+        // Nothing left needs it
+        let mut plan: InstallPlan = serde_json::from_str(LINUX)?;
+        apply(
+            &mut plan.actions,
+            vec![Edit::Remove("create_upstream_init_service".into())],
+        )?;
+        assert!(!tags(&plan).contains(&"create_upstream_init_service"));
+
+        let mut plan: InstallPlan = serde_json::from_str(LINUX)?;
+        assert!(matches!(
+            apply(
+                &mut plan.actions,
+                vec![Edit::Remove("create_volume".into())]
+            ),
+            Err(InstallPlanBuilderError::NotPlanned("create_volume"))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inserted_actions_must_be_uncompleted() -> eyre::Result<()> {
+        let mut plan: InstallPlan = serde_json::from_str(LINUX)?;
+        let mut completed = directory("/opt/done").await?;
+        completed.state = ActionState::Completed;
+        assert!(matches!(
+            apply(
+                &mut plan.actions,
+                vec![Edit::InsertAfter("provision_nix".into(), completed)]
+            ),
+            Err(InstallPlanBuilderError::ActionNotUncompleted {
+                state: ActionState::Completed,
+                ..
+            })
+        ));
+        Ok(())
+    }
+}
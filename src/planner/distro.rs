@@ -0,0 +1,103 @@
+//! Linux distributions which need planning to deviate from the defaults
+
+use std::path::Path;
+
+use crate::util::host_path;
+
+/// A distribution with quirks the [`Linux`](crate::planner::linux::Linux) planner accounts for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Distro {
+    /// Amazon Linux 2023, which ECS-optimized AMIs are also based on
+    ///
+    /// SELinux is on by default, and the `nix` policy module is not loaded into its policy, so
+    /// file contexts are registered with `semanage fcontext` instead.
+    AmazonLinux2023,
+}
+
+impl Distro {
+    /// The distribution this host runs, if it has quirks
+    pub(crate) fn detect() -> Option<Self> {
+        Self::from_os_release(&host_path("/etc/os-release"))
+    }
+
+    fn from_os_release(path: &Path) -> Option<Self> {
+        let os_release = os_release::OsRelease::new_from(path).ok()?;
+        match (os_release.id.as_str(), os_release.version_id.as_str()) {
+            ("amzn", "2023") => Some(Self::AmazonLinux2023),
+            _ => None,
+        }
+    }
+
+    /// If SELinux is handled by registering file contexts instead of loading the policy module
+    pub(crate) fn selinux_file_contexts_only(&self) -> bool {
+        match self {
+            Self::AmazonLinux2023 => true,
+        }
+    }
+}
+
+impl std::fmt::Display for Distro {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AmazonLinux2023 => f.write_str("Amazon Linux 2023"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Distro;
+    use crate::test_harness::SandboxContext;
+
+    /// From an ECS-optimized AMI
+    const AMAZON_LINUX_2023: &str = r#"NAME="Amazon Linux"
+VERSION="2023"
+ID="amzn"
+ID_LIKE="fedora"
+VERSION_ID="2023"
+PLATFORM_ID="platform:al2023"
+PRETTY_NAME="Amazon Linux 2023.6.20241111"
+ANSI_COLOR="0;33"
+CPE_NAME="cpe:2.3:o:amazon:amazon_linux:2023"
+HOME_URL="https://aws.amazon.com/linux/amazon-linux-2023/"
+DOCUMENTATION_URL="https://docs.aws.amazon.com/linux/"
+SUPPORT_URL="https://aws.amazon.com/premiumsupport/"
+BUG_REPORT_URL="https://github.com/amazonlinux/amazon-linux-2023"
+VENDOR_NAME="AWS"
+VENDOR_URL="https://aws.amazon.com/"
+SUPPORT_END="2028-03-15"
+"#;
+
+    const AMAZON_LINUX_2: &str = r#"NAME="Amazon Linux"
+VERSION="2"
+ID="amzn"
+ID_LIKE="centos rhel fedora"
+VERSION_ID="2"
+PRETTY_NAME="Amazon Linux 2"
+"#;
+
+    const FEDORA: &str = r#"NAME="Fedora Linux"
+VERSION="41 (Server Edition)"
+ID=fedora
+VERSION_ID=41
+"#;
+
+    #[tokio::test]
+    async fn detects_amazon_linux_2023() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/etc"))?;
+
+        for (os_release, expected) in [
+            (AMAZON_LINUX_2023, Some(Distro::AmazonLinux2023)),
+            (AMAZON_LINUX_2, None),
+            (FEDORA, None),
+        ] {
+            std::fs::write(sandbox.path("/etc/os-release"), os_release)?;
+            assert_eq!(sandbox.scope(async { Distro::detect() }).await, expected);
+        }
+
+        std::fs::remove_file(sandbox.path("/etc/os-release"))?;
+        assert_eq!(sandbox.scope(async { Distro::detect() }).await, None);
+        Ok(())
+    }
+}
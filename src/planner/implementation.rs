@@ -0,0 +1,440 @@
+//! Nix implementations which may own an existing installation, such as Lix
+
+use std::process::Stdio;
+
+use tokio::process::Command;
+
+use crate::{
+    planner::PlannerError,
+    settings::CommonSettings,
+    util::host_path,
+    warning::{self, WarningKind},
+};
+
+/// The daemon units and launchd daemons an existing installation may have, in the order they are checked
+const DAEMON_UNITS: &[&str] = &[
+    "/etc/systemd/system/nix-daemon.service",
+    "/usr/lib/systemd/system/nix-daemon.service",
+    "/Library/LaunchDaemons/systems.determinate.nix-daemon.plist",
+    "/Library/LaunchDaemons/org.nixos.nix-daemon.plist",
+];
+
+/// The `nix` of the default profile, which resolves into the store path of the installed Nix
+const DEFAULT_PROFILE_NIX: &str = "/nix/var/nix/profiles/default/bin/nix";
+
+/// A Nix implementation an existing installation may belong to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NixImplementation {
+    /// Upstream Nix
+    Nix,
+    DeterminateNix,
+    Lix,
+    /// Another fork, by the name it gives in `nix --version`
+    Other(String),
+}
+
+impl NixImplementation {
+    /// From the output of `nix --version`, such as `nix (Lix, like Nix) 2.91.1`
+    fn from_version(version: &str) -> Option<Self> {
+        let name = version.lines().next()?.trim().strip_prefix("nix (")?;
+        let (name, _) = name.split_once(')')?;
+        // Forks describe themselves as `<name>, like Nix`
+        let name = name.strip_suffix(", like Nix").unwrap_or(name);
+        // Determinate Nix includes its own version, as in `Determinate Nix 3.0.0`
+        let name = name
+            .trim_end_matches(|c: char| c.is_ascii_digit() || c == '.')
+            .trim_end();
+        match name {
+            "" => None,
+            "Nix" => Some(Self::Nix),
+            "Determinate Nix" => Some(Self::DeterminateNix),
+            "Lix" => Some(Self::Lix),
+            other => Some(Self::Other(other.to_string())),
+        }
+    }
+
+    /// From the contents of a systemd unit or launchd plist starting the daemon
+    fn from_daemon_unit(contents: &str) -> Option<Self> {
+        if contents.contains("determinate-nixd") {
+            return Some(Self::DeterminateNix);
+        }
+        if contents.contains("docs.lix.systems") {
+            return Some(Self::Lix);
+        }
+        // Units linked from a profile start the daemon from its store path
+        Self::from_store_paths(contents)
+    }
+
+    /// From the first store path in `text` which names a package of a known implementation
+    fn from_store_paths(text: &str) -> Option<Self> {
+        text.match_indices("/nix/store/").find_map(|(idx, prefix)| {
+            let name = text[idx + prefix.len()..]
+                .split(|c: char| c == '/' || c.is_whitespace())
+                .next()?;
+            Self::from_store_path_name(name)
+        })
+    }
+
+    /// From a store path name, such as `7kfk0ix8dbzmdbr7v2vlnbg5r8k8l6y6-lix-2.91.1`
+    fn from_store_path_name(name: &str) -> Option<Self> {
+        let (_hash, name) = name.split_once('-')?;
+        // The package name ends where its version starts
+        let package = match name.find(|c: char| c.is_ascii_digit()) {
+            Some(version_start) => name[..version_start].strip_suffix('-')?,
+            None => return None,
+        };
+        match package {
+            "nix" => Some(Self::Nix),
+            "determinate-nix" => Some(Self::DeterminateNix),
+            "lix" => Some(Self::Lix),
+            _ => None,
+        }
+    }
+
+    /// If `nix-installer` installs this implementation itself, so its existing receipt handling applies
+    fn installed_by_nix_installer(&self) -> bool {
+        matches!(self, Self::Nix | Self::DeterminateNix)
+    }
+
+    /// Where to read about uninstalling this implementation
+    fn uninstall_guide(&self) -> &str {
+        match self {
+            Self::Nix => "https://nix.dev/manual/nix/stable/installation/uninstall",
+            Self::DeterminateNix => "run `/nix/nix-installer uninstall`",
+            Self::Lix => "https://docs.lix.systems/manual/lix/stable/installation/uninstall.html",
+            Self::Other(_) => "see its documentation",
+        }
+    }
+}
+
+impl std::fmt::Display for NixImplementation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nix => f.write_str("Nix"),
+            Self::DeterminateNix => f.write_str("Determinate Nix"),
+            Self::Lix => f.write_str("Lix"),
+            Self::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+/// An existing installation, and how the implementation owning it was recognized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExistingInstallation {
+    pub(crate) implementation: NixImplementation,
+    pub(crate) evidence: String,
+}
+
+impl ExistingInstallation {
+    /// Fingerprint an existing installation by its daemon unit, then the store path of the default
+    /// profile's `nix`, then `nix --version`
+    pub(crate) async fn detect() -> Option<Self> {
+        for unit in DAEMON_UNITS {
+            let Ok(contents) = std::fs::read_to_string(host_path(unit)) else {
+                continue;
+            };
+            if let Some(implementation) = NixImplementation::from_daemon_unit(&contents) {
+                return Some(Self {
+                    implementation,
+                    evidence: format!("daemon unit `{unit}`"),
+                });
+            }
+        }
+
+        if let Ok(nix) = std::fs::canonicalize(host_path(DEFAULT_PROFILE_NIX)) {
+            if let Some(implementation) =
+                NixImplementation::from_store_paths(&nix.to_string_lossy())
+            {
+                return Some(Self {
+                    implementation,
+                    evidence: format!("`{DEFAULT_PROFILE_NIX}` is `{}`", nix.display()),
+                });
+            }
+        }
+
+        let version = nix_version().await?;
+        Some(Self {
+            implementation: NixImplementation::from_version(&version)?,
+            evidence: format!("`nix --version` is `{version}`"),
+        })
+    }
+}
+
+async fn nix_version() -> Option<String> {
+    let nix = crate::util::which("nix").ok()?;
+    let output = crate::command_output(
+        Command::new(nix)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .process_group(0),
+    )
+    .await
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!version.is_empty()).then_some(version)
+}
+
+/// Detect an existing installation of a Nix implementation `nix-installer` does not install (such
+/// as Lix), returning if it will be replaced
+///
+/// Its daemon unit would be overwritten, so replacing it requires both
+/// [`CommonSettings::force`] and [`CommonSettings::replace_existing_implementation`].
+pub(crate) async fn check_existing_implementation(
+    settings: &CommonSettings,
+) -> Result<bool, PlannerError> {
+    let Some(existing) = ExistingInstallation::detect().await else {
+        return Ok(false);
+    };
+    tracing::debug!(
+        implementation = %existing.implementation,
+        evidence = existing.evidence,
+        "Found an existing installation"
+    );
+    if existing.implementation.installed_by_nix_installer() {
+        return Ok(false);
+    }
+
+    if !(settings.force && settings.replace_existing_implementation) {
+        return Err(PlannerError::ExistingImplementation {
+            implementation: existing.implementation.to_string(),
+            evidence: existing.evidence,
+            uninstall_guide: existing.implementation.uninstall_guide().to_string(),
+        });
+    }
+
+    warning::warn(
+        WarningKind::ReplacingImplementation,
+        format!(
+            "Replacing the existing {} installation ({}), its daemon will be overwritten",
+            existing.implementation, existing.evidence
+        ),
+    );
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_existing_implementation, ExistingInstallation, NixImplementation};
+    use crate::{
+        planner::PlannerError,
+        settings::CommonSettings,
+        test_harness::{FakeCommand, SandboxContext},
+    };
+
+    /// `nix-daemon.service` from the Lix 2.91.1 `lib/systemd/system`
+    const LIX_SERVICE: &str = "\
+[Unit]
+Description=Nix Daemon
+Documentation=man:nix-daemon https://docs.lix.systems/manual/lix/stable
+RequiresMountsFor=/nix/store
+RequiresMountsFor=/nix/var
+RequiresMountsFor=/nix/var/nix/db
+ConditionPathIsReadWrite=/nix/var/nix/daemon-socket
+
+[Service]
+ExecStart=@/nix/store/7kfk0ix8dbzmdbr7v2vlnbg5r8k8l6y6-lix-2.91.1/bin/nix-daemon nix-daemon --daemon
+KillMode=process
+LimitNOFILE=1048576
+TasksMax=1048576
+Delegate=yes
+
+[Install]
+WantedBy=multi-user.target
+";
+
+    /// `nix-daemon.service` from the Nix 2.24.10 `lib/systemd/system`
+    const NIX_SERVICE: &str = "\
+[Unit]
+Description=Nix Daemon
+Documentation=man:nix-daemon https://nixos.org/manual
+RequiresMountsFor=/nix/store
+RequiresMountsFor=/nix/var
+RequiresMountsFor=/nix/var/nix/db
+ConditionPathIsReadWrite=/nix/var/nix/daemon-socket
+
+[Service]
+ExecStart=@/nix/store/ahyjafkgyn6zji9qlvv92z8gxmcmaky4-nix-2.24.10/bin/nix-daemon nix-daemon --daemon
+KillMode=process
+LimitNOFILE=1048576
+TasksMax=1048576
+Delegate=yes
+
+[Install]
+WantedBy=multi-user.target
+";
+
+    /// `org.nixos.nix-daemon.plist` as installed by upstream Nix and Lix alike
+    const UPSTREAM_PLIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+  <dict>
+    <key>EnvironmentVariables</key>
+    <dict>
+      <key>OBJC_DISABLE_INITIALIZE_FORK_SAFETY</key>
+      <string>YES</string>
+    </dict>
+    <key>Label</key>
+    <string>org.nixos.nix-daemon</string>
+    <key>KeepAlive</key>
+    <true/>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>ProgramArguments</key>
+    <array>
+      <string>/bin/sh</string>
+      <string>-c</string>
+      <string>/bin/wait4path /nix/var/nix/profiles/default/bin/nix-daemon &amp;&amp; exec /nix/var/nix/profiles/default/bin/nix-daemon</string>
+    </array>
+    <key>StandardErrorPath</key>
+    <string>/var/log/nix-daemon.log</string>
+    <key>StandardOutPath</key>
+    <string>/dev/null</string>
+  </dict>
+</plist>
+"#;
+
+    #[test]
+    fn version_fingerprints() {
+        for (version, expected) in [
+            ("nix (Nix) 2.24.10", Some(NixImplementation::Nix)),
+            ("nix (Nix) 2.3.18\n", Some(NixImplementation::Nix)),
+            (
+                "nix (Determinate Nix 3.0.0) 2.26.3",
+                Some(NixImplementation::DeterminateNix),
+            ),
+            ("nix (Lix, like Nix) 2.91.1", Some(NixImplementation::Lix)),
+            (
+                "nix (Lix, like Nix) 2.92.0\nSystem type: x86_64-linux",
+                Some(NixImplementation::Lix),
+            ),
+            (
+                "nix (Snix, like Nix) 0.1.0",
+                Some(NixImplementation::Other("Snix".into())),
+            ),
+            ("nix-env (Nix) 2.24.10", None),
+            ("", None),
+        ] {
+            assert_eq!(
+                NixImplementation::from_version(version),
+                expected,
+                "{version}"
+            );
+        }
+    }
+
+    #[test]
+    fn daemon_unit_fingerprints() {
+        let determinate_service = include_str!(
+            "../action/common/configure_determinate_nixd_init_service/nix-daemon.determinate-nixd.service"
+        );
+        for (unit, expected) in [
+            (LIX_SERVICE, Some(NixImplementation::Lix)),
+            (NIX_SERVICE, Some(NixImplementation::Nix)),
+            (determinate_service, Some(NixImplementation::DeterminateNix)),
+            (
+                // Without the documentation link, as in older Lix releases
+                &LIX_SERVICE.replace("https://docs.lix.systems/manual/lix/stable", ""),
+                Some(NixImplementation::Lix),
+            ),
+            (UPSTREAM_PLIST, None),
+        ] {
+            assert_eq!(NixImplementation::from_daemon_unit(unit), expected);
+        }
+    }
+
+    #[test]
+    fn store_path_fingerprints() {
+        for (name, expected) in [
+            (
+                "7kfk0ix8dbzmdbr7v2vlnbg5r8k8l6y6-lix-2.91.1",
+                Some(NixImplementation::Lix),
+            ),
+            (
+                "ahyjafkgyn6zji9qlvv92z8gxmcmaky4-nix-2.24.10",
+                Some(NixImplementation::Nix),
+            ),
+            (
+                "ahyjafkgyn6zji9qlvv92z8gxmcmaky4-nix-2.24.10-man",
+                Some(NixImplementation::Nix),
+            ),
+            (
+                "hl4kzrmchkpmyh5lxn7r4a93xn4fxjgh-determinate-nix-3.0.0",
+                Some(NixImplementation::DeterminateNix),
+            ),
+            ("g0rmbs9kgzbfx3xrr2lb87j2n8vr6w2f-nix-daemon.conf", None),
+            ("hl4kzrmchkpmyh5lxn7r4a93xn4fxjgh-bash-5.2p37", None),
+        ] {
+            assert_eq!(NixImplementation::from_store_path_name(name), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn lix_installations_are_not_replaced_unacknowledged() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/etc/systemd/system"))?;
+        std::fs::write(
+            sandbox.path("/etc/systemd/system/nix-daemon.service"),
+            LIX_SERVICE,
+        )?;
+
+        assert_eq!(
+            sandbox.scope(ExistingInstallation::detect()).await,
+            Some(ExistingInstallation {
+                implementation: NixImplementation::Lix,
+                evidence: "daemon unit `/etc/systemd/system/nix-daemon.service`".into(),
+            })
+        );
+
+        let mut settings = sandbox.scope(CommonSettings::default()).await?;
+        let err = sandbox
+            .scope(check_existing_implementation(&settings))
+            .await
+            .expect_err("Lix was replaced without acknowledgment");
+        assert!(matches!(err, PlannerError::ExistingImplementation { .. }));
+        assert!(err
+            .to_string()
+            .starts_with("An existing Lix installation was detected"));
+
+        settings.force = true;
+        assert!(sandbox
+            .scope(check_existing_implementation(&settings))
+            .await
+            .is_err());
+
+        settings.replace_existing_implementation = true;
+        assert!(
+            sandbox
+                .scope(check_existing_implementation(&settings))
+                .await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn version_is_fingerprinted_without_a_daemon_unit() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake_once(
+            "nix",
+            FakeCommand::success().stdout("nix (Lix, like Nix) 2.91.1\n"),
+        );
+        sandbox.fake("nix", FakeCommand::success().stdout("nix (Nix) 2.24.10\n"));
+
+        let existing = sandbox.scope(ExistingInstallation::detect()).await;
+        assert_eq!(
+            existing.map(|existing| existing.implementation),
+            Some(NixImplementation::Lix)
+        );
+
+        // Installations `nix-installer` installs itself are left to the existing checks
+        let settings = sandbox.scope(CommonSettings::default()).await?;
+        assert!(
+            !sandbox
+                .scope(check_existing_implementation(&settings))
+                .await?
+        );
+        Ok(())
+    }
+}
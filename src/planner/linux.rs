@@ -1,4 +1,8 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    os::unix::fs::MetadataExt as _,
+    path::{Path, PathBuf},
+};
 
 use tokio::process::Command;
 use which::which;
@@ -6,21 +10,37 @@ use which::which;
 use super::ShellProfileLocations;
 use crate::{
     action::{
-        base::{CreateDirectory, RemoveDirectory},
+        base::{
+            CreateDirectory, ImportSeedClosure, InstallDefaultProfileFlakes, RemoveDirectory,
+            VerifyStore,
+        },
         common::{
             ConfigureDeterminateNixdInitService, ConfigureNix, ConfigureUpstreamInitService,
             CreateUsersAndGroups, ProvisionDeterminateNixd, ProvisionNix,
         },
         linux::{
+            create_store_bind_mount,
             provision_selinux::{DETERMINATE_SELINUX_POLICY_PP_CONTENT, SELINUX_POLICY_PP_CONTENT},
-            ProvisionSelinux,
+            provision_selinux_file_contexts::{
+                DETERMINATE_SELINUX_FILE_CONTEXTS, SELINUX_FILE_CONTEXTS,
+            },
+            CreateStoreBindMount, CreateZfsDataset, MigrateLegacyDaemonSocket, ProvisionSelinux,
+            ProvisionSelinuxFileContexts,
         },
-        StatefulAction,
+        ActionErrorKind, StatefulAction,
     },
+    backup::BackupStore,
+    cross_target, daemon_capabilities,
     error::HasExpectedErrors,
-    planner::{Planner, PlannerError},
+    messages::message,
+    planner::{
+        check_offline, check_shared_store, daemon_resources, distro::Distro,
+        implementation::check_existing_implementation, plan_daemon_tcp_listener, plan_daemon_user,
+        plan_service_mode, plan_stale_build_users, Planner, PlannerError,
+    },
     settings::{
         determinate_nix_settings, CommonSettings, InitSettings, InitSystem, InstallSettingsError,
+        SystemdScope, UrlOrPathOrString,
     },
     Action, BuiltinPlanner,
 };
@@ -35,6 +55,51 @@ pub struct Linux {
     pub settings: CommonSettings,
     #[cfg_attr(feature = "cli", clap(flatten))]
     pub init: InitSettings,
+
+    /// Create the ZFS dataset (like `rpool/nix`) mounted at `/nix` and install into it
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_ZFS_DATASET"))]
+    #[serde(default)]
+    pub zfs_dataset: Option<String>,
+    /// Exclude the `--zfs-dataset` from automatic snapshots, by setting `com.sun:auto-snapshot=false`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(clap::ArgAction::SetTrue),
+            default_value = "false",
+            requires = "zfs_dataset",
+            env = "NIX_INSTALLER_ZFS_NO_AUTO_SNAPSHOT"
+        )
+    )]
+    #[serde(default)]
+    pub zfs_no_auto_snapshot: bool,
+    /// Keep the Nix store in this directory on another filesystem (like `/data/nix`), bind mounted at `/nix`
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, conflicts_with = "zfs_dataset", env = "NIX_INSTALLER_STORE_ROOT")
+    )]
+    #[serde(default)]
+    pub store_root: Option<PathBuf>,
+    /// Run the Nix daemon in the systemd user manager of the `--daemon-user`, rather than the system manager
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            value_enum,
+            default_value_t = SystemdScope::System,
+            requires_if("user", "daemon_user"),
+            env = "NIX_INSTALLER_SYSTEMD_SCOPE"
+        )
+    )]
+    #[serde(default)]
+    pub systemd_scope: SystemdScope,
+}
+
+/// The user whose systemd user manager runs the Nix daemon, with `--systemd-scope user`
+struct UserManager {
+    name: String,
+    uid: u32,
+    home: PathBuf,
 }
 
 #[async_trait::async_trait]
@@ -44,107 +109,236 @@ impl Planner for Linux {
         Ok(Self {
             settings: CommonSettings::default().await?,
             init: InitSettings::default().await?,
+            zfs_dataset: None,
+            zfs_no_auto_snapshot: false,
+            store_root: None,
+            systemd_scope: SystemdScope::System,
         })
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
-        let has_selinux = detect_selinux().await?;
+        // Distributions where SELinux is handled by only registering file contexts
+        let (file_contexts_distro, has_selinux) = if cross_target::is_active() {
+            cross_target::placeholder("selinux");
+            (None, false)
+        } else {
+            let file_contexts_distro = Distro::detect().filter(Distro::selinux_file_contexts_only);
+            let has_selinux = match file_contexts_distro {
+                Some(distro) => detect_selinux_file_contexts(distro).await?,
+                None => detect_selinux().await?,
+            };
+            (file_contexts_distro, has_selinux)
+        };
+        check_offline(&self.settings)?;
+        let shared_store = check_shared_store(&self.settings)?;
+        let daemon_tcp_listener =
+            plan_daemon_tcp_listener(&self.settings, self.init.init, self.init.start_daemon)
+                .await?;
+        let service_mode = plan_service_mode(&self.settings, self.init.init)?;
+        let user_manager = self.check_user_manager().await?;
+        let daemon_resources =
+            daemon_resources(&self.settings, self.init.init, user_manager.is_some())?;
+        let daemon_user =
+            plan_daemon_user(&self.settings, self.init.init, shared_store, None).await?;
+        let stale_build_users = plan_stale_build_users(&self.settings).await?;
 
         let mut plan = vec![];
 
+        if let Some(zfs_dataset) = &self.zfs_dataset {
+            plan.push(
+                cross_target::plan_action(CreateZfsDataset::plan(
+                    zfs_dataset,
+                    self.zfs_no_auto_snapshot,
+                ))
+                .await?,
+            );
+        }
+        if let Some(store_root) = &self.store_root {
+            check_store_root(store_root)?;
+            plan.push(cross_target::plan_action(CreateStoreBindMount::plan(store_root)).await?);
+        }
+
+        let create_nix_directory = CreateDirectory::plan("/nix", None, None, 0o0755, !shared_store)
+            .await
+            .map_err(PlannerError::Action)?;
         plan.push(
-            CreateDirectory::plan("/nix", None, None, 0o0755, true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            if shared_store {
+                create_nix_directory.preserve_existing()
+            } else if self.zfs_dataset.is_some() || self.store_root.is_some() {
+                create_nix_directory.onto_mountpoint()
+            } else {
+                create_nix_directory
+            }
+            .boxed(),
         );
 
         if self.settings.determinate_nix {
-            plan.push(
-                ProvisionDeterminateNixd::plan()
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
-            );
+            plan.push(cross_target::plan_action(ProvisionDeterminateNixd::plan()).await?);
         }
 
         plan.push(
-            ProvisionNix::plan(&self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(ProvisionNix::plan(&self.settings.clone(), shared_store))
+                .await?,
         );
+        plan.extend(stale_build_users);
         plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(CreateUsersAndGroups::plan(self.settings.clone())).await?,
         );
+        // Only the daemon may use the store directly, everything else reaches it through its socket
+        let mut settings = self.settings.clone();
+        if let Some(UserManager { uid, .. }) = &user_manager {
+            settings.extra_conf.push(UrlOrPathOrString::String(format!(
+                "store = unix:///run/user/{uid}/nix/daemon-socket/socket"
+            )));
+        }
         plan.push(
-            ConfigureNix::plan(
+            cross_target::plan_action(ConfigureNix::plan(
                 ShellProfileLocations::default(),
-                &self.settings,
+                &settings,
                 self.settings.determinate_nix.then(determinate_nix_settings),
-            )
-            .await
-            .map_err(PlannerError::Action)?
-            .boxed(),
+            ))
+            .await?,
         );
 
-        if has_selinux {
+        plan.extend(daemon_user);
+
+        if let (true, Some(distro)) = (has_selinux, file_contexts_distro) {
+            plan.push(
+                cross_target::plan_action(ProvisionSelinuxFileContexts::plan(
+                    if self.settings.determinate_nix {
+                        DETERMINATE_SELINUX_FILE_CONTEXTS
+                    } else {
+                        SELINUX_FILE_CONTEXTS
+                    },
+                    format!("{distro} was detected, the `nix` policy module is not loaded into its SELinux policy."),
+                )).await?,
+            );
+        } else if has_selinux {
             plan.push(
-                ProvisionSelinux::plan(
+                cross_target::plan_action(ProvisionSelinux::plan(
                     FHS_SELINUX_POLICY_PATH.into(),
                     if self.settings.determinate_nix {
                         DETERMINATE_SELINUX_POLICY_PP_CONTENT
                     } else {
                         SELINUX_POLICY_PP_CONTENT
                     },
-                )
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                ))
+                .await?,
             );
         }
 
         plan.push(
-            CreateDirectory::plan("/etc/tmpfiles.d", None, None, 0o0755, false)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(CreateDirectory::plan(
+                "/etc/tmpfiles.d",
+                None,
+                None,
+                0o0755,
+                false,
+            ))
+            .await?,
         );
 
+        if self.init.init == InitSystem::Systemd {
+            plan.push(
+                cross_target::plan_action(MigrateLegacyDaemonSocket::plan(BackupStore::new(
+                    &self.settings.state_dir,
+                )))
+                .await?,
+            );
+        }
+
         if self.settings.determinate_nix {
             plan.push(
-                ConfigureDeterminateNixdInitService::plan(self.init.init, self.init.start_daemon)
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
+                cross_target::plan_action(async {
+                    Ok(ConfigureDeterminateNixdInitService::plan(
+                        self.init.init,
+                        self.init.start_daemon,
+                    )
+                    .await?
+                    .with_resources(daemon_resources))
+                })
+                .await?,
             );
         } else {
+            let init_service = async {
+                let init_service = ConfigureUpstreamInitService::plan(
+                    self.init.init,
+                    self.init.start_daemon,
+                    self.settings.daemon_user(),
+                )
+                .await?;
+                let init_service = match &user_manager {
+                    Some(UserManager { name, uid, home }) => {
+                        init_service.in_user_manager(name, *uid, home)
+                    },
+                    None => init_service,
+                };
+                Ok(init_service
+                    .with_service_mode(service_mode)
+                    .with_resources(daemon_resources))
+            };
+            plan.push(cross_target::plan_action(init_service).await?);
+        }
+        plan.extend(daemon_tcp_listener);
+        if self
+            .settings
+            .default_profile_packages
+            .iter()
+            .any(|package| package.flake().is_some())
+        {
+            plan.push(
+                cross_target::plan_action(InstallDefaultProfileFlakes::plan(
+                    &self.settings.default_profile_packages,
+                ))
+                .await?,
+            );
+        }
+        if let Some(seed_closure) = &self.settings.seed_closure {
             plan.push(
-                ConfigureUpstreamInitService::plan(self.init.init, self.init.start_daemon)
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
+                cross_target::plan_action(ImportSeedClosure::plan(
+                    seed_closure,
+                    self.settings.seed_closure_require_sigs,
+                ))
+                .await?,
+            );
+        }
+        if self.settings.verify_store {
+            let series = daemon_capabilities::series_to_configure(&self.settings)?;
+            plan.push(
+                cross_target::plan_action(VerifyStore::plan(
+                    series.as_deref(),
+                    self.init.start_daemon && self.init.init != InitSystem::None,
+                ))
+                .await?,
             );
         }
         plan.push(
-            RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(RemoveDirectory::plan(crate::settings::SCRATCH_DIR)).await?,
         );
 
         Ok(plan)
     }
 
     fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
-        let Self { settings, init } = self;
+        let Self {
+            settings,
+            init,
+            zfs_dataset,
+            zfs_no_auto_snapshot,
+            store_root,
+            systemd_scope,
+        } = self;
         let mut map = HashMap::default();
 
         map.extend(settings.settings()?);
         map.extend(init.settings()?);
+        map.insert("zfs_dataset".into(), serde_json::to_value(zfs_dataset)?);
+        map.insert(
+            "zfs_no_auto_snapshot".into(),
+            serde_json::to_value(zfs_no_auto_snapshot)?,
+        );
+        map.insert("store_root".into(), serde_json::to_value(store_root)?);
+        map.insert("systemd_scope".into(), serde_json::to_value(systemd_scope)?);
 
         Ok(map)
     }
@@ -169,19 +363,20 @@ impl Planner for Linux {
     async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
         Ok(crate::diagnostics::DiagnosticData::new(
             self.settings.diagnostic_attribution.clone(),
-            self.settings.diagnostic_endpoint.clone(),
+            self.settings.diagnostic_endpoint_unless_offline(),
             self.typetag_name().into(),
             self.configured_settings()
                 .await?
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
+            self.settings.proxy.clone(),
         )?)
     }
 
     async fn platform_check(&self) -> Result<(), PlannerError> {
         use target_lexicon::OperatingSystem;
-        match target_lexicon::OperatingSystem::host() {
+        match crate::cross_target::operating_system() {
             OperatingSystem::Linux => Ok(()),
             host_os => Err(PlannerError::IncompatibleOperatingSystem {
                 planner: self.typetag_name(),
@@ -203,7 +398,7 @@ impl Planner for Linux {
     async fn pre_install_check(&self) -> Result<(), PlannerError> {
         check_not_nixos()?;
 
-        check_nix_not_already_installed().await?;
+        check_nix_not_already_installed(&self.settings).await?;
 
         check_not_wsl1()?;
 
@@ -215,6 +410,52 @@ impl Planner for Linux {
     }
 }
 
+impl Linux {
+    /// With `--systemd-scope user`, check the `--daemon-user` exists and can run a user manager
+    async fn check_user_manager(&self) -> Result<Option<UserManager>, PlannerError> {
+        if self.systemd_scope == SystemdScope::System {
+            return Ok(None);
+        }
+        if self.init.init != InitSystem::Systemd {
+            return Err(LinuxErrorKind::UserScopeRequiresSystemd(self.init.init).into());
+        }
+        let Some(name) = &self.settings.daemon_user else {
+            return Err(LinuxErrorKind::UserScopeRequiresDaemonUser.into());
+        };
+        let user = nix::unistd::User::from_name(name)
+            .map_err(|e| {
+                PlannerError::Action(ConfigureUpstreamInitService::error(
+                    ActionErrorKind::GettingUserId(name.clone(), e),
+                ))
+            })?
+            .ok_or_else(|| LinuxErrorKind::UserScopeUserMissing(name.clone()))?;
+        if user.uid.as_raw() != self.settings.daemon_user_id {
+            return Err(LinuxErrorKind::UserScopeUidMismatch {
+                user: name.clone(),
+                uid: user.uid.as_raw(),
+                expected: self.settings.daemon_user_id,
+            }
+            .into());
+        }
+        // Lingering does not activate a home managed by systemd-homed, only logging in does
+        if crate::homed::homed_user(name).await.is_some() {
+            return Err(LinuxErrorKind::UserScopeHomed(name.clone()).into());
+        }
+        if !crate::util::host_path(&user.dir).is_dir() {
+            return Err(LinuxErrorKind::UserScopeNoHome {
+                user: name.clone(),
+                home: user.dir,
+            }
+            .into());
+        }
+        Ok(Some(UserManager {
+            name: name.clone(),
+            uid: user.uid.as_raw(),
+            home: user.dir,
+        }))
+    }
+}
+
 impl From<Linux> for BuiltinPlanner {
     fn from(val: Linux) -> Self {
         BuiltinPlanner::Linux(val)
@@ -253,7 +494,28 @@ pub(crate) async fn detect_selinux() -> Result<bool, PlannerError> {
     }
 }
 
-pub(crate) async fn check_nix_not_already_installed() -> Result<(), PlannerError> {
+/// Like [`detect_selinux`], for distributions which only register file contexts, see [`Distro::selinux_file_contexts_only`]
+async fn detect_selinux_file_contexts(distro: Distro) -> Result<bool, PlannerError> {
+    if !Path::new("/sys/fs/selinux").exists() {
+        return Ok(false);
+    }
+    if which("semanage").is_ok() && which("restorecon").is_ok() {
+        Ok(true)
+    } else {
+        Err(PlannerError::SelinuxFileContextRequirements(
+            distro.to_string(),
+        ))
+    }
+}
+
+pub(crate) async fn check_nix_not_already_installed(
+    settings: &CommonSettings,
+) -> Result<(), PlannerError> {
+    if check_existing_implementation(settings).await? {
+        // The existing `nix-env` belongs to the installation being replaced
+        return Ok(());
+    }
+
     // For now, we don't try to repair the user's Nix install or anything special.
     if Command::new("nix-env")
         .arg("--version")
@@ -280,29 +542,77 @@ pub(crate) fn check_systemd_active() -> Result<(), PlannerError> {
     Ok(())
 }
 
+/// Check `--store-root` is an empty directory on a filesystem other than `/`, unless it is already bind mounted at `/nix`
+pub(crate) fn check_store_root(store_root: &Path) -> Result<(), PlannerError> {
+    let error = |kind: ActionErrorKind| PlannerError::Action(CreateStoreBindMount::error(kind));
+    if !store_root.is_absolute() {
+        return Err(LinuxErrorKind::StoreRootNotAbsolute(store_root.to_path_buf()).into());
+    }
+    let host_store_root = crate::util::host_path(store_root);
+    let metadata = match std::fs::metadata(&host_store_root) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(LinuxErrorKind::StoreRootMissing(store_root.to_path_buf()).into())
+        },
+        Err(e) => return Err(error(ActionErrorKind::GettingMetadata(host_store_root, e))),
+    };
+    if !metadata.is_dir() {
+        return Err(LinuxErrorKind::StoreRootNotDirectory(store_root.to_path_buf()).into());
+    }
+    let root = crate::util::host_path("/");
+    let root =
+        std::fs::metadata(&root).map_err(|e| error(ActionErrorKind::GettingMetadata(root, e)))?;
+    if metadata.dev() == root.dev() {
+        return Err(LinuxErrorKind::StoreRootOnRootFilesystem(store_root.to_path_buf()).into());
+    }
+    if create_store_bind_mount::is_bind_mounted(store_root).map_err(error)? {
+        // Planning again after the bind mount, when it holds the store
+        return Ok(());
+    }
+    let mut entries = host_store_root
+        .read_dir()
+        .map_err(|e| error(ActionErrorKind::Read(host_store_root.clone(), e)))?;
+    if entries.next().is_some() {
+        return Err(LinuxErrorKind::StoreRootNotEmpty(store_root.to_path_buf()).into());
+    }
+    Ok(())
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum LinuxErrorKind {
-    #[error(
-        "\
-        systemd was not active.\n\
-        \n\
-        If it will be started later consider, passing `--no-start-daemon`.\n\
-        \n\
-        To use a `root`-only Nix install, consider passing `--init none`."
-    )]
+    #[error("{}", message!(ErrorSystemdNotActive))]
     SystemdNotActive,
+    #[error("{}", message!(ErrorWsl2SystemdNotActive))]
+    Wsl2SystemdNotActive,
+    #[error("The `--store-root` `{0}` must be an absolute path")]
+    StoreRootNotAbsolute(PathBuf),
+    #[error("The `--store-root` `{0}` does not exist, create it on the filesystem the Nix store should live on")]
+    StoreRootMissing(PathBuf),
+    #[error("The `--store-root` `{0}` is not a directory")]
+    StoreRootNotDirectory(PathBuf),
+    #[error("The `--store-root` `{0}` is on the same filesystem as `/`, choose a directory on another disk or install without `--store-root`")]
+    StoreRootOnRootFilesystem(PathBuf),
+    #[error("The `--store-root` `{0}` is not empty, the Nix store must be installed into an empty directory")]
+    StoreRootNotEmpty(PathBuf),
+    #[error("`--systemd-scope user` requires `--init systemd`, not `--init {0}`")]
+    UserScopeRequiresSystemd(InitSystem),
     #[error(
-        "\
-        systemd was not active.\n\
-        \n\
-        On WSL2, systemd is not enabled by default. Consider enabling it by adding it to your `/etc/wsl.conf` with `echo -e '[boot]\\nsystemd=true'` then restarting WSL2 with `wsl.exe --shutdown` and re-entering the WSL shell. For more information, see https://devblogs.microsoft.com/commandline/systemd-support-is-now-available-in-wsl/.\n\
-        \n\
-        If it will be started later consider, passing `--no-start-daemon`.\n\
-        \n\
-        To use a `root`-only Nix install, consider passing `--init none`."
+        "`--systemd-scope user` requires a `--daemon-user`, whose user manager runs the Nix daemon"
     )]
-    Wsl2SystemdNotActive,
+    UserScopeRequiresDaemonUser,
+    #[error("`--systemd-scope user` requires the `--daemon-user` `{0}` to already exist, with a home directory for its systemd units")]
+    UserScopeUserMissing(String),
+    #[error("The `--daemon-user` `{user}` has UID {uid}, pass `--daemon-user-id {uid}` rather than {expected}")]
+    UserScopeUidMismatch {
+        user: String,
+        uid: u32,
+        expected: u32,
+    },
+    #[error("The `--daemon-user` `{user}` has no home directory `{}` for its systemd units", home.display())]
+    UserScopeNoHome { user: String, home: PathBuf },
+    #[error("The home of the `--daemon-user` `{0}` is managed by systemd-homed, which only activates it while they are logged in, so their user manager could not load the Nix daemon units; pick another `--daemon-user`, or install with `--systemd-scope system`")]
+    UserScopeHomed(String),
 }
 
 impl HasExpectedErrors for LinuxErrorKind {
@@ -310,6 +620,17 @@ impl HasExpectedErrors for LinuxErrorKind {
         match self {
             LinuxErrorKind::SystemdNotActive => Some(Box::new(self)),
             LinuxErrorKind::Wsl2SystemdNotActive => Some(Box::new(self)),
+            LinuxErrorKind::StoreRootNotAbsolute(_)
+            | LinuxErrorKind::StoreRootMissing(_)
+            | LinuxErrorKind::StoreRootNotDirectory(_)
+            | LinuxErrorKind::StoreRootOnRootFilesystem(_)
+            | LinuxErrorKind::StoreRootNotEmpty(_) => Some(Box::new(self)),
+            LinuxErrorKind::UserScopeRequiresSystemd(_)
+            | LinuxErrorKind::UserScopeRequiresDaemonUser
+            | LinuxErrorKind::UserScopeUserMissing(_)
+            | LinuxErrorKind::UserScopeUidMismatch { .. }
+            | LinuxErrorKind::UserScopeNoHome { .. }
+            | LinuxErrorKind::UserScopeHomed(_) => Some(Box::new(self)),
         }
     }
 }
@@ -319,3 +640,393 @@ impl From<LinuxErrorKind> for PlannerError {
         PlannerError::Custom(Box::new(v))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::{Linux, LinuxErrorKind};
+    use crate::{
+        planner::{Planner, PlannerError},
+        test_harness::{FakeCommand, SandboxContext},
+        warning::WarningKind,
+    };
+
+    #[test]
+    fn minimal_resolves_to_individual_settings() -> eyre::Result<()> {
+        let linux = Linux::try_parse_from(["linux", "--minimal", "--init", "systemd"])?;
+        let settings = linux.settings()?;
+        assert_eq!(settings["minimal"], serde_json::json!(true));
+        assert_eq!(settings["modify_profile"], serde_json::json!(false));
+
+        assert!(Linux::try_parse_from([
+            "linux",
+            "--minimal",
+            "--default-profile-package",
+            "nixpkgs#git"
+        ])
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ci_implies_no_modify_profile_and_is_configured() -> eyre::Result<()> {
+        let linux = Linux::try_parse_from(["linux", "--ci", "--init", "systemd"])?;
+        let settings = linux.settings()?;
+        assert_eq!(settings["ci"], serde_json::json!(true));
+        assert_eq!(settings["modify_profile"], serde_json::json!(false));
+        Ok(())
+    }
+
+    // The actions of a `--minimal` install are documented, so they should only change deliberately
+    #[tokio::test]
+    async fn minimal_plan_is_stable() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let linux = Linux::try_parse_from(["linux", "--minimal", "--init", "none"])?;
+        let shell = sandbox.path(&linux.settings.nix_build_user_shell);
+        std::fs::create_dir_all(shell.parent().unwrap())?;
+        std::fs::write(shell, "")?;
+        let plan = sandbox.scope(linux.plan()).await?;
+        let tags = plan
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            [
+                "create_directory",
+                "provision_nix",
+                "create_users_and_group",
+                "configure_nix",
+                "create_directory",
+                "create_upstream_init_service",
+                "remove_directory",
+            ]
+        );
+        let configure_nix = serde_json::to_value(&plan[3])?;
+        assert_eq!(
+            configure_nix["action"]["configure_shell_profile"],
+            serde_json::Value::Null
+        );
+        Ok(())
+    }
+    // `--trace-actions` finds the spans of actions by their tag
+    #[tokio::test]
+    async fn action_spans_are_named_after_their_tag() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let linux = Linux::try_parse_from(["linux", "--init", "none"])?;
+        let shell = sandbox.path(&linux.settings.nix_build_user_shell);
+        std::fs::create_dir_all(shell.parent().unwrap())?;
+        std::fs::write(shell, "")?;
+        let plan = sandbox.scope(linux.plan()).await?;
+
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry());
+        for action in &plan {
+            let span = action.tracing_span();
+            let metadata = span.metadata().expect("spans are enabled");
+            assert_eq!(metadata.name(), action.inner_typetag_name());
+            assert!(
+                !metadata.fields().is_empty(),
+                "`{}` has no span fields",
+                metadata.name()
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn daemon_tcp_listen_is_opt_in() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        assert!(Linux::try_parse_from(["linux", "--daemon-tcp-listen", "10.0.0.5:9000"]).is_err());
+        assert!(Linux::try_parse_from(["linux", "--daemon-tcp-allow-wildcard"]).is_err());
+
+        let acknowledged = |listen: &str| {
+            Linux::try_parse_from([
+                "linux",
+                "--init",
+                "none",
+                "--daemon-tcp-listen",
+                listen,
+                "--i-understand-daemon-tcp-is-unauthenticated",
+            ])
+        };
+        for wildcard in ["0.0.0.0:9000", "[::]:9000"] {
+            let linux = acknowledged(wildcard)?;
+            assert!(matches!(
+                sandbox.scope(linux.plan()).await,
+                Err(PlannerError::DaemonTcpWildcard(_))
+            ));
+        }
+        let linux = acknowledged("10.0.0.5:9000")?;
+        assert!(matches!(
+            sandbox.scope(linux.plan()).await,
+            Err(PlannerError::DaemonTcpRequiresInit)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn daemon_user_is_validated() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        assert!(Linux::try_parse_from(["linux", "--daemon-user-id", "30100"]).is_err());
+        for invalid in ["root", "", "-nix", "nix daemon"] {
+            assert!(
+                Linux::try_parse_from(["linux", "--daemon-user", invalid]).is_err(),
+                "`{invalid}` should be rejected"
+            );
+        }
+
+        let linux =
+            Linux::try_parse_from(["linux", "--init", "none", "--daemon-user", "nix-daemon"])?;
+        assert_eq!(
+            linux.settings.daemon_user().map(|user| user.uid),
+            Some(crate::settings::default_daemon_user_id())
+        );
+        assert!(matches!(
+            sandbox.scope(linux.plan()).await,
+            Err(PlannerError::DaemonUserRequiresInit)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn daemon_resources_are_validated() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        for (flag, invalid) in [
+            ("--daemon-nice", "20"),
+            ("--daemon-nice", "-21"),
+            ("--daemon-cpu-weight", "0"),
+            ("--daemon-io-weight", "10001"),
+        ] {
+            assert!(
+                Linux::try_parse_from(["linux", flag, invalid]).is_err(),
+                "`{flag} {invalid}` should be rejected"
+            );
+        }
+
+        let linux = Linux::try_parse_from([
+            "linux",
+            "--init",
+            "none",
+            "--daemon-nice",
+            "-5",
+            "--daemon-cpu-weight",
+            "20",
+        ])?;
+        assert_eq!(linux.settings.daemon_resources().nice, Some(-5));
+        assert_eq!(
+            linux.settings()?["daemon_cpu_weight"],
+            serde_json::json!(20)
+        );
+        assert!(matches!(
+            sandbox.scope(linux.plan()).await,
+            Err(PlannerError::DaemonResourcesRequireInit)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn zfs_dataset_is_created_before_nix() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        assert!(Linux::try_parse_from(["linux", "--zfs-no-auto-snapshot"]).is_err());
+        sandbox.fake(
+            "zfs",
+            FakeCommand::success().stdout("rpool\tnone\nrpool/ROOT/debian\t/\n"),
+        );
+        let linux = Linux::try_parse_from([
+            "linux",
+            "--init",
+            "none",
+            "--zfs-dataset",
+            "rpool/nix",
+            "--zfs-no-auto-snapshot",
+        ])?;
+        let settings = linux.settings()?;
+        assert_eq!(settings["zfs_dataset"], serde_json::json!("rpool/nix"));
+        assert_eq!(settings["zfs_no_auto_snapshot"], serde_json::json!(true));
+
+        let shell = sandbox.path(&linux.settings.nix_build_user_shell);
+        std::fs::create_dir_all(shell.parent().unwrap())?;
+        std::fs::write(shell, "")?;
+        let plan = sandbox.scope(linux.plan()).await?;
+        assert_eq!(plan[0].inner_typetag_name(), "create_zfs_dataset");
+        // `/nix` is the dataset's mountpoint, so it is only cleaned for the dataset to be destroyed
+        let create_nix_directory = serde_json::to_value(&plan[1])?;
+        assert_eq!(create_nix_directory["action"]["path"], "/nix");
+        assert_eq!(create_nix_directory["action"]["is_mountpoint"], true);
+
+        std::fs::create_dir_all(sandbox.path("/nix/store"))?;
+        assert!(matches!(
+            sandbox.scope(linux.plan()).await,
+            Err(PlannerError::Action(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_root_is_validated() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        assert!(Linux::try_parse_from([
+            "linux",
+            "--store-root",
+            "/data/nix",
+            "--zfs-dataset",
+            "rpool/nix"
+        ])
+        .is_err());
+        let linux = |store_root: &str| {
+            Linux::try_parse_from(["linux", "--init", "none", "--store-root", store_root])
+        };
+        let store_root_error = |result: Result<_, PlannerError>| match result {
+            Err(PlannerError::Custom(e)) => e.downcast::<LinuxErrorKind>().ok().map(|e| *e),
+            _ => None,
+        };
+
+        assert!(matches!(
+            store_root_error(sandbox.scope(linux("data/nix")?.plan()).await),
+            Some(LinuxErrorKind::StoreRootNotAbsolute(_))
+        ));
+        assert!(matches!(
+            store_root_error(sandbox.scope(linux("/data/nix")?.plan()).await),
+            Some(LinuxErrorKind::StoreRootMissing(_))
+        ));
+        std::fs::create_dir_all(sandbox.path("/data"))?;
+        std::fs::write(sandbox.path("/data/nix"), "")?;
+        assert!(matches!(
+            store_root_error(sandbox.scope(linux("/data/nix")?.plan()).await),
+            Some(LinuxErrorKind::StoreRootNotDirectory(_))
+        ));
+        // The sandbox is a single filesystem
+        std::fs::remove_file(sandbox.path("/data/nix"))?;
+        std::fs::create_dir(sandbox.path("/data/nix"))?;
+        assert!(matches!(
+            store_root_error(sandbox.scope(linux("/data/nix")?.plan()).await),
+            Some(LinuxErrorKind::StoreRootOnRootFilesystem(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn systemd_user_scope_needs_an_existing_daemon_user() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        assert!(Linux::try_parse_from(["linux", "--systemd-scope", "user"]).is_err());
+
+        let linux = |init: &str| {
+            Linux::try_parse_from([
+                "linux",
+                "--init",
+                init,
+                "--daemon-user",
+                "nix-installer-no-such-user",
+                "--systemd-scope",
+                "user",
+            ])
+        };
+        let user_scope_error = |result: Result<_, PlannerError>| match result {
+            Err(PlannerError::Custom(e)) => e.downcast::<LinuxErrorKind>().ok().map(|e| *e),
+            _ => None,
+        };
+
+        assert!(matches!(
+            user_scope_error(sandbox.scope(linux("none")?.plan()).await),
+            Some(LinuxErrorKind::UserScopeRequiresSystemd(_))
+        ));
+        assert!(matches!(
+            user_scope_error(sandbox.scope(linux("systemd")?.plan()).await),
+            Some(LinuxErrorKind::UserScopeUserMissing(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn init_is_checked_against_the_nix_version() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        const NIX_2_3: &str =
+            "https://releases.nixos.org/nix/nix-2.3.16/nix-2.3.16-x86_64-linux.tar.xz";
+        assert!(Linux::try_parse_from(["linux", "--nix-version", "latest"]).is_err());
+        let linux = |args: &[&str]| {
+            Linux::try_parse_from(
+                ["linux", "--init", "systemd", "--no-start-daemon"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+
+        // A daemon which cannot be socket activated is started at boot instead
+        let planner = linux(&["--nix-package-url", NIX_2_3])?;
+        let shell = sandbox.path(&planner.settings.nix_build_user_shell);
+        std::fs::create_dir_all(shell.parent().unwrap())?;
+        std::fs::write(shell, "")?;
+        let (plan, warnings) = crate::warning::collect(sandbox.scope(planner.plan())).await;
+        let init_service = plan?
+            .into_iter()
+            .find(|action| action.inner_typetag_name() == "create_upstream_init_service")
+            .ok_or_else(|| eyre::eyre!("No init service was planned"))?;
+        let configure_init_service =
+            &serde_json::to_value(&init_service)?["action"]["configure_init_service"]["action"];
+        assert_eq!(configure_init_service["service_mode"], "always_running");
+        assert_eq!(
+            configure_init_service["socket_files"],
+            serde_json::json!([])
+        );
+        assert!(init_service.describe_execute()[0].explanation[0]
+            .contains("running the daemon always rather than socket activated"));
+        assert!(warnings
+            .iter()
+            .any(|warning| warning.kind == WarningKind::DaemonNotSocketActivated));
+
+        // What the daemon cannot run under at all is refused
+        let planner = linux(&["--nix-package-url", NIX_2_3, "--determinate"])?;
+        assert!(matches!(
+            sandbox.scope(planner.plan()).await,
+            Err(PlannerError::NixDaemonLacks { series, minimum: "2.25", .. }) if series == "2.3"
+        ));
+        let planner = linux(&["--nix-package-url", NIX_2_3, "--use-xdg-base-directories"])?;
+        assert!(matches!(
+            sandbox.scope(planner.plan()).await,
+            Err(PlannerError::NixDaemonLacks {
+                requirement: "`--use-xdg-base-directories`",
+                minimum: "2.14",
+                ..
+            })
+        ));
+        let planner = linux(&["--nix-package-url", NIX_2_3, "--nix-version", "2.24.9"])?;
+        assert!(matches!(
+            sandbox.scope(planner.plan()).await,
+            Err(PlannerError::NixVersionMismatch { .. })
+        ));
+        let planner = linux(&[
+            "--nix-package-url",
+            "https://releases.nixos.org/nix/nix-1.9/nix-1.9-x86_64-linux.tar.bz2",
+        ])?;
+        assert!(matches!(
+            sandbox.scope(planner.plan()).await,
+            Err(PlannerError::NixTooOld { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn offline_installs_need_no_network() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let linux = |flag: &str, value: &str| {
+            Linux::try_parse_from(["linux", "--init", "none", "--offline", flag, value])
+        };
+
+        for flag in ["--nix-package-url", "--extra-conf"] {
+            let planner = linux(flag, "https://example.com/resource")?;
+            assert!(matches!(
+                sandbox.scope(planner.plan()).await,
+                Err(PlannerError::OfflineRemoteResource { flag: found, .. }) if found == flag
+            ));
+        }
+        #[cfg(feature = "diagnostics")]
+        assert_eq!(
+            Linux::try_parse_from(["linux", "--offline"])?
+                .settings
+                .diagnostic_endpoint_unless_offline(),
+            None
+        );
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
-use std::{collections::HashMap, io::Cursor, path::PathBuf};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 #[cfg(feature = "cli")]
 use clap::ArgAction;
@@ -7,7 +12,13 @@ use which::which;
 
 use super::ShellProfileLocations;
 use crate::action::common::provision_nix::NIX_STORE_LOCATION;
+use crate::cross_target;
+use crate::daemon_capabilities;
+use crate::messages::message;
+use crate::nix_settings::system_to_install;
+use crate::planner::implementation::check_existing_implementation;
 use crate::planner::HasExpectedErrors;
+use crate::warning::{self, WarningKind};
 
 mod profile_queries;
 mod profiles;
@@ -16,20 +27,23 @@ use crate::action::common::ConfigureDeterminateNixdInitService;
 use crate::os::darwin::diskutil::DiskUtilList;
 use crate::{
     action::{
-        base::RemoveDirectory,
+        base::{ImportSeedClosure, InstallDefaultProfileFlakes, RemoveDirectory, VerifyStore},
         common::{
             ConfigureNix, ConfigureUpstreamInitService, CreateUsersAndGroups,
             ProvisionDeterminateNixd, ProvisionNix,
         },
         macos::{
             ConfigureRemoteBuilding, CreateDeterminateNixVolume, CreateNixHookService,
-            CreateNixVolume, SetTmutilExclusions,
+            CreateNixVolume, SetTmutilExclusions, VolumeLimits, VolumeSize,
         },
         StatefulAction,
     },
     execute_command,
     os::darwin::DiskUtilInfoOutput,
-    planner::{Planner, PlannerError},
+    planner::{
+        check_offline, check_shared_store, daemon_resources, plan_daemon_tcp_listener,
+        plan_daemon_user, plan_service_mode, plan_stale_build_users, Planner, PlannerError,
+    },
     settings::InstallSettingsError,
     settings::{determinate_nix_settings, CommonSettings, InitSystem},
     Action, BuiltinPlanner,
@@ -74,6 +88,16 @@ pub struct Macos {
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_ROOT_DISK"))]
     pub root_disk: Option<String>,
 
+    /// The most of its APFS container the Nix Store volume may use, like `50G` or `100GiB`
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_VOLUME_QUOTA"))]
+    #[serde(default)]
+    pub volume_quota: Option<VolumeSize>,
+
+    /// Space of its APFS container to set aside for the Nix Store volume, like `10G`, at most the `--volume-quota`
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_VOLUME_RESERVE"))]
+    #[serde(default)]
+    pub volume_reserve: Option<VolumeSize>,
+
     /// On AWS, put the Nix Store volume on the EC2 instances' instance store volume.
     ///
     /// WARNING: Using the instance store volume means the machine must never be Stopped in AWS.
@@ -91,6 +115,82 @@ pub struct Macos {
         clap(long, default_value = "false", requires = "determinate_nix")
     )]
     pub use_ec2_instance_store: bool,
+
+    /// Leave `/nix/store` and `/nix/var` in Time Machine backups, rather than excluding them
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            conflicts_with = "tmutil_exclude",
+            env = "NIX_INSTALLER_NO_TMUTIL_EXCLUSIONS",
+            default_value_if("minimal", "true", Some("true"))
+        )
+    )]
+    #[serde(default)]
+    pub no_tmutil_exclusions: bool,
+
+    /// An extra path to exclude from Time Machine backups, alongside `/nix/store` and `/nix/var` (repeatable)
+    ///
+    /// The path must already exist, or be below `/nix`.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long = "tmutil-exclude",
+            action = ArgAction::Append,
+            env = "NIX_INSTALLER_TMUTIL_EXCLUDE",
+            conflicts_with = "minimal",
+        )
+    )]
+    #[serde(default)]
+    pub tmutil_exclude: Vec<PathBuf>,
+
+    /// Install Nix for this Mac's arm64 hardware even though the installer runs in a shell translated by Rosetta
+    ///
+    /// The translated shell keeps running x86_64 programs, open a native terminal to use Nix.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            env = "NIX_INSTALLER_ALLOW_ROSETTA_SHELL"
+        )
+    )]
+    #[serde(default)]
+    pub allow_rosetta_shell: bool,
+}
+
+impl Macos {
+    fn volume_limits(&self) -> VolumeLimits {
+        VolumeLimits {
+            quota: self.volume_quota,
+            reserve: self.volume_reserve,
+        }
+    }
+
+    /// The paths to exclude from Time Machine backups, none with `--no-tmutil-exclusions`
+    fn tmutil_exclusions(&self) -> Result<Vec<PathBuf>, PlannerError> {
+        if self.no_tmutil_exclusions {
+            return Ok(vec![]);
+        }
+        let mut exclusions = vec![PathBuf::from(NIX_STORE_LOCATION), PathBuf::from("/nix/var")];
+        for path in &self.tmutil_exclude {
+            // Paths below `/nix` are created by the install, before the exclusions are set
+            let will_exist = path.is_absolute()
+                && (path.starts_with("/nix") || crate::util::host_path(path).exists());
+            if !will_exist {
+                return Err(PlannerError::Custom(Box::new(
+                    MacosError::TmutilExclusionMissing(path.clone()),
+                )));
+            }
+            if !exclusions.contains(path) {
+                exclusions.push(path.clone());
+            }
+        }
+        Ok(exclusions)
+    }
 }
 
 async fn default_root_disk() -> Result<String, PlannerError> {
@@ -136,10 +236,19 @@ impl Planner for Macos {
         Ok(Self {
             settings: CommonSettings::default().await?,
             use_ec2_instance_store: false,
-            root_disk: Some(default_root_disk().await?),
+            // Found while planning when planning for another platform
+            root_disk: match cross_target::is_active() {
+                true => None,
+                false => Some(default_root_disk().await?),
+            },
             case_sensitive: false,
             encrypt: None,
             volume_label: "Nix Store".into(),
+            volume_quota: None,
+            volume_reserve: None,
+            no_tmutil_exclusions: false,
+            tmutil_exclude: vec![],
+            allow_rosetta_shell: false,
         })
     }
 
@@ -147,9 +256,23 @@ impl Planner for Macos {
         if self.use_ec2_instance_store && !self.settings.determinate_nix {
             return Err(PlannerError::Ec2InstanceStoreRequiresDeterminateNix);
         }
+        check_offline(&self.settings)?;
+        let shared_store = check_shared_store(&self.settings)?;
+        let daemon_tcp_listener =
+            plan_daemon_tcp_listener(&self.settings, InitSystem::Launchd, true).await?;
+        // launchd always runs the daemon, so this only refuses what it cannot run
+        plan_service_mode(&self.settings, InitSystem::Launchd)?;
+        let daemon_user =
+            plan_daemon_user(&self.settings, InitSystem::Launchd, shared_store, None).await?;
+        let stale_build_users = plan_stale_build_users(&self.settings).await?;
+        let daemon_resources = daemon_resources(&self.settings, InitSystem::Launchd, false)?;
 
         let root_disk = match &self.root_disk {
             root_disk @ Some(_) => root_disk.clone(),
+            None if cross_target::is_active() => {
+                cross_target::placeholder("root_disk");
+                Some(cross_target::PLACEHOLDER.into())
+            },
             None => {
                 if self.use_ec2_instance_store {
                     default_internal_root_disk().await?
@@ -172,7 +295,10 @@ impl Planner for Macos {
                         .flatten()
                 {
                     if diskutil_info.file_vault {
-                        tracing::warn!("Existing volume was encrypted with FileVault, forcing `encrypt` to true");
+                        warning::warn(
+                            WarningKind::FileVaultForcedEncrypt,
+                            "Existing volume was encrypted with FileVault, forcing `encrypt` to true",
+                        );
                         true
                     } else {
                         choice
@@ -181,6 +307,10 @@ impl Planner for Macos {
                     choice
                 }
             },
+            (false, None) if cross_target::is_active() => {
+                cross_target::placeholder("encrypt");
+                false
+            },
             (false, None) => {
                 let root_disk_is_encrypted = {
                     let output = Command::new("/usr/bin/fdesetup")
@@ -218,110 +348,125 @@ impl Planner for Macos {
         let mut plan = vec![];
 
         if self.settings.determinate_nix {
-            plan.push(
-                ProvisionDeterminateNixd::plan()
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
-            );
+            plan.push(cross_target::plan_action(ProvisionDeterminateNixd::plan()).await?);
         }
 
         if self.settings.determinate_nix {
             plan.push(
-                CreateDeterminateNixVolume::plan(
+                cross_target::plan_action(CreateDeterminateNixVolume::plan(
                     root_disk.unwrap(), /* We just ensured it was populated */
                     self.volume_label.clone(),
                     self.case_sensitive,
+                    self.volume_limits(),
                     self.settings.force,
                     self.use_ec2_instance_store,
-                )
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                ))
+                .await?,
             );
         } else {
             plan.push(
-                CreateNixVolume::plan(
+                cross_target::plan_action(CreateNixVolume::plan(
                     root_disk.unwrap(), /* We just ensured it was populated */
                     self.volume_label.clone(),
                     self.case_sensitive,
+                    self.volume_limits(),
                     encrypt,
-                )
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                ))
+                .await?,
             );
         }
 
         plan.push(
-            ProvisionNix::plan(&self.settings)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(ProvisionNix::plan(&self.settings, shared_store)).await?,
         );
         // Auto-allocate uids is broken on Mac. Tools like `whoami` don't work.
         // e.g. https://github.com/NixOS/nix/issues/8444
+        plan.extend(stale_build_users);
         plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-        );
-        plan.push(
-            SetTmutilExclusions::plan(vec![
-                PathBuf::from(NIX_STORE_LOCATION),
-                PathBuf::from("/nix/var"),
-            ])
-            .await
-            .map_err(PlannerError::Action)?
-            .boxed(),
+            cross_target::plan_action(CreateUsersAndGroups::plan(self.settings.clone())).await?,
         );
+        let tmutil_exclusions = self.tmutil_exclusions()?;
+        if !tmutil_exclusions.is_empty() {
+            plan.push(
+                cross_target::plan_action(SetTmutilExclusions::plan(tmutil_exclusions)).await?,
+            );
+        }
         plan.push(
-            ConfigureNix::plan(
+            cross_target::plan_action(ConfigureNix::plan(
                 ShellProfileLocations::default(),
                 &self.settings,
                 self.settings.determinate_nix.then(determinate_nix_settings),
-            )
-            .await
-            .map_err(PlannerError::Action)?
-            .boxed(),
+            ))
+            .await?,
         );
-        plan.push(
-            ConfigureRemoteBuilding::plan()
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-        );
-
-        if self.settings.modify_profile {
+        plan.extend(daemon_user);
+        if !self.settings.minimal {
             plan.push(
-                CreateNixHookService::plan()
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
+                cross_target::plan_action(ConfigureRemoteBuilding::plan(
+                    self.settings.managed_file_annotation.clone(),
+                ))
+                .await?,
             );
         }
 
+        if self.settings.modify_profile {
+            plan.push(cross_target::plan_action(CreateNixHookService::plan()).await?);
+        }
+
         if self.settings.determinate_nix {
             plan.push(
-                ConfigureDeterminateNixdInitService::plan(InitSystem::Launchd, true)
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
+                cross_target::plan_action(async {
+                    Ok(
+                        ConfigureDeterminateNixdInitService::plan(InitSystem::Launchd, true)
+                            .await?
+                            .with_resources(daemon_resources),
+                    )
+                })
+                .await?,
             );
         } else {
             plan.push(
-                ConfigureUpstreamInitService::plan(InitSystem::Launchd, true)
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
+                cross_target::plan_action(async {
+                    Ok(ConfigureUpstreamInitService::plan(
+                        InitSystem::Launchd,
+                        true,
+                        self.settings.daemon_user(),
+                    )
+                    .await?
+                    .with_resources(daemon_resources))
+                })
+                .await?,
+            );
+        }
+        plan.extend(daemon_tcp_listener);
+        if self
+            .settings
+            .default_profile_packages
+            .iter()
+            .any(|package| package.flake().is_some())
+        {
+            plan.push(
+                cross_target::plan_action(InstallDefaultProfileFlakes::plan(
+                    &self.settings.default_profile_packages,
+                ))
+                .await?,
+            );
+        }
+        if let Some(seed_closure) = &self.settings.seed_closure {
+            plan.push(
+                cross_target::plan_action(ImportSeedClosure::plan(
+                    seed_closure,
+                    self.settings.seed_closure_require_sigs,
+                ))
+                .await?,
             );
         }
+        if self.settings.verify_store {
+            let series = daemon_capabilities::series_to_configure(&self.settings)?;
+            plan.push(cross_target::plan_action(VerifyStore::plan(series.as_deref(), true)).await?);
+        }
         plan.push(
-            RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(RemoveDirectory::plan(crate::settings::SCRATCH_DIR)).await?,
         );
 
         Ok(plan)
@@ -334,7 +479,12 @@ impl Planner for Macos {
             volume_label,
             case_sensitive,
             root_disk,
+            volume_quota,
+            volume_reserve,
             use_ec2_instance_store,
+            no_tmutil_exclusions,
+            tmutil_exclude,
+            allow_rosetta_shell,
         } = self;
         let mut map = HashMap::default();
 
@@ -342,6 +492,11 @@ impl Planner for Macos {
         map.insert("volume_encrypt".into(), serde_json::to_value(encrypt)?);
         map.insert("volume_label".into(), serde_json::to_value(volume_label)?);
         map.insert("root_disk".into(), serde_json::to_value(root_disk)?);
+        map.insert("volume_quota".into(), serde_json::to_value(volume_quota)?);
+        map.insert(
+            "volume_reserve".into(),
+            serde_json::to_value(volume_reserve)?,
+        );
         map.insert(
             "use_ec2_instance_store".into(),
             serde_json::to_value(use_ec2_instance_store)?,
@@ -350,6 +505,18 @@ impl Planner for Macos {
             "case_sensitive".into(),
             serde_json::to_value(case_sensitive)?,
         );
+        map.insert(
+            "no_tmutil_exclusions".into(),
+            serde_json::to_value(no_tmutil_exclusions)?,
+        );
+        map.insert(
+            "tmutil_exclude".into(),
+            serde_json::to_value(tmutil_exclude)?,
+        );
+        map.insert(
+            "allow_rosetta_shell".into(),
+            serde_json::to_value(allow_rosetta_shell)?,
+        );
 
         Ok(map)
     }
@@ -374,19 +541,20 @@ impl Planner for Macos {
     async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
         Ok(crate::diagnostics::DiagnosticData::new(
             self.settings.diagnostic_attribution.clone(),
-            self.settings.diagnostic_endpoint.clone(),
+            self.settings.diagnostic_endpoint_unless_offline(),
             self.typetag_name().into(),
             self.configured_settings()
                 .await?
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
+            self.settings.proxy.clone(),
         )?)
     }
 
     async fn platform_check(&self) -> Result<(), PlannerError> {
         use target_lexicon::OperatingSystem;
-        match target_lexicon::OperatingSystem::host() {
+        match crate::cross_target::operating_system() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => Ok(()),
             host_os => Err(PlannerError::IncompatibleOperatingSystem {
                 planner: self.typetag_name(),
@@ -402,8 +570,16 @@ impl Planner for Macos {
     }
 
     async fn pre_install_check(&self) -> Result<(), PlannerError> {
-        check_suis().await?;
-        check_not_running_in_rosetta()?;
+        check_existing_implementation(&self.settings).await?;
+        let policies = load_profiles().await;
+        if let Some(policies) = &policies {
+            check_profiles(policies)?;
+        }
+        // Only `root` can write there, so there is nothing to learn when only planning
+        if nix::unistd::Uid::effective().is_root() {
+            check_launch_daemons_writable(Path::new(LAUNCH_DAEMONS), policies.as_ref()).await?;
+        }
+        check_rosetta(&self.settings, self.allow_rosetta_shell)?;
 
         Ok(())
     }
@@ -438,39 +614,186 @@ async fn check_nix_darwin_not_installed() -> Result<(), PlannerError> {
     Ok(())
 }
 
-fn check_not_running_in_rosetta() -> Result<(), PlannerError> {
-    use sysctl::{Ctl, Sysctl};
-    const CTLNAME: &str = "sysctl.proc_translated";
+/// How an installer translated by Rosetta is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RosettaShell {
+    Native,
+    /// Translated on arm64 hardware, and installing for the hardware with `--allow-rosetta-shell`
+    Allowed,
+}
 
-    match Ctl::new(CTLNAME) {
-        // This Mac doesn't have Rosetta!
-        Err(sysctl::SysctlError::NotFound(_)) => (),
-        Err(e) => Err(e)?,
-        Ok(ctl) => {
-            let str_val = ctl.value_string()?;
+fn rosetta_shell(
+    translated: bool,
+    hardware_arm64: bool,
+    allow_rosetta_shell: bool,
+) -> Result<RosettaShell, PlannerError> {
+    match (translated, hardware_arm64, allow_rosetta_shell) {
+        (false, _, _) => Ok(RosettaShell::Native),
+        (true, true, true) => Ok(RosettaShell::Allowed),
+        // Rosetta only runs on arm64, there is no hardware to install for otherwise
+        (true, true, false) | (true, false, _) => Err(PlannerError::RosettaDetected),
+    }
+}
 
-            if str_val == "1" {
-                return Err(PlannerError::RosettaDetected);
-            }
+/// Check if the installer is translated by Rosetta, and if so that it will install Nix for the
+/// arm64 hardware rather than for the translated process
+fn check_rosetta(settings: &CommonSettings, allow_rosetta_shell: bool) -> Result<(), PlannerError> {
+    // Macs without Rosetta don't have `sysctl.proc_translated`
+    let translated = sysctl_flag("sysctl.proc_translated")?;
+    let hardware_arm64 = translated && sysctl_flag("hw.optional.arm64")?;
+    match rosetta_shell(translated, hardware_arm64, allow_rosetta_shell)? {
+        RosettaShell::Native => Ok(()),
+        RosettaShell::Allowed => {
+            check_native_nix(settings)?;
+            warning::warn(
+                WarningKind::RosettaShell,
+                "This shell is translated by Rosetta, so `$PATH` and `arch` may point at x86_64 programs. \
+                Installing `aarch64-darwin` Nix for this Mac's arm64 hardware, open a native terminal to use it.",
+            );
+            Ok(())
         },
     }
+}
 
-    Ok(())
+/// Check that the Nix to install is for the arm64 hardware, not the translated installer
+///
+/// A translated installer is an x86_64 build, so what it bundles is for x86_64.
+fn check_native_nix(settings: &CommonSettings) -> Result<(), PlannerError> {
+    const NATIVE_SYSTEM: &str = "aarch64-darwin";
+    let system = match settings.determinate_nix {
+        // `determinate-nixd` is always the bundled one
+        true => Some(crate::self_test::SYSTEM.to_string()),
+        false => system_to_install(settings.nix_package_url.as_ref()),
+    };
+    match system {
+        // A URL which names no system is trusted to be the right one
+        None => Ok(()),
+        Some(system) if system == NATIVE_SYSTEM => Ok(()),
+        Some(system) => Err(PlannerError::Custom(Box::new(
+            MacosError::RosettaNeedsNativeNix(system),
+        ))),
+    }
 }
 
-async fn check_suis() -> Result<(), PlannerError> {
-    let policies: profiles::Policies = match profiles::load().await {
-        Ok(pol) => pol,
+/// If the sysctl `name` is `1`, `false` if this Mac doesn't have it
+fn sysctl_flag(name: &str) -> Result<bool, PlannerError> {
+    use sysctl::{Ctl, Sysctl};
+
+    match Ctl::new(name) {
+        Err(sysctl::SysctlError::NotFound(_)) => Ok(false),
+        Err(e) => Err(e)?,
+        Ok(ctl) => Ok(ctl.value_string()?.trim() == "1"),
+    }
+}
+
+async fn load_profiles() -> Option<profiles::Policies> {
+    match profiles::load().await {
+        Ok(pol) => Some(pol),
         Err(e) => {
-            tracing::warn!(
-                "Skipping SystemUIServer checks: failed to load profile data: {:?}",
-                e
+            warning::warn(
+                WarningKind::ProfileChecksSkipped,
+                format!(
+                    "Skipping configuration profile checks: failed to load profile data: {e:?}"
+                ),
             );
-            return Ok(());
+            None
         },
+    }
+}
+
+fn check_profiles(policies: &profiles::Policies) -> Result<(), PlannerError> {
+    check_suis(policies)?;
+    check_system_policy(policies)?;
+    check_managed_nix_configuration(policies)?;
+
+    Ok(())
+}
+
+/// Where the plist of the Nix daemon (and of `determinate-nixd`) is placed
+const LAUNCH_DAEMONS: &str = "/Library/LaunchDaemons";
+
+/// Check that a file can be created in `launch_daemons`, before anything has been changed
+///
+/// Some managed Macs refuse the write, which would otherwise fail the install once the Nix Store
+/// volume and the build users already exist. The refusal is blamed on a Managed Login Items profile
+/// if there is one, then on a custom System Integrity Protection configuration.
+async fn check_launch_daemons_writable(
+    launch_daemons: &Path,
+    policies: Option<&profiles::Policies>,
+) -> Result<(), PlannerError> {
+    let Err(error) = probe_writable(launch_daemons).await else {
+        return Ok(());
     };
+    let path = launch_daemons.display();
 
-    let blocks: Vec<_> = profile_queries::blocks_internal_mounting(&policies)
+    let managing: Vec<_> = policies
+        .map(profile_queries::manages_login_items)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|managing_policy| managing_policy.display())
+        .collect();
+    let error = if !managing.is_empty() {
+        message!(
+            ErrorMacosLaunchDaemonsProfiles,
+            path = path,
+            error = error,
+            profiles = managing.join("\n\n")
+        )
+    } else if let Some(status) = custom_sip_status().await {
+        message!(
+            ErrorMacosLaunchDaemonsSip,
+            path = path,
+            error = error,
+            status = status
+        )
+    } else {
+        message!(
+            ErrorMacosLaunchDaemonsNotWritable,
+            path = path,
+            error = error
+        )
+    };
+
+    Err(MacosError::LaunchDaemonsNotWritable(error)).map_err(|e| PlannerError::Custom(Box::new(e)))
+}
+
+/// Create and remove a uniquely named file in `dir`
+async fn probe_writable(dir: &Path) -> std::io::Result<()> {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or_default();
+    let probe = crate::util::host_path(dir).join(format!(
+        ".nix-installer-probe-{}-{nanos}",
+        std::process::id()
+    ));
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .await?;
+    if let Err(e) = tokio::fs::remove_file(&probe).await {
+        tracing::warn!(%e, "Failed to remove `{}`", probe.display());
+    }
+    Ok(())
+}
+
+/// The output of `csrutil status`, if System Integrity Protection has a custom configuration
+async fn custom_sip_status() -> Option<String> {
+    let output = crate::command_output(
+        Command::new("csrutil")
+            .arg("status")
+            .stdin(std::process::Stdio::null())
+            .process_group(0),
+    )
+    .await
+    .ok()?;
+    let status = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    status.contains("Custom Configuration").then_some(status)
+}
+
+fn check_suis(policies: &profiles::Policies) -> Result<(), PlannerError> {
+    let blocks: Vec<_> = profile_queries::blocks_internal_mounting(policies)
         .into_iter()
         .map(|blocking_policy| blocking_policy.display())
         .collect();
@@ -479,30 +802,77 @@ async fn check_suis() -> Result<(), PlannerError> {
         [] => {
             return Ok(());
         },
-        [block] => format!(
-            "The following macOS configuration profile includes a 'Restrictions - Media' policy, which interferes with the Nix Store volume:\n\n{}\n\nSee https://dtr.mn/suis-premount-dissented",
-            block
+        [block] => message!(ErrorMacosMediaRestrictionProfile, profiles = block),
+        blocks => message!(
+            ErrorMacosMediaRestrictionProfiles,
+            profiles = blocks.join("\n\n")
         ),
-        blocks => {
-            format!(
-                "The following macOS configuration profiles include a 'Restrictions - Media' policy, which interferes with the Nix Store volume:\n\n{}\n\nSee https://dtr.mn/suis-premount-dissented",
-                blocks.join("\n\n")
-            )
-        },
     };
 
     Err(MacosError::BlockedBySystemUIServerPolicy(error))
         .map_err(|e| PlannerError::Custom(Box::new(e)))
 }
 
+fn check_system_policy(policies: &profiles::Policies) -> Result<(), PlannerError> {
+    let blocks: Vec<_> = profile_queries::blocks_unsigned_execution(policies)
+        .into_iter()
+        .map(|blocking_policy| blocking_policy.display())
+        .collect();
+
+    let error: String = match &blocks[..] {
+        [] => {
+            return Ok(());
+        },
+        [block] => message!(ErrorMacosGatekeeperProfile, profiles = block),
+        blocks => message!(ErrorMacosGatekeeperProfiles, profiles = blocks.join("\n\n")),
+    };
+
+    Err(MacosError::BlockedBySystemPolicy(error)).map_err(|e| PlannerError::Custom(Box::new(e)))
+}
+
+fn check_managed_nix_configuration(policies: &profiles::Policies) -> Result<(), PlannerError> {
+    let managing: Vec<_> = profile_queries::manages_nix_configuration(policies)
+        .into_iter()
+        .map(|managing_policy| managing_policy.display())
+        .collect();
+
+    let error: String = match &managing[..] {
+        [] => {
+            return Ok(());
+        },
+        [managing] => message!(ErrorMacosNixConfigurationProfile, profiles = managing),
+        managing => message!(
+            ErrorMacosNixConfigurationProfiles,
+            profiles = managing.join("\n\n")
+        ),
+    };
+
+    Err(MacosError::ManagedNixConfiguration(error)).map_err(|e| PlannerError::Custom(Box::new(e)))
+}
+
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug)]
 pub enum MacosError {
-    #[error("`nix-darwin` installation detected, it must be removed before uninstalling Nix. Please refer to https://github.com/LnL7/nix-darwin#uninstalling for instructions how to uninstall `nix-darwin`.")]
+    #[error("{}", message!(ErrorNixDarwinInstalled))]
     UninstallNixDarwin,
 
     #[error("{0}")]
     BlockedBySystemUIServerPolicy(String),
+
+    #[error("{0}")]
+    BlockedBySystemPolicy(String),
+
+    #[error("{0}")]
+    ManagedNixConfiguration(String),
+
+    #[error("{}", message!(ErrorTmutilExclusionMissing, path = .0.display()))]
+    TmutilExclusionMissing(PathBuf),
+
+    #[error("{0}")]
+    LaunchDaemonsNotWritable(String),
+
+    #[error("{}", message!(ErrorRosettaNeedsNativeNix, system = .0))]
+    RosettaNeedsNativeNix(String),
 }
 
 impl HasExpectedErrors for MacosError {
@@ -510,6 +880,252 @@ impl HasExpectedErrors for MacosError {
         match self {
             this @ MacosError::UninstallNixDarwin => Some(Box::new(this)),
             this @ MacosError::BlockedBySystemUIServerPolicy(_) => Some(Box::new(this)),
+            this @ MacosError::BlockedBySystemPolicy(_) => Some(Box::new(this)),
+            this @ MacosError::ManagedNixConfiguration(_) => Some(Box::new(this)),
+            this @ MacosError::TmutilExclusionMissing(_) => Some(Box::new(this)),
+            this @ MacosError::LaunchDaemonsNotWritable(_) => Some(Box::new(this)),
+            this @ MacosError::RosettaNeedsNativeNix(_) => Some(Box::new(this)),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use crate::{
+        planner::{Planner, PlannerError},
+        settings::CommonSettings,
+        test_harness::{FakeCommand, SandboxContext},
+    };
+
+    use super::{
+        check_launch_daemons_writable, check_native_nix, profiles::Policies, rosetta_shell, Macos,
+        MacosError, RosettaShell,
+    };
+
+    async fn macos(sandbox: &SandboxContext) -> eyre::Result<Macos> {
+        Ok(Macos {
+            settings: sandbox.scope(CommonSettings::default()).await?,
+            encrypt: Some(false),
+            case_sensitive: false,
+            volume_label: "Nix Store".into(),
+            root_disk: Some("disk3".into()),
+            volume_quota: None,
+            volume_reserve: None,
+            use_ec2_instance_store: false,
+            no_tmutil_exclusions: false,
+            tmutil_exclude: vec![],
+            allow_rosetta_shell: false,
+        })
+    }
+
+    #[test]
+    fn rosetta_shell_decisions() {
+        let decide = |translated, hardware_arm64, allow| match rosetta_shell(
+            translated,
+            hardware_arm64,
+            allow,
+        ) {
+            Ok(decision) => Some(decision),
+            Err(PlannerError::RosettaDetected) => None,
+            Err(e) => panic!("unexpected error: {e}"),
+        };
+        for hardware_arm64 in [false, true] {
+            for allow in [false, true] {
+                assert_eq!(
+                    decide(false, hardware_arm64, allow),
+                    Some(RosettaShell::Native)
+                );
+            }
+        }
+        assert_eq!(decide(true, true, true), Some(RosettaShell::Allowed));
+        assert_eq!(decide(true, true, false), None);
+        assert_eq!(decide(true, false, false), None);
+        assert_eq!(decide(true, false, true), None);
+    }
+
+    #[tokio::test]
+    async fn rosetta_installs_follow_the_hardware() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let mut settings = sandbox.scope(CommonSettings::default()).await?;
+        let refused_system = |result: Result<(), PlannerError>| match result {
+            Ok(()) => None,
+            Err(PlannerError::Custom(e)) => match e.downcast_ref() {
+                Some(MacosError::RosettaNeedsNativeNix(system)) => Some(system.clone()),
+                _ => panic!("unexpected error: {e}"),
+            },
+            Err(e) => panic!("unexpected error: {e}"),
+        };
+
+        // The bundled Nix is only right if this is an `aarch64-darwin` build
+        assert_eq!(
+            refused_system(check_native_nix(&settings)),
+            (crate::self_test::SYSTEM != "aarch64-darwin")
+                .then(|| crate::self_test::SYSTEM.to_string())
+        );
+
+        settings.nix_package_url = Some(
+            "https://releases.nixos.org/nix/nix-2.24.10/nix-2.24.10-x86_64-darwin.tar.xz"
+                .parse()?,
+        );
+        assert_eq!(
+            refused_system(check_native_nix(&settings)).as_deref(),
+            Some("x86_64-darwin")
+        );
+
+        settings.nix_package_url = Some(
+            "https://releases.nixos.org/nix/nix-2.24.10/nix-2.24.10-aarch64-darwin.tar.xz"
+                .parse()?,
+        );
+        assert_eq!(refused_system(check_native_nix(&settings)), None);
+
+        // `determinate-nixd` is bundled whatever the package URL
+        settings.determinate_nix = true;
+        assert_eq!(
+            refused_system(check_native_nix(&settings)),
+            (crate::self_test::SYSTEM != "aarch64-darwin")
+                .then(|| crate::self_test::SYSTEM.to_string())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tmutil_exclusions_are_customizable() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/Users/Shared/builds"))?;
+        let mut planner = macos(&sandbox).await?;
+        planner.tmutil_exclude = vec![
+            PathBuf::from("/nix/var/determinate"),
+            PathBuf::from("/Users/Shared/builds"),
+            PathBuf::from("/nix/store"),
+        ];
+        assert_eq!(
+            sandbox.scope(async { planner.tmutil_exclusions() }).await?,
+            vec![
+                PathBuf::from("/nix/store"),
+                PathBuf::from("/nix/var"),
+                PathBuf::from("/nix/var/determinate"),
+                PathBuf::from("/Users/Shared/builds"),
+            ]
+        );
+        let configured = planner.settings()?;
+        assert_eq!(
+            configured.get("tmutil_exclude"),
+            Some(&serde_json::json!([
+                "/nix/var/determinate",
+                "/Users/Shared/builds",
+                "/nix/store"
+            ]))
+        );
+
+        // Only paths below `/nix` are created by the install
+        planner
+            .tmutil_exclude
+            .push(PathBuf::from("/Users/Shared/missing"));
+        let err = sandbox
+            .scope(async { planner.tmutil_exclusions() })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, PlannerError::Custom(e) if matches!(e.downcast_ref(), Some(MacosError::TmutilExclusionMissing(path)) if path == &PathBuf::from("/Users/Shared/missing"))),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    async fn plans_tmutil_exclusions(
+        sandbox: &SandboxContext,
+        planner: &Macos,
+    ) -> Result<bool, PlannerError> {
+        let actions = sandbox.scope(planner.plan()).await?;
+        Ok(actions
+            .iter()
+            .any(|action| action.inner_typetag_name() == "set_tmutil_exclusions"))
+    }
+
+    #[tokio::test]
+    async fn tmutil_exclusions_can_be_skipped() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/sbin"))?;
+        std::fs::write(sandbox.path("/sbin/nologin"), "")?;
+        // An `apfs list` with no existing volumes
+        sandbox.fake(
+            "diskutil",
+            FakeCommand::success().stdout(concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><plist version="1.0">"#,
+                "<dict><key>Containers</key><array/></dict></plist>",
+            )),
+        );
+        let mut planner = macos(&sandbox).await?;
+        assert!(plans_tmutil_exclusions(&sandbox, &planner).await?);
+
+        planner.no_tmutil_exclusions = true;
+        assert!(!plans_tmutil_exclusions(&sandbox, &planner).await?);
+        assert_eq!(
+            planner.settings()?.get("no_tmutil_exclusions"),
+            Some(&serde_json::json!(true))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unwritable_launch_daemons_are_explained() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let launch_daemons = Path::new("/Library/LaunchDaemons");
+        std::fs::create_dir_all(sandbox.path(launch_daemons))?;
+        let sandbox = &sandbox;
+        let check = |policies: Option<Policies>| async move {
+            let err = match sandbox
+                .scope(check_launch_daemons_writable(
+                    launch_daemons,
+                    policies.as_ref(),
+                ))
+                .await
+            {
+                Ok(()) => return None,
+                Err(err) => err,
+            };
+            match err {
+                PlannerError::Custom(e) => match e.downcast_ref() {
+                    Some(MacosError::LaunchDaemonsNotWritable(message)) => Some(message.clone()),
+                    _ => panic!("{e:?}"),
+                },
+                err => panic!("{err:?}"),
+            }
+        };
+
+        assert_eq!(check(None).await, None);
+        // The probe cleans up after itself
+        assert_eq!(std::fs::read_dir(sandbox.path(launch_daemons))?.count(), 0);
+
+        // Even `root` cannot create a file inside of a file
+        std::fs::remove_dir(sandbox.path(launch_daemons))?;
+        std::fs::write(sandbox.path(launch_daemons), "")?;
+        sandbox.fake(
+            "csrutil",
+            FakeCommand::success().stdout("System Integrity Protection status: enabled.\n"),
+        );
+        let message = check(None).await.unwrap();
+        assert!(message.contains("ask your MDM administrator"), "{message}");
+
+        sandbox.fake(
+            "csrutil",
+            FakeCommand::success().stdout(
+                "System Integrity Protection status: unknown (Custom Configuration).\n\nConfiguration:\n\tFilesystem Protections: disabled\n",
+            ),
+        );
+        let message = check(None).await.unwrap();
+        assert!(
+            message.contains("Filesystem Protections: disabled"),
+            "{message}"
+        );
+
+        let policies: Policies = plist::from_reader(std::io::Cursor::new(include_str!(
+            "./profile.sample.login-items.plist"
+        )))?;
+        let message = check(Some(policies)).await.unwrap();
+        assert!(message.contains("Managed Login Items"), "{message}");
+        Ok(())
+    }
+}
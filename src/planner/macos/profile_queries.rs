@@ -1,5 +1,8 @@
+use std::path::Path;
+
 use crate::planner::macos::profiles::{
-    HardDiskInternalOpts, MountControls, Policies, Profile, ProfileItem, SystemUIServer, Target,
+    HardDiskInternalOpts, MountControls, Policies, Profile, ProfileItem, SystemPolicyControl,
+    SystemUIServer, Target, UnknownProfileItem,
 };
 
 struct TargetProfileItem<'a> {
@@ -8,13 +11,12 @@ struct TargetProfileItem<'a> {
     item: &'a ProfileItem,
 }
 
-pub struct TargetProfileHardDiskInternalOpts<'a> {
+pub struct TargetProfile<'a> {
     pub target: &'a Target,
     pub profile: &'a Profile,
-    pub opts: &'a [HardDiskInternalOpts],
 }
 
-impl TargetProfileHardDiskInternalOpts<'_> {
+impl TargetProfile<'_> {
     pub fn display(&self) -> String {
         let owner = match self.target {
             crate::planner::macos::profiles::Target::Computer => {
@@ -25,6 +27,7 @@ impl TargetProfileHardDiskInternalOpts<'_> {
 
         let desc = [
             ("Name", &self.profile.profile_display_name),
+            ("Organization", &self.profile.profile_organization),
             (
                 "Version",
                 &self.profile.profile_version.map(|v| v.to_string()),
@@ -44,6 +47,22 @@ impl TargetProfileHardDiskInternalOpts<'_> {
     }
 }
 
+pub struct TargetProfileHardDiskInternalOpts<'a> {
+    pub target: &'a Target,
+    pub profile: &'a Profile,
+    pub opts: &'a [HardDiskInternalOpts],
+}
+
+impl TargetProfileHardDiskInternalOpts<'_> {
+    pub fn display(&self) -> String {
+        TargetProfile {
+            target: self.target,
+            profile: self.profile,
+        }
+        .display()
+    }
+}
+
 fn flatten(policies: &Policies) -> impl Iterator<Item = TargetProfileItem> {
     policies
         .iter()
@@ -96,6 +115,77 @@ pub fn blocks_internal_mounting(policies: &Policies) -> Vec<TargetProfileHardDis
         .collect()
 }
 
+/// Gatekeeper policies which only allow App Store software, which prevents running the (unsigned) binaries in the Nix store
+pub fn blocks_unsigned_execution(policies: &Policies) -> Vec<TargetProfile<'_>> {
+    profiles_where(policies, |item| {
+        let ProfileItem::SystemPolicyControl(control) = item else {
+            return false;
+        };
+        let SystemPolicyControl {
+            enable_assessment,
+            allow_identified_developers,
+        } = control;
+
+        *enable_assessment == Some(true) && *allow_identified_developers == Some(false)
+    })
+}
+
+/// Profiles which reference files under `/etc/nix`, such as an MDM pushing its own `/etc/nix/nix.conf`
+pub fn manages_nix_configuration(policies: &Policies) -> Vec<TargetProfile<'_>> {
+    profiles_where(policies, |item| {
+        let ProfileItem::Unknown(UnknownProfileItem {
+            payload_content: Some(payload_content),
+            ..
+        }) = item
+        else {
+            return false;
+        };
+
+        mentions_etc_nix(payload_content)
+    })
+}
+
+/// Profiles with a Managed Login Items payload, which restricts the launch daemons `root` may place
+pub fn manages_login_items(policies: &Policies) -> Vec<TargetProfile<'_>> {
+    profiles_where(policies, |item| {
+        matches!(
+            item,
+            ProfileItem::Unknown(UnknownProfileItem {
+                payload_type: Some(payload_type),
+                ..
+            }) if payload_type == "com.apple.servicemanagement"
+        )
+    })
+}
+
+/// Each profile (once) which has any item matching `predicate`
+fn profiles_where(
+    policies: &Policies,
+    predicate: impl Fn(&ProfileItem) -> bool,
+) -> Vec<TargetProfile<'_>> {
+    policies
+        .iter()
+        .flat_map(|(target, profiles)| {
+            profiles
+                .iter()
+                .map(move |profile| TargetProfile { target, profile })
+        })
+        .filter(|TargetProfile { profile, .. }| profile.profile_items.iter().any(&predicate))
+        .collect()
+}
+
+fn mentions_etc_nix(value: &plist::Value) -> bool {
+    match value {
+        plist::Value::String(string) => {
+            let path = Path::new(string);
+            path.starts_with("/etc/nix") || path.starts_with("/private/etc/nix")
+        },
+        plist::Value::Array(values) => values.iter().any(mentions_etc_nix),
+        plist::Value::Dictionary(dictionary) => dictionary.values().any(mentions_etc_nix),
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,4 +224,75 @@ mod tests {
         let blocks = blocks_internal_mounting(&parsed);
         assert!(blocks.is_empty());
     }
+
+    #[test]
+    fn blocks_unsigned() {
+        let parsed: Policies = plist::from_reader(std::io::Cursor::new(include_str!(
+            "./profile.sample.systempolicy.plist"
+        )))
+        .unwrap();
+
+        let blocks = blocks_unsigned_execution(&parsed);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(
+            r#"A computer-wide profile:
+ * Name: Gatekeeper
+ * Organization: Example Corp IT
+ * Version: 1
+ * ID: com.example.gatekeeper
+ * UUID: 0C7B5D0E-2A64-4B43-9B57-7A4A1E0B8F21
+ * Installation Date: 2024-05-01 09:00:00 +0000"#
+                .trim()
+                .to_string(),
+            blocks[0].display().trim()
+        );
+        assert!(manages_nix_configuration(&parsed).is_empty());
+    }
+
+    #[test]
+    fn manages_etc_nix() {
+        let parsed: Policies = plist::from_reader(std::io::Cursor::new(include_str!(
+            "./profile.sample.etc-nix.plist"
+        )))
+        .unwrap();
+
+        let managing = manages_nix_configuration(&parsed);
+        assert_eq!(managing.len(), 1);
+        assert_eq!(
+            managing[0].profile.profile_display_name.as_deref(),
+            Some("Developer Tooling")
+        );
+        assert!(blocks_unsigned_execution(&parsed).is_empty());
+        assert!(blocks_internal_mounting(&parsed).is_empty());
+    }
+
+    #[test]
+    fn manages_launch_daemons() {
+        let parsed: Policies = plist::from_reader(std::io::Cursor::new(include_str!(
+            "./profile.sample.login-items.plist"
+        )))
+        .unwrap();
+
+        let managing = manages_login_items(&parsed);
+        assert_eq!(managing.len(), 1);
+        assert_eq!(
+            managing[0].profile.profile_display_name.as_deref(),
+            Some("Managed Login Items")
+        );
+        assert!(manages_nix_configuration(&parsed).is_empty());
+    }
+
+    #[test]
+    fn no_system_policy_or_nix_configuration_error() {
+        for sample in [
+            include_str!("./profile.sample.unknown.plist"),
+            include_str!("./profile.sample.block.plist"),
+        ] {
+            let parsed: Policies = plist::from_reader(std::io::Cursor::new(sample)).unwrap();
+
+            assert!(blocks_unsigned_execution(&parsed).is_empty());
+            assert!(manages_nix_configuration(&parsed).is_empty());
+            assert!(manages_login_items(&parsed).is_empty());
+        }
+    }
 }
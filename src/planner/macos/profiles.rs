@@ -44,6 +44,7 @@ pub struct Profile {
     pub profile_display_name: Option<String>,
     pub profile_identifier: Option<String>,
     pub profile_install_date: Option<String>,
+    pub profile_organization: Option<String>,
     #[serde(rename = "ProfileUUID")]
     pub profile_uuid: Option<String>,
     pub profile_version: Option<usize>,
@@ -58,6 +59,9 @@ pub enum ProfileItem {
     #[serde(rename = "com.apple.systemuiserver")]
     SystemUIServer(SystemUIServer),
 
+    #[serde(rename = "com.apple.systempolicy.control")]
+    SystemPolicyControl(SystemPolicyControl),
+
     #[serde(untagged)]
     Unknown(UnknownProfileItem),
 }
@@ -65,8 +69,8 @@ pub enum ProfileItem {
 #[derive(serde::Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct UnknownProfileItem {
-    payload_type: Option<String>,
-    payload_content: Option<plist::Value>,
+    pub payload_type: Option<String>,
+    pub payload_content: Option<plist::Value>,
 }
 
 impl std::cmp::Eq for UnknownProfileItem {}
@@ -84,6 +88,16 @@ pub struct MountControls {
     pub harddisk_internal: Vec<HardDiskInternalOpts>,
 }
 
+/// Gatekeeper settings
+///
+/// See: <https://developer.apple.com/documentation/devicemanagement/systempolicycontrol>
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct SystemPolicyControl {
+    pub enable_assessment: Option<bool>,
+    pub allow_identified_developers: Option<bool>,
+}
+
 #[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub enum HardDiskInternalOpts {
@@ -113,6 +127,7 @@ mod tests {
                         "MyProfile.6F6670A3-65AC-4EA4-8665-91F8FCE289AB".into()
                     ),
                     profile_install_date: Some("2024-04-22 14:12:42 +0000".into()),
+                    profile_organization: None,
                     profile_uuid: Some("6F6670A3-65AC-4EA4-8665-91F8FCE289AB".into()),
                     profile_version: Some(1),
                     profile_items: vec![ProfileItem::SystemUIServer(SystemUIServer {
@@ -143,6 +158,7 @@ mod tests {
                     ),
                     profile_identifier: Some("com.example".into()),
                     profile_install_date: Some("2024-04-22 00:00:00 +0000".into()),
+                    profile_organization: None,
                     profile_uuid: Some("F7972F85-2A4D-4609-A4BB-02CB0C34A3F8".into()),
                     profile_version: Some(1),
                     profile_items: vec![ProfileItem::Unknown(UnknownProfileItem {
@@ -159,4 +175,32 @@ mod tests {
             parsed
         );
     }
+
+    #[test]
+    fn try_parse_system_policy() {
+        let parsed: Policies = plist::from_reader(std::io::Cursor::new(include_str!(
+            "./profile.sample.systempolicy.plist"
+        )))
+        .unwrap();
+
+        assert_eq!(
+            Policies::from([(
+                Target::Computer,
+                vec![Profile {
+                    profile_description: None,
+                    profile_display_name: Some("Gatekeeper".into()),
+                    profile_identifier: Some("com.example.gatekeeper".into()),
+                    profile_install_date: Some("2024-05-01 09:00:00 +0000".into()),
+                    profile_organization: Some("Example Corp IT".into()),
+                    profile_uuid: Some("0C7B5D0E-2A64-4B43-9B57-7A4A1E0B8F21".into()),
+                    profile_version: Some(1),
+                    profile_items: vec![ProfileItem::SystemPolicyControl(SystemPolicyControl {
+                        enable_assessment: Some(true),
+                        allow_identified_developers: Some(false),
+                    })],
+                }]
+            )]),
+            parsed
+        );
+    }
 }
@@ -80,6 +80,7 @@ impl Planner for MyPlanner {
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.common.ssl_cert_file.clone(),
+            self.common.proxy.clone(),
         )?)
     }
 
@@ -114,19 +115,40 @@ match plan.install(None).await {
 ```
 
 */
+mod distro;
+mod implementation;
 pub mod linux;
 pub mod macos;
+pub mod optional;
 pub mod ostree;
 pub mod steam_deck;
+pub mod wsl;
 
-use std::{collections::HashMap, path::PathBuf, string::FromUtf8Error};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    string::FromUtf8Error,
+};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    action::{ActionError, StatefulAction},
+    action::{
+        common::{
+            ConfigureDaemonTcpListener, ConfigureDaemonUser, DeleteStaleBuildUsers, ServiceMode,
+        },
+        ActionError, StatefulAction,
+    },
+    daemon_capabilities::{self, DaemonCapabilities},
     error::HasExpectedErrors,
-    settings::{CommonSettings, InstallSettingsError},
+    messages::message,
+    settings::{
+        CommonSettings, DaemonResources, InitSystem, InstallSettingsError, Shell, UrlOrPath,
+        UrlOrPathOrString,
+    },
+    util::LossyPath,
+    warning::{self, WarningKind},
     Action, InstallPlan, NixInstallerError,
 };
 
@@ -164,6 +186,20 @@ pub trait Planner: std::fmt::Debug + Send + Sync + dyn_clone::DynClone {
         Ok(())
     }
 
+    /// Check that `extra` actions can be run after the `planned` ones, see [`InstallPlan::extend_with`]
+    fn validate_extra_actions(
+        &self,
+        _planned: &[StatefulAction<Box<dyn Action>>],
+        _extra: &[StatefulAction<Box<dyn Action>>],
+    ) -> Result<(), PlannerError> {
+        Ok(())
+    }
+
+    /// The [`Phase`](crate::phase::Phase) of a phased install which starts with `action`, or `None` if it executes in the phase of the action before it
+    fn phase(&self, action: &StatefulAction<Box<dyn Action>>) -> Option<crate::phase::Phase> {
+        crate::phase::Phase::starting_with(action.inner_typetag_name())
+    }
+
     #[cfg(feature = "diagnostics")]
     async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError>;
 }
@@ -183,6 +219,9 @@ pub enum BuiltinPlanner {
     #[cfg_attr(not(target_os = "linux"), clap(hide = true))]
     /// A planner suitable for immutable systems using ostree, such as Fedora Silverblue
     Ostree(ostree::Ostree),
+    #[cfg_attr(not(target_os = "linux"), clap(hide = true))]
+    /// A planner for Windows Subsystem for Linux 2, with or without systemd
+    Wsl(wsl::Wsl),
     #[cfg_attr(not(target_os = "macos"), clap(hide = true))]
     /// A planner for MacOS (Darwin) systems
     Macos(macos::Macos),
@@ -192,7 +231,14 @@ impl BuiltinPlanner {
     /// Heuristically determine the default planner for the target system
     pub async fn default() -> Result<Self, PlannerError> {
         use target_lexicon::{Architecture, OperatingSystem};
-        match (Architecture::host(), OperatingSystem::host()) {
+        match (
+            crate::cross_target::architecture(),
+            crate::cross_target::operating_system(),
+        ) {
+            // Other platforms are planned for without inspecting the host, so as a plain Linux
+            (Architecture::X86_64, OperatingSystem::Linux) if crate::cross_target::is_active() => {
+                Ok(Self::Linux(linux::Linux::default().await?))
+            },
             (Architecture::X86_64, OperatingSystem::Linux) => Self::detect_linux_distro().await,
             (Architecture::X86_32(_), OperatingSystem::Linux) => {
                 Ok(Self::Linux(linux::Linux::default().await?))
@@ -208,11 +254,18 @@ impl BuiltinPlanner {
             | (Architecture::Aarch64(_), OperatingSystem::Darwin) => {
                 Ok(Self::Macos(macos::Macos::default().await?))
             },
-            _ => Err(PlannerError::UnsupportedArchitecture(target_lexicon::HOST)),
+            _ => Err(PlannerError::UnsupportedArchitecture(
+                crate::cross_target::triple(),
+            )),
         }
     }
 
     async fn detect_linux_distro() -> Result<Self, PlannerError> {
+        // WSL1 is refused by the WSL planner
+        if wsl::detect_wsl().is_some() {
+            return Ok(Self::Wsl(wsl::Wsl::default().await?));
+        }
+
         let is_steam_deck =
             os_release::OsRelease::new().is_ok_and(|os_release| os_release.id == "steamos");
         if is_steam_deck {
@@ -237,11 +290,32 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(inner) => inner.settings = settings,
             BuiltinPlanner::SteamDeck(inner) => inner.settings = settings,
             BuiltinPlanner::Ostree(inner) => inner.settings = settings,
+            BuiltinPlanner::Wsl(inner) => inner.settings = settings,
             BuiltinPlanner::Macos(inner) => inner.settings = settings,
         }
         Ok(built)
     }
 
+    pub(crate) fn common_settings(&self) -> &CommonSettings {
+        match self {
+            BuiltinPlanner::Linux(inner) => &inner.settings,
+            BuiltinPlanner::SteamDeck(inner) => &inner.settings,
+            BuiltinPlanner::Ostree(inner) => &inner.settings,
+            BuiltinPlanner::Wsl(inner) => &inner.settings,
+            BuiltinPlanner::Macos(inner) => &inner.settings,
+        }
+    }
+
+    pub(crate) fn common_settings_mut(&mut self) -> &mut CommonSettings {
+        match self {
+            BuiltinPlanner::Linux(inner) => &mut inner.settings,
+            BuiltinPlanner::SteamDeck(inner) => &mut inner.settings,
+            BuiltinPlanner::Ostree(inner) => &mut inner.settings,
+            BuiltinPlanner::Wsl(inner) => &mut inner.settings,
+            BuiltinPlanner::Macos(inner) => &mut inner.settings,
+        }
+    }
+
     pub async fn configured_settings(
         &self,
     ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
@@ -249,6 +323,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(inner) => inner.configured_settings().await,
             BuiltinPlanner::SteamDeck(inner) => inner.configured_settings().await,
             BuiltinPlanner::Ostree(inner) => inner.configured_settings().await,
+            BuiltinPlanner::Wsl(inner) => inner.configured_settings().await,
             BuiltinPlanner::Macos(inner) => inner.configured_settings().await,
         }
     }
@@ -258,14 +333,31 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(planner) => InstallPlan::plan(planner).await,
             BuiltinPlanner::SteamDeck(planner) => InstallPlan::plan(planner).await,
             BuiltinPlanner::Ostree(planner) => InstallPlan::plan(planner).await,
+            BuiltinPlanner::Wsl(planner) => InstallPlan::plan(planner).await,
             BuiltinPlanner::Macos(planner) => InstallPlan::plan(planner).await,
         }
     }
+    /// Like [`plan`](Self::plan), for `target` rather than the host, see [`crate::cross_target`]
+    pub async fn plan_for_target(
+        self,
+        target: target_lexicon::Triple,
+    ) -> Result<InstallPlan, NixInstallerError> {
+        match self {
+            BuiltinPlanner::Linux(planner) => InstallPlan::plan_for_target(planner, target).await,
+            BuiltinPlanner::SteamDeck(planner) => {
+                InstallPlan::plan_for_target(planner, target).await
+            },
+            BuiltinPlanner::Ostree(planner) => InstallPlan::plan_for_target(planner, target).await,
+            BuiltinPlanner::Wsl(planner) => InstallPlan::plan_for_target(planner, target).await,
+            BuiltinPlanner::Macos(planner) => InstallPlan::plan_for_target(planner, target).await,
+        }
+    }
     pub fn boxed(self) -> Box<dyn Planner> {
         match self {
             BuiltinPlanner::Linux(i) => i.boxed(),
             BuiltinPlanner::SteamDeck(i) => i.boxed(),
             BuiltinPlanner::Ostree(i) => i.boxed(),
+            BuiltinPlanner::Wsl(i) => i.boxed(),
             BuiltinPlanner::Macos(i) => i.boxed(),
         }
     }
@@ -275,6 +367,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(i) => i.typetag_name(),
             BuiltinPlanner::SteamDeck(i) => i.typetag_name(),
             BuiltinPlanner::Ostree(i) => i.typetag_name(),
+            BuiltinPlanner::Wsl(i) => i.typetag_name(),
             BuiltinPlanner::Macos(i) => i.typetag_name(),
         }
     }
@@ -284,6 +377,7 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(i) => i.settings(),
             BuiltinPlanner::SteamDeck(i) => i.settings(),
             BuiltinPlanner::Ostree(i) => i.settings(),
+            BuiltinPlanner::Wsl(i) => i.settings(),
             BuiltinPlanner::Macos(i) => i.settings(),
         }
     }
@@ -296,15 +390,19 @@ impl BuiltinPlanner {
             BuiltinPlanner::Linux(i) => i.diagnostic_data().await,
             BuiltinPlanner::SteamDeck(i) => i.diagnostic_data().await,
             BuiltinPlanner::Ostree(i) => i.diagnostic_data().await,
+            BuiltinPlanner::Wsl(i) => i.diagnostic_data().await,
             BuiltinPlanner::Macos(i) => i.diagnostic_data().await,
         }
     }
 }
 
+#[serde_with::serde_as]
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct ShellProfileLocations {
     pub fish: FishShellProfileLocations,
+    #[serde_as(as = "Vec<LossyPath>")]
     pub bash: Vec<PathBuf>,
+    #[serde_as(as = "Vec<LossyPath>")]
     pub zsh: Vec<PathBuf>,
 }
 
@@ -326,16 +424,61 @@ impl Default for ShellProfileLocations {
     }
 }
 
+impl ShellProfileLocations {
+    /// The profiles in the user's `home` standing in for these, for the same shells
+    ///
+    /// `~/.profile` for `bash`, `~/.zshrc` for `zsh`, and `~/.config/fish/conf.d` for `fish`.
+    pub fn for_user(&self, home: &Path) -> Self {
+        let profile = |system: &[PathBuf], name: &str| match system.is_empty() {
+            true => vec![],
+            false => vec![home.join(name)],
+        };
+        let fish = [
+            &self.fish.confd_prefixes[..],
+            &self.fish.vendor_confd_prefixes[..],
+        ]
+        .concat();
+        Self {
+            fish: FishShellProfileLocations {
+                confd_prefixes: profile(&fish, ".config/fish"),
+                vendor_confd_prefixes: vec![],
+                ..self.fish.clone()
+            },
+            bash: profile(&self.bash, ".profile"),
+            zsh: profile(&self.zsh, ".zshrc"),
+        }
+    }
+
+    /// These locations, less those of `shells`
+    pub fn without(mut self, shells: &[Shell]) -> Self {
+        for shell in shells {
+            match shell {
+                Shell::Bash => self.bash.clear(),
+                Shell::Zsh => self.zsh.clear(),
+                Shell::Fish => {
+                    self.fish.confd_prefixes.clear();
+                    self.fish.vendor_confd_prefixes.clear();
+                },
+            }
+        }
+        self
+    }
+}
+
+#[serde_with::serde_as]
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct FishShellProfileLocations {
+    #[serde_as(as = "LossyPath")]
     pub confd_suffix: PathBuf,
     /**
      Each of these are common values of $__fish_sysconf_dir,
     under which Fish will look for the file named by
     `confd_suffix`.
     */
+    #[serde_as(as = "Vec<LossyPath>")]
     pub confd_prefixes: Vec<PathBuf>,
     /// Fish has different syntax than zsh/bash, treat it separate
+    #[serde_as(as = "LossyPath")]
     pub vendor_confd_suffix: PathBuf,
     /**
     Each of these are common values of $__fish_vendor_confdir,
@@ -344,6 +487,7 @@ pub struct FishShellProfileLocations {
 
     More info: <https://fishshell.com/docs/3.3/index.html#configuration-files>
     */
+    #[serde_as(as = "Vec<LossyPath>")]
     pub vendor_confd_prefixes: Vec<PathBuf>,
 }
 
@@ -363,17 +507,311 @@ impl Default for FishShellProfileLocations {
     }
 }
 
+/// Files at least one of which exists in any Nix store created by `nix-installer`, even after
+/// `split-receipt` (which writes its phase 2 receipt to the [state directory](crate::state_dir))
+const INSTALLER_STORE_MARKERS: &[&str] = &["/nix/receipt.json", "/nix/nix-installer"];
+
+/// Detect a populated Nix store at `/nix` which `nix-installer` did not create (such as a host's
+/// store bind mounted into a container), returning if the plan must preserve it
+///
+/// Installing alongside such a store requires [`CommonSettings::shared_store_ok`].
+pub(crate) fn check_shared_store(settings: &CommonSettings) -> Result<bool, PlannerError> {
+    let store = crate::util::host_path(crate::action::common::provision_nix::NIX_STORE_LOCATION);
+    let populated = store
+        .read_dir()
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+    if !populated {
+        return Ok(false);
+    }
+
+    if INSTALLER_STORE_MARKERS
+        .iter()
+        .any(|marker| crate::util::host_path(marker).exists())
+        || crate::state_dir::find(&settings.state_dir, crate::state_dir::PHASE2_RECEIPT).is_some()
+    {
+        return Ok(false);
+    }
+
+    if !settings.shared_store_ok {
+        return Err(PlannerError::SharedStoreNotAcknowledged(store));
+    }
+
+    tracing::info!(
+        "Installing alongside the existing Nix store at `{}`, it will not be removed on revert",
+        store.display()
+    );
+    Ok(true)
+}
+
+/// With [`CommonSettings::offline`], check nothing the plan needs is fetched over the network
+pub(crate) fn check_offline(settings: &CommonSettings) -> Result<(), PlannerError> {
+    if !settings.offline {
+        return Ok(());
+    }
+    // Without a `--nix-package-url`, the Nix package embedded in `nix-installer` is used
+    if let Some(UrlOrPath::Url(url)) = &settings.nix_package_url {
+        if url.scheme() != "file" {
+            return Err(PlannerError::OfflineRemoteResource {
+                flag: "--nix-package-url",
+                url: url.to_string(),
+            });
+        }
+    }
+    for extra_conf in &settings.extra_conf {
+        if let UrlOrPathOrString::Url(url) = extra_conf {
+            if url.scheme() != "file" {
+                return Err(PlannerError::OfflineRemoteResource {
+                    flag: "--extra-conf",
+                    url: url.to_string(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Plan exposing the Nix daemon over TCP, if [`CommonSettings::daemon_tcp_listen`] asks for it
+///
+/// It must be acknowledged as unauthenticated, and wildcard addresses must be explicitly allowed.
+pub(crate) async fn plan_daemon_tcp_listener(
+    settings: &CommonSettings,
+    init: InitSystem,
+    start_daemon: bool,
+) -> Result<Option<StatefulAction<Box<dyn Action>>>, PlannerError> {
+    let Some(listen) = settings.daemon_tcp_listen else {
+        return Ok(None);
+    };
+    if !settings.i_understand_daemon_tcp_is_unauthenticated {
+        return Err(PlannerError::DaemonTcpNotAcknowledged(listen));
+    }
+    if listen.ip().is_unspecified() && !settings.daemon_tcp_allow_wildcard {
+        return Err(PlannerError::DaemonTcpWildcard(listen));
+    }
+    if init == InitSystem::None {
+        return Err(PlannerError::DaemonTcpRequiresInit);
+    }
+    Ok(Some(
+        ConfigureDaemonTcpListener::plan(init, listen, start_daemon)
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+    ))
+}
+
+/// Plan running the Nix daemon as a non-root user, if [`CommonSettings::daemon_user`] asks for it
+///
+/// `planner` is the name of planners which cannot run the daemon as another user, passing `None`
+/// for those which can.
+pub(crate) async fn plan_daemon_user(
+    settings: &CommonSettings,
+    init: InitSystem,
+    shared_store: bool,
+    planner: Option<&'static str>,
+) -> Result<Option<StatefulAction<Box<dyn Action>>>, PlannerError> {
+    if settings.daemon_user.is_none() {
+        return Ok(None);
+    }
+    let unsupported = |reason: String| Err(PlannerError::DaemonUserUnsupported(reason));
+    if let Some(planner) = planner {
+        return unsupported(format!("by the `{planner}` planner"));
+    }
+    if settings.determinate_nix {
+        return unsupported("with Determinate Nix".into());
+    }
+    if shared_store {
+        return unsupported("alongside an existing Nix store".into());
+    }
+    if init == InitSystem::None {
+        return Err(PlannerError::DaemonUserRequiresInit);
+    }
+    let Some(series) = crate::nix_settings::series_to_install(settings.nix_package_url.as_ref())
+    else {
+        return unsupported("for a Nix package whose version is not in its file name".into());
+    };
+    let minimum = crate::nix_settings::DAEMON_USER_MIN_SERIES;
+    if !crate::nix_settings::series_at_least(&series, minimum) {
+        return Err(PlannerError::DaemonUserNixTooOld { series, minimum });
+    }
+    Ok(Some(
+        ConfigureDaemonUser::plan(settings)
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+    ))
+}
+
+/// The scheduling settings of the Nix daemon, see [`DaemonResources`], checked against what `init`
+/// can apply them to
+///
+/// `in_user_manager` is if a systemd user manager runs the daemon, which may not raise its priority.
+pub(crate) fn daemon_resources(
+    settings: &CommonSettings,
+    init: InitSystem,
+    in_user_manager: bool,
+) -> Result<DaemonResources, PlannerError> {
+    let resources = settings.daemon_resources();
+    if resources.is_unset() {
+        return Ok(resources);
+    }
+    if init == InitSystem::None {
+        return Err(PlannerError::DaemonResourcesRequireInit);
+    }
+    if let Some(nice) = resources.nice.filter(|nice| in_user_manager && *nice < 0) {
+        return Err(PlannerError::DaemonNiceInUserManager(nice));
+    }
+    if init == InitSystem::Launchd
+        && [resources.cpu_weight, resources.io_weight]
+            .into_iter()
+            .flatten()
+            .any(|weight| weight > DaemonResources::DEFAULT_WEIGHT)
+    {
+        warning::warn(
+            WarningKind::DaemonWeightIgnored,
+            format!(
+                "launchd has no weights, so only a `--daemon-cpu-weight` or `--daemon-io-weight` below {} has an effect, running the daemon as a `Background` process",
+                DaemonResources::DEFAULT_WEIGHT
+            ),
+        );
+    }
+    Ok(resources)
+}
+
+/// Report the build users left by installs with another build user prefix or count, planning their
+/// deletion if [`CommonSettings::remove_stale_build_users`] asks for it
+///
+/// Users still running processes are reported, but never deleted.
+pub(crate) async fn plan_stale_build_users(
+    settings: &CommonSettings,
+) -> Result<Option<StatefulAction<Box<dyn Action>>>, PlannerError> {
+    if crate::cross_target::is_active() {
+        if settings.remove_stale_build_users {
+            crate::cross_target::placeholder("stale build users");
+        }
+        return Ok(None);
+    }
+    let (in_use, removable): (Vec<_>, Vec<_>) = crate::stale_build_users::scan(settings)
+        .await
+        .into_iter()
+        .partition(|(_, in_use)| *in_use);
+    let list = |users: &[(crate::stale_build_users::StaleBuildUser, bool)]| {
+        users
+            .iter()
+            .map(|(user, _)| user.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !settings.remove_stale_build_users {
+        let stale = [removable, in_use].concat();
+        if !stale.is_empty() {
+            warning::warn(
+                WarningKind::StaleBuildUsers,
+                format!(
+                    "Found build users left by an install with another build user prefix or count: {}. Remove them with `--remove-stale-build-users`",
+                    list(&stale)
+                ),
+            );
+        }
+        return Ok(None);
+    }
+    if !in_use.is_empty() {
+        warning::warn(
+            WarningKind::StaleBuildUsers,
+            format!(
+                "Not removing stale build users still running processes: {}. Stop their processes and run the installer again to remove them",
+                list(&in_use)
+            ),
+        );
+    }
+    if removable.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(
+        DeleteStaleBuildUsers::plan(removable.into_iter().map(|(user, _)| user).collect())
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+    ))
+}
+
+/// How `init` starts the daemon of the Nix `settings` installs, checked against what it supports
+///
+/// A daemon which cannot be socket activated is started at boot instead, and configurations it
+/// cannot run at all are refused. A Nix whose version is not known is assumed to support them all.
+pub(crate) fn plan_service_mode(
+    settings: &CommonSettings,
+    init: InitSystem,
+) -> Result<ServiceMode, PlannerError> {
+    let Some(series) = daemon_capabilities::series_to_configure(settings)? else {
+        tracing::debug!(
+            "The version of the Nix package is not known, so its daemon is not checked"
+        );
+        return Ok(ServiceMode::SocketActivated);
+    };
+    let Some(capabilities) = daemon_capabilities::capabilities(&series) else {
+        return Err(PlannerError::NixTooOld {
+            series,
+            minimum: daemon_capabilities::OLDEST_SERIES,
+        });
+    };
+    let lacks = |requirement: &'static str,
+                 capability: fn(&DaemonCapabilities) -> bool,
+                 description: &'static str| {
+        (!capability(&capabilities)).then(|| PlannerError::NixDaemonLacks {
+            series: series.clone(),
+            requirement,
+            description,
+            minimum: daemon_capabilities::first_series_with(capability),
+        })
+    };
+    if settings.determinate_nix {
+        if let Some(err) = lacks(
+            "`--determinate`",
+            |capabilities| capabilities.multiple_sockets,
+            "accepts the second socket `determinate-nixd` hands it",
+        ) {
+            return Err(err);
+        }
+    }
+    if settings.daemon_tcp_listen.is_some() {
+        if let Some(err) = lacks(
+            "`--daemon-tcp-listen`",
+            |capabilities| capabilities.stdio,
+            "serves a single client with `nix-daemon --stdio`",
+        ) {
+            return Err(err);
+        }
+    }
+    if settings.use_xdg_base_directories {
+        if let Some(err) = lacks(
+            "`--use-xdg-base-directories`",
+            |capabilities| capabilities.xdg_base_directories,
+            "honors `use-xdg-base-directories`",
+        ) {
+            return Err(err);
+        }
+    }
+    if init == InitSystem::Systemd && !capabilities.socket_activation {
+        warning::warn(
+            WarningKind::DaemonNotSocketActivated,
+            format!("The daemon of Nix {series} cannot be socket activated, so systemd starts it at boot and keeps it running instead"),
+        );
+        return Ok(ServiceMode::AlwaysRunning);
+    }
+    Ok(ServiceMode::SocketActivated)
+}
+
 /// An error originating from a [`Planner`]
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
 pub enum PlannerError {
-    #[error("The selected planner (`{planner}`) does not support the host's operating system (`{host_os}`)")]
+    #[error("{}", message!(ErrorIncompatibleOperatingSystem, planner = .planner, host_os = .host_os))]
     IncompatibleOperatingSystem {
         planner: &'static str,
         host_os: target_lexicon::OperatingSystem,
     },
     /// `nix-installer` does not have a default planner for the target architecture right now
-    #[error("`nix-installer` does not have a default planner for the `{0}` architecture right now, pass a specific archetype")]
+    #[error("{}", message!(ErrorUnsupportedArchitecture, architecture = .0))]
     UnsupportedArchitecture(target_lexicon::Triple),
     /// Error executing action
     #[error("Error executing action")]
@@ -393,27 +831,77 @@ pub enum PlannerError {
     Plist(#[from] plist::Error),
     #[error(transparent)]
     Sysctl(#[from] sysctl::SysctlError),
-    #[error("Detected that this process is running under Rosetta, using Nix in Rosetta is not supported (Please open an issue with your use case)")]
+    #[error("{}", message!(ErrorRosettaDetected))]
     RosettaDetected,
-    #[error("Determinate Nix is not available. See: https://determinate.systems/enterprise")]
+    #[error("{}", message!(ErrorDeterminateNixUnavailable))]
     DeterminateNixUnavailable,
-    #[error("Running Nix on the EC2 instance store requires Determinate Nix to be enabled")]
+    #[error("{}", message!(ErrorEc2InstanceStoreRequiresDeterminateNix))]
     Ec2InstanceStoreRequiresDeterminateNix,
     /// A Linux SELinux related error
-    #[error("Unable to install on an SELinux system without common SELinux tooling, the binaries `restorecon`, and `semodule` are required")]
+    #[error("{}", message!(ErrorSelinuxRequirements))]
     SelinuxRequirements,
+    /// A Linux SELinux related error, where only file contexts are registered
+    #[error("{}", message!(ErrorSelinuxFileContextRequirements, distro = .0))]
+    SelinuxFileContextRequirements(String),
     /// A UTF-8 related error
     #[error("UTF-8 error")]
     Utf8(#[from] FromUtf8Error),
     /// Custom planner error
     #[error("Custom planner error")]
     Custom(#[source] Box<dyn std::error::Error + Send + Sync>),
-    #[error("NixOS already has Nix installed")]
+    #[error("{}", message!(ErrorNixOs))]
     NixOs,
-    #[error("`nix` is already a valid command, so it is installed")]
+    #[error("{}", message!(ErrorNixExists))]
     NixExists,
-    #[error("WSL1 is not supported, please upgrade to WSL2: https://learn.microsoft.com/en-us/windows/wsl/install#upgrade-version-from-wsl-1-to-wsl-2")]
+    #[error("{}", message!(ErrorWsl1))]
     Wsl1,
+    #[error("{}", message!(ErrorSharedStoreNotAcknowledged, path = .0.display()))]
+    SharedStoreNotAcknowledged(PathBuf),
+    /// A daemon TCP listener, which is unauthenticated, was asked for without acknowledging it
+    #[error("{}", message!(ErrorDaemonTcpNotAcknowledged, listen = .0))]
+    DaemonTcpNotAcknowledged(SocketAddr),
+    /// A daemon TCP listener on a wildcard address, without allowing it
+    #[error("{}", message!(ErrorDaemonTcpWildcard, listen = .0))]
+    DaemonTcpWildcard(SocketAddr),
+    /// A daemon TCP listener without an init system to run its socket
+    #[error("{}", message!(ErrorDaemonTcpRequiresInit))]
+    DaemonTcpRequiresInit,
+    #[error("{}", message!(ErrorDaemonUserRequiresInit))]
+    DaemonUserRequiresInit,
+    #[error("{}", message!(ErrorDaemonResourcesRequireInit))]
+    DaemonResourcesRequireInit,
+    #[error("{}", message!(ErrorDaemonNiceInUserManager, nice = .0))]
+    DaemonNiceInUserManager(i8),
+    #[error("{}", message!(ErrorDaemonUserUnsupported, reason = .0))]
+    DaemonUserUnsupported(String),
+    #[error("{}", message!(ErrorDaemonUserNixTooOld, series = .series, minimum = .minimum))]
+    DaemonUserNixTooOld {
+        series: String,
+        minimum: &'static str,
+    },
+    #[error("{}", message!(ErrorNixVersionMismatch, installed = .installed, hinted = .hinted))]
+    NixVersionMismatch { installed: String, hinted: String },
+    #[error("{}", message!(ErrorNixTooOld, series = .series, minimum = .minimum))]
+    NixTooOld {
+        series: String,
+        minimum: &'static str,
+    },
+    #[error("{}", message!(ErrorNixDaemonLacks, series = .series, requirement = .requirement, description = .description, minimum = .minimum))]
+    NixDaemonLacks {
+        series: String,
+        requirement: &'static str,
+        description: &'static str,
+        minimum: &'static str,
+    },
+    #[error("{}", message!(ErrorOfflineRemoteResource, flag = .flag, url = .url))]
+    OfflineRemoteResource { flag: &'static str, url: String },
+    /// An installation of a Nix implementation `nix-installer` does not install, such as Lix
+    #[error("{}", message!(ErrorExistingImplementation, implementation = .implementation, evidence = .evidence, uninstall_guide = .uninstall_guide))]
+    ExistingImplementation {
+        implementation: String,
+        evidence: String,
+        uninstall_guide: String,
+    },
     /// Failed to execute command
     #[error("Failed to execute command `{0}`")]
     Command(String, #[source] std::io::Error),
@@ -426,7 +914,8 @@ impl HasExpectedErrors for PlannerError {
     fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
         match self {
             this @ PlannerError::UnsupportedArchitecture(_) => Some(Box::new(this)),
-            PlannerError::Action(_) => None,
+            // Such as the `--zfs-dataset` checks made while planning its action
+            PlannerError::Action(action_error) => action_error.kind().expected(),
             PlannerError::InstallSettings(_) => None,
             PlannerError::Plist(_) => None,
             PlannerError::Sysctl(_) => None,
@@ -437,6 +926,7 @@ impl HasExpectedErrors for PlannerError {
             PlannerError::OsRelease(_) => None,
             PlannerError::Utf8(_) => None,
             PlannerError::SelinuxRequirements => Some(Box::new(self)),
+            PlannerError::SelinuxFileContextRequirements(_) => Some(Box::new(self)),
             PlannerError::Custom(_e) => {
                 #[cfg(target_os = "linux")]
                 if let Some(err) = _e.downcast_ref::<linux::LinuxErrorKind>() {
@@ -451,6 +941,20 @@ impl HasExpectedErrors for PlannerError {
             this @ PlannerError::NixOs => Some(Box::new(this)),
             this @ PlannerError::NixExists => Some(Box::new(this)),
             this @ PlannerError::Wsl1 => Some(Box::new(this)),
+            this @ PlannerError::SharedStoreNotAcknowledged(_) => Some(Box::new(this)),
+            this @ PlannerError::DaemonTcpNotAcknowledged(_) => Some(Box::new(this)),
+            this @ PlannerError::DaemonTcpWildcard(_) => Some(Box::new(this)),
+            this @ PlannerError::DaemonTcpRequiresInit => Some(Box::new(this)),
+            this @ PlannerError::DaemonUserRequiresInit => Some(Box::new(this)),
+            this @ PlannerError::DaemonResourcesRequireInit => Some(Box::new(this)),
+            this @ PlannerError::DaemonNiceInUserManager(_) => Some(Box::new(this)),
+            this @ PlannerError::DaemonUserUnsupported(_) => Some(Box::new(this)),
+            this @ PlannerError::DaemonUserNixTooOld { .. } => Some(Box::new(this)),
+            this @ PlannerError::NixVersionMismatch { .. } => Some(Box::new(this)),
+            this @ PlannerError::NixTooOld { .. } => Some(Box::new(this)),
+            this @ PlannerError::NixDaemonLacks { .. } => Some(Box::new(this)),
+            this @ PlannerError::OfflineRemoteResource { .. } => Some(Box::new(this)),
+            this @ PlannerError::ExistingImplementation { .. } => Some(Box::new(this)),
             PlannerError::Command(_, _) => None,
             #[cfg(feature = "diagnostics")]
             PlannerError::Diagnostic(diagnostic_error) => Some(Box::new(diagnostic_error)),
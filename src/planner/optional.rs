@@ -0,0 +1,226 @@
+/*! The parts of a plan a user can leave out
+
+Each [`OptionalPart`] is controlled by a setting, so leaving one out of a [`BuiltinPlanner`] is the
+same as passing its flag. This backs the `[c]ustomize` choice of `nix-installer install`, which
+plans again with the changed settings, so the receipt records them as if they had been passed.
+*/
+
+use crate::settings::{InitSystem, Shell};
+
+use super::BuiltinPlanner;
+
+/// A part of a plan which can be left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionalPart {
+    /// Loading Nix from the profile of a shell
+    ShellProfile(Shell),
+    /// Excluding the Nix store from Time Machine backups
+    TmutilExclusions,
+    /// Starting the Nix daemon once it is installed
+    StartDaemon,
+}
+
+impl OptionalPart {
+    /// The parts `planner` can leave out
+    pub fn of(planner: &BuiltinPlanner) -> Vec<Self> {
+        let mut parts = vec![
+            Self::ShellProfile(Shell::Bash),
+            Self::ShellProfile(Shell::Zsh),
+            Self::ShellProfile(Shell::Fish),
+        ];
+        match planner {
+            BuiltinPlanner::Linux(linux) if linux.init.init != InitSystem::None => {
+                parts.push(Self::StartDaemon)
+            },
+            BuiltinPlanner::Macos(_) => parts.push(Self::TmutilExclusions),
+            _ => (),
+        }
+        parts
+    }
+
+    pub fn description(&self) -> String {
+        match self {
+            Self::ShellProfile(shell) => format!("Load Nix in {shell} shells"),
+            Self::TmutilExclusions => "Exclude the Nix store from Time Machine backups".into(),
+            Self::StartDaemon => "Start the Nix daemon".into(),
+        }
+    }
+
+    /// The flag which leaves this part out
+    pub fn flag(&self) -> String {
+        match self {
+            Self::ShellProfile(shell) => format!("--skip-shell-profile {shell}"),
+            Self::TmutilExclusions => "--no-tmutil-exclusions".into(),
+            Self::StartDaemon => "--no-start-daemon".into(),
+        }
+    }
+
+    /// If `planner` plans this part
+    pub fn is_enabled(&self, planner: &BuiltinPlanner) -> bool {
+        match (self, planner) {
+            (Self::ShellProfile(shell), planner) => {
+                let settings = planner.common_settings();
+                settings.modify_profile && !settings.skip_shell_profiles.contains(shell)
+            },
+            (Self::TmutilExclusions, BuiltinPlanner::Macos(macos)) => !macos.no_tmutil_exclusions,
+            (Self::StartDaemon, BuiltinPlanner::Linux(linux)) => linux.init.start_daemon,
+            _ => false,
+        }
+    }
+
+    /// Change the settings of `planner` so it plans this part, or leaves it out
+    pub fn set_enabled(&self, planner: &mut BuiltinPlanner, enabled: bool) {
+        if enabled && !self.is_enabled(planner) {
+            // Bringing a part back means the install is no longer `--minimal`
+            planner.common_settings_mut().minimal = false;
+        }
+        match (self, planner) {
+            (Self::ShellProfile(shell), planner) => {
+                let settings = planner.common_settings_mut();
+                if enabled {
+                    if !settings.modify_profile {
+                        // `--no-modify-profile` left out every shell, only bring this one back
+                        settings.modify_profile = true;
+                        settings.skip_shell_profiles = all_shells();
+                    }
+                    settings
+                        .skip_shell_profiles
+                        .retain(|skipped| skipped != shell);
+                } else if settings.modify_profile && !settings.skip_shell_profiles.contains(shell) {
+                    settings.skip_shell_profiles.push(*shell);
+                    if all_shells()
+                        .iter()
+                        .all(|shell| settings.skip_shell_profiles.contains(shell))
+                    {
+                        settings.modify_profile = false;
+                        settings.skip_shell_profiles.clear();
+                    }
+                }
+            },
+            (Self::TmutilExclusions, BuiltinPlanner::Macos(macos)) => {
+                macos.no_tmutil_exclusions = !enabled;
+                if !enabled {
+                    // Conflicts with `--no-tmutil-exclusions`
+                    macos.tmutil_exclude.clear();
+                }
+            },
+            (Self::StartDaemon, BuiltinPlanner::Linux(linux)) => linux.init.start_daemon = enabled,
+            (part, planner) => {
+                tracing::debug!(
+                    ?part,
+                    planner = planner.typetag_name(),
+                    "Planner has no such optional part"
+                )
+            },
+        }
+    }
+}
+
+fn all_shells() -> Vec<Shell> {
+    vec![Shell::Bash, Shell::Zsh, Shell::Fish]
+}
+
+#[cfg(test)]
+mod test {
+    use super::OptionalPart;
+    use crate::{
+        planner::{linux::Linux, macos::Macos, BuiltinPlanner},
+        settings::{CommonSettings, InitSettings, InitSystem, Shell, SystemdScope},
+    };
+
+    async fn linux() -> eyre::Result<BuiltinPlanner> {
+        Ok(BuiltinPlanner::Linux(Linux {
+            settings: CommonSettings::default().await?,
+            init: InitSettings {
+                init: InitSystem::Systemd,
+                start_daemon: true,
+            },
+            zfs_dataset: None,
+            zfs_no_auto_snapshot: false,
+            store_root: None,
+            systemd_scope: SystemdScope::System,
+        }))
+    }
+
+    #[tokio::test]
+    async fn shell_profiles_map_to_skipped_shells() -> eyre::Result<()> {
+        let mut planner = linux().await?;
+        let zsh = OptionalPart::ShellProfile(Shell::Zsh);
+        assert!(zsh.is_enabled(&planner));
+
+        zsh.set_enabled(&mut planner, false);
+        assert!(!zsh.is_enabled(&planner));
+        assert!(OptionalPart::ShellProfile(Shell::Bash).is_enabled(&planner));
+        let settings = planner.settings()?;
+        assert_eq!(
+            settings.get("skip_shell_profiles"),
+            Some(&serde_json::json!(["Zsh"]))
+        );
+        assert_eq!(
+            settings.get("modify_profile"),
+            Some(&serde_json::json!(true))
+        );
+
+        // Leaving every shell out is `--no-modify-profile`
+        OptionalPart::ShellProfile(Shell::Bash).set_enabled(&mut planner, false);
+        OptionalPart::ShellProfile(Shell::Fish).set_enabled(&mut planner, false);
+        let settings = planner.settings()?;
+        assert_eq!(
+            settings.get("modify_profile"),
+            Some(&serde_json::json!(false))
+        );
+        assert_eq!(
+            settings.get("skip_shell_profiles"),
+            Some(&serde_json::json!([]))
+        );
+
+        // Bringing one back after `--no-modify-profile` leaves the others out
+        zsh.set_enabled(&mut planner, true);
+        let settings = planner.settings()?;
+        assert_eq!(
+            settings.get("modify_profile"),
+            Some(&serde_json::json!(true))
+        );
+        assert_eq!(
+            settings.get("skip_shell_profiles"),
+            Some(&serde_json::json!(["Bash", "Fish"]))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn planner_specific_parts() -> eyre::Result<()> {
+        let mut planner = linux().await?;
+        assert!(OptionalPart::of(&planner).contains(&OptionalPart::StartDaemon));
+        assert!(!OptionalPart::of(&planner).contains(&OptionalPart::TmutilExclusions));
+        OptionalPart::StartDaemon.set_enabled(&mut planner, false);
+        assert_eq!(
+            planner.settings()?.get("start_daemon"),
+            Some(&serde_json::json!(false))
+        );
+
+        let mut planner = BuiltinPlanner::Macos(Macos {
+            settings: CommonSettings::default().await?,
+            encrypt: Some(false),
+            case_sensitive: false,
+            volume_label: "Nix Store".into(),
+            root_disk: Some("disk3".into()),
+            volume_quota: None,
+            volume_reserve: None,
+            use_ec2_instance_store: false,
+            no_tmutil_exclusions: false,
+            tmutil_exclude: vec!["/nix/var/cache".into()],
+            allow_rosetta_shell: false,
+        });
+        assert!(OptionalPart::of(&planner).contains(&OptionalPart::TmutilExclusions));
+        assert!(!OptionalPart::of(&planner).contains(&OptionalPart::StartDaemon));
+        OptionalPart::TmutilExclusions.set_enabled(&mut planner, false);
+        let settings = planner.settings()?;
+        assert_eq!(
+            settings.get("no_tmutil_exclusions"),
+            Some(&serde_json::json!(true))
+        );
+        assert_eq!(settings.get("tmutil_exclude"), Some(&serde_json::json!([])));
+        Ok(())
+    }
+}
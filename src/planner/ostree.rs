@@ -1,22 +1,36 @@
 use crate::{
+    action::common::provision_determinate_nixd::DETERMINATE_NIXD_BINARY_PATH,
     action::{
-        base::{CreateDirectory, CreateFile, RemoveDirectory},
+        base::{
+            CreateDirectory, CreateFile, ImportSeedClosure, InstallDefaultProfileFlakes,
+            RemoveDirectory, VerifyStore,
+        },
         common::{
             ConfigureNix, ConfigureUpstreamInitService, CreateUsersAndGroups,
             ProvisionDeterminateNixd, ProvisionNix,
         },
         linux::{
             provision_selinux::{DETERMINATE_SELINUX_POLICY_PP_CONTENT, SELINUX_POLICY_PP_CONTENT},
-            ProvisionSelinux, StartSystemdUnit, SystemctlDaemonReload,
+            MigrateLegacyDaemonSocket, ProvisionSelinux, StartSystemdUnit, SystemctlDaemonReload,
         },
         StatefulAction,
     },
+    backup::BackupStore,
+    cross_target, daemon_capabilities,
     error::HasExpectedErrors,
-    planner::{Planner, PlannerError},
+    messages::message,
+    os::ostree::{in_usr, OstreeStatus, Unlocked},
+    planner::{
+        check_offline, check_shared_store, daemon_resources, plan_daemon_tcp_listener,
+        plan_daemon_user, plan_service_mode, plan_stale_build_users, Planner, PlannerError,
+    },
     settings::{determinate_nix_settings, CommonSettings, InitSystem, InstallSettingsError},
     Action, BuiltinPlanner,
 };
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use super::{
     linux::{
@@ -37,6 +51,51 @@ pub struct Ostree {
     pub settings: CommonSettings,
 }
 
+impl Ostree {
+    /// Refuse to install where the next deployment would lose some of the install
+    fn check_deployments(&self, status: &OstreeStatus) -> Result<(), OstreeError> {
+        // A staged deployment merges `/etc` when the system shuts down, any other already did
+        if let Some(pending) = status.pending().filter(|pending| !pending.staged) {
+            return Err(OstreeError::PendingDeployment(pending.checksum.clone()));
+        }
+
+        let unlocked = status
+            .booted()
+            .map(|booted| booted.unlocked)
+            .unwrap_or_default();
+        if in_usr(&self.persistence) {
+            return Err(OstreeError::PersistenceInUsr {
+                persistence: self.persistence.clone(),
+                unlocked,
+            });
+        }
+        // `/usr/local` is usually a link to `/var/usrlocal`
+        if self.settings.determinate_nix && in_usr(Path::new(DETERMINATE_NIXD_BINARY_PATH)) {
+            return Err(OstreeError::DeterminateNixdInUsr { unlocked });
+        }
+        Ok(())
+    }
+}
+
+/// The shell profiles to hook into, leaving out those in `/usr`, which the next deployment replaces
+///
+/// `/usr/share/fish` is left out, fish reads `/etc/fish/conf.d` as well. `/usr/local` is usually a
+/// link to `/var/usrlocal`, so what is under it stays.
+pub(crate) fn persistent_shell_profile_locations() -> ShellProfileLocations {
+    let mut locations = ShellProfileLocations::default();
+    locations.bash.retain(|profile| !in_usr(profile));
+    locations.zsh.retain(|profile| !in_usr(profile));
+    locations
+        .fish
+        .confd_prefixes
+        .retain(|prefix| !in_usr(prefix));
+    locations
+        .fish
+        .vendor_confd_prefixes
+        .retain(|prefix| !in_usr(prefix));
+    locations
+}
+
 #[async_trait::async_trait]
 #[typetag::serde(name = "ostree")]
 impl Planner for Ostree {
@@ -48,20 +107,38 @@ impl Planner for Ostree {
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        if let Some(status) = OstreeStatus::query().await {
+            self.check_deployments(&status)?;
+        }
         let has_selinux = detect_selinux().await?;
+        check_offline(&self.settings)?;
+        let shared_store = check_shared_store(&self.settings)?;
+        let daemon_tcp_listener =
+            plan_daemon_tcp_listener(&self.settings, InitSystem::Systemd, true).await?;
+        let service_mode = plan_service_mode(&self.settings, InitSystem::Systemd)?;
+        let daemon_resources = daemon_resources(&self.settings, InitSystem::Systemd, false)?;
+        plan_daemon_user(
+            &self.settings,
+            InitSystem::Systemd,
+            shared_store,
+            Some("ostree"),
+        )
+        .await?;
+        let stale_build_users = plan_stale_build_users(&self.settings).await?;
         let mut plan = vec![
             // Primarily for uninstall
-            SystemctlDaemonReload::plan()
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(SystemctlDaemonReload::plan()).await?,
         ];
 
         plan.push(
-            CreateDirectory::plan(&self.persistence, None, None, 0o0755, true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(CreateDirectory::plan(
+                &self.persistence,
+                None,
+                None,
+                0o0755,
+                true,
+            ))
+            .await?,
         );
 
         let nix_directory_buf = "\
@@ -155,106 +232,111 @@ impl Planner for Ostree {
         .map_err(PlannerError::Action)?;
         plan.push(ensure_symlinked_units_resolve_unit.boxed());
 
-        // We need to remove this path since it's part of the read-only install.
-        let mut shell_profile_locations = ShellProfileLocations::default();
-        if let Some(index) = shell_profile_locations
-            .fish
-            .vendor_confd_prefixes
-            .iter()
-            .position(|v| *v == PathBuf::from("/usr/share/fish/"))
-        {
-            shell_profile_locations
-                .fish
-                .vendor_confd_prefixes
-                .remove(index);
-        }
+        let shell_profile_locations = persistent_shell_profile_locations();
 
         plan.push(
-            StartSystemdUnit::plan("nix.mount".to_string(), false)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(StartSystemdUnit::plan("nix.mount".to_string(), false))
+                .await?,
         );
 
         if self.settings.determinate_nix {
-            plan.push(
-                ProvisionDeterminateNixd::plan()
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
-            );
+            plan.push(cross_target::plan_action(ProvisionDeterminateNixd::plan()).await?);
         }
 
         plan.push(
-            ProvisionNix::plan(&self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(ProvisionNix::plan(&self.settings.clone(), shared_store))
+                .await?,
         );
+        plan.extend(stale_build_users);
         plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(CreateUsersAndGroups::plan(self.settings.clone())).await?,
         );
         plan.push(
-            ConfigureNix::plan(
+            cross_target::plan_action(ConfigureNix::plan(
                 shell_profile_locations,
                 &self.settings,
                 self.settings.determinate_nix.then(determinate_nix_settings),
-            )
-            .await
-            .map_err(PlannerError::Action)?
-            .boxed(),
+            ))
+            .await?,
         );
 
         if has_selinux {
             plan.push(
-                ProvisionSelinux::plan(
+                cross_target::plan_action(ProvisionSelinux::plan(
                     "/etc/nix-installer/selinux/packages/nix.pp".into(),
                     if self.settings.determinate_nix {
                         DETERMINATE_SELINUX_POLICY_PP_CONTENT
                     } else {
                         SELINUX_POLICY_PP_CONTENT
                     },
-                )
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                ))
+                .await?,
             );
         }
 
         plan.push(
-            CreateDirectory::plan("/etc/tmpfiles.d", None, None, 0o0755, false)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(CreateDirectory::plan(
+                "/etc/tmpfiles.d",
+                None,
+                None,
+                0o0755,
+                false,
+            ))
+            .await?,
         );
 
         plan.push(
-            ConfigureUpstreamInitService::plan(InitSystem::Systemd, true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(MigrateLegacyDaemonSocket::plan(BackupStore::new(
+                &self.settings.state_dir,
+            )))
+            .await?,
         );
         plan.push(
-            StartSystemdUnit::plan("ensure-symlinked-units-resolve.service".to_string(), true)
+            ConfigureUpstreamInitService::plan(InitSystem::Systemd, true, None)
                 .await
                 .map_err(PlannerError::Action)?
+                .with_service_mode(service_mode)
+                .with_resources(daemon_resources)
                 .boxed(),
         );
+        plan.extend(daemon_tcp_listener);
         plan.push(
-            RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(StartSystemdUnit::plan(
+                "ensure-symlinked-units-resolve.service".to_string(),
+                true,
+            ))
+            .await?,
         );
+        if self
+            .settings
+            .default_profile_packages
+            .iter()
+            .any(|package| package.flake().is_some())
+        {
+            plan.push(
+                cross_target::plan_action(InstallDefaultProfileFlakes::plan(
+                    &self.settings.default_profile_packages,
+                ))
+                .await?,
+            );
+        }
+        if let Some(seed_closure) = &self.settings.seed_closure {
+            plan.push(
+                cross_target::plan_action(ImportSeedClosure::plan(
+                    seed_closure,
+                    self.settings.seed_closure_require_sigs,
+                ))
+                .await?,
+            );
+        }
+        if self.settings.verify_store {
+            let series = daemon_capabilities::series_to_configure(&self.settings)?;
+            plan.push(cross_target::plan_action(VerifyStore::plan(series.as_deref(), true)).await?);
+        }
         plan.push(
-            SystemctlDaemonReload::plan()
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(RemoveDirectory::plan(crate::settings::SCRATCH_DIR)).await?,
         );
+        plan.push(cross_target::plan_action(SystemctlDaemonReload::plan()).await?);
 
         Ok(plan)
     }
@@ -295,19 +377,20 @@ impl Planner for Ostree {
     async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
         Ok(crate::diagnostics::DiagnosticData::new(
             self.settings.diagnostic_attribution.clone(),
-            self.settings.diagnostic_endpoint.clone(),
+            self.settings.diagnostic_endpoint_unless_offline(),
             self.typetag_name().into(),
             self.configured_settings()
                 .await?
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
+            self.settings.proxy.clone(),
         )?)
     }
 
     async fn platform_check(&self) -> Result<(), PlannerError> {
         use target_lexicon::OperatingSystem;
-        match target_lexicon::OperatingSystem::host() {
+        match crate::cross_target::operating_system() {
             OperatingSystem::Linux => Ok(()),
             host_os => Err(PlannerError::IncompatibleOperatingSystem {
                 planner: self.typetag_name(),
@@ -327,7 +410,7 @@ impl Planner for Ostree {
     async fn pre_install_check(&self) -> Result<(), PlannerError> {
         check_not_nixos()?;
 
-        check_nix_not_already_installed().await?;
+        check_nix_not_already_installed(&self.settings).await?;
 
         check_not_wsl1()?;
 
@@ -346,26 +429,29 @@ impl From<Ostree> for BuiltinPlanner {
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum OstreeError {
-    #[error(
-        "\
-        systemd was not active.\n\
-        \n\
-        If it will be started later consider, passing `--no-start-daemon`.\n\
-        \n\
-        To use a `root`-only Nix install, consider passing `--init none`."
-    )]
+    #[error("{}", message!(ErrorSystemdNotActive))]
     SystemdNotActive,
-    #[error(
-        "\
-        systemd was not active.\n\
-        \n\
-        On WSL2, systemd is not enabled by default. Consider enabling it by adding it to your `/etc/wsl.conf` with `echo -e '[boot]\\nsystemd=true'` then restarting WSL2 with `wsl.exe --shutdown` and re-entering the WSL shell. For more information, see https://devblogs.microsoft.com/commandline/systemd-support-is-now-available-in-wsl/.\n\
-        \n\
-        If it will be started later consider, passing `--no-start-daemon`.\n\
-        \n\
-        To use a `root`-only Nix install, consider passing `--init none`."
-    )]
+    #[error("{}", message!(ErrorWsl2SystemdNotActive))]
     Wsl2SystemdNotActive,
+    #[error("The deployment `{0}` is pending, and its `/etc` was already merged, so what the install writes to `/etc` would be missing once it is booted. Reboot into it (or drop it with `rpm-ostree cleanup --pending`), then install")]
+    PendingDeployment(String),
+    #[error("`{}` is in `/usr`, which the next deployment replaces{}. Pass a `--persistence` under `/var`", .persistence.display(), unlocked_note(*.unlocked))]
+    PersistenceInUsr {
+        persistence: PathBuf,
+        unlocked: Unlocked,
+    },
+    #[error("`{DETERMINATE_NIXD_BINARY_PATH}` is in `/usr`, which the next deployment replaces{}. Determinate Nix needs `/usr/local` to be a link to `/var/usrlocal`", unlocked_note(*.unlocked))]
+    DeterminateNixdInUsr { unlocked: Unlocked },
+}
+
+/// Why writing to `/usr` seems to work, when `ostree admin unlock` made it writable
+fn unlocked_note(unlocked: Unlocked) -> String {
+    match unlocked {
+        Unlocked::None => String::new(),
+        unlocked => format!(
+            " (`/usr` was made writable by `ostree admin unlock` ({unlocked}), but that is undone by the next deployment)"
+        ),
+    }
 }
 
 impl HasExpectedErrors for OstreeError {
@@ -373,6 +459,9 @@ impl HasExpectedErrors for OstreeError {
         match self {
             OstreeError::SystemdNotActive => Some(Box::new(self)),
             OstreeError::Wsl2SystemdNotActive => Some(Box::new(self)),
+            OstreeError::PendingDeployment(_) => Some(Box::new(self)),
+            OstreeError::PersistenceInUsr { .. } => Some(Box::new(self)),
+            OstreeError::DeterminateNixdInUsr { .. } => Some(Box::new(self)),
         }
     }
 }
@@ -382,3 +471,47 @@ impl From<OstreeError> for PlannerError {
         PlannerError::Custom(Box::new(v))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{Ostree, OstreeError};
+    use crate::{
+        os::ostree::OstreeStatus, planner::Planner, settings::CommonSettings,
+        test_harness::SandboxContext,
+    };
+
+    const NORMAL: &str = include_str!("../../tests/fixtures/ostree/normal.json");
+    const UNLOCKED: &str = include_str!("../../tests/fixtures/ostree/unlocked.json");
+    const STAGED: &str = include_str!("../../tests/fixtures/ostree/staged.json");
+
+    #[tokio::test]
+    async fn refuses_what_the_next_deployment_would_lose() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let ostree = sandbox.scope(Ostree::default()).await?;
+        sandbox.scope(async {
+            ostree.check_deployments(&OstreeStatus::parse(NORMAL)?)?;
+            // A staged deployment merges `/etc` as it is when it is booted
+            let mut staged = OstreeStatus::parse(STAGED)?;
+            ostree.check_deployments(&staged)?;
+            staged.deployments[0].staged = false;
+            assert!(matches!(
+                ostree.check_deployments(&staged),
+                Err(OstreeError::PendingDeployment(checksum)) if checksum == staged.deployments[0].checksum
+            ));
+
+            let in_usr = Ostree {
+                persistence: PathBuf::from("/usr/nix"),
+                settings: CommonSettings::default().await?,
+            };
+            let err = in_usr
+                .check_deployments(&OstreeStatus::parse(UNLOCKED)?)
+                .unwrap_err();
+            assert!(matches!(err, OstreeError::PersistenceInUsr { .. }));
+            assert!(err.to_string().contains("ostree admin unlock"), "{err}");
+            eyre::Ok(())
+        })
+        .await
+    }
+}
@@ -102,18 +102,28 @@ use tokio::process::Command;
 
 use crate::{
     action::{
-        base::{CreateDirectory, CreateFile, RemoveDirectory},
+        base::{
+            CreateDirectory, CreateFile, ImportSeedClosure, InstallDefaultProfileFlakes,
+            RemoveDirectory, VerifyStore,
+        },
         common::{
+            configure_daemon_tcp_listener::{TCP_SERVICE_NAME, TCP_SOCKET_NAME},
             ConfigureNix, ConfigureUpstreamInitService, CreateUsersAndGroups,
             ProvisionDeterminateNixd, ProvisionNix,
         },
         linux::{
-            EnsureSteamosNixDirectory, RevertCleanSteamosNixOffload, StartSystemdUnit,
-            SystemctlDaemonReload,
+            persist_steamos_units::RELINK_UNITS_SERVICE, EnsureSteamosNixDirectory,
+            MigrateLegacyDaemonSocket, PersistSteamosUnits, RevertCleanSteamosNixOffload,
+            StartSystemdUnit, SystemctlDaemonReload,
         },
         Action, StatefulAction,
     },
-    planner::{Planner, PlannerError},
+    backup::BackupStore,
+    cross_target, daemon_capabilities,
+    planner::{
+        check_offline, check_shared_store, daemon_resources, plan_daemon_tcp_listener,
+        plan_daemon_user, plan_service_mode, plan_stale_build_users, Planner, PlannerError,
+    },
     settings::{determinate_nix_settings, CommonSettings, InitSystem, InstallSettingsError},
     BuiltinPlanner,
 };
@@ -151,13 +161,24 @@ impl Planner for SteamDeck {
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
         // Starting in roughly build ID `20230522.1000`, the Steam Deck has a `/home/.steamos/offload/nix` directory and `nix.mount` unit we can use instead of creating a mountpoint.
         let requires_nix_bind_mount = detect_requires_bind_mount().await?;
+        check_offline(&self.settings)?;
+        let shared_store = check_shared_store(&self.settings)?;
+        let daemon_tcp_listener =
+            plan_daemon_tcp_listener(&self.settings, InitSystem::Systemd, true).await?;
+        let service_mode = plan_service_mode(&self.settings, InitSystem::Systemd)?;
+        let daemon_resources = daemon_resources(&self.settings, InitSystem::Systemd, false)?;
+        plan_daemon_user(
+            &self.settings,
+            InitSystem::Systemd,
+            shared_store,
+            Some("steam-deck"),
+        )
+        .await?;
+        let stale_build_users = plan_stale_build_users(&self.settings).await?;
 
         let mut actions = vec![
             // Primarily for uninstall
-            SystemctlDaemonReload::plan()
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            cross_target::plan_action(SystemctlDaemonReload::plan()).await?,
         ];
 
         if let Ok(nix_mount_status) = systemctl_status("nix.mount").await {
@@ -177,10 +198,14 @@ impl Planner for SteamDeck {
                 )));
             };
             actions.push(
-                CreateDirectory::plan(&persistence, None, None, 0o0755, true)
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
+                cross_target::plan_action(CreateDirectory::plan(
+                    &persistence,
+                    None,
+                    None,
+                    0o0755,
+                    true,
+                ))
+                .await?,
             );
 
             let nix_directory_buf = "\
@@ -271,19 +296,24 @@ impl Planner for SteamDeck {
         }
 
         if std::path::Path::new("/etc/atomic-update.conf.d").exists() {
-            let create_atomic_update_buf = "\
+            // The unit restoring the other Nix units after an update must survive it itself
+            let create_atomic_update_buf = format!(
+                "\
                 /etc/fish/conf.d/nix.fish\n\
                 /etc/nix/**\n\
                 /etc/profile.d/nix.sh\n\
                 /etc/systemd/system/nix-daemon.socket\n\
+                /etc/systemd/system/{RELINK_UNITS_SERVICE}\n\
+                /etc/systemd/system/sysinit.target.wants/{RELINK_UNITS_SERVICE}\n\
                 /etc/tmpfiles.d/nix-daemon.conf\n\
-            ";
+            "
+            );
             let create_atomic_update_unit = CreateFile::plan(
                 "/etc/atomic-update.conf.d/nix-installer.conf",
                 None,
                 None,
                 0o0644,
-                create_atomic_update_buf.to_string(),
+                create_atomic_update_buf,
                 false,
             )
             .await
@@ -336,57 +366,92 @@ impl Planner for SteamDeck {
 
         if requires_nix_bind_mount {
             actions.push(
-                StartSystemdUnit::plan("nix.mount".to_string(), false)
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
+                cross_target::plan_action(StartSystemdUnit::plan("nix.mount".to_string(), false))
+                    .await?,
             )
         }
 
         if self.settings.determinate_nix {
-            actions.push(
-                ProvisionDeterminateNixd::plan()
-                    .await
-                    .map_err(PlannerError::Action)?
-                    .boxed(),
-            );
+            actions.push(cross_target::plan_action(ProvisionDeterminateNixd::plan()).await?);
         }
 
+        actions.extend(stale_build_users);
         actions.append(&mut vec![
-            ProvisionNix::plan(&self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-            CreateUsersAndGroups::plan(self.settings.clone())
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-            ConfigureNix::plan(
+            cross_target::plan_action(ProvisionNix::plan(&self.settings.clone(), shared_store))
+                .await?,
+            cross_target::plan_action(CreateUsersAndGroups::plan(self.settings.clone())).await?,
+            cross_target::plan_action(ConfigureNix::plan(
                 shell_profile_locations,
                 &self.settings,
                 self.settings.determinate_nix.then(determinate_nix_settings),
-            )
-            .await
-            .map_err(PlannerError::Action)?
-            .boxed(),
+            ))
+            .await?,
+            cross_target::plan_action(MigrateLegacyDaemonSocket::plan(BackupStore::new(
+                &self.settings.state_dir,
+            )))
+            .await?,
             // Init is required for the steam-deck archetype to make the `/nix` mount
-            ConfigureUpstreamInitService::plan(InitSystem::Systemd, true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-            StartSystemdUnit::plan("ensure-symlinked-units-resolve.service".to_string(), true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-            RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
-            SystemctlDaemonReload::plan()
+            ConfigureUpstreamInitService::plan(InitSystem::Systemd, true, None)
                 .await
                 .map_err(PlannerError::Action)?
+                .with_service_mode(service_mode)
+                .with_resources(daemon_resources)
                 .boxed(),
+            cross_target::plan_action(StartSystemdUnit::plan(
+                "ensure-symlinked-units-resolve.service".to_string(),
+                true,
+            ))
+            .await?,
+            cross_target::plan_action(RemoveDirectory::plan(crate::settings::SCRATCH_DIR)).await?,
+            cross_target::plan_action(SystemctlDaemonReload::plan()).await?,
         ]);
+        actions.extend(daemon_tcp_listener);
+        // Units left out of `/etc/atomic-update.conf.d` are lost on an update, they are restored
+        // from a copy on `/home` at boot
+        let mut persisted_units = vec![
+            "nix-daemon.service",
+            "nix-daemon.socket",
+            TCP_SOCKET_NAME,
+            TCP_SERVICE_NAME,
+            "ensure-symlinked-units-resolve.service",
+        ];
+        if requires_nix_bind_mount {
+            persisted_units.extend(["nix-directory.service", "nix.mount"]);
+        }
+        actions.push(
+            cross_target::plan_action(PersistSteamosUnits::plan(
+                persisted_units.into_iter().map(String::from).collect(),
+            ))
+            .await?,
+        );
+        if self
+            .settings
+            .default_profile_packages
+            .iter()
+            .any(|package| package.flake().is_some())
+        {
+            actions.push(
+                cross_target::plan_action(InstallDefaultProfileFlakes::plan(
+                    &self.settings.default_profile_packages,
+                ))
+                .await?,
+            );
+        }
+        if let Some(seed_closure) = &self.settings.seed_closure {
+            actions.push(
+                cross_target::plan_action(ImportSeedClosure::plan(
+                    seed_closure,
+                    self.settings.seed_closure_require_sigs,
+                ))
+                .await?,
+            );
+        }
+        if self.settings.verify_store {
+            let series = daemon_capabilities::series_to_configure(&self.settings)?;
+            actions
+                .push(cross_target::plan_action(VerifyStore::plan(series.as_deref(), true)).await?);
+        }
+
         Ok(actions)
     }
 
@@ -426,19 +491,20 @@ impl Planner for SteamDeck {
     async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
         Ok(crate::diagnostics::DiagnosticData::new(
             self.settings.diagnostic_attribution.clone(),
-            self.settings.diagnostic_endpoint.clone(),
+            self.settings.diagnostic_endpoint_unless_offline(),
             self.typetag_name().into(),
             self.configured_settings()
                 .await?
                 .into_keys()
                 .collect::<Vec<_>>(),
             self.settings.ssl_cert_file.clone(),
+            self.settings.proxy.clone(),
         )?)
     }
 
     async fn platform_check(&self) -> Result<(), PlannerError> {
         use target_lexicon::OperatingSystem;
-        match target_lexicon::OperatingSystem::host() {
+        match crate::cross_target::operating_system() {
             OperatingSystem::Linux => Ok(()),
             host_os => Err(PlannerError::IncompatibleOperatingSystem {
                 planner: self.typetag_name(),
@@ -459,7 +525,7 @@ impl Planner for SteamDeck {
     async fn pre_install_check(&self) -> Result<(), PlannerError> {
         super::linux::check_not_nixos()?;
 
-        super::linux::check_nix_not_already_installed().await?;
+        super::linux::check_nix_not_already_installed(&self.settings).await?;
 
         super::linux::check_not_wsl1()?;
 
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+
+use crate::{
+    action::{linux::ConfigureWslDaemonLauncher, StatefulAction},
+    cross_target,
+    error::HasExpectedErrors,
+    planner::{
+        linux::{check_nix_not_already_installed, check_not_nixos, check_not_wsl1, Linux},
+        Planner, PlannerError, ShellProfileLocations,
+    },
+    settings::{
+        CommonSettings, InitSettings, InitSystem, InstallSettingsError, Shell, SystemdScope,
+    },
+    util::host_path,
+    warning::{self, WarningKind},
+    Action, BuiltinPlanner,
+};
+
+/// Where WSL reads the settings of a distribution, like whether to boot it with systemd
+const WSL_CONF: &str = "/etc/wsl.conf";
+
+/// A planner for Windows Subsystem for Linux 2, with or without systemd
+///
+/// Installs like [`Linux`], with the Nix daemon run by systemd when WSL boots with it. Otherwise
+/// the daemon is started from the shell profiles.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::Parser))]
+pub struct Wsl {
+    #[cfg_attr(feature = "cli", clap(flatten))]
+    pub settings: CommonSettings,
+    /// Leave the recommendation to enable systemd in `/etc/wsl.conf` out of the plan
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(clap::ArgAction::SetTrue),
+            default_value = "false",
+            env = "NIX_INSTALLER_NO_SYSTEMD_RECOMMENDATION"
+        )
+    )]
+    #[serde(default)]
+    pub no_systemd_recommendation: bool,
+}
+
+/// The version of WSL, from the kernel Microsoft builds for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WslVersion {
+    Wsl1,
+    Wsl2,
+}
+
+/// The version of WSL this is, if it is WSL
+pub(crate) fn detect_wsl() -> Option<WslVersion> {
+    let proc_version = std::fs::read_to_string(host_path("/proc/version")).ok()?;
+    wsl_version_of(&proc_version)
+}
+
+fn wsl_version_of(proc_version: &str) -> Option<WslVersion> {
+    // WSL2 kernels are like `5.15.153.1-microsoft-standard-WSL2`, while WSL1 emulates
+    // `4.4.0-19041-Microsoft (Microsoft@Microsoft.com)`
+    if proc_version.contains("microsoft") {
+        Some(WslVersion::Wsl2)
+    } else if proc_version.contains("Microsoft") {
+        Some(WslVersion::Wsl1)
+    } else {
+        None
+    }
+}
+
+/// Whether the `[boot]` section of `wsl_conf` enables systemd
+fn wsl_conf_enables_systemd(wsl_conf: &str) -> bool {
+    let mut section = String::new();
+    let mut enabled = false;
+    for line in wsl_conf.lines() {
+        let line = line.split(['#', ';']).next().unwrap_or_default().trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_ascii_lowercase();
+        } else if let Some((key, value)) = line.split_once('=') {
+            if section == "boot" && key.trim().eq_ignore_ascii_case("systemd") {
+                // The last setting wins
+                enabled = value.trim().eq_ignore_ascii_case("true");
+            }
+        }
+    }
+    enabled
+}
+
+/// Whether systemd runs, which it only does once WSL was restarted after enabling it
+fn systemd_running() -> bool {
+    host_path("/run/systemd/system").exists()
+}
+
+impl Wsl {
+    /// The [`Linux`] planner this installs like, with the init system WSL runs
+    fn linux(&self, systemd: bool) -> Linux {
+        Linux {
+            settings: self.settings.clone(),
+            init: InitSettings {
+                init: if systemd {
+                    InitSystem::Systemd
+                } else {
+                    InitSystem::None
+                },
+                start_daemon: systemd,
+            },
+            zfs_dataset: None,
+            zfs_no_auto_snapshot: false,
+            store_root: None,
+            systemd_scope: SystemdScope::System,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "wsl")]
+impl Planner for Wsl {
+    async fn default() -> Result<Self, PlannerError> {
+        Ok(Self {
+            settings: CommonSettings::default().await?,
+            no_systemd_recommendation: false,
+        })
+    }
+
+    async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        let systemd = systemd_running();
+        if systemd {
+            return self.linux(true).plan().await;
+        }
+
+        if self.settings.determinate_nix {
+            return Err(WslErrorKind::DeterminateRequiresSystemd.into());
+        }
+        let enabled = std::fs::read_to_string(host_path(WSL_CONF))
+            .is_ok_and(|wsl_conf| wsl_conf_enables_systemd(&wsl_conf));
+        if enabled {
+            warning::warn(
+                WarningKind::WslSystemdNotRunning,
+                format!("`{WSL_CONF}` enables systemd, but it is not running, so the Nix daemon will be started from the shell profiles. Restart WSL with `wsl.exe --shutdown` to run the daemon with systemd instead, then reinstall."),
+            );
+        } else if !self.no_systemd_recommendation {
+            warning::warn(
+                WarningKind::WslSystemdNotRunning,
+                format!("systemd is not enabled in WSL, so the Nix daemon will be started from the shell profiles. For the daemon to start with WSL, add `[boot]` with `systemd=true` to `{WSL_CONF}`, restart WSL with `wsl.exe --shutdown`, then reinstall."),
+            );
+        }
+
+        let mut plan = self.linux(false).plan().await?;
+        let locations = match self.settings.modify_profile {
+            true => ShellProfileLocations::default().without(&self.settings.skip_shell_profiles),
+            // The launcher still starts the daemon once, after installing
+            false => {
+                ShellProfileLocations::default().without(&[Shell::Bash, Shell::Zsh, Shell::Fish])
+            },
+        };
+        plan.push(cross_target::plan_action(ConfigureWslDaemonLauncher::plan(locations)).await?);
+        Ok(plan)
+    }
+
+    fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+        let Self {
+            settings,
+            no_systemd_recommendation,
+        } = self;
+        let mut map = HashMap::default();
+
+        map.extend(settings.settings()?);
+        map.insert(
+            "no_systemd_recommendation".into(),
+            serde_json::to_value(no_systemd_recommendation)?,
+        );
+
+        Ok(map)
+    }
+
+    async fn configured_settings(
+        &self,
+    ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
+        let default = Self::default().await?.settings()?;
+        let configured = self.settings()?;
+
+        let mut settings: HashMap<String, serde_json::Value> = HashMap::new();
+        for (key, value) in configured.iter() {
+            if default.get(key) != Some(value) {
+                settings.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(settings)
+    }
+
+    #[cfg(feature = "diagnostics")]
+    async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
+        Ok(crate::diagnostics::DiagnosticData::new(
+            self.settings.diagnostic_attribution.clone(),
+            self.settings.diagnostic_endpoint_unless_offline(),
+            self.typetag_name().into(),
+            self.configured_settings()
+                .await?
+                .into_keys()
+                .collect::<Vec<_>>(),
+            self.settings.ssl_cert_file.clone(),
+            self.settings.proxy.clone(),
+        )?)
+    }
+
+    async fn platform_check(&self) -> Result<(), PlannerError> {
+        use target_lexicon::OperatingSystem;
+        match crate::cross_target::operating_system() {
+            OperatingSystem::Linux => Ok(()),
+            host_os => Err(PlannerError::IncompatibleOperatingSystem {
+                planner: self.typetag_name(),
+                host_os,
+            }),
+        }
+    }
+
+    async fn pre_uninstall_check(&self) -> Result<(), PlannerError> {
+        check_wsl2()
+    }
+
+    async fn pre_install_check(&self) -> Result<(), PlannerError> {
+        check_not_nixos()?;
+
+        check_nix_not_already_installed(&self.settings).await?;
+
+        check_wsl2()
+    }
+}
+
+/// Refuse WSL1, which has no real Linux kernel to run Nix on
+fn check_wsl2() -> Result<(), PlannerError> {
+    // `sudo` may drop the variables `check_not_wsl1` looks for, the kernel version remains
+    if detect_wsl() == Some(WslVersion::Wsl1) {
+        return Err(PlannerError::Wsl1);
+    }
+    check_not_wsl1()
+}
+
+impl From<Wsl> for BuiltinPlanner {
+    fn from(val: Wsl) -> Self {
+        BuiltinPlanner::Wsl(val)
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum WslErrorKind {
+    #[error("Determinate Nix on WSL requires systemd. Add `[boot]` with `systemd=true` to `/etc/wsl.conf`, restart WSL with `wsl.exe --shutdown`, then try again.")]
+    DeterminateRequiresSystemd,
+}
+
+impl HasExpectedErrors for WslErrorKind {
+    fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
+        match self {
+            WslErrorKind::DeterminateRequiresSystemd => Some(Box::new(self)),
+        }
+    }
+}
+
+impl From<WslErrorKind> for PlannerError {
+    fn from(v: WslErrorKind) -> PlannerError {
+        PlannerError::Custom(Box::new(v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::{wsl_conf_enables_systemd, wsl_version_of, Wsl, WslErrorKind, WslVersion};
+    use crate::{
+        planner::{Planner, PlannerError},
+        test_harness::SandboxContext,
+        warning::{self, WarningKind},
+    };
+
+    #[test]
+    fn wsl_is_told_apart_by_its_kernel() {
+        assert_eq!(
+            wsl_version_of("Linux version 5.15.153.1-microsoft-standard-WSL2 (root@941d701f84f1)"),
+            Some(WslVersion::Wsl2)
+        );
+        assert_eq!(
+            wsl_version_of("Linux version 4.4.0-19041-Microsoft (Microsoft@Microsoft.com)"),
+            Some(WslVersion::Wsl1)
+        );
+        assert_eq!(
+            wsl_version_of("Linux version 6.8.0-45-generic (buildd@lcy02-amd64-115)"),
+            None
+        );
+    }
+
+    #[test]
+    fn systemd_is_enabled_in_the_boot_section() {
+        assert!(wsl_conf_enables_systemd("[boot]\nsystemd=true\n"));
+        assert!(wsl_conf_enables_systemd(
+            "[automount]\nenabled = true\n\n[Boot]\nsystemd = True # since 0.67.6\n"
+        ));
+        assert!(!wsl_conf_enables_systemd("[automount]\nsystemd=true\n"));
+        assert!(!wsl_conf_enables_systemd("[boot]\n# systemd=true\n"));
+        assert!(!wsl_conf_enables_systemd(
+            "[boot]\nsystemd=true\nsystemd=false\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn without_systemd_the_profiles_start_the_daemon() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let wsl = Wsl::try_parse_from(["wsl"])?;
+        let shell = sandbox.path(&wsl.settings.nix_build_user_shell);
+        std::fs::create_dir_all(shell.parent().unwrap())?;
+        std::fs::write(shell, "")?;
+
+        let (plan, warnings) = sandbox.scope(warning::collect(wsl.plan())).await;
+        let tags = plan?
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        assert_eq!(tags.last(), Some(&"configure_wsl_daemon_launcher"));
+        assert!(!tags.contains(&"migrate_legacy_daemon_socket"));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::WslSystemdNotRunning);
+        assert!(warnings[0].message.contains("systemd=true"));
+
+        let wsl = Wsl::try_parse_from(["wsl", "--no-systemd-recommendation"])?;
+        let (_, warnings) = sandbox.scope(warning::collect(wsl.plan())).await;
+        assert!(warnings.is_empty());
+
+        // Determinate Nix has no launcher
+        let wsl = Wsl::try_parse_from(["wsl", "--determinate"])?;
+        let error = match sandbox.scope(wsl.plan()).await {
+            Err(PlannerError::Custom(e)) => e.downcast::<WslErrorKind>().ok().map(|e| *e),
+            _ => None,
+        };
+        assert!(matches!(
+            error,
+            Some(WslErrorKind::DeterminateRequiresSystemd)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn settings_round_trip_through_the_receipt() -> eyre::Result<()> {
+        let wsl = Wsl::try_parse_from(["wsl", "--no-systemd-recommendation"])?;
+        let planner: Box<dyn Planner> =
+            serde_json::from_value(serde_json::to_value(&wsl as &dyn Planner)?)?;
+        assert_eq!(planner.typetag_name(), "wsl");
+        assert_eq!(planner.settings()?, wsl.settings()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,774 @@
+/*! Inspecting the default Nix profile
+
+Shell hooks put `/nix/var/nix/profiles/default/bin` on the `PATH`. That only provides `nix` while
+the chain `/nix/var/nix/profiles/default` → `default-N-link` → `/nix/store/...-user-environment`
+resolves, which a manual `rm` of a generation, a garbage collection, or restoring `/nix` from a
+backup can break without anything else noticing.
+*/
+
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{action::ActionErrorKind, util::host_path};
+
+pub(crate) const NIX_STORE: &str = "/nix/store";
+pub(crate) const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/default";
+pub(crate) const ROOT_CHANNELS_PROFILE: &str = "/nix/var/nix/profiles/per-user/root/channels";
+/// Where profiles which could not be built on are moved, below the profiles directory so their
+/// (surviving) store paths stay protected from garbage collection
+pub(crate) const SIDELINED_PROFILES_DIR: &str = "/nix/var/nix/profiles/sidelined";
+
+/// Profiles are rarely more than `default` → `default-N-link` → store path
+const MAX_LINKS: usize = 8;
+/// Deeper than any package nests its files, so a link back up a tree cannot be followed forever
+const MAX_DEPTH: usize = 32;
+
+/// The store paths `SetupDefaultProfile` installed into the default profile, as recorded in a receipt
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct RecordedStorePaths {
+    pub(crate) nix: Option<PathBuf>,
+    pub(crate) nss_ca_cert: Option<PathBuf>,
+}
+
+impl RecordedStorePaths {
+    /// Search a receipt (of any version) for the store paths recorded by `setup_default_profile`
+    pub(crate) fn from_receipt(receipt: &serde_json::Value) -> Self {
+        match receipt {
+            serde_json::Value::Object(map) => {
+                if map.get("action_name").and_then(|v| v.as_str()) == Some("setup_default_profile")
+                {
+                    let path = |key: &str| map.get(key).and_then(|v| v.as_str()).map(PathBuf::from);
+                    return Self {
+                        nix: path("nix_store_path"),
+                        nss_ca_cert: path("nss_ca_cert_store_path"),
+                    };
+                }
+                map.values()
+                    .map(Self::from_receipt)
+                    .find(|found| *found != Self::default())
+                    .unwrap_or_default()
+            },
+            serde_json::Value::Array(values) => values
+                .iter()
+                .map(Self::from_receipt)
+                .find(|found| *found != Self::default())
+                .unwrap_or_default(),
+            _ => Self::default(),
+        }
+    }
+}
+
+/// Walk the default profile's symlink chain, returning the store path it resolves to
+///
+/// The store path must contain `bin/nix`.
+#[tracing::instrument(level = "debug")]
+pub(crate) async fn verify_default_profile() -> Result<PathBuf, DefaultProfileError> {
+    let mut link = PathBuf::from(DEFAULT_PROFILE);
+    for _ in 0..MAX_LINKS {
+        let target = resolve_link(&link).await?;
+        if let Some(store_path) = store_path_of(&target) {
+            if !has_nix_binary(&store_path) {
+                return Err(DefaultProfileError::MissingNix(store_path));
+            }
+            tracing::debug!(store_path = %store_path.display(), "Default profile resolves");
+            return Ok(store_path);
+        }
+        link = target;
+    }
+    Err(DefaultProfileError::TooManyLinks(PathBuf::from(
+        DEFAULT_PROFILE,
+    )))
+}
+
+/// The target of the symlink at `link`, which must exist
+async fn resolve_link(link: &Path) -> Result<PathBuf, DefaultProfileError> {
+    let host_link = host_path(link);
+    let metadata = match tokio::fs::symlink_metadata(&host_link).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(DefaultProfileError::Missing(link.to_path_buf()))
+        },
+        Err(e) => return Err(DefaultProfileError::ReadLink(link.to_path_buf(), e)),
+    };
+    if !metadata.file_type().is_symlink() {
+        return Err(DefaultProfileError::NotSymlink(link.to_path_buf()));
+    }
+    let target = tokio::fs::read_link(&host_link)
+        .await
+        .map_err(|e| DefaultProfileError::ReadLink(link.to_path_buf(), e))?;
+    // Generation links are relative to the profiles directory
+    let target = match target.is_absolute() {
+        true => target,
+        false => link.parent().unwrap_or(Path::new("/")).join(target),
+    };
+    if tokio::fs::symlink_metadata(host_path(&target))
+        .await
+        .is_err()
+    {
+        return Err(DefaultProfileError::Dangling {
+            link: link.to_path_buf(),
+            target,
+        });
+    }
+    Ok(target)
+}
+
+/// The top level store path containing `path` (`/nix/store/$hash-$name`), if any
+fn store_path_of(path: &Path) -> Option<PathBuf> {
+    let rest = path.strip_prefix(NIX_STORE).ok()?;
+    match rest.components().next() {
+        Some(Component::Normal(name)) => Some(Path::new(NIX_STORE).join(name)),
+        _ => None,
+    }
+}
+
+fn has_nix_binary(store_path: &Path) -> bool {
+    host_path(store_path).join("bin/nix").exists()
+}
+
+/// Every `nix` package in the store which provides `bin/nix`
+pub(crate) fn nix_store_path_candidates() -> Result<Vec<PathBuf>, DefaultProfileError> {
+    store_paths_matching("*-nix-[0-9]*", "bin/nix")
+}
+
+fn store_paths_matching(
+    name_glob: &str,
+    contains: &str,
+) -> Result<Vec<PathBuf>, DefaultProfileError> {
+    let pattern = host_path(NIX_STORE).join(name_glob).join(contains);
+    let mut found = vec![];
+    for entry in glob::glob(&pattern.to_string_lossy())? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Unreadable store paths can't be used anyway
+            Err(_) => continue,
+        };
+        let store_path = entry
+            .ancestors()
+            .nth(Path::new(contains).components().count())
+            .and_then(|store_path| store_path.file_name())
+            .map(|name| Path::new(NIX_STORE).join(name));
+        if let Some(store_path) = store_path {
+            found.push(store_path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// The `nix` store path to rebuild the default profile from
+///
+/// Prefers `use_store_path`, then the path recorded in the receipt, then the only `nix` package in
+/// the store. Refuses to guess between several packages.
+pub(crate) fn choose_nix_store_path(
+    use_store_path: Option<&Path>,
+    recorded: Option<&Path>,
+) -> Result<PathBuf, DefaultProfileError> {
+    if let Some(use_store_path) = use_store_path {
+        if store_path_of(use_store_path).as_deref() != Some(use_store_path)
+            || !has_nix_binary(use_store_path)
+        {
+            return Err(DefaultProfileError::UnusableStorePath(
+                use_store_path.to_path_buf(),
+            ));
+        }
+        return Ok(use_store_path.to_path_buf());
+    }
+
+    if let Some(recorded) = recorded {
+        if has_nix_binary(recorded) {
+            return Ok(recorded.to_path_buf());
+        }
+        tracing::debug!(
+            recorded = %recorded.display(),
+            "The `nix` store path recorded in the receipt is no longer usable"
+        );
+    }
+
+    let mut candidates = nix_store_path_candidates()?;
+    match candidates.len() {
+        0 => Err(DefaultProfileError::NoCandidate),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(DefaultProfileError::AmbiguousCandidates(candidates)),
+    }
+}
+
+/// The `nss-cacert` store path to put back into the default profile alongside `nix`, if one can be found unambiguously
+pub(crate) fn choose_nss_ca_cert_store_path(recorded: Option<&Path>) -> Option<PathBuf> {
+    const CA_BUNDLE: &str = "etc/ssl/certs/ca-bundle.crt";
+    if let Some(recorded) = recorded {
+        if host_path(recorded).join(CA_BUNDLE).exists() {
+            return Some(recorded.to_path_buf());
+        }
+    }
+    match store_paths_matching("*-nss-cacert-*", CA_BUNDLE) {
+        Ok(mut found) if found.len() == 1 => Some(found.remove(0)),
+        _ => None,
+    }
+}
+
+/// Check that no two of `packages` (each named for errors) provide the same file
+///
+/// A profile can only link one of them, so `nix-env` would refuse the install part way through.
+pub(crate) fn validate_paths_can_cohabitate(
+    packages: &[(String, PathBuf)],
+) -> Result<(), DefaultProfileError> {
+    let mut provided: HashMap<PathBuf, (&str, PathBuf)> = HashMap::new();
+    for (name, store_path) in packages {
+        for (file, target) in provided_files(store_path)? {
+            match provided.get(&file) {
+                Some((other, other_target)) if *other_target != target => {
+                    return Err(DefaultProfileError::Collision {
+                        file,
+                        first: other.to_string(),
+                        second: name.clone(),
+                    });
+                },
+                Some(_) => continue,
+                None => {
+                    provided.insert(file, (name, target));
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every file below `store_path`, relative to it, along with the file it resolves to
+fn provided_files(store_path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, DefaultProfileError> {
+    let mut files = vec![];
+    let mut pending = vec![(PathBuf::new(), store_path.to_path_buf())];
+    while let Some((relative, dir)) = pending.pop() {
+        if relative.components().count() > MAX_DEPTH {
+            continue;
+        }
+        let entries = std::fs::read_dir(host_path(&dir))
+            .map_err(|e| DefaultProfileError::ReadDir(dir.clone(), e))?;
+        for entry in entries {
+            let name = entry
+                .map_err(|e| DefaultProfileError::ReadDir(dir.clone(), e))?
+                .file_name();
+            let path = dir.join(&name);
+            // Profiles link whole directories when only one package provides them
+            let target = match std::fs::read_link(host_path(&path)) {
+                Ok(target) => dir.join(target),
+                Err(_) => path,
+            };
+            if host_path(&target).is_dir() {
+                pending.push((relative.join(&name), target));
+            } else {
+                files.push((relative.join(&name), target));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Links in the profiles directory belonging to the default profile which no longer resolve
+pub(crate) async fn dangling_default_profile_links() -> Result<Vec<PathBuf>, DefaultProfileError> {
+    let mut dangling = vec![];
+    for link in profile_links(Path::new(DEFAULT_PROFILE)).await? {
+        if let Err(DefaultProfileError::Dangling { .. }) = resolve_link(&link).await {
+            dangling.push(link);
+        }
+    }
+    Ok(dangling)
+}
+
+/// The links making up `profile`: the profile itself and each of its `$name-N-link` generations
+async fn profile_links(profile: &Path) -> Result<Vec<PathBuf>, DefaultProfileError> {
+    let (Some(profiles_dir), Some(profile_name)) = (profile.parent(), profile.file_name()) else {
+        return Ok(vec![]);
+    };
+    let profile_name = profile_name.to_string_lossy();
+    let mut entries = match tokio::fs::read_dir(host_path(profiles_dir)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(DefaultProfileError::ReadLink(profiles_dir.to_path_buf(), e)),
+    };
+    let mut links = vec![];
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| DefaultProfileError::ReadLink(profiles_dir.to_path_buf(), e))?
+    {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let is_generation = name
+            .strip_prefix(&*profile_name)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .and_then(|rest| rest.strip_suffix("-link"))
+            .is_some_and(|number| number.parse::<u64>().is_ok());
+        if name == profile_name || is_generation {
+            links.push(profiles_dir.join(&*name));
+        }
+    }
+    links.sort();
+    Ok(links)
+}
+
+/// Store paths which `profile`'s generations refer to, but which no longer exist
+///
+/// Both the store path each generation links to and the links at the top of that store path (such
+/// as `manifest.nix`, or a channel) are checked. A profile which does not exist has none.
+pub(crate) async fn dead_profile_references(
+    profile: &Path,
+) -> Result<Vec<PathBuf>, DefaultProfileError> {
+    let mut dead = vec![];
+    for link in profile_links(profile).await? {
+        let target = match resolve_link(&link).await {
+            Ok(target) => target,
+            Err(DefaultProfileError::Dangling { target, .. }) => {
+                dead.push(target);
+                continue;
+            },
+            Err(DefaultProfileError::NotSymlink(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        // The profile itself links to a generation, which is checked on its own
+        let Some(store_path) = store_path_of(&target) else {
+            continue;
+        };
+        let entries = match std::fs::read_dir(host_path(&store_path)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotADirectory => continue,
+            Err(e) => return Err(DefaultProfileError::ReadDir(store_path, e)),
+        };
+        for entry in entries {
+            let path = store_path.join(
+                entry
+                    .map_err(|e| DefaultProfileError::ReadDir(store_path.clone(), e))?
+                    .file_name(),
+            );
+            if let Err(DefaultProfileError::Dangling { target, .. }) = resolve_link(&path).await {
+                dead.push(target);
+            }
+        }
+    }
+    dead.sort();
+    dead.dedup();
+    Ok(dead)
+}
+
+/// The store path the current generation of `profile` links to, if it resolves
+pub(crate) async fn current_generation(profile: &Path) -> Option<PathBuf> {
+    let mut link = profile.to_path_buf();
+    for _ in 0..MAX_LINKS {
+        let target = resolve_link(&link).await.ok()?;
+        if let Some(store_path) = store_path_of(&target) {
+            return Some(store_path);
+        }
+        link = target;
+    }
+    None
+}
+
+/// A profile whose links were moved aside so a fresh one could be started in its place
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct SidelinedProfile {
+    /// The profile, such as `/nix/var/nix/profiles/default`
+    pub(crate) profile: PathBuf,
+    /// The directory the profile's links were moved into
+    pub(crate) backup: PathBuf,
+}
+
+/// Move the links of `profile` into a new directory below [`SIDELINED_PROFILES_DIR`]
+///
+/// Links keep their names, so the relative link from a profile to its generation still resolves
+/// once they are moved back by [`restore_sidelined_profile`].
+pub(crate) async fn sideline_profile(
+    profile: &Path,
+) -> Result<Option<SidelinedProfile>, DefaultProfileError> {
+    let links = profile_links(profile).await?;
+    let Some(profile_name) = profile.file_name().filter(|_| !links.is_empty()) else {
+        return Ok(None);
+    };
+    let profile_name = profile_name.to_string_lossy();
+
+    let sidelined_dir = Path::new(SIDELINED_PROFILES_DIR);
+    let mut backup = sidelined_dir.join(&*profile_name);
+    let mut attempt = 1;
+    while tokio::fs::symlink_metadata(host_path(&backup))
+        .await
+        .is_ok()
+    {
+        attempt += 1;
+        backup = sidelined_dir.join(format!("{profile_name}-{attempt}"));
+    }
+    tokio::fs::create_dir_all(host_path(&backup))
+        .await
+        .map_err(|e| DefaultProfileError::Sideline(backup.clone(), e))?;
+
+    for link in links {
+        // Every link is below the profile's directory, so has a name
+        let destination = backup.join(link.file_name().unwrap_or_default());
+        tracing::debug!(link = %link.display(), destination = %destination.display(), "Sidelining profile link");
+        tokio::fs::rename(host_path(&link), host_path(&destination))
+            .await
+            .map_err(|e| DefaultProfileError::Sideline(link.clone(), e))?;
+    }
+    Ok(Some(SidelinedProfile {
+        profile: profile.to_path_buf(),
+        backup,
+    }))
+}
+
+/// Replace whatever links `sidelined.profile` now has with the ones [`sideline_profile`] moved aside
+pub(crate) async fn restore_sidelined_profile(
+    sidelined: &SidelinedProfile,
+) -> Result<(), DefaultProfileError> {
+    let SidelinedProfile { profile, backup } = sidelined;
+    let profiles_dir = profile.parent().unwrap_or(Path::new("/"));
+    for link in profile_links(profile).await? {
+        tokio::fs::remove_file(host_path(&link))
+            .await
+            .map_err(|e| DefaultProfileError::Sideline(link.clone(), e))?;
+    }
+
+    let mut entries = tokio::fs::read_dir(host_path(backup))
+        .await
+        .map_err(|e| DefaultProfileError::ReadDir(backup.clone(), e))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| DefaultProfileError::ReadDir(backup.clone(), e))?
+    {
+        let link = profiles_dir.join(entry.file_name());
+        tokio::fs::rename(entry.path(), host_path(&link))
+            .await
+            .map_err(|e| DefaultProfileError::Sideline(link.clone(), e))?;
+    }
+    tokio::fs::remove_dir(host_path(backup))
+        .await
+        .map_err(|e| DefaultProfileError::Sideline(backup.clone(), e))?;
+    Ok(())
+}
+
+#[non_exhaustive]
+#[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
+pub enum DefaultProfileError {
+    #[error("The default profile `{0}` does not exist, run `nix-installer repair default-profile` to recreate it")]
+    Missing(PathBuf),
+    #[error("`{0}` is not a symlink, so the default profile cannot be resolved")]
+    NotSymlink(PathBuf),
+    #[error("Reading `{0}`")]
+    ReadLink(PathBuf, #[source] std::io::Error),
+    #[error("The default profile link `{link}` points to `{target}`, which does not exist, run `nix-installer repair default-profile` to rebuild it")]
+    Dangling { link: PathBuf, target: PathBuf },
+    #[error("The default profile resolves to `{0}`, which does not contain `bin/nix`, run `nix-installer repair default-profile` to rebuild it")]
+    MissingNix(PathBuf),
+    #[error(
+        "The default profile `{0}` does not resolve to a store path after following several links"
+    )]
+    TooManyLinks(PathBuf),
+    #[error("No `nix` package in `/nix/store` provides `bin/nix`, reinstall Nix to recover")]
+    NoCandidate,
+    #[error("Found multiple `nix` packages in `/nix/store`, pass the one to use with `--use-store-path`:\n{}", .0.iter().map(|v| format!("  {}", v.display())).collect::<Vec<_>>().join("\n"))]
+    AmbiguousCandidates(Vec<PathBuf>),
+    #[error("`{0}` is not a store path providing `bin/nix`")]
+    UnusableStorePath(PathBuf),
+    #[error("Searching `/nix/store`")]
+    Glob(#[from] glob::PatternError),
+    #[error("Listing `{0}`")]
+    ReadDir(PathBuf, #[source] std::io::Error),
+    #[error("Moving the broken profile link `{0}`")]
+    Sideline(PathBuf, #[source] std::io::Error),
+    #[error("`{first}` and `{second}` both provide `{}`, so they cannot share the default profile", file.display())]
+    Collision {
+        file: PathBuf,
+        first: String,
+        second: String,
+    },
+}
+
+impl From<DefaultProfileError> for ActionErrorKind {
+    fn from(val: DefaultProfileError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use crate::test_harness::SandboxContext;
+
+    use super::{
+        choose_nix_store_path, current_generation, dangling_default_profile_links,
+        dead_profile_references, restore_sidelined_profile, sideline_profile,
+        validate_paths_can_cohabitate, verify_default_profile, DefaultProfileError,
+        RecordedStorePaths, SidelinedProfile, DEFAULT_PROFILE, ROOT_CHANNELS_PROFILE,
+    };
+
+    const NIX: &str = "/nix/store/aaaa-nix-2.24.9";
+    const OTHER_NIX: &str = "/nix/store/bbbb-nix-2.25.0";
+    const ENVIRONMENT: &str = "/nix/store/cccc-user-environment";
+
+    fn store_path_with_nix(sandbox: &SandboxContext, store_path: &str) -> std::io::Result<()> {
+        let bin = sandbox.path(store_path).join("bin");
+        std::fs::create_dir_all(&bin)?;
+        std::fs::write(bin.join("nix"), "")
+    }
+
+    fn default_profile(sandbox: &SandboxContext) -> std::io::Result<()> {
+        store_path_with_nix(sandbox, ENVIRONMENT)?;
+        sandbox.symlink(ENVIRONMENT, "/nix/var/nix/profiles/default-1-link")?;
+        sandbox.symlink("default-1-link", "/nix/var/nix/profiles/default")
+    }
+
+    #[tokio::test]
+    async fn healthy_chain_resolves() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        default_profile(&sandbox)?;
+
+        let store_path = sandbox.scope(verify_default_profile()).await?;
+        assert_eq!(store_path, PathBuf::from(ENVIRONMENT));
+        assert!(sandbox
+            .scope(dangling_default_profile_links())
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broken_chain_is_reported() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        default_profile(&sandbox)?;
+        std::fs::remove_dir_all(sandbox.path(ENVIRONMENT))?;
+
+        let err = sandbox.scope(verify_default_profile()).await.unwrap_err();
+        assert!(
+            matches!(&err, DefaultProfileError::Dangling { link, target } if link == Path::new("/nix/var/nix/profiles/default-1-link") && target == Path::new(ENVIRONMENT)),
+            "{err:?}"
+        );
+        assert_eq!(
+            sandbox.scope(dangling_default_profile_links()).await?,
+            vec![PathBuf::from("/nix/var/nix/profiles/default-1-link")]
+        );
+
+        // A store path without `nix` is just as unusable
+        std::fs::create_dir_all(sandbox.path(ENVIRONMENT))?;
+        let err = sandbox.scope(verify_default_profile()).await.unwrap_err();
+        assert!(matches!(err, DefaultProfileError::MissingNix(_)), "{err:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn profiles_with_dead_references_are_sidelined() -> eyre::Result<()> {
+        const CHANNELS: &str = "/nix/store/eeee-user-environment";
+        let sandbox = SandboxContext::new()?;
+        // A restored `/nix` where the channels' manifest was garbage collected
+        std::fs::create_dir_all(sandbox.path(CHANNELS))?;
+        std::fs::create_dir_all(sandbox.path("/nix/store/gggg-nixpkgs"))?;
+        std::os::unix::fs::symlink(
+            "/nix/store/ffff-env-manifest.nix",
+            sandbox.path(CHANNELS).join("manifest.nix"),
+        )?;
+        std::os::unix::fs::symlink(
+            "/nix/store/gggg-nixpkgs",
+            sandbox.path(CHANNELS).join("nixpkgs"),
+        )?;
+        std::fs::create_dir_all(sandbox.path("/nix/var/nix/profiles/per-user/root"))?;
+        std::os::unix::fs::symlink(
+            CHANNELS,
+            sandbox.path("/nix/var/nix/profiles/per-user/root/channels-1-link"),
+        )?;
+        std::os::unix::fs::symlink(
+            "/nix/store/hhhh-user-environment",
+            sandbox.path("/nix/var/nix/profiles/per-user/root/channels-2-link"),
+        )?;
+        std::os::unix::fs::symlink("channels-1-link", sandbox.path(ROOT_CHANNELS_PROFILE))?;
+        // Not one of the profile's links
+        std::fs::write(
+            sandbox.path("/nix/var/nix/profiles/per-user/root/channels-old"),
+            "",
+        )?;
+
+        let profile = Path::new(ROOT_CHANNELS_PROFILE);
+        assert_eq!(
+            sandbox.scope(dead_profile_references(profile)).await?,
+            vec![
+                PathBuf::from("/nix/store/ffff-env-manifest.nix"),
+                PathBuf::from("/nix/store/hhhh-user-environment"),
+            ]
+        );
+        assert_eq!(
+            sandbox.scope(current_generation(profile)).await,
+            Some(PathBuf::from(CHANNELS))
+        );
+        // The default profile is healthy, and missing profiles have nothing to sideline
+        default_profile(&sandbox)?;
+        assert!(sandbox
+            .scope(dead_profile_references(Path::new(DEFAULT_PROFILE)))
+            .await?
+            .is_empty());
+        assert!(sandbox
+            .scope(sideline_profile(Path::new("/nix/var/nix/profiles/absent")))
+            .await?
+            .is_none());
+
+        let sidelined = sandbox.scope(sideline_profile(profile)).await?;
+        assert_eq!(
+            sidelined,
+            Some(SidelinedProfile {
+                profile: profile.to_path_buf(),
+                backup: PathBuf::from("/nix/var/nix/profiles/sidelined/channels"),
+            })
+        );
+        assert!(!sandbox.path(ROOT_CHANNELS_PROFILE).is_symlink());
+        assert!(sandbox
+            .path("/nix/var/nix/profiles/per-user/root/channels-old")
+            .exists());
+        assert_eq!(
+            std::fs::read_link(sandbox.path("/nix/var/nix/profiles/sidelined/channels/channels"))?,
+            PathBuf::from("channels-1-link")
+        );
+
+        // A fresh profile is started in its place, then replaced by the original on revert
+        std::os::unix::fs::symlink(
+            ENVIRONMENT,
+            sandbox.path("/nix/var/nix/profiles/per-user/root/channels-1-link"),
+        )?;
+        std::os::unix::fs::symlink("channels-1-link", sandbox.path(ROOT_CHANNELS_PROFILE))?;
+        let sidelined = sidelined.expect("Profile was sidelined");
+        sandbox.scope(restore_sidelined_profile(&sidelined)).await?;
+        assert_eq!(
+            std::fs::read_link(
+                sandbox.path("/nix/var/nix/profiles/per-user/root/channels-1-link")
+            )?,
+            PathBuf::from(CHANNELS)
+        );
+        assert!(sandbox
+            .path("/nix/var/nix/profiles/per-user/root/channels-2-link")
+            .is_symlink());
+        assert!(!sandbox
+            .path("/nix/var/nix/profiles/sidelined/channels")
+            .exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiple_candidates_require_a_choice() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        store_path_with_nix(&sandbox, NIX)?;
+
+        let chosen = sandbox
+            .scope(async { choose_nix_store_path(None, None) })
+            .await?;
+        assert_eq!(chosen, PathBuf::from(NIX));
+
+        store_path_with_nix(&sandbox, OTHER_NIX)?;
+        let err = sandbox
+            .scope(async { choose_nix_store_path(None, None) })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, DefaultProfileError::AmbiguousCandidates(candidates) if candidates.len() == 2),
+            "{err:?}"
+        );
+
+        let chosen = sandbox
+            .scope(async { choose_nix_store_path(Some(Path::new(OTHER_NIX)), None) })
+            .await?;
+        assert_eq!(chosen, PathBuf::from(OTHER_NIX));
+        let chosen = sandbox
+            .scope(async { choose_nix_store_path(None, Some(Path::new(NIX))) })
+            .await?;
+        assert_eq!(chosen, PathBuf::from(NIX));
+
+        let err = sandbox
+            .scope(async {
+                choose_nix_store_path(Some(Path::new("/nix/store/dddd-nix-2.26.0")), None)
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, DefaultProfileError::UnusableStorePath(_)),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn recorded_store_paths_are_found_in_receipts() {
+        let receipt = serde_json::json!({
+            "version": "0.0.0",
+            "actions": [
+                { "action": { "action_name": "create_directory" }, "state": "Completed" },
+                { "action": {
+                    "action_name": "configure_nix",
+                    "setup_default_profile": { "action": {
+                        "action_name": "setup_default_profile",
+                        "unpacked_path": "/nix/temp-install-dir",
+                        "nix_store_path": NIX,
+                    }, "state": "Completed" },
+                }, "state": "Completed" },
+            ],
+        });
+        assert_eq!(
+            RecordedStorePaths::from_receipt(&receipt),
+            RecordedStorePaths {
+                nix: Some(PathBuf::from(NIX)),
+                nss_ca_cert: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn colliding_packages_are_named() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        store_path_with_nix(&sandbox, NIX)?;
+        store_path_with_nix(&sandbox, OTHER_NIX)?;
+        let cachix = sandbox.path("/nix/store/dddd-cachix-1.7.4/bin");
+        std::fs::create_dir_all(&cachix)?;
+        std::fs::write(cachix.join("cachix"), "")?;
+        // Profiles link a whole directory when one package provides it, targets are left unmapped
+        std::fs::create_dir_all(sandbox.path(ENVIRONMENT))?;
+        std::os::unix::fs::symlink(format!("{NIX}/bin"), sandbox.path(ENVIRONMENT).join("bin"))?;
+
+        let package = |name: &str, store_path: &str| (name.to_string(), PathBuf::from(store_path));
+        sandbox
+            .scope(async {
+                validate_paths_can_cohabitate(&[
+                    package("the default profile", ENVIRONMENT),
+                    package("cachix", "/nix/store/dddd-cachix-1.7.4"),
+                ])
+            })
+            .await?;
+        // The profile links the very file `nix` provides
+        sandbox
+            .scope(async {
+                validate_paths_can_cohabitate(&[
+                    package("the default profile", ENVIRONMENT),
+                    package("nix", NIX),
+                ])
+            })
+            .await?;
+
+        let err = sandbox
+            .scope(async {
+                validate_paths_can_cohabitate(&[
+                    package("the default profile", ENVIRONMENT),
+                    package("cachix", "/nix/store/dddd-cachix-1.7.4"),
+                    package("nixpkgs#nix", OTHER_NIX),
+                ])
+            })
+            .await
+            .unwrap_err();
+        match err {
+            DefaultProfileError::Collision {
+                file,
+                first,
+                second,
+            } => {
+                assert_eq!(file, PathBuf::from("bin/nix"));
+                assert_eq!(first, "the default profile");
+                assert_eq!(second, "nixpkgs#nix");
+            },
+            err => panic!("Expected a collision, got {err:?}"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+/*! Replacing the content of a file so it is never seen half written
+
+The usual way is to write a temporary file next to the destination and rename it over the
+destination, which is atomic. Some hosts can't do that rename: an `/etc/nix/nix.conf`
+bind mounted into a container, or an overlayfs or NFS `/etc`, fail it with `EXDEV`, `EINVAL`, or
+`EBUSY`, and a read-only directory holding a writable file can't hold the temporary file at all.
+There, the destination is truncated and rewritten in place instead. A copy of the original is
+kept in the system temporary directory until that finishes, and put back if it fails.
+
+Which of the two [`ReplaceStrategy`]s was used is logged.
+*/
+
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    unistd::{chown, Gid, Uid},
+};
+use rand::Rng;
+use tokio::io::AsyncWriteExt;
+
+use crate::{action::ActionErrorKind, util::OnMissing};
+
+/// How [`replace_file`] put the new content in place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReplaceStrategy {
+    /// A temporary file next to the destination was renamed over it
+    Rename,
+    /// The destination was truncated and rewritten, as the rename was not possible
+    InPlace,
+}
+
+/// The mode and owner [`replace_file`] gives the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Attributes {
+    pub(crate) mode: u32,
+    /// Left as the user running `nix-installer` if `None`
+    pub(crate) uid: Option<Uid>,
+    /// Left as the group running `nix-installer` if `None`
+    pub(crate) gid: Option<Gid>,
+}
+
+impl Attributes {
+    pub(crate) fn mode(mode: u32) -> Self {
+        Self {
+            mode,
+            uid: None,
+            gid: None,
+        }
+    }
+}
+
+/// Replace the content of `path` with `content`, creating it if it does not exist
+#[tracing::instrument(level = "debug", skip_all, fields(path = %path.display()))]
+pub(crate) async fn replace_file(
+    path: &Path,
+    content: &[u8],
+    attributes: Attributes,
+) -> Result<ReplaceStrategy, ReplaceFileError> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(
+        ".{file_name}.nix-installer-tmp.{}",
+        rand::thread_rng().gen::<u32>()
+    ));
+
+    match write_temp(&temp, content, attributes).await {
+        Ok(()) => match rename(&temp, path).await {
+            Ok(()) => {
+                tracing::debug!(strategy = ?ReplaceStrategy::Rename, "Replaced `{}`", path.display());
+                return Ok(ReplaceStrategy::Rename);
+            },
+            Err(e) if rename_unsupported(&e) => {
+                remove_temp(&temp).await;
+                tracing::warn!(
+                    "Renaming `{}` over `{}` is not supported here ({e}), rewriting it in place",
+                    temp.display(),
+                    path.display()
+                );
+            },
+            Err(e) => {
+                remove_temp(&temp).await;
+                return Err(ReplaceFileError::Rename(temp, path.to_path_buf(), e));
+            },
+        },
+        Err(ReplaceFileError::Stage(_, e)) if directory_unwritable(&e) => {
+            tracing::warn!(
+                "`{}` cannot hold a temporary file ({e}), rewriting `{}` in place",
+                temp.parent().unwrap_or(Path::new("/")).display(),
+                path.display()
+            );
+        },
+        Err(e) => {
+            remove_temp(&temp).await;
+            return Err(e);
+        },
+    }
+
+    write_in_place(path, content, attributes).await?;
+    tracing::debug!(strategy = ?ReplaceStrategy::InPlace, "Replaced `{}`", path.display());
+    Ok(ReplaceStrategy::InPlace)
+}
+
+/// Write `content` to the new file `temp`, with `attributes`
+async fn write_temp(
+    temp: &Path,
+    content: &[u8],
+    attributes: Attributes,
+) -> Result<(), ReplaceFileError> {
+    // Owner only until the owner and mode are set, in case it is a secret or will become setuid
+    let mut file = tokio::fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(temp)
+        .await
+        .map_err(|e| ReplaceFileError::Stage(temp.to_path_buf(), e))?;
+    write_and_sync(&mut file, temp, content).await?;
+    drop(file);
+    set_attributes(temp, attributes).await
+}
+
+/// Truncate and rewrite `path`, putting the original back if that fails
+async fn write_in_place(
+    path: &Path,
+    content: &[u8],
+    attributes: Attributes,
+) -> Result<(), ReplaceFileError> {
+    let original = match path.exists() {
+        true => Some(copy_original(path).await?),
+        false => None,
+    };
+
+    let written = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .await
+            .map_err(|e| ReplaceFileError::Write(path.to_path_buf(), e))?;
+        write_and_sync(&mut file, path, content).await?;
+        drop(file);
+        set_attributes(path, attributes).await
+    }
+    .await;
+
+    match (written, original) {
+        (Ok(()), Some(original)) => {
+            remove_temp(&original).await;
+            Ok(())
+        },
+        (Ok(()), None) => Ok(()),
+        (Err(e), Some(original)) => match tokio::fs::copy(&original, path).await {
+            Ok(_) => {
+                remove_temp(&original).await;
+                Err(e)
+            },
+            Err(restore_error) => Err(ReplaceFileError::Restore {
+                path: path.to_path_buf(),
+                original,
+                write_error: Box::new(e),
+                restore_error,
+            }),
+        },
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// Copy `path` into the system temporary directory, preserving its mode
+async fn copy_original(path: &Path) -> Result<PathBuf, ReplaceFileError> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let original = std::env::temp_dir().join(format!(
+        "nix-installer-original.{file_name}.{}",
+        rand::thread_rng().gen::<u32>()
+    ));
+    tokio::fs::copy(path, &original)
+        .await
+        .map_err(|e| ReplaceFileError::CopyOriginal(path.to_path_buf(), original.clone(), e))?;
+    async { tokio::fs::File::open(&original).await?.sync_all().await }
+        .await
+        .map_err(|e| ReplaceFileError::CopyOriginal(path.to_path_buf(), original.clone(), e))?;
+    Ok(original)
+}
+
+async fn write_and_sync(
+    file: &mut tokio::fs::File,
+    path: &Path,
+    content: &[u8],
+) -> Result<(), ReplaceFileError> {
+    file.write_all(content)
+        .await
+        .map_err(|e| ReplaceFileError::Write(path.to_path_buf(), e))?;
+    file.sync_all()
+        .await
+        .map_err(|e| ReplaceFileError::Write(path.to_path_buf(), e))
+}
+
+async fn set_attributes(path: &Path, attributes: Attributes) -> Result<(), ReplaceFileError> {
+    if attributes.uid.is_some() || attributes.gid.is_some() {
+        chown(path, attributes.uid, attributes.gid)
+            .map_err(|e| ReplaceFileError::Chown(path.to_path_buf(), e))?;
+    }
+    // After `chown`, which clears the setuid and setgid bits
+    tokio::fs::set_permissions(path, PermissionsExt::from_mode(attributes.mode))
+        .await
+        .map_err(|e| ReplaceFileError::SetPermissions(attributes.mode, path.to_path_buf(), e))
+}
+
+async fn rename(from: &Path, to: &Path) -> std::io::Result<()> {
+    #[cfg(any(test, feature = "test-harness"))]
+    if let Some(errno) = crate::test_harness::intercept_rename() {
+        return Err(std::io::Error::from_raw_os_error(errno as i32));
+    }
+    tokio::fs::rename(from, to).await
+}
+
+async fn remove_temp(temp: &Path) {
+    if let Err(e) = crate::util::remove_file(temp, OnMissing::Ignore).await {
+        tracing::debug!(%e, "Could not remove `{}`", temp.display());
+    }
+}
+
+/// If the rename failed because the host cannot rename over the destination, rather than because
+/// of the files themselves
+fn rename_unsupported(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error().map(Errno::from_raw),
+        Some(Errno::EXDEV | Errno::EINVAL | Errno::EBUSY)
+    )
+}
+
+/// If the temporary file could not be created because its directory does not allow it
+fn directory_unwritable(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error().map(Errno::from_raw),
+        Some(Errno::EROFS | Errno::EACCES | Errno::EPERM)
+    )
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum ReplaceFileError {
+    #[error("Creating the temporary file `{0}`")]
+    Stage(PathBuf, #[source] std::io::Error),
+    #[error("Writing `{0}`")]
+    Write(PathBuf, #[source] std::io::Error),
+    #[error("Setting the owner of `{0}`")]
+    Chown(PathBuf, #[source] Errno),
+    #[error("Setting the mode of `{1}` to `{0:#o}`")]
+    SetPermissions(u32, PathBuf, #[source] std::io::Error),
+    #[error("Renaming `{0}` to `{1}`")]
+    Rename(PathBuf, PathBuf, #[source] std::io::Error),
+    #[error("Copying `{0}` to `{1}` before rewriting it in place")]
+    CopyOriginal(PathBuf, PathBuf, #[source] std::io::Error),
+    #[error("Rewriting `{path}` in place failed ({write_error}), and putting back the original failed too, it is kept at `{original}`")]
+    Restore {
+        path: PathBuf,
+        original: PathBuf,
+        write_error: Box<ReplaceFileError>,
+        #[source]
+        restore_error: std::io::Error,
+    },
+}
+
+impl From<ReplaceFileError> for ActionErrorKind {
+    fn from(val: ReplaceFileError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use nix::errno::Errno;
+
+    use super::{replace_file, Attributes, ReplaceStrategy};
+    use crate::test_harness::SandboxContext;
+
+    #[tokio::test]
+    async fn renames_into_place() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let path = sandbox.path("/etc/nix.conf");
+        std::fs::write(&path, "old")?;
+
+        let strategy = sandbox
+            .scope(replace_file(&path, b"new", Attributes::mode(0o640)))
+            .await?;
+
+        assert_eq!(strategy, ReplaceStrategy::Rename);
+        assert_eq!(std::fs::read_to_string(&path)?, "new");
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o640
+        );
+        assert_eq!(std::fs::read_dir(sandbox.path("/etc"))?.count(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rewrites_in_place_across_devices() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fail_renames(Errno::EXDEV);
+        let path = sandbox.path("/etc/nix.conf");
+        std::fs::write(&path, "old")?;
+
+        let strategy = sandbox
+            .scope(replace_file(&path, b"new", Attributes::mode(0o600)))
+            .await?;
+
+        assert_eq!(strategy, ReplaceStrategy::InPlace);
+        assert_eq!(std::fs::read_to_string(&path)?, "new");
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o600
+        );
+        // No temporary file is left behind
+        let mut entries = std::fs::read_dir(sandbox.path("/etc"))?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec!["nix.conf", "profile.d", "systemd", "tmpfiles.d"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn other_rename_failures_are_errors() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fail_renames(Errno::EIO);
+        let path = sandbox.path("/etc/nix.conf");
+        std::fs::write(&path, "old")?;
+
+        let err = sandbox
+            .scope(replace_file(&path, b"new", Attributes::mode(0o644)))
+            .await
+            .expect_err("Rename failed");
+
+        assert!(
+            matches!(err, super::ReplaceFileError::Rename(..)),
+            "{err:?}"
+        );
+        assert_eq!(std::fs::read_to_string(&path)?, "old");
+        Ok(())
+    }
+}
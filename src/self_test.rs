@@ -1,8 +1,19 @@
-use std::{process::Output, time::SystemTime};
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Output,
+    time::SystemTime,
+};
 
 use tokio::process::Command;
 use which::which;
 
+use crate::util::host_path;
+
+pub use crate::action::base::VerifyStoreError;
+pub use crate::daemon_socket::DaemonSocketError;
+pub use crate::profile::DefaultProfileError;
+
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
 pub enum SelfTestError {
@@ -12,6 +23,13 @@ pub enum SelfTestError {
         command: String,
         output: Output,
     },
+    /// The login environment of a shell leaves the per-user profile off `$PATH`
+    #[error("The `{shell}` login environment does not put `{}` on `$PATH` (`{path}`), check the Nix hook in its profile or run `nix-installer repair hooks`", profile.join("bin").display())]
+    ProfileNotOnPath {
+        shell: Shell,
+        profile: PathBuf,
+        path: String,
+    },
     /// Failed to execute command
     #[error("Failed to execute command `{command}`",
         command = .command,
@@ -24,6 +42,61 @@ pub enum SelfTestError {
     },
     #[error(transparent)]
     SystemTime(#[from] std::time::SystemTimeError),
+    /// The default profile does not resolve to a store path providing `nix`
+    #[error(transparent)]
+    DefaultProfile(#[from] DefaultProfileError),
+    /// The daemon listens somewhere other than where `nix` connects, or in more than one place
+    #[error(transparent)]
+    DaemonSocket(#[from] DaemonSocketError),
+    /// SELinux is enforcing, and the daemon is labelled so `systemd` may not start it
+    #[error("`{}` is labelled `{context}` instead of `{SELINUX_DAEMON_TYPE}`, so the Nix daemon cannot be started while SELinux is enforcing, relabel it with `restorecon -FR /nix`", path.display())]
+    SelinuxLabel { path: PathBuf, context: String },
+    /// The daemon was configured to run as a non-root user, but builds as someone else
+    #[error("The Nix daemon was configured to run as `{user}` (UID {expected}), but `{}` was built by UID {actual}", path.display())]
+    DaemonUser {
+        user: String,
+        expected: u32,
+        actual: u32,
+        path: PathBuf,
+    },
+    /// The daemon was configured to run as a non-root user, but could not build
+    #[error("The Nix daemon was configured to run as `{user}`, but `{command}` failed: {reason}")]
+    DaemonUserBuildFailed {
+        user: String,
+        command: String,
+        reason: String,
+    },
+    /// The daemon was configured with a niceness, but runs with another
+    #[error("The Nix daemon was configured to run with a niceness of {expected}, but `{command}` (PID {pid}) runs with {actual}, restart it to pick up its service")]
+    DaemonNice {
+        expected: i8,
+        actual: i32,
+        pid: u32,
+        command: String,
+    },
+    /// Nix could not build a derivation through the daemon
+    #[error("Building through the Nix daemon with `{command}` failed: {reason}")]
+    DaemonBuild { command: String, reason: String },
+    /// Nix could not read a flake, such as when the `nix-command` and `flakes` experimental features are not enabled
+    #[error("Reading a flake with `{command}` failed, check that the `nix-command` and `flakes` experimental features are enabled: {reason}")]
+    Flake { command: String, reason: String },
+    /// The user who ran `sudo` cannot connect to the daemon
+    #[error("User `{user}` (UID {uid}) cannot connect to the Nix daemon with `{command}`, check the permissions of `/nix/var/nix/daemon-socket`: {reason}")]
+    DaemonSocketAccess {
+        user: String,
+        uid: u32,
+        command: String,
+        reason: String,
+    },
+    /// The contents of the store do not match their hashes, or could not be verified
+    #[error(transparent)]
+    StoreContents(#[from] VerifyStoreError),
+    /// The Nix volume is mounted by a UUID it no longer has, such as after restoring from a backup
+    #[error("The Nix volume `{label}` is mounted by the UUID {stale}, so `/nix` is not mounted at boot, run `sudo nix-installer repair volume-mount`")]
+    StaleVolumeUuid {
+        label: String,
+        stale: crate::action::macos::repair_volume_mount::StaleVolumeUuids,
+    },
 }
 
 #[cfg(feature = "diagnostics")]
@@ -32,8 +105,26 @@ impl crate::diagnostics::ErrorDiagnostic for SelfTestError {
         let static_str: &'static str = (self).into();
         let context = match self {
             Self::ShellFailed { shell, .. } => vec![shell.to_string()],
+            Self::ProfileNotOnPath { shell, .. } => vec![shell.to_string()],
             Self::Command { shell, .. } => vec![shell.to_string()],
             Self::SystemTime(_) => vec![],
+            Self::DefaultProfile(err) => {
+                let static_str: &'static str = err.into();
+                vec![static_str.to_string()]
+            },
+            Self::DaemonSocket(err) => {
+                let static_str: &'static str = err.into();
+                vec![static_str.to_string()]
+            },
+            Self::SelinuxLabel { .. } => vec![],
+            Self::DaemonUser { .. } => vec![],
+            Self::DaemonUserBuildFailed { .. } => vec![],
+            Self::DaemonNice { .. } => vec![],
+            Self::StaleVolumeUuid { .. } => vec![],
+            Self::DaemonBuild { .. } => vec![],
+            Self::Flake { .. } => vec![],
+            Self::DaemonSocketAccess { .. } => vec![],
+            Self::StoreContents(_) => vec![],
         };
         format!(
             "{}({})",
@@ -74,31 +165,21 @@ impl Shell {
         }
     }
 
-    #[tracing::instrument(skip_all)]
-    pub async fn self_test(&self) -> Result<(), SelfTestError> {
-        let executable = self.executable();
-        let mut command = match &self {
+    /// A command running the shell as a terminal would, loading its profiles
+    fn command(&self) -> Command {
+        let mut command = Command::new(self.executable());
+        match &self {
             // On Mac, `bash -ic nix` won't work, but `bash -lc nix` will.
-            Shell::Sh | Shell::Bash => {
-                let mut command = Command::new(executable);
-                command.arg("-lc");
-                command
-            },
-            Shell::Zsh | Shell::Fish => {
-                let mut command = Command::new(executable);
-                command.arg("-ic");
-                command
-            },
+            Shell::Sh | Shell::Bash => command.arg("-lc"),
+            Shell::Zsh | Shell::Fish => command.arg("-ic"),
         };
+        command
+    }
 
-        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-        const SYSTEM: &str = "x86_64-linux";
-        #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
-        const SYSTEM: &str = "aarch64-linux";
-        #[cfg(all(target_os = "macos", target_arch = "x86_64"))]
-        const SYSTEM: &str = "x86_64-darwin";
-        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
-        const SYSTEM: &str = "aarch64-darwin";
+    #[tracing::instrument(skip_all)]
+    pub async fn self_test(&self) -> Result<(), SelfTestError> {
+        let executable = self.executable();
+        let mut command = self.command();
 
         let timestamp_millis = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
@@ -133,6 +214,61 @@ impl Shell {
         }
     }
 
+    /// Check the per-user profile Nix uses is on the `$PATH` of the shell, in either layout
+    #[tracing::instrument(skip_all)]
+    pub async fn verify_profile_path(&self) -> Result<(), SelfTestError> {
+        let Some(home) = std::env::var_os("HOME") else {
+            return Ok(());
+        };
+        let xdg_state_home = std::env::var_os("XDG_STATE_HOME").map(PathBuf::from);
+        self.verify_profile_path_in(Path::new(&home), xdg_state_home.as_deref())
+            .await
+    }
+
+    async fn verify_profile_path_in(
+        &self,
+        home: &Path,
+        xdg_state_home: Option<&Path>,
+    ) -> Result<(), SelfTestError> {
+        let Some(profile) = active_user_profile(home, xdg_state_home) else {
+            tracing::debug!("No per-user profile exists yet, so `$PATH` is not checked");
+            return Ok(());
+        };
+        let mut command = self.command();
+        command.arg(match self {
+            Shell::Fish => "string join : $PATH",
+            Shell::Sh | Shell::Bash | Shell::Zsh => r#"printf '%s\n' "$PATH""#,
+        });
+        let command_str = format!("{:?}", command.as_std());
+        let output =
+            crate::command_output(&mut command)
+                .await
+                .map_err(|error| SelfTestError::Command {
+                    shell: *self,
+                    command: command_str.clone(),
+                    error,
+                })?;
+        if !output.status.success() {
+            return Err(SelfTestError::ShellFailed {
+                shell: *self,
+                command: command_str,
+                output,
+            });
+        }
+        // Interactive shells may print a greeting first
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let path = stdout.lines().last().unwrap_or_default();
+        let bin = profile.join("bin");
+        match path.split(':').any(|entry| Path::new(entry) == bin) {
+            true => Ok(()),
+            false => Err(SelfTestError::ProfileNotOnPath {
+                shell: *self,
+                profile,
+                path: path.to_string(),
+            }),
+        }
+    }
+
     #[tracing::instrument(skip_all)]
     pub fn discover() -> Vec<Shell> {
         let mut found_shells = vec![];
@@ -146,22 +282,702 @@ impl Shell {
     }
 }
 
+/// The per-user profile Nix uses in `home`, if there is one: the one under `$XDG_STATE_HOME` once it
+/// exists, as `use-xdg-base-directories` moves it there, `~/.nix-profile` otherwise
+fn active_user_profile(home: &Path, xdg_state_home: Option<&Path>) -> Option<PathBuf> {
+    let [legacy, xdg] =
+        crate::action::common::configure_shell_profile::user_profiles(home, xdg_state_home);
+    [xdg, legacy]
+        .into_iter()
+        .find(|profile| host_path(profile).exists())
+}
+
+/// The Nix system this `nix-installer` was built for
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub(crate) const SYSTEM: &str = "x86_64-linux";
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+pub(crate) const SYSTEM: &str = "aarch64-linux";
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+pub(crate) const SYSTEM: &str = "x86_64-darwin";
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub(crate) const SYSTEM: &str = "aarch64-darwin";
+
+/// The `nix` of the default profile
+const NIX: &str = "/nix/var/nix/profiles/default/bin/nix";
+
+/// The outcome of one of the checks of [`self_test_report`]
+#[derive(Debug)]
+pub struct SelfTestCheck {
+    /// What was checked, like `flakes` or `shell zsh`
+    pub name: String,
+    pub result: Result<(), SelfTestError>,
+}
+
+impl SelfTestCheck {
+    fn new(name: impl Into<String>, result: Result<(), SelfTestError>) -> Self {
+        Self {
+            name: name.into(),
+            result,
+        }
+    }
+}
+
 #[tracing::instrument(skip_all)]
 pub async fn self_test() -> Result<(), Vec<SelfTestError>> {
+    let failures = self_test_report()
+        .await
+        .into_iter()
+        .filter_map(|check| check.result.err())
+        .collect::<Vec<_>>();
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+/// Run every check of the install, returning each outcome
+#[tracing::instrument(skip_all)]
+pub async fn self_test_report() -> Vec<SelfTestCheck> {
     let shells = Shell::discover();
 
-    let mut failures = vec![];
+    // Without a working default profile, the shell tests below can only report that `nix` is missing
+    let mut checks = vec![
+        SelfTestCheck::new(
+            "default profile",
+            crate::profile::verify_default_profile()
+                .await
+                .map(|_| ())
+                .map_err(Into::into),
+        ),
+        SelfTestCheck::new(
+            "daemon socket",
+            crate::daemon_socket::verify_daemon_socket()
+                .await
+                .map(|_| ())
+                .map_err(Into::into),
+        ),
+        SelfTestCheck::new("SELinux labels", verify_selinux_labels().await),
+        SelfTestCheck::new("daemon user", verify_daemon_user().await),
+        SelfTestCheck::new("volume mount", verify_volume_mount().await),
+        SelfTestCheck::new("daemon build", verify_daemon_build().await),
+        SelfTestCheck::new("flakes", verify_flakes().await),
+        // After the daemon build, so a socket activated daemon is running
+        SelfTestCheck::new("daemon nice", verify_daemon_nice().await),
+    ];
+
+    if let Some(user) = sudo_user() {
+        checks.push(SelfTestCheck::new(
+            format!("daemon access as `{}`", user.name),
+            verify_daemon_socket_access(&user).await,
+        ));
+    }
 
     for shell in shells {
-        match shell.self_test().await {
-            Ok(()) => (),
-            Err(err) => failures.push(err),
+        checks.push(SelfTestCheck::new(
+            format!("shell {shell}"),
+            shell.self_test().await,
+        ));
+        checks.push(SelfTestCheck::new(
+            format!("shell {shell} PATH"),
+            shell.verify_profile_path().await,
+        ));
+    }
+
+    checks
+}
+
+/// Verify the contents of every store path, with the command of the Nix the receipt installed
+///
+/// Reads the whole store, so only `nix-installer self-test --verify-store` runs it.
+pub async fn verify_store_contents() -> SelfTestCheck {
+    let receipt = crate::plan::receipt_reader(host_path(crate::plan::RECEIPT_LOCATION))
+        .ok()
+        .and_then(|reader| serde_json::from_reader::<_, serde_json::Value>(reader).ok());
+    SelfTestCheck::new(
+        "store contents",
+        crate::action::base::verify_store::verify_installed_store(receipt.as_ref())
+            .await
+            .into_result()
+            .map_err(Into::into),
+    )
+}
+
+/// Run `command`, returning why it failed if it did not succeed
+async fn successful_output(command: &mut Command) -> Result<Output, String> {
+    let output = crate::command_output(command)
+        .await
+        .map_err(|e| e.to_string())?;
+    match output.status.success() {
+        true => Ok(output),
+        false => Err(format!(
+            "stderr:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )),
+    }
+}
+
+/// Check that the daemon builds, rather than only that `nix` runs
+///
+/// Nothing is substituted, so no network is needed.
+async fn verify_daemon_build() -> Result<(), SelfTestError> {
+    let timestamp_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    let mut command = Command::new(NIX);
+    command
+        .args([
+            "build",
+            "--store",
+            "daemon",
+            "--option",
+            "substitute",
+            "false",
+            "--no-link",
+            "--expr",
+        ])
+        .arg(format!(
+            r#"derivation {{ name = "self-test-daemon-{timestamp_millis}"; system = "{SYSTEM}"; builder = "/bin/sh"; args = ["-c" "echo hello > $out"]; }}"#
+        ))
+        .stdin(std::process::Stdio::null())
+        .process_group(0);
+    let command_str = format!("{:?}", command.as_std());
+    successful_output(&mut command)
+        .await
+        .map_err(|reason| SelfTestError::DaemonBuild {
+            command: command_str,
+            reason,
+        })?;
+    Ok(())
+}
+
+/// Check that `nix` reads flakes, which needs the `nix-command` and `flakes` experimental features
+///
+/// A flake without inputs is written to a temporary directory, so no network is needed.
+async fn verify_flakes() -> Result<(), SelfTestError> {
+    let timestamp_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    let dir = std::env::temp_dir().join(format!("nix-installer-self-test-{timestamp_millis}"));
+    let mut command = Command::new(NIX);
+    command
+        .args(["flake", "metadata", "--offline", "--no-write-lock-file"])
+        .arg(format!("path:{}", dir.display()))
+        .stdin(std::process::Stdio::null())
+        .process_group(0);
+    let command_str = format!("{:?}", command.as_std());
+    let failed = |reason: String| SelfTestError::Flake {
+        command: command_str.clone(),
+        reason,
+    };
+
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| failed(format!("creating `{}`: {e}", dir.display())))?;
+    let result =
+        match tokio::fs::write(dir.join("flake.nix"), "{ outputs = { self }: { }; }\n").await {
+            Ok(()) => successful_output(&mut command).await.map(|_| ()),
+            Err(e) => Err(format!(
+                "writing `{}`: {e}",
+                dir.join("flake.nix").display()
+            )),
+        };
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        tracing::debug!("Removing `{}`: {e}", dir.display());
+    }
+    result.map_err(failed)
+}
+
+/// The user who ran `nix-installer` with `sudo`, if it did
+fn sudo_user() -> Option<nix::unistd::User> {
+    if !nix::unistd::Uid::effective().is_root() {
+        return None;
+    }
+    let uid = std::env::var("SUDO_UID").ok()?.parse::<u32>().ok()?;
+    if uid == 0 {
+        return None;
+    }
+    nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid))
+        .ok()
+        .flatten()
+}
+
+/// Check that `user` can connect to the daemon, not only `root`
+///
+/// `root` may use the store directly, so the daemon socket being unreachable for anyone else, such
+/// as after its directory lost its permissions, otherwise only shows up once `sudo` is dropped.
+async fn verify_daemon_socket_access(user: &nix::unistd::User) -> Result<(), SelfTestError> {
+    let mut command = Command::new(NIX);
+    command
+        .args(["store", "ping", "--store", "daemon"])
+        .env("HOME", &user.dir)
+        .uid(user.uid.as_raw())
+        .gid(user.gid.as_raw())
+        .stdin(std::process::Stdio::null())
+        .process_group(0);
+    let command_str = format!("{:?}", command.as_std());
+    successful_output(&mut command)
+        .await
+        .map_err(|reason| SelfTestError::DaemonSocketAccess {
+            user: user.name.clone(),
+            uid: user.uid.as_raw(),
+            command: command_str,
+            reason,
+        })?;
+    Ok(())
+}
+
+/// The SELinux type the daemon must have for `systemd` (as `init_t`) to execute it
+const SELINUX_DAEMON_TYPE: &str = "bin_t";
+
+/// Check that the daemon executables are labelled so `systemd` can start them, if SELinux is enforcing
+///
+/// An install which provisioned its policy (or file contexts) but was relabelled since, or a
+/// policy missing the rules, otherwise only shows up as a daemon which never starts.
+async fn verify_selinux_labels() -> Result<(), SelfTestError> {
+    let enforcing = tokio::fs::read_to_string(host_path("/sys/fs/selinux/enforce"))
+        .await
+        .is_ok_and(|v| v.trim() == "1");
+    if !enforcing {
+        return Ok(());
+    }
+
+    let mut daemons = vec![];
+    // A broken default profile is already reported by `verify_default_profile`
+    if let Ok(store_path) = crate::profile::verify_default_profile().await {
+        daemons.push(store_path.join("bin/nix-daemon"));
+    }
+    daemons.push(PathBuf::from("/usr/local/bin/determinate-nixd"));
+
+    for daemon in daemons {
+        if !host_path(&daemon).exists() {
+            continue;
+        }
+        let Some(context) = selinux_context(&daemon).await else {
+            continue;
+        };
+        if context.split(':').nth(2) != Some(SELINUX_DAEMON_TYPE) {
+            return Err(SelfTestError::SelinuxLabel {
+                path: daemon,
+                context,
+            });
         }
     }
+    Ok(())
+}
 
-    if failures.is_empty() {
+/// Check that builds run as the daemon user, if the receipt configured one
+///
+/// A daemon still running as `root`, such as one started from a unit written before the install,
+/// would otherwise go unnoticed, as builds succeed either way.
+async fn verify_daemon_user() -> Result<(), SelfTestError> {
+    let Ok(reader) = crate::plan::receipt_reader(host_path(crate::plan::RECEIPT_LOCATION)) else {
+        return Ok(());
+    };
+    let Some(user) = serde_json::from_reader(reader)
+        .ok()
+        .as_ref()
+        .and_then(crate::action::common::configure_daemon_user::daemon_user_of)
+    else {
+        return Ok(());
+    };
+
+    let timestamp_millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    let mut command = Command::new(NIX);
+    command
+        .args([
+            "build",
+            "--option",
+            "substitute",
+            "false",
+            "--no-link",
+            "--print-out-paths",
+            "--expr",
+        ])
+        .arg(format!(
+            r#"derivation {{ name = "self-test-daemon-user-{timestamp_millis}"; system = "{SYSTEM}"; builder = "/bin/sh"; args = ["-c" "echo hello > $out"]; }}"#
+        ))
+        .stdin(std::process::Stdio::null())
+        .process_group(0);
+    let command_str = format!("{:?}", command.as_std());
+    let failed = |reason: String| SelfTestError::DaemonUserBuildFailed {
+        user: user.name.clone(),
+        command: command_str.clone(),
+        reason,
+    };
+    let output = crate::command_output(&mut command)
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        return Err(failed(format!(
+            "stderr:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    let path = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let actual = tokio::fs::symlink_metadata(host_path(&path))
+        .await
+        .map_err(|e| failed(format!("reading `{}`: {e}", path.display())))?
+        .uid();
+    if actual != user.uid {
+        return Err(SelfTestError::DaemonUser {
+            user: user.name,
+            expected: user.uid,
+            actual,
+            path,
+        });
+    }
+    Ok(())
+}
+
+/// Check that the running Nix daemon has the niceness its service was configured with, if any
+///
+/// A daemon started before its service was written keeps running with the niceness it had, as do
+/// the builds it starts.
+async fn verify_daemon_nice() -> Result<(), SelfTestError> {
+    let Ok(reader) = crate::plan::receipt_reader(host_path(crate::plan::RECEIPT_LOCATION)) else {
+        return Ok(());
+    };
+    let Some(expected) = serde_json::from_reader(reader).ok().and_then(|receipt| {
+        crate::action::common::configure_init_service::daemon_resources_of(&receipt).nice
+    }) else {
+        return Ok(());
+    };
+
+    let mut command = Command::new("ps");
+    command
+        .args(["-A", "-o", "pid=,nice=,args="])
+        .stdin(std::process::Stdio::null());
+    // Without `ps`, there is nothing to compare against
+    let Ok(output) = successful_output(&mut command).await else {
+        return Ok(());
+    };
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(pid), Some(nice), Some(program)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let is_daemon = Path::new(program)
+            .file_name()
+            .is_some_and(|name| name == "nix-daemon" || name == "determinate-nixd");
+        let (Ok(pid), Ok(actual)) = (pid.parse::<u32>(), nice.parse::<i32>()) else {
+            continue;
+        };
+        if is_daemon && actual != i32::from(expected) {
+            return Err(SelfTestError::DaemonNice {
+                expected,
+                actual,
+                pid,
+                command: program.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check that the Nix volume is mounted by the UUID it has, if the receipt created one
+///
+/// Restoring a Mac from a backup gives the volume a new UUID, so nothing is mounted on `/nix` at
+/// boot, and every other check fails without saying why.
+async fn verify_volume_mount() -> Result<(), SelfTestError> {
+    let Ok(reader) = crate::plan::receipt_reader(host_path(crate::plan::RECEIPT_LOCATION)) else {
+        return Ok(());
+    };
+    let Some(volume) = serde_json::from_reader(reader)
+        .ok()
+        .as_ref()
+        .and_then(crate::action::macos::repair_volume_mount::nix_volume_of)
+    else {
+        return Ok(());
+    };
+    warn_near_volume_quota(&volume.label).await;
+
+    match crate::action::macos::repair_volume_mount::stale_volume_uuids(&volume).await {
+        Ok(stale) if stale.is_empty() => Ok(()),
+        Ok(stale) => Err(SelfTestError::StaleVolumeUuid {
+            label: volume.label,
+            stale,
+        }),
+        Err(e) => {
+            tracing::warn!(%e, "Could not check the UUID the Nix volume is mounted by");
+            Ok(())
+        },
+    }
+}
+
+/// Warn if the Nix volume labelled `label` uses most of its quota, before Nix starts failing to write to it
+async fn warn_near_volume_quota(label: &str) {
+    use crate::action::macos::create_apfs_volume::{apfs_volume, QuotaUsage};
+
+    match apfs_volume(label).await {
+        Ok(Some(volume)) => match QuotaUsage::of(&volume) {
+            Some(usage) if usage.nearly_full() => tracing::warn!(
+                "The Nix volume `{label}` uses {} of its {} quota ({}%), run `nix-collect-garbage` or raise the quota with `diskutil apfs setQuota`",
+                usage.in_use,
+                usage.quota,
+                usage.percent()
+            ),
+            _ => (),
+        },
+        Ok(None) => (),
+        Err(e) => tracing::debug!(%e, "Could not check the quota of the Nix volume"),
+    }
+}
+
+/// The SELinux context of `path`, following symlinks, like `system_u:object_r:bin_t:s0`
+async fn selinux_context(path: &Path) -> Option<String> {
+    let output = crate::command_output(
+        Command::new("stat")
+            .args(["--dereference", "--format", "%C"])
+            .arg(path)
+            .stdin(std::process::Stdio::null())
+            .process_group(0),
+    )
+    .await
+    .ok()?;
+    let context = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !context.is_empty()).then_some(context)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        verify_daemon_build, verify_daemon_nice, verify_daemon_socket_access, verify_daemon_user,
+        verify_flakes, verify_selinux_labels, SelfTestError, Shell,
+    };
+    use crate::test_harness::{FakeCommand, SandboxContext};
+
+    const ENVIRONMENT: &str = "/nix/store/cccc-user-environment";
+
+    #[tokio::test]
+    async fn daemon_must_be_labelled_when_enforcing() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path(ENVIRONMENT).join("bin"))?;
+        std::fs::write(sandbox.path(ENVIRONMENT).join("bin/nix"), "")?;
+        std::fs::write(sandbox.path(ENVIRONMENT).join("bin/nix-daemon"), "")?;
+        sandbox.symlink(ENVIRONMENT, "/nix/var/nix/profiles/default")?;
+        std::fs::create_dir_all(sandbox.path("/sys/fs/selinux"))?;
+        std::fs::write(sandbox.path("/sys/fs/selinux/enforce"), "1")?;
+
+        sandbox.fake(
+            "stat",
+            FakeCommand::success().stdout("system_u:object_r:bin_t:s0\n"),
+        );
+        sandbox.scope(verify_selinux_labels()).await?;
+
+        sandbox.fake(
+            "stat",
+            FakeCommand::success().stdout("unconfined_u:object_r:default_t:s0\n"),
+        );
+        let err = sandbox.scope(verify_selinux_labels()).await.unwrap_err();
+        assert!(
+            matches!(&err, SelfTestError::SelinuxLabel { context, .. } if context.contains("default_t")),
+            "{err:?}"
+        );
+
+        // Labels only matter while enforcing
+        std::fs::write(sandbox.path("/sys/fs/selinux/enforce"), "0")?;
+        sandbox.scope(verify_selinux_labels()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn builds_must_run_as_the_daemon_user() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let output = "/nix/store/dddd-self-test-daemon-user";
+        std::fs::create_dir_all(sandbox.path("/nix/store"))?;
+        std::fs::write(sandbox.path(output), "hello\n")?;
+        sandbox.fake("nix", FakeCommand::success().stdout(format!("{output}\n")));
+        let uid = nix::unistd::Uid::effective().as_raw();
+        let receipt = |uid: u32| {
+            serde_json::json!({
+                "actions": [{
+                    "action": {
+                        "action_name": "configure_daemon_user",
+                        "user": { "name": "nix-daemon", "uid": uid, "group": "nixbld", "gid": 30000 },
+                    },
+                    "state": "Completed",
+                }],
+            })
+        };
+
+        // Without a daemon user, there is nothing to check
+        sandbox.scope(verify_daemon_user()).await?;
+        assert!(sandbox.invocations_of("nix").is_empty());
+
+        std::fs::write(
+            sandbox.path(crate::plan::RECEIPT_LOCATION),
+            receipt(uid).to_string(),
+        )?;
+        sandbox.scope(verify_daemon_user()).await?;
+
+        std::fs::write(
+            sandbox.path(crate::plan::RECEIPT_LOCATION),
+            receipt(uid + 1).to_string(),
+        )?;
+        let err = sandbox.scope(verify_daemon_user()).await.unwrap_err();
+        assert!(
+            matches!(&err, SelfTestError::DaemonUser { actual, .. } if *actual == uid),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn the_daemon_must_run_with_its_niceness() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let processes =
+            "  1  0 /sbin/init\n 812 10 nix-daemon --daemon\n 901  0 nix-daemon --daemon\n";
+        sandbox.fake("ps", FakeCommand::success().stdout(processes));
+        let receipt = |nice: i8| {
+            serde_json::json!({
+                "actions": [{
+                    "action": {
+                        "action_name": "create_upstream_init_service",
+                        "configure_init_service": {
+                            "action": {
+                                "action_name": "configure_init_service",
+                                "resources": { "nice": nice },
+                            },
+                            "state": "Completed",
+                        },
+                    },
+                    "state": "Completed",
+                }],
+            })
+        };
+
+        // Without a niceness, there is nothing to check
+        sandbox.scope(verify_daemon_nice()).await?;
+        assert!(sandbox.invocations_of("ps").is_empty());
+
+        let receipt_path = sandbox.path(crate::plan::RECEIPT_LOCATION);
+        std::fs::create_dir_all(receipt_path.parent().unwrap())?;
+        std::fs::write(receipt_path, receipt(10).to_string())?;
+        let err = sandbox.scope(verify_daemon_nice()).await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                SelfTestError::DaemonNice {
+                    pid: 901,
+                    actual: 0,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+
+        sandbox.fake(
+            "ps",
+            FakeCommand::success().stdout(" 812 10 nix-daemon --daemon\n"),
+        );
+        sandbox.scope(verify_daemon_nice()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn each_nix_check_has_its_own_error() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let user = nix::unistd::User::from_uid(nix::unistd::Uid::effective())?
+            .expect("The current user exists");
+
+        sandbox.fake("nix", FakeCommand::success());
+        sandbox.scope(verify_daemon_build()).await?;
+        sandbox.scope(verify_flakes()).await?;
+        sandbox.scope(verify_daemon_socket_access(&user)).await?;
+        let invocations = sandbox.invocations_of("nix");
+        assert!(invocations[0]
+            .args
+            .windows(2)
+            .any(|w| w == ["--store", "daemon"]));
+        assert_eq!(
+            invocations[1].args[..4],
+            ["flake", "metadata", "--offline", "--no-write-lock-file"]
+        );
+        assert_eq!(invocations[2].args[..2], ["store", "ping"]);
+
+        sandbox.fake(
+            "nix",
+            FakeCommand::failure(1)
+                .stderr("error: experimental Nix feature 'flakes' is disabled\n"),
+        );
+        let err = sandbox.scope(verify_daemon_build()).await.unwrap_err();
+        assert!(matches!(err, SelfTestError::DaemonBuild { .. }), "{err:?}");
+        let err = sandbox.scope(verify_flakes()).await.unwrap_err();
+        assert!(
+            matches!(&err, SelfTestError::Flake { reason, .. } if reason.contains("'flakes' is disabled")),
+            "{err:?}"
+        );
+        let err = sandbox
+            .scope(verify_daemon_socket_access(&user))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, SelfTestError::DaemonSocketAccess { uid, .. } if *uid == user.uid.as_raw()),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn the_user_profile_is_on_path_in_either_layout() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let home = std::path::Path::new("/home/alice");
+        let path =
+            |entries: &[&str]| FakeCommand::success().stdout(format!("{}\n", entries.join(":")));
+
+        // Without a profile, there is nothing to find
+        sandbox
+            .scope(Shell::Bash.verify_profile_path_in(home, None))
+            .await?;
+        assert!(sandbox.invocations_of("bash").is_empty());
+
+        std::fs::create_dir_all(sandbox.path("/home/alice/.nix-profile"))?;
+        sandbox.fake("bash", path(&["/home/alice/.nix-profile/bin", "/usr/bin"]));
+        sandbox
+            .scope(Shell::Bash.verify_profile_path_in(home, None))
+            .await?;
+
+        // Once moved under `$XDG_STATE_HOME`, that is the profile in use
+        std::fs::create_dir_all(sandbox.path("/home/alice/.local/state/nix/profile"))?;
+        let err = sandbox
+            .scope(Shell::Bash.verify_profile_path_in(home, None))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, SelfTestError::ProfileNotOnPath { profile, .. } if profile.ends_with(".local/state/nix/profile")),
+            "{err:?}"
+        );
+        sandbox.fake(
+            "fish",
+            // Interactive shells may greet first
+            FakeCommand::success().stdout(
+                "Welcome to fish\n/home/alice/.local/state/nix/profile/bin:/home/alice/.nix-profile/bin\n",
+            ),
+        );
+        sandbox
+            .scope(Shell::Fish.verify_profile_path_in(home, None))
+            .await?;
+        assert_eq!(
+            sandbox.invocations_of("fish")[0].args,
+            ["-ic", "string join : $PATH"]
+        );
+
+        // A custom `$XDG_STATE_HOME` moves it again
+        std::fs::create_dir_all(sandbox.path("/var/state/alice/nix/profile"))?;
+        let err = sandbox
+            .scope(Shell::Fish.verify_profile_path_in(home, Some("/var/state/alice".as_ref())))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, SelfTestError::ProfileNotOnPath { profile, .. } if profile.starts_with("/var/state/alice")),
+            "{err:?}"
+        );
         Ok(())
-    } else {
-        Err(failures)
     }
 }
@@ -0,0 +1,373 @@
+/*! Build users left behind by installs with another build user prefix or count
+
+Build users are named `<nix_build_user_prefix><index>`. An install with another
+`--nix-build-user-prefix` (or fewer `--nix-build-user-count`) than an earlier one creates its own
+users next to the earlier ones, which keep their UIDs and group memberships. While planning, the
+host's users are scanned for names made from a known prefix which the plan will not create: the
+platform's default prefix, and every prefix recorded in the receipt or the receipts kept in the
+[state directory](crate::state_dir).
+
+They are reported with a warning, or removed with `--remove-stale-build-users`. Users still
+running processes are never removed, nor are macOS users holding a Secure Token (see
+`delete_user_macos`).
+*/
+
+use std::{collections::BTreeSet, path::Path};
+
+use target_lexicon::OperatingSystem;
+use tokio::process::Command;
+
+use crate::{plan::RECEIPT_LOCATION, settings::CommonSettings, util::host_path};
+
+/// A build user made from a known prefix, which the plan will not create
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub(crate) struct StaleBuildUser {
+    pub(crate) name: String,
+    pub(crate) uid: u32,
+    /// The `nix_build_user_prefix` it was made from
+    pub(crate) prefix: String,
+}
+
+impl std::fmt::Display for StaleBuildUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` (UID {}, prefix `{}`)",
+            self.name, self.uid, self.prefix
+        )
+    }
+}
+
+/// The stale build users on this host, with if each is still running processes
+///
+/// Nothing is found if the users cannot be listed.
+pub(crate) async fn scan(settings: &CommonSettings) -> Vec<(StaleBuildUser, bool)> {
+    let prefixes = historical_prefixes(settings);
+    let stale = classify(
+        &list_users().await,
+        &prefixes,
+        &settings.nix_build_user_prefix,
+        settings.nix_build_user_count,
+    );
+    let mut scanned = Vec::with_capacity(stale.len());
+    for user in stale {
+        let in_use = in_use(&user.name).await;
+        scanned.push((user, in_use));
+    }
+    scanned
+}
+
+/// The prefixes build users may have been made from: the platform's default, the one being
+/// planned, and those recorded in every receipt
+pub(crate) fn historical_prefixes(settings: &CommonSettings) -> BTreeSet<String> {
+    let mut prefixes = BTreeSet::from([
+        crate::settings::default_nix_build_user_prefix().to_string(),
+        settings.nix_build_user_prefix.clone(),
+    ]);
+    let mut receipts = vec![Path::new(RECEIPT_LOCATION).to_path_buf()];
+    receipts.extend(crate::state_dir::receipts(&settings.state_dir));
+    for receipt in receipts {
+        let Ok(content) = std::fs::read(host_path(&receipt)) else {
+            continue;
+        };
+        match serde_json::from_slice::<serde_json::Value>(&content) {
+            Ok(receipt) => prefixes.extend(
+                receipt
+                    .pointer("/planner/settings/nix_build_user_prefix")
+                    .and_then(serde_json::Value::as_str)
+                    .map(ToString::to_string),
+            ),
+            Err(e) => {
+                tracing::debug!(%e, receipt = %receipt.display(), "Could not parse receipt")
+            },
+        }
+    }
+    prefixes.retain(|prefix| !prefix.is_empty());
+    prefixes
+}
+
+/// The users made from one of the `prefixes` which a plan creating `count` users made from
+/// `prefix` would not create
+pub(crate) fn classify(
+    users: &[(String, u32)],
+    prefixes: &BTreeSet<String>,
+    prefix: &str,
+    count: u32,
+) -> Vec<StaleBuildUser> {
+    users
+        .iter()
+        .filter_map(|(name, uid)| {
+            let made_from = prefixes
+                .iter()
+                .filter_map(|known| {
+                    let index = name.strip_prefix(known.as_str())?;
+                    if index.is_empty() || !index.bytes().all(|byte| byte.is_ascii_digit()) {
+                        return None;
+                    }
+                    Some((known, index.parse::<u32>().ok()?))
+                })
+                .collect::<Vec<_>>();
+            // Such as `nixbld12` made from both `nixbld` and `nixbld1`, the plan's own wins
+            if made_from
+                .iter()
+                .any(|(known, index)| *known == prefix && (1..=count).contains(index))
+            {
+                return None;
+            }
+            let (known, _) = made_from.into_iter().max_by_key(|(known, _)| known.len())?;
+            Some(StaleBuildUser {
+                name: name.clone(),
+                uid: *uid,
+                prefix: known.clone(),
+            })
+        })
+        .collect()
+}
+
+/// The users of this host, with their UIDs
+async fn list_users() -> Vec<(String, u32)> {
+    match crate::cross_target::operating_system() {
+        OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => {
+            let output = crate::command_output(
+                Command::new("/usr/bin/dscl")
+                    .process_group(0)
+                    .args([".", "-list", "/Users", "UniqueID"])
+                    .stdin(std::process::Stdio::null()),
+            )
+            .await;
+            match output {
+                Ok(output) if output.status.success() => parse_dscl_unique_ids(&output.stdout),
+                Ok(output) => {
+                    tracing::debug!(
+                        stderr = %String::from_utf8_lossy(&output.stderr),
+                        "Could not list users for stale build users"
+                    );
+                    vec![]
+                },
+                Err(e) => {
+                    tracing::debug!(%e, "Could not list users for stale build users");
+                    vec![]
+                },
+            }
+        },
+        _ => match std::fs::read(host_path("/etc/passwd")) {
+            Ok(content) => parse_passwd_uids(&content),
+            Err(e) => {
+                tracing::debug!(%e, "Could not read `/etc/passwd` for stale build users");
+                vec![]
+            },
+        },
+    }
+}
+
+/// The users in `content`, in the format of `/etc/passwd`
+fn parse_passwd_uids(content: &[u8]) -> Vec<(String, u32)> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let user = fields.next()?;
+            let uid = fields.nth(1)?.parse().ok()?;
+            // NIS entries (`+user`) are not local users
+            (!user.is_empty() && !user.starts_with(['+', '-'])).then(|| (user.to_string(), uid))
+        })
+        .collect()
+}
+
+/// The users in the output of `dscl . -list /Users UniqueID`
+fn parse_dscl_unique_ids(content: &[u8]) -> Vec<(String, u32)> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter_map(|line| {
+            let (user, uid) = line.trim().split_once(char::is_whitespace)?;
+            Some((user.to_string(), uid.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// If `user` is running processes, assumed so if it cannot be checked
+async fn in_use(user: &str) -> bool {
+    let output = crate::command_output(
+        Command::new("pgrep")
+            .process_group(0)
+            .args(["-u", user])
+            .stdin(std::process::Stdio::null()),
+    )
+    .await;
+    match output {
+        Ok(output) => match output.status.code() {
+            Some(0) => true,
+            // No processes matched
+            Some(1) => false,
+            _ => {
+                tracing::debug!(
+                    stderr = %String::from_utf8_lossy(&output.stderr),
+                    user,
+                    "Could not check for processes of a stale build user, assuming it is in use"
+                );
+                true
+            },
+        },
+        Err(e) => {
+            tracing::debug!(%e, user, "Could not check for processes of a stale build user, assuming it is in use");
+            true
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::test_harness::{FakeCommand, SandboxContext};
+
+    fn names(users: &[StaleBuildUser]) -> Vec<&str> {
+        users.iter().map(|user| user.name.as_str()).collect()
+    }
+
+    #[test]
+    fn listings_are_parsed() {
+        let passwd = b"# local users\nroot:x:0:0:root:/root:/bin/bash\n+nisuser::::::\nnixbld1:x:30001:30000:Nix build user 1:/var/empty:/sbin/nologin\nbroken\n";
+        assert_eq!(
+            parse_passwd_uids(passwd),
+            vec![("root".into(), 0), ("nixbld1".into(), 30001)]
+        );
+        let dscl = b"_nixbld1                 351\n_www                     70\nnobody                   -2\n";
+        assert_eq!(
+            parse_dscl_unique_ids(dscl),
+            vec![("_nixbld1".into(), 351), ("_www".into(), 70)]
+        );
+    }
+
+    #[test]
+    fn users_from_other_prefixes_are_stale() {
+        let users = parse_passwd_uids(
+            b"nixbld1:x:30001:30000::/var/empty:/sbin/nologin\n\
+              nixbld2:x:30002:30000::/var/empty:/sbin/nologin\n\
+              nixbld3:x:30003:30000::/var/empty:/sbin/nologin\n\
+              oldbld7:x:30107:30000::/var/empty:/sbin/nologin\n\
+              oldbldx:x:30200:30000::/var/empty:/sbin/nologin\n\
+              nixbld_backup:x:30300:30000::/var/empty:/sbin/nologin\n\
+              alice:x:1000:1000::/home/alice:/bin/bash\n",
+        );
+        let prefixes = BTreeSet::from(["nixbld".to_string(), "oldbld".to_string()]);
+
+        let stale = classify(&users, &prefixes, "nixbld", 2);
+        assert_eq!(names(&stale), ["nixbld3", "oldbld7"]);
+        assert_eq!(stale[1].prefix, "oldbld");
+        assert_eq!(stale[1].uid, 30107);
+
+        // After moving to `oldbld`, the `nixbld` users are the stale ones
+        assert_eq!(
+            names(&classify(&users, &prefixes, "oldbld", 32)),
+            ["nixbld1", "nixbld2", "nixbld3"]
+        );
+    }
+
+    #[test]
+    fn the_planned_prefix_wins_overlapping_ones() {
+        let users = parse_dscl_unique_ids(b"_nixbld12 362\n_nixbld1 351\n");
+        let prefixes = BTreeSet::from(["_nixbld".to_string(), "_nixbld1".to_string()]);
+        assert!(classify(&users, &prefixes, "_nixbld", 32).is_empty());
+        let stale = classify(&users, &prefixes, "_nixbld", 1);
+        assert_eq!(names(&stale), ["_nixbld12"]);
+        assert_eq!(stale[0].prefix, "_nixbld1");
+    }
+
+    #[tokio::test]
+    async fn prefixes_are_read_from_every_receipt() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let settings = CommonSettings {
+            nix_build_user_prefix: "newbld".into(),
+            ..CommonSettings::default().await?
+        };
+        let receipt = |prefix: &str| {
+            serde_json::json!({ "planner": { "settings": { "nix_build_user_prefix": prefix } } })
+                .to_string()
+        };
+        std::fs::create_dir_all(sandbox.path("/nix/var/nix-installer"))?;
+        std::fs::write(sandbox.path(RECEIPT_LOCATION), receipt("oldbld"))?;
+        std::fs::write(
+            sandbox.path("/nix/var/nix-installer/receipt.pre-repair.1700000000000.json"),
+            receipt("olderbld"),
+        )?;
+        std::fs::write(
+            sandbox.path("/nix/var/nix-installer/original-receipt.1700000000001.json"),
+            "not json",
+        )?;
+
+        let prefixes = sandbox
+            .scope(async { historical_prefixes(&settings) })
+            .await;
+        assert_eq!(
+            prefixes,
+            BTreeSet::from(
+                [
+                    crate::settings::default_nix_build_user_prefix(),
+                    "newbld",
+                    "oldbld",
+                    "olderbld",
+                ]
+                .map(ToString::to_string)
+            )
+        );
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn stale_users_are_reported_unless_removed() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path("/etc"))?;
+        std::fs::write(
+            sandbox.path("/etc/passwd"),
+            "nixbld1:x:30001:30000::/var/empty:/sbin/nologin\n\
+             nixbld2:x:30002:30000::/var/empty:/sbin/nologin\n",
+        )?;
+        sandbox.fake("pgrep", FakeCommand::failure(1));
+        let settings = CommonSettings {
+            nix_build_user_prefix: "newbld".into(),
+            ..CommonSettings::default().await?
+        };
+
+        let (planned, warnings) = crate::warning::collect(
+            sandbox.scope(crate::planner::plan_stale_build_users(&settings)),
+        )
+        .await;
+        assert!(planned?.is_none());
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert_eq!(
+            warnings[0].kind,
+            crate::warning::WarningKind::StaleBuildUsers
+        );
+        assert!(warnings[0]
+            .message
+            .contains("`nixbld2` (UID 30002, prefix `nixbld`)"));
+        assert!(warnings[0].message.contains("--remove-stale-build-users"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_running_processes_are_in_use() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        sandbox.fake_once("pgrep", FakeCommand::success().stdout("4242\n"));
+        sandbox.fake_once("pgrep", FakeCommand::failure(1));
+        sandbox.fake_once("pgrep", FakeCommand::failure(2));
+
+        let checked = sandbox
+            .scope(async {
+                [
+                    in_use("oldbld1").await,
+                    in_use("oldbld2").await,
+                    in_use("oldbld3").await,
+                ]
+            })
+            .await;
+        // An error is taken as in use, so nothing is removed unchecked
+        assert_eq!(checked, [true, false, true]);
+        assert_eq!(sandbox.invocations_of("pgrep")[1].args, ["-u", "oldbld2"]);
+        Ok(())
+    }
+}
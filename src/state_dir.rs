@@ -0,0 +1,195 @@
+/*! The directory `nix-installer` keeps its own state in
+
+Everything `nix-installer` persists, besides the files it manages and the receipt at
+[`RECEIPT_LOCATION`](crate::plan::RECEIPT_LOCATION), is kept in the state directory. It defaults to
+[`DEFAULT_STATE_DIR`], inside `/nix`, so hosts which wipe their root filesystem on boot but keep
+`/nix` on a persistent volume keep it too. It can be moved with `--state-dir`, the choice is
+recorded in the receipt.
+
+It holds:
+
+* `uninstall-phase1.json` and `uninstall-phase2.json`, the receipts written by `split-receipt`
+* `original-receipt.<timestamp>.json`, each receipt `split-receipt` split
+* `receipt.pre-repair.<timestamp>.json`, each receipt as it was before `repair` updated it
+* `managed-files.json`, the [index](crate::managed_files) of the files the receipt records
+* `backups/`, the [backups](crate::backup) of files the install replaced or modified
+* `logs/`, the full log of each `install`, `repair`, and `uninstall` (see `cli::run_log`)
+
+It is created along with the `/nix` tree, and `uninstall` removes it once everything else is
+reverted. Releases before the state directory kept these files directly in `/nix`, they are
+still read from there.
+*/
+
+use std::path::{Path, PathBuf};
+
+use crate::{plan::InstallPlan, util::host_path};
+
+/// Where the state directory is, unless `--state-dir` was given
+pub const DEFAULT_STATE_DIR: &str = "/nix/var/nix-installer";
+
+/// Where state was kept before the state directory
+const LEGACY_STATE_DIR: &str = "/nix";
+
+/// The phase 1 receipt written by `split-receipt`
+pub(crate) const PHASE1_RECEIPT: &str = "uninstall-phase1.json";
+/// The phase 2 receipt written by `split-receipt`
+pub(crate) const PHASE2_RECEIPT: &str = "uninstall-phase2.json";
+
+/// The state directory `plan` was installed with, receipts written before it could be moved use the default
+pub(crate) fn of_plan(plan: &InstallPlan) -> PathBuf {
+    plan.planner
+        .settings()
+        .ok()
+        .and_then(|settings| settings.get("state_dir")?.as_str().map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR))
+}
+
+/// Like [`of_plan`], for a receipt which could only be parsed as JSON
+pub(crate) fn of_receipt_json(receipt: &serde_json::Value) -> PathBuf {
+    receipt
+        .pointer("/planner/settings/state_dir")
+        .and_then(serde_json::Value::as_str)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR))
+}
+
+/// Every place the state file `name` may be, in the order they are read: `state_dir`, then the
+/// default state directory, then where releases before the state directory wrote it
+pub(crate) fn read_locations(state_dir: &Path, name: &str) -> Vec<PathBuf> {
+    read_dirs(state_dir)
+        .into_iter()
+        .map(|dir| dir.join(name))
+        .collect()
+}
+
+/// The directories state is read from, in order: `state_dir`, then the default state directory,
+/// then where releases before the state directory wrote it
+fn read_dirs(state_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::with_capacity(3);
+    for dir in [
+        state_dir,
+        Path::new(DEFAULT_STATE_DIR),
+        Path::new(LEGACY_STATE_DIR),
+    ] {
+        if !dirs.iter().any(|known: &PathBuf| known == dir) {
+            dirs.push(dir.to_path_buf());
+        }
+    }
+    dirs
+}
+
+/// Every receipt kept where state is read from: those written by `split-receipt`, and the ones it
+/// split or `repair` updated
+pub(crate) fn receipts(state_dir: &Path) -> Vec<PathBuf> {
+    let mut receipts = vec![];
+    for dir in read_dirs(state_dir) {
+        let Ok(entries) = host_path(&dir).read_dir() else {
+            continue;
+        };
+        let mut names = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| {
+                name == PHASE1_RECEIPT
+                    || name == PHASE2_RECEIPT
+                    || ((name.starts_with("original-receipt.")
+                        || name.starts_with("receipt.pre-repair."))
+                        && name.ends_with(".json"))
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        receipts.extend(names.into_iter().map(|name| dir.join(name)));
+    }
+    receipts
+}
+
+/// The first of the [`read_locations`] of `name` which exists
+pub(crate) fn find(state_dir: &Path, name: &str) -> Option<PathBuf> {
+    read_locations(state_dir, name)
+        .into_iter()
+        .find(|location| host_path(location).exists())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::{find, read_locations, receipts, DEFAULT_STATE_DIR, PHASE2_RECEIPT};
+    use crate::test_harness::SandboxContext;
+
+    #[test]
+    fn read_order() {
+        assert_eq!(
+            read_locations(Path::new("/persist/nix-installer"), PHASE2_RECEIPT),
+            vec![
+                PathBuf::from("/persist/nix-installer/uninstall-phase2.json"),
+                PathBuf::from("/nix/var/nix-installer/uninstall-phase2.json"),
+                PathBuf::from("/nix/uninstall-phase2.json"),
+            ]
+        );
+        assert_eq!(
+            read_locations(Path::new(DEFAULT_STATE_DIR), PHASE2_RECEIPT),
+            vec![
+                PathBuf::from("/nix/var/nix-installer/uninstall-phase2.json"),
+                PathBuf::from("/nix/uninstall-phase2.json"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn state_is_read_from_the_first_location_holding_it() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let state_dir = Path::new("/persist/nix-installer");
+        let find_phase2 = || sandbox.scope(async { find(state_dir, PHASE2_RECEIPT) });
+
+        assert_eq!(find_phase2().await, None);
+
+        std::fs::create_dir_all(sandbox.path("/nix"))?;
+        std::fs::write(sandbox.path("/nix/uninstall-phase2.json"), "{}")?;
+        assert_eq!(
+            find_phase2().await,
+            Some(PathBuf::from("/nix/uninstall-phase2.json"))
+        );
+
+        std::fs::create_dir_all(sandbox.path(DEFAULT_STATE_DIR))?;
+        std::fs::write(
+            sandbox.path("/nix/var/nix-installer/uninstall-phase2.json"),
+            "{}",
+        )?;
+        assert_eq!(
+            find_phase2().await,
+            Some(PathBuf::from(
+                "/nix/var/nix-installer/uninstall-phase2.json"
+            ))
+        );
+
+        std::fs::create_dir_all(sandbox.path(state_dir))?;
+        std::fs::write(sandbox.path(state_dir.join(PHASE2_RECEIPT)), "{}")?;
+        assert_eq!(find_phase2().await, Some(state_dir.join(PHASE2_RECEIPT)));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn receipts_are_listed_from_every_location() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        std::fs::create_dir_all(sandbox.path(DEFAULT_STATE_DIR))?;
+        for name in [
+            "original-receipt.1700000000000.json",
+            "receipt.pre-repair.1700000000001.json",
+            "logs",
+        ] {
+            std::fs::write(sandbox.path(Path::new(DEFAULT_STATE_DIR).join(name)), "{}")?;
+        }
+        std::fs::write(sandbox.path("/nix/uninstall-phase1.json"), "{}")?;
+
+        assert_eq!(
+            sandbox
+                .scope(async { receipts(Path::new(DEFAULT_STATE_DIR)) })
+                .await,
+            vec![
+                PathBuf::from("/nix/var/nix-installer/original-receipt.1700000000000.json"),
+                PathBuf::from("/nix/var/nix-installer/receipt.pre-repair.1700000000001.json"),
+                PathBuf::from("/nix/uninstall-phase1.json"),
+            ]
+        );
+        Ok(())
+    }
+}
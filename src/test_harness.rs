@@ -0,0 +1,393 @@
+/*! Support for exercising [`Action`]s without root
+
+Custom [`Action`]s and [`Planner`](crate::planner::Planner)s are easiest to trust once their
+`execute` and `revert` have actually run, but most actions touch well-known absolute paths (`/nix`,
+`/etc/nix`, `/etc/systemd/system`, ...) and shell out to tools like `systemctl`, `launchctl`,
+`dscl`, or `diskutil`, none of which are safe (or possible) to use on a contributor's laptop.
+
+A [`SandboxContext`] provides:
+
+* A temporary directory standing in for `/`, any of the well-known paths `nix-installer` uses
+  internally are remapped underneath it while the sandbox is active. Use [`SandboxContext::path`]
+  to remap paths passed as arguments to an action's `plan`.
+* Scriptable fakes for every command run through `nix-installer`'s command helpers. No command
+  is ever spawned while the sandbox is active; unless a [`FakeCommand`] was registered with
+  [`SandboxContext::fake`], a command succeeds with empty output. Each invocation is recorded and
+  can be inspected via [`SandboxContext::invocations`].
+
+The sandbox is only active inside [`SandboxContext::scope`] (or the [`SandboxContext::execute`]
+and [`SandboxContext::revert`] helpers). It is tracked per task, so actions which spawn sub-tasks will not
+see it from inside those tasks.
+
+This module requires the `test-harness` feature:
+
+```toml
+[dev-dependencies]
+nix-installer = { version = "*", features = ["test-harness"] }
+```
+
+```rust,no_run
+use nix_installer::{
+    action::{base::CreateDirectory, common::ConfigureInitService, common::SocketFile, common::UnitSrc},
+    settings::InitSystem,
+    test_harness::{FakeCommand, Invocation, SandboxContext},
+};
+
+# async fn sandboxed() -> color_eyre::Result<()> {
+let sandbox = SandboxContext::new()?;
+sandbox.fake("systemctl", FakeCommand::success());
+
+let mut actions = vec![
+    sandbox
+        .scope(CreateDirectory::plan(sandbox.path("/nix"), None, None, 0o0755, true))
+        .await?
+        .boxed(),
+    sandbox
+        .scope(ConfigureInitService::plan(
+            InitSystem::Systemd,
+            false,
+            Some(sandbox.path("/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.service")),
+            Some(sandbox.path("/etc/systemd/system/nix-daemon.service")),
+            None,
+            vec![SocketFile {
+                name: "nix-daemon.socket".into(),
+                src: UnitSrc::Literal("[Socket]\n".into()),
+                dest: sandbox.path("/etc/systemd/system/nix-daemon.socket"),
+            }],
+            None,
+        ))
+        .await?
+        .boxed(),
+];
+
+sandbox.execute(&mut actions).await?;
+assert!(sandbox.path("/etc/systemd/system/nix-daemon.socket").exists());
+assert!(sandbox
+    .invocations()
+    .contains(&Invocation::new("systemctl", ["enable", "nix-daemon.socket"])));
+
+sandbox.revert(&mut actions).await?;
+assert!(!sandbox.path("/nix").exists());
+# Ok(())
+# }
+```
+*/
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Output},
+    sync::{Arc, Mutex},
+};
+
+use nix::errno::Errno;
+use tokio::process::Command;
+
+use crate::action::{Action, ActionError, StatefulAction};
+
+/// Directories which exist on any real host, and are created inside each sandbox root
+const SKELETON: &[&str] = &[
+    "etc",
+    "etc/profile.d",
+    "etc/systemd/system",
+    "etc/tmpfiles.d",
+    "Library/LaunchDaemons",
+];
+
+tokio::task_local! {
+    static SANDBOX: Arc<Sandbox>;
+}
+
+/// A command invocation recorded by a [`SandboxContext`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    /// The file name of the program, such as `systemctl` (even if invoked as `/bin/systemctl`)
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl Invocation {
+    pub fn new<S: AsRef<str>>(program: &str, args: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            program: program.to_string(),
+            args: args.into_iter().map(|v| v.as_ref().to_string()).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for Invocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.program)?;
+        for arg in &self.args {
+            write!(f, " {arg}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The scripted result of a faked command
+#[derive(Debug, Clone)]
+pub struct FakeCommand {
+    status: i32,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl FakeCommand {
+    /// A command which exits `0` with no output
+    pub fn success() -> Self {
+        Self {
+            status: 0,
+            stdout: vec![],
+            stderr: vec![],
+        }
+    }
+
+    /// A command which exits with `status` and no output
+    pub fn failure(status: i32) -> Self {
+        Self {
+            status,
+            stdout: vec![],
+            stderr: vec![],
+        }
+    }
+
+    pub fn stdout(mut self, stdout: impl Into<Vec<u8>>) -> Self {
+        self.stdout = stdout.into();
+        self
+    }
+
+    pub fn stderr(mut self, stderr: impl Into<Vec<u8>>) -> Self {
+        self.stderr = stderr.into();
+        self
+    }
+
+    fn output(&self) -> Output {
+        Output {
+            // The raw value is a `wait` status, so the exit code lives in the second byte
+            status: ExitStatus::from_raw(self.status << 8),
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Fake {
+    /// Consumed in order before falling back to `default`
+    queued: VecDeque<FakeCommand>,
+    default: Option<FakeCommand>,
+}
+
+#[derive(Debug)]
+struct Sandbox {
+    root: PathBuf,
+    fakes: Mutex<HashMap<String, Fake>>,
+    invocations: Mutex<Vec<Invocation>>,
+    rename_failure: Mutex<Option<Errno>>,
+}
+
+impl Sandbox {
+    fn remap(&self, path: &Path) -> PathBuf {
+        if !path.is_absolute() || path.starts_with(&self.root) {
+            return path.to_path_buf();
+        }
+        // `is_absolute` guarantees a root to strip
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    fn intercept(&self, command: &Command) -> Output {
+        let std_command = command.as_std();
+        let program = Path::new(std_command.get_program())
+            .file_name()
+            .unwrap_or_else(|| std_command.get_program())
+            .to_string_lossy()
+            .to_string();
+        let invocation = Invocation {
+            program: program.clone(),
+            args: std_command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect(),
+        };
+        tracing::trace!(%invocation, "Intercepted command in sandbox");
+        self.invocations
+            .lock()
+            .expect("Sandbox invocation lock poisoned")
+            .push(invocation);
+
+        let mut fakes = self.fakes.lock().expect("Sandbox fake lock poisoned");
+        let fake = fakes
+            .get_mut(&program)
+            .and_then(|fake| fake.queued.pop_front().or_else(|| fake.default.clone()));
+        fake.unwrap_or_else(FakeCommand::success).output()
+    }
+}
+
+/// A temporary root directory and set of command fakes which actions can be run against
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct SandboxContext {
+    // Held so the directory is removed on drop
+    _dir: tempfile::TempDir,
+    inner: Arc<Sandbox>,
+}
+
+impl SandboxContext {
+    pub fn new() -> std::io::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        for skeleton in SKELETON {
+            std::fs::create_dir_all(dir.path().join(skeleton))?;
+        }
+        let inner = Arc::new(Sandbox {
+            root: dir.path().to_path_buf(),
+            fakes: Default::default(),
+            invocations: Default::default(),
+            rename_failure: Default::default(),
+        });
+        Ok(Self { _dir: dir, inner })
+    }
+
+    /// The directory standing in for `/`
+    pub fn root(&self) -> &Path {
+        &self.inner.root
+    }
+
+    /// Remap an absolute path (such as `/etc/nix/nix.conf`) into the sandbox root
+    ///
+    /// Relative paths, and paths already inside the sandbox root, are returned unchanged.
+    pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.inner.remap(path.as_ref())
+    }
+
+    /// Create a symlink at `link`, remapped into the sandbox along with any missing parents, to
+    /// `target`
+    ///
+    /// `target` is written as given rather than remapped, so the link reads as it would on a real
+    /// host, and code following it has to remap the target itself.
+    pub fn symlink(&self, target: impl AsRef<Path>, link: impl AsRef<Path>) -> std::io::Result<()> {
+        let link = self.path(link);
+        if let Some(parent) = link.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    /// Respond to every invocation of `program` with `fake`, after any [`fake_once`](Self::fake_once) responses are used
+    pub fn fake(&self, program: &str, fake: FakeCommand) -> &Self {
+        let mut fakes = self.inner.fakes.lock().expect("Sandbox fake lock poisoned");
+        fakes.entry(program.to_string()).or_default().default = Some(fake);
+        self
+    }
+
+    /// Respond to the next invocation of `program` with `fake`, responses are queued in the order given
+    pub fn fake_once(&self, program: &str, fake: FakeCommand) -> &Self {
+        let mut fakes = self.inner.fakes.lock().expect("Sandbox fake lock poisoned");
+        fakes
+            .entry(program.to_string())
+            .or_default()
+            .queued
+            .push_back(fake);
+        self
+    }
+
+    /// Fail every rename made while replacing a file's content with `errno`, such as `EXDEV` for a
+    /// destination on another filesystem
+    pub fn fail_renames(&self, errno: Errno) -> &Self {
+        *self
+            .inner
+            .rename_failure
+            .lock()
+            .expect("Sandbox rename lock poisoned") = Some(errno);
+        self
+    }
+
+    /// Every command invoked so far, in order
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.inner
+            .invocations
+            .lock()
+            .expect("Sandbox invocation lock poisoned")
+            .clone()
+    }
+
+    /// Every invocation of `program` so far, in order
+    pub fn invocations_of(&self, program: &str) -> Vec<Invocation> {
+        self.invocations()
+            .into_iter()
+            .filter(|invocation| invocation.program == program)
+            .collect()
+    }
+
+    /// Run `fut` with the sandbox active
+    ///
+    /// Action `plan` functions should be run inside this as well, as many inspect the host.
+    pub async fn scope<F: Future>(&self, fut: F) -> F::Output {
+        SANDBOX.scope(self.inner.clone(), fut).await
+    }
+
+    /// Execute `actions` in order inside the sandbox, the same way [`InstallPlan::install`](crate::InstallPlan::install) would
+    pub async fn execute(
+        &self,
+        actions: &mut [StatefulAction<Box<dyn Action>>],
+    ) -> Result<(), ActionError> {
+        self.scope(async {
+            for action in actions.iter_mut() {
+                action.try_execute().await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Revert `actions` in reverse order inside the sandbox, the same way [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) would
+    ///
+    /// Unlike [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) this stops at the first error.
+    pub async fn revert(
+        &self,
+        actions: &mut [StatefulAction<Box<dyn Action>>],
+    ) -> Result<(), ActionError> {
+        self.scope(async {
+            for action in actions.iter_mut().rev() {
+                action.try_revert().await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn current() -> Option<Arc<Sandbox>> {
+    SANDBOX.try_with(|sandbox| sandbox.clone()).ok()
+}
+
+/// Remap `path` into the active sandbox, if any
+pub(crate) fn remap(path: &Path) -> PathBuf {
+    match current() {
+        Some(sandbox) => sandbox.remap(path),
+        None => path.to_path_buf(),
+    }
+}
+
+/// The faked output of `command`, if a sandbox is active
+pub(crate) fn intercept(command: &Command) -> Option<Output> {
+    current().map(|sandbox| sandbox.intercept(command))
+}
+
+/// If a sandbox is active, every program is considered present since none are actually run
+pub(crate) fn is_active() -> bool {
+    current().is_some()
+}
+
+/// The error a rename should fail with, if a sandbox is active and was told to fail them
+pub(crate) fn intercept_rename() -> Option<Errno> {
+    current().and_then(|sandbox| {
+        *sandbox
+            .rename_failure
+            .lock()
+            .expect("Sandbox rename lock poisoned")
+    })
+}
@@ -0,0 +1,608 @@
+/*! What uninstalling would do on this host, without doing it
+
+`nix-installer uninstall --impact-report` combines each completed action in the receipt with what is
+on the host now:
+
+* the users and groups deleted, with their current IDs (or that they are already gone)
+* the files removed, or edited to remove what `nix-installer` added, and whether they changed since
+  the install (using the hashes recorded in the receipt, when there are any)
+* the services stopped, and whether they are running
+* the Nix store removed, with how many store paths and how much data it holds
+
+Actions which cannot itemize their changes are reported with their revert description instead.
+Nothing is written, and nothing is reverted.
+*/
+
+use std::{
+    collections::HashSet,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use crate::{
+    action::{
+        common::provision_nix::NIX_STORE_LOCATION, Action, ActionDescription, ActionState,
+        PrivilegedOperation, StatefulAction,
+    },
+    audit::{find_planned, lookup_gid, lookup_uid, read_database, AuditError, Planned},
+    drift::{written_files, DriftStatus, WrittenFile},
+    managed_files::ManagedFile,
+    util::{host_path, LossyPath},
+};
+
+/// What uninstalling would do, see [`InstallPlan::uninstall_impact`](crate::InstallPlan::uninstall_impact)
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct UninstallImpact {
+    pub users: Vec<AccountImpact>,
+    pub groups: Vec<AccountImpact>,
+    pub files: Vec<FileImpact>,
+    pub services: Vec<ServiceImpact>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store: Option<StoreImpact>,
+    /// What actions which cannot itemize their changes would revert, as they describe it
+    pub other: Vec<ActionDescription>,
+}
+
+/// A user or group the install created
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AccountImpact {
+    pub name: String,
+    /// The UID or GID it was created with
+    pub id: u32,
+    /// The UID or GID it has now, `None` if it no longer exists
+    pub current_id: Option<u32>,
+    /// A user which existed before the install is left in place
+    pub removed: bool,
+}
+
+/// A file the install wrote or edited
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileImpact {
+    #[serde_as(as = "LossyPath")]
+    pub path: PathBuf,
+    /// Only what `nix-installer` added is removed, the rest of the file is kept
+    pub partial: bool,
+    /// How the file compares to what was written, `None` if the receipt recorded no hash
+    pub status: Option<DriftStatus>,
+}
+
+/// A service the install registered
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ServiceImpact {
+    pub name: String,
+    /// `None` if the init system could not be asked
+    pub active: Option<bool>,
+}
+
+/// The Nix store the install created
+#[serde_with::serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StoreImpact {
+    #[serde_as(as = "LossyPath")]
+    pub path: PathBuf,
+    /// A store the install was made alongside of is left in place
+    pub removed: bool,
+    /// The number of entries in the store, each a store path
+    pub store_paths: u64,
+    /// The size of every file in the store, counting hard linked files once
+    pub bytes: u64,
+}
+
+impl UninstallImpact {
+    fn itemized(&self) -> usize {
+        self.users.len()
+            + self.groups.len()
+            + self.files.len()
+            + self.services.len()
+            + usize::from(self.store.is_some())
+    }
+}
+
+impl std::fmt::Display for UninstallImpact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            users,
+            groups,
+            files,
+            services,
+            store,
+            other,
+        } = self;
+
+        let mut sections: Vec<(&str, Vec<String>)> = vec![];
+        sections.push((
+            "Users deleted",
+            users
+                .iter()
+                .filter(|user| user.removed)
+                .map(|user| describe_account(user, "UID"))
+                .collect(),
+        ));
+        sections.push((
+            "Users kept, as they existed before the install",
+            users
+                .iter()
+                .filter(|user| !user.removed)
+                .map(|user| describe_account(user, "UID"))
+                .collect(),
+        ));
+        sections.push((
+            "Groups deleted",
+            groups
+                .iter()
+                .map(|group| describe_account(group, "GID"))
+                .collect(),
+        ));
+        sections.push(("Files removed", files.iter().map(describe_file).collect()));
+        sections.push((
+            "Services stopped",
+            services
+                .iter()
+                .map(|service| {
+                    let state = match service.active {
+                        Some(true) => "running",
+                        Some(false) => "not running",
+                        None => "state unknown",
+                    };
+                    format!("* `{}` ({state})", service.name)
+                })
+                .collect(),
+        ));
+        if let Some(store) = store {
+            let size = format!(
+                "{} store paths, {}",
+                store.store_paths,
+                crate::host_snapshot::format_bytes(store.bytes)
+            );
+            let line = match store.removed {
+                true => format!("* `{}` ({size}) is deleted", store.path.display()),
+                false => format!(
+                    "* `{}` ({size}) is kept, it existed before the install",
+                    store.path.display()
+                ),
+            };
+            sections.push(("Nix store", vec![line]));
+        }
+        sections.push((
+            "Also reverted",
+            other
+                .iter()
+                .map(|description| format!("* {}", description.description))
+                .collect(),
+        ));
+
+        let mut first = true;
+        for (title, lines) in sections.into_iter().filter(|(_, lines)| !lines.is_empty()) {
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            writeln!(f, "{title}:")?;
+            for line in lines {
+                writeln!(f, "{line}")?;
+            }
+        }
+        if first {
+            writeln!(f, "Nothing would be reverted")?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_account(account: &AccountImpact, id: &str) -> String {
+    let current = match account.current_id {
+        Some(current) if current == account.id => format!("{id} {current}"),
+        Some(current) => format!("now {id} {current}, created as {id} {}", account.id),
+        None => "already gone".to_string(),
+    };
+    format!("* `{}` ({current})", account.name)
+}
+
+fn describe_file(file: &FileImpact) -> String {
+    let path = file.path.display();
+    let what = match file.partial {
+        true => format!("`{path}`, only what `nix-installer` added"),
+        false => format!("`{path}`"),
+    };
+    let status = match file.status {
+        Some(DriftStatus::Unchanged) => "unchanged since the install",
+        Some(DriftStatus::Modified) => "modified since the install",
+        Some(DriftStatus::BlockRemoved) => "what `nix-installer` added was already removed",
+        Some(DriftStatus::PermissionsChanged) => "its mode or group changed since the install",
+        Some(DriftStatus::Missing) => "already gone",
+        None => "no hash was recorded to compare with",
+    };
+    format!("* {what} ({status})")
+}
+
+/// Assemble what reverting the completed `actions` would do on this host
+///
+/// Files are compared with what the `indexed` files record, if the receipt of `actions` has an up
+/// to date [managed file index](crate::managed_files), otherwise with what the actions record.
+pub(crate) async fn uninstall_impact(
+    actions: &[StatefulAction<Box<dyn Action>>],
+    indexed: Option<&[ManagedFile]>,
+) -> Result<UninstallImpact, AuditError> {
+    let passwd = read_database("/etc/passwd").await?;
+    let group = read_database("/etc/group").await?;
+
+    let mut impact = UninstallImpact::default();
+    for action in actions.iter().filter(|v| v.state == ActionState::Completed) {
+        let itemized = impact.itemized();
+        let value = serde_json::to_value(action).map_err(AuditError::SerializingPlan)?;
+        let value = completed_only(value);
+
+        let mut planned = vec![];
+        find_planned(&value, &mut planned);
+        let written = match indexed {
+            Some(indexed) => indexed.iter().map(|file| file.written.clone()).collect(),
+            None => written_files(&value),
+        };
+        let kept_users = find_actions(&value, "create_user")
+            .into_iter()
+            .filter(|user| user.get("update_existing") == Some(&serde_json::Value::Bool(true)))
+            .filter_map(|user| user.get("name")?.as_str())
+            .collect::<HashSet<_>>();
+
+        for item in &planned {
+            match item {
+                Planned::User { name, uid, .. } => impact.users.push(AccountImpact {
+                    name: name.clone(),
+                    id: *uid,
+                    current_id: lookup_uid(&passwd, name),
+                    removed: !kept_users.contains(name.as_str()),
+                }),
+                Planned::Group { name, gid } => impact.groups.push(AccountImpact {
+                    name: name.clone(),
+                    id: *gid,
+                    current_id: lookup_gid(&group, name),
+                    removed: true,
+                }),
+                item => {
+                    let Some(path) = item.path() else {
+                        continue;
+                    };
+                    if impact.files.iter().any(|file| file.path == path) {
+                        continue;
+                    }
+                    let partial =
+                        matches!(item, Planned::Insertion { .. } | Planned::NixConfig { .. });
+                    let written = written.iter().find(|written| written.path == path);
+                    impact.files.push(FileImpact {
+                        path: path.to_path_buf(),
+                        partial,
+                        status: file_status(path, written).await?,
+                    });
+                },
+            }
+        }
+
+        for name in services(&value) {
+            if impact.services.iter().any(|service| service.name == name) {
+                continue;
+            }
+            impact.services.push(ServiceImpact {
+                active: service_active(&name).await,
+                name,
+            });
+        }
+
+        if let Some(provision) = find_actions(&value, "provision_nix").first() {
+            let shared_store =
+                provision.get("shared_store") == Some(&serde_json::Value::Bool(true));
+            impact.store = Some(store_impact(Path::new(NIX_STORE_LOCATION), !shared_store)?);
+        }
+
+        if impact.itemized() == itemized {
+            impact.other.extend(action.describe_revert());
+        }
+    }
+    Ok(impact)
+}
+
+/// `value`, a serialized action, without the nested actions which did not complete
+fn completed_only(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let is_stateful = map.contains_key("action") && map.contains_key("state");
+            if is_stateful && map.get("state").and_then(|v| v.as_str()) != Some("Completed") {
+                return serde_json::Value::Null;
+            }
+            serde_json::Value::Object(
+                map.into_iter()
+                    .map(|(key, value)| (key, completed_only(value)))
+                    .collect(),
+            )
+        },
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(completed_only).collect())
+        },
+        value => value,
+    }
+}
+
+/// The services registered by `value`, a serialized action, or the actions nested in it
+///
+/// A completed action reports no privileged operations, so each action is deserialized on its own
+/// and asked which services it registers. Actions which delegate to nested actions report none.
+fn services(value: &serde_json::Value) -> Vec<String> {
+    let mut services = vec![];
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        match value {
+            serde_json::Value::Object(map) => {
+                if map.contains_key("action_name") {
+                    let action = serde_json::from_value::<Box<dyn Action>>(value.clone());
+                    services.extend(action.into_iter().flat_map(|action| {
+                        action
+                            .privileged_operations()
+                            .into_iter()
+                            .filter_map(|operation| match operation {
+                                PrivilegedOperation::Service { name } => Some(name),
+                                _ => None,
+                            })
+                    }));
+                }
+                pending.extend(map.values());
+            },
+            serde_json::Value::Array(values) => pending.extend(values),
+            _ => (),
+        }
+    }
+    services
+}
+
+/// Every serialized action named `action_name` in `value`, however deeply nested
+fn find_actions<'a>(
+    value: &'a serde_json::Value,
+    action_name: &str,
+) -> Vec<&'a serde_json::Map<String, serde_json::Value>> {
+    let mut found = vec![];
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        match value {
+            serde_json::Value::Object(map) => {
+                if map.get("action_name").and_then(|v| v.as_str()) == Some(action_name) {
+                    found.push(map);
+                }
+                pending.extend(map.values());
+            },
+            serde_json::Value::Array(values) => pending.extend(values),
+            _ => (),
+        }
+    }
+    found
+}
+
+async fn file_status(
+    path: &Path,
+    written: Option<&WrittenFile>,
+) -> Result<Option<DriftStatus>, AuditError> {
+    if let Some(written) = written {
+        let drift = written
+            .check()
+            .await
+            .map_err(|e| AuditError::Read(path.to_path_buf(), e))?;
+        return Ok(Some(drift.status));
+    }
+    match tokio::fs::symlink_metadata(host_path(path)).await {
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Some(DriftStatus::Missing)),
+        Err(e) => Err(AuditError::Read(path.to_path_buf(), e)),
+    }
+}
+
+/// If the init system runs the service `name`, `None` if it could not be asked
+async fn service_active(name: &str) -> Option<bool> {
+    #[cfg(target_os = "macos")]
+    {
+        let mut command = Command::new("launchctl");
+        command.arg("print").arg(format!(
+            "{}/{name}",
+            crate::action::macos::DARWIN_LAUNCHD_DOMAIN
+        ));
+        command.stdin(std::process::Stdio::null()).process_group(0);
+        let output = crate::command_output(&mut command).await.ok()?;
+        Some(output.status.success())
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        let mut command = Command::new("systemctl");
+        command.arg("is-active").arg(name);
+        command.stdin(std::process::Stdio::null()).process_group(0);
+        let output = crate::command_output(&mut command).await.ok()?;
+        // `is-active` exits nonzero for inactive units, which is still an answer
+        match String::from_utf8_lossy(&output.stdout).trim() {
+            "" => None,
+            state => Some(state == "active"),
+        }
+    }
+}
+
+/// Count the store paths in `store`, and the bytes they hold
+fn store_impact(store: &Path, removed: bool) -> Result<StoreImpact, AuditError> {
+    let root = host_path(store);
+    let store_paths = match std::fs::read_dir(&root) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .count() as u64,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(AuditError::Read(store.to_path_buf(), e)),
+    };
+
+    // `auto-optimise-store` hard links identical files, which only take up space once
+    let mut seen = HashSet::new();
+    let mut bytes = 0;
+    for entry in walkdir::WalkDir::new(&root)
+        .follow_links(false)
+        .same_file_system(true)
+        .into_iter()
+        .filter_map(Result::ok)
+    {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() || !seen.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        bytes += metadata.len();
+    }
+
+    Ok(StoreImpact {
+        path: store.to_path_buf(),
+        removed,
+        store_paths,
+        bytes,
+    })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::path::Path;
+
+    use super::{AccountImpact, FileImpact, ServiceImpact};
+    use crate::{
+        drift::{DriftStatus, WrittenFile},
+        test_harness::{FakeCommand, SandboxContext},
+        InstallPlan,
+    };
+
+    const LINUX: &str = include_str!("../tests/fixtures/linux/linux.json");
+    const SNIPPET: &str = "\n# Nix\nif [ -e '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh' ]; then\n    . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\nfi\n# End Nix\n\n        \n";
+
+    /// Record a hash for the block inserted into `path`, as newer receipts do
+    fn record_hash(value: &mut serde_json::Value, path: &str) {
+        match value {
+            serde_json::Value::Object(map) => {
+                map.values_mut().for_each(|value| record_hash(value, path));
+                if map.get("path").and_then(|v| v.as_str()) == Some(path) {
+                    map.insert(
+                        "written_file".into(),
+                        serde_json::to_value(WrittenFile::fenced(path, SNIPPET)).unwrap(),
+                    );
+                }
+            },
+            serde_json::Value::Array(values) => {
+                values.iter_mut().for_each(|value| record_hash(value, path))
+            },
+            _ => (),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_what_uninstalling_would_do_here() -> eyre::Result<()> {
+        let sandbox = SandboxContext::new()?;
+        let write = |path: &str, contents: &[u8]| -> std::io::Result<()> {
+            std::fs::create_dir_all(sandbox.path(path).parent().unwrap())?;
+            std::fs::write(sandbox.path(path), contents)
+        };
+        // `nixbld2` was recreated with another UID since, and the others were already deleted
+        write(
+            "/etc/passwd",
+            b"root:x:0:0:root:/root:/bin/bash\nnixbld1:x:30001:30000::/var/empty:/sbin/nologin\nnixbld2:x:31002:30000::/var/empty:/sbin/nologin\n",
+        )?;
+        write("/etc/group", b"root:x:0:\nnixbld:x:30000:nixbld1,nixbld2\n")?;
+        write(
+            "/etc/bashrc",
+            format!("alias ll='ls -l'\n{SNIPPET}").as_bytes(),
+        )?;
+        write("/etc/zshrc", b"# Nix\n# End Nix\n")?;
+        write("/etc/nix/nix.conf", b"build-users-group = nixbld\n")?;
+        write("/nix/store/aaaa-hello/bin/hello", &[0; 1000])?;
+        write("/nix/store/bbbb-hello.drv", &[0; 200])?;
+        // Hard linked by `auto-optimise-store`, so only counted once
+        std::fs::hard_link(
+            sandbox.path("/nix/store/aaaa-hello/bin/hello"),
+            sandbox.path("/nix/store/cccc-hello-copy"),
+        )?;
+        std::fs::create_dir_all(sandbox.path("/nix/store/.links"))?;
+        sandbox.fake("systemctl", FakeCommand::failure(3).stdout("inactive\n"));
+
+        let mut receipt: serde_json::Value = serde_json::from_str(LINUX)?;
+        record_hash(&mut receipt, "/etc/bashrc");
+        record_hash(&mut receipt, "/etc/zshrc");
+        let plan: InstallPlan = serde_json::from_value(receipt)?;
+
+        let impact = sandbox.scope(plan.uninstall_impact()).await?;
+
+        assert_eq!(impact.users.len(), 32);
+        assert_eq!(
+            impact.users[..3],
+            [
+                AccountImpact {
+                    name: "nixbld1".into(),
+                    id: 30001,
+                    current_id: Some(30001),
+                    removed: true,
+                },
+                AccountImpact {
+                    name: "nixbld2".into(),
+                    id: 30002,
+                    current_id: Some(31002),
+                    removed: true,
+                },
+                AccountImpact {
+                    name: "nixbld3".into(),
+                    id: 30003,
+                    current_id: None,
+                    removed: true,
+                },
+            ]
+        );
+        assert_eq!(
+            impact.groups,
+            [AccountImpact {
+                name: "nixbld".into(),
+                id: 30000,
+                current_id: Some(30000),
+                removed: true,
+            }]
+        );
+
+        let file = |path: &str| {
+            impact
+                .files
+                .iter()
+                .find(|file| file.path == Path::new(path))
+        };
+        assert_eq!(
+            file("/etc/bashrc"),
+            Some(&FileImpact {
+                path: "/etc/bashrc".into(),
+                partial: true,
+                status: Some(DriftStatus::Unchanged),
+            })
+        );
+        assert_eq!(
+            file("/etc/zshrc").and_then(|file| file.status),
+            Some(DriftStatus::Modified)
+        );
+        // Without a recorded hash, only whether it exists is known
+        assert_eq!(file("/etc/nix/nix.conf").unwrap().status, None);
+        assert_eq!(
+            file("/etc/profile.d/nix.sh").unwrap().status,
+            Some(DriftStatus::Missing)
+        );
+
+        assert!(impact.services.contains(&ServiceImpact {
+            name: "nix-daemon.socket".into(),
+            active: Some(false),
+        }));
+
+        let store = impact.store.as_ref().unwrap();
+        assert!(store.removed);
+        assert_eq!(store.store_paths, 3);
+        assert_eq!(store.bytes, 1200);
+
+        let report = impact.to_string();
+        assert!(report.contains("* `nixbld2` (now UID 31002, created as UID 30002)"));
+        assert!(report.contains(
+            "* `/etc/zshrc`, only what `nix-installer` added (modified since the install)"
+        ));
+        Ok(())
+    }
+}
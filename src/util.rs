@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) enum OnMissing {
@@ -33,3 +33,65 @@ pub(crate) async fn remove_dir_all(path: &Path, on_missing: OnMissing) -> std::i
         e @ Err(_) => e,
     }
 }
+
+/// The location of a well-known absolute path (like `/etc/tmpfiles.d`) on this host
+///
+/// This is the path itself, unless a [`SandboxContext`](crate::test_harness::SandboxContext) is active.
+pub(crate) fn host_path(path: impl AsRef<Path>) -> PathBuf {
+    #[cfg(any(test, feature = "test-harness"))]
+    return crate::test_harness::remap(path.as_ref());
+    #[cfg(not(any(test, feature = "test-harness")))]
+    return path.as_ref().to_path_buf();
+}
+
+/// Like [`which::which`], but every program is considered present while a [`SandboxContext`](crate::test_harness::SandboxContext) is active, or while planning for another platform
+pub(crate) fn which(program: &str) -> Result<PathBuf, which::Error> {
+    if crate::cross_target::is_active() {
+        return Ok(PathBuf::from(program));
+    }
+    #[cfg(any(test, feature = "test-harness"))]
+    if crate::test_harness::is_active() {
+        return Ok(PathBuf::from(program));
+    }
+    which::which(program)
+}
+
+/// Convert `path` to a `String` for a destination which can only hold UTF-8 (such as a plist or
+/// `nix.conf`), replacing any invalid sequences with `U+FFFD` and warning if that was required
+pub(crate) fn path_to_string_lossy(path: &Path) -> String {
+    match path.to_str() {
+        Some(path_str) => path_str.to_string(),
+        None => {
+            let lossy = path.to_string_lossy().to_string();
+            tracing::warn!(
+                path = %lossy,
+                "Path is not valid UTF-8, invalid sequences were replaced and the path may not be found later"
+            );
+            lossy
+        },
+    }
+}
+
+/// A [`serde_with`] adapter which serializes non-UTF-8 paths lossily (with a warning) instead of failing
+///
+/// Receipts are JSON, so a path which can't be represented as UTF-8 would otherwise prevent the
+/// entire receipt from being written.
+pub(crate) struct LossyPath;
+
+impl serde_with::SerializeAs<PathBuf> for LossyPath {
+    fn serialize_as<S>(source: &PathBuf, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&path_to_string_lossy(source))
+    }
+}
+
+impl<'de> serde_with::DeserializeAs<'de, PathBuf> for LossyPath {
+    fn deserialize_as<D>(deserializer: D) -> Result<PathBuf, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        <PathBuf as serde::Deserialize>::deserialize(deserializer)
+    }
+}
@@ -0,0 +1,194 @@
+/*! Warnings raised while planning, installing, or uninstalling
+
+Most problems which don't stop an install are only logged, which is easy to miss in a long log and
+invisible to tools reading `--format json`. The ones worth acting on are raised with [`warn`]
+instead, which logs them as before and also records a [`Warning`] with a stable [`WarningKind`].
+
+[`InstallPlan`](crate::InstallPlan) collects the warnings raised while planning and executing it:
+they are listed in the confirmation prompt and the final summary, sent as
+[`ProgressEvent::Warning`](crate::report::ProgressEvent::Warning)s, included in the JSON result, and
+stored in the receipt.
+*/
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use owo_colors::OwoColorize;
+
+tokio::task_local! {
+    static COLLECTOR: Arc<Mutex<Vec<Warning>>>;
+}
+
+/// Something which did not stop the install, but which a user should know about
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+/// What a [`Warning`] is about, for tools which act on some of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// The existing Nix volume is encrypted with FileVault, so `encrypt` was forced on
+    FileVaultForcedEncrypt,
+    /// The configuration profiles could not be loaded, so the checks against them were skipped
+    ProfileChecksSkipped,
+    /// The installer runs in a shell translated by Rosetta
+    RosettaShell,
+    /// The `nixbld` users could not be marked as hidden
+    UsersNotHidden,
+    /// `dscl` could not delete a user, which must be removed by hand
+    UserNotDeleted,
+    /// A user holds a Secure Token, so it was not deleted
+    SecureTokenHolder,
+    /// An existing Nix installation from another installer is being replaced
+    ReplacingImplementation,
+    /// The Nix daemon accepts unauthenticated TCP connections
+    UnauthenticatedDaemon,
+    /// A setting written to `nix.conf` is unknown to the Nix being installed
+    UnknownNixSetting,
+    /// The `nix` SELinux module could not be removed
+    SelinuxModuleLeftInPlace,
+    /// The ZFS dataset for `/nix` was kept because it is not empty
+    ZfsDatasetKept,
+    /// systemd is not running in WSL, so the Nix daemon is started from the shell profiles
+    WslSystemdNotRunning,
+    /// The `--seed-closure` could not be imported, so the store has only what was installed
+    SeedClosureNotImported,
+    /// The daemon of the Nix being installed cannot be socket activated, so it is always running
+    DaemonNotSocketActivated,
+    /// `--verify-store` ran out of time, so the store was not verified
+    StoreNotVerified,
+    /// The system-wide shell profiles are read-only, so those of the invoking user were configured
+    UserShellProfiles,
+    /// Build users left by an install with another build user prefix or count were found
+    StaleBuildUsers,
+    /// The quota of the Nix Store volume is more than its APFS container has free
+    VolumeQuotaAboveFreeSpace,
+    /// launchd has no weights, so a `--daemon-cpu-weight` or `--daemon-io-weight` above the default does nothing
+    DaemonWeightIgnored,
+    /// A home managed by systemd-homed is not active, so the files of its user were skipped
+    InactiveHome,
+    /// A deprecated flag or environment variable was used
+    Deprecated,
+    /// A kind this `nix-installer` does not know, read from a newer receipt
+    #[serde(other)]
+    Other,
+}
+
+impl Warning {
+    pub fn new(kind: WarningKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Log `message`, and record it as a [`Warning`] of `kind` if warnings are being [`collect`]ed
+pub(crate) fn warn(kind: WarningKind, message: impl Into<String>) {
+    let warning = Warning::new(kind, message);
+    tracing::warn!("{warning}");
+    record(warning);
+}
+
+fn record(warning: Warning) {
+    let _ = COLLECTOR.try_with(|collector| {
+        collector
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(warning)
+    });
+}
+
+/// Run `fut`, returning the warnings it raised alongside its output
+///
+/// Those warnings are also passed on to any enclosing `collect`. Tasks spawned by `fut` do not
+/// inherit the collector.
+pub(crate) async fn collect<F: Future>(fut: F) -> (F::Output, Vec<Warning>) {
+    let collector = Arc::new(Mutex::new(vec![]));
+    let output = COLLECTOR.scope(collector.clone(), fut).await;
+    let warnings = std::mem::take(
+        &mut *collector
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    );
+    for warning in &warnings {
+        record(warning.clone());
+    }
+    (output, warnings)
+}
+
+/// A `Warnings` section listing `warnings` for the prompt and summaries, empty if there are none
+pub fn describe(warnings: &[Warning]) -> String {
+    if warnings.is_empty() {
+        return String::new();
+    }
+    let mut buf = "Warnings:\n".to_string();
+    for warning in warnings {
+        buf.push_str(&format!("* {}\n", warning.message.yellow()));
+    }
+    buf.push('\n');
+    buf
+}
+
+/// Add each of `warnings` to `into`, unless an identical one is already there
+///
+/// Checks run more than once (like the pre-install checks) would otherwise repeat their warnings.
+pub(crate) fn merge(into: &mut Vec<Warning>, warnings: impl IntoIterator<Item = Warning>) {
+    for warning in warnings {
+        if !into.contains(&warning) {
+            into.push(warning);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{collect, warn, Warning, WarningKind};
+
+    #[tokio::test]
+    async fn collects_nested_warnings() {
+        let (((), inner), outer) = collect(async {
+            let inner = collect(async {
+                warn(WarningKind::UnknownNixSetting, "inner");
+            })
+            .await;
+            warn(WarningKind::RosettaShell, "outer");
+            inner
+        })
+        .await;
+        assert_eq!(
+            inner,
+            vec![Warning::new(WarningKind::UnknownNixSetting, "inner")]
+        );
+        assert_eq!(
+            outer,
+            vec![
+                Warning::new(WarningKind::UnknownNixSetting, "inner"),
+                Warning::new(WarningKind::RosettaShell, "outer"),
+            ]
+        );
+
+        // Outside of `collect` it is only logged
+        warn(WarningKind::RosettaShell, "ignored");
+    }
+
+    #[test]
+    fn unknown_kinds_are_read_as_other() -> eyre::Result<()> {
+        let warning: Warning =
+            serde_json::from_str(r#"{"kind":"from_the_future","message":"Hello"}"#)?;
+        assert_eq!(warning.kind, WarningKind::Other);
+        Ok(())
+    }
+}
@@ -0,0 +1,6 @@
+
+# Nix
+if [ -e '/nix/var/nix/profiles/default/etc/profile.d/nix.sh' ]; then
+  . '/nix/var/nix/profiles/default/etc/profile.d/nix.sh'
+fi
+# End Nix
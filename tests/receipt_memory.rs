@@ -0,0 +1,133 @@
+//! Keeps the peak heap use of loading and describing a receipt in check, since Nix is installed on
+//! machines with very little memory. This is its own test binary so the counting allocator only
+//! sees these tests.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    io::Write as _,
+};
+
+use nix_installer::InstallPlan;
+
+const LINUX: &str = include_str!("./fixtures/linux/linux.json");
+
+/// How many copies of the fixture's actions the synthetic receipt has
+const ACTION_COPIES: usize = 500;
+
+/// Counts the live and peak heap bytes of the current thread, while measuring
+struct CountingAllocator;
+
+thread_local! {
+    static MEASURING: Cell<bool> = const { Cell::new(false) };
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+fn record(delta: isize) {
+    // `try_with` as the allocator is still used while thread locals are torn down
+    let _ = MEASURING.try_with(|measuring| {
+        if !measuring.get() {
+            return;
+        }
+        let live = LIVE.with(|live| {
+            live.set(live.get() + delta);
+            live.get()
+        });
+        PEAK.with(|peak| peak.set(peak.get().max(live)));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(new_size as isize - layout.size() as isize);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The peak heap bytes allocated on this thread above what was live before `f` ran
+fn peak_heap_use<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    LIVE.with(|live| live.set(0));
+    PEAK.with(|peak| peak.set(0));
+    MEASURING.with(|measuring| measuring.set(true));
+    let ret = f();
+    MEASURING.with(|measuring| measuring.set(false));
+    (ret, PEAK.with(Cell::get) as usize)
+}
+
+/// A receipt with the Linux fixture's actions repeated [`ACTION_COPIES`] times
+fn large_receipt() -> eyre::Result<tempfile::NamedTempFile> {
+    let mut receipt: serde_json::Value = serde_json::from_str(LINUX)?;
+    let actions = receipt["actions"]
+        .as_array()
+        .cloned()
+        .ok_or_else(|| eyre::eyre!("Fixture has no actions"))?;
+    receipt["actions"] = actions
+        .iter()
+        .cycle()
+        .take(actions.len() * ACTION_COPIES)
+        .cloned()
+        .collect();
+
+    let mut file = tempfile::NamedTempFile::new()?;
+    serde_json::to_writer(&mut file, &receipt)?;
+    file.flush()?;
+    Ok(file)
+}
+
+#[test]
+fn loading_a_large_receipt_is_bounded() -> eyre::Result<()> {
+    let receipt = large_receipt()?;
+    let receipt_len = receipt.as_file().metadata()?.len() as usize;
+
+    let (plan, peak) = peak_heap_use(|| -> eyre::Result<InstallPlan> {
+        let reader = std::io::BufReader::new(std::fs::File::open(receipt.path())?);
+        Ok(serde_json::from_reader(reader)?)
+    });
+    let _plan = plan?;
+
+    // The parsed plan is about the size of its JSON, reading the whole receipt into a `String`
+    // before parsing it doubles that
+    assert!(
+        peak < receipt_len * 3 / 2,
+        "Peak heap use of {peak} bytes loading a {receipt_len} byte receipt"
+    );
+    Ok(())
+}
+
+#[test]
+fn describing_a_large_receipt_is_bounded() -> eyre::Result<()> {
+    let receipt = large_receipt()?;
+    let reader = std::io::BufReader::new(std::fs::File::open(receipt.path())?);
+    let plan: InstallPlan = serde_json::from_reader(reader)?;
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+
+    // Explaining, as only listing configured settings plans a default planner for the host first
+    let (described, peak) = peak_heap_use(|| -> eyre::Result<usize> {
+        let install = runtime.block_on(plan.describe_install(true))?;
+        let uninstall = runtime.block_on(plan.describe_uninstall(true))?;
+        Ok(install.len().max(uninstall.len()))
+    });
+    let described = described?;
+
+    // Writing the descriptions straight into the output stays within the `String`'s own growth,
+    // collecting them into pieces and joining those holds two or three copies
+    assert!(
+        peak < described * 2,
+        "Peak heap use of {peak} bytes for a {described} byte description"
+    );
+    Ok(())
+}